@@ -0,0 +1,51 @@
+//! Times writing a file of many parts that take long to line up, which is where building
+//! the grids of the parts in parallel pays off. Compare it with a sequential build:
+//!
+//! ```text
+//! cargo bench --bench merge
+//! cargo bench --bench merge --no-default-features
+//! ```
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use polyrhythmix::dsl::dsl::groups;
+use polyrhythmix::midi::core::{create_smf, DrumPart, SmfOptions};
+use polyrhythmix::midi::registry::PartRegistry;
+use polyrhythmix::midi::time::TimeSignature;
+
+fn main() {
+    // Co-prime lengths don't line up within the limit of 1000 bars.
+    let patterns = [
+        (DrumPart::KickDrum, "16x--x--"),
+        (DrumPart::SnareDrum, "16-x-x-x-x-"),
+        (DrumPart::HiHat, "16xxxxxxxxxxxxx"),
+        (DrumPart::CrashCymbal, "16x----------------"),
+        (DrumPart::Cowbell, "16x-x-x-x-x-x-x-x-x-x-x"),
+        (DrumPart::Claves, "16x--x--x--x--x--x--x--x--x-"),
+        (DrumPart::Shaker, "16xx-xx-xx-xx-xx-xx-xx-xx-xx-xx-x"),
+    ];
+    let parts: BTreeMap<_, _> = patterns.iter().map(|(part, pattern)| (*part, groups(pattern).unwrap().1)).collect();
+    let time_signature = TimeSignature::from_str("4/4").unwrap();
+
+    let mut best = Duration::MAX;
+    for _ in 0..5 {
+        let start = Instant::now();
+        let smf = create_smf(
+            &PartRegistry::default(),
+            parts.clone(),
+            BTreeMap::new(),
+            "",
+            &SmfOptions { time_signature, add_bass: true, ..SmfOptions::default() },
+        );
+        best = best.min(start.elapsed());
+        assert!(!smf.tracks.is_empty());
+    }
+    println!(
+        "{} parts over 1000 bars: {:?} ({})",
+        parts.len(),
+        best,
+        if cfg!(feature = "parallel") { "parallel" } else { "sequential" }
+    );
+}
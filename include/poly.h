@@ -0,0 +1,33 @@
+/* C interface of Poly, built with
+ *
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * into target/release/libpolyrhythmix.so (.dylib on macOS, polyrhythmix.dll on Windows).
+ */
+#ifndef POLY_H
+#define POLY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Writes the MIDI file of a JSON spec like {"patterns": {"kick": "8x--x--"}}, see the README
+ * for its settings. Returns 0 and stores the file in *out and its length in *len, which are
+ * freed with poly_free. Returns -1 on failure, poly_last_error tells why. */
+int poly_generate(const char *spec_json, uint8_t **out, size_t *len);
+
+/* Why the last poly_generate of the calling thread failed, NULL if it didn't. The string
+ * belongs to the library and lives until the next poly_generate of the thread. */
+const char *poly_last_error(void);
+
+/* Frees a file written by poly_generate, NULL is ignored. */
+void poly_free(uint8_t *out, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* POLY_H */
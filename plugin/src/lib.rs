@@ -0,0 +1,203 @@
+//! `Poly` as a CLAP and VST3 plugin. It keeps a JSON spec in its state, the same as the one of
+//! `api::spec`, and plays the bars it converges over in a loop while the transport of the host
+//! runs, following its tempo and its jumps. The loop is laid out when the plugin is
+//! initialized, which hosts do after restoring the state too, so the audio thread only walks
+//! through `live::playback::Loop` and never allocates.
+
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use midly::live::LiveEvent;
+use midly::MidiMessage;
+use nih_plug::prelude::*;
+use polyrhythmix::api::spec::Spec;
+use polyrhythmix::live::playback::Loop;
+
+pub struct Poly {
+    params: Arc<PolyParams>,
+    /// The loop of the spec, `None` when there's no spec or it can't be played.
+    looped: Option<Loop>,
+    /// Whether the transport ran in the last block, to set the instruments up when it starts
+    /// and to stop the notes still sounding when it stops.
+    playing: bool,
+}
+
+#[derive(Params)]
+struct PolyParams {
+    /// JSON of the spec, like `{"patterns": {"kick": "8x--x--", "snare": "4-x"}}`.
+    #[persist = "spec"]
+    spec: RwLock<String>,
+}
+
+impl Default for Poly {
+    fn default() -> Self {
+        Poly {
+            params: Arc::new(PolyParams {
+                spec: RwLock::new(String::new()),
+            }),
+            looped: None,
+            playing: false,
+        }
+    }
+}
+
+impl Plugin for Poly {
+    const NAME: &'static str = "Poly";
+    const VENDOR: &'static str = "Denis Redozubov";
+    const URL: &'static str = env!("CARGO_PKG_HOMEPAGE");
+    const EMAIL: &'static str = "denis.redozubov@gmail.com";
+    const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+    // Notes only, no audio goes in or out.
+    const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[];
+    const MIDI_OUTPUT: MidiConfig = MidiConfig::MidiCCs;
+
+    type SysExMessage = ();
+    type BackgroundTask = ();
+
+    fn params(&self) -> Arc<dyn Params> {
+        self.params.clone()
+    }
+
+    fn initialize(
+        &mut self,
+        _audio_io_layout: &AudioIOLayout,
+        _buffer_config: &BufferConfig,
+        _context: &mut impl InitContext<Self>,
+    ) -> bool {
+        let spec = self.params.spec.read().unwrap();
+        self.looped = if spec.trim().is_empty() {
+            None
+        } else {
+            match Spec::from_str(&spec).and_then(|spec| Loop::from_spec(&spec)) {
+                Ok(looped) => Some(looped),
+                Err(e) => {
+                    nih_error!("Can't play the spec: {}", e);
+                    None
+                }
+            }
+        };
+        true
+    }
+
+    fn reset(&mut self) {
+        self.playing = false;
+    }
+
+    fn process(
+        &mut self,
+        buffer: &mut Buffer,
+        _aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
+    ) -> ProcessStatus {
+        let Some(looped) = &self.looped else {
+            return ProcessStatus::Normal;
+        };
+        let transport = context.transport();
+        let (playing, tempo, sample_rate, from) =
+            (transport.playing, transport.tempo, transport.sample_rate, transport.pos_beats());
+        if !playing {
+            if self.playing {
+                for event in looped.silence() {
+                    if let Some(event) = note_event(event, 0) {
+                        context.send_event(event);
+                    }
+                }
+            }
+            self.playing = false;
+            return ProcessStatus::Normal;
+        }
+        let (Some(tempo), Some(from)) = (tempo, from) else {
+            return ProcessStatus::Normal;
+        };
+        if !self.playing {
+            for event in looped.preamble() {
+                if let Some(event) = note_event(event, 0) {
+                    context.send_event(event);
+                }
+            }
+            self.playing = true;
+        }
+        let samples = buffer.samples() as u32;
+        let samples_per_beat = sample_rate as f64 * 60.0 / tempo;
+        let to = from + samples as f64 / samples_per_beat;
+        for (beat, event) in looped.between(from, to) {
+            let timing = (((beat - from) * samples_per_beat) as u32).min(samples.saturating_sub(1));
+            if let Some(event) = note_event(event, timing) {
+                context.send_event(event);
+            }
+        }
+        ProcessStatus::Normal
+    }
+}
+
+/// The note event of a message of a loop, `timing` samples into the block. Messages a loop
+/// doesn't play are left out.
+fn note_event(event: LiveEvent<'static>, timing: u32) -> Option<NoteEvent<()>> {
+    let LiveEvent::Midi { channel, message } = event else {
+        return None;
+    };
+    let channel = channel.as_int();
+    match message {
+        MidiMessage::NoteOn { key, vel } if vel > 0 => Some(NoteEvent::NoteOn {
+            timing,
+            voice_id: None,
+            channel,
+            note: key.as_int(),
+            velocity: vel.as_int() as f32 / 127.0,
+        }),
+        MidiMessage::NoteOn { key, vel } | MidiMessage::NoteOff { key, vel } => Some(NoteEvent::NoteOff {
+            timing,
+            voice_id: None,
+            channel,
+            note: key.as_int(),
+            velocity: vel.as_int() as f32 / 127.0,
+        }),
+        MidiMessage::Controller { controller, value } => Some(NoteEvent::MidiCC {
+            timing,
+            channel,
+            cc: controller.as_int(),
+            value: value.as_int() as f32 / 127.0,
+        }),
+        MidiMessage::ProgramChange { program } => Some(NoteEvent::MidiProgramChange {
+            timing,
+            channel,
+            program: program.as_int(),
+        }),
+        _ => None,
+    }
+}
+
+impl ClapPlugin for Poly {
+    const CLAP_ID: &'static str = "com.github.dredozubov.poly";
+    const CLAP_DESCRIPTION: Option<&'static str> = Some("Polyrhythmically-inclinded Midi Drum generator");
+    const CLAP_MANUAL_URL: Option<&'static str> = Some(Self::URL);
+    const CLAP_SUPPORT_URL: Option<&'static str> = None;
+    const CLAP_FEATURES: &'static [ClapFeature] = &[ClapFeature::NoteEffect, ClapFeature::Drum, ClapFeature::Utility];
+}
+
+impl Vst3Plugin for Poly {
+    const VST3_CLASS_ID: [u8; 16] = *b"PolyrhythmixPlug";
+    const VST3_SUBCATEGORIES: &'static [Vst3SubCategory] = &[Vst3SubCategory::Instrument, Vst3SubCategory::Drum];
+}
+
+nih_export_clap!(Poly);
+nih_export_vst3!(Poly);
+
+#[test]
+fn test_note_event() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "4x--", "snare": "4-x"}, "ppqn": 4}"#).unwrap();
+    let looped = Loop::from_spec(&spec).unwrap();
+    let notes: Vec<(u32, u8, u8, bool)> = looped
+        .between(0.0, 2.0)
+        .filter_map(|(beat, event)| note_event(event, beat as u32))
+        .map(|event| match event {
+            NoteEvent::NoteOn { timing, channel, note, .. } => (timing, channel, note, true),
+            NoteEvent::NoteOff { timing, channel, note, .. } => (timing, channel, note, false),
+            _ => panic!("Unexpected {:?}", event),
+        })
+        .collect();
+    assert_eq!(notes, vec![(0, 9, 36, true), (1, 9, 36, false), (1, 9, 38, true)]);
+    assert!(looped.preamble().all(|event| matches!(note_event(event, 0), Some(NoteEvent::MidiProgramChange { .. }))));
+    assert!(looped.silence().all(|event| matches!(note_event(event, 0), Some(NoteEvent::NoteOff { .. }))));
+}
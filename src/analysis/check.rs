@@ -0,0 +1,170 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::dsl::dsl::{parse_pattern, KnownLength};
+use crate::error::Error;
+use crate::midi::alignment::{align, fill_parts, fit, Alignment};
+use crate::midi::core::{convergence, is_silent, resolution, DrumPart, Limits, OnLimit};
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Something wrong with the patterns of a file, found without writing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    /// A pattern doesn't follow the DSL.
+    Malformed(DrumPart, Error),
+    /// A pattern doesn't fill whole bars, an error with `Alignment::Strict` and a warning
+    /// with `Alignment::Warn`.
+    Misaligned(DrumPart, Error, Alignment),
+    /// A pattern has no hits, a placeholder of a part that's yet to be written.
+    Empty(DrumPart),
+    /// No pattern has hits, the file would be silent.
+    Silent,
+    /// A pattern is parsed but can't be written, like a pickup of a whole bar.
+    Invalid(DrumPart, String),
+    /// The patterns don't line up again within the limits.
+    Limit(String),
+}
+
+impl Problem {
+    /// Whether the patterns can't be written as they are, rather than just look off.
+    pub fn is_error(&self) -> bool {
+        !matches!(self, Problem::Misaligned(_, _, Alignment::Warn | Alignment::Pad | Alignment::Cycle) | Problem::Empty(_))
+    }
+
+    pub fn part(&self) -> Option<DrumPart> {
+        match self {
+            Problem::Malformed(part, _) | Problem::Misaligned(part, _, _) | Problem::Empty(part) | Problem::Invalid(part, _) => Some(*part),
+            Problem::Silent | Problem::Limit(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Malformed(part, e) => write!(f, "{} pattern is malformed.\n{}", part.name(), e),
+            Problem::Misaligned(_, e, _) => write!(f, "{}", e),
+            Problem::Empty(part) => write!(f, "{} pattern has no hits", part.name()),
+            Problem::Invalid(part, message) => write!(f, "{} {}", part.name(), message),
+            Problem::Silent => write!(f, "{}", Error::Silent),
+            Problem::Limit(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Finds what's wrong with `patterns` in the order `poly` would run into it: how each of them
+/// is written, whether they fill whole bars and have hits, then whether they line up again
+/// within the `limits`, which is only worked out when nothing else is wrong. Returns the
+/// problems and the bars the patterns take to line up again if they do.
+pub fn check(
+    patterns: &BTreeMap<DrumPart, String>,
+    time_signature: TimeSignature,
+    alignment: Alignment,
+    limits: &Limits,
+) -> (Vec<Problem>, Option<u32>) {
+    let bar = time_signature.to_128th();
+    let mut problems = Vec::new();
+    let mut groups = BTreeMap::new();
+    let mut pickups = BTreeMap::new();
+    let mut fills = BTreeSet::new();
+    for (part, pattern) in patterns {
+        let pattern = match parse_pattern(pattern) {
+            Ok(x) => x,
+            Err(e) => {
+                problems.push(Problem::Malformed(*part, e));
+                continue;
+            }
+        };
+        if !pattern.pickup.has_hits() && !pattern.groups.has_hits() {
+            problems.push(Problem::Empty(*part));
+        }
+        if pattern.pickup.to_128th() >= bar {
+            problems.push(Problem::Invalid(*part, format!("pickup should be shorter than a bar of {}", time_signature)));
+            continue;
+        }
+        let part_groups = match pattern.fit {
+            Some(bars) => match fit(&pattern.groups, bars * bar) {
+                Ok((fitted, _)) => fitted,
+                Err(e) => {
+                    problems.push(Problem::Invalid(*part, format!("can't be fit: {}", e)));
+                    continue;
+                }
+            },
+            None => pattern.groups,
+        };
+        // Parts filling the bars of the others don't have to fill whole bars themselves.
+        let part_groups = match pattern.fills {
+            true => {
+                fills.insert(*part);
+                part_groups
+            }
+            false => match align(*part, part_groups.clone(), time_signature, alignment) {
+                Ok((aligned, warning)) => {
+                    problems.extend(warning.map(|w| Problem::Misaligned(*part, w, alignment)));
+                    aligned
+                }
+                Err(e) => {
+                    problems.push(Problem::Misaligned(*part, e, alignment));
+                    part_groups
+                }
+            },
+        };
+        pickups.insert(*part, pattern.pickup);
+        groups.insert(*part, part_groups);
+    }
+    if !groups.is_empty() && is_silent(&groups, &pickups, &BTreeSet::new(), false, u32::MAX) {
+        problems.push(Problem::Silent);
+    }
+    if problems.iter().any(Problem::is_error) {
+        return (problems, None);
+    }
+    fill_parts(&mut groups, &fills, time_signature, limits.max_bars);
+    let ppqn = resolution(groups.values().chain(pickups.values()));
+    let limits = Limits {
+        on_limit: OnLimit::Fail,
+        ..*limits
+    };
+    match convergence(&groups, time_signature, ppqn, &limits) {
+        Ok(c) => (problems, c.converges),
+        Err(e) => {
+            problems.push(Problem::Limit(e));
+            (problems, None)
+        }
+    }
+}
+
+#[test]
+fn test_check() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let patterns = |parts: &[(DrumPart, &str)]| parts.iter().map(|(p, s)| (*p, s.to_string())).collect::<BTreeMap<_, _>>();
+    let limits = Limits::default();
+    let fine = patterns(&[(DrumPart::KickDrum, "8x--x--"), (DrumPart::SnareDrum, "4-x")]);
+    let (problems, converges) = check(&fine, four_four, Alignment::Warn, &limits);
+    assert_eq!((problems.len(), converges), (2, Some(3)));
+    assert!(!problems.iter().any(Problem::is_error));
+    let bars = patterns(&[(DrumPart::KickDrum, "4x-x-"), (DrumPart::SnareDrum, "(*,4-x)")]);
+    assert_eq!(check(&bars, four_four, Alignment::Strict, &limits), (Vec::new(), Some(1)));
+
+    let (problems, converges) = check(&fine, four_four, Alignment::Strict, &limits);
+    assert_eq!(problems.len(), 2);
+    assert!(problems.iter().all(Problem::is_error));
+    assert_eq!((problems[0].part(), converges), (Some(DrumPart::KickDrum), None));
+
+    let (problems, _) = check(&patterns(&[(DrumPart::KickDrum, "4x(3"), (DrumPart::HiHat, "4----")]), four_four, Alignment::Warn, &limits);
+    assert!(matches!(problems[0], Problem::Malformed(DrumPart::KickDrum, Error::Parse(_))));
+    assert_eq!(problems[1].to_string(), "hihat pattern has no hits");
+    assert!(!problems[1].is_error());
+    let (problems, _) = check(&patterns(&[(DrumPart::HiHat, "4----")]), four_four, Alignment::Warn, &limits);
+    assert_eq!(problems, vec![Problem::Empty(DrumPart::HiHat), Problem::Silent]);
+
+    let slow = Limits {
+        max_bars: 2,
+        ..Limits::default()
+    };
+    let (problems, converges) = check(&fine, four_four, Alignment::Warn, &slow);
+    assert!(matches!(problems.as_slice(), [Problem::Misaligned(..), Problem::Misaligned(..), Problem::Limit(_)]));
+    assert_eq!(converges, None);
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::analysis::metrics::onsets;
+use crate::dsl::dsl::{Groups, KnownLength};
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+
+/// Which bar of the two of a clave has three strokes, written `3-2` and `2-3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ClaveDirection {
+    /// Three strokes in the first bar, two in the second.
+    ThreeTwo,
+    /// Two strokes in the first bar, three in the second.
+    TwoThree,
+}
+
+impl fmt::Display for ClaveDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClaveDirection::ThreeTwo => write!(f, "3-2"),
+            ClaveDirection::TwoThree => write!(f, "2-3"),
+        }
+    }
+}
+
+impl FromStr for ClaveDirection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "3-2" | "3:2" => Ok(ClaveDirection::ThreeTwo),
+            "2-3" | "2:3" => Ok(ClaveDirection::TwoThree),
+            s => Err(format!("{} is not a clave direction, expected 3-2 or 2-3", s)),
+        }
+    }
+}
+
+/// Direction a pattern of two bars, or of an even number of them, leans to. A clave is `3-2`
+/// when it has three strokes in the first bar and two in the second, and `2-3` the other way
+/// round. Other parts lean to the bar that's the `3` side of their clave by playing off the
+/// beat more there, like the clave itself does. Patterns of an odd number of bars, or playing
+/// both bars alike, lean to neither.
+pub fn direction(groups: &Groups, time_signature: TimeSignature) -> Option<ClaveDirection> {
+    let (bar, beat) = (time_signature.to_128th(), time_signature.denominator.to_128th());
+    let length = groups.to_128th();
+    if bar == 0 || length == 0 || !length.is_multiple_of(2 * bar) {
+        return None;
+    }
+    // Hits and hits off the beat of the first bar of every pair, then of the second.
+    let (mut hits, mut off_beat) = ([0, 0], [0, 0]);
+    for onset in onsets(groups) {
+        let side = ((onset / bar) % 2) as usize;
+        hits[side] += 1;
+        off_beat[side] += !onset.is_multiple_of(beat) as u32;
+    }
+    let pairs = length / (2 * bar);
+    match (hits, off_beat) {
+        (h, _) if h == [3 * pairs, 2 * pairs] => Some(ClaveDirection::ThreeTwo),
+        (h, _) if h == [2 * pairs, 3 * pairs] => Some(ClaveDirection::TwoThree),
+        (_, [first, second]) if first > second => Some(ClaveDirection::ThreeTwo),
+        (_, [first, second]) if first < second => Some(ClaveDirection::TwoThree),
+        _ => None,
+    }
+}
+
+/// Parts leaning to the other direction than the `declared` one, with the direction they lean
+/// to. The claves are held to it like any other part.
+pub fn conflicts(
+    groups: &BTreeMap<DrumPart, Groups>,
+    declared: ClaveDirection,
+    time_signature: TimeSignature,
+) -> Vec<(DrumPart, ClaveDirection)> {
+    groups
+        .iter()
+        .filter_map(|(part, groups)| direction(groups, time_signature).map(|d| (*part, d)))
+        .filter(|(_, direction)| *direction != declared)
+        .collect()
+}
+
+#[test]
+fn test_clave_direction() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let direction_of = |pattern: &str| direction(&groups(pattern).unwrap().1, four_four);
+    // Son and rumba claves.
+    assert_eq!(direction_of("8x--x--x---x-x---"), Some(ClaveDirection::ThreeTwo));
+    assert_eq!(direction_of("8--x-x---x--x--x-"), Some(ClaveDirection::TwoThree));
+    assert_eq!(direction_of("8x--x---x--x-x---"), Some(ClaveDirection::ThreeTwo));
+    // Twice over, as a four bar pattern.
+    assert_eq!(direction_of("8x--x--x---x-x---x--x--x---x-x---"), Some(ClaveDirection::ThreeTwo));
+    // A bar of the cowbell leans to neither side, and neither do two bars played alike.
+    assert_eq!(direction_of("4x"), None);
+    assert_eq!(direction_of("8-x-x-x-x-x-x-x-x"), None);
+    // A conga off the beat in the second bar backs a 2-3 clave.
+    assert_eq!(direction_of("4x-x-8x-x--x-x"), Some(ClaveDirection::TwoThree));
+
+    let parts = BTreeMap::from_iter([
+        (DrumPart::Claves, groups("8x--x--x---x-x---").unwrap().1),
+        (DrumPart::Cowbell, groups("4x").unwrap().1),
+        (DrumPart::CongaSlap, groups("4x-x-8x-x--x-x").unwrap().1),
+    ]);
+    assert_eq!(conflicts(&parts, ClaveDirection::ThreeTwo, four_four), vec![(DrumPart::CongaSlap, ClaveDirection::TwoThree)]);
+    assert_eq!(conflicts(&parts, ClaveDirection::TwoThree, four_four), vec![(DrumPart::Claves, ClaveDirection::ThreeTwo)]);
+    assert_eq!(ClaveDirection::from_str("2-3"), Ok(ClaveDirection::TwoThree));
+    assert_eq!(ClaveDirection::ThreeTwo.to_string(), "3-2");
+    assert!(ClaveDirection::from_str("3-3").is_err());
+}
@@ -0,0 +1,84 @@
+use crate::dsl::dsl::{Groups, KnownLength};
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Share of notes of a pattern which are hits, from 0 (only rests) to 1 (only hits).
+pub fn density(groups: &Groups) -> f64 {
+    let (hits, notes) = groups.0.iter().flat_map(|g| g.notes.iter()).fold((0, 0), |(hits, notes), n| {
+        (hits + n.is_hit() as u32, notes + 1)
+    });
+    if notes == 0 {
+        0.0
+    } else {
+        hits as f64 / notes as f64
+    }
+}
+
+/// Start positions of hits in 128th notes over a single pass of the pattern.
+pub fn onsets(groups: &Groups) -> Vec<u32> {
+    let mut position = 0;
+    let mut out = Vec::new();
+    for group in groups.0.iter() {
+        let note_length = group.length.to_128th();
+        for note in group.notes.iter() {
+            if note.is_hit() {
+                out.push(position);
+            }
+            position += note_length;
+        }
+    }
+    out
+}
+
+/// Share of hits that don't land on a beat of the time signature, from 0 to 1.
+///
+/// A pattern which length isn't a multiple of the beat lands differently against it on every
+/// pass, so it's measured over as many passes as it takes to line up with the beat again.
+pub fn syncopation(groups: &Groups, time_signature: TimeSignature) -> f64 {
+    let beat = time_signature.denominator.to_128th();
+    let length = groups.to_128th();
+    if length == 0 {
+        return 0.0;
+    }
+    let passes = beat / gcd(beat, length);
+    let onsets = onsets(groups);
+    let (off_beat, total) = (0..passes)
+        .flat_map(|pass| onsets.iter().map(move |o| pass * length + o))
+        .fold((0, 0), |(off_beat, total), o| {
+            (off_beat + !o.is_multiple_of(beat) as u32, total + 1)
+        });
+    if total == 0 {
+        0.0
+    } else {
+        off_beat as f64 / total as f64
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[test]
+fn test_density() {
+    assert_eq!(density(&groups("16x-x-").unwrap().1), 0.5);
+    assert_eq!(density(&groups("4xxx8-").unwrap().1), 0.75);
+    assert_eq!(density(&Groups(vec![])), 0.0);
+}
+
+#[test]
+fn test_syncopation() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    assert_eq!(syncopation(&groups("4x-x-").unwrap().1, four_fourth), 0.0);
+    assert_eq!(syncopation(&groups("8-x").unwrap().1, four_fourth), 1.0);
+    assert_eq!(syncopation(&groups("16x-x-").unwrap().1, four_fourth), 0.5);
+    // Dotted eighths land on the beat once every four passes.
+    assert_eq!(syncopation(&groups("8.x").unwrap().1, four_fourth), 0.75);
+}
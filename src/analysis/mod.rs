@@ -0,0 +1,4 @@
+pub mod check;
+pub mod clave;
+pub mod metrics;
+pub mod pulses;
@@ -0,0 +1,105 @@
+use crate::generator::ratio::Ratio;
+
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Longest cycle in steps a `PulseGrid` lays out, a screen or two of them.
+pub const STEP_LIMIT: u64 = 4096;
+
+/// Pulse streams of a polyrhythm on the finest grid they all land on: every stream plays its
+/// number of evenly spaced pulses over a cycle of `steps` steps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PulseGrid {
+    /// Number of pulses of every stream over a cycle.
+    pub voices: Vec<u16>,
+    /// Steps of the grid in a cycle, the lowest common multiple of the voices.
+    pub steps: u32,
+}
+
+impl PulseGrid {
+    /// The grid of the voices of a ratio, if its cycle is no longer than `STEP_LIMIT` steps.
+    pub fn new(ratio: &Ratio) -> Result<PulseGrid, String> {
+        let steps = ratio.0.iter().try_fold(1u64, |acc, n| (acc / gcd(acc, *n as u64)).checked_mul(*n as u64));
+        match steps {
+            Some(steps) if steps <= STEP_LIMIT => Ok(PulseGrid {
+                voices: ratio.0.clone(),
+                steps: steps as u32,
+            }),
+            _ => Err(format!("The pulses take more than {} steps to line up again", STEP_LIMIT)),
+        }
+    }
+
+    /// Indices of the voices landing on a step, counted from 0.
+    pub fn landing(&self, step: u32) -> Vec<usize> {
+        let steps = self.steps.max(1);
+        self.voices
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| step.is_multiple_of(steps / **n as u32))
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Steps two or more of the streams land on together, with the voices landing there.
+    pub fn coincidences(&self) -> Vec<(u32, Vec<usize>)> {
+        (0..self.steps)
+            .map(|step| (step, self.landing(step)))
+            .filter(|(_, voices)| voices.len() > 1)
+            .collect()
+    }
+
+    /// A row per stream with `x` on its pulses and `-` between them, over a row with `*` where
+    /// every stream lands at once and `+` where some of them do. Rows are wrapped every `width`
+    /// steps, the blocks are apart by an empty line.
+    pub fn render(&self, width: usize) -> String {
+        let labels: Vec<String> = self.voices.iter().map(|n| n.to_string()).collect();
+        let label_width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+        let width = width.max(1) as u32;
+        let mut blocks = Vec::new();
+        for start in (0..self.steps).step_by(width as usize) {
+            let end = (start + width).min(self.steps);
+            let mut rows: Vec<String> = labels.iter().map(|l| format!("{:>w$} ", l, w = label_width)).collect();
+            let mut marks = format!("{:>w$} ", "", w = label_width);
+            for step in start..end {
+                let landing = self.landing(step);
+                for (i, row) in rows.iter_mut().enumerate() {
+                    row.push(if landing.contains(&i) { 'x' } else { '-' });
+                }
+                marks.push(match landing.len() {
+                    n if n == self.voices.len() && n > 1 => '*',
+                    n if n > 1 => '+',
+                    _ => ' ',
+                });
+            }
+            rows.push(marks.trim_end().to_string());
+            blocks.push(rows.join("\n"));
+        }
+        blocks.join("\n\n") + "\n"
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[test]
+fn test_pulse_grid() {
+    let grid = PulseGrid::new(&Ratio::from_str("3:4:5").unwrap()).unwrap();
+    assert_eq!(grid.steps, 60);
+    assert_eq!(grid.landing(0), vec![0, 1, 2]);
+    assert_eq!(grid.landing(20), vec![0]);
+    assert_eq!(grid.coincidences(), vec![(0, vec![0, 1, 2])]);
+
+    let grid = PulseGrid::new(&Ratio::from_str("4:6").unwrap()).unwrap();
+    assert_eq!(grid.coincidences(), vec![(0, vec![0, 1]), (6, vec![0, 1])]);
+    assert_eq!(grid.render(64), "4 x--x--x--x--\n6 x-x-x-x-x-x-\n  *     *\n");
+    assert_eq!(grid.render(8), "4 x--x--x-\n6 x-x-x-x-\n  *     *\n\n4 -x--\n6 x-x-\n\n");
+
+    let grid = PulseGrid::new(&Ratio::from_str("2:3:4").unwrap()).unwrap();
+    assert_eq!(grid.render(12), "2 x-----x-----\n3 x---x---x---\n4 x--x--x--x--\n  *     +\n");
+    assert!(PulseGrid::new(&Ratio::from_str("61:63:64:65").unwrap()).is_err());
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::error::{Error, ParseError};
+use crate::midi::core::MidiTempo;
+use crate::midi::profile::Profile;
+
+/// Defaults of the command line, read from `config.toml` in the configuration directory of the
+/// user and from `.poly.toml` in the working directory, which wins. Flags win over both. The
+/// files are a few `key = value` lines of TOML named like the flags:
+///
+/// ```text
+/// # ~/.config/poly/config.toml
+/// tempo = 132.5
+/// ppqn = 480
+/// key-map = "keys.json"
+/// target = "reaper"
+/// output-dir = "renders"
+/// part-velocity = "snare=45:30-60,kick=100-120"
+/// ```
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Config {
+    pub tempo: Option<f64>,
+    pub ppqn: Option<u16>,
+    pub key_map: Option<String>,
+    pub target: Option<Profile>,
+    /// Directory relative output paths are written to.
+    pub output_dir: Option<String>,
+    /// Velocities of parts, like `--part-velocity`.
+    pub part_velocity: Option<String>,
+}
+
+static KEYS: [&str; 6] = ["tempo", "ppqn", "key-map", "target", "output-dir", "part-velocity"];
+
+impl Config {
+    /// The settings of `other` on top of these ones.
+    pub fn layer(self, other: Config) -> Config {
+        Config {
+            tempo: other.tempo.or(self.tempo),
+            ppqn: other.ppqn.or(self.ppqn),
+            key_map: other.key_map.or(self.key_map),
+            target: other.target.or(self.target),
+            output_dir: other.output_dir.or(self.output_dir),
+            part_velocity: other.part_velocity.or(self.part_velocity),
+        }
+    }
+
+    /// Values of the settings as the flags they're the defaults of.
+    pub fn defaults(&self) -> Vec<(&'static str, String)> {
+        let mut defaults = Vec::new();
+        defaults.extend(self.tempo.map(|t| ("tempo", t.to_string())));
+        defaults.extend(self.ppqn.map(|p| ("ppqn", p.to_string())));
+        defaults.extend(self.key_map.clone().map(|k| ("key-map", k)));
+        defaults.extend(self.target.map(|t| ("target", t.to_string())));
+        defaults.extend(self.output_dir.clone().map(|d| ("output-dir", d)));
+        defaults.extend(self.part_velocity.clone().map(|v| ("part-velocity", v)));
+        defaults
+    }
+
+    /// The files a configuration is read from, the ones read last win. The one of the user is
+    /// in `$XDG_CONFIG_HOME/poly`, `~/.config/poly` if it's not set.
+    pub fn paths() -> Vec<PathBuf> {
+        let home = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+        let mut paths: Vec<PathBuf> = home.into_iter().map(|config| config.join("poly").join("config.toml")).collect();
+        paths.push(PathBuf::from(".poly.toml"));
+        paths
+    }
+
+    /// Reads the files of `paths` that exist on top of each other.
+    pub fn load(paths: &[PathBuf]) -> Result<Config, String> {
+        let mut config = Config::default();
+        for path in paths.iter().filter(|p| p.is_file()) {
+            let contents = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let layer = Config::from_str(&contents).map_err(|e| format!("Failed to parse {}:\n{}", path.display(), e))?;
+            config = config.layer(layer);
+        }
+        Ok(config)
+    }
+}
+
+impl FromStr for Config {
+    type Err = Error;
+
+    /// Reports every problem of the file at once.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Config::default();
+        let mut errors = Vec::new();
+        let mut line_start = 0;
+        for raw in s.split('\n') {
+            let offset = line_start;
+            line_start += raw.len() + 1;
+            let line = strip_comment(raw);
+            let start = line.len() - line.trim_start().len();
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| ParseError::at(s, offset + start, message);
+            if line.starts_with('[') {
+                errors.push(error("tables aren't supported, every setting goes at the top of the file".to_string()));
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                errors.push(error(format!("expected `key = value`, got `{}`", line)));
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if let Err(e) = config.set(key, value) {
+                errors.push(error(e));
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(config),
+            false => Err(Error::Parse(errors)),
+        }
+    }
+}
+
+impl Config {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let number = || value.parse::<f64>().map_err(|_| format!("{} should be a number, got `{}`", key, value));
+        match key {
+            "tempo" => match number()? {
+                tempo if MidiTempo::RANGE.contains(&tempo) => self.tempo = Some(tempo),
+                _ => return Err("tempo should be between 3.6 and 60000000 BPM".to_string()),
+            },
+            "ppqn" => match value.parse::<u16>() {
+                Ok(ppqn) if (1..=32767).contains(&ppqn) => self.ppqn = Some(ppqn),
+                _ => return Err(format!("ppqn should be a whole number of ticks from 1 to 32767, got `{}`", value)),
+            },
+            "key-map" => self.key_map = Some(string(key, value)?),
+            "target" => self.target = Some(Profile::from_str(&string(key, value)?)?),
+            "output-dir" => self.output_dir = Some(string(key, value)?),
+            "part-velocity" => self.part_velocity = Some(string(key, value)?),
+            _ => return Err(format!("{} is not a setting, expected one of: {}", key, KEYS.join(", "))),
+        }
+        Ok(())
+    }
+}
+
+/// The line up to a `#` that isn't in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// A basic string of TOML, in double quotes with `\"` and `\\` escaped, or a literal one in
+/// single quotes.
+fn string(key: &str, value: &str) -> Result<String, String> {
+    if let Some(literal) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+        return Ok(literal.to_string());
+    }
+    let quoted = value.strip_prefix('"').and_then(|v| v.strip_suffix('"'));
+    let Some(quoted) = quoted.filter(|_| value.len() >= 2) else {
+        return Err(format!("{} should be a string in quotes like \"{}\"", key, value.trim_matches(['"', '\''])));
+    };
+    let mut out = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(escaped @ ('"' | '\\')) => out.push(escaped),
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            other => return Err(format!("{} has an unknown escape `\\{}`", key, other.map_or(String::new(), String::from))),
+        }
+    }
+    Ok(out)
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, value) in self.defaults() {
+            match key {
+                "tempo" | "ppqn" => writeln!(f, "{} = {}", key, value)?,
+                _ => writeln!(f, "{} = \"{}\"", key, value.replace('\\', "\\\\").replace('"', "\\\""))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_config_from_str() {
+    let config = Config::from_str("# defaults\ntempo = 132.5\nppqn = 480 # fine\nkey-map = \"keys # all.json\"\ntarget = 'reaper'\n\noutput-dir = \"C:\\\\midi\"\npart-velocity = \"snare=30-60\"\n").unwrap();
+    assert_eq!(
+        config,
+        Config {
+            tempo: Some(132.5),
+            ppqn: Some(480),
+            key_map: Some("keys # all.json".to_string()),
+            target: Some(Profile::Reaper),
+            output_dir: Some("C:\\midi".to_string()),
+            part_velocity: Some("snare=30-60".to_string()),
+        }
+    );
+    assert_eq!(Config::from_str(&config.to_string()), Ok(config));
+    let errors = match Config::from_str("tempo = fast\n[poly]\nppqn = 0\ntarget = \"cubase\"\ntempoo = 120\nkey-map = keys.json") {
+        Err(Error::Parse(errors)) => errors,
+        other => panic!("expected parse errors, got {:?}", other),
+    };
+    assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), vec![1, 2, 3, 4, 5, 6]);
+    assert_eq!(errors[4].message, "tempoo is not a setting, expected one of: tempo, ppqn, key-map, target, output-dir, part-velocity");
+    assert_eq!(errors[5].message, "key-map should be a string in quotes like \"keys.json\"");
+}
+
+#[test]
+fn test_config_layers() {
+    let user = Config::from_str("tempo = 100\ntarget = \"logic\"").unwrap();
+    let project = Config::from_str("tempo = 90\noutput-dir = \"out\"").unwrap();
+    let config = user.layer(project);
+    assert_eq!((config.tempo, config.target, config.output_dir.as_deref()), (Some(90.0), Some(Profile::Logic), Some("out")));
+    assert_eq!(
+        config.defaults(),
+        vec![("tempo", "90".to_string()), ("target", "logic".to_string()), ("output-dir", "out".to_string())]
+    );
+    assert_eq!(Config::load(&[PathBuf::from("no/such/config.toml")]), Ok(Config::default()));
+}
@@ -0,0 +1,106 @@
+//! C interface of the library, declared in `include/poly.h`, for hosts like DAW plugins.
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+use std::str::FromStr;
+
+use crate::api::spec::{generate, Spec};
+
+thread_local! {
+    /// Why the last `poly_generate` of the thread failed.
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Writes the MIDI file of the JSON spec `spec_json`, see `Spec`. On success stores the file
+/// in `out` and its length in `len` and returns 0, the file is freed with `poly_free`. On
+/// failure returns -1 and `poly_last_error` tells why.
+///
+/// # Safety
+///
+/// `spec_json` should be a NUL-terminated string, `out` and `len` should be valid to write to.
+#[no_mangle]
+pub unsafe extern "C" fn poly_generate(spec_json: *const c_char, out: *mut *mut u8, len: *mut usize) -> c_int {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+    if spec_json.is_null() || out.is_null() || len.is_null() {
+        set_last_error("spec_json, out and len shouldn't be NULL".to_string());
+        return -1;
+    }
+    let json = CStr::from_ptr(spec_json);
+    // Unwinding into C is undefined, a bug of the generator is reported like any other error.
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        json.to_str()
+            .map_err(|e| e.to_string())
+            .and_then(Spec::from_str)
+            .and_then(|spec| generate(&spec))
+    }))
+    .unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Err(format!("Poly failed unexpectedly: {}", message))
+    });
+    match result {
+        Ok(bytes) => {
+            let bytes = bytes.into_boxed_slice();
+            *len = bytes.len();
+            *out = Box::into_raw(bytes) as *mut u8;
+            0
+        }
+        Err(e) => {
+            *out = ptr::null_mut();
+            *len = 0;
+            set_last_error(e);
+            -1
+        }
+    }
+}
+
+/// Why the last `poly_generate` of the calling thread failed, NULL if it didn't. The string
+/// belongs to the library and lives until the next `poly_generate` of the thread.
+#[no_mangle]
+pub extern "C" fn poly_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Frees a file written by `poly_generate`, NULL is ignored.
+///
+/// # Safety
+///
+/// `out` and `len` should be the ones of a single successful `poly_generate`.
+#[no_mangle]
+pub unsafe extern "C" fn poly_free(out: *mut u8, len: usize) {
+    if !out.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(out, len)));
+    }
+}
+
+#[test]
+fn test_poly_generate() {
+    let mut out = ptr::null_mut();
+    let mut len = 0;
+    let json = CString::new(r#"{"patterns": {"kick": "8x--x--", "snare": "4-x"}}"#).unwrap();
+    unsafe {
+        assert_eq!(poly_generate(json.as_ptr(), &mut out, &mut len), 0);
+        assert!(poly_last_error().is_null());
+        assert!(std::slice::from_raw_parts(out, len).starts_with(b"MThd"));
+        poly_free(out, len);
+    }
+
+    let json = CString::new(r#"{"patterns": {"kick": "4y"}}"#).unwrap();
+    unsafe {
+        assert_eq!(poly_generate(json.as_ptr(), &mut out, &mut len), -1);
+        assert_eq!((out, len), (ptr::null_mut(), 0));
+        let error = CStr::from_ptr(poly_last_error()).to_str().unwrap();
+        assert!(error.starts_with("Kick Drum pattern is malformed."));
+        assert_eq!(poly_generate(ptr::null(), &mut out, &mut len), -1);
+    }
+}
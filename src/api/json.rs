@@ -0,0 +1,331 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Deepest arrays and objects may be nested in a document, deeper ones are an error rather
+/// than running out of stack.
+pub const MAX_DEPTH: usize = 128;
+
+/// A JSON value, just enough of it to read the specs of `generate` and write key maps.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(BTreeMap<String, Json>),
+}
+
+impl Json {
+    /// Parses a whole JSON document, nothing but whitespace may follow the value.
+    pub fn parse(input: &str) -> Result<Json, String> {
+        let mut parser = Parser { input, position: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.whitespace();
+        if parser.position < input.len() {
+            return Err(parser.error("expected the end of the document"));
+        }
+        Ok(value)
+    }
+
+    /// Name of the kind of the value, for error messages.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Json::Null => "null",
+            Json::Bool(_) => "a boolean",
+            Json::Number(_) => "a number",
+            Json::String(_) => "a string",
+            Json::Array(_) => "an array",
+            Json::Object(_) => "an object",
+        }
+    }
+}
+
+/// Compact JSON, `{:#}` indents it by two spaces a level. Numbers that aren't finite are null.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_json(f, self, f.alternate().then_some(0))
+    }
+}
+
+fn write_json(f: &mut fmt::Formatter<'_>, value: &Json, indent: Option<usize>) -> fmt::Result {
+    let newline = |f: &mut fmt::Formatter<'_>, level: usize| match indent {
+        Some(_) => write!(f, "\n{:1$}", "", level * 2),
+        None => Ok(()),
+    };
+    let inner = indent.map(|i| i + 1);
+    match value {
+        Json::Null => write!(f, "null"),
+        Json::Bool(b) => write!(f, "{}", b),
+        Json::Number(n) if n.is_finite() => write!(f, "{}", n),
+        Json::Number(_) => write!(f, "null"),
+        Json::String(s) => write_string(f, s),
+        Json::Array(values) if values.is_empty() => write!(f, "[]"),
+        Json::Array(values) => {
+            write!(f, "[")?;
+            for (i, v) in values.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                newline(f, inner.unwrap_or(0))?;
+                write_json(f, v, inner)?;
+            }
+            newline(f, indent.unwrap_or(0))?;
+            write!(f, "]")
+        }
+        Json::Object(members) if members.is_empty() => write!(f, "{{}}"),
+        Json::Object(members) => {
+            write!(f, "{{")?;
+            for (i, (name, v)) in members.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ",")?;
+                }
+                newline(f, inner.unwrap_or(0))?;
+                write_string(f, name)?;
+                write!(f, "{}", if indent.is_some() { ": " } else { ":" })?;
+                write_json(f, v, inner)?;
+            }
+            newline(f, indent.unwrap_or(0))?;
+            write!(f, "}}")
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    /// Byte offset of the next character.
+    position: usize,
+    /// Arrays and objects the next value is in.
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("{} at byte {} of the JSON", message, self.position)
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\n' | '\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.whitespace();
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected `{}`", expected))),
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.whitespace();
+        match self.peek() {
+            Some('{' | '[') if self.depth >= MAX_DEPTH => Err(self.error(&format!("arrays and objects are nested more than {} deep", MAX_DEPTH))),
+            Some('{') => self.nested(Parser::object),
+            Some('[') => self.nested(Parser::array),
+            Some('"') => self.string().map(Json::String),
+            Some('-' | '0'..='9') => self.number(),
+            Some(_) => {
+                for (word, value) in [("null", Json::Null), ("true", Json::Bool(true)), ("false", Json::Bool(false))] {
+                    if self.input[self.position..].starts_with(word) {
+                        self.position += word.len();
+                        return Ok(value);
+                    }
+                }
+                Err(self.error("expected a value"))
+            }
+            None => Err(self.error("expected a value")),
+        }
+    }
+
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<Json, String>) -> Result<Json, String> {
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut members = BTreeMap::new();
+        self.whitespace();
+        if self.peek() == Some('}') {
+            self.position += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            self.whitespace();
+            if self.peek() != Some('"') {
+                return Err(self.error("expected the name of a member"));
+            }
+            let name = self.string()?;
+            self.expect(':')?;
+            let value = self.value()?;
+            if members.insert(name.clone(), value).is_some() {
+                return Err(self.error(&format!("`{}` is given more than once", name)));
+            }
+            self.whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some('}') => return Ok(Json::Object(members)),
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.whitespace();
+        if self.peek() == Some(']') {
+            self.position += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.whitespace();
+            match self.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Json::Array(items)),
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.expect('"')?;
+        let mut out = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(out),
+                Some('\\') => {
+                    let c = match self.next() {
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('/') => '/',
+                        Some('b') => '\u{8}',
+                        Some('f') => '\u{c}',
+                        Some('n') => '\n',
+                        Some('r') => '\r',
+                        Some('t') => '\t',
+                        Some('u') => self.unicode_escape()?,
+                        _ => return Err(self.error("unknown escape")),
+                    };
+                    out.push(c);
+                }
+                Some(c) if c < ' ' => return Err(self.error("control characters should be escaped")),
+                Some(c) => out.push(c),
+                None => return Err(self.error("expected `\"` to close the string")),
+            }
+        }
+    }
+
+    /// A character of a `\u` escape, characters out of the Basic Multilingual Plane come as
+    /// two of them.
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let high = self.hex()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            if !self.input[self.position..].starts_with("\\u") {
+                return Err(self.error("expected the second half of a surrogate pair"));
+            }
+            self.position += 2;
+            let low = self.hex()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return Err(self.error("expected the second half of a surrogate pair"));
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid character"))
+    }
+
+    fn hex(&mut self) -> Result<u32, String> {
+        let digits = self.input.get(self.position..self.position + 4).ok_or_else(|| self.error("expected 4 hex digits"))?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.error("expected 4 hex digits"))?;
+        self.position += 4;
+        Ok(code)
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start = self.position;
+        while matches!(self.peek(), Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9')) {
+            self.position += 1;
+        }
+        let number = &self.input[start..self.position];
+        let digits = number.strip_prefix('-').unwrap_or(number);
+        if digits.starts_with('0') && digits[1..].starts_with(|c: char| c.is_ascii_digit()) {
+            self.position = start;
+            return Err(self.error("numbers can't start with 0"));
+        }
+        number.parse::<f64>().map(Json::Number).map_err(|_| {
+            self.position = start;
+            self.error("expected a number")
+        })
+    }
+}
+
+#[test]
+fn test_json_parse() {
+    assert_eq!(Json::parse(" null "), Ok(Json::Null));
+    assert_eq!(Json::parse("[true, false, -1.5e2, \"a\\\"\\u00e9\\ud83e\\udd41\"]"), Ok(Json::Array(vec![
+        Json::Bool(true),
+        Json::Bool(false),
+        Json::Number(-150.0),
+        Json::String("a\"é🥁".to_string()),
+    ])));
+    assert_eq!(
+        Json::parse("{\"kick\": \"8x--x--\", \"bars\": {}}"),
+        Ok(Json::Object(BTreeMap::from_iter([
+            ("kick".to_string(), Json::String("8x--x--".to_string())),
+            ("bars".to_string(), Json::Object(BTreeMap::new())),
+        ])))
+    );
+    assert_eq!(Json::parse("{\"a\": 1,}"), Err("expected the name of a member at byte 8 of the JSON".to_string()));
+    assert!(Json::parse("{\"a\": 1, \"a\": 2}").is_err());
+    assert!(Json::parse("[1] 2").is_err());
+    assert!(Json::parse("\"tab\there\"").is_err());
+    assert!(Json::parse("nul").is_err());
+    let deep = |n: usize| format!("{}{}", "[".repeat(n), "]".repeat(n));
+    assert!(Json::parse(&deep(MAX_DEPTH)).is_ok());
+    assert_eq!(
+        Json::parse(&deep(200_000)),
+        Err(format!("arrays and objects are nested more than {} deep at byte {} of the JSON", MAX_DEPTH, MAX_DEPTH))
+    );
+}
+
+#[test]
+fn test_json_display() {
+    let value = Json::parse("{\"name\": \"a\\\"b\\n\", \"keys\": [36, 1.5], \"none\": null, \"empty\": []}").unwrap();
+    assert_eq!(value.to_string(), "{\"empty\":[],\"keys\":[36,1.5],\"name\":\"a\\\"b\\n\",\"none\":null}");
+    assert_eq!(Json::parse(&value.to_string()), Ok(value.clone()));
+    assert_eq!(Json::parse(&format!("{:#}", value)), Ok(value));
+    assert_eq!(format!("{:#}", Json::Array(vec![Json::Bool(true)])), "[\n  true\n]");
+}
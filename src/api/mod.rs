@@ -0,0 +1,8 @@
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod config;
+pub mod json;
+pub mod spec;
+pub mod template;
+#[cfg(feature = "wasm")]
+pub mod wasm;
@@ -0,0 +1,637 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use midly::{Smf, Timing};
+
+use crate::api::json::Json;
+use crate::analysis::clave::{self, ClaveDirection};
+use crate::dsl::dsl::{parse_pattern, Groups, KnownLength};
+use crate::dsl::pitch::{Degree, Key};
+use crate::midi::alignment::{align, fill_parts, fit, Alignment};
+use crate::error::Error;
+use crate::midi::core::{
+    bar_length, convergence, create_smf_with_progress, create_tracks, fits_resolution, is_silent, resolution, Convergence, DrumPart, Limits, MidiTempo, OnLimit, Overlap, Part, Progress,
+    SmfOptions, Tick,
+};
+use crate::midi::accents::{Accents, Every};
+use crate::midi::bassline::BassLine;
+use crate::midi::dynamics::Dynamics;
+use crate::midi::echo::Echo;
+use crate::midi::events::EventList;
+use crate::midi::groove::Groove;
+use crate::midi::humanize::Humanize;
+use crate::midi::keymap::KeyMap;
+use crate::midi::mixer::Mix;
+use crate::midi::names::{TrackName, TrackNames};
+use crate::midi::profile::Profile;
+use crate::midi::registry::{CustomPart, Layer, PartRegistry, PartVelocity};
+use crate::midi::stream;
+use crate::midi::tempo::{Modulation, TempoMap};
+use crate::midi::time::TimeSignature;
+use crate::midi::velocity::{Curve, NoteOffs, Release};
+
+
+/// Everything `generate` needs to write a MIDI file, read from JSON like
+///
+/// ```json
+/// {
+///   "patterns": { "kick": "8x--x--", "snare": "4-x", "Cajon": "16x-x-" },
+///   "parts": ["\"Cajon\" key=48 velocity=100"],
+///   "layers": ["kick key=35 channel=2"],
+///   "partVelocity": ["snare=45:30-60"],
+///   "timeSignature": "4/4",
+///   "tempo": 120,
+///   "bass": true
+/// }
+/// ```
+///
+/// Only `patterns` is required, the other members mirror the options of `poly` and default
+/// to the same values: `layers`, `partVelocity`, `bassKey`, `bassLine`, `key`, `modulate`,
+/// `target`, `ppqn`, `align`, `overlap`, `maxBars`, `maxEvents`, `onLimit`, `dynamics`,
+/// `accentDownbeats`, `accentStrongBeats`, `accentEvery`, `echo`, `humanizeTiming`,
+/// `humanizeVelocity`, `humanizeSeed`, `mix`, `velocityCurves`, `noteOffVelocity`,
+/// `noteOffsAsNoteOns`, `highResolutionVelocity`, `trackNames`, `mute`, `clave` and
+/// `allowSilence`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spec {
+    pub patterns: BTreeMap<DrumPart, String>,
+    pub registry: PartRegistry,
+    pub time_signature: TimeSignature,
+    pub tempo: f64,
+    pub modulations: Vec<Modulation>,
+    pub bass: bool,
+    pub target: Profile,
+    /// The lowest resolution that fits every note if `None`.
+    pub ppqn: Option<u16>,
+    /// Patterns are taken as they are if `None`.
+    pub alignment: Option<Alignment>,
+    pub overlap: Overlap,
+    pub limits: Limits,
+    pub dynamics: Vec<Dynamics>,
+    pub accents: Accents,
+    pub echoes: Vec<Echo>,
+    /// Seeded with `humanizeSeed`, 0 by default, as a spec always makes the same bytes.
+    pub humanize: Humanize,
+    pub mix: Vec<Mix>,
+    pub curves: Vec<Curve>,
+    pub note_offs: NoteOffs,
+    /// Whether velocities get high resolution velocity prefixes.
+    pub high_resolution_velocity: bool,
+    /// Parts left out of the file, which is as long as it is with them.
+    pub muted: BTreeSet<Part>,
+    pub names: TrackNames,
+    /// Grooves of the parts, they come from files so the JSON doesn't have them.
+    pub grooves: BTreeMap<DrumPart, Groove>,
+    /// Direction of the clave, parts of two bars that lean to the other one get a warning.
+    pub clave: Option<ClaveDirection>,
+    /// Whether patterns that only rest make a file of silence rather than `Error::Silent`.
+    pub allow_silence: bool,
+}
+
+impl FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Json::Object(mut members) = Json::parse(s)? else {
+            return Err("the spec should be a JSON object".to_string());
+        };
+        let mut take = |name: &str| members.remove(name).filter(|v| *v != Json::Null);
+
+        let mut registry = PartRegistry::default();
+        for declaration in strings(take("parts"), "parts")? {
+            registry.declare(CustomPart::from_str(&declaration)?)?;
+        }
+        for layer in strings(take("layers"), "layers")? {
+            let layer = Layer::parse(&layer, &registry)?;
+            registry.layer(layer);
+        }
+        for velocity in strings(take("partVelocity"), "partVelocity")? {
+            let velocity = PartVelocity::parse(&velocity, &registry)?;
+            registry.set_velocity(velocity);
+        }
+        let key = parsed::<Key>(take("bassKey"), "bassKey")?.unwrap_or_default();
+        let degrees = strings(take("bassLine"), "bassLine")?
+            .iter()
+            .map(|d| Degree::from_str(d))
+            .collect::<Result<_, _>>()?;
+        registry.set_bass_line(BassLine::new(key, degrees));
+        if let Some(key) = parsed::<Key>(take("key"), "key")? {
+            registry.set_key(key);
+        }
+        let mut patterns = BTreeMap::new();
+        match take("patterns") {
+            Some(Json::Object(parts)) if !parts.is_empty() => {
+                for (name, pattern) in parts {
+                    let part = registry.part(&name)?;
+                    patterns.insert(part, string(pattern, &format!("patterns.{}", name))?);
+                }
+            }
+            Some(Json::Object(_)) | None => return Err("no drum pattern was supplied".to_string()),
+            Some(other) => return Err(format!("patterns should be an object, got {}", other.kind())),
+        }
+        let time_signature = match take("timeSignature") {
+            Some(v) => TimeSignature::from_str(&string(v, "timeSignature")?)?,
+            None => TimeSignature::from_str("4/4")?,
+        };
+        let tempo = match take("tempo") {
+            Some(v) => number(v, "tempo")?,
+            None => 120.0,
+        };
+        let bass = match take("bass") {
+            Some(Json::Bool(b)) => b,
+            Some(other) => return Err(format!("bass should be a boolean, got {}", other.kind())),
+            None => false,
+        };
+        let target = parsed(take("target"), "target")?.unwrap_or_default();
+        let ppqn = integer(take("ppqn"), "ppqn")?;
+        let alignment = parsed(take("align"), "align")?;
+        let overlap = parsed(take("overlap"), "overlap")?.unwrap_or_default();
+        let defaults = Limits::default();
+        let limits = Limits {
+            max_bars: integer(take("maxBars"), "maxBars")?.unwrap_or(defaults.max_bars),
+            max_events: integer(take("maxEvents"), "maxEvents")?,
+            on_limit: parsed::<OnLimit>(take("onLimit"), "onLimit")?.unwrap_or(defaults.on_limit),
+        };
+        let dynamics = strings(take("dynamics"), "dynamics")?
+            .iter()
+            .map(|d| Dynamics::parse(d, &registry))
+            .collect::<Result<_, _>>()?;
+        let accents = Accents {
+            downbeats: match integer::<u8>(take("accentDownbeats"), "accentDownbeats")? {
+                Some(amount) if amount > 127 => return Err(format!("accentDownbeats should be at most 127, got {}", amount)),
+                amount => amount.unwrap_or(0),
+            },
+            strong_beats: match take("accentStrongBeats") {
+                Some(Json::Bool(b)) => b,
+                Some(other) => return Err(format!("accentStrongBeats should be a boolean, got {}", other.kind())),
+                None => false,
+            },
+            every: strings(take("accentEvery"), "accentEvery")?
+                .iter()
+                .map(|e| Every::parse(e, &registry))
+                .collect::<Result<_, _>>()?,
+        };
+        let modulations = strings(take("modulate"), "modulate")?
+            .iter()
+            .map(|m| Modulation::from_str(m))
+            .collect::<Result<_, _>>()?;
+        let echoes = strings(take("echo"), "echo")?
+            .iter()
+            .map(|e| Echo::parse(e, &registry))
+            .collect::<Result<_, _>>()?;
+        let humanize = Humanize {
+            timing: match take("humanizeTiming").map(|v| number(v, "humanizeTiming")).transpose()? {
+                Some(ms) if !(0.0..=1000.0).contains(&ms) => return Err(format!("humanizeTiming should be from 0 to 1000 milliseconds, got {}", ms)),
+                ms => ms.unwrap_or(0.0),
+            },
+            velocity: match integer::<u8>(take("humanizeVelocity"), "humanizeVelocity")? {
+                Some(amount) if amount > 127 => return Err(format!("humanizeVelocity should be at most 127, got {}", amount)),
+                amount => amount.unwrap_or(0),
+            },
+            seed: integer(take("humanizeSeed"), "humanizeSeed")?.unwrap_or(0),
+        };
+        let mix = strings(take("mix"), "mix")?
+            .iter()
+            .map(|m| Mix::parse(m, &registry))
+            .collect::<Result<_, _>>()?;
+        let curves = strings(take("velocityCurves"), "velocityCurves")?
+            .iter()
+            .map(|c| Curve::parse(c, &registry))
+            .collect::<Result<_, _>>()?;
+        let note_offs = NoteOffs {
+            releases: strings(take("noteOffVelocity"), "noteOffVelocity")?
+                .iter()
+                .map(|r| Release::parse(r, &registry))
+                .collect::<Result<_, _>>()?,
+            as_note_ons: match take("noteOffsAsNoteOns") {
+                Some(Json::Bool(b)) => b,
+                Some(other) => return Err(format!("noteOffsAsNoteOns should be a boolean, got {}", other.kind())),
+                None => false,
+            },
+        };
+        let high_resolution_velocity = match take("highResolutionVelocity") {
+            Some(Json::Bool(b)) => b,
+            Some(other) => return Err(format!("highResolutionVelocity should be a boolean, got {}", other.kind())),
+            None => false,
+        };
+        let muted = strings(take("mute"), "mute")?
+            .iter()
+            .map(|name| match name.as_str() {
+                "bass" => Ok(Part::Bass),
+                name => registry.part(name).map(Part::Drum),
+            })
+            .collect::<Result<_, _>>()?;
+        let names: Vec<TrackName> = strings(take("trackNames"), "trackNames")?
+            .iter()
+            .map(|n| TrackName::parse(n, &registry))
+            .collect::<Result<_, _>>()?;
+        let names = TrackNames::new(&names, None, &registry);
+        let clave = parsed(take("clave"), "clave")?;
+        let allow_silence = match take("allowSilence") {
+            Some(Json::Bool(b)) => b,
+            Some(other) => return Err(format!("allowSilence should be a boolean, got {}", other.kind())),
+            None => false,
+        };
+        if let Some(unknown) = members.keys().next() {
+            return Err(format!("`{}` is not a setting of the spec", unknown));
+        }
+        Ok(Spec {
+            patterns,
+            registry,
+            time_signature,
+            tempo,
+            modulations,
+            bass,
+            target,
+            ppqn,
+            alignment,
+            overlap,
+            limits,
+            dynamics,
+            accents,
+            echoes,
+            humanize,
+            mix,
+            curves,
+            note_offs,
+            high_resolution_velocity,
+            muted,
+            names,
+            grooves: BTreeMap::new(),
+            clave,
+            allow_silence,
+        })
+    }
+}
+
+fn string(value: Json, name: &str) -> Result<String, String> {
+    match value {
+        Json::String(s) => Ok(s),
+        other => Err(format!("{} should be a string, got {}", name, other.kind())),
+    }
+}
+
+fn strings(value: Option<Json>, name: &str) -> Result<Vec<String>, String> {
+    match value {
+        Some(Json::Array(items)) => items.into_iter().map(|item| string(item, name)).collect(),
+        Some(other) => Err(format!("{} should be an array of strings, got {}", name, other.kind())),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn number(value: Json, name: &str) -> Result<f64, String> {
+    match value {
+        Json::Number(n) => Ok(n),
+        other => Err(format!("{} should be a number, got {}", name, other.kind())),
+    }
+}
+
+fn integer<T: TryFrom<u64>>(value: Option<Json>, name: &str) -> Result<Option<T>, String> {
+    value
+        .map(|v| {
+            let n = number(v, name)?;
+            Some(n)
+                .filter(|n| n.fract() == 0.0 && *n >= 0.0 && *n <= u64::MAX as f64)
+                .and_then(|n| T::try_from(n as u64).ok())
+                .ok_or_else(|| format!("{} is not a valid {}", n, name))
+        })
+        .transpose()
+}
+
+/// A setting given as a string, like the ones of `poly`.
+fn parsed<T: FromStr<Err = String>>(value: Option<Json>, name: &str) -> Result<Option<T>, String> {
+    value.map(|v| T::from_str(&string(v, name)?)).transpose()
+}
+
+/// Text embedded into the files, the patterns they were made of.
+pub fn description(patterns: &BTreeMap<DrumPart, String>, registry: &PartRegistry) -> String {
+    let mut parts: String = "".to_string();
+    for (part, pattern) in patterns {
+        parts.push_str(&format!("\n{} - {}", registry.title(*part), pattern));
+    }
+    format!("{}{}", "Created using Poly. Part blueprints:", parts)
+}
+
+/// The patterns of a spec made ready to be written: parsed, fit, aligned and checked, with
+/// the resolution, the bars and the tempo of the file, see `arrange`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Arrangement {
+    pub groups: BTreeMap<DrumPart, Groups>,
+    pub pickups: BTreeMap<DrumPart, Groups>,
+    pub ppqn: u16,
+    pub convergence: Convergence,
+    /// Tick bar 1 starts on, after the pickup.
+    pub bar_one: Tick,
+    pub tempo: TempoMap,
+    /// What became of the patterns, like the ones scaled to fill their bars.
+    pub messages: Vec<String>,
+    /// Things about the patterns that don't stop the file from being written, like patterns
+    /// that don't fill whole bars or lean against the clave.
+    pub warnings: Vec<String>,
+}
+
+/// Parses the patterns of a spec and works out everything about the file of them but its
+/// events.
+pub fn arrange(spec: &Spec) -> Result<Arrangement, String> {
+    if !MidiTempo::RANGE.contains(&spec.tempo) {
+        return Err("Tempo should be between 3.6 and 60000000 BPM".to_string());
+    }
+    let bar = spec.time_signature.to_128th();
+    let mut groups = BTreeMap::new();
+    let mut pickups = BTreeMap::new();
+    let mut fills = BTreeSet::new();
+    let (mut messages, mut warnings, mut misaligned) = (Vec::new(), Vec::new(), Vec::new());
+    for (part, pattern) in spec.patterns.iter() {
+        let pattern = parse_pattern(pattern)
+            .map_err(|e| format!("{} pattern is malformed.\n{}", spec.registry.title(*part), e))?;
+        if pattern.fills {
+            fills.insert(*part);
+        }
+        if pattern.pickup.to_128th() >= bar {
+            return Err(format!(
+                "{} pickup should be shorter than a bar of {}",
+                spec.registry.title(*part),
+                spec.time_signature
+            ));
+        }
+        if !pattern.pickup.0.is_empty() {
+            pickups.insert(*part, pattern.pickup);
+        }
+        let part_groups = match pattern.fit {
+            Some(bars) => {
+                let (fitted, (times, of)) = fit(&pattern.groups, bars * bar)
+                    .map_err(|e| format!("{} can't be fit: {}", spec.registry.title(*part), e))?;
+                let plural = if bars == 1 { "" } else { "s" };
+                messages.push(format!("{} is scaled by {}/{} to fill {} bar{} as {}", spec.registry.title(*part), times, of, bars, plural, fitted));
+                fitted
+            }
+            None => pattern.groups,
+        };
+        let part_groups = match spec.alignment {
+            Some(alignment) => match align(*part, part_groups, spec.time_signature, alignment) {
+                Ok((aligned, warning)) => {
+                    warnings.extend(warning.map(|w| w.to_string()));
+                    aligned
+                }
+                Err(e) => {
+                    misaligned.push(e.to_string());
+                    continue;
+                }
+            },
+            None => part_groups,
+        };
+        groups.insert(*part, part_groups);
+    }
+    if !misaligned.is_empty() {
+        return Err(misaligned.join("\n"));
+    }
+    fill_parts(&mut groups, &fills, spec.time_signature, spec.limits.max_bars);
+    if let Some(declared) = spec.clave {
+        for (part, direction) in clave::conflicts(&groups, declared, spec.time_signature) {
+            warnings.push(format!("{} pattern leans to {} clave, against the declared {}", spec.registry.title(part), direction, declared));
+        }
+    }
+    let ppqn = spec.ppqn.unwrap_or_else(|| resolution(groups.values().chain(pickups.values())));
+    if ppqn == 0 {
+        return Err("MIDI resolution should be at least 1 tick per quarter note".to_string());
+    }
+    if !fits_resolution(groups.values().chain(pickups.values()), ppqn) {
+        return Err(format!("Notes of the patterns aren't a whole number of ticks at {} ticks per quarter note, {} ticks fit every note", ppqn, resolution(groups.values().chain(pickups.values()))));
+    }
+    let convergence = convergence(&groups, spec.time_signature, ppqn, &spec.limits)?;
+    if !spec.allow_silence && is_silent(&groups, &pickups, &spec.muted, spec.bass, convergence.bars * bar) {
+        return Err(Error::Silent.to_string());
+    }
+    let bar_one = Tick(pickups.values().map(|p| p.to_128th()).max().unwrap_or(0) as u128 * ppqn as u128 / 32);
+    let tempo = TempoMap::modulated(spec.tempo, &spec.modulations, bar_one, bar_length(spec.time_signature, ppqn), convergence.bars)?;
+    Ok(Arrangement {
+        groups,
+        pickups,
+        ppqn,
+        convergence,
+        bar_one,
+        tempo,
+        messages,
+        warnings,
+    })
+}
+
+impl Arrangement {
+    /// The MIDI file of the arranged patterns of `spec` with `text` embedded into it, calling
+    /// `progress` as the bars are written.
+    pub fn smf<'a>(&self, spec: &'a Spec, text: &'a str, progress: &mut dyn FnMut(Progress)) -> Smf<'a> {
+        let options = self.options(spec);
+        create_smf_with_progress(&spec.registry, self.groups.clone(), self.pickups.clone(), self.convergence.bars, text, &options, progress)
+    }
+
+    /// Writes the file `smf` returns to `out`, mapping the events of its tracks as they're
+    /// written rather than holding the whole file in memory.
+    pub fn write(&self, spec: &Spec, text: &str, progress: &mut dyn FnMut(Progress), out: impl Write) -> io::Result<()> {
+        let options = self.options(spec);
+        let tracks = create_tracks(&spec.registry, self.groups.clone(), self.pickups.clone(), self.convergence.bars, text, &options, progress);
+        stream::write_tracks(&tracks, out)
+    }
+
+    fn options<'a>(&self, spec: &'a Spec) -> SmfOptions<'a> {
+        SmfOptions {
+            grooves: spec.grooves.clone(),
+            dynamics: spec.dynamics.clone(),
+            accents: spec.accents.clone(),
+            echoes: spec.echoes.clone(),
+            humanize: spec.humanize,
+            mix: spec.mix.clone(),
+            curves: spec.curves.clone(),
+            note_offs: spec.note_offs.clone(),
+            high_resolution_velocity: spec.high_resolution_velocity,
+            muted: spec.muted.clone(),
+            names: &spec.names,
+            profile: spec.target,
+            overlap: spec.overlap,
+            time_signature: spec.time_signature,
+            tempo: self.tempo.clone(),
+            add_bass: spec.bass,
+            ppqn: self.ppqn,
+        }
+    }
+}
+
+/// The MIDI file of a spec with `text` embedded into it, and the number of bars it takes
+/// after the pickup.
+pub fn render<'a>(spec: &'a Spec, text: &'a str) -> Result<(Smf<'a>, u32), String> {
+    let arrangement = arrange(spec)?;
+    Ok((arrangement.smf(spec, text, &mut |_| {}), arrangement.convergence.bars))
+}
+
+/// Writes the MIDI file of a spec to memory, the same bytes `poly` writes for the same settings.
+/// Neither touches files nor prints anything, so it runs anywhere the library compiles to.
+pub fn generate(spec: &Spec) -> Result<Vec<u8>, String> {
+    let text = description(&spec.patterns, &spec.registry);
+    let mut bytes = Vec::new();
+    arrange(spec)?.write(spec, &text, &mut |_| {}, &mut bytes).map_err(|e| e.to_string())?;
+    Ok(bytes)
+}
+
+/// The notes of the MIDI file of a spec as the JSON of an `EventList`, with the parts playing
+/// them, for sequencers in the browser.
+pub fn generate_events(spec: &Spec) -> Result<String, String> {
+    let text = description(&spec.patterns, &spec.registry);
+    let (smf, bars) = render(spec, &text)?;
+    let ppqn = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int(),
+        Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing aren't supported".to_string()),
+    };
+    let pickup = spec.patterns.values().filter_map(|p| parse_pattern(p).ok()).map(|p| p.pickup.to_128th()).max().unwrap_or(0);
+    let bar_one = Tick(pickup as u128 * ppqn as u128 / 32);
+    let mut parts: Vec<Part> = spec.patterns.keys().map(|part| Part::Drum(*part)).collect();
+    if spec.bass {
+        parts.push(Part::Bass);
+    }
+    let mut keys = KeyMap { ppqn, ..KeyMap::default() };
+    keys.read(&smf, &parts, &spec.registry, spec.target);
+    let mut events = EventList::new(bar_one, bar_one + bar_length(spec.time_signature, ppqn) * bars as u128);
+    events.read(&smf, &keys)?;
+    Ok(events.to_json().to_string())
+}
+
+#[test]
+fn test_spec_from_str() {
+    let spec = Spec::from_str(
+        r#"{"patterns": {"kick": "8x--x--", "Cajon": "4-x"}, "parts": ["\"Cajon\" key=48"], "tempo": 90.5, "bass": true, "maxEvents": 20}"#,
+    )
+    .unwrap();
+    assert_eq!(spec.patterns.len(), 2);
+    assert_eq!(spec.patterns[&spec.registry.part("Cajon").unwrap()], "4-x");
+    assert_eq!((spec.tempo, spec.bass, spec.ppqn), (90.5, true, None));
+    assert_eq!(spec.limits.max_events, Some(20));
+    assert_eq!(spec.target, Profile::GenericGm);
+
+    assert_eq!(Spec::from_str("[]"), Err("the spec should be a JSON object".to_string()));
+    assert_eq!(Spec::from_str(r#"{"patterns": {}}"#), Err("no drum pattern was supplied".to_string()));
+    assert_eq!(
+        Spec::from_str(r#"{"patterns": {"kick": "4x"}, "tempo": "fast"}"#),
+        Err("tempo should be a number, got a string".to_string())
+    );
+    assert_eq!(
+        Spec::from_str(r#"{"patterns": {"kick": "4x"}, "ppqn": 1.5}"#),
+        Err("1.5 is not a valid ppqn".to_string())
+    );
+    assert_eq!(
+        Spec::from_str(r#"{"patterns": {"kick": "4x"}, "swing": 0.6}"#),
+        Err("`swing` is not a setting of the spec".to_string())
+    );
+    assert!(Spec::from_str(r#"{"patterns": {"cajon": "4x"}}"#).is_err());
+}
+
+#[test]
+fn test_generate() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "8x--x--", "snare": "4-x"}, "bass": true}"#).unwrap();
+    let bytes = generate(&spec).unwrap();
+    let smf = Smf::parse(&bytes).unwrap();
+    assert_eq!(smf.tracks.len(), 2);
+    assert_eq!(generate(&spec).unwrap(), bytes);
+
+    // Muted parts are left out, the file is as long as it is with them.
+    let muted = Spec::from_str(r#"{"patterns": {"kick": "8x--x--", "snare": "4-x"}, "bass": true, "mute": ["snare", "bass"]}"#).unwrap();
+    let bytes = generate(&muted).unwrap();
+    let smf = Smf::parse(&bytes).unwrap();
+    let note_ons = |track: &[midly::TrackEvent]| {
+        track
+            .iter()
+            .filter_map(|e| match e.kind {
+                midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    assert!(note_ons(&smf.tracks[0]).iter().all(|key| *key == 36));
+    assert!(note_ons(&smf.tracks[1]).is_empty());
+
+    let events = generate_events(&Spec::from_str(r#"{"patterns": {"kick": "8x|4x"}}"#).unwrap()).unwrap();
+    assert!(events.contains("\"length\":216,\"loopStart\":24,"));
+    assert_eq!(events.matches("\"part\":\"kick\"").count(), 5);
+
+    let named = Spec::from_str(r#"{"patterns": {"kick": "4x"}, "bass": true, "trackNames": ["Verse", "bass=Walking"]}"#).unwrap();
+    let bytes = generate(&named).unwrap();
+    let smf = Smf::parse(&bytes).unwrap();
+    let names: Vec<&[u8]> = smf
+        .tracks
+        .iter()
+        .flat_map(|track| track.iter())
+        .filter_map(|e| match e.kind {
+            midly::TrackEventKind::Meta(midly::MetaMessage::TrackName(name)) => Some(name),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(names, vec![b"Verse".as_slice(), b"Walking".as_slice()]);
+
+    // Plain hits of the snare at 45, the quieter one kept at 30 rather than scaled to 23.
+    let ghosts = Spec::from_str(r#"{"patterns": {"snare": "8x*2<"}, "partVelocity": ["snare=45:30-60"]}"#).unwrap();
+    let smf_bytes = generate(&ghosts).unwrap();
+    let velocities: Vec<u8> = Smf::parse(&smf_bytes).unwrap().tracks[0]
+        .iter()
+        .filter_map(|e| match e.kind {
+            midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOn { vel, .. }, .. } => Some(vel.as_int()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(velocities[..2], [30, 45]);
+    assert!(Spec::from_str(r#"{"patterns": {"snare": "4x"}, "partVelocity": ["snare=70:30-60"]}"#).is_err());
+
+    // Velocities vary by up to 10 and the hits stay on the grid, the same every time.
+    let humanized = Spec::from_str(r#"{"patterns": {"hihat": "8x"}, "humanizeVelocity": 10, "humanizeSeed": 3}"#).unwrap();
+    let smf_bytes = generate(&humanized).unwrap();
+    assert_eq!(generate(&humanized).unwrap(), smf_bytes);
+    let notes: Vec<(u32, u8)> = Smf::parse(&smf_bytes).unwrap().tracks[0]
+        .iter()
+        .filter_map(|e| match e.kind {
+            midly::TrackEventKind::Midi { message: midly::MidiMessage::NoteOn { vel, .. }, .. } => Some((e.delta.as_int(), vel.as_int())),
+            _ => None,
+        })
+        .collect();
+    assert!(notes.iter().all(|(_, v)| (117..=127).contains(v)) && notes.iter().any(|(_, v)| *v < 127));
+    assert!(notes[1..].iter().all(|(delta, _)| *delta == 0 || *delta == 24));
+    assert!(Spec::from_str(r#"{"patterns": {"hihat": "8x"}, "humanizeTiming": -5}"#).is_err());
+
+    // A dotted eighth of 90 BPM becomes the quarter note of bar 2, at 120 BPM.
+    let modulated = Spec::from_str(r#"{"patterns": {"kick": "4x---x---"}, "tempo": 90, "modulate": ["8.=4@2"]}"#).unwrap();
+    let smf_bytes = generate(&modulated).unwrap();
+    let smf = Smf::parse(&smf_bytes).unwrap();
+    assert_eq!(TempoMap::read(&smf).changes(), &[(Tick(0), MidiTempo::from_tempo(90.0)), (Tick(192), MidiTempo::from_tempo(120.0))]);
+    let short = Spec::from_str(r#"{"patterns": {"kick": "4x"}, "modulate": ["8.=4@2"]}"#).unwrap();
+    assert!(generate(&short).is_err());
+    assert!(Spec::from_str(r#"{"patterns": {"kick": "4x"}, "modulate": ["8.=4"]}"#).is_err());
+
+    let malformed = Spec::from_str(r#"{"patterns": {"kick": "8x-(7,8xx"}}"#).unwrap();
+    assert!(generate(&malformed).unwrap_err().starts_with("Kick Drum pattern is malformed."));
+    let slow = Spec::from_str(r#"{"patterns": {"kick": "4x"}, "tempo": 1}"#).unwrap();
+    assert!(generate(&slow).is_err());
+    let rests = Spec::from_str(r#"{"patterns": {"kick": "4x---"}, "mute": ["kick"]}"#).unwrap();
+    assert_eq!(generate(&rests), Err(Error::Silent.to_string()));
+    assert!(generate(&Spec { allow_silence: true, ..rests }).is_ok());
+    // Eighth notes are half a tick at a resolution of 1.
+    let coarse = Spec::from_str(r#"{"patterns": {"kick": "8x"}, "ppqn": 1}"#).unwrap();
+    assert!(generate(&coarse).unwrap_err().contains("at 1 ticks per quarter note"));
+    assert!(generate(&Spec { ppqn: Some(2), ..coarse }).is_ok());
+}
+
+#[test]
+fn test_arrange() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "8x--x--", "snare": "4-x"}, "ppqn": 24}"#).unwrap();
+    let arrangement = arrange(&spec).unwrap();
+    assert_eq!((arrangement.ppqn, arrangement.convergence.bars, arrangement.bar_one), (24, 3, Tick(0)));
+    assert!(arrangement.messages.is_empty() && arrangement.warnings.is_empty());
+
+    // Patterns that don't fill whole bars and lean against the clave are let through with warnings.
+    let warned = Spec::from_str(r#"{"patterns": {"kick": "8x--x--", "conga": "4x-x-8x-x--x-x"}, "align": "warn", "clave": "3-2"}"#).unwrap();
+    let warnings = arrange(&warned).unwrap().warnings;
+    assert_eq!(warnings.len(), 2);
+    assert!(warnings[0].contains("short of filling whole bars"));
+    assert_eq!(warnings[1], "Open Conga pattern leans to 2-3 clave, against the declared 3-2");
+    let strict = Spec::from_str(r#"{"patterns": {"kick": "8x--x--", "snare": "8xx"}, "align": "strict"}"#).unwrap();
+    assert_eq!(arrange(&strict).unwrap_err().lines().count(), 2);
+
+    let fitted = Spec::from_str(r#"{"patterns": {"hihat": "(=1,16xxxxxxx)"}, "highResolutionVelocity": true}"#).unwrap();
+    assert!(fitted.high_resolution_velocity);
+    assert_eq!(arrange(&fitted).unwrap().messages.len(), 1);
+}
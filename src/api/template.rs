@@ -0,0 +1,171 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::midi::profile::Profile;
+use crate::midi::time::TimeSignature;
+
+/// An output path with placeholders in braces filled in from the patterns written to it, like
+/// `{name}_{tempo}bpm_{signature}.mid`. `{{` and `}}` write braces of their own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    Text(String),
+    Field(Field),
+}
+
+/// What a placeholder is filled in with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// Name of the drum track if it's given one, of the file the patterns are read from if
+    /// they are, `poly` otherwise.
+    Name,
+    /// Tempo in BPM, like `120` or `132.5`.
+    Tempo,
+    /// Time signature with a dash, like `7-8`, as a slash would start a directory.
+    Signature,
+    /// Number of bars written.
+    Bars,
+    Ppqn,
+    Target,
+}
+
+static FIELDS: [(&str, Field); 6] = [
+    ("name", Field::Name),
+    ("tempo", Field::Tempo),
+    ("signature", Field::Signature),
+    ("bars", Field::Bars),
+    ("ppqn", Field::Ppqn),
+    ("target", Field::Target),
+];
+
+/// What the placeholders of a template are filled in from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub name: String,
+    pub tempo: f64,
+    pub signature: TimeSignature,
+    pub bars: u32,
+    pub ppqn: u16,
+    pub target: Profile,
+}
+
+impl Template {
+    /// The path with the placeholders filled in. Separators of paths in the values are written
+    /// as `_`, so a track called `Verse/Chorus` doesn't make a directory.
+    pub fn fill(&self, metadata: &Metadata) -> String {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Field(field) => out.extend(metadata.value(*field).chars().map(|c| match c {
+                    '/' | '\\' | ':' => '_',
+                    c => c,
+                })),
+            }
+        }
+        out
+    }
+
+    /// Whether the path has placeholders at all.
+    pub fn is_plain(&self) -> bool {
+        self.pieces.iter().all(|p| matches!(p, Piece::Text(_)))
+    }
+}
+
+impl Metadata {
+    fn value(&self, field: Field) -> String {
+        match field {
+            Field::Name => self.name.clone(),
+            Field::Tempo => self.tempo.to_string(),
+            Field::Signature => format!("{}-{}", self.signature.numerator, self.signature.denominator),
+            Field::Bars => self.bars.to_string(),
+            Field::Ppqn => self.ppqn.to_string(),
+            Field::Target => self.target.to_string(),
+        }
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut rest = s;
+        while let Some(c) = rest.chars().next() {
+            if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+                text.push(c);
+                rest = after;
+                continue;
+            }
+            match c {
+                '{' => {
+                    let Some((name, after)) = rest[1..].split_once('}') else {
+                        return Err(format!("expected `}}` to close the placeholder in `{}`, write `{{{{` for a brace", s));
+                    };
+                    let Some((_, field)) = FIELDS.iter().find(|(n, _)| *n == name.trim()) else {
+                        let known: Vec<&str> = FIELDS.iter().map(|(n, _)| *n).collect();
+                        return Err(format!("`{{{}}}` is not a placeholder, expected one of: {}", name, known.join(", ")));
+                    };
+                    if !text.is_empty() {
+                        pieces.push(Piece::Text(std::mem::take(&mut text)));
+                    }
+                    pieces.push(Piece::Field(*field));
+                    rest = after;
+                }
+                '}' => return Err(format!("`}}` closes no placeholder in `{}`, write `}}}}` for a brace", s)),
+                c => {
+                    text.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        Ok(Template { pieces })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => write!(f, "{}", text.replace('{', "{{").replace('}', "}}"))?,
+                Piece::Field(field) => {
+                    let name = FIELDS.iter().find(|(_, f)| f == field).map(|(n, _)| *n).unwrap_or_default();
+                    write!(f, "{{{}}}", name)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_template() {
+    let metadata = Metadata {
+        name: "Verse/Chorus".to_string(),
+        tempo: 132.5,
+        signature: TimeSignature::from_str("7/8").unwrap(),
+        bars: 15,
+        ppqn: 96,
+        target: Profile::Reaper,
+    };
+    let template = Template::from_str("out/{name}_{tempo}bpm_{signature}.mid").unwrap();
+    assert_eq!(template.fill(&metadata), "out/Verse_Chorus_132.5bpm_7-8.mid");
+    assert_eq!(Template::from_str("{bars}x{ppqn}-{ target }.mid").unwrap().fill(&metadata), "15x96-reaper.mid");
+    assert_eq!(Template::from_str("{{literal}}.mid").unwrap().fill(&metadata), "{literal}.mid");
+    assert_eq!(Template::from_str(&template.to_string()), Ok(template));
+    assert!(Template::from_str("plain.mid").unwrap().is_plain());
+    assert_eq!(
+        Template::from_str("{bpm}.mid"),
+        Err("`{bpm}` is not a placeholder, expected one of: name, tempo, signature, bars, ppqn, target".to_string())
+    );
+    assert!(Template::from_str("{name.mid").is_err());
+    assert!(Template::from_str("name}.mid").is_err());
+}
@@ -0,0 +1,36 @@
+//! Exports of the WebAssembly build: `generate_midi(patternJson) -> Uint8Array` and
+//! `generate_events(patternJson) -> object`. `wasm-bindgen` writes the JavaScript that loads
+//! the module and passes the strings and bytes in and out of it. Errors are thrown as an
+//! `Error` with the reason.
+
+use std::str::FromStr;
+
+use wasm_bindgen::prelude::*;
+
+use crate::api::spec::{generate, generate_events as events, Spec};
+
+/// Writes the MIDI file of a spec given as JSON.
+#[wasm_bindgen]
+pub fn generate_midi(pattern_json: &str) -> Result<Vec<u8>, JsError> {
+    midi(pattern_json).map_err(|e| JsError::new(&e))
+}
+
+/// The notes of the MIDI file of a spec given as JSON, as an object in the schema of
+/// `midi::events::EventList`.
+#[wasm_bindgen]
+pub fn generate_events(pattern_json: &str) -> Result<JsValue, JsError> {
+    let json = Spec::from_str(pattern_json).and_then(|spec| events(&spec)).map_err(|e| JsError::new(&e))?;
+    js_sys::JSON::parse(&json).map_err(|_| JsError::new("the events aren't valid JSON"))
+}
+
+fn midi(pattern_json: &str) -> Result<Vec<u8>, String> {
+    Spec::from_str(pattern_json).and_then(|spec| generate(&spec))
+}
+
+#[test]
+fn test_generate_midi() {
+    // Errors become JavaScript values, which only exist in a WebAssembly build.
+    let bytes = generate_midi(r#"{"patterns": {"kick": "8x--x--", "snare": "4-x"}}"#).ok().unwrap();
+    assert!(bytes.starts_with(b"MThd"));
+    assert_eq!(midi("{}"), Err("no drum pattern was supplied".to_string()));
+}
@@ -1,20 +1,173 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, BufWriter, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use polyrhythmix::dsl::dsl;
-use polyrhythmix::midi::core::{create_smf, DrumPart};
+use polyrhythmix::analysis::check::{check, Problem};
+use polyrhythmix::analysis::clave::ClaveDirection;
+use polyrhythmix::analysis::pulses::PulseGrid;
+use polyrhythmix::api::config::Config;
+use polyrhythmix::api::json::Json;
+use polyrhythmix::api::spec::{self, description, Arrangement, Spec};
+use polyrhythmix::api::template::{Metadata, Template};
+use polyrhythmix::dsl::dsl::{self, BasicLength, Groups, KnownLength};
+use polyrhythmix::dsl::file::PatternFile;
+use polyrhythmix::dsl::onsets;
+use polyrhythmix::generator::evolve::{evolve, Settings, Target};
+use polyrhythmix::generator::displacement;
+use polyrhythmix::generator::hemiola::{meter_pulse, CrossRhythm};
+use polyrhythmix::generator::ladder::{Ladder, DEFAULT_RUNGS};
+use polyrhythmix::generator::markov::GrooveModel;
+use polyrhythmix::generator::ratio::Ratio;
+use polyrhythmix::generator::variation::vary;
+use polyrhythmix::live::{link, midi_clock};
+use polyrhythmix::live::playback::Loop;
+use polyrhythmix::live::scheduler::{run, Clock, Player, Update};
+use polyrhythmix::live::mute::Mutes;
+use polyrhythmix::live::tempo::TempoControl;
+use polyrhythmix::midi::alignment::Alignment;
+use polyrhythmix::midi::compare::compare;
+use polyrhythmix::midi::core::{
+    bar_length, insert_at, DrumPart, Limits, OnLimit, Overlap, Part, Progress, Tick,
+};
+use polyrhythmix::midi::dump::dump_events;
+use polyrhythmix::midi::accents::{Accents, Every};
+use polyrhythmix::dsl::pitch::{Degree, Key};
+use polyrhythmix::error::Error;
+use polyrhythmix::midi::bassline::BassLine;
+use polyrhythmix::midi::dynamics::Dynamics;
+use polyrhythmix::midi::echo::Echo;
+use polyrhythmix::midi::grid::{Grid, Grids};
+use polyrhythmix::midi::groove::{Groove, Swing};
+use polyrhythmix::midi::humanize::Humanize;
+use polyrhythmix::midi::import::{self, import_channel, import_drums};
+use polyrhythmix::midi::dawproject::DawProject;
+use polyrhythmix::midi::events::EventList;
+use polyrhythmix::midi::keymap::KeyMap;
+use polyrhythmix::midi::guitar_pro;
+use polyrhythmix::midi::musicxml;
+use polyrhythmix::midi::merge::{merge, Position, SongMap};
+use polyrhythmix::midi::mixer::Mix;
+use polyrhythmix::midi::names::{TrackName, TrackNames};
+use polyrhythmix::midi::optimize;
+use polyrhythmix::midi::profile::Profile;
+use polyrhythmix::midi::registry::{CustomPart, Layer, PartRegistry, PartVelocity};
+use polyrhythmix::midi::smpte::FileTiming;
+use polyrhythmix::midi::stream;
+use polyrhythmix::midi::tempo::Modulation;
 use polyrhythmix::midi::time::TimeSignature;
+use polyrhythmix::midi::velocity::{Curve, NoteOffs, Release, Response};
+use polyrhythmix::random::Rng;
+use polyrhythmix::tui::audition::Audition;
+use polyrhythmix::tui::editor::{Action, Editor};
+use polyrhythmix::tui::terminal::Terminal;
 
 use clap::*;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+use tracing::Level;
 use DrumPart::*;
 
+/// Set when the MIDI file is written to stdout, which leaves stderr for the messages.
+static PIPED: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--json`, which writes the messages as JSON objects on stderr, a line each.
+static JSON: AtomicBool = AtomicBool::new(false);
+
+/// Prints a message for the user, to stderr if stdout is taken by the MIDI file.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if JSON.load(Ordering::Relaxed) {
+            report("info", &format!($($arg)*), Vec::new())
+        } else if PIPED.load(Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+/// Prints a warning, the file is written anyway.
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        if JSON.load(Ordering::Relaxed) {
+            report("warning", &format!($($arg)*), Vec::new())
+        } else {
+            say!("Warning: {}", format!($($arg)*))
+        }
+    };
+}
+
+/// Prints an error and exits.
+macro_rules! fail {
+    ($($arg:tt)*) => {{
+        if JSON.load(Ordering::Relaxed) {
+            report("error", &format!($($arg)*), Vec::new())
+        } else {
+            say!($($arg)*)
+        }
+        exit(1)
+    }};
+}
+
+/// Writes a message of `level` with the `fields` about it as a JSON object on stderr, like
+/// `{"level":"warning","message":"..."}`.
+fn report(level: &str, message: &str, fields: Vec<(String, Json)>) {
+    let mut object: BTreeMap<String, Json> = fields.into_iter().collect();
+    object.insert("level".to_string(), Json::String(level.to_string()));
+    object.insert("message".to_string(), Json::String(message.to_string()));
+    eprintln!("{}", Json::Object(object));
+}
+
+/// Prints the problems of a pattern as `error: ...` or `warning: ...`, with the line and the
+/// column of each of them with `--json`, along with the `fields` that tell where it's from.
+fn problem(level: &str, error: &Error, fields: &[(&str, &str)]) {
+    if !JSON.load(Ordering::Relaxed) {
+        return say!("{}: {}", level, error);
+    }
+    let Json::Array(problems) = error.to_json() else {
+        return;
+    };
+    for problem in problems {
+        let Json::Object(mut problem) = problem else {
+            continue;
+        };
+        let message = match problem.remove("message") {
+            Some(Json::String(message)) => message,
+            _ => error.to_string(),
+        };
+        let context = fields.iter().map(|(k, v)| (k.to_string(), Json::String(v.to_string())));
+        report(level, &message, problem.into_iter().chain(context).collect());
+    }
+}
+
+/// Prints what was written as a JSON object with `--json`, on stdout unless the MIDI file is.
+fn summarize(summary: Vec<(&str, Json)>) {
+    if !JSON.load(Ordering::Relaxed) {
+        return;
+    }
+    let summary = Json::Object(summary.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+    match PIPED.load(Ordering::Relaxed) {
+        true => eprintln!("{}", summary),
+        false => println!("{}", summary),
+    }
+}
+
 #[derive(Debug, Parser, Clone)]
 #[command(name = "Polyrhythmix")]
 #[command(author = "Denis Redozubov <denis.redozubov@gmail.com>")]
 #[command(version = "0.1.0")]
 #[command(about = "Polyrhythmically-inclinded Midi Drum generator", long_about = None)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     #[arg(short = 'K', long = "kick", default_value = None, help = "Kick drum pattern")]
     kick: Option<String>,
 
@@ -27,131 +180,2682 @@ struct Cli {
     #[arg(short = 'C', long = "crash", default_value = None, help = "Crash cymbal pattern")]
     crash: Option<String>,
 
-    #[arg(short = 't', long = "tempo", default_value = "120", help = "Tempo value")]
-    tempo: u16,
+    #[arg(long = "cowbell", default_value = None, help = "Cowbell pattern")]
+    cowbell: Option<String>,
+
+    #[arg(long = "claves", default_value = None, help = "Claves pattern")]
+    claves: Option<String>,
+
+    #[arg(long = "conga-open", default_value = None, help = "Open conga pattern")]
+    conga_open: Option<String>,
+
+    #[arg(long = "conga-slap", default_value = None, help = "Slapped conga pattern")]
+    conga_slap: Option<String>,
+
+    #[arg(long = "conga-mute", default_value = None, help = "Muted conga pattern")]
+    conga_mute: Option<String>,
+
+    #[arg(long = "bongo-high", default_value = None, help = "High bongo pattern")]
+    bongo_high: Option<String>,
+
+    #[arg(long = "bongo-low", default_value = None, help = "Low bongo pattern")]
+    bongo_low: Option<String>,
+
+    #[arg(long = "shaker", default_value = None, help = "Shaker pattern")]
+    shaker: Option<String>,
+
+    #[arg(long = "tambourine", default_value = None, help = "Tambourine pattern")]
+    tambourine: Option<String>,
+
+    #[arg(long = "pattern", help = "Pattern of any part, including the ones declared with --custom-part, like cajon=8x-x")]
+    patterns: Vec<String>,
+
+    #[arg(long = "implied-subdivision", help = "Step of patterns written as bare onsets like x..x..x., a hit or a rest a step each, e.g. 16 or 8t")]
+    implied_subdivision: Option<Grid>,
+
+    #[command(flatten)]
+    output: OutputArgs,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum Command {
+    #[command(about = "Generate a canonical polyrhythm from a ratio like 3:4 or 3:4:5")]
+    Ratio {
+        #[arg(help = "Polyrhythm ratio, e.g. 3:4")]
+        ratio: Ratio,
+
+        #[arg(long = "subdivision", default_value = "8", help = "Note length of the shared pulse grid")]
+        subdivision: u16,
+
+        #[arg(long = "parts", value_delimiter = ',', default_value = None, help = "Drum parts for every voice of the ratio, e.g. kick,snare")]
+        parts: Vec<String>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Write a file of every polyrhythm of two co-prime voices up to a number of hits, like 2:3, 3:4 and 2:5")]
+    Family {
+        #[arg(long = "max", default_value = "9", value_parser = clap::value_parser!(u16).range(3..=32), help = "Most hits a voice plays over a cycle, from 3 to 32")]
+        max: u16,
+
+        #[arg(long = "subdivision", default_value = "8", help = "Note length of the shared pulse grid")]
+        subdivision: u16,
+
+        #[arg(long = "parts", value_delimiter = ',', default_value = None, help = "Drum parts of the slower and the faster voice, kick,snare if omitted")]
+        parts: Vec<String>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Group notes of the time signature in a cross-rhythm and report where it resolves")]
+    Hemiola {
+        #[arg(short = 'g', long = "grouping", value_parser = clap::value_parser!(u16).range(1..), help = "Number of notes in a grouping, e.g. 3")]
+        grouping: u16,
+
+        #[arg(long = "subdivision", default_value = "16", help = "Note length of the grouped notes")]
+        subdivision: u16,
+
+        #[arg(long = "part", default_value = "hihat", help = "Drum part playing the cross-rhythm")]
+        part: String,
+
+        #[arg(long = "pulse", default_value = None, help = "Drum part playing every beat of the time signature")]
+        pulse: Option<String>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Step through subdivisions over a steady pulse, a few bars of quarters, then eighths, triplets and so on")]
+    Ladder {
+        #[arg(long = "rungs", value_delimiter = ',', default_value = DEFAULT_RUNGS, help = "Subdivisions in the order they're played, like 8, 8t or 16:5 for quintuplets")]
+        rungs: Vec<Grid>,
+
+        #[arg(long = "bars", default_value = "2", value_parser = clap::value_parser!(u16).range(1..), help = "Bars of every subdivision")]
+        bars: u16,
+
+        #[arg(long = "part", default_value = "snare", help = "Drum part playing the subdivisions")]
+        part: String,
+
+        #[arg(long = "pulse", default_value = "kick", help = "Drum part playing every beat of the time signature")]
+        pulse: String,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Show where the pulses of a polyrhythm like 3 4 5 land on their common grid")]
+    Align {
+        #[arg(required = true, num_args = 2.., help = "Number of evenly spaced pulses of every stream over a cycle, e.g. 3 4 5")]
+        voices: Vec<u16>,
+
+        #[arg(long = "subdivision", default_value = "16", help = "Note length of a step of the common grid")]
+        subdivision: u16,
+
+        #[arg(long = "width", default_value = "64", help = "Steps to a line before the grid wraps")]
+        width: usize,
+
+        #[arg(long = "animate", help = "Step through the cycle in time in the terminal, showing the streams landing on every step")]
+        animate: bool,
+
+        #[arg(short = 't', long = "tempo", default_value = "120", help = "Tempo in BPM of --animate")]
+        tempo: f64,
+    },
+    #[command(about = "Generate a variation of a pattern file by adding, removing or displacing hits")]
+    Vary {
+        #[arg(help = "Pattern file, one `part = pattern` per line, - reads it from stdin")]
+        input: String,
+
+        #[arg(long = "amount", default_value = "0.2", help = "Probability of every note being changed, from 0 to 1")]
+        amount: f64,
+
+        #[arg(long = "seed", default_value = None, help = "Seed of the variation, random if omitted")]
+        seed: Option<u64>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Play a pattern file over and over, a step later every time, with a marker naming every displacement")]
+    Displace {
+        #[arg(help = "Pattern file, one `part = pattern` per line, - reads it from stdin")]
+        input: String,
+
+        #[arg(long = "subdivision", default_value = "16", help = "Step the groove is displaced by, like 16, 8t or 16:5")]
+        subdivision: Grid,
+
+        #[arg(long = "repeats", default_value = "1", value_parser = clap::value_parser!(u16).range(1..), help = "Times every displacement is played")]
+        repeats: u16,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Learn the style of drum MIDI files in a folder and generate new patterns in it")]
+    Learn {
+        #[arg(help = "Folder with .mid files to learn from")]
+        input: String,
+
+        #[arg(long = "bars", default_value = "2", help = "Number of bars to generate")]
+        bars: u32,
+
+        #[arg(long = "order", default_value = "4", help = "Number of previous 16th notes the next one depends on")]
+        order: usize,
+
+        #[arg(long = "seed", default_value = None, help = "Seed of the generator, random if omitted")]
+        seed: Option<u64>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Print the notes of a MIDI file as a table of bar:beat:tick, part, on/off and velocity")]
+    Dump {
+        #[arg(help = "MIDI file, - reads it from stdin")]
+        input: String,
+
+        #[arg(long = "custom-part", help = "Declare a part of your own to name its notes, like \"Cajon key=48 channel=10\"")]
+        custom_parts: Vec<CustomPart>,
+
+        #[arg(long = "bass-track", default_value = "Bass", help = "Name of the track of the bass, its notes are named bass")]
+        bass_track: String,
+
+        #[arg(long = "layer", help = "Name the notes of a layer of a part after it, like \"kick key=35 channel=2\"")]
+        layers: Vec<String>,
+    },
+    #[command(about = "Compare the events of two MIDI files, exiting with 1 if they play differently")]
+    Cmp {
+        #[arg(help = "MIDI file to compare against, like a golden file of a test, - reads it from stdin")]
+        expected: String,
+
+        #[arg(help = "MIDI file to compare, - reads it from stdin")]
+        actual: String,
+    },
+    #[command(about = "Play a pattern file in a loop to a MIDI device")]
+    Play {
+        #[arg(help = "Pattern file, one `part = pattern` per line, - reads it from stdin")]
+        input: String,
+
+        #[arg(short = 'd', long = "device", help = "Raw MIDI device to play to, like /dev/snd/midiC1D0, - writes the messages to stdout")]
+        device: String,
+
+        #[arg(long = "rounds", default_value = None, help = "Number of times to play the loop, until interrupted if omitted")]
+        rounds: Option<u32>,
+
+        #[arg(long = "link", num_args = 0..=1, default_missing_value = link::CARABINER, help = "Follow the tempo and phase of an Ableton Link session through Carabiner, at 127.0.0.1:17000 unless an address is given")]
+        link: Option<String>,
+
+        #[arg(long = "send-clock", help = "Send MIDI clock, start, stop and song position, for drum machines and other devices to follow")]
+        send_clock: bool,
+
+        #[arg(long = "clock-in", conflicts_with_all = ["link", "send_clock"], help = "Follow the MIDI clock, start, stop and song position a DAW or sequencer sends to this raw MIDI input")]
+        clock_in: Option<String>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Edit a pattern file on a grid of steps in the terminal, toggling hits and playing it to a MIDI device")]
+    Edit {
+        #[arg(help = "Pattern file to edit, written back in steps of the grid, created if it doesn't exist")]
+        input: String,
+
+        #[arg(long = "subdivision", default_value = "16", help = "Length of a step to start with, like 16, 8t or 16:5")]
+        subdivision: Grid,
+
+        #[arg(long = "parts", value_delimiter = ',', default_value = "kick,snare,hihat", help = "Parts with a row of their own even if the file has no pattern for them")]
+        parts: Vec<String>,
+
+        #[arg(short = 'd', long = "device", default_value = None, help = "Raw MIDI device to play the patterns to, like /dev/snd/midiC1D0")]
+        device: Option<String>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Add drums to an existing MIDI file, following its tempo and time signatures")]
+    Merge {
+        #[arg(help = "Pattern file, one `part = pattern` per line, - reads it from stdin")]
+        input: String,
+
+        #[arg(long = "into", help = "MIDI file to add the drums to")]
+        into: String,
+
+        #[arg(long = "at", default_value = "1", help = "Where bar 1 of the drums goes: a bar of the song like 5, or seconds into it like 12.5s")]
+        at: Position,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Turn the drums of a MIDI file into patterns and a clean drum track")]
+    Extract {
+        #[arg(help = "MIDI file to take the drums of")]
+        input: String,
+
+        #[arg(long = "channel", default_value = "10", help = "Channel of the drums, from 1 to 16, 0 takes every channel")]
+        channel: u8,
+
+        #[arg(long = "subdivision", value_delimiter = ',', default_value = "16", help = "Grid the hits are snapped to, like 16, 8t or 16:5 for quintuplets, for every part or for a single one with part=grid")]
+        subdivision: Vec<String>,
+
+        #[arg(long = "quantize", help = "Move the hits towards the grid first by this many percent of the way, reporting the ones that move")]
+        quantize: Option<u8>,
+
+        #[arg(long = "quantized", requires = "quantize", help = "Write the file with the quantized hits to this path")]
+        quantized: Option<String>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Evolve patterns towards a target density, syncopation and convergence length")]
+    Evolve {
+        #[arg(long = "parts", value_delimiter = ',', default_value = "kick,snare,hihat", help = "Drum parts to evolve patterns for")]
+        parts: Vec<String>,
+
+        #[arg(long = "density", default_value = "0.4", help = "Target share of hits among all notes, from 0 to 1")]
+        density: f64,
+
+        #[arg(long = "min-syncopation", default_value = "0.2", help = "Lowest acceptable share of hits off the beat")]
+        min_syncopation: f64,
+
+        #[arg(long = "max-syncopation", default_value = "0.6", help = "Highest acceptable share of hits off the beat")]
+        max_syncopation: f64,
+
+        #[arg(long = "bars", default_value = "4", help = "Target number of bars for the parts to converge")]
+        bars: u32,
+
+        #[arg(long = "subdivision", default_value = "16", help = "Note length of the evolved patterns")]
+        subdivision: u16,
+
+        #[arg(long = "population", default_value = "64", help = "Number of candidates in every generation")]
+        population: usize,
+
+        #[arg(long = "generations", default_value = "200", help = "Number of generations to evolve")]
+        generations: usize,
+
+        #[arg(long = "top", default_value = "3", help = "Number of best candidates to print")]
+        top: usize,
+
+        #[arg(long = "seed", default_value = None, help = "Seed of the evolution, random if omitted")]
+        seed: Option<u64>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+    #[command(about = "Check pattern files without writing anything, exiting with 1 if any of them can't be written")]
+    Check {
+        #[arg(required = true, help = "Pattern files, one `part = pattern` per line, - reads one from stdin")]
+        inputs: Vec<String>,
+
+        #[arg(short = 's', long = "time-signature", default_value = "4/4", help = "Time signature")]
+        time_signature: String,
+
+        #[arg(long = "align", default_value = "warn", value_parser = clap::builder::TypedValueParser::map(clap::builder::PossibleValuesParser::new(["strict", "warn", "pad", "cycle"]), |s| s.parse::<Alignment>().unwrap()), help = "What to do with patterns that don't fill whole bars: strict fails, warn reports them, pad and cycle let them be")]
+        alignment: Alignment,
+
+        #[arg(long = "max-bars", default_value = "1000", help = "Most bars the patterns may take to converge")]
+        max_bars: u32,
+
+        #[arg(long = "max-events", help = "Most note on and off events of the drums the patterns may take to converge")]
+        max_events: Option<usize>,
+
+        #[arg(long = "custom-part", help = "Declare a part of your own, like \"Cajon key=48 channel=10 velocity=100\"")]
+        custom_parts: Vec<CustomPart>,
+
+        #[arg(long = "json", help = "Write the problems as JSON objects on stderr and a summary as JSON on stdout")]
+        json: bool,
+    },
+}
+
+#[derive(Debug, Args, Clone)]
+struct OutputArgs {
+    #[arg(short = 't', long = "tempo", default_value = "120", help = "Tempo in BPM, like 120 or 132.5")]
+    tempo: f64,
+
+    #[arg(long = "modulate", help = "Metric modulation like 8.=4@5, from bar 5 on a quarter note is as long as a dotted eighth note was, changing the tempo to match")]
+    modulate: Vec<String>,
 
     #[arg(short = 's', long = "time-signature", default_value = "4/4", help = "Time signature")]
     time_signature: String,
 
-    #[arg(short = 'o', long = "output-file", default_value = None, help = "Output file path, make a dry run if omitted")]
+    #[arg(short = 'o', long = "output-file", default_value = None, help = "Output file path like {name}_{tempo}bpm_{signature}.mid, - writes to stdout, make a dry run if omitted")]
     output: Option<String>,
 
+    #[arg(long = "output-dir", help = "Directory the output file, the key map and the files of --split-parts go to when their paths are relative")]
+    output_dir: Option<String>,
+
     #[clap(short = 'B', long = "follow-kick-drum-with-bass", help = "Generate a second MIDI track for the bass following the kick drum")]
     follow_kick_drum_with_bass: bool,
-}
 
-fn part_to_string(part: DrumPart) -> String {
-    match part {
-        KickDrum => String::from("Kick Drum"),
-        SnareDrum => String::from("Snare Drum"),
-        HiHat => String::from("Hi-Hat"),
-        CrashCymbal => String::from("Crash Cymbal"),
-    }
+    #[arg(long = "bass-key", requires = "follow_kick_drum_with_bass", help = "Key of the bass like E, F#2 or A1m for A minor in octave 1, E1 if omitted")]
+    bass_key: Option<Key>,
+
+    #[arg(long = "bass-line", value_delimiter = ',', requires = "follow_kick_drum_with_bass", help = "Degrees of the key the bass plays on the hits of the kick drum in turn, like 1,5,b7,8, the root if omitted")]
+    bass_line: Vec<Degree>,
+
+    #[arg(long = "key", help = "Key the degrees of pitched hits like x[b3] are in, like C4 or A3m for A minor, C4 if omitted")]
+    key: Option<Key>,
+
+    #[arg(long = "align", value_parser = clap::builder::TypedValueParser::map(clap::builder::PossibleValuesParser::new(["strict", "warn", "pad", "cycle"]), |s| s.parse::<Alignment>().unwrap()), help = "What to do with patterns that don't fill whole bars: strict fails, warn reports them, pad fills them with rests up to the next bar line, cycle repeats them as they are")]
+    alignment: Option<Alignment>,
+
+    #[arg(long = "clave", help = "Direction of the clave, 3-2 or 2-3, warning about parts of two bars that lean to the other one")]
+    clave: Option<ClaveDirection>,
+
+    #[arg(long = "ppqn", help = "MIDI resolution in ticks per quarter note, the lowest one that fits every note if omitted")]
+    ppqn: Option<u16>,
+
+    #[arg(long = "groove", help = "Groove template file or reference .mid file to learn one from, applied to every part or to a single one with part=file")]
+    grooves: Vec<String>,
+
+    #[arg(long = "swing", help = "Swing of every part like 60, or of one with hihat=66, from 50 for straight to 75, on sixteenths or on a grid like hihat=60:8")]
+    swing: Vec<String>,
+
+    #[arg(long = "dynamics", help = "Crescendo or decrescendo like pp-ff, of a single part with hihat=pp-ff, over bars 1 to 4 with pp-ff@1-4")]
+    dynamics: Vec<String>,
+
+    #[arg(long = "accent-downbeats", value_parser = clap::value_parser!(u8).range(0..=127), help = "Velocity added to the hits of every part on the first beat of a bar, from 0 to 127")]
+    accent_downbeats: Option<u8>,
+
+    #[arg(long = "accent-strong-beats", requires = "accent_downbeats", help = "Also accent the other strong beats of the meter, like beat 3 of 4/4 or beat 4 of 6/8, by half as much")]
+    accent_strong_beats: bool,
+
+    #[arg(long = "accent-every", help = "Accent every Nth hit of every part, or of one with hihat=3, by 20 or by a velocity like hihat=3:30")]
+    accent_every: Vec<String>,
+
+    #[arg(long = "echo", help = "Echo the hits of every part, or of one with snare=8.,3, by a note length a number of times, each keeping 50 or a percentage like snare=8.,3,60 of the velocity")]
+    echoes: Vec<String>,
+
+    #[arg(long = "humanize-timing", help = "Play the hits of the drums up to this many milliseconds early or late, like 8")]
+    humanize_timing: Option<f64>,
+
+    #[arg(long = "humanize-velocity", value_parser = clap::value_parser!(u8).range(0..=127), help = "Vary the velocities of the hits of the drums by up to this much up or down, like 10, on the grid unless --humanize-timing is given too")]
+    humanize_velocity: Option<u8>,
+
+    #[arg(long = "humanize-seed", help = "Seed of the humanization, random if omitted")]
+    humanize_seed: Option<u64>,
+
+    #[arg(long = "mix", help = "Volume and pan of the drum kit like 100,-20, of the channel of a part with hihat=100,-20 or of the bass with bass=100,-20")]
+    mix: Vec<String>,
+
+    #[arg(long = "velocity-curve", help = "Velocity response of every part or of one with hihat=soft: linear, soft, hard, a fixed velocity like 100 or breakpoints like 1:1,64:100,127:127")]
+    velocity_curves: Vec<String>,
+
+    #[arg(long = "note-off-velocity", value_delimiter = ',', help = "Velocity of note offs from 0 to 127, 127 by default, for every part like 64 or for one with hihat=0")]
+    note_off_velocities: Vec<String>,
+
+    #[arg(long = "zero-velocity-note-offs", conflicts_with = "note_off_velocities", help = "End notes with note ons of velocity 0 rather than note offs")]
+    zero_velocity_note_offs: bool,
+
+    #[arg(long = "optimize-size", conflicts_with = "note_off_velocities", help = "Make the file smaller without changing how it plays, note offs lose their velocities")]
+    optimize_size: bool,
+
+    #[arg(long = "timing", default_value = "metrical", help = "Timing of the file: metrical, in ticks of quarter notes, or smpte:fps:ticks-per-frame like smpte:25:40 for absolute time")]
+    timing: FileTiming,
+
+    #[arg(long = "high-resolution-velocity", help = "Experimental: write velocities of 14 bits, the low 7 bits in a high resolution velocity prefix (CC88) before every note on")]
+    high_resolution_velocity: bool,
+
+    #[arg(long = "key-map", help = "Write the keys and channels of the parts, the resolution and the length of the file to a JSON file, - writes it to stdout")]
+    key_map: Option<String>,
+
+    #[arg(long = "musicxml", help = "Write the drums as a MusicXML score with the durations, tuplets and articulations of the patterns, for Guitar Pro and notation programs, - writes it to stdout")]
+    musicxml: Option<String>,
+
+    #[arg(long = "gp", help = "Write the drums as a Guitar Pro 7 file with the durations, tuplets and articulations of the patterns, - writes it to stdout")]
+    gp: Option<String>,
+
+    #[arg(long = "dawproject", help = "Write the tracks as looped clips of a DAWproject for Bitwig Studio, Studio One and Cubase, with the tempo, time signature and track names, - writes it to stdout")]
+    dawproject: Option<String>,
+
+    #[arg(long = "events-json", help = "Write every note with its tick, part, velocity and duration to a JSON file for sequencers in the browser, - writes it to stdout")]
+    events_json: Option<String>,
+
+    #[arg(long = "track-name", help = "Name of the drum track like Verse, or of the track of a part with bass=Walking, parts go by their own names with --split-parts")]
+    track_names: Vec<String>,
+
+    #[arg(long = "mute", value_delimiter = ',', help = "Parts to leave out of the file, like kick,bass, it's as long as it is with them")]
+    mute: Vec<String>,
+
+    #[arg(long = "solo", value_delimiter = ',', help = "Parts to keep in the file, the rest are muted")]
+    solo: Vec<String>,
+
+    #[arg(long = "target", default_value = "generic-gm", help = "Application the file is written for: guitar-pro, logic, ableton, reaper or generic-gm")]
+    target: Profile,
+
+    #[arg(long = "deterministic", help = "Produce the same bytes for the same arguments, seeds of random generators default to 0")]
+    deterministic: bool,
+
+    #[arg(long = "custom-part", help = "Declare a part of your own, like \"Cajon key=48 channel=10 velocity=100\"")]
+    custom_parts: Vec<CustomPart>,
+
+    #[arg(long = "layer", help = "Play another key along with a part, like \"kick key=35 channel=2 velocity=90\", on the channel of the part if it's left out")]
+    layers: Vec<String>,
+
+    #[arg(long = "part-velocity", value_delimiter = ',', help = "Velocity of plain hits of a part and the range its velocities are kept in, like snare=45:30-60, kick=100-120 or hihat=90")]
+    part_velocity: Vec<String>,
+
+    #[arg(long = "split-parts", help = "Write a file per part next to the output file, like out.kick.mid and out.snare.mid")]
+    split_parts: bool,
+
+    #[arg(long = "print-events", help = "Print every note as a table of bar:beat:tick, part, on/off and velocity")]
+    print_events: bool,
+
+    #[arg(long = "overlap", default_value = "truncate-previous", help = "What to do with a part hit again before its previous note ends: truncate-previous, merge or allow")]
+    overlap: Overlap,
+
+    #[arg(long = "max-bars", default_value = "1000", help = "Longest file to write in bars, for patterns that take long to converge")]
+    max_bars: u32,
+
+    #[arg(long = "max-events", help = "Most note on and off events of the drums to write")]
+    max_events: Option<usize>,
+
+    #[arg(long = "on-limit", default_value = "truncate", help = "What to do with patterns running past --max-bars or --max-events: truncate or fail")]
+    on_limit: OnLimit,
+
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count, help = "Log what is going on to stderr, repeat for more details like -vv")]
+    verbose: u8,
+
+    #[arg(long = "reference-pulse", help = "Drum part playing every beat of the time signature quietly, like hihat, to keep the meter of sparse polyrhythms")]
+    reference_pulse: Option<String>,
+
+    #[arg(long = "reference-pulse-velocity", default_value = "40", value_parser = clap::value_parser!(u8).range(1..=127), help = "Velocity of the hits of --reference-pulse, from 1 to 127")]
+    reference_pulse_velocity: u8,
+
+    #[arg(long = "allow-silence", help = "Write a file of silence as long as the bars when every part only rests or is muted, rather than failing")]
+    allow_silence: bool,
+
+    #[arg(long = "dry-run", help = "Go through everything but writing files, then print the bars, the note events and how long the file plays")]
+    dry_run: bool,
+
+    #[arg(long = "json", help = "Write messages, warnings and errors as JSON objects on stderr and a summary of the file as JSON on stdout")]
+    json: bool,
+
+    /// File the patterns are read from, which `{name}` of the output path goes by.
+    #[arg(skip)]
+    source: Option<String>,
 }
 
 fn validate_and_parse_part(
     cli: Option<String>,
     part: DrumPart,
-    patterns: &mut BTreeMap<DrumPart, dsl::Groups>,
-) -> () {
+    registry: &PartRegistry,
+    patterns: &mut BTreeMap<DrumPart, dsl::Pattern>,
+) {
     match cli {
         None => {}
-        Some(pattern) => match dsl::groups(pattern.as_str()) {
-            Ok((_, groups)) => {
-                patterns.insert(part, groups);
+        Some(pattern) => match dsl::parse_pattern(pattern.as_str()) {
+            Ok(parsed) => {
+                patterns.insert(part, parsed);
             },
-            Err(_) => {
-                panic!("{} pattern is malformed.", part_to_string(part))
+            Err(e) if JSON.load(Ordering::Relaxed) => {
+                problem("error", &e, &[("part", &registry.name(part))]);
+                exit(1)
+            }
+            Err(e) if onsets::is_onsets(&pattern) => {
+                fail!("{} pattern is malformed.\n{}\nIt reads as bare onsets, which take a step like --implied-subdivision 16", registry.title(part), e)
+            }
+            Err(e) => {
+                fail!("{} pattern is malformed.\n{}", registry.title(part), e)
             }
         },
     }
 }
 
-fn create_text_description(
-    kick: &Option<String>,
-    snare: &Option<String>,
-    hihat: &Option<String>,
-    crash: &Option<String>,
-) -> String {
-    let mut parts: String = "".to_string();
-    if kick.is_some() {
-        parts.push_str(&format!("\nKick Drum - {}", kick.clone().unwrap()));
+/// Adds the parts declared with `--part` to the ones of `registry`.
+fn declare_parts(mut registry: PartRegistry, parts: &[CustomPart]) -> PartRegistry {
+    for part in parts {
+        if let Err(e) = registry.declare(part.clone()) {
+            fail!("{}, exiting...", e)
+        }
+    }
+    registry
+}
+
+fn add_layers(registry: &mut PartRegistry, layers: &[String]) {
+    for layer in layers {
+        match Layer::parse(layer, registry) {
+            Ok(layer) => registry.layer(layer),
+            Err(e) => {
+                fail!("{}, exiting...", e)
+            }
+        }
     }
-    if snare.is_some() {
-        parts.push_str(&format!("\nSnare Drum - {}", snare.clone().unwrap()));
+}
+
+fn set_velocities(registry: &mut PartRegistry, velocities: &[String]) {
+    for velocity in velocities {
+        match PartVelocity::parse(velocity, registry) {
+            Ok(velocity) => registry.set_velocity(velocity),
+            Err(e) => {
+                fail!("{}, exiting...", e)
+            }
+        }
     }
-    if hihat.is_some() {
-        parts.push_str(&format!("\nHi-Hat - {}", hihat.clone().unwrap()));
+}
+
+fn resolve_part(name: &str, registry: &PartRegistry) -> DrumPart {
+    match registry.part(name) {
+        Ok(part) => part,
+        Err(e) => {
+            fail!("{}", e)
+        }
     }
-    if crash.is_some() {
-        parts.push_str(&format!("\nCrash Cymbal - {}", crash.clone().unwrap()));
+}
+
+/// A drum part or the bass by its name.
+fn resolve_any_part(name: &str, registry: &PartRegistry) -> Part {
+    match name {
+        "bass" => Part::Bass,
+        name => Part::Drum(resolve_part(name, registry)),
     }
-    format!("{}{}", "Created using Poly. Part blueprints:", parts)
 }
 
-fn main() {
-    let matches = Cli::parse();
-    match matches {
-        Cli {
-            kick,
-            snare,
-            hihat,
-            crash,
-            tempo,
-            time_signature,
-            output,
-            follow_kick_drum_with_bass,
-        } => {
-            if kick == None && snare == None && hihat == None && crash == None {
-                println!("No drum pattern was supplied, exiting...");
-                exit(1)
-            } else {
-                let signature = match TimeSignature::from_str(&time_signature) {
-                    Err(e) => panic!("Can't parse the time signature: {}", e),
-                    Ok(x) => x,
-                };
-                let text_description = create_text_description(&kick, &snare, &hihat, &crash);
-
-                let mut groups = BTreeMap::new();
-                validate_and_parse_part(kick, KickDrum, &mut groups);
-                validate_and_parse_part(snare, SnareDrum, &mut groups);
-                validate_and_parse_part(hihat, HiHat, &mut groups);
-                validate_and_parse_part(crash, CrashCymbal, &mut groups);
-
-                let output_file = output.clone();
-
-                match output_file {
-                    None => {
-                        println!("No output file path was supplied, running a dry run...");
-                        create_smf(
-                            groups,
-                            signature,
-                            text_description.as_str(),
-                            tempo,
-                            matches.follow_kick_drum_with_bass,
-                        )
-                    }
-                    Some(path) => {
-                        match create_smf(
-                            groups,
-                            signature,
-                            text_description.as_str(),
-                            tempo,
-                            follow_kick_drum_with_bass,
-                        )
-                        .save(path.clone())
-                        {
-                            Ok(_) => {
-                                println!("{} was written successfully", path);
-                                exit(0)
-                            }
-                            Err(e) => {
-                                println!("Failed to write {}: {}", path, e);
-                                exit(1)
-                            }
-                        };
-                    }
-                };
+/// Parts of `parts` and the bass muted by `--mute` and `--solo`.
+fn muted_parts(output_args: &OutputArgs, parts: &[DrumPart], registry: &PartRegistry) -> BTreeSet<Part> {
+    let mut muted: BTreeSet<Part> = output_args.mute.iter().map(|name| resolve_any_part(name, registry)).collect();
+    if !output_args.solo.is_empty() {
+        let solo: BTreeSet<Part> = output_args.solo.iter().map(|name| resolve_any_part(name, registry)).collect();
+        let all = parts.iter().map(|part| Part::Drum(*part)).chain(output_args.follow_kick_drum_with_bass.then_some(Part::Bass));
+        muted.extend(all.filter(|part| !solo.contains(part)));
+    }
+    muted
+}
+
+fn parse_subdivision(subdivision: u16) -> BasicLength {
+    match BasicLength::from_num(subdivision) {
+        Err(e) => fail!("Can't parse the subdivision: {}, exiting...", e),
+        Ok(x) => x,
+    }
+}
+
+/// Grids given as `grid` or `part=grid`. Parts without a grid of their own are on sixteenths,
+/// unless a grid is given for every part.
+fn parse_grids(args: &[String], registry: &PartRegistry) -> Grids {
+    let mut grids = Grids::from(Grid::from(BasicLength::Sixteenth));
+    for arg in args {
+        let (part, grid) = match arg.split_once('=') {
+            Some((part, grid)) => (Some(resolve_part(part, registry)), grid),
+            None => (None, arg.as_str()),
+        };
+        let grid = match Grid::from_str(grid) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}, exiting...", e)
+            }
+        };
+        match part {
+            Some(part) => {
+                grids.parts.insert(part, grid);
+            }
+            None => grids.every = grid,
+        }
+    }
+    grids
+}
+
+fn parse_time_signature(time_signature: &str) -> TimeSignature {
+    match TimeSignature::from_str(time_signature) {
+        Err(e) => fail!("Can't parse the time signature: {}, exiting...", e),
+        Ok(x) => x,
+    }
+}
+
+fn hemiola_patterns(
+    cross_rhythm: CrossRhythm,
+    part: DrumPart,
+    pulse: Option<DrumPart>,
+    time_signature: TimeSignature,
+    registry: &PartRegistry,
+) -> BTreeMap<DrumPart, String> {
+    let grouping = match cross_rhythm.to_group() {
+        Ok(group) => group.to_string(),
+        Err(e) => fail!("{}, exiting...", e),
+    };
+    match cross_rhythm.resolution(time_signature) {
+        Ok(resolution) => say!(
+            "Grouping {} resolves after {} groupings over {} bar(s)",
+            grouping, resolution.groupings, resolution.bars
+        ),
+        Err(e) => say!("Grouping {} doesn't resolve: {}", grouping, e),
+    }
+    let mut patterns = BTreeMap::from_iter([(part, grouping)]);
+    if let Some(pulse_part) = pulse {
+        if patterns.insert(pulse_part, meter_pulse(time_signature).to_string()).is_some() {
+            fail!("{} can't play both the grouping and the pulse, exiting...", registry.title(part))
+        }
+    }
+    patterns
+}
+
+fn ladder_patterns(
+    ladder: Ladder,
+    part: DrumPart,
+    pulse: DrumPart,
+    time_signature: TimeSignature,
+    registry: &PartRegistry,
+) -> BTreeMap<DrumPart, String> {
+    if part == pulse {
+        fail!("{} can't play both the subdivisions and the pulse, exiting...", registry.title(part))
+    }
+    let rungs = match ladder.to_groups(time_signature) {
+        Ok(rungs) => rungs,
+        Err(e) => fail!("The ladder doesn't fit {}: {}, exiting...", time_signature, e),
+    };
+    for (i, grid) in ladder.rungs.iter().enumerate() {
+        let (first, last) = (i * ladder.bars as usize + 1, (i + 1) * ladder.bars as usize);
+        let bars = if first == last { format!("Bar {}", first) } else { format!("Bars {}-{}", first, last) };
+        say!("{} - {} on {}", bars, registry.title(part), grid);
+    }
+    let pattern: String = rungs.iter().map(|g| g.to_string()).collect();
+    BTreeMap::from_iter([(part, pattern), (pulse, meter_pulse(time_signature).to_string())])
+}
+
+/// Adds the pattern of `--reference-pulse` to `patterns`, a hit on every beat of the time
+/// signature. Returns the curve keeping it quiet, which goes before the ones of
+/// `--velocity-curve` so a curve given for the part wins.
+fn add_reference_pulse(patterns: &mut BTreeMap<DrumPart, String>, output_args: &OutputArgs, registry: &PartRegistry) -> Option<Curve> {
+    let part = resolve_part(output_args.reference_pulse.as_deref()?, registry);
+    let pulse = meter_pulse(parse_time_signature(&output_args.time_signature)).to_string();
+    if patterns.insert(part, pulse).is_some() {
+        fail!("{} already plays a pattern, pick another part for --reference-pulse, exiting...", registry.title(part))
+    }
+    Some(Curve {
+        part: Some(Part::Drum(part)),
+        response: Response::Fixed(output_args.reference_pulse_velocity),
+    })
+}
+
+/// Contents of a file, or of stdin if the path is `-`.
+fn read_input(path: &str) -> io::Result<String> {
+    if path == "-" {
+        let mut contents = String::new();
+        io::stdin().read_to_string(&mut contents)?;
+        Ok(contents)
+    } else {
+        fs::read_to_string(path)
+    }
+}
+
+fn read_pattern_file(path: &str) -> PatternFile {
+    let contents = match read_input(path) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to read {}: {}", path, e)
+        }
+    };
+    match PatternFile::from_str(&contents) {
+        Ok(x) => x,
+        Err(e) if JSON.load(Ordering::Relaxed) => {
+            problem("error", &e, &[("file", path)]);
+            exit(1)
+        }
+        Err(e) => {
+            fail!("Failed to parse {}:\n{}", path, e)
+        }
+    }
+}
+
+fn seeded_rng(seed: Option<u64>) -> Rng {
+    Rng::new(pick_seed(seed))
+}
+
+/// The seed if it's given, or one from the clock, printed so the run can be repeated.
+fn pick_seed(seed: Option<u64>) -> u64 {
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    });
+    say!("Using seed {}", seed);
+    seed
+}
+
+/// Humanization of `--humanize-timing` and `--humanize-velocity`, seeded like the generators.
+fn humanize_of(output_args: &OutputArgs) -> Humanize {
+    let humanize = Humanize {
+        timing: output_args.humanize_timing.unwrap_or(0.0),
+        velocity: output_args.humanize_velocity.unwrap_or(0),
+        seed: 0,
+    };
+    if !(0.0..=1000.0).contains(&humanize.timing) {
+        fail!("--humanize-timing should be from 0 to 1000 milliseconds, exiting...")
+    }
+    if humanize.is_empty() {
+        return humanize;
+    }
+    Humanize {
+        seed: pick_seed(fixed_seed(output_args.humanize_seed, output_args)),
+        ..humanize
+    }
+}
+
+/// The seed of a deterministic run is 0 unless it's given.
+fn fixed_seed(seed: Option<u64>, output: &OutputArgs) -> Option<u64> {
+    seed.or(if output.deterministic { Some(0) } else { None })
+}
+
+fn vary_patterns(
+    file: PatternFile,
+    amount: f64,
+    seed: Option<u64>,
+    time_signature: TimeSignature,
+    registry: &PartRegistry,
+) -> BTreeMap<DrumPart, String> {
+    let mut rng = seeded_rng(seed);
+    let mut groups = BTreeMap::new();
+    for (part, pattern) in file.parts {
+        validate_and_parse_part(Some(pattern), part, registry, &mut groups);
+    }
+    groups
+        .into_iter()
+        .map(|(part, pattern)| {
+            let groups = vary(&pattern.groups, time_signature, amount, &mut rng);
+            (part, dsl::Pattern { groups, ..pattern }.to_string())
+        })
+        .collect()
+}
+
+fn learn_patterns(
+    input: &str,
+    bars: u32,
+    order: usize,
+    seed: Option<u64>,
+    time_signature: TimeSignature,
+) -> BTreeMap<DrumPart, String> {
+    let entries = match fs::read_dir(input) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to read {}: {}", input, e)
+        }
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .map(|ext| ext.eq_ignore_ascii_case("mid") || ext.eq_ignore_ascii_case("midi"))
+                .unwrap_or(false)
+        })
+        .collect();
+    paths.sort();
+
+    let mut model = GrooveModel::new(order);
+    for path in paths.iter() {
+        let learned = fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| import_drums(&bytes))
+            .and_then(|drums| model.learn_drums(&drums));
+        match learned {
+            Ok(_) => say!("Learned {}", path.display()),
+            Err(e) => say!("Skipping {}: {}", path.display(), e),
+        }
+    }
+    if model.parts().is_empty() {
+        fail!("No drum parts were found in {}, exiting...", input)
+    }
+
+    let mut rng = seeded_rng(seed);
+    model
+        .parts()
+        .into_iter()
+        .map(|part| match model.generate(part, bars, time_signature, &mut rng) {
+            Ok(groups) => (part, groups.to_string()),
+            Err(e) => {
+                fail!("{}, exiting...", e)
+            }
+        })
+        .collect()
+}
+
+/// Patterns of the drums on `channel` of a MIDI file, snapped to the `grids` of their parts,
+/// after moving them towards them by `quantize` percent and writing them to `quantized` if it's given.
+/// The time signature of the file, if it has one, replaces the one of `output`.
+fn extract_patterns(
+    input: &str,
+    channel: u8,
+    grids: &Grids,
+    quantize: Option<u8>,
+    quantized: Option<&str>,
+    output: &mut OutputArgs,
+) -> BTreeMap<DrumPart, String> {
+    if channel > 16 {
+        fail!("MIDI channels go from 1 to 16, exiting...")
+    }
+    if quantize.is_some_and(|q| q > 100) {
+        fail!("Quantize strength should be between 0 and 100 percent, exiting...")
+    }
+    let bytes = match fs::read(input) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to read {}: {}", input, e)
+        }
+    };
+    let mut smf = match Smf::parse(&bytes) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Can't parse {}: {}", input, e)
+        }
+    };
+    if let Some(strength) = quantize {
+        let moved = match import::quantize(&mut smf, channel.checked_sub(1), grids, strength as f64 / 100.0) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}, exiting...", e)
+            }
+        };
+        for m in moved.iter() {
+            let name = m.part.map_or(format!("key {}", m.key), |part| part.name().to_string());
+            say!("{} at tick {} moved {:+} ticks to {}", name, m.from.0, m.shift(), m.to.0);
+        }
+        let furthest = moved.iter().map(|m| m.shift().unsigned_abs()).max().unwrap_or(0);
+        say!("Quantized {} hits by {}%, {} ticks at most", moved.len(), strength, furthest);
+        if let Some(path) = quantized {
+            match stream::save(&smf, path) {
+                Ok(_) => say!("{} was written successfully", path),
+                Err(e) => {
+                    fail!("Failed to write {}: {}", path, e)
+                }
+            }
+        }
+    }
+    let drums = import_channel(&smf, channel.checked_sub(1));
+    let drums = match drums {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to read {}: {}", input, e)
+        }
+    };
+    let time_signature = match drums.time_signature {
+        Some(x) => {
+            output.time_signature = x.to_string();
+            x
+        }
+        None => parse_time_signature(&output.time_signature),
+    };
+    let patterns = drums.to_patterns(grids, time_signature);
+    let parts: BTreeSet<DrumPart> = drums.onsets.iter().map(|o| o.part).collect();
+    for part in parts.iter().filter(|p| !patterns.contains_key(p)) {
+        say!("{} on a {} grid can't be written as a pattern and is left out", part.name(), grids.of(Some(*part)));
+    }
+    if patterns.is_empty() {
+        fail!("No drum parts were found in {}, exiting...", input)
+    }
+    patterns.into_iter().map(|(part, groups)| (part, groups.to_string())).collect()
+}
+
+/// Prints the grid of the pulse streams and where they land together, then steps through a
+/// cycle of it in time with `animate`.
+fn show_pulses(voices: Vec<u16>, subdivision: u16, width: usize, animate: bool, tempo: f64) {
+    let step_length = parse_subdivision(subdivision);
+    let name = voices.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(":");
+    let grid = match Ratio::from_str(&name).and_then(|ratio| PulseGrid::new(&ratio)) {
+        Ok(x) => x,
+        Err(e) => fail!("{}, exiting...", e),
+    };
+    print!("{}", grid.render(width));
+    say!("{} lines up again after {} steps of 1/{}", name, grid.steps, subdivision);
+    // Steps of every set of streams landing together.
+    let mut together: BTreeMap<Vec<usize>, Vec<u32>> = BTreeMap::new();
+    for (step, landing) in grid.coincidences() {
+        together.entry(landing).or_default().push(step + 1);
+    }
+    for (landing, steps) in together.iter() {
+        let streams: Vec<String> = landing.iter().map(|i| voices[*i].to_string()).collect();
+        let steps: Vec<String> = steps.iter().map(|s| s.to_string()).collect();
+        say!("{} land together on step(s) {}", streams.join(", "), steps.join(", "));
+    }
+    if !animate {
+        return;
+    }
+    if !io::stdout().is_terminal() {
+        warn!("--animate needs a terminal, the grid is only printed");
+        return;
+    }
+    if tempo <= 0.0 {
+        fail!("Tempo should be above 0 BPM, exiting...")
+    }
+    let step = Duration::from_secs_f64(60.0 / tempo * step_length.to_128th() as f64 / 32.0);
+    let mut stdout = io::stdout();
+    for at in 0..grid.steps {
+        let landing = grid.landing(at);
+        let streams: Vec<String> = voices
+            .iter()
+            .enumerate()
+            .map(|(i, n)| if landing.contains(&i) { n.to_string() } else { " ".repeat(n.to_string().len()) })
+            .collect();
+        let _ = write!(stdout, "\rstep {:>w$}/{}  {}", at + 1, grid.steps, streams.join(" "), w = grid.steps.to_string().len());
+        let _ = stdout.flush();
+        thread::sleep(step);
+    }
+    let _ = writeln!(stdout);
+}
+
+fn ratio_patterns(
+    ratio: &Ratio,
+    subdivision: u16,
+    parts: Vec<DrumPart>,
+    registry: &PartRegistry,
+) -> BTreeMap<DrumPart, String> {
+    let subdivision = parse_subdivision(subdivision);
+    let parts = if parts.is_empty() {
+        vec![KickDrum, SnareDrum, HiHat, CrashCymbal]
+            .into_iter()
+            .take(ratio.0.len())
+            .collect()
+    } else {
+        parts
+    };
+    if parts.len() != ratio.0.len() {
+        fail!(
+            "Ratio {} has {} voices, but {} drum parts were supplied, exiting...",
+            ratio.0.iter().map(|n| n.to_string()).collect::<Vec<_>>().join(":"),
+            ratio.0.len(),
+            parts.len()
+        )
+    }
+    let groups = match ratio.to_groups(subdivision) {
+        Ok(x) => x,
+        Err(e) => fail!("{}, exiting...", e),
+    };
+    let mut patterns = BTreeMap::new();
+    for (part, group) in parts.into_iter().zip(groups) {
+        if patterns.insert(part, group.to_string()).is_some() {
+            fail!("{} is used for more than one voice, exiting...", registry.title(part))
+        }
+    }
+    patterns
+}
+
+/// Writes every ratio of `Ratio::family` to a file of its own in the directory of `-o`,
+/// named like `3-4.mid` and marked with the ratio and the parts that play it.
+fn render_family(max: u16, subdivision: u16, parts: Vec<DrumPart>, registry: PartRegistry, output_args: OutputArgs) {
+    let dir = match output_args.output.as_deref() {
+        None | Some("-") => fail!("poly family needs a directory to write the files to, like -o out/, exiting..."),
+        Some(dir) => PathBuf::from(dir),
+    };
+    let family = Ratio::family(max);
+    let parts = if parts.is_empty() { vec![KickDrum, SnareDrum] } else { parts };
+    let mut spec = spec_of(ratio_patterns(&family[0], subdivision, parts.clone(), &registry), registry, &output_args);
+    if !output_args.dry_run {
+        if let Err(e) = fs::create_dir_all(&dir) {
+            fail!("Failed to create {}: {}", dir.display(), e)
+        }
+    }
+    let mut files = Vec::new();
+    for ratio in family.iter() {
+        spec.patterns = ratio_patterns(ratio, subdivision, parts.clone(), &spec.registry);
+        let voices: Vec<String> = parts.iter().zip(ratio.0.iter()).map(|(part, n)| format!("{} {}", spec.registry.title(*part), n)).collect();
+        let marker = format!("Polyrhythm {}, {}", ratio, voices.join(" against "));
+        let text = description(&spec.patterns, &spec.registry);
+        let (mut smf, bars) = match spec::render(&spec, &text) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}: {}, exiting...", ratio, e)
+            }
+        };
+        smf.tracks[0].insert(0, TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::Marker(marker.as_bytes())) });
+        finish(&mut smf, output_args.timing, output_args.optimize_size);
+        let path = dir.join(format!("{}.mid", ratio.0.iter().map(|n| n.to_string()).collect::<Vec<_>>().join("-")));
+        let path = path.to_string_lossy().into_owned();
+        if output_args.dry_run {
+            say!("{} over {} bar{} would go to {}", ratio, bars, if bars == 1 { "" } else { "s" }, path);
+            continue;
+        }
+        match stream::save(&smf, &path) {
+            Ok(_) => {
+                say!("{} was written successfully", path);
+                files.push(path);
+            }
+            Err(e) => {
+                fail!("Failed to write {}: {}", path, e)
+            }
+        }
+    }
+    summarize(vec![("files", Json::Array(files.into_iter().map(Json::String).collect()))]);
+}
+
+/// Renders the displacement exercise of the groove of a pattern file, with a marker at the start
+/// of every displacement.
+fn render_displacements(file: PatternFile, grid: Grid, repeats: u16, registry: PartRegistry, output_args: OutputArgs) {
+    let mut parsed = BTreeMap::new();
+    for (part, pattern) in file.parts {
+        validate_and_parse_part(Some(pattern), part, &registry, &mut parsed);
+    }
+    let mut parts = BTreeMap::new();
+    for (part, pattern) in parsed {
+        if !pattern.pickup.0.is_empty() || pattern.fit.is_some() {
+            fail!("{} pattern should be a plain groove to be displaced, without a pickup or a fit, exiting...", registry.title(part))
+        }
+        parts.insert(part, pattern.groups);
+    }
+    let (exercise, cycle) = match displacement::exercise(&parts, grid, repeats as usize) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("The groove can't be displaced: {}, exiting...", e)
+        }
+    };
+    say!("{} displacements of a cycle of {} steps of {}", cycle, cycle, grid);
+    let patterns = exercise.into_iter().map(|(part, groups)| (part, groups.to_string())).collect();
+    let template = output_args.output.as_deref().map(output_template);
+    let spec = spec_of(patterns, registry, &output_args);
+    let markers: Vec<String> = (0..cycle)
+        .map(|by| match by {
+            0 => "Not displaced".to_string(),
+            1 => format!("Displaced by 1 step of {}", grid),
+            by => format!("Displaced by {} steps of {}", by, grid),
+        })
+        .collect();
+    let text = description(&spec.patterns, &spec.registry);
+    let (mut smf, bars) = match spec::render(&spec, &text) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}, exiting...", e)
+        }
+    };
+    let ppqn = match smf.header.timing {
+        Timing::Metrical(ppqn) => ppqn.as_int(),
+        Timing::Timecode(..) => unreachable!("poly writes metrical files"),
+    };
+    let (step, steps) = (grid.step_length().fraction(), cycle as u128 * repeats as u128);
+    for (by, marker) in markers.iter().enumerate() {
+        let tick = Tick(by as u128 * steps * step.0 as u128 * ppqn as u128 / (step.1 as u128 * 32));
+        insert_at(&mut smf.tracks[0], tick, vec![TrackEventKind::Meta(MetaMessage::Marker(marker.as_bytes()))]);
+    }
+    finish(&mut smf, output_args.timing, output_args.optimize_size);
+    let output = output_args.output.zip(template).map(|(path, template)| {
+        let metadata = Metadata {
+            name: output_name(&parse_track_names(&output_args.track_names, &spec.registry), output_args.source.as_deref()),
+            tempo: spec.tempo,
+            signature: spec.time_signature,
+            bars,
+            ppqn,
+            target: spec.target,
+        };
+        fill_output_path(path, &template, &metadata)
+    });
+    let summary = |files: Vec<&str>| {
+        vec![
+            ("bars", Json::Number(bars as f64)),
+            ("displacements", Json::Number(cycle as f64)),
+            ("ppqn", Json::Number(ppqn as f64)),
+            ("files", Json::Array(files.into_iter().map(|f| Json::String(f.to_string())).collect())),
+        ]
+    };
+    match output.as_deref().filter(|_| !output_args.dry_run) {
+        None if output_args.dry_run => {
+            say!("Running a dry run, nothing is written...");
+            summarize(summary(Vec::new()))
+        }
+        None => {
+            say!("No output file path was supplied, running a dry run...");
+            summarize(summary(Vec::new()))
+        }
+        Some("-") => {
+            if let Err(e) = stream::write(&smf, BufWriter::new(io::stdout().lock())) {
+                fail!("Failed to write to stdout: {}", e)
+            }
+            summarize(summary(vec!["-"]))
+        }
+        Some(path) => match stream::save(&smf, path) {
+            Ok(_) => {
+                say!("{} was written successfully", path);
+                summarize(summary(vec![path]))
+            }
+            Err(e) => {
+                fail!("Failed to write {}: {}", path, e)
+            }
+        },
+    }
+}
+
+/// Reads the grooves given as `file` or `part=file` for the `parts` they apply to, then the
+/// swings of `--swing`, which win over them. Grooves and swings of a single part win over the
+/// ones of every part.
+fn read_grooves(args: &[String], swings: &[String], parts: &[DrumPart], registry: &PartRegistry) -> BTreeMap<DrumPart, Groove> {
+    let mut grooves = BTreeMap::new();
+    let (single, every): (Vec<_>, Vec<_>) = args
+        .iter()
+        .map(|arg| match arg.split_once('=') {
+            Some((part, path)) => (Some(resolve_part(part, registry)), path),
+            None => (None, arg.as_str()),
+        })
+        .partition(|(part, _)| part.is_some());
+    for (part, path) in every.into_iter().chain(single) {
+        for target in parts.iter().filter(|p| part.is_none() || part == Some(**p)) {
+            grooves.insert(*target, read_groove(path, *target));
+        }
+    }
+    let swings: Vec<Swing> = swings
+        .iter()
+        .map(|s| match Swing::parse(s, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect();
+    for swing in swings.iter().filter(|s| s.part.is_none()).chain(swings.iter().filter(|s| s.part.is_some())) {
+        for target in parts.iter().filter(|p| swing.part.is_none() || swing.part == Some(**p)) {
+            grooves.insert(*target, swing.to_groove());
+        }
+    }
+    grooves
+}
+
+fn read_groove(path: &str, part: DrumPart) -> Groove {
+    if path.ends_with(".mid") || path.ends_with(".midi") {
+        let bytes = match fs::read(path) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("Failed to read {}: {}", path, e)
+            }
+        };
+        let groove = import_drums(&bytes)
+            .and_then(|drums| {
+                Groove::extract(&drums, Some(part), Grid::from(BasicLength::Sixteenth))
+                    .ok_or_else(|| "There are no drum hits to learn a groove from".to_string())
+            });
+        return match groove {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("Failed to learn a groove from {}: {}", path, e)
+            }
+        };
+    }
+    let contents = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to read {}: {}", path, e)
+        }
+    };
+    match Groove::from_str(&contents) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to parse {}:\n{}", path, e)
+        }
+    }
+}
+
+fn parse_dynamics(args: &[String], registry: &PartRegistry) -> Vec<Dynamics> {
+    args.iter()
+        .map(|d| match Dynamics::parse(d, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect()
+}
+
+fn parse_mix(args: &[String], registry: &PartRegistry) -> Vec<Mix> {
+    args.iter()
+        .map(|m| match Mix::parse(m, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect()
+}
+
+fn parse_accent_every(args: &[String], registry: &PartRegistry) -> Vec<Every> {
+    args.iter()
+        .map(|e| match Every::parse(e, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect()
+}
+
+fn parse_echoes(args: &[String], registry: &PartRegistry) -> Vec<Echo> {
+    args.iter()
+        .map(|e| match Echo::parse(e, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect()
+}
+
+fn parse_curves(args: &[String], registry: &PartRegistry) -> Vec<Curve> {
+    args.iter()
+        .map(|c| match Curve::parse(c, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect()
+}
+
+fn parse_track_names(args: &[String], registry: &PartRegistry) -> Vec<TrackName> {
+    args.iter()
+        .map(|n| match TrackName::parse(n, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect()
+}
+
+/// Puts the names of the tracks of a pattern file before the ones of `--track-name`, which win
+/// over them.
+fn name_tracks(names: &[TrackName], registry: &PartRegistry, output_args: &mut OutputArgs) {
+    output_args.track_names.splice(0..0, names.iter().map(|n| n.to_string_with(registry)));
+}
+
+/// Adds the modulations of a pattern file ahead of the ones of `--modulate`, which win over them.
+fn add_modulations(modulations: &[Modulation], output_args: &mut OutputArgs) {
+    output_args.modulate.splice(0..0, modulations.iter().map(|m| m.to_string()));
+}
+
+fn parse_modulations(args: &[String]) -> Vec<Modulation> {
+    args.iter()
+        .map(|m| match Modulation::from_str(m) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect()
+}
+
+fn parse_note_offs(args: &[String], as_note_ons: bool, registry: &PartRegistry) -> NoteOffs {
+    let releases = args
+        .iter()
+        .map(|r| match Release::parse(r, registry) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("{}", e)
+            }
+        })
+        .collect();
+    NoteOffs { releases, as_note_ons }
+}
+
+/// Makes a file smaller for `--optimize-size` and tells by how much.
+fn shrink(smf: &mut Smf) {
+    let before = optimize::size(smf);
+    optimize::optimize(smf);
+    let after = optimize::size(smf);
+    let saved = before.saturating_sub(after);
+    say!("Optimized {} bytes down to {}, {:.1}% smaller", before, after, saved as f64 * 100.0 / before.max(1) as f64);
+}
+
+/// Moves a file to the `--timing` of the output and makes it smaller with `--optimize-size`.
+fn finish(smf: &mut Smf, timing: FileTiming, optimize_size: bool) {
+    if let Err(e) = timing.apply(smf) {
+        fail!("{}, exiting...", e)
+    }
+    if optimize_size {
+        shrink(smf);
+    }
+}
+
+fn write_key_map(keys: &KeyMap, path: &str) {
+    let json = format!("{:#}\n", keys.to_json());
+    if path == "-" {
+        print!("{}", json);
+        return;
+    }
+    match fs::write(path, json) {
+        Ok(_) => say!("{} was written successfully", path),
+        Err(e) => {
+            fail!("Failed to write {}: {}", path, e)
+        }
+    }
+}
+
+fn read_events(events: &mut EventList, smf: &Smf, keys: &KeyMap) {
+    if let Err(e) = events.read(smf, keys) {
+        fail!("{}, exiting...", e)
+    }
+}
+
+fn write_events(events: &EventList, path: &str) {
+    let json = format!("{}\n", events.to_json());
+    if path == "-" {
+        print!("{}", json);
+        return;
+    }
+    match fs::write(path, json) {
+        Ok(_) => say!("{} was written successfully", path),
+        Err(e) => {
+            fail!("Failed to write {}: {}", path, e)
+        }
+    }
+}
+
+fn read_project(project: &mut DawProject, smf: &Smf) {
+    if let Err(e) = project.read(smf) {
+        fail!("{}, exiting...", e)
+    }
+}
+
+fn write_project(project: &DawProject, path: &str) {
+    let bytes = project.to_bytes();
+    if path == "-" {
+        if let Err(e) = io::stdout().lock().write_all(&bytes) {
+            fail!("Failed to write to stdout: {}", e)
+        }
+        return;
+    }
+    match fs::write(path, bytes) {
+        Ok(_) => say!("{} was written successfully", path),
+        Err(e) => {
+            fail!("Failed to write {}: {}", path, e)
+        }
+    }
+}
+
+/// Writes the parts that aren't muted as a MusicXML score, or a Guitar Pro file with
+/// `guitar_pro`.
+#[allow(clippy::too_many_arguments)]
+fn write_score(
+    groups: &BTreeMap<DrumPart, Groups>,
+    muted: &BTreeSet<Part>,
+    registry: &PartRegistry,
+    signature: TimeSignature,
+    tempos: &[(u32, f64)],
+    bars: u32,
+    title: &str,
+    path: &str,
+    guitar_pro: bool,
+) {
+    let parts: BTreeMap<DrumPart, Groups> = groups.iter().filter(|(part, _)| !muted.contains(&Part::Drum(**part))).map(|(p, g)| (*p, g.clone())).collect();
+    let score = if guitar_pro {
+        guitar_pro::archive(&parts, registry, signature, tempos, bars, title)
+    } else {
+        musicxml::score(&parts, registry, signature, tempos, bars, title).map(String::into_bytes)
+    };
+    let bytes = match score {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}, exiting...", e)
+        }
+    };
+    if path == "-" {
+        if let Err(e) = io::stdout().lock().write_all(&bytes) {
+            fail!("Failed to write to stdout: {}", e)
+        }
+        return;
+    }
+    match fs::write(path, bytes) {
+        Ok(_) => say!("{} was written successfully", path),
+        Err(e) => {
+            fail!("Failed to write {}: {}", path, e)
+        }
+    }
+}
+
+fn render(patterns: BTreeMap<DrumPart, String>, registry: &PartRegistry, output_args: OutputArgs) {
+    // Patterns that don't parse are reported with the hints of the command line.
+    for (part, pattern) in patterns.iter() {
+        validate_and_parse_part(Some(pattern.clone()), *part, registry, &mut BTreeMap::new());
+    }
+    let spec = spec_of(patterns, registry.clone(), &output_args);
+    let OutputArgs {
+        tempo,
+        modulate: _,
+        time_signature: _,
+        output,
+        follow_kick_drum_with_bass: _,
+        bass_key: _,
+        bass_line: _,
+        key: _,
+        alignment: _,
+        clave: _,
+        ppqn: _,
+        grooves: _,
+        swing: _,
+        dynamics: _,
+        accent_downbeats: _,
+        accent_strong_beats: _,
+        accent_every: _,
+        echoes: _,
+        humanize_timing: _,
+        humanize_velocity: _,
+        humanize_seed: _,
+        mix: _,
+        velocity_curves: _,
+        note_off_velocities: _,
+        zero_velocity_note_offs: _,
+        optimize_size,
+        timing,
+        high_resolution_velocity: _,
+        track_names,
+        key_map,
+        musicxml,
+        gp,
+        dawproject,
+        events_json,
+        output_dir: _,
+        mute: _,
+        solo: _,
+        target,
+        deterministic: _,
+        custom_parts: _,
+        layers: _,
+        part_velocity: _,
+        print_events,
+        split_parts,
+        overlap: _,
+        max_bars: _,
+        max_events: _,
+        on_limit: _,
+        verbose: _,
+        allow_silence: _,
+        reference_pulse: _,
+        reference_pulse_velocity: _,
+        dry_run,
+        json: _,
+        source,
+    } = output_args;
+    if split_parts && matches!(output.as_deref(), None | Some("-")) {
+        fail!("--split-parts needs an output file path to name the files of the parts after, exiting...")
+    }
+    let registry = &spec.registry;
+    let signature = spec.time_signature;
+    let muted = &spec.muted;
+    let text_description = description(&spec.patterns, registry);
+    let track_names = parse_track_names(&track_names, registry);
+    let template = output.as_deref().map(output_template);
+    let arrangement = match spec::arrange(&spec) {
+        Ok(x) => x,
+        Err(e) if e == Error::Silent.to_string() => fail!("{}, --allow-silence writes it anyway", e),
+        Err(e) => fail!("{}, exiting...", e),
+    };
+    for message in arrangement.messages.iter() {
+        say!("{}", message);
+    }
+    for warning in arrangement.warnings.iter() {
+        warn!("{}", warning);
+    }
+
+    if dry_run {
+        say!("Running a dry run, nothing is written...");
+    } else if output.is_none() {
+        say!("No output file path was supplied, running a dry run...");
+    }
+    let key_map = key_map.filter(|_| !dry_run);
+    let musicxml = musicxml.filter(|_| !dry_run);
+    let gp = gp.filter(|_| !dry_run);
+    let dawproject = dawproject.filter(|_| !dry_run);
+    let events_json = events_json.filter(|_| !dry_run);
+    let (ppqn, bar_one, tempo_map, convergence) = (arrangement.ppqn, arrangement.bar_one, &arrangement.tempo, &arrangement.convergence);
+    let bars = convergence.bars;
+    for (at, change) in tempo_map.changes().iter().skip(1) {
+        let bpm = 60_000_000.0 / change.microseconds() as f64;
+        let bar = (*at - bar_one).0 / bar_length(signature, ppqn).0 + 1;
+        say!("Modulates to {} BPM on bar {}", (bpm * 100.0).round() / 100.0, bar);
+    }
+    let scores = [(musicxml, false), (gp, true)];
+    if scores.iter().any(|(path, _)| path.is_some()) {
+        let tempos: Vec<(u32, f64)> = tempo_map
+            .changes()
+            .iter()
+            .map(|(at, change)| ((at.0.saturating_sub(bar_one.0) / bar_length(signature, ppqn).0) as u32 + 1, 60_000_000.0 / change.microseconds() as f64))
+            .collect();
+        for (path, guitar_pro) in scores {
+            let Some(path) = path else {
+                continue;
+            };
+            write_score(&arrangement.groups, muted, registry, signature, &tempos, bars, &output_name(&track_names, source.as_deref()), &path, guitar_pro);
+            if !arrangement.pickups.is_empty() {
+                warn!("the pickup is left out of {}, the score starts on bar 1", path);
+            }
+        }
+    }
+    let output = output.zip(template).map(|(path, template)| {
+        let metadata = Metadata {
+            name: output_name(&track_names, source.as_deref()),
+            tempo,
+            signature,
+            bars,
+            ppqn,
+            target,
+        };
+        fill_output_path(path, &template, &metadata)
+    });
+    let output = output.filter(|_| !dry_run);
+    let plural = |n: u32| if n == 1 { "" } else { "s" };
+    match convergence.converges {
+        Some(c) if !convergence.is_truncated() => say!("Converges over {} bar{}", c, plural(c)),
+        Some(c) => warn!(
+            "converges over {} bar{}, cut short after {} bar{} of {} events to stay within --max-events",
+            c,
+            plural(c),
+            bars,
+            plural(bars),
+            convergence.events
+        ),
+        None => match convergence.cycle {
+            Some(c) => warn!("converges over {} bars, cut short after {} bar{}", c, bars, plural(bars)),
+            None => warn!("doesn't converge within 2^128 128th notes, cut short after {} bar{}", bars, plural(bars)),
+        },
+    }
+    let summary = |files: Vec<String>| {
+        let number = |n: f64| Json::Number(n);
+        vec![
+            ("bars", number(bars as f64)),
+            ("converges", convergence.converges.map_or(Json::Null, |c| number(c as f64))),
+            ("events", number(convergence.events as f64)),
+            ("duration", number(tempo_map.seconds_at(bar_length(signature, ppqn) * bars as u128, ppqn))),
+            ("ppqn", number(ppqn as f64)),
+            ("files", Json::Array(files.into_iter().map(Json::String).collect())),
+        ]
+    };
+    // Reports every tenth of the file, a bar at a time is too chatty for thousands of them.
+    let mut reported = 0;
+    let mut progress = |p: Progress| {
+        if p.bars * 10 >= (reported + 1) * p.total {
+            reported = p.bars * 10 / p.total.max(1);
+            tracing::info!("{} of {} bars written", p.bars, p.total);
+        }
+    };
+    let mut lanes: Vec<Part> = arrangement.groups.keys().chain(arrangement.pickups.keys()).map(|part| Part::Drum(*part)).collect();
+    lanes.sort();
+    lanes.dedup();
+    if spec.bass {
+        lanes.push(Part::Bass);
+    }
+    let mut keys = KeyMap::new(ppqn, convergence);
+    let length = bar_one + bar_length(signature, ppqn) * bars as u128;
+    let mut project = DawProject::new(&output_name(&track_names, source.as_deref()), signature, tempo_map, ppqn, bar_one, length);
+    let mut events = EventList::new(bar_one, length);
+    // The file is only built in memory when it's read back or reworked, otherwise its events
+    // are written as they're mapped.
+    let reworked = key_map.is_some() || events_json.is_some() || dawproject.is_some() || timing != FileTiming::Metrical || optimize_size;
+    if let Some(path) = output.as_deref().filter(|_| split_parts) {
+        let mut files = Vec::new();
+        for part in lanes.iter().filter(|part| !muted.contains(part)) {
+            let drum = match part {
+                Part::Drum(drum) => Some(*drum),
+                Part::Bass => None,
+            };
+            let alone = Spec {
+                muted: lanes.iter().filter(|p| *p != part).cloned().collect(),
+                names: TrackNames::new(&track_names, drum, registry),
+                ..spec.clone()
+            };
+            let name = match part {
+                Part::Drum(drum) => registry.name(*drum),
+                Part::Bass => "bass".to_string(),
+            };
+            let part_path = part_file_path(path, &name);
+            let written = if reworked {
+                let mut smf = arrangement.smf(&alone, &text_description, &mut progress);
+                keys.read(&smf, &lanes, registry, target);
+                if dawproject.is_some() {
+                    read_project(&mut project, &smf);
+                }
+                if events_json.is_some() {
+                    read_events(&mut events, &smf, &keys);
+                }
+                finish(&mut smf, timing, optimize_size);
+                stream::save(&smf, &part_path)
+            } else {
+                write_arrangement(&arrangement, &alone, &text_description, &mut progress, &part_path)
+            };
+            match written {
+                Ok(_) => {
+                    say!("{} was written successfully", part_path);
+                    files.push(part_path);
+                }
+                Err(e) => {
+                    fail!("Failed to write {}: {}", part_path, e)
+                }
+            }
+        }
+        if let Some(key_map) = key_map {
+            write_key_map(&keys, &key_map);
+        }
+        if let Some(path) = dawproject {
+            write_project(&project, &path);
+        }
+        if let Some(path) = events_json {
+            write_events(&events, &path);
+        }
+        summarize(summary(files));
+        exit(0)
+    }
+    if let Some(path) = output.as_deref().filter(|_| !reworked && !print_events) {
+        if let Err(e) = write_arrangement(&arrangement, &spec, &text_description, &mut progress, path) {
+            fail!("Failed to write {}: {}", if path == "-" { "to stdout" } else { path }, e)
+        }
+        if path != "-" {
+            say!("{} was written successfully", path);
+        }
+        summarize(summary(vec![path.to_string()]));
+        exit(0)
+    }
+    let mut smf = arrangement.smf(&spec, &text_description, &mut progress);
+    if print_events {
+        say!("{}", dump_events(&smf, registry, &spec.names.bass).trim_end());
+    }
+    if key_map.is_some() || events_json.is_some() {
+        keys.read(&smf, &lanes, registry, target);
+    }
+    if let Some(key_map) = key_map {
+        write_key_map(&keys, &key_map);
+    }
+    if let Some(path) = events_json {
+        read_events(&mut events, &smf, &keys);
+        write_events(&events, &path);
+    }
+    if let Some(path) = dawproject {
+        read_project(&mut project, &smf);
+        write_project(&project, &path);
+    }
+    finish(&mut smf, timing, optimize_size);
+
+    if dry_run {
+        let notes = smf
+            .tracks
+            .iter()
+            .flatten()
+            .filter(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. }, .. }))
+            .count();
+        let length = tempo_map.seconds_at(bar_one + bar_length(signature, ppqn) * bars as u128, ppqn);
+        say!("{} bar{}, {} note events, plays for {} at {} BPM", bars, plural(bars), notes, clock(length), tempo);
+        if let Some(cycle) = convergence.cycle.filter(|_| convergence.is_truncated()) {
+            let whole = tempo_map.seconds_at(Tick(bar_length(signature, ppqn).0.saturating_mul(cycle)), ppqn);
+            say!("The whole cycle of {} bars would play for {}", cycle, clock(whole));
+        }
+    }
+    match output.as_deref() {
+        None => summarize(summary(Vec::new())),
+        Some("-") => {
+            if let Err(e) = stream::write(&smf, BufWriter::new(io::stdout().lock())) {
+                fail!("Failed to write to stdout: {}", e)
+            }
+            summarize(summary(vec!["-".to_string()]))
+        }
+        Some(path) => match stream::save(&smf, path) {
+            Ok(_) => {
+                say!("{} was written successfully", path);
+                summarize(summary(vec![path.to_string()]));
+                exit(0)
+            }
+            Err(e) => {
+                fail!("Failed to write {}: {}", path, e)
+            }
+        },
+    };
+}
+
+/// Writes the file of an arrangement to `path`, or to stdout for `-`, as its events are mapped.
+fn write_arrangement(arrangement: &Arrangement, spec: &Spec, text: &str, progress: &mut dyn FnMut(Progress), path: &str) -> io::Result<()> {
+    if path == "-" {
+        arrangement.write(spec, text, progress, BufWriter::new(io::stdout().lock()))
+    } else {
+        arrangement.write(spec, text, progress, BufWriter::new(fs::File::create(path)?))
+    }
+}
+
+/// The spec of the patterns and the settings of the command line.
+fn spec_of(mut patterns: BTreeMap<DrumPart, String>, mut registry: PartRegistry, output_args: &OutputArgs) -> Spec {
+    let pulse_curve = add_reference_pulse(&mut patterns, output_args, &registry);
+    add_layers(&mut registry, &output_args.layers);
+    set_velocities(&mut registry, &output_args.part_velocity);
+    registry.set_bass_line(BassLine::new(output_args.bass_key.unwrap_or_default(), output_args.bass_line.clone()));
+    if let Some(key) = output_args.key {
+        registry.set_key(key);
+    }
+    let parts: Vec<DrumPart> = patterns.keys().cloned().collect();
+    Spec {
+        grooves: read_grooves(&output_args.grooves, &output_args.swing, &parts, &registry),
+        modulations: parse_modulations(&output_args.modulate),
+        dynamics: parse_dynamics(&output_args.dynamics, &registry),
+        accents: Accents {
+            downbeats: output_args.accent_downbeats.unwrap_or(0),
+            strong_beats: output_args.accent_strong_beats,
+            every: parse_accent_every(&output_args.accent_every, &registry),
+        },
+        echoes: parse_echoes(&output_args.echoes, &registry),
+        humanize: humanize_of(output_args),
+        mix: parse_mix(&output_args.mix, &registry),
+        curves: pulse_curve.into_iter().chain(parse_curves(&output_args.velocity_curves, &registry)).collect(),
+        note_offs: parse_note_offs(&output_args.note_off_velocities, output_args.zero_velocity_note_offs, &registry),
+        high_resolution_velocity: output_args.high_resolution_velocity,
+        muted: muted_parts(output_args, &parts, &registry),
+        names: TrackNames::new(&parse_track_names(&output_args.track_names, &registry), None, &registry),
+        patterns,
+        registry,
+        time_signature: parse_time_signature(&output_args.time_signature),
+        tempo: output_args.tempo,
+        bass: output_args.follow_kick_drum_with_bass,
+        target: output_args.target,
+        ppqn: output_args.ppqn,
+        alignment: output_args.alignment,
+        overlap: output_args.overlap,
+        limits: Limits {
+            max_bars: output_args.max_bars,
+            max_events: output_args.max_events,
+            on_limit: output_args.on_limit,
+        },
+        clave: output_args.clave,
+        allow_silence: output_args.allow_silence,
+    }
+}
+
+/// Adds the patterns to the MIDI file `into`, bar 1 of them at position `at` of it. They're
+/// written in the resolution and the time signature of the file there, to its tempo.
+fn merge_into(patterns: BTreeMap<DrumPart, String>, registry: PartRegistry, into: &str, at: Position, output_args: OutputArgs) {
+    let bytes = match fs::read(into) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to read {}: {}", into, e)
+        }
+    };
+    let song = match Smf::parse(&bytes) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Can't parse {}: {}", into, e)
+        }
+    };
+    let map = match SongMap::read(&song) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}, exiting...", e)
+        }
+    };
+    let tick = map.tick_of(at);
+    let template = output_args.output.as_deref().map(output_template);
+    let mut spec = spec_of(patterns, registry, &output_args);
+    if !spec.modulations.is_empty() {
+        warn!("the drums follow the tempo of {}, --modulate doesn't apply", into);
+        spec.modulations.clear();
+    }
+    spec.tempo = 60_000_000.0 / map.tempos.tempo_at(Tick(tick as u128)).microseconds() as f64;
+    spec.time_signature = map.time_signature_at(tick);
+    spec.ppqn = Some(map.ppqn);
+    let text = description(&spec.patterns, &spec.registry);
+    let (drums, bars) = match spec::render(&spec, &text) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}, exiting...", e)
+        }
+    };
+    say!("Adding {} bars of {} drums at tick {} of {}", bars, spec.time_signature, tick, into);
+    let end = tick + bars as u64 * bar_length(spec.time_signature, map.ppqn).0 as u64;
+    if map.changes_signature(tick, end) {
+        warn!("the time signature of {} changes under the drums, they stay in {}", into, spec.time_signature);
+    }
+    let merged = match merge(song, drums, tick) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}, exiting...", e)
+        }
+    };
+    let output = output_args.output.zip(template).map(|(path, template)| {
+        let metadata = Metadata {
+            name: output_name(&parse_track_names(&output_args.track_names, &spec.registry), output_args.source.as_deref()),
+            tempo: spec.tempo,
+            signature: spec.time_signature,
+            bars,
+            ppqn: map.ppqn,
+            target: spec.target,
+        };
+        fill_output_path(path, &template, &metadata)
+    });
+    let summary = |files: Vec<&str>| {
+        vec![
+            ("bars", Json::Number(bars as f64)),
+            ("tick", Json::Number(tick as f64)),
+            ("ppqn", Json::Number(map.ppqn as f64)),
+            ("files", Json::Array(files.into_iter().map(|f| Json::String(f.to_string())).collect())),
+        ]
+    };
+    match output.as_deref().filter(|_| !output_args.dry_run) {
+        None if output_args.dry_run => {
+            say!("Running a dry run, nothing is written...");
+            summarize(summary(Vec::new()))
+        }
+        None => {
+            say!("No output file path was supplied, running a dry run...");
+            summarize(summary(Vec::new()))
+        }
+        Some("-") => {
+            if let Err(e) = stream::write(&merged, BufWriter::new(io::stdout().lock())) {
+                fail!("Failed to write to stdout: {}", e)
+            }
+            summarize(summary(vec!["-"]))
+        }
+        Some(path) => match stream::save(&merged, path) {
+            Ok(_) => {
+                say!("{} was written successfully", path);
+                summarize(summary(vec![path]))
+            }
+            Err(e) => {
+                fail!("Failed to write {}: {}", path, e)
+            }
+        },
+    }
+}
+
+/// A duration like `0:08`, `1:02:03`, `3 days 4:05:06` or, for cycles that outlast any
+/// listener, `2.5e12 years`.
+fn clock(seconds: f64) -> String {
+    const DAY: f64 = 86400.0;
+    const YEAR: f64 = 365.25 * DAY;
+    if seconds >= 1000.0 * YEAR {
+        return format!("{:.1e} years", seconds / YEAR);
+    }
+    if seconds >= YEAR {
+        return format!("{:.1} years", seconds / YEAR);
+    }
+    let total = seconds.round() as u64;
+    let (days, hours, minutes, secs) = (total / 86400, total / 3600 % 24, total / 60 % 60, total % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}:{:02}", minutes, secs),
+        (0, h) => format!("{}:{:02}:{:02}", h, minutes, secs),
+        (d, h) => format!("{} day{} {}:{:02}:{:02}", d, if d == 1 { "" } else { "s" }, h, minutes, secs),
+    }
+}
+
+/// The placeholders of an output path, `-` has none.
+fn output_template(path: &str) -> Template {
+    match path {
+        "-" => Template::from_str("").unwrap(),
+        path => Template::from_str(path).unwrap_or_else(|e| {
+            fail!("Can't name the output file: {}", e)
+        }),
+    }
+}
+
+/// What `{name}` of an output path stands for: the name of the drum track, the file the
+/// patterns are read from or `poly`.
+fn output_name(track_names: &[TrackName], source: Option<&str>) -> String {
+    let drums = track_names.iter().rev().find(|n| n.part.is_none()).map(|n| n.name.clone());
+    let stem = source.filter(|s| *s != "-").and_then(|s| Path::new(s).file_stem()).map(|s| s.to_string_lossy().into_owned());
+    drums.or(stem).unwrap_or_else(|| "poly".to_string())
+}
+
+/// The output path with its placeholders filled in, telling what it became if it had any.
+fn fill_output_path(path: String, template: &Template, metadata: &Metadata) -> String {
+    if path == "-" {
+        return path;
+    }
+    let filled = template.fill(metadata);
+    if !template.is_plain() {
+        tracing::info!("{} is written to {}", path, filled);
+    }
+    filled
+}
+
+/// Path of the file of a part next to `path`, `out.kick.mid` for `out.mid`.
+fn part_file_path(path: &str, part: &str) -> String {
+    let file = Path::new(path);
+    let extension = file.extension().and_then(|e| e.to_str()).unwrap_or("mid");
+    file.with_extension(format!("{}.{}", part, extension)).to_string_lossy().into_owned()
+}
+
+/// Checks the pattern files of `inputs` and reports what's wrong with each of them, whether
+/// they all can be written.
+/// Bytes of a MIDI file, of stdin for `-`.
+fn read_midi(input: &str) -> Vec<u8> {
+    let bytes = if input == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+    } else {
+        fs::read(input)
+    };
+    match bytes {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to read {}: {}", input, e)
+        }
+    }
+}
+
+fn parse_midi<'a>(input: &str, bytes: &'a [u8]) -> Smf<'a> {
+    match Smf::parse(bytes) {
+        Ok(smf) => smf,
+        Err(e) => {
+            fail!("Can't parse MIDI file {}: {}", input, e)
+        }
+    }
+}
+
+fn check_files(inputs: &[String], time_signature: TimeSignature, alignment: Alignment, limits: &Limits, custom_parts: &[CustomPart]) -> bool {
+    let json = JSON.load(Ordering::Relaxed);
+    let (mut errors, mut warnings) = (0, 0);
+    for path in inputs {
+        let file = match read_input(path).map_err(|e| e.to_string()).map(|contents| PatternFile::from_str(&contents)) {
+            Ok(Ok(file)) => file,
+            Ok(Err(e)) => {
+                errors += 1;
+                match json {
+                    true => problem("error", &e, &[("file", path)]),
+                    false => say!("Failed to parse {}:\n{}", path, e),
+                }
+                continue;
+            }
+            Err(e) => {
+                errors += 1;
+                match json {
+                    true => report("error", &e, vec![("file".to_string(), Json::String(path.clone()))]),
+                    false => say!("Failed to read {}: {}", path, e),
+                }
+                continue;
+            }
+        };
+        let registry = declare_parts(file.registry, custom_parts);
+        let (problems, converges) = check(&file.parts, time_signature, alignment, limits);
+        for p in problems.iter() {
+            let level = if p.is_error() { "error" } else { "warning" };
+            match p.is_error() {
+                true => errors += 1,
+                false => warnings += 1,
+            }
+            let part = p.part().map(|part| registry.name(part)).unwrap_or_default();
+            let mut fields = vec![("file", path.as_str())];
+            if !part.is_empty() {
+                fields.push(("part", part.as_str()));
+            }
+            match p {
+                // Errors of parsing say what they are themselves.
+                Problem::Malformed(..) if !json => say!("{}: {}", path, p),
+                _ if !json => say!("{}: {}: {}", path, level, p),
+                Problem::Malformed(_, e) | Problem::Misaligned(_, e, _) => problem(level, e, &fields),
+                p => report(level, &p.to_string(), fields.iter().map(|(k, v)| (k.to_string(), Json::String(v.to_string()))).collect()),
+            }
+        }
+        if let Some(c) = converges {
+            say!("{}: converges over {} bar{}", path, c, if c == 1 { "" } else { "s" });
+        }
+    }
+    summarize(vec![
+        ("files", Json::Number(inputs.len() as f64)),
+        ("errors", Json::Number(errors as f64)),
+        ("warnings", Json::Number(warnings as f64)),
+    ]);
+    errors == 0
+}
+
+/// Plays the patterns in a loop to a raw MIDI `device`, following the Link session of the
+/// Carabiner at `link` if it's given, and sending MIDI clock with `send_clock`.
+#[allow(clippy::too_many_arguments)]
+fn play(
+    patterns: BTreeMap<DrumPart, String>,
+    registry: PartRegistry,
+    device: &str,
+    rounds: Option<u32>,
+    link: Option<String>,
+    send_clock: bool,
+    clock_in: Option<String>,
+    output_args: OutputArgs,
+) {
+    let spec = spec_of(patterns, registry, &output_args);
+    let time_signature = spec.time_signature;
+    let looped = match Loop::from_spec(&spec) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}, exiting...", e)
+        }
+    };
+    let (sender, mut updates) = channel();
+    // The tempo of a loop following nothing else is set from the keyboard.
+    let tapping = link.is_none() && clock_in.is_none();
+    let mut clock = Clock {
+        tempo: spec.tempo,
+        beat: 0.0,
+        at: Instant::now(),
+    };
+    let mut from = 0.0;
+    if let Some(address) = link {
+        updates = match link::follow(&address) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("Failed to reach Carabiner at {}: {}, exiting...", address, e)
+            }
+        };
+        clock = match updates.recv_timeout(Duration::from_secs(2)) {
+            Ok(Update::Sync(x)) => x,
+            _ => {
+                fail!("Carabiner at {} didn't report its Link session, exiting...", address)
+            }
+        };
+        // Starts on the next bar of the session.
+        let bar = time_signature.to_128th() as f64 / 32.0;
+        from = (clock.beat_at(Instant::now()) / bar).ceil() * bar;
+        say!("Joined the Link session at {:.2} BPM", clock.tempo);
+    }
+    if let Some(input) = clock_in {
+        updates = match midi_clock::follow(&input) {
+            Ok(x) => x,
+            Err(e) => {
+                fail!("Failed to open {}: {}, exiting...", input, e)
+            }
+        };
+        // Stands still a pulse before the loop until the sequencer starts.
+        clock.tempo = 0.0;
+        clock.beat = -1.0 / 24.0;
+        say!("Waiting for the clock of {}", input);
+    }
+    let out: Box<dyn Write> = if device == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        match fs::OpenOptions::new().write(true).open(device) {
+            Ok(x) => Box::new(x),
+            Err(e) => {
+                fail!("Failed to open {}: {}", device, e)
+            }
+        }
+    };
+    let mut mutes = Mutes::new(looped.parts(), &spec.muted);
+    let muted = mutes.muted();
+    if io::stdin().is_terminal() {
+        let mut control = TempoControl::new(spec.tempo);
+        let registry = spec.registry.clone();
+        thread::spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let Ok(line) = line else { break };
+                let update = match line.trim().split_once(' ') {
+                    Some((command @ ("m" | "s"), name)) => {
+                        let part = match name.trim() {
+                            "bass" => Ok(Part::Bass),
+                            name => registry.part(name).map(Part::Drum),
+                        };
+                        let muted = part.and_then(|part| if command == "m" { mutes.toggle(part) } else { mutes.solo(part) });
+                        match muted {
+                            Ok(muted) => Some(Update::Mute(muted)),
+                            Err(e) => {
+                                say!("{}", e);
+                                None
+                            }
+                        }
+                    }
+                    _ if tapping => control.line(&line, Instant::now()).map(|tempo| {
+                        say!("{:.1} BPM", tempo);
+                        Update::Tempo(tempo)
+                    }),
+                    _ => None,
+                };
+                if let Some(update) = update {
+                    if sender.send(update).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        if tapping {
+            say!("Press Enter to tap the tempo, type + or - and Enter to nudge it");
+        }
+        say!("Type m and a part, like m kick, to mute or unmute it, s and a part to solo it");
+    }
+    say!("Playing a loop of {} quarter notes to {}", looped.beats(), device);
+    let until = rounds.map(|r| from + r as f64 * looped.beats());
+    let player = Player::new(&looped, out, clock, from).and_then(|mut player| {
+        player.update(Update::Mute(muted))?;
+        if send_clock {
+            player.send_clock()?;
+        }
+        Ok(player)
+    });
+    if let Err(e) = player.and_then(|player| run(player, &updates, until)) {
+        fail!("Failed to play to {}: {}", device, e)
+    }
+}
+
+/// Edits a pattern file on a grid of steps until `q` is pressed, `p` plays it in a loop to a
+/// device and `w` writes it back.
+fn edit(input: &str, grid: Grid, parts: &[String], device: Option<&str>, output_args: OutputArgs) {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        fail!("poly edit runs in a terminal, exiting...")
+    }
+    if input == "-" {
+        fail!("poly edit writes the patterns back to a file, give it one, exiting...")
+    }
+    let file = if Path::new(input).exists() {
+        read_pattern_file(input)
+    } else {
+        PatternFile::default()
+    };
+    let registry = declare_parts(file.registry.clone(), &output_args.custom_parts);
+    let mut parsed = BTreeMap::new();
+    for (part, pattern) in file.parts.iter() {
+        validate_and_parse_part(Some(pattern.clone()), *part, &registry, &mut parsed);
+    }
+    let mut patterns = BTreeMap::new();
+    for (part, pattern) in parsed {
+        if !pattern.pickup.0.is_empty() || pattern.fit.is_some() || pattern.fills {
+            fail!("{} pattern should be a plain groove to be edited on a grid, without a pickup, a fit or a fill, exiting...", registry.title(part))
+        }
+        patterns.insert(part, pattern.groups);
+    }
+    let parts: Vec<DrumPart> = parts.iter().map(|name| resolve_part(name, &registry)).collect();
+    let time_signature = parse_time_signature(&output_args.time_signature);
+    let mut editor = match Editor::new(&patterns, &parts, grid, time_signature, &registry) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}, try another --subdivision, exiting...", e)
+        }
+    };
+    let audition = device.map(|device| match fs::OpenOptions::new().write(true).open(device) {
+        Ok(out) => Audition::start(out, output_args.tempo),
+        Err(e) => {
+            fail!("Failed to open {}: {}", device, e)
+        }
+    });
+    let looped = |editor: &Editor| Loop::from_spec(&spec_of(editor.patterns(), registry.clone(), &output_args));
+    let mut terminal = match Terminal::open() {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("Failed to take over the terminal: {}, exiting...", e)
+        }
+    };
+    let keys = terminal.keys();
+    loop {
+        if let Err(e) = terminal.draw(&editor.render(terminal.width())) {
+            drop(terminal);
+            fail!("Failed to draw the editor: {}, exiting...", e)
+        }
+        let Ok(key) = keys.recv() else { break };
+        let played = match (editor.key(key), &audition) {
+            (Action::Quit, _) => break,
+            (Action::Save, _) => {
+                let contents = PatternFile { parts: editor.patterns(), ..file.clone() }.to_string();
+                editor.status = match fs::write(input, contents) {
+                    Ok(()) => format!("{} was written successfully", input),
+                    Err(e) => format!("Failed to write {}: {}", input, e),
+                };
+                Ok(())
+            }
+            (Action::Play, None) => Err("Give --device to play the patterns to".to_string()),
+            (Action::Play, Some(audition)) if editor.playing => {
+                editor.playing = false;
+                audition.stop()
+            }
+            (Action::Play, Some(audition)) => {
+                editor.playing = true;
+                looped(&editor).and_then(|looped| audition.play(looped))
+            }
+            (Action::Changed, Some(audition)) if editor.playing => looped(&editor).and_then(|looped| audition.play(looped)),
+            _ => Ok(()),
+        };
+        if let Err(e) = played {
+            // The loop of the patterns before the change shouldn't carry on.
+            if let Some(audition) = audition.as_ref().filter(|_| editor.playing) {
+                let _ = audition.stop();
+            }
+            editor.playing = false;
+            editor.status = e;
+        }
+    }
+}
+
+/// The command line with the settings of the configuration files as the defaults of its flags,
+/// in every subcommand that has them.
+fn with_config(mut command: clap::Command, defaults: &[(&'static str, String)]) -> clap::Command {
+    for (flag, value) in defaults {
+        let id = flag.replace('-', "_");
+        if command.get_arguments().any(|arg| arg.get_id() == id.as_str()) {
+            // Read once per run, leaking them gives clap the static strings it wants.
+            let value: &'static str = Box::leak(value.clone().into_boxed_str());
+            command = command.mut_arg(id, |arg| arg.default_value(value));
+        }
+    }
+    let subcommands: Vec<String> = command.get_subcommands().map(|c| c.get_name().to_string()).collect();
+    for name in subcommands {
+        command = command.mut_subcommand(name, |c| with_config(c, defaults));
+    }
+    command
+}
+
+/// Flags of `OutputArgs` a subcommand has no use for, and why. They're hidden from its help
+/// and refused on its command line.
+const UNUSED_FLAGS: &[(&str, &str, &[&str])] = &[
+    (
+        "merge",
+        "the drums follow the tempo, the time signatures and the resolution of the song and go into a single file",
+        &["tempo", "modulate", "time_signature", "ppqn", "optimize_size", "timing", "key_map", "musicxml", "gp", "dawproject", "events_json", "split_parts", "print_events"],
+    ),
+    (
+        "play",
+        "the patterns are played rather than written",
+        &[
+            "modulate", "output", "output_dir", "optimize_size", "timing", "key_map", "musicxml", "gp", "dawproject", "events_json", "track_names", "split_parts",
+            "print_events", "dry_run",
+        ],
+    ),
+    (
+        "edit",
+        "the patterns are played rather than written",
+        &[
+            "modulate", "output", "output_dir", "optimize_size", "timing", "key_map", "musicxml", "gp", "dawproject", "events_json", "track_names", "split_parts",
+            "print_events", "dry_run",
+        ],
+    ),
+];
+
+/// The command line without the flags its subcommands have no use for in their help.
+fn without_unused(mut command: clap::Command) -> clap::Command {
+    for (name, _, flags) in UNUSED_FLAGS {
+        command = command.mut_subcommand(*name, |mut subcommand| {
+            for flag in flags.iter() {
+                subcommand = subcommand.mut_arg(*flag, |arg| arg.hide(true));
+            }
+            subcommand
+        });
+    }
+    command
+}
+
+/// Fails on a flag given to a subcommand that has no use for it, rather than ignoring it.
+fn refuse_unused(command: &clap::Command, matches: &ArgMatches) {
+    let Some((name, arguments)) = matches.subcommand() else {
+        return;
+    };
+    let Some((_, why, flags)) = UNUSED_FLAGS.iter().find(|(n, _, _)| *n == name) else {
+        return;
+    };
+    for flag in flags.iter() {
+        if arguments.value_source(flag) == Some(parser::ValueSource::CommandLine) {
+            let long = command.find_subcommand(name).and_then(|c| c.get_arguments().find(|a| a.get_id() == *flag)).and_then(|a| a.get_long()).unwrap_or(flag);
+            fail!("--{} doesn't apply to poly {}, {}, exiting...", long, name, why)
+        }
+    }
+}
+
+/// Puts relative output paths into `--output-dir`, creating it when something is written there.
+fn in_output_dir(output: &mut OutputArgs) {
+    let Some(dir) = output.output_dir.as_deref() else {
+        return;
+    };
+    let mut written = false;
+    for path in [&mut output.output, &mut output.key_map, &mut output.musicxml, &mut output.gp, &mut output.dawproject, &mut output.events_json].into_iter().flatten() {
+        if path != "-" && Path::new(path.as_str()).is_relative() {
+            *path = Path::new(dir).join(path.as_str()).to_string_lossy().into_owned();
+            written = true;
+        }
+    }
+    if written {
+        if let Err(e) = fs::create_dir_all(dir) {
+            fail!("Failed to create {}: {}", dir, e)
+        }
+    }
+}
+
+fn main() {
+    let config = match Config::load(&Config::paths()) {
+        Ok(x) => x,
+        Err(e) => {
+            fail!("{}", e)
+        }
+    };
+    let command = without_unused(with_config(Cli::command(), &config.defaults()));
+    let matches = command.clone().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match &mut cli.command {
+        Some(
+            Command::Ratio { output, .. }
+            | Command::Family { output, .. }
+            | Command::Hemiola { output, .. }
+            | Command::Ladder { output, .. }
+            | Command::Vary { output, .. }
+            | Command::Displace { output, .. }
+            | Command::Learn { output, .. }
+            | Command::Play { output, .. }
+            | Command::Edit { output, .. }
+            | Command::Merge { output, .. }
+            | Command::Extract { output, .. }
+            | Command::Evolve { output, .. },
+        ) => in_output_dir(output),
+        Some(Command::Align { .. } | Command::Dump { .. } | Command::Cmp { .. } | Command::Check { .. }) | None => in_output_dir(&mut cli.output),
+    }
+    let output_args = match &cli.command {
+        Some(
+            Command::Ratio { output, .. }
+            | Command::Family { output, .. }
+            | Command::Hemiola { output, .. }
+            | Command::Ladder { output, .. }
+            | Command::Vary { output, .. }
+            | Command::Displace { output, .. }
+            | Command::Learn { output, .. }
+            | Command::Play { output, .. }
+            | Command::Edit { output, .. }
+            | Command::Merge { output, .. }
+            | Command::Extract { output, .. }
+            | Command::Evolve { output, .. },
+        ) => output,
+        Some(Command::Align { .. } | Command::Dump { .. } | Command::Cmp { .. } | Command::Check { .. }) | None => &cli.output,
+    };
+    let check_json = matches!(&cli.command, Some(Command::Check { json: true, .. }));
+    JSON.store(output_args.json || check_json, Ordering::Relaxed);
+    let playing_to_stdout = matches!(&cli.command, Some(Command::Play { device, .. }) if device == "-");
+    let export_to_stdout = output_args.key_map.as_deref() == Some("-")
+        || output_args.musicxml.as_deref() == Some("-")
+        || output_args.gp.as_deref() == Some("-")
+        || output_args.dawproject.as_deref() == Some("-")
+        || output_args.events_json.as_deref() == Some("-");
+    PIPED.store(output_args.output.as_deref() == Some("-") || playing_to_stdout || export_to_stdout, Ordering::Relaxed);
+    refuse_unused(&command, &matches);
+    let level = match output_args.verbose {
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
+    };
+    tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr)
+        .with_target(false)
+        .without_time()
+        .init();
+    let Cli {
+        command,
+        kick,
+        snare,
+        hihat,
+        crash,
+        cowbell,
+        claves,
+        conga_open,
+        conga_slap,
+        conga_mute,
+        bongo_high,
+        bongo_low,
+        shaker,
+        tambourine,
+        patterns,
+        implied_subdivision,
+        output,
+    } = cli;
+    match command {
+        Some(Command::Ratio {
+            ratio,
+            subdivision,
+            parts,
+            output,
+        }) => {
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let parts = parts.iter().map(|p| resolve_part(p, &registry)).collect();
+            let patterns = ratio_patterns(&ratio, subdivision, parts, &registry);
+            for (part, pattern) in patterns.iter() {
+                say!("{} - {}", registry.title(*part), pattern);
+            }
+            render(patterns, &registry, output)
+        }
+        Some(Command::Family {
+            max,
+            subdivision,
+            parts,
+            output,
+        }) => {
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let parts = parts.iter().map(|p| resolve_part(p, &registry)).collect();
+            render_family(max, subdivision, parts, registry, output)
+        }
+        Some(Command::Hemiola {
+            grouping,
+            subdivision,
+            part,
+            pulse,
+            output,
+        }) => {
+            let cross_rhythm = CrossRhythm {
+                grouping,
+                subdivision: parse_subdivision(subdivision),
+            };
+            let time_signature = parse_time_signature(&output.time_signature);
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let part = resolve_part(&part, &registry);
+            let pulse = pulse.map(|p| resolve_part(&p, &registry));
+            let patterns = hemiola_patterns(cross_rhythm, part, pulse, time_signature, &registry);
+            for (part, pattern) in patterns.iter() {
+                say!("{} - {}", registry.title(*part), pattern);
+            }
+            render(patterns, &registry, output)
+        }
+        Some(Command::Ladder {
+            rungs,
+            bars,
+            part,
+            pulse,
+            output,
+        }) => {
+            let time_signature = parse_time_signature(&output.time_signature);
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let part = resolve_part(&part, &registry);
+            let pulse = resolve_part(&pulse, &registry);
+            let patterns = ladder_patterns(Ladder { rungs, bars }, part, pulse, time_signature, &registry);
+            render(patterns, &registry, output)
+        }
+        Some(Command::Align {
+            voices,
+            subdivision,
+            width,
+            animate,
+            tempo,
+        }) => show_pulses(voices, subdivision, width, animate, tempo),
+        Some(Command::Vary {
+            input,
+            amount,
+            seed,
+            mut output,
+        }) => {
+            if !(0.0..=1.0).contains(&amount) {
+                fail!("Amount should be between 0 and 1, exiting...")
+            }
+            let time_signature = parse_time_signature(&output.time_signature);
+            let file = read_pattern_file(&input);
+            let registry = declare_parts(file.registry.clone(), &output.custom_parts);
+            name_tracks(&file.names, &registry, &mut output);
+            add_modulations(&file.modulations, &mut output);
+            output.source = Some(input);
+            let (names, modulations, macros) = (file.names.clone(), file.modulations.clone(), file.macros.clone());
+            let seed = fixed_seed(seed, &output);
+            let patterns = vary_patterns(file, amount, seed, time_signature, &registry);
+            say!("{}", PatternFile { parts: patterns.clone(), registry: registry.clone(), names, modulations, macros }.to_string().trim_end());
+            render(patterns, &registry, output)
+        }
+        Some(Command::Displace {
+            input,
+            subdivision,
+            repeats,
+            mut output,
+        }) => {
+            let file = read_pattern_file(&input);
+            let registry = declare_parts(file.registry.clone(), &output.custom_parts);
+            name_tracks(&file.names, &registry, &mut output);
+            add_modulations(&file.modulations, &mut output);
+            output.source = Some(input);
+            render_displacements(file, subdivision, repeats, registry, output)
+        }
+        Some(Command::Learn {
+            input,
+            bars,
+            order,
+            seed,
+            mut output,
+        }) => {
+            let time_signature = parse_time_signature(&output.time_signature);
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let seed = fixed_seed(seed, &output);
+            output.source = Some(input.clone());
+            let patterns = learn_patterns(&input, bars, order, seed, time_signature);
+            say!("{}", PatternFile { parts: patterns.clone(), registry: registry.clone(), ..Default::default() }.to_string().trim_end());
+            render(patterns, &registry, output)
+        }
+        Some(Command::Dump {
+            input,
+            custom_parts,
+            bass_track,
+            layers,
+        }) => {
+            let mut registry = declare_parts(PartRegistry::default(), &custom_parts);
+            add_layers(&mut registry, &layers);
+            let bytes = read_midi(&input);
+            print!("{}", dump_events(&parse_midi(&input, &bytes), &registry, &bass_track))
+        }
+        Some(Command::Cmp { expected, actual }) => {
+            let (expected_bytes, actual_bytes) = (read_midi(&expected), read_midi(&actual));
+            let differences = compare(&parse_midi(&expected, &expected_bytes), &parse_midi(&actual, &actual_bytes));
+            if differences.is_empty() {
+                say!("{} and {} play the same", expected, actual);
+                return;
+            }
+            for difference in differences.iter() {
+                println!("{}", difference);
+            }
+            fail!("{} and {} differ in {} place(s)", expected, actual, differences.len())
+        }
+        Some(Command::Play {
+            input,
+            device,
+            rounds,
+            link,
+            send_clock,
+            clock_in,
+            mut output,
+        }) => {
+            let file = read_pattern_file(&input);
+            let registry = declare_parts(file.registry.clone(), &output.custom_parts);
+            name_tracks(&file.names, &registry, &mut output);
+            play(file.parts, registry, &device, rounds, link, send_clock, clock_in, output)
+        }
+        Some(Command::Edit {
+            input,
+            subdivision,
+            parts,
+            device,
+            output,
+        }) => edit(&input, subdivision, &parts, device.as_deref(), output),
+        Some(Command::Merge {
+            input,
+            into,
+            at,
+            mut output,
+        }) => {
+            let file = read_pattern_file(&input);
+            let registry = declare_parts(file.registry.clone(), &output.custom_parts);
+            name_tracks(&file.names, &registry, &mut output);
+            output.source = Some(input);
+            merge_into(file.parts, registry, &into, at, output)
+        }
+        Some(Command::Extract {
+            input,
+            channel,
+            subdivision,
+            quantize,
+            quantized,
+            mut output,
+        }) => {
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let grids = parse_grids(&subdivision, &registry);
+            output.source = Some(input.clone());
+            let patterns = extract_patterns(&input, channel, &grids, quantize, quantized.as_deref(), &mut output);
+            say!("{}", PatternFile { parts: patterns.clone(), registry: registry.clone(), ..Default::default() }.to_string().trim_end());
+            render(patterns, &registry, output)
+        }
+        Some(Command::Evolve {
+            parts,
+            density,
+            min_syncopation,
+            max_syncopation,
+            bars,
+            subdivision,
+            population,
+            generations,
+            top,
+            seed,
+            output,
+        }) => {
+            let time_signature = parse_time_signature(&output.time_signature);
+            let subdivision = parse_subdivision(subdivision);
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let parts: Vec<DrumPart> = parts.iter().map(|p| resolve_part(p, &registry)).collect();
+            let target = Target {
+                density,
+                syncopation: (min_syncopation, max_syncopation),
+                bars,
+            };
+            // Patterns up to two bars long leave enough room to converge over many bars.
+            let max_steps = (2 * time_signature.to_128th() / subdivision.to_128th()).max(1) as usize;
+            let settings = Settings {
+                population,
+                generations,
+                subdivision,
+                max_steps,
+            };
+            let candidates = evolve(&parts, time_signature, &target, &settings, &mut seeded_rng(fixed_seed(seed, &output)));
+            for (i, candidate) in candidates.iter().take(top.max(1)).enumerate() {
+                say!("# Candidate {}, fitness {:.3}", i + 1, candidate.fitness);
+                let patterns = candidate.parts.iter().map(|(p, g)| (*p, g.to_string())).collect();
+                say!("{}", PatternFile { parts: patterns, registry: registry.clone(), ..Default::default() }.to_string().trim_end());
+            }
+            let best = candidates[0].parts.iter().map(|(p, g)| (*p, g.to_string())).collect();
+            render(best, &registry, output)
+        }
+        Some(Command::Check {
+            inputs,
+            time_signature,
+            alignment,
+            max_bars,
+            max_events,
+            custom_parts,
+            json: _,
+        }) => {
+            let limits = Limits {
+                max_bars,
+                max_events,
+                on_limit: OnLimit::Fail,
+            };
+            if !check_files(&inputs, parse_time_signature(&time_signature), alignment, &limits, &custom_parts) {
+                exit(1)
+            }
+        }
+        None => {
+            let registry = declare_parts(PartRegistry::default(), &output.custom_parts);
+            let mut flags: BTreeMap<DrumPart, String> = [
+                (KickDrum, kick),
+                (SnareDrum, snare),
+                (HiHat, hihat),
+                (CrashCymbal, crash),
+                (Cowbell, cowbell),
+                (Claves, claves),
+                (CongaOpen, conga_open),
+                (CongaSlap, conga_slap),
+                (CongaMute, conga_mute),
+                (BongoHigh, bongo_high),
+                (BongoLow, bongo_low),
+                (Shaker, shaker),
+                (Tambourine, tambourine),
+            ]
+            .into_iter()
+            .filter_map(|(part, pattern)| pattern.map(|p| (part, p)))
+            .collect();
+            for arg in patterns {
+                let Some((name, pattern)) = arg.split_once('=') else {
+                    fail!("Expected a pattern like part=pattern, got {}, exiting...", arg)
+                };
+                let part = resolve_part(name, &registry);
+                if flags.insert(part, pattern.to_string()).is_some() {
+                    fail!("{} has more than one pattern, exiting...", registry.title(part))
+                }
+            }
+            if let Some(grid) = implied_subdivision {
+                for (part, pattern) in flags.iter_mut().filter(|(_, p)| onsets::is_onsets(p)) {
+                    match onsets::parse_onsets(pattern, grid.step_length()) {
+                        Ok(groups) => *pattern = groups.to_string(),
+                        Err(e) => fail!("{} pattern is malformed.\n{}", registry.title(*part), e),
+                    }
+                }
+            }
+            if flags.is_empty() && !io::stdin().is_terminal() {
+                // Patterns piped in are read like a pattern file.
+                let file = read_pattern_file("-");
+                let registry = declare_parts(file.registry, &output.custom_parts);
+                if !file.parts.is_empty() {
+                    let mut output = output;
+                    name_tracks(&file.names, &registry, &mut output);
+                    add_modulations(&file.modulations, &mut output);
+                    return render(file.parts, &registry, output);
+                }
+            }
+            if flags.is_empty() {
+                fail!("No drum pattern was supplied, exiting...")
+            } else {
+                render(flags, &registry, output)
             }
         }
     }
@@ -1,15 +1,20 @@
+use std::fmt;
 use std::num::ParseIntError;
 use std::ops::Add;
 use std::str::{self, FromStr};
 use std::vec::Vec;
 
 use nom::branch::alt;
-pub use nom::character::complete::{char, digit1};
-use nom::multi::many1;
-use nom::sequence::{delimited, separated_pair, tuple};
+pub use nom::character::complete::{char, digit1, one_of};
+use nom::multi::{many0, many1, separated_list1};
+use nom::sequence::{delimited, preceded, separated_pair, tuple};
 use nom::{Err, IResult};
 
-use nom::combinator::{all_consuming, map, map_res};
+use nom::combinator::{all_consuming, map, map_res, opt, peek, recognize, verify};
+
+use crate::dsl::pitch::{Degree, Pitch, NOTE_NAMES};
+use crate::error::{Error, ParseError};
+use crate::random::{Rng, GOLDEN_GAMMA};
 
 /// Allows measurement in 128th notes.
 pub trait KnownLength {
@@ -25,6 +30,7 @@ pub enum BasicLength {
     Sixteenth,
     ThirtySecond,
     SixtyFourth,
+    OneHundredTwentyEighth,
 }
 
 impl FromStr for BasicLength {
@@ -39,6 +45,22 @@ impl FromStr for BasicLength {
     }
 }
 
+impl fmt::Display for BasicLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let n = match self {
+            BasicLength::Whole => 1,
+            BasicLength::Half => 2,
+            BasicLength::Fourth => 4,
+            BasicLength::Eighth => 8,
+            BasicLength::Sixteenth => 16,
+            BasicLength::ThirtySecond => 32,
+            BasicLength::SixtyFourth => 64,
+            BasicLength::OneHundredTwentyEighth => 128,
+        };
+        write!(f, "{}", n)
+    }
+}
+
 impl KnownLength for BasicLength {
     fn to_128th(&self) -> u32 {
         match self {
@@ -49,6 +71,7 @@ impl KnownLength for BasicLength {
             BasicLength::Sixteenth => 8,
             BasicLength::ThirtySecond => 4,
             BasicLength::SixtyFourth => 2,
+            BasicLength::OneHundredTwentyEighth => 1,
         }
     }
 }
@@ -56,6 +79,7 @@ impl KnownLength for BasicLength {
 impl BasicLength {
     pub fn from_num(n: u16) -> Result<Self, String> {
         match n {
+            128 => Ok(BasicLength::OneHundredTwentyEighth),
             64 => Ok(BasicLength::SixtyFourth),
             32 => Ok(BasicLength::ThirtySecond),
             16 => Ok(BasicLength::Sixteenth),
@@ -74,6 +98,7 @@ impl BasicLength {
     /// Reverses `to_128th`.
     fn from_128th(n: u16) -> Result<Self, String> {
         match n {
+            1 => Ok(BasicLength::OneHundredTwentyEighth),
             2 => Ok(BasicLength::SixtyFourth),
             4 => Ok(BasicLength::ThirtySecond),
             8 => Ok(BasicLength::Sixteenth),
@@ -101,6 +126,7 @@ impl Add<BasicLength> for BasicLength {
             BasicLength::Sixteenth => 8,
             BasicLength::ThirtySecond => 4,
             BasicLength::SixtyFourth => 2,
+            BasicLength::OneHundredTwentyEighth => 1,
         };
         if self == rhs && self != BasicLength::Whole {
             Length::Simple(ModdedLength::Plain(
@@ -172,6 +198,13 @@ fn test_add_basic_length() {
 pub enum ModdedLength {
     Plain(BasicLength),
     Dotted(BasicLength),
+    /// Two or more dots, every dot adds half of the previous one: `4..` is 4 + 8 + 16.
+    MultiDotted(BasicLength, u8),
+}
+
+/// `length` with `dots` dots, every dot adds half of what the previous one did.
+pub(crate) fn dotted(length: u32, dots: u8) -> u32 {
+    (0..=dots as u32).map(|i| length.checked_shr(i).unwrap_or(0)).sum()
 }
 
 impl KnownLength for ModdedLength {
@@ -182,6 +215,17 @@ impl KnownLength for ModdedLength {
                 let l = bl.to_128th();
                 l + l / 2
             }
+            ModdedLength::MultiDotted(bl, dots) => dotted(bl.to_128th(), *dots),
+        }
+    }
+}
+
+impl fmt::Display for ModdedLength {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ModdedLength::Plain(bl) => write!(f, "{}", bl),
+            ModdedLength::Dotted(bl) => write!(f, "{}.", bl),
+            ModdedLength::MultiDotted(bl, dots) => write!(f, "{}{}", bl, ".".repeat(*dots as usize)),
         }
     }
 }
@@ -189,6 +233,8 @@ impl KnownLength for ModdedLength {
 #[test]
 fn test_known_length_modded_length() {
     assert_eq!(ModdedLength::Dotted(BasicLength::Eighth).to_128th(), 24);
+    assert_eq!(ModdedLength::MultiDotted(BasicLength::Fourth, 2).to_128th(), 56);
+    assert_eq!(ModdedLength::MultiDotted(BasicLength::Half, 3).to_128th(), 120);
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -196,6 +242,9 @@ pub enum Length {
     Simple(ModdedLength),
     Tied(ModdedLength, ModdedLength),
     Triplet(ModdedLength),
+    /// `n` notes in the time of `m` notes of a length, written `4:7:4` for seven in the time
+    /// of four quarter notes.
+    Tuplet(ModdedLength, u16, u16),
 }
 
 impl KnownLength for Length {
@@ -204,6 +253,30 @@ impl KnownLength for Length {
             Length::Simple(ml) => ml.to_128th(),
             Length::Tied(ml1, ml2) => ml1.to_128th() + ml2.to_128th(),
             Length::Triplet(ml) => ml.to_128th() * 2 / 3,
+            Length::Tuplet(ml, n, m) => ml.to_128th() * *m as u32 / *n as u32,
+        }
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Length::Simple(ml) => write!(f, "{}", ml),
+            Length::Tied(ml1, ml2) => write!(f, "{}+{}", ml1, ml2),
+            Length::Triplet(ml) => write!(f, "{}t", ml),
+            Length::Tuplet(ml, n, m) => write!(f, "{}:{}:{}", ml, n, m),
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    /// Reads a length the way patterns write them, like `8`, `8.`, `8t` or `4+16`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match all_consuming(length)(s.trim()) {
+            Ok((_, length)) => Ok(length),
+            Result::Err(_) => Err(format!("expected a note length like 8, 8., 8t or 4+16, got `{}`", s.trim())),
         }
     }
 }
@@ -214,12 +287,248 @@ fn test_known_length_of_length() {
     let triplet_dotted_eighth = Length::Triplet(dotted_eighth);
     assert_eq!(Length::Tied(dotted_eighth, dotted_eighth).to_128th(), 48);
     assert_eq!(triplet_dotted_eighth.to_128th(), 16);
+    assert_eq!(Length::from_str("8."), Ok(Length::Simple(dotted_eighth)));
+    assert_eq!(Length::from_str("4+8.").map(|l| l.to_string()), Ok("4+8.".to_string()));
+    assert!(Length::from_str("8x").is_err());
+    let septuplet = Length::from_str("4:7:4").unwrap();
+    assert_eq!(septuplet, Length::Tuplet(ModdedLength::Plain(BasicLength::Fourth), 7, 4));
+    assert_eq!(septuplet.to_string(), "4:7:4");
+    assert_eq!(septuplet.span(7), 128);
+    assert!(Length::from_str("4:0:4").is_err());
 }
 
+impl Length {
+    /// Length of a note in 128th notes as a fraction, triplets and tuplets take a part of one.
+    pub fn fraction(&self) -> (u32, u32) {
+        match self {
+            Length::Triplet(ml) => (ml.to_128th() * 2, 3),
+            Length::Tuplet(ml, n, m) => (ml.to_128th() * *m as u32, *n as u32),
+            _ => (self.to_128th(), 1),
+        }
+    }
+
+    /// Length of `count` notes in 128th notes. Triplets and tuplets only add up to a whole
+    /// number of 128ths all together, so they aren't counted note by note.
+    pub fn span(&self, count: u32) -> u32 {
+        let (n, d) = self.fraction();
+        n * count / d
+    }
+
+    /// Length of one of `n` equal parts of a note, if the DSL can write it down.
+    /// Splits in a power of two halve the note, splits in three times that turn it into a triplet.
+    pub fn split(self, n: u8) -> Option<Length> {
+        if n == 0 {
+            return None;
+        }
+        let halvings = n.trailing_zeros();
+        let odd = n >> halvings;
+        let halve = |ml: ModdedLength, times: u32| -> Option<ModdedLength> {
+            let basic = |bl: BasicLength| {
+                bl.to_128th()
+                    .checked_shr(times)
+                    .filter(|l| *l > 0)
+                    .and_then(|l| BasicLength::from_128th(l as u16).ok())
+            };
+            match ml {
+                ModdedLength::Plain(bl) => basic(bl).map(ModdedLength::Plain),
+                // Dots of the halves can't be shorter than a 128th note either.
+                ModdedLength::Dotted(bl) => basic(bl).filter(|bl| bl.to_128th() > 1).map(ModdedLength::Dotted),
+                ModdedLength::MultiDotted(bl, dots) => basic(bl)
+                    .filter(|bl| bl.to_128th().trailing_zeros() >= dots as u32)
+                    .map(|bl| ModdedLength::MultiDotted(bl, dots)),
+            }
+        };
+        match (self, odd) {
+            (Length::Simple(ml), 1) => halve(ml, halvings).map(Length::Simple),
+            (Length::Triplet(ml), 1) => halve(ml, halvings).map(Length::Triplet),
+            (Length::Tied(a, b), 1) => Some(Length::Tied(halve(a, halvings)?, halve(b, halvings)?)),
+            (Length::Simple(ml), 3) => halve(ml, halvings + 1).map(Length::Triplet),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_split_length() {
+    assert_eq!(EIGHTH.split(4), Some(*THIRTY_SECOND));
+    assert_eq!(FOURTH.split(3), Some(*EIGHTH_TRIPLET));
+    assert_eq!(FOURTH.split(6), Some(*SIXTEENTH_TRIPLET));
+    assert_eq!(
+        Length::Simple(ModdedLength::Dotted(BasicLength::Eighth)).split(2),
+        Some(Length::Simple(ModdedLength::Dotted(BasicLength::Sixteenth)))
+    );
+    assert_eq!(EIGHTH_TRIPLET.split(3), None);
+    assert_eq!(FOURTH.split(5), None);
+    assert_eq!(Length::Simple(ModdedLength::Plain(BasicLength::OneHundredTwentyEighth)).split(2), None);
+    assert_eq!(Length::Simple(ModdedLength::Dotted(BasicLength::Eighth)).split(16), None);
+    assert_eq!(Length::Simple(ModdedLength::MultiDotted(BasicLength::Fourth, 2)).split(16), None);
+}
+
+/// Velocity of a plain hit.
+pub const DEFAULT_VELOCITY: u8 = 127;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Note {
     Hit,
     Rest,
+    /// Lets the hit before it ring for one more note, even across groups and repeats.
+    /// Acts as a rest when there's no hit to hold.
+    Tie,
+    /// A hit with its own expression, rolls are made of these.
+    Stroke(Stroke),
+}
+
+impl Note {
+    /// Whether the note is played, as opposed to rests and ties.
+    pub fn is_hit(&self) -> bool {
+        matches!(self, Note::Hit | Note::Stroke(_))
+    }
+
+    /// How the note is played, plain hits are played the default way.
+    pub fn stroke(&self) -> Stroke {
+        match self {
+            Note::Stroke(stroke) => *stroke,
+            _ => Stroke::default(),
+        }
+    }
+}
+
+impl fmt::Display for Note {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Note::Hit => write!(f, "x"),
+            Note::Stroke(stroke) => {
+                write!(f, "{}", stroke.articulation)?;
+                if let Some(pitch) = stroke.pitch {
+                    write!(f, "[{}]", pitch)?;
+                }
+                if stroke.offset != 0 {
+                    write!(f, "[{:+}]", stroke.offset)?;
+                }
+                Ok(())
+            }
+            Note::Rest => write!(f, "-"),
+            Note::Tie => write!(f, "_"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stroke {
+    /// MIDI velocity, from 1 to 127.
+    pub velocity: u8,
+    /// 128ths of a velocity step above `velocity`, or below it if negative, that a MIDI
+    /// velocity of 7 bits has no room for. Written as a high resolution velocity prefix.
+    pub fine: i8,
+    /// Timing offset in 128th notes, written `x[+3]`. Positive drags the hit behind the beat,
+    /// negative pushes it ahead.
+    pub offset: i8,
+    pub articulation: Articulation,
+    /// Note written `x[C2]` or `x[b3]`, played instead of the key of the part.
+    pub pitch: Option<Pitch>,
+}
+
+impl Stroke {
+    /// The velocity with the fraction of a step of `fine`.
+    pub fn precise_velocity(&self) -> f64 {
+        self.velocity as f64 + self.fine as f64 / 128.0
+    }
+}
+
+impl Default for Stroke {
+    fn default() -> Self {
+        Stroke {
+            velocity: DEFAULT_VELOCITY,
+            fine: 0,
+            offset: 0,
+            articulation: Articulation::Normal,
+            pitch: None,
+        }
+    }
+}
+
+/// The way a drum is struck. Only the snare and the hi-hat have other sounds than the normal
+/// one, other parts play a normal hit instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Articulation {
+    /// `x`
+    #[default]
+    Normal,
+    /// `s`, the stick laid across the drum clicks on the rim.
+    SideStick,
+    /// `r`, the stick hits the head and the rim at once.
+    Rimshot,
+    /// `c`, the hi-hat played closed, a plain hit plays it open.
+    Closed,
+}
+
+impl Articulation {
+    /// Velocity of a hit played this way.
+    pub fn default_velocity(self) -> u8 {
+        match self {
+            Articulation::Normal | Articulation::Rimshot | Articulation::Closed => DEFAULT_VELOCITY,
+            Articulation::SideStick => 96,
+        }
+    }
+}
+
+impl fmt::Display for Articulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Articulation::Normal => write!(f, "x"),
+            Articulation::SideStick => write!(f, "s"),
+            Articulation::Rimshot => write!(f, "r"),
+            Articulation::Closed => write!(f, "c"),
+        }
+    }
+}
+
+/// A hit split into equal rapid strokes (a ratchet), written `x*4`.
+/// A trailing `<` or `>` makes the strokes grow or fade in velocity: `x*4<`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Roll {
+    pub strokes: u8,
+    pub ramp: Option<Ramp>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ramp {
+    Crescendo,
+    Decrescendo,
+}
+
+impl Roll {
+    /// Notes the roll expands into, played at `length` each.
+    fn notes(&self) -> Vec<Note> {
+        let n = self.strokes as u32;
+        (1..=n)
+            .map(|i| {
+                let step = match self.ramp {
+                    None => return Note::Hit,
+                    Some(Ramp::Crescendo) => i,
+                    Some(Ramp::Decrescendo) => n + 1 - i,
+                };
+                let precise = DEFAULT_VELOCITY as i32 * step as i32 * 128 / n as i32;
+                let velocity = (precise / 128).max(1);
+                Note::Stroke(Stroke {
+                    velocity: velocity as u8,
+                    fine: (precise - velocity * 128) as i8,
+                    ..Stroke::default()
+                })
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Roll {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "x*{}", self.strokes)?;
+        match self.ramp {
+            None => Ok(()),
+            Some(Ramp::Crescendo) => write!(f, "<"),
+            Some(Ramp::Decrescendo) => write!(f, ">"),
+        }
+    }
 }
 
 #[allow(unused_imports)]
@@ -305,6 +614,96 @@ pub struct Times(pub u16);
 pub enum GroupOrNote<T> {
     SingleGroup(Group<GroupOrNote<T>, T>),
     SingleNote(Note),
+    /// Expanded into strokes by `flatten_group`.
+    SingleRoll(Roll),
+    /// A note or a group that only plays on some repeats of the group it's in and rests on
+    /// the others, written `x@4` or `(16xx)@2,4`. Resolved by `flatten_group`.
+    Volta(Vec<u16>, Box<GroupOrNote<T>>),
+    /// Notes played in turn on the repeats of the group it's in, written `{xx|x-}`: the
+    /// first ones on odd repeats, the second ones on even ones. Notes in them count the times
+    /// they're played. Resolved by `flatten_group`.
+    Alternation(Vec<Vec<GroupOrNote<T>>>),
+    /// Notes picked at random on every repeat of the group it's in, written `{xx,x-:3}?`
+    /// with the weights of the picks, 1 if left out, and `{xx,x-}?7` with a seed. Choices
+    /// without a seed are seeded by how they're written, so a pattern always plays the same.
+    /// Resolved by `flatten_group`.
+    Choice(Vec<(u16, Vec<GroupOrNote<T>>)>, Option<u64>),
+}
+
+impl GroupOrNote<Times> {
+    /// Length in 128th notes of the entry in a group of notes of `length`, as a fraction.
+    fn fraction(&self, length: &Length) -> (u64, u64) {
+        let sum = |entries: &[GroupOrNote<Times>]| entries.iter().map(|entry| entry.fraction(length)).fold((0, 1), add);
+        match self {
+            SingleGroup(group) => group.fraction(),
+            SingleNote(_) | SingleRoll(_) => {
+                let (n, d) = length.fraction();
+                (n as u64, d as u64)
+            }
+            Volta(_, entry) => entry.fraction(length),
+            // What the first repeat plays, the others may take longer or shorter.
+            Alternation(alternatives) => sum(&alternatives[0]),
+            Choice(choices, _) => sum(&choices[0].1),
+        }
+    }
+
+    /// How many repeats of the group the entry is in it takes for it to go through all of
+    /// its turns, 1 if it plays the same every time.
+    fn turns(&self) -> u64 {
+        match self {
+            SingleGroup(group) => group.turns(),
+            Volta(on, entry) => lcm(on.iter().copied().max().unwrap_or(1) as u64, entry.turns()),
+            Alternation(alternatives) => alternatives.len() as u64 * alternatives.iter().flatten().map(|entry| entry.turns()).fold(1, lcm),
+            // Picks never come round, the notes in them count the repeats of the group.
+            Choice(choices, _) => choices.iter().flat_map(|(_, entries)| entries).map(|entry| entry.turns()).fold(1, lcm),
+            SingleNote(_) | SingleRoll(_) => 1,
+        }
+    }
+}
+
+/// Whether an entry playing on the repeats `on` of a group plays on its `repeat`th one,
+/// counted from 1. The turns come round again, `@4` plays on every 4th repeat.
+fn plays_on(on: &[u16], repeat: u64) -> bool {
+    let every = on.iter().copied().max().unwrap_or(1) as u64;
+    on.iter().any(|n| (repeat - 1) % every + 1 == *n as u64)
+}
+
+/// Notes a choice seeded with `seed` picks on the `repeat`th repeat: draw number `repeat` of
+/// a generator with the seed, so every repeat is picked on its own.
+fn pick(choices: &[(u16, Vec<GroupOrNote<Times>>)], seed: u64, repeat: u64) -> &[GroupOrNote<Times>] {
+    let total: u64 = choices.iter().map(|(weight, _)| *weight as u64).sum();
+    let mut draw = Rng::new(seed.wrapping_add(repeat.wrapping_sub(1).wrapping_mul(GOLDEN_GAMMA))).below(total);
+    for (weight, entries) in choices {
+        match draw.checked_sub(*weight as u64) {
+            Some(rest) => draw = rest,
+            None => return entries,
+        }
+    }
+    &choices[choices.len() - 1].1
+}
+
+/// Seed of a choice written without one: a hash (FNV-1a) of how it's written.
+fn seed_of(choice: &GroupOrNote<Times>) -> u64 {
+    choice.to_string().bytes().fold(0xcbf29ce484222325, |hash, b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    let (mut x, mut y) = (a, b);
+    while y != 0 {
+        (x, y) = (y, x % y);
+    }
+    x
+}
+
+fn lcm(a: u64, b: u64) -> u64 {
+    a / gcd(a, b) * b
+}
+
+/// Sum of two fractions, in lowest terms.
+fn add((n1, d1): (u64, u64), (n2, d2): (u64, u64)) -> (u64, u64) {
+    let (n, d) = (n1 * d2 + n2 * d1, d1 * d2);
+    let common = gcd(n, d).max(1);
+    (n / common, d / common)
 }
 
 #[allow(unused_imports)]
@@ -320,60 +719,116 @@ pub struct Group<T, R> {
     pub times: R,
 }
 
+impl Group<GroupOrNote<Times>, Times> {
+    /// How many times the group is played before its notes that only play on some of its
+    /// repeats all come round, 1 if it counts its own repeats.
+    fn turns(&self) -> u64 {
+        match self.times.0 {
+            1 => self.notes.iter().map(|entry| entry.turns()).fold(1, lcm),
+            _ => 1,
+        }
+    }
+}
+
 impl<T> Group<T, Times> {
     pub fn empty() -> Self {
         Group {
             notes: Vec::new(),
-            length: FOURTH.clone(),
+            length: *FOURTH,
             times: Times(1),
         }
     }
 }
 
-impl KnownLength for &Group<GroupOrNote<Times>, Times> {
-    fn to_128th(&self) -> u32 {
-        let mut acc = 0;
-        let note_length = self.length.to_128th();
-        for group in self.notes.iter() {
-            match group {
-                GroupOrNote::SingleGroup(subgroup) => {
-                    acc += subgroup.to_128th();
-                }
-                GroupOrNote::SingleNote(_) => {
-                    acc += note_length;
+/// Renders a group back into the DSL, so generated patterns can be printed and re-parsed.
+impl fmt::Display for Group<GroupOrNote<Times>, Times> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.times.0 != 1 {
+            write!(f, "({},{}", self.times.0, self.length)?;
+        } else {
+            write!(f, "{}", self.length)?;
+        }
+        for entry in self.notes.iter() {
+            write!(f, "{}", entry)?;
+        }
+        if self.times.0 != 1 {
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for GroupOrNote<Times> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SingleNote(note) => write!(f, "{}", note),
+            SingleRoll(roll) => write!(f, "{}", roll),
+            SingleGroup(group) if group.times.0 != 1 => write!(f, "{}", group),
+            SingleGroup(group) => write!(f, "({})", group),
+            Volta(on, entry) => {
+                let on: Vec<String> = on.iter().map(|n| n.to_string()).collect();
+                write!(f, "{}@{}", entry, on.join(","))
+            }
+            Alternation(alternatives) => {
+                let alternatives: Vec<String> = alternatives.iter().map(|entries| entries.iter().map(|e| e.to_string()).collect()).collect();
+                write!(f, "{{{}}}", alternatives.join("|"))
+            }
+            Choice(choices, seed) => {
+                let choices: Vec<String> = choices
+                    .iter()
+                    .map(|(weight, entries)| {
+                        let entries: String = entries.iter().map(|e| e.to_string()).collect();
+                        match weight {
+                            1 => entries,
+                            weight => format!("{}:{}", entries, weight),
+                        }
+                    })
+                    .collect();
+                write!(f, "{{{}}}?", choices.join(","))?;
+                match seed {
+                    Some(seed) => write!(f, "{}", seed),
+                    None => Ok(()),
                 }
             }
         }
-        acc * self.times.0 as u32
+    }
+}
+
+impl fmt::Display for Group<Note, ()> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.length)?;
+        for note in self.notes.iter() {
+            write!(f, "{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+impl Group<GroupOrNote<Times>, Times> {
+    /// Length in 128th notes as a fraction, so triplets in it add up to whole 128ths however
+    /// they're split between repeats and nested groups.
+    fn fraction(&self) -> (u64, u64) {
+        let (n, d) = self.notes.iter().map(|entry| entry.fraction(&self.length)).fold((0, 1), add);
+        add((n * self.times.0 as u64, d), (0, 1))
+    }
+}
+
+impl KnownLength for &Group<GroupOrNote<Times>, Times> {
+    fn to_128th(&self) -> u32 {
+        let (n, d) = self.fraction();
+        (n / d) as u32
     }
 }
 
 impl KnownLength for Group<GroupOrNote<Times>, Times> {
     fn to_128th(&self) -> u32 {
-        let mut acc = 0;
-        let note_length = self.length.to_128th();
-        for group in self.notes.iter() {
-            match group {
-                GroupOrNote::SingleGroup(subgroup) => {
-                    acc += subgroup.to_128th();
-                }
-                GroupOrNote::SingleNote(_) => {
-                    acc += note_length;
-                }
-            }
-        }
-        acc * self.times.0 as u32
+        (&self).to_128th()
     }
 }
 
 impl KnownLength for Group<Note, ()> {
     fn to_128th(&self) -> u32 {
-        let mut acc = 0;
-        let note_length = self.length.to_128th();
-        for _ in self.notes.iter() {
-            acc += note_length;
-        }
-        acc
+        self.length.span(self.notes.len() as u32)
     }
 }
 
@@ -390,7 +845,7 @@ fn test_known_length_group() {
             SingleNote(Hit),
             SingleNote(Rest),
         ],
-        length: SIXTEENTH.clone(),
+        length: *SIXTEENTH,
         times: Times(1),
     };
     assert_eq!(group.to_128th(), 64);
@@ -409,21 +864,57 @@ impl IntoIterator for Groups {
     }
 }
 
+impl Groups {
+    /// Whether any note is played, patterns of rests only are placeholders.
+    pub fn has_hits(&self) -> bool {
+        self.0.iter().flat_map(|g| g.notes.iter()).any(|n| n.is_hit())
+    }
+
+    /// 128th notes from the start of the pattern to its first hit, `None` if it only rests.
+    pub fn first_hit(&self) -> Option<u32> {
+        let mut at = 0;
+        for group in self.0.iter() {
+            for note in group.notes.iter() {
+                if note.is_hit() {
+                    return Some(at);
+                }
+                at += group.length.to_128th();
+            }
+        }
+        None
+    }
+}
+
 impl FromIterator<Group<Note, ()>> for Groups {
     fn from_iter<T: IntoIterator<Item = Group<Note, ()>>>(iter: T) -> Self {
         Self(Vec::from_iter(iter))
     }
 }
 
+impl fmt::Display for Groups {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Empty groups have no DSL representation, a bare length would glue onto the next group.
+        for group in self.0.iter().filter(|g| !g.notes.is_empty()) {
+            write!(f, "{}", group)?;
+        }
+        Ok(())
+    }
+}
+
 impl KnownLength for Groups {
     fn to_128th(&self) -> u32 {
-        self.0.iter().fold(0, |acc, x| acc + x.to_128th())
+        (&self).to_128th()
     }
 }
 
 impl KnownLength for &Groups {
     fn to_128th(&self) -> u32 {
-        self.0.iter().fold(0, |acc, x| acc + x.to_128th())
+        // Added up as fractions, triplets split across groups still make whole 128ths.
+        let (n, d) = self.0.iter().fold((0, 1), |sum, group| {
+            let (n, d) = group.length.fraction();
+            add(sum, (n as u64 * group.notes.len() as u64, d as u64))
+        });
+        (n / d) as u32
     }
 }
 
@@ -431,22 +922,152 @@ impl KnownLength for &Groups {
 fn test_known_length_groups() {
     let groups = Groups(vec![Group {
         notes: vec![Hit, Hit, Rest, Hit, Rest, Hit, Hit, Rest],
-        length: SIXTEENTH.clone(),
+        length: *SIXTEENTH,
         times: (),
     }]);
     assert_eq!(groups.to_128th(), 64);
 }
 
+fn articulation(input: &str) -> IResult<&str, Articulation> {
+    alt((
+        map(char('x'), |_| Articulation::Normal),
+        map(char('s'), |_| Articulation::SideStick),
+        map(char('r'), |_| Articulation::Rimshot),
+        map(char('c'), |_| Articulation::Closed),
+    ))(input)
+}
+
+/// A hit played a way, `offset` 128th notes off the beat, on the key of a `pitch` if it has one.
+fn struck(articulation: Articulation, offset: i8, pitch: Option<Pitch>) -> Note {
+    match (articulation, offset, pitch) {
+        (Articulation::Normal, 0, None) => Note::Hit,
+        (articulation, offset, pitch) => Note::Stroke(Stroke {
+            velocity: articulation.default_velocity(),
+            fine: 0,
+            offset,
+            articulation,
+            pitch,
+        }),
+    }
+}
+
 fn hit(input: &str) -> IResult<&str, Note> {
-    map(char('x'), |_| Note::Hit)(input)
+    map(tuple((articulation, opt(delimited(char('['), pitch, char(']'))), opt(offset))), |(articulation, pitch, offset)| {
+        struck(articulation, offset.unwrap_or(0), pitch)
+    })(input)
+}
+
+/// A note name like `C2`, `F#3` or `Bb1`, the way `note_name` writes it, or a degree of the
+/// key like `5` or `b3`.
+fn pitch(input: &str) -> IResult<&str, Pitch> {
+    let note = map_res(tuple((one_of("CDEFGAB"), opt(one_of("#b")), digit1)), |(name, accidental, octave): (char, Option<char>, &str)| {
+        let class = NOTE_NAMES.iter().position(|n| n.starts_with(name)).unwrap_or(0) as i32;
+        let shift = match accidental {
+            Some('#') => 1,
+            Some(_) => -1,
+            None => 0,
+        };
+        let key = (octave.parse::<i32>().map_err(|e| e.to_string())? + 1) * 12 + class + shift;
+        match u8::try_from(key) {
+            Ok(key) if key <= 127 => Ok(Pitch::Note(key)),
+            _ => Err(format!("{}{} is beyond the MIDI keys", name, octave)),
+        }
+    });
+    let degree = map_res(recognize(tuple((many0(one_of("b#")), digit1))), |s: &str| Degree::from_str(s).map(Pitch::Degree));
+    alt((note, degree))(input)
+}
+
+/// Articulations the plain hits of a group play in turn, written after its length like
+/// `16{cx}xxxx` for a hi-hat going closed, open, closed, open.
+fn cycle(input: &str) -> IResult<&str, Vec<Articulation>> {
+    delimited(char('{'), many1(articulation), char('}'))(input)
+}
+
+/// Plain hits of a repeat of a group, the ones of its groups included.
+fn plain_hits(group: &Group<GroupOrNote<Times>, Times>) -> usize {
+    group
+        .notes
+        .iter()
+        .map(|entry| match entry {
+            SingleNote(Note::Hit) => 1,
+            SingleNote(Note::Stroke(stroke)) if stroke.articulation == Articulation::Normal => 1,
+            SingleGroup(group) => plain_hits(group) * group.times.0 as usize,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Gives the plain hits of a group the articulations of `cycle` in turn, from the `turn`th
+/// one on, through its groups and repeats. Repeats that would start at another turn of the
+/// cycle are written out. Hits played another way and rolls keep theirs.
+fn articulate(group: Group<GroupOrNote<Times>, Times>, cycle: &[Articulation], turn: &mut usize) -> Group<GroupOrNote<Times>, Times> {
+    let hits = plain_hits(&group);
+    let group = if group.times.0 > 1 && !hits.is_multiple_of(cycle.len()) {
+        Group {
+            notes: group.notes.iter().cycle().take(group.notes.len() * group.times.0 as usize).cloned().collect(),
+            length: group.length,
+            times: Times(1),
+        }
+    } else {
+        group
+    };
+    let notes = group
+        .notes
+        .into_iter()
+        .map(|entry| match entry {
+            SingleNote(note @ (Note::Hit | Note::Stroke(Stroke { articulation: Articulation::Normal, .. }))) => {
+                let articulation = cycle[*turn % cycle.len()];
+                *turn += 1;
+                SingleNote(struck(articulation, note.stroke().offset, note.stroke().pitch))
+            }
+            SingleGroup(group) => SingleGroup(articulate(group, cycle, turn)),
+            entry => entry,
+        })
+        .collect();
+    *turn += hits * (group.times.0 as usize).saturating_sub(1);
+    Group {
+        notes,
+        length: group.length,
+        times: group.times,
+    }
+}
+
+/// Timing offset of a hit in 128th notes: `[+3]` or `[-3]`.
+fn offset(input: &str) -> IResult<&str, i8> {
+    delimited(
+        char('['),
+        map_res(tuple((alt((char('+'), char('-'))), digit1)), |(sign, digits): (char, &str)| {
+            format!("{}{}", sign, digits).parse::<i8>()
+        }),
+        char(']'),
+    )(input)
 }
 
 fn rest(input: &str) -> IResult<&str, Note> {
     map(char('-'), |_| Note::Rest)(input)
 }
 
+fn tie(input: &str) -> IResult<&str, Note> {
+    map(char('_'), |_| Note::Tie)(input)
+}
+
 fn note(input: &str) -> IResult<&str, Note> {
-    alt((hit, rest))(input)
+    alt((hit, rest, tie))(input)
+}
+
+/// A roll of a note of `length`, failing when it can't be split into that many strokes.
+fn roll(length: Length) -> impl Fn(&str) -> IResult<&str, Roll> {
+    move |input| {
+        let (rest, (_, _, strokes)) = tuple((char('x'), char('*'), map_res(digit1, str::parse::<u8>)))(input)?;
+        if length.split(strokes).is_none() {
+            return Err(Err::Error(nom::error::make_error(input, nom::error::ErrorKind::Verify)));
+        }
+        let (rest, ramp) = opt(alt((
+            map(char('<'), |_| Ramp::Crescendo),
+            map(char('>'), |_| Ramp::Decrescendo),
+        )))(rest)?;
+        Ok((rest, Roll { strokes, ramp }))
+    }
 }
 
 fn length_basic(input: &str) -> IResult<&str, BasicLength> {
@@ -458,6 +1079,7 @@ fn length_basic(input: &str) -> IResult<&str, BasicLength> {
         Ok((r, 16)) => Ok((r, BasicLength::Sixteenth)),
         Ok((r, 32)) => Ok((r, BasicLength::ThirtySecond)),
         Ok((r, 64)) => Ok((r, BasicLength::SixtyFourth)),
+        Ok((r, 128)) => Ok((r, BasicLength::OneHundredTwentyEighth)),
         Ok((r, _)) => Err(Err::Error(nom::error::make_error(
             r,
             nom::error::ErrorKind::Fail,
@@ -466,14 +1088,19 @@ fn length_basic(input: &str) -> IResult<&str, BasicLength> {
     }
 }
 
+/// A length with dots, down to a dot of a 128th note, the shortest one there is.
 fn dotted_length(input: &str) -> IResult<&str, ModdedLength> {
-    map(tuple((length_basic, char('.'))), |(l, _)| {
-        ModdedLength::Dotted(l)
+    let dots = verify(tuple((length_basic, many1(char('.')))), |(l, dots): &(BasicLength, Vec<char>)| {
+        dots.len() <= l.to_128th().trailing_zeros() as usize
+    });
+    map(dots, |(l, dots)| match dots.len() {
+        1 => ModdedLength::Dotted(l),
+        n => ModdedLength::MultiDotted(l, n as u8),
     })(input)
 }
 
 fn modded_length(input: &str) -> IResult<&str, ModdedLength> {
-    alt((dotted_length, map(length_basic, |x| ModdedLength::Plain(x))))(input)
+    alt((dotted_length, map(length_basic, ModdedLength::Plain)))(input)
 }
 
 fn triplet_length(input: &str) -> IResult<&str, Length> {
@@ -482,6 +1109,18 @@ fn triplet_length(input: &str) -> IResult<&str, Length> {
     })(input)
 }
 
+fn tuplet_length(input: &str) -> IResult<&str, Length> {
+    let count = |i| {
+        map_res(digit1, |d: &str| match d.parse::<u16>() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(()),
+        })(i)
+    };
+    map(tuple((modded_length, char(':'), count, char(':'), count)), |(l, _, n, _, m)| {
+        Length::Tuplet(l, n, m)
+    })(input)
+}
+
 fn tied_length(input: &str) -> IResult<&str, Length> {
     map(
         separated_pair(modded_length, char('+'), modded_length),
@@ -492,47 +1131,106 @@ fn tied_length(input: &str) -> IResult<&str, Length> {
 fn length(input: &str) -> IResult<&str, Length> {
     alt((
         triplet_length,
+        tuplet_length,
         tied_length,
-        map(modded_length, |x| Length::Simple(x)),
+        map(modded_length, Length::Simple),
     ))(input)
 }
 
+/// Repeats of a group, from 1: a group played no times would have no length to repeat.
 fn times(input: &str) -> IResult<&str, Times> {
-    map(map_res(digit1, str::parse), |x| Times(x))(input)
+    let count = map_res(digit1, |d: &str| match d.parse::<u16>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(()),
+    });
+    map(count, Times)(input)
 }
 
 fn group(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
-    let repeated_syntax = map(
-        tuple((
-            times,
-            char(','),
-            length,
-            many1(alt((
-                map_res(note, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleNote(x))}),
-                map_res(delimited_group, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleGroup(x))}),
-            ))),
-        )),
-        |(t, _, l, n)| (t, l, n),
-    );
-    let single_syntax = map(
-        tuple((
-            length,
-            many1(alt((
-                map_res(note, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleNote(x))}),
-                map_res(delimited_group, |x| -> Result<GroupOrNote<Times>, &str> { Ok(SingleGroup(x))}),
-            ))),
-        )), |(l, vn)| (Times(1), l, vn));
-    let (rem, (t, l, n)) = alt((repeated_syntax, single_syntax))(input)?;
-    Ok((
-        rem,
-        Group {
-            notes: n
-                .into_iter()
-                .collect(),
-            length: l,
-            times: t,
-        },
-    ))
+    // A group that starts with a group can leave its length out, it takes the one of that group.
+    let own_length = |i| length(i).or_else(|e| peek(leading_length)(i).map_err(|_| e));
+    let repeated_syntax = map(tuple((times, char(','), own_length)), |(t, _, l)| (t, l));
+    let single_syntax = map(own_length, |l| (Times(1), l));
+    let (rem, (t, l)) = alt((repeated_syntax, single_syntax))(input)?;
+    let (rem, articulations) = opt(cycle)(rem)?;
+    let (rem, n) = many1(|i| entry(l, i))(rem)?;
+    let group = Group {
+        notes: n,
+        length: l,
+        times: t,
+    };
+    match articulations {
+        Some(articulations) => Ok((rem, articulate(group, &articulations, &mut 0))),
+        None => Ok((rem, group)),
+    }
+}
+
+/// A note, a roll, a group or alternatives of notes of `length` in a group, and the repeats of
+/// the group it plays on.
+fn entry(length: Length, input: &str) -> IResult<&str, GroupOrNote<Times>> {
+    let played = alt((
+        map(roll(length), SingleRoll),
+        map(note, SingleNote),
+        map(delimited_group, SingleGroup),
+        |i| braces(length, i),
+    ));
+    map(tuple((played, opt(volta))), |(entry, on)| match on {
+        Some(on) => Volta(on, Box::new(entry)),
+        None => entry,
+    })(input)
+}
+
+/// Two or more runs of notes of `length` in curly braces, played in turn like `{xx|x-}` or
+/// picked at random like `{xx,x-:3}?7`.
+fn braces(length: Length, input: &str) -> IResult<&str, GroupOrNote<Times>> {
+    let entries = |i| many1(|i| entry(length, i))(i);
+    let (rem, (_, first, first_weight)) = tuple((char('{'), entries, opt(weight)))(input)?;
+    let (_, separator) = match first_weight {
+        Some(_) => char(',')(rem)?,
+        None => one_of("|,")(rem)?,
+    };
+    if separator == '|' {
+        let (rem, (rest, _)) = tuple((many1(preceded(char('|'), entries)), char('}')))(rem)?;
+        let mut alternatives = vec![first];
+        alternatives.extend(rest);
+        return Ok((rem, Alternation(alternatives)));
+    }
+    let (rem, (rest, _, _, seed)) = tuple((
+        many1(preceded(char(','), tuple((entries, opt(weight))))),
+        char('}'),
+        char('?'),
+        opt(map_res(digit1, str::parse::<u64>)),
+    ))(rem)?;
+    let mut choices = vec![(first_weight.unwrap_or(1), first)];
+    choices.extend(rest.into_iter().map(|(entries, weight)| (weight.unwrap_or(1), entries)));
+    Ok((rem, Choice(choices, seed)))
+}
+
+/// How likely a pick of a choice is next to the others: `:3`.
+fn weight(input: &str) -> IResult<&str, u16> {
+    preceded(
+        char(':'),
+        map_res(digit1, |d: &str| match d.parse::<u16>() {
+            Ok(w) if w > 0 => Ok(w),
+            _ => Err(()),
+        }),
+    )(input)
+}
+
+/// Repeats a note or a group plays on, counted from 1: `@4` or `@2,4`.
+fn volta(input: &str) -> IResult<&str, Vec<u16>> {
+    let repeat = map_res(digit1, |d: &str| match d.parse::<u16>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(()),
+    });
+    map(tuple((char('@'), separated_list1(char(','), repeat))), |(_, on)| on)(input)
+}
+
+/// Length of the group at the start of `input`, its own or the one of the group it starts with,
+/// without parsing the notes of either.
+fn leading_length(input: &str) -> IResult<&str, Length> {
+    let (rem, _) = tuple((char('('), opt(tuple((times, char(','))))))(input)?;
+    alt((length, leading_length))(rem)
 }
 
 fn delimited_group(input: &str) -> IResult<&str, Group<GroupOrNote<Times>, Times>> {
@@ -551,52 +1249,578 @@ pub fn groups(input: &str) -> IResult<&str, Groups> {
         })(input)
 }
 
+/// Deepest groups may be nested in a pattern, deeper ones are an error rather than running out
+/// of stack.
+pub const MAX_NESTING: usize = 64;
+
+/// Parses a whole pattern like `groups`, pointing at the offending tokens when it's malformed.
+/// The parser picks up after every problem, so all of them are reported at once.
+pub fn parse_groups(input: &str) -> Result<Groups, Error> {
+    if let Some(offset) = too_deep(input) {
+        let message = format!("groups are nested more than {} deep", MAX_NESTING);
+        return Err(Error::Parse(vec![ParseError::at(input, offset, message)]));
+    }
+    let mut start = 0;
+    let mut out = Vec::new();
+    let mut errors = Vec::new();
+    while start < input.len() {
+        let rest = &input[start..];
+        match group_or_delimited_group(rest) {
+            Ok((r, g)) => {
+                out.push(g);
+                start = input.len() - r.len();
+            }
+            Err(_) => {
+                let offset = furthest_failure(input, rest);
+                errors.push(diagnose(input, start, offset));
+                start = recover(input, start, offset);
+            }
+        }
+    }
+    if out.is_empty() && errors.is_empty() {
+        errors.push(diagnose(input, 0, 0));
+    }
+    if !errors.is_empty() {
+        return Err(Error::Parse(errors));
+    }
+    Ok(flatten_groups(out))
+}
+
+/// A pattern with an optional pickup (anacrusis) played before the first bar,
+/// written in front of the pattern and separated with `|`: `8xx|4x-x-`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    /// Empty when the pattern has no pickup.
+    pub pickup: Groups,
+    pub groups: Groups,
+    /// Whether the pattern is repeated over the bars the other parts take to line up again
+    /// rather than adding to them, written `(*,8x--)`. See `alignment::fill_parts`.
+    pub fills: bool,
+    /// Number of bars the pattern is stretched or squeezed to fill, written `(=1,16xxxxxxx)`.
+    /// See `alignment::fit`.
+    pub fit: Option<u32>,
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.pickup.0.is_empty() {
+            write!(f, "{}|", self.pickup)?;
+        }
+        match (self.fills, self.fit) {
+            (true, _) => write!(f, "(*,{})", self.groups),
+            (false, Some(bars)) => write!(f, "(={},{})", bars, self.groups),
+            (false, None) => write!(f, "{}", self.groups),
+        }
+    }
+}
+
+/// Parses a pattern which may start with a pickup, reporting problems of both sides at once.
+pub fn parse_pattern(input: &str) -> Result<Pattern, Error> {
+    // Both sides are parsed on their own, errors point into the whole pattern.
+    let parse_at = |part: &str, column: usize| {
+        parse_groups(part).map_err(|e| match e {
+            Error::Parse(errors) => Error::Parse(errors.into_iter().map(|e| e.within(input, 1, column)).collect()),
+            e => e,
+        })
+    };
+    // Alternatives in curly braces are separated with `|` too.
+    let mut depth = 0;
+    let bar = input.find(|c| {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+        c == '|' && depth == 0
+    });
+    let (pickup, body, body_column) = match bar.map(|i| (&input[..i], &input[i + 1..])) {
+        Some((pickup, body)) => (parse_at(pickup, 1).map(Some), body, pickup.chars().count() + 2),
+        None => (Ok(None), input, 1),
+    };
+    // Patterns that fill the bars of the others, or a number of bars, go around the whole body.
+    let mut fit = None;
+    let wrapped = match body.strip_prefix("(=") {
+        Some(rest) => {
+            let (bars, inner) = rest.split_once(',').unwrap_or((rest, ""));
+            match bars.parse::<u32>() {
+                Ok(n) if n > 0 => {
+                    fit = Some(n);
+                    Some((inner, bars.len() + 3))
+                }
+                _ => {
+                    let message = format!("expected the number of bars to fit the pattern into after `(=`, like `(=1,16xxxxxxx)`, found `{}`", bars);
+                    return Err(Error::Parse(vec![ParseError::at(input, input.len() - body.len() + 2, message)]));
+                }
+            }
+        }
+        None => body.strip_prefix("(*,").map(|inner| (inner, 3)),
+    };
+    let mut groups = match wrapped {
+        Some((inner, skip)) => parse_at(inner.strip_suffix(')').unwrap_or(inner), body_column + skip),
+        None => parse_at(body, body_column),
+    };
+    if wrapped.is_some_and(|(inner, _)| !inner.ends_with(')')) {
+        let unclosed = ParseError::at(input, input.len(), "expected `)` to close the group, found end of pattern".to_string());
+        groups = match groups {
+            Err(Error::Parse(mut errors)) => {
+                errors.push(unclosed);
+                Err(Error::Parse(errors))
+            }
+            _ => Err(Error::Parse(vec![unclosed])),
+        };
+    }
+    match (pickup, groups) {
+        (Ok(pickup), Ok(groups)) => Ok(Pattern {
+            pickup: pickup.unwrap_or(Groups(vec![])),
+            groups,
+            fills: body.starts_with("(*,"),
+            fit,
+        }),
+        (Err(Error::Parse(mut a)), Err(Error::Parse(b))) => {
+            a.extend(b);
+            Err(Error::Parse(a))
+        }
+        (Err(e), _) | (_, Err(e)) => Err(e),
+    }
+}
+
+#[test]
+fn test_parse_pattern() {
+    let pattern = parse_pattern("8xx|4x-x-").unwrap();
+    assert_eq!(pattern.pickup, groups("8xx").unwrap().1);
+    assert_eq!(pattern.groups, groups("4x-x-").unwrap().1);
+    assert_eq!(pattern.to_string(), "8xx|4x-x-");
+    assert_eq!(parse_pattern("4x-x-").unwrap().pickup, Groups(vec![]));
+    let fills = parse_pattern("8xx|(*,8x--16xx)").unwrap();
+    assert!(fills.fills && !parse_pattern("8x--16xx").unwrap().fills);
+    assert_eq!(fills.groups, groups("8x--16xx").unwrap().1);
+    assert_eq!(fills.to_string(), "8xx|(*,8x--16xx)");
+    let fit = parse_pattern("(=2,16xxxxxxx)").unwrap();
+    assert_eq!((fit.fit, fit.fills), (Some(2), false));
+    assert_eq!(fit.groups, groups("16xxxxxxx").unwrap().1);
+    assert_eq!(fit.to_string(), "(=2,16xxxxxxx)");
+    assert_eq!(parse_pattern("8x--").unwrap().fit, None);
+    match parse_pattern("(=0,16xxx)") {
+        Err(Error::Parse(errors)) => assert_eq!(errors.iter().map(|e| e.column).collect::<Vec<_>>(), vec![3]),
+        other => panic!("expected parse errors, got {:?}", other),
+    }
+    match parse_pattern("(=12,8xy") {
+        Err(Error::Parse(errors)) => assert_eq!(errors.iter().map(|e| e.column).collect::<Vec<_>>(), vec![8, 9]),
+        other => panic!("expected parse errors, got {:?}", other),
+    }
+    match parse_pattern("(*,8x-y") {
+        Err(Error::Parse(errors)) => {
+            assert_eq!(errors.iter().map(|e| e.column).collect::<Vec<_>>(), vec![7, 8]);
+        }
+        other => panic!("expected parse errors, got {:?}", other),
+    }
+    match parse_pattern("8xy|4x-z") {
+        Err(Error::Parse(errors)) => {
+            assert_eq!(errors.iter().map(|e| e.column).collect::<Vec<_>>(), vec![3, 8]);
+            assert!(errors.iter().all(|e| e.source_line == "8xy|4x-z"));
+        }
+        other => panic!("expected parse errors, got {:?}", other),
+    }
+}
+
+/// Offset of the deepest point any of the alternatives made it to before failing.
+fn furthest_failure(input: &str, rest: &str) -> usize {
+    fn failed_at<T>(input: &str, rest: &str, r: IResult<&str, T>) -> usize {
+        match r {
+            Err(Err::Error(e)) | Err(Err::Failure(e)) => input.len() - e.input.len(),
+            _ => input.len() - rest.len(),
+        }
+    }
+    // Curly braces left over behind a group are broken ones.
+    failed_at(input, rest, delimited_group(rest))
+        .max(failed_at(input, rest, group(rest)))
+        .max(failed_at(input, rest, braces(*FOURTH, rest)))
+}
+
+/// Finds where to carry on parsing after a failure at `offset` of a group starting at `start`:
+/// behind the group the failure is nested in, or at the next thing that looks like a group.
+fn recover(input: &str, start: usize, offset: usize) -> usize {
+    // The stroke count of a broken roll, the numbers of a broken timing offset and the
+    // repeats of a broken `@` aren't the length of a new group.
+    let offset = match input[offset..].strip_prefix('*') {
+        Some(r) => input.len() - r.trim_start_matches(|c: char| c.is_ascii_digit()).len(),
+        None if input[offset..].starts_with('@') => {
+            input.len() - input[offset + 1..].trim_start_matches(|c: char| c.is_ascii_digit() || c == ',').len()
+        }
+        None if input[offset..].starts_with('[') => {
+            offset + input[offset..].find(']').map(|i| i + 1).unwrap_or(input.len() - offset)
+        }
+        None => offset,
+    };
+    // Nothing in broken curly braces starts a group.
+    let before = &input[start..offset];
+    let offset = match before.rfind('{').filter(|i| !before[*i..].contains('}')) {
+        Some(_) => input[offset..].find('}').map(|i| offset + i + 1).unwrap_or(input.len()),
+        None => offset,
+    };
+    let mut depth = unclosed(&input[start..offset]);
+    for (i, c) in input[offset..].char_indices() {
+        let i = offset + i;
+        if depth > 0 {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                return i + 1;
+            }
+        } else if i > offset && (c == '(' || c.is_ascii_digit()) {
+            return i;
+        }
+    }
+    input.len()
+}
+
+/// Offset of the first `(` of a group nested deeper than `MAX_NESTING`.
+fn too_deep(s: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    for (i, c) in s.char_indices() {
+        match c {
+            '(' if depth == MAX_NESTING => return Some(i),
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Offset of the first repeat count of 0 in `s`, like the one of `(0,8x)`.
+fn zero_repeats(s: &str) -> Option<usize> {
+    s.match_indices('(').map(|(i, _)| i + 1).find(|i| {
+        let digits = s[*i..].len() - s[*i..].trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        digits > 0 && s[i + digits..].starts_with(',') && s[*i..i + digits].bytes().all(|b| b == b'0')
+    })
+}
+
+fn unclosed(s: &str) -> i64 {
+    s.matches('(').count() as i64 - s.matches(')').count() as i64
+}
+
+/// Whether the curly brace at `offset` opens a cycle of articulations rather than notes played
+/// in turn or picked at random: it follows the length of a group and has no `|` or `,` in it.
+fn is_cycle(input: &str, offset: usize) -> bool {
+    let after_length = input[..offset].ends_with(|c: char| c.is_ascii_digit() || c == '.' || c == 't');
+    after_length && !input[offset..].split('}').next().unwrap_or("").contains(['|', ','])
+}
+
+fn diagnose(input: &str, start: usize, offset: usize) -> ParseError {
+    // A group repeated no times leaves the parser somewhere around it, the count is the problem.
+    let end = recover(input, start, offset).max(offset);
+    if let Some(count) = zero_repeats(&input[start..end]) {
+        let digits: String = input[start + count..].chars().take_while(|c| c.is_ascii_digit()).collect();
+        return ParseError::at(
+            input,
+            start + count,
+            format!("expected the number of repeats of the group, from 1 like `(3,8x-)`, found `{}`", digits),
+        );
+    }
+    let before = &input[..offset];
+    let next = input[offset..].chars().next();
+    let found = match next {
+        None => "end of pattern".to_string(),
+        Some(c) => format!("`{}`", c),
+    };
+    let digits_start = before.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+    let digits = &before[digits_start..];
+    if !digits.is_empty() && next != Some(',') && BasicLength::from_num(digits.parse().unwrap_or(0)).is_err() {
+        return ParseError::at(
+            input,
+            digits_start,
+            format!("`{}` is not a note length, expected one of 1, 2, 4, 8, 16, 32, 64 or 128", digits),
+        );
+    }
+    if next == Some('.') && !digits.is_empty() {
+        let dots = input[offset..].chars().take_while(|c| *c == '.').count();
+        return ParseError::at(
+            input,
+            digits_start,
+            format!("`{}{}` has a dot shorter than a 128th note, the shortest note there is", digits, ".".repeat(dots)),
+        );
+    }
+    // A tie that doesn't parse leaves the parser behind it, the problem is what follows it.
+    if next == Some('+') && before.ends_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return diagnose(input, start, offset + 1);
+    }
+    if next == Some('*') && before.ends_with('x') {
+        let count: String = input[offset + 1..].chars().take_while(|c| c.is_ascii_digit()).collect();
+        let message = if count.is_empty() {
+            let found = match input[offset + 1..].chars().next() {
+                None => "end of pattern".to_string(),
+                Some(c) => format!("`{}`", c),
+            };
+            format!("expected the number of strokes after `*`, found {}", found)
+        } else {
+            format!(
+                "can't split the note into {} equal strokes, a roll takes 2, 4, 8… strokes, or 3, 6, 12… on notes that aren't triplets or tied",
+                count
+            )
+        };
+        return ParseError::at(input, offset + 1, message);
+    }
+    if next == Some(':') && before.contains('{') {
+        let found = match input[offset + 1..].chars().next() {
+            None => "end of pattern".to_string(),
+            Some(c) => format!("`{}`", c),
+        };
+        return ParseError::at(input, offset + 1, format!("expected the weight of the pick after `:`, from 1 like `:3`, found {}", found));
+    }
+    if next == Some('$') {
+        return ParseError::at(input, offset, "macros like `$fill` are defined and used in pattern files".to_string());
+    }
+    if next == Some('@') {
+        let found = match input[offset + 1..].chars().next() {
+            None => "end of pattern".to_string(),
+            Some(c) => format!("`{}`", c),
+        };
+        return ParseError::at(
+            input,
+            offset + 1,
+            format!("expected the repeats to play on after `@`, counted from 1 like `@4` or `@2,4`, found {}", found),
+        );
+    }
+    if next == Some('[') && before.ends_with(['x', 's', 'r', 'c', ']']) {
+        return ParseError::at(
+            input,
+            offset,
+            "expected a timing offset in 128th notes, like `[+3]` or `[-2]`, or a note like `[C2]` or `[b3]`".to_string(),
+        );
+    }
+    // A broken cycle of articulations or broken alternatives leave the parser in front of them.
+    if next == Some('{') && is_cycle(input, offset) {
+        let inside = &input[offset + 1..];
+        let valid = inside.find(|c| !matches!(c, 'x' | 'c' | 's' | 'r')).unwrap_or(inside.len());
+        if valid == 0 || !inside[valid..].starts_with('}') {
+            return diagnose(input, start, offset + 1 + valid);
+        }
+    } else if next == Some('{') {
+        if let Err(Err::Error(e)) = braces(*FOURTH, &input[offset..]) {
+            return diagnose(input, start, input.len() - e.input.len());
+        }
+    }
+    let unclosed = unclosed(&input[start..offset]);
+    let open_brace = before.rfind('{').filter(|i| !before[*i..].contains('}'));
+    let in_cycle = open_brace.is_some_and(|i| is_cycle(input, i));
+    let in_braces = open_brace.is_some() && !in_cycle;
+    let closed_choice = before.ends_with('}') && before.rfind('{').is_some_and(|i| before[i..].contains(','));
+    let message = match (before.chars().last(), next) {
+        _ if in_cycle => format!("expected articulations (`x`, `c`, `s` or `r`) closed by `}}`, found {}", found),
+        _ if closed_choice => format!("expected `?` after notes to pick from, like `{{xx,x-}}?`, found {}", found),
+        (_, Some('|')) if in_braces => "notes picked at random with `,` or weights can't be played in turn with `|`".to_string(),
+        (_, Some(',')) if in_braces => "notes played in turn with `|` can't be picked at random with `,`".to_string(),
+        (Some(c @ ('|' | ',')), _) if in_braces => format!("expected a note (`x`, `-` or `_`) or a group after `{}`, found {}", c, found),
+        (_, Some('}')) if in_braces => "expected `|` and the notes played on the next repeat, found `}`".to_string(),
+        (_, None | Some(')')) if in_braces => format!("expected `}}` to close the curly braces, found {}", found),
+        (Some('}'), _) => format!("expected a note (`x`, `-` or `_`) after the articulations, found {}", found),
+        (_, Some(')')) if unclosed <= 0 => "found `)` without a matching `(`".to_string(),
+        (_, None) if unclosed > 0 => format!("expected `)` to close the group, found {}", found),
+        (Some(p), _) if p.is_ascii_digit() || p == '.' || p == 't' => {
+            format!("expected a note (`x`, `-` or `_`) after the note length, found {}", found)
+        }
+        (Some('('), Some('*')) => "`(*,…)` goes around a whole pattern, repeats in it take a count like `(3,8x-)`".to_string(),
+        (Some('('), Some('=')) => "`(=1,…)` goes around a whole pattern, repeats in it take a count like `(3,8x-)`".to_string(),
+        (Some('+'), _) => format!("expected a note length after `+`, found {}", found),
+        (Some(','), _) => format!("expected a note length after the repeat count, found {}", found),
+        (Some('x' | 's' | 'r' | 'c' | '-' | '_' | ')' | ']' | '<' | '>'), _) => {
+            format!("expected a note (`x`, `-` or `_`), a note length or a group, found {}", found)
+        }
+        _ => format!("expected a note length, like `8` or `16.`, found {}", found),
+    };
+    ParseError::at(input, offset, message)
+}
+
+#[test]
+fn test_parse_groups_diagnostics() {
+    let messages = |s: &str| match parse_groups(s) {
+        Err(Error::Parse(errors)) => errors.into_iter().map(|e| (e.column, e.message)).collect::<Vec<_>>(),
+        _ => vec![],
+    };
+    let message = |s: &str| -> Result<(), (usize, String)> { Err(messages(s).remove(0)) };
+    assert_eq!(parse_groups("8x-(3,16x-)4x"), Ok(groups("8x-(3,16x-)4x").unwrap().1));
+    assert_eq!(message("8x-(7,8xx"), Err((10, "expected `)` to close the group, found end of pattern".to_string())));
+    assert_eq!(
+        message("8x-3x"),
+        Err((4, "`3` is not a note length, expected one of 1, 2, 4, 8, 16, 32, 64 or 128".to_string()))
+    );
+    assert_eq!(message("8x-y"), Err((4, "expected a note (`x`, `-` or `_`), a note length or a group, found `y`".to_string())));
+    assert_eq!(message("16.y"), Err((4, "expected a note (`x`, `-` or `_`) after the note length, found `y`".to_string())));
+    // Dots worth less than a 128th note would be dropped from the length.
+    assert_eq!(message("(64,128.x)"), Err((5, "`128.` has a dot shorter than a 128th note, the shortest note there is".to_string())));
+    assert_eq!(message("8x64..x"), Err((3, "`64..` has a dot shorter than a 128th note, the shortest note there is".to_string())));
+    assert!(parse_groups("64.x32..x").is_ok());
+    assert_eq!(message("8x)"), Err((3, "found `)` without a matching `(`".to_string())));
+    assert_eq!(message("(x)"), Err((2, "expected a note length, like `8` or `16.`, found `x`".to_string())));
+    assert_eq!(message("8x(*,4x)"), Err((4, "`(*,…)` goes around a whole pattern, repeats in it take a count like `(3,8x-)`".to_string())));
+    assert_eq!(message("8x$fill"), Err((3, "macros like `$fill` are defined and used in pattern files".to_string())));
+    assert_eq!(message("8x(=1,4x)"), Err((4, "`(=1,…)` goes around a whole pattern, repeats in it take a count like `(3,8x-)`".to_string())));
+    assert_eq!(message("4+x"), Err((3, "expected a note length after `+`, found `x`".to_string())));
+    assert_eq!(message(""), Err((1, "expected a note length, like `8` or `16.`, found end of pattern".to_string())));
+    assert_eq!(
+        messages("8x[y]-x4x[+x]"),
+        vec![
+            (3, "expected a timing offset in 128th notes, like `[+3]` or `[-2]`, or a note like `[C2]` or `[b3]`".to_string()),
+            (10, "expected a timing offset in 128th notes, like `[+3]` or `[-2]`, or a note like `[C2]` or `[b3]`".to_string()),
+        ]
+    );
+    assert_eq!(message("8x*y"), Err((4, "expected the number of strokes after `*`, found `y`".to_string())));
+    assert_eq!(
+        messages("8tx*3-x4x*2"),
+        vec![(5, "can't split the note into 3 equal strokes, a roll takes 2, 4, 8… strokes, or 3, 6, 12… on notes that aren't triplets or tied".to_string())]
+    );
+    assert_eq!(message("16{xq}xx"), Err((5, "expected articulations (`x`, `c`, `s` or `r`) closed by `}`, found `q`".to_string())));
+    assert_eq!(
+        messages("8x@0x4x@y"),
+        vec![
+            (4, "expected the repeats to play on after `@`, counted from 1 like `@4` or `@2,4`, found `0`".to_string()),
+            (9, "expected the repeats to play on after `@`, counted from 1 like `@4` or `@2,4`, found `y`".to_string()),
+        ]
+    );
+    assert_eq!(message("8x{xx|y}"), Err((7, "expected a note (`x`, `-` or `_`) or a group after `|`, found `y`".to_string())));
+    assert_eq!(message("8{xx|q}"), Err((6, "expected a note (`x`, `-` or `_`) or a group after `|`, found `q`".to_string())));
+    assert_eq!(message("8x{xx}4x"), Err((6, "expected `|` and the notes played on the next repeat, found `}`".to_string())));
+    assert_eq!(message("8x{xx|x-"), Err((9, "expected `}` to close the curly braces, found end of pattern".to_string())));
+    assert_eq!(message("8x{x,-}4x"), Err((8, "expected `?` after notes to pick from, like `{xx,x-}?`, found `4`".to_string())));
+    assert_eq!(message("8x{x:2|-}"), Err((7, "notes picked at random with `,` or weights can't be played in turn with `|`".to_string())));
+    assert_eq!(message("8x{x|-,x}"), Err((7, "notes played in turn with `|` can't be picked at random with `,`".to_string())));
+    assert_eq!(message("8x{x,y}?"), Err((6, "expected a note (`x`, `-` or `_`) or a group after `,`, found `y`".to_string())));
+    assert_eq!(message("8x{x:0,-}?"), Err((6, "expected the weight of the pick after `:`, from 1 like `:3`, found `0`".to_string())));
+    assert_eq!(message("16{cx}"), Err((7, "expected a note (`x`, `-` or `_`) after the articulations, found end of pattern".to_string())));
+    // Every problem is reported, a broken group is skipped as a whole.
+    assert_eq!(
+        messages("8x-y-4x(3,8x-z-)3x16x)"),
+        vec![
+            (4, "expected a note (`x`, `-` or `_`), a note length or a group, found `y`".to_string()),
+            (14, "expected a note (`x`, `-` or `_`), a note length or a group, found `z`".to_string()),
+            (17, "`3` is not a note length, expected one of 1, 2, 4, 8, 16, 32, 64 or 128".to_string()),
+            (22, "found `)` without a matching `(`".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn test_articulation_cycle() {
+    let closed = struck(Articulation::Closed, 0, None);
+    let open = Note::Hit;
+    let notes = |s: &str| groups(s).unwrap().1 .0.into_iter().flat_map(|g| g.notes).collect::<Vec<_>>();
+    assert_eq!(notes("16{cx}xxxx"), vec![closed, open, closed, open]);
+    // The cycle carries on through rests, nested groups and repeats, hits played another way
+    // keep theirs.
+    assert_eq!(notes("8{cx}x-x(16xx)s"), vec![closed, Note::Rest, open, closed, open, struck(Articulation::SideStick, 0, None)]);
+    assert_eq!(notes("(3,16{rx}xx)"), vec![struck(Articulation::Rimshot, 0, None), open, struck(Articulation::Rimshot, 0, None), open, struck(Articulation::Rimshot, 0, None), open]);
+    assert_eq!(notes("(2,16{rx}xxx)"), vec![struck(Articulation::Rimshot, 0, None), open, struck(Articulation::Rimshot, 0, None), open, struck(Articulation::Rimshot, 0, None), open]);
+    assert_eq!(notes("16{c}x[+2]"), vec![struck(Articulation::Closed, 2, None)]);
+    let group = group_or_delimited_group("8{cx}xxxx").unwrap().1;
+    assert_eq!(group.to_string(), "8cxcx");
+    assert_eq!(group_or_delimited_group(&group.to_string()).unwrap().1, group);
+}
+
+/// Flattens the groups of a pattern. A pattern with notes that only play on some of its
+/// loops is written out as many times as it takes for all of them to come round.
 pub fn flatten_groups<I>(input_groups: I) -> Groups
 where
     I: IntoIterator<Item = Group<GroupOrNote<Times>, Times>>,
 {
+    let groups: Vec<_> = input_groups.into_iter().collect();
+    let loops = groups.iter().map(|g| g.turns()).fold(1, lcm);
     let mut out = Vec::new();
-    input_groups.into_iter().for_each(|g| {
-        let flattened = flatten_group(g).0;
-        out.extend(flattened);
-    });
+    for repeat in 1..=loops {
+        for group in groups.iter() {
+            flatten_group_(group, repeat, &mut out);
+        }
+    }
     Groups(out)
 }
 
 pub fn flatten_group(input: Group<GroupOrNote<Times>, Times>) -> Groups {
-    flatten_group_(&input, &mut Vec::new())
+    let mut out = Vec::new();
+    flatten_group_(&input, 1, &mut out);
+    Groups(out)
 }
 
-fn flatten_group_(input: &Group<GroupOrNote<Times>, Times>, out_groups: &mut Vec<Group<Note, ()>>) -> Groups {
+/// Flattens the `repeat`th time a group is played, its own repeats are counted by the group
+/// itself when it has any.
+fn flatten_group_(input: &Group<GroupOrNote<Times>, Times>, repeat: u64, out_groups: &mut Vec<Group<Note, ()>>) {
     let mut note_group = Vec::new();
-    let inlined_notes = input.notes.iter().cycle().take(input.notes.len() * input.times.0 as usize);
-    let group = Group { notes: inlined_notes.collect(), times: (), length: input.length };
-    group.notes.iter().for_each(|&g| {
-        match g {
-            SingleGroup(group) => {
-                let isolated_group = Group {
-                    notes: note_group.clone(),
-                    length: input.length,
+    for i in 0..input.times.0 as u64 {
+        let repeat = if input.times.0 == 1 { repeat } else { i + 1 };
+        for entry in input.notes.iter() {
+            flatten_entry(entry, input.length, repeat, &mut note_group, out_groups);
+        }
+    }
+    if !note_group.is_empty() {
+        out_groups.push(Group {
+            notes: note_group,
+            length: input.length,
+            times: (),
+        });
+    }
+}
+
+/// Adds an entry of a group of notes of `length` to the notes so far, or to the flattened
+/// groups when it has a length of its own.
+fn flatten_entry(entry: &GroupOrNote<Times>, length: Length, repeat: u64, note_group: &mut Vec<Note>, out_groups: &mut Vec<Group<Note, ()>>) {
+    let isolate = |note_group: &mut Vec<Note>, out_groups: &mut Vec<Group<Note, ()>>| {
+        if !note_group.is_empty() {
+            out_groups.push(Group {
+                notes: std::mem::take(note_group),
+                length,
+                times: (),
+            });
+        }
+    };
+    match entry {
+        SingleGroup(group) => {
+            isolate(note_group, out_groups);
+            flatten_group_(group, repeat, out_groups);
+        }
+        SingleNote(note) => note_group.push(*note),
+        SingleRoll(roll) => match length.split(roll.strokes) {
+            Some(stroke_length) => {
+                isolate(note_group, out_groups);
+                out_groups.push(Group {
+                    notes: roll.notes(),
+                    length: stroke_length,
                     times: (),
-                };
-                out_groups.push(isolated_group);
-                note_group.clear();
-                flatten_group_(&group, out_groups);
+                });
             }
-            SingleNote(note) => {
-                note_group.push(*note);
+            // The parser rejects rolls that don't fit their note, built by hand they're a plain hit.
+            None => note_group.push(Note::Hit),
+        },
+        Volta(on, entry) if plays_on(on, repeat) => flatten_entry(entry, length, repeat, note_group, out_groups),
+        Volta(_, entry) => {
+            // Flattened on its own first, so it rests for as long as it would have played.
+            let (mut rested, mut rested_groups) = (Vec::new(), Vec::new());
+            flatten_entry(entry, length, repeat, &mut rested, &mut rested_groups);
+            let rest = |notes: &mut Vec<Note>| notes.iter_mut().for_each(|note| *note = Note::Rest);
+            rest(&mut rested);
+            rested_groups.iter_mut().for_each(|group| rest(&mut group.notes));
+            if !rested_groups.is_empty() {
+                isolate(note_group, out_groups);
+                out_groups.extend(rested_groups);
+            }
+            note_group.extend(rested);
+        }
+        Choice(choices, seed) => {
+            let seed = seed.unwrap_or_else(|| seed_of(entry));
+            for entry in pick(choices, seed, repeat).iter() {
+                flatten_entry(entry, length, repeat, note_group, out_groups);
+            }
+        }
+        Alternation(alternatives) => {
+            // The notes of an alternative count the times it's played.
+            let turns = alternatives.len() as u64;
+            let alternative = &alternatives[((repeat - 1) % turns) as usize];
+            for entry in alternative.iter() {
+                flatten_entry(entry, length, (repeat - 1) / turns + 1, note_group, out_groups);
             }
         }
-    });
-    if !note_group.is_empty() {
-        let isolated_group = Group {
-            notes: note_group.clone(),
-            length: group.length,
-            times: (),
-        };
-        out_groups.push(isolated_group);
     }
-    Groups(out_groups.iter().cloned().collect())
 }
 
 #[test]
@@ -623,6 +1847,148 @@ fn test_flatten_group() {
     assert_eq!(flatten_group(input), output);
 }
 
+#[test]
+fn test_nested_groups() {
+    let parsed = |s: &str| group_or_delimited_group(s).unwrap().1;
+    let flat = |s: &str| groups(s).unwrap().1;
+    // Every group repeats its own notes, the ones around it repeat it whole.
+    assert_eq!(flat("(2,8x(3,16x(2,32xx))-)").to_string(), "8x16x32xxxx16x32xxxx16x32xxxx8-x16x32xxxx16x32xxxx16x32xxxx8-");
+    assert_eq!(flat("(2,4-(3,16x-))").to_string(), "4-16x-x-x-4-16x-x-x-");
+    let deep = flat("(2,(2,(2,(2,(2,16x)))))");
+    assert_eq!((deep.0.iter().map(|g| g.notes.len()).sum::<usize>(), deep.to_128th()), (32, 256));
+    // A group made of groups takes the length of the first one.
+    assert_eq!(parsed("(2,(3,8x)(2,16xx))"), parsed("(2,8(3,8x)(2,16xx))"));
+    assert_eq!(parsed("(2,(3,8x)x)").to_string(), "(2,8(3,8x)x)");
+    assert_eq!(flat("((16xx))"), flat("16xx"));
+    // Nested and flattened, a group is as long, triplets add up however they're split.
+    for pattern in ["(2,8x(3,16xx)-)", "(3,4x(2,8-x))", "(2,4.x(2,8t-x)_)", "8t(16xx)x(2,8txx)", "(4,8tx{xx|(16txx)x}@2)"] {
+        assert_eq!(parsed(pattern).to_128th(), flat(pattern).to_128th(), "{}", pattern);
+    }
+    assert_eq!(flat("(4,8txxx)").to_128th(), 128);
+    assert_eq!(parsed("(2,4.x(2,8t-x)_)").to_128th(), 277);
+    // A group is played at least once, however deep it's nested.
+    let message = |s: &str| match parse_groups(s) {
+        Err(Error::Parse(errors)) => errors.into_iter().map(|e| (e.column, e.message)).next(),
+        _ => None,
+    };
+    for (pattern, column, count) in [("(0,8x)", 2, "0"), ("(2,8x(0,16xx)-)", 7, "0"), ("(2,(00,8x))", 5, "00")] {
+        assert!(group_or_delimited_group(pattern).is_err(), "{}", pattern);
+        assert_eq!(
+            message(pattern),
+            Some((column, format!("expected the number of repeats of the group, from 1 like `(3,8x-)`, found `{}`", count)))
+        );
+    }
+    // Groups nested too deep for the parser are an error at the first one too many.
+    let nested = |n: usize| format!("{}8x{}", "(".repeat(n), ")".repeat(n));
+    assert_eq!(flat(&nested(MAX_NESTING)), flat("8x"));
+    assert_eq!(message(&nested(200_000)), Some((MAX_NESTING + 1, format!("groups are nested more than {} deep", MAX_NESTING))));
+}
+
+#[test]
+fn test_volta() {
+    let notes = |s: &str| groups(s).unwrap().1 .0.into_iter().flat_map(|g| g.notes).collect::<Vec<_>>();
+    // A crash on the last of four repeats, the pattern keeps its length.
+    assert_eq!(notes("(4,8xx@4)"), vec![Hit, Rest, Hit, Rest, Hit, Rest, Hit, Hit]);
+    assert_eq!(groups("(4,8xx@4)").unwrap().1.to_128th(), groups("(4,8xx)").unwrap().1.to_128th());
+    // Groups rest as a whole, repeats come round again.
+    assert_eq!(notes("(4,8x(16xx)@2)"), vec![Hit, Rest, Rest, Hit, Hit, Hit, Hit, Rest, Rest, Hit, Hit, Hit]);
+    assert_eq!(notes("(3,8xx@1,3)"), vec![Hit, Hit, Hit, Rest, Hit, Hit]);
+    // Outside of a repeated group it's the loops of the pattern, written out until they line up.
+    assert_eq!(notes("4xx@2"), vec![Hit, Rest, Hit, Hit]);
+    assert_eq!(notes("4x@2(8x@3-)"), vec![Rest, Rest, Rest, Hit, Rest, Rest, Rest, Hit, Rest, Hit, Rest, Rest, Rest, Rest, Rest, Hit, Hit, Rest]);
+    // Rolls and articulations rest too.
+    assert_eq!(notes("(2,8x*2@2)"), vec![Rest, Rest, Hit, Hit]);
+    let parsed = group_or_delimited_group("(4,8x(16xx)@2,4x[+2]@3)").unwrap().1;
+    assert_eq!(parsed.to_string(), "(4,8x(16xx)@2,4x[+2]@3)");
+    assert_eq!(group_or_delimited_group(&parsed.to_string()).unwrap().1, parsed);
+}
+
+#[test]
+fn test_alternation() {
+    let notes = |s: &str| groups(s).unwrap().1 .0.into_iter().flat_map(|g| g.notes).collect::<Vec<_>>();
+    assert_eq!(notes("(4,8x{x-|-x})"), vec![Hit, Hit, Rest, Hit, Rest, Hit, Hit, Hit, Rest, Hit, Rest, Hit]);
+    // Outside of a repeated group the pattern alternates between its loops.
+    assert_eq!(notes("4x{x|-}"), vec![Hit, Hit, Hit, Rest]);
+    assert_eq!(notes("4{x|-|(8xx)}"), vec![Hit, Rest, Hit, Hit]);
+    // Alternatives take any length and nest, the pattern loops once all of them came round.
+    // Nested ones take turns every time the one they're in is played.
+    assert_eq!(groups("4{x|xx}").unwrap().1.to_128th(), 3 * 32);
+    assert_eq!(notes("(2,8{x{x|-}|-})"), vec![Hit, Hit, Rest]);
+    assert_eq!(notes("8{x{x|-}|-}"), vec![Hit, Hit, Rest, Hit, Rest, Rest]);
+    assert_eq!(notes("(3,8{x|-}@3)"), vec![Rest, Rest, Hit]);
+    let parsed = group_or_delimited_group("(4,8x{x[+2]-|(16xx)x*2}@2)").unwrap().1;
+    assert_eq!(parsed.to_string(), "(4,8x{x[+2]-|(16xx)x*2}@2)");
+    assert_eq!(group_or_delimited_group(&parsed.to_string()).unwrap().1, parsed);
+    // The bar of a pickup isn't one of the alternatives.
+    let pattern = parse_pattern("8x{x|-}|4x{x|-}").unwrap();
+    assert_eq!(pattern.pickup, groups("8x{x|-}").unwrap().1);
+    assert_eq!(pattern.groups, groups("4x{x|-}").unwrap().1);
+}
+
+#[test]
+fn test_choice() {
+    let notes = |s: &str| groups(s).unwrap().1 .0.into_iter().flat_map(|g| g.notes).collect::<Vec<_>>();
+    // A pick on every repeat, the same ones every time the pattern is parsed.
+    let picks = notes("(64,4{x,-}?)");
+    assert_eq!(picks.len(), 64);
+    assert!(picks.contains(&Hit) && picks.contains(&Rest));
+    assert_eq!(notes("(64,4{x,-}?)"), picks);
+    assert_ne!(notes("(64,4{x,-}?7)"), picks);
+    assert_eq!(notes("(64,4{x,-}?7)"), notes("(64,4{x,-}?7)"));
+    // Weights make picks more likely.
+    let hits = notes("(1000,4{x:9,-}?)").into_iter().filter(|n| *n == Hit).count();
+    assert!((850..950).contains(&hits), "{} hits", hits);
+    // Picks of a pattern outside of a repeated group are made once.
+    assert_eq!(notes("4x{x,-,(8xx)}?").len(), notes("4x{x,-,(8xx)}?").len());
+    assert!(notes("4x{x,-,(8xx)}?").len() <= 3);
+    let parsed = group_or_delimited_group("(4,8x{x[+2]-:3,(16xx)x*2}?12(8x-))").unwrap().1;
+    assert_eq!(parsed.to_string(), "(4,8x{x[+2]-:3,(16xx)x*2}?12(8x-))");
+    assert_eq!(group_or_delimited_group(&parsed.to_string()).unwrap().1, parsed);
+}
+
+#[test]
+fn test_parse_offset() {
+    assert_eq!(hit("x[-3]"), Ok(("", Stroke(Stroke { offset: -3, ..Default::default() }))));
+    assert_eq!(hit("x[+0]"), Ok(("", Hit)));
+    assert_eq!(hit("x[+200]"), Ok(("[+200]", Hit)));
+    assert_eq!(
+        hit("s"),
+        Ok(("", Stroke(Stroke { velocity: 96, fine: 0, offset: 0, articulation: Articulation::SideStick, pitch: None })))
+    );
+    assert_eq!(
+        hit("r[+1]"),
+        Ok(("", Stroke(Stroke { velocity: DEFAULT_VELOCITY, fine: 0, offset: 1, articulation: Articulation::Rimshot, pitch: None })))
+    );
+}
+
+#[test]
+fn test_parse_pitch() {
+    let c2 = Stroke { pitch: Some(Pitch::Note(36)), ..Default::default() };
+    assert_eq!(hit("x[C2]"), Ok(("", Stroke(c2))));
+    assert_eq!(hit("x[F#3][-2]"), Ok(("", Stroke(Stroke { pitch: Some(Pitch::Note(54)), offset: -2, ..Default::default() }))));
+    assert_eq!(hit("s[Bb1]").map(|(_, n)| n.stroke().pitch), Ok(Some(Pitch::Note(34))));
+    assert_eq!(hit("x[G9]").map(|(_, n)| n.stroke().pitch), Ok(Some(Pitch::Note(127))));
+    assert_eq!(hit("x[A9]"), Ok(("[A9]", Hit)));
+    assert_eq!(hit("x[H2]"), Ok(("[H2]", Hit)));
+    let line = groups("8x[C2]-x[Eb2][+2]x").unwrap().1;
+    assert_eq!(line.to_string(), "8x[C2]-x[D#2][+2]x");
+    let degrees = Degree::from_str("b3").map(Pitch::Degree);
+    assert_eq!(hit("x[b3]").map(|(_, n)| n.stroke().pitch), Ok(degrees.ok()));
+    assert_eq!(groups("16x[1]x[5]x[##4]x").unwrap().1.to_string(), "16x[1]x[5]x[##4]x");
+    assert_eq!(hit("x[0]"), Ok(("[0]", Hit)));
+}
+
+#[test]
+fn test_flatten_roll() {
+    // Rolls expand inside repeats, the notes around them keep their length.
+    let (_, parsed) = groups("(2,8-x*4)4x*3>").unwrap();
+    assert_eq!(parsed.to_string(), "8-32xxxx8-32xxxx8txxx");
+    let velocities: Vec<u8> = parsed.0.last().unwrap().notes.iter().map(|n| n.stroke().velocity).collect();
+    assert_eq!(velocities, vec![127, 84, 42]);
+    assert_eq!(groups("(2,8-x*4)").unwrap().1.to_128th(), groups("(2,8-x)").unwrap().1.to_128th());
+    assert_eq!(groups("8x*2<").unwrap().1.0[0].notes, vec![Stroke(Stroke { velocity: 63, fine: 64, ..Default::default() }), Stroke(Stroke { velocity: 127, ..Default::default() })]);
+}
+
 #[test]
 fn parse_length() {
     assert_eq!(length("16"), Ok(("", *SIXTEENTH)));
@@ -638,6 +2004,16 @@ fn parse_length() {
     );
     assert_eq!(length("8t"), Ok(("", *EIGHTH_TRIPLET)));
     assert_eq!(length("4.t"), Ok(("", *FOURTH_DOTTED_TRIPLET)));
+    assert_eq!(
+        length("4..+16..."),
+        Ok((
+            "",
+            Length::Tied(
+                ModdedLength::MultiDotted(BasicLength::Fourth, 2),
+                ModdedLength::MultiDotted(BasicLength::Sixteenth, 3)
+            )
+        ))
+    );
 }
 
 #[test]
@@ -739,6 +2115,19 @@ fn test_parse_group() {
     );
 }
 
+#[test]
+fn test_display_roundtrip() {
+    for pattern in ["8x-(7,8xx)", "(3,16x(3,8txxx(3,32x-x-x-)))", "8.t-xxx", "(2,16+32x-xx)", "(2,8x__-)", "4..x8...-", "8x*4-(2,16x*2<)4.x*3>", "(3,16x[+3]-x[-12])", "8xsr[-2]-s"] {
+        let (_, group) = group_or_delimited_group(pattern).unwrap();
+        assert_eq!(group_or_delimited_group(&group.to_string()), Ok(("", group.clone())));
+    }
+    let (_, parsed) = groups("32xx16xx").unwrap();
+    assert_eq!(parsed.to_string(), "32xx16xx");
+    let (_, parsed) = groups("8x16_").unwrap();
+    assert_eq!(parsed.0[1].notes, vec![Tie]);
+    assert_eq!(parsed.to_string(), "8x16_");
+}
+
 #[test]
 fn parse_delimited_group() {
     assert_eq!(
@@ -800,7 +2189,7 @@ fn test_parse_group_or_delimited_group() {
         group_or_delimited_group("(7,8xx"),
         Err(Err::Error(nom::error::make_error(
             "(7,8xx",
-            nom::error::ErrorKind::Digit
+            nom::error::ErrorKind::Char
         )))
     );
 }
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dsl::dsl::parse_pattern;
+use crate::dsl::macros::{expand, Macro};
+use crate::error::{Error, ParseError};
+use crate::midi::core::DrumPart;
+use crate::midi::names::TrackName;
+use crate::midi::registry::{CustomPart, Layer, PartRegistry, PartVelocity};
+use crate::midi::tempo::Modulation;
+
+#[allow(unused_imports)]
+use crate::midi::core::Part;
+
+/// Contents of a `.poly` pattern file. Every line assigns a pattern to a drum part, declares a
+/// part of its own, layers a part, sets the velocities of a part, names a track, modulates the
+/// tempo or defines a macro for the lines after it, empty lines and everything after `#` are
+/// ignored:
+///
+/// ```text
+/// # Bleed
+/// $backbeat(len) = len-x-len-x
+/// kick = 32xx16xx
+/// hihat = 8x
+/// snare = $backbeat(4)
+/// part "Cajon" key=48 velocity=100
+/// Cajon = 16x-x-
+/// layer kick key=35 channel=2
+/// velocity snare=45:30-60
+/// track Verse
+/// track bass=Walking
+/// modulate 8.=4@5
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PatternFile {
+    /// Patterns of the parts, with their macros written out.
+    pub parts: BTreeMap<DrumPart, String>,
+    /// Parts, layers and velocities declared by the file, parts should be declared before
+    /// their patterns, layers and velocities.
+    pub registry: PartRegistry,
+    /// Names of the tracks, the ones of parts after the declarations of the parts.
+    pub names: Vec<TrackName>,
+    /// Metric modulations, in the order they're written.
+    pub modulations: Vec<Modulation>,
+    pub macros: Vec<Macro>,
+}
+
+impl FromStr for PatternFile {
+    type Err = Error;
+
+    /// Reports every problem of the file at once, including malformed patterns.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = BTreeMap::new();
+        let mut registry = PartRegistry::default();
+        let mut names = Vec::new();
+        let mut modulations = Vec::new();
+        let mut macros: Vec<Macro> = Vec::new();
+        let mut errors = Vec::new();
+        let mut line_start = 0;
+        for (n, raw) in s.split('\n').enumerate() {
+            let offset = line_start;
+            line_start += raw.len() + 1;
+            let content = raw.split('#').next().unwrap_or("");
+            let start = content.len() - content.trim_start().len();
+            let line = content.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('$') {
+                match Macro::from_str(line) {
+                    Ok(m) if macros.iter().any(|other| other.name == m.name) => {
+                        errors.push(ParseError::at(s, offset + start, format!("${} is defined more than once", m.name)));
+                    }
+                    Ok(m) => macros.push(m),
+                    Err(e) => errors.push(ParseError::at(s, offset + start, e)),
+                }
+                continue;
+            }
+            let declaration = line
+                .strip_prefix("part")
+                .filter(|d| d.starts_with(char::is_whitespace) && !d.trim_start().starts_with('='));
+            if let Some(declaration) = declaration {
+                if let Err(e) = CustomPart::from_str(declaration).and_then(|p| registry.declare(p)) {
+                    errors.push(ParseError::at(s, offset + start, e));
+                }
+                continue;
+            }
+            let layer = line
+                .strip_prefix("layer")
+                .filter(|l| l.starts_with(char::is_whitespace) && !l.trim_start().starts_with('='));
+            if let Some(layer) = layer {
+                match Layer::parse(layer, &registry) {
+                    Ok(layer) => registry.layer(layer),
+                    Err(e) => errors.push(ParseError::at(s, offset + start, e)),
+                }
+                continue;
+            }
+            let velocity = line
+                .strip_prefix("velocity")
+                .filter(|v| v.starts_with(char::is_whitespace) && !v.trim_start().starts_with('='));
+            if let Some(velocity) = velocity {
+                match PartVelocity::parse(velocity, &registry) {
+                    Ok(velocity) => registry.set_velocity(velocity),
+                    Err(e) => errors.push(ParseError::at(s, offset + start, e)),
+                }
+                continue;
+            }
+            let track = line
+                .strip_prefix("track")
+                .filter(|t| t.starts_with(char::is_whitespace) && !t.trim_start().starts_with('='));
+            if let Some(track) = track {
+                match TrackName::parse(track.trim(), &registry) {
+                    Ok(name) => names.push(name),
+                    Err(e) => errors.push(ParseError::at(s, offset + start, e)),
+                }
+                continue;
+            }
+            let modulation = line
+                .strip_prefix("modulate")
+                .filter(|m| m.starts_with(char::is_whitespace) && !m.trim_start().starts_with('='));
+            if let Some(modulation) = modulation {
+                match Modulation::from_str(modulation.trim()) {
+                    Ok(modulation) => modulations.push(modulation),
+                    Err(e) => errors.push(ParseError::at(s, offset + start, e)),
+                }
+                continue;
+            }
+            let Some((name, pattern)) = line.split_once('=') else {
+                errors.push(ParseError::at(s, offset + start, format!("expected `part = pattern`, got `{}`", line)));
+                continue;
+            };
+            let part = match registry.part(name.trim()) {
+                Ok(part) => part,
+                Err(e) => {
+                    errors.push(ParseError::at(s, offset + start, e));
+                    continue;
+                }
+            };
+            let pattern_start = start + name.len() + 1 + (pattern.len() - pattern.trim_start().len());
+            let written = pattern.trim();
+            let pattern = match expand(written, &macros) {
+                Ok(pattern) => pattern,
+                Err((at, e)) => {
+                    errors.push(ParseError::at(s, offset + pattern_start + at, e));
+                    continue;
+                }
+            };
+            if let Err(Error::Parse(pattern_errors)) = parse_pattern(&pattern) {
+                // Problems of a pattern with macros are shown in the pattern they make.
+                let column = raw[..pattern_start].chars().count() + 1;
+                let source_line = format!("{}{}{}", &raw[..pattern_start], pattern, &raw[pattern_start + written.len()..]);
+                let source_line = source_line.trim_end_matches('\r');
+                errors.extend(pattern_errors.into_iter().map(|e| e.within(source_line, n + 1, column)));
+            }
+            if parts.insert(part, pattern).is_some() {
+                errors.push(ParseError::at(s, offset + start, format!("{} is defined more than once", name.trim())));
+            }
+        }
+        if errors.is_empty() {
+            Ok(PatternFile { parts, registry, names, modulations, macros })
+        } else {
+            Err(Error::Parse(errors))
+        }
+    }
+}
+
+impl fmt::Display for PatternFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for m in self.macros.iter() {
+            writeln!(f, "{}", m)?;
+        }
+        for part in self.registry.custom() {
+            writeln!(f, "part {}", part)?;
+        }
+        for layer in self.registry.layers() {
+            writeln!(f, "layer {}", layer.to_string_with(&self.registry))?;
+        }
+        for velocity in self.registry.velocities() {
+            writeln!(f, "velocity {}", velocity.to_string_with(&self.registry))?;
+        }
+        for (part, pattern) in self.parts.iter() {
+            writeln!(f, "{} = {}", self.registry.name(*part), pattern)?;
+        }
+        for name in self.names.iter() {
+            writeln!(f, "track {}", name.to_string_with(&self.registry))?;
+        }
+        for modulation in self.modulations.iter() {
+            writeln!(f, "modulate {}", modulation)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pattern_file_from_str() {
+    let file = PatternFile::from_str("# Bleed\nkick = 32xx16xx\n\nhihat = 8x # steady\nsnare=4--x-\n").unwrap();
+    assert_eq!(
+        file.parts,
+        BTreeMap::from_iter([
+            (DrumPart::KickDrum, "32xx16xx".to_string()),
+            (DrumPart::HiHat, "8x".to_string()),
+            (DrumPart::SnareDrum, "4--x-".to_string()),
+        ])
+    );
+    assert_eq!(PatternFile::from_str(&file.to_string()), Ok(file));
+    assert!(PatternFile::from_str("kick 8x").is_err());
+    assert!(PatternFile::from_str("kick = 8x\nkick = 4x").is_err());
+}
+
+#[test]
+fn test_pattern_file_custom_parts() {
+    let file = PatternFile::from_str("part \"Low Tom\" key=45 channel=2\nkick = 4x\nLow Tom = 8-x\n").unwrap();
+    let tom = file.registry.part("Low Tom").unwrap();
+    assert_eq!(file.parts.get(&tom), Some(&"8-x".to_string()));
+    assert_eq!(file.to_string(), "part \"Low Tom\" key=45 channel=2 velocity=127\nkick = 4x\nLow Tom = 8-x\n");
+    assert_eq!(PatternFile::from_str(&file.to_string()), Ok(file));
+    assert!(PatternFile::from_str("Low Tom = 8-x\npart \"Low Tom\" key=45").is_err());
+    assert!(PatternFile::from_str("part kick key=45").is_err());
+}
+
+#[test]
+fn test_pattern_file_track_names() {
+    let file = PatternFile::from_str("part \"Low Tom\" key=45\nkick = 4x\ntrack Verse\ntrack Low Tom=Floor\n").unwrap();
+    let tom = file.registry.part("Low Tom").unwrap();
+    assert_eq!(file.names[1], TrackName { part: Some(Part::Drum(tom)), name: "Floor".to_string() });
+    assert_eq!(PatternFile::from_str(&file.to_string()), Ok(file));
+    assert!(PatternFile::from_str("track cajon=Cajon\nkick = 4x").is_err());
+
+    let file = PatternFile::from_str("modulate 8.=4@5\nkick = 4x\n").unwrap();
+    assert_eq!(file.modulations, vec![Modulation::from_str("8.=4@5").unwrap()]);
+    assert_eq!(file.to_string(), "kick = 4x\nmodulate 8.=4@5\n");
+    assert_eq!(PatternFile::from_str(&file.to_string()), Ok(file));
+    assert!(PatternFile::from_str("modulate 8.=4\nkick = 4x").is_err());
+}
+
+#[test]
+fn test_pattern_file_layers() {
+    let file = PatternFile::from_str("kick = 4x\nlayer kick key=35 channel=2\n").unwrap();
+    assert_eq!(file.registry.layers().len(), 1);
+    assert_eq!(file.to_string(), "layer \"kick\" key=35 channel=2 velocity=127\nkick = 4x\n");
+    assert_eq!(PatternFile::from_str(&file.to_string()), Ok(file));
+    assert!(PatternFile::from_str("layer kick channel=2").is_err());
+
+    let file = PatternFile::from_str("snare = 4-x\nvelocity snare=45:30-60\n").unwrap();
+    assert_eq!(file.to_string(), "velocity snare=45:30-60\nsnare = 4-x\n");
+    assert_eq!(PatternFile::from_str(&file.to_string()), Ok(file));
+    assert!(PatternFile::from_str("velocity snare=0").is_err());
+}
+
+#[test]
+fn test_pattern_file_macros() {
+    let file = PatternFile::from_str("$backbeat(len) = len-x-len-x\n$fill = 16xxxx\nsnare = $backbeat(8)$fill\n").unwrap();
+    assert_eq!(file.parts.get(&DrumPart::SnareDrum), Some(&"8-x-8-x16xxxx".to_string()));
+    assert_eq!(file.to_string(), "$backbeat(len) = len-x-len-x\n$fill = 16xxxx\nsnare = 8-x-8-x16xxxx\n");
+    assert_eq!(PatternFile::from_str(&file.to_string()), Ok(file));
+    let errors = match PatternFile::from_str("kick = $fill\n$fill = 16xxxx\n$fill = 8x\nsnare = $fill(4)\nhihat = 8x$backbeat\n$backbeat(len) = len-y\ncrash = $backbeat(8)") {
+        Err(Error::Parse(errors)) => errors,
+        other => panic!("expected parse errors, got {:?}", other),
+    };
+    let locations: Vec<_> = errors.iter().map(|e| (e.line, e.column)).collect();
+    assert_eq!(locations, vec![(1, 8), (3, 1), (4, 9), (5, 11), (7, 11)]);
+    assert_eq!(errors[0].message, "`$fill` is not a macro defined before this line");
+    assert_eq!(errors[4].source_line, "crash = 8-y");
+}
+
+#[test]
+fn test_pattern_file_reports_every_problem() {
+    let errors = match PatternFile::from_str("kick = 8x\ncajon = 4x\n  snare = 4-y # fill\nhihat = 8x)\n") {
+        Err(Error::Parse(errors)) => errors,
+        other => panic!("expected parse errors, got {:?}", other),
+    };
+    let locations: Vec<_> = errors.iter().map(|e| (e.line, e.column)).collect();
+    assert_eq!(locations, vec![(2, 1), (3, 13), (4, 11)]);
+    assert_eq!(errors[0].message, "cajon is not a known drum part, expected one of: kick, snare, hihat, crash, cowbell, claves, conga-open, conga-slap, conga-mute, bongo-high, bongo-low, shaker, tambourine");
+    assert_eq!(errors[1].source_line, "  snare = 4-y # fill");
+}
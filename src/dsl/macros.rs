@@ -0,0 +1,195 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// A pattern template of a pattern file, written `$fill = 16xxxx` and used in the patterns
+/// after it as `$fill`. It can take parameters, `$backbeat(len) = len-x-len-x` makes
+/// `$backbeat(8)` read `8-x-8-x`. Macros are expanded before the patterns are parsed, so an
+/// argument can be any text a pattern can hold, other macros included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Macro {
+    pub name: String,
+    /// Names the arguments are written in place of in the body.
+    pub params: Vec<String>,
+    pub body: String,
+}
+
+impl FromStr for Macro {
+    type Err = String;
+
+    /// Reads a definition like `$backbeat(len) = len-x-len-x`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || format!("expected a macro like `$fill = 16xxxx` or `$backbeat(len) = len-x-len-x`, got `{}`", s.trim());
+        let (head, body) = s.trim().strip_prefix('$').and_then(|d| d.split_once('=')).ok_or_else(malformed)?;
+        let head = head.trim();
+        let (name, params) = match head.split_once('(') {
+            Some((name, params)) => {
+                let params = params.strip_suffix(')').ok_or_else(malformed)?;
+                (name, params.split(',').map(|p| p.trim().to_string()).collect())
+            }
+            None => (head, Vec::new()),
+        };
+        if !is_name(name) {
+            return Err(format!("`{}` can't name a macro, names are made of letters and `_`", name));
+        }
+        if let Some(param) = params.iter().find(|p| !is_name(p)) {
+            return Err(format!("`{}` can't name a parameter of `${}`, names are made of letters and `_`", param, name));
+        }
+        if let Some(param) = params.iter().enumerate().find(|(i, p)| params[..*i].contains(p)).map(|(_, p)| p) {
+            return Err(format!("`${}` has more than one parameter called `{}`", name, param));
+        }
+        Ok(Macro {
+            name: name.to_string(),
+            params,
+            body: body.trim().to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Macro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.params.is_empty() {
+            true => write!(f, "${} = {}", self.name, self.body),
+            false => write!(f, "${}({}) = {}", self.name, self.params.join(", "), self.body),
+        }
+    }
+}
+
+impl Macro {
+    /// The body with `args` written in place of the parameters. Parameters are found anywhere
+    /// but in the names of macros, the longest one wins where they overlap.
+    fn apply(&self, args: &[&str]) -> String {
+        let mut params: Vec<(&String, &&str)> = self.params.iter().zip(args.iter()).collect();
+        params.sort_by_key(|(param, _)| std::cmp::Reverse(param.len()));
+        let mut out = String::new();
+        let mut rest = self.body.as_str();
+        while let Some(c) = rest.chars().next() {
+            if c == '$' {
+                let name = rest[1..].find(|c| !is_name_char(c)).map_or(rest.len(), |i| i + 1);
+                out.push_str(&rest[..name]);
+                rest = &rest[name..];
+                continue;
+            }
+            match params.iter().find(|(param, _)| rest.starts_with(param.as_str())) {
+                Some((param, arg)) => {
+                    out.push_str(arg);
+                    rest = &rest[param.len()..];
+                }
+                None => {
+                    out.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Names end at anything a pattern is written with, so `$fill8x` plays `8x` after the macro.
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(is_name_char)
+}
+
+/// Writes out the macros a pattern uses, and the ones they use. Problems are reported with the
+/// byte offset of the use of the macro they're in.
+pub fn expand(pattern: &str, macros: &[Macro]) -> Result<String, (usize, String)> {
+    expand_(pattern, macros, &mut Vec::new())
+}
+
+fn expand_(pattern: &str, macros: &[Macro], using: &mut Vec<String>) -> Result<String, (usize, String)> {
+    let mut out = String::new();
+    let mut at = 0;
+    while let Some(start) = pattern[at..].find('$').map(|i| at + i) {
+        out.push_str(&pattern[at..start]);
+        let name_end = pattern[start + 1..].find(|c| !is_name_char(c)).map_or(pattern.len(), |i| start + 1 + i);
+        let name = &pattern[start + 1..name_end];
+        let Some(used) = macros.iter().find(|m| m.name == name) else {
+            return Err((start, format!("`${}` is not a macro defined before this line", name)));
+        };
+        let (args, end) = match pattern[name_end..].starts_with('(') {
+            true => arguments(pattern, name_end).ok_or_else(|| (name_end, format!("expected `)` to close the arguments of `${}`", name)))?,
+            false => (Vec::new(), name_end),
+        };
+        if args.len() != used.params.len() {
+            let expected = match used.params.len() {
+                0 => "no arguments".to_string(),
+                1 => format!("1 argument ({})", used.params[0]),
+                n => format!("{} arguments ({})", n, used.params.join(", ")),
+            };
+            return Err((start, format!("`${}` takes {}, got {}", name, expected, args.len())));
+        }
+        if using.iter().any(|u| u == name) {
+            return Err((start, format!("`${}` uses itself", name)));
+        }
+        using.push(name.to_string());
+        let body = expand_(&used.apply(&args), macros, using).map_err(|(_, e)| (start, format!("in `${}`: {}", name, e)))?;
+        using.pop();
+        out.push_str(&body);
+        at = end;
+    }
+    out.push_str(&pattern[at..]);
+    Ok(out)
+}
+
+/// Arguments in the parentheses at `open`, split at the commas outside of groups, and where
+/// they end. `None` if the parentheses aren't closed.
+fn arguments(pattern: &str, open: usize) -> Option<(Vec<&str>, usize)> {
+    let mut depth = 0;
+    let mut start = open + 1;
+    let mut args = Vec::new();
+    for (i, c) in pattern[open..].char_indices().map(|(i, c)| (open + i, c)) {
+        match c {
+            '(' | '{' | '[' => depth += 1,
+            ')' | '}' | ']' if depth > 1 => depth -= 1,
+            ')' => {
+                args.push(pattern[start..i].trim());
+                // Empty parentheses hold no arguments, `$fill()x` ends the name before a note.
+                if args == [""] {
+                    args.clear();
+                }
+                return Some((args, i + 1));
+            }
+            ',' if depth == 1 => {
+                args.push(pattern[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+#[test]
+fn test_macro_from_str() {
+    let backbeat = Macro::from_str("$backbeat(len, hit) = len-hit-len-hit").unwrap();
+    assert_eq!(backbeat.params, vec!["len".to_string(), "hit".to_string()]);
+    assert_eq!(backbeat.to_string(), "$backbeat(len, hit) = len-hit-len-hit");
+    assert_eq!(Macro::from_str(&backbeat.to_string()), Ok(backbeat));
+    assert_eq!(Macro::from_str("$fill=16xxxx").unwrap().to_string(), "$fill = 16xxxx");
+    assert!(Macro::from_str("$8 = 16xxxx").is_err());
+    assert!(Macro::from_str("$fill(len = 16xxxx").is_err());
+    assert!(Macro::from_str("$fill(len, len) = len").is_err());
+    assert!(Macro::from_str("fill = 16xxxx").is_err());
+}
+
+#[test]
+fn test_expand() {
+    let macros: Vec<Macro> = ["$backbeat(len) = len-x-len-x", "$fill = 16xxxx", "$bar(beat, end) = $backbeat(beat)end", "$loop = 4x$loop"]
+        .iter()
+        .map(|m| m.parse().unwrap())
+        .collect();
+    assert_eq!(expand("$backbeat(8)", &macros), Ok("8-x-8-x".to_string()));
+    assert_eq!(expand("4x$fill8x", &macros), Ok("4x16xxxx8x".to_string()));
+    assert_eq!(expand("$fill()x", &macros), Ok("16xxxxx".to_string()));
+    // Arguments hold groups and other macros, and are written in wherever a parameter is.
+    assert_eq!(expand("$bar(16, (3,8x)$fill)", &macros), Ok("16-x-16-x(3,8x)16xxxx".to_string()));
+    assert_eq!(expand("8x-", &macros), Ok("8x-".to_string()));
+    assert_eq!(expand("8x$drop", &macros), Err((2, "`$drop` is not a macro defined before this line".to_string())));
+    assert_eq!(expand("$backbeat(8, 16)", &macros), Err((0, "`$backbeat` takes 1 argument (len), got 2".to_string())));
+    assert_eq!(expand("$fill(8)", &macros), Err((0, "`$fill` takes no arguments, got 1".to_string())));
+    assert_eq!(expand("4$backbeat(8", &macros), Err((10, "expected `)` to close the arguments of `$backbeat`".to_string())));
+    assert_eq!(expand("8x$loop", &macros), Err((2, "in `$loop`: `$loop` uses itself".to_string())));
+}
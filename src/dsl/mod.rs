@@ -1 +1,6 @@
-pub mod dsl;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod dsl;
+pub mod file;
+pub mod macros;
+pub mod onsets;
+pub mod pitch;
@@ -0,0 +1,53 @@
+use crate::dsl::dsl::{Group, Groups, Length, Note};
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::{groups, KnownLength};
+#[allow(unused_imports)]
+use crate::midi::grid::Grid;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Whether a pattern is written as bare onsets the way step sequencers show them, `x..x..x.`:
+/// `x` or `X` for a hit, `.` or `-` for a step without one, spaces and `|` between beats and
+/// bars. It has no length, so it takes a hit and can't read as a pattern of the DSL.
+pub fn is_onsets(s: &str) -> bool {
+    let steps: Vec<char> = s.chars().filter(|c| !c.is_whitespace() && *c != '|').collect();
+    steps.iter().any(|c| matches!(c, 'x' | 'X')) && steps.iter().all(|c| matches!(c, 'x' | 'X' | '.' | '-'))
+}
+
+/// The notes of bare onsets, every character a `step` long.
+pub fn parse_onsets(s: &str, step: Length) -> Result<Groups, String> {
+    let mut notes = Vec::new();
+    for c in s.chars().filter(|c| !c.is_whitespace() && *c != '|') {
+        notes.push(match c {
+            'x' | 'X' => Note::Hit,
+            '.' | '-' => Note::Rest,
+            c => return Err(format!("expected onsets like x..x..x., `x` for a hit and `.` for a rest, got `{}` in `{}`", c, s.trim())),
+        });
+    }
+    if notes.is_empty() {
+        return Err("there are no steps in the onsets".to_string());
+    }
+    Ok(Groups(vec![Group { notes, length: step, times: () }]))
+}
+
+#[test]
+fn test_onsets() {
+    let sixteenths = Grid::from_str("16").unwrap().step_length();
+    assert!(is_onsets("x..x..x."));
+    assert!(is_onsets("X--x | ..x. "));
+    assert!(!is_onsets("8x-x-"));
+    assert!(!is_onsets("...."));
+    assert!(!is_onsets("x_x."));
+    let tresillo = parse_onsets("x..x..x.", sixteenths).unwrap();
+    assert_eq!(tresillo.to_string(), "16x--x--x-");
+    assert_eq!(tresillo, groups("16x--x--x-").unwrap().1);
+    assert_eq!(tresillo.to_128th(), 64);
+    // Bar lines and spaces are only there to read the steps by.
+    assert_eq!(parse_onsets("x... x... | X.x. x...", sixteenths).unwrap().to_string(), "16x---x---x-x-x---");
+    let triplets = parse_onsets("x.xx.x", Grid::from_str("8t").unwrap().step_length()).unwrap();
+    assert_eq!(triplets.to_128th(), 64);
+    assert_eq!(triplets, groups("8tx-xx-x").unwrap().1);
+    assert!(parse_onsets("x.o.", sixteenths).is_err());
+    assert!(parse_onsets(" | ", sixteenths).is_err());
+}
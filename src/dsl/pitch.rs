@@ -0,0 +1,168 @@
+use std::fmt;
+use std::str::FromStr;
+
+pub(crate) const NOTE_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+/// Name of a MIDI key with its octave, like `C2` for key 36. Octaves go as in scientific pitch
+/// notation, so middle C is `C4`.
+pub fn note_name(key: u8) -> String {
+    format!("{}{}", NOTE_NAMES[key as usize % 12], key as i32 / 12 - 1)
+}
+
+/// A key, written `E`, `F#2` or `A1m`: the root, its octave, 1 if it's left out, and `m` for
+/// a minor key. Octaves go as in scientific pitch notation, so `E1` is the low string of a
+/// bass guitar, MIDI key 28, and the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key {
+    /// MIDI key of the root.
+    pub root: u8,
+    pub minor: bool,
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        Key { root: 28, minor: false }
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("expected a key like E, F#2 or A1m, got `{}`", s);
+        let mut rest = s.trim();
+        let mut class: i32 = match rest.chars().next().map(|c| c.to_ascii_uppercase()) {
+            Some('C') => 0,
+            Some('D') => 2,
+            Some('E') => 4,
+            Some('F') => 5,
+            Some('G') => 7,
+            Some('A') => 9,
+            Some('B') => 11,
+            _ => return Err(error()),
+        };
+        rest = &rest[1..];
+        if let Some(r) = rest.strip_prefix('#') {
+            class += 1;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('b') {
+            class -= 1;
+            rest = r;
+        }
+        let minor = match rest.strip_suffix('m') {
+            Some(r) => {
+                rest = r;
+                true
+            }
+            None => false,
+        };
+        let octave: i32 = match rest {
+            "" => 1,
+            octave => octave.parse().map_err(|_| error())?,
+        };
+        match u8::try_from((octave + 1) * 12 + class) {
+            Ok(root) if root <= 127 => Ok(Key { root, minor }),
+            _ => Err(format!("{} is beyond the MIDI keys", s.trim())),
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", note_name(self.root), if self.minor { "m" } else { "" })
+    }
+}
+
+/// A degree of the scale of the key, written `5`, `b7` or `#4`. Degrees past 7 go up an
+/// octave, `8` is the root an octave up. Flats and sharps alter the degree of the major scale
+/// of the root, so `b3` is a minor third in any key, the way chord charts write it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Degree {
+    /// From 1.
+    pub step: u8,
+    /// Semitones the degree is raised by, or lowered if negative.
+    pub shift: i8,
+}
+
+impl Degree {
+    /// Semitones from the root of a `key` to the degree.
+    pub fn semitones(&self, key: Key) -> i32 {
+        const MAJOR: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+        const MINOR: [i32; 7] = [0, 2, 3, 5, 7, 8, 10];
+        let scale = if key.minor && self.shift == 0 { MINOR } else { MAJOR };
+        let step = self.step as usize - 1;
+        (step / 7) as i32 * 12 + scale[step % 7] + self.shift as i32
+    }
+}
+
+impl FromStr for Degree {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let trimmed = s.trim();
+        let shift = trimmed.chars().take_while(|c| *c == 'b' || *c == '#').map(|c| if c == '#' { 1 } else { -1 }).sum::<i8>();
+        let step = trimmed.trim_start_matches(['b', '#']);
+        match step.parse::<u8>() {
+            Ok(step) if (1..=22).contains(&step) => Ok(Degree { step, shift }),
+            _ => Err(format!("expected a degree of the scale from 1 to 22 like 5 or b7, got `{}`", trimmed)),
+        }
+    }
+}
+
+impl fmt::Display for Degree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let accidental = if self.shift < 0 { "b" } else { "#" };
+        write!(f, "{}{}", accidental.repeat(self.shift.unsigned_abs() as usize), self.step)
+    }
+}
+
+/// The note a hit plays, written in square brackets after it: a note name like `x[C2]`, or a
+/// degree of the key like `x[b3]`, which moves along with the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pitch {
+    /// A MIDI key.
+    Note(u8),
+    Degree(Degree),
+}
+
+impl Pitch {
+    /// MIDI key of the pitch in a `key`.
+    pub fn key(&self, key: Key) -> u8 {
+        match self {
+            Pitch::Note(note) => *note,
+            Pitch::Degree(degree) => (key.root as i32 + degree.semitones(key)).clamp(0, 127) as u8,
+        }
+    }
+}
+
+impl fmt::Display for Pitch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pitch::Note(note) => write!(f, "{}", note_name(*note)),
+            Pitch::Degree(degree) => write!(f, "{}", degree),
+        }
+    }
+}
+
+#[test]
+fn test_keys_and_degrees() {
+    assert_eq!(Key::from_str("E"), Ok(Key::default()));
+    assert_eq!(Key::from_str("A1m"), Ok(Key { root: 33, minor: true }));
+    assert_eq!(Key::from_str("F#2"), Ok(Key { root: 42, minor: false }));
+    assert_eq!(Key::from_str("Bb0").map(|k| k.root), Ok(22));
+    assert_eq!(Key::from_str("A1m").unwrap().to_string(), "A1m");
+    assert!(Key::from_str("H2").is_err());
+    assert!(Key::from_str("A9").is_err());
+    assert_eq!(Degree::from_str("b7").unwrap().to_string(), "b7");
+    assert!(Degree::from_str("0").is_err());
+    assert!(Degree::from_str("x").is_err());
+    assert_eq!(note_name(60), "C4");
+    let c4 = Key::from_str("C4").unwrap();
+    assert_eq!(Pitch::Degree(Degree::from_str("b3").unwrap()).key(c4), 63);
+    let a_minor = Key::from_str("A3m").unwrap();
+    assert_eq!(Pitch::Degree(Degree::from_str("9").unwrap()).key(a_minor), 71);
+    assert_eq!(Pitch::Degree(Degree::from_str("b3").unwrap()).key(a_minor), 60);
+    assert_eq!(Pitch::Degree(Degree::from_str("6").unwrap()).key(a_minor), 65);
+    assert_eq!(Pitch::Degree(Degree::from_str("#6").unwrap()).key(a_minor), 67);
+    assert_eq!(Pitch::Note(36).key(c4), 36);
+}
@@ -0,0 +1,183 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::api::json::Json;
+use crate::dsl::dsl::KnownLength;
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Errors returned by the library.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A pattern doesn't follow the DSL, with every problem found in it.
+    Parse(Vec<ParseError>),
+    /// A pattern doesn't fill a whole number of bars, `length` is in 128th notes.
+    Misaligned {
+        part: DrumPart,
+        length: u32,
+        time_signature: TimeSignature,
+    },
+    /// Every part only rests or is muted, so the file would have no notes.
+    Silent,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Parse(errors) => {
+                for (i, e) in errors.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "\n\n")?;
+                    }
+                    write!(f, "{}", e)?;
+                }
+                Ok(())
+            }
+            Error::Misaligned {
+                part,
+                length,
+                time_signature,
+            } => {
+                let bar = time_signature.to_128th();
+                write!(
+                    f,
+                    "{} pattern lasts {}, which is {} short of filling whole bars of {}",
+                    part.name(),
+                    whole_notes(*length),
+                    whole_notes(bar - length % bar),
+                    time_signature
+                )
+            }
+            Error::Silent => write!(f, "every part only rests or is muted, the file would be silent"),
+        }
+    }
+}
+
+/// Renders a length in 128th notes as a fraction of a whole note.
+fn whole_notes(length: u32) -> String {
+    let (mut a, mut b) = (length, 128);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let divisor = a.max(1);
+    format!("{}/{}", length / divisor, 128 / divisor)
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    /// Every problem as an object with its `message`, and where it is for errors of parsing,
+    /// for editors to point at:
+    ///
+    /// ```json
+    /// [{ "column": 10, "line": 1, "message": "expected `)` to close the group", "source": "8x-(7,8xx" }]
+    /// ```
+    pub fn to_json(&self) -> Json {
+        match self {
+            Error::Parse(errors) => Json::Array(errors.iter().map(ParseError::to_json).collect()),
+            Error::Misaligned { part, length, .. } => Json::Array(vec![Json::Object(BTreeMap::from_iter([
+                ("message".to_string(), Json::String(self.to_string())),
+                ("part".to_string(), Json::String(part.name().to_string())),
+                ("length".to_string(), Json::Number(*length as f64)),
+            ]))]),
+            Error::Silent => Json::Array(vec![Json::Object(BTreeMap::from_iter([("message".to_string(), Json::String(self.to_string()))]))]),
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(vec![e])
+    }
+}
+
+/// Location of a problem in a pattern with a human-readable explanation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// 1-based line of the problem.
+    pub line: usize,
+    /// 1-based column of the problem, counted in characters.
+    pub column: usize,
+    /// The whole line the problem is on.
+    pub source_line: String,
+    pub message: String,
+}
+
+impl ParseError {
+    /// Builds an error pointing at a byte `offset` of `input`.
+    pub fn at(input: &str, offset: usize, message: String) -> Self {
+        let offset = offset.min(input.len());
+        let before = &input[..offset];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = input[offset..].find('\n').map(|i| offset + i).unwrap_or(input.len());
+        ParseError {
+            line: before.matches('\n').count() + 1,
+            column: input[line_start..offset].chars().count() + 1,
+            source_line: input[line_start..line_end].trim_end_matches('\r').to_string(),
+            message,
+        }
+    }
+
+    /// Moves an error of a pattern into a bigger text, where the pattern starts
+    /// at `line` and `column` of `source_line`.
+    pub fn within(self, source_line: &str, line: usize, column: usize) -> Self {
+        ParseError {
+            line,
+            column: self.column + column - 1,
+            source_line: source_line.to_string(),
+            ..self
+        }
+    }
+
+    /// The error as one of the objects of `Error::to_json`.
+    pub fn to_json(&self) -> Json {
+        Json::Object(BTreeMap::from_iter([
+            ("message".to_string(), Json::String(self.message.clone())),
+            ("line".to_string(), Json::Number(self.line as f64)),
+            ("column".to_string(), Json::Number(self.column as f64)),
+            ("source".to_string(), Json::String(self.source_line.clone())),
+        ]))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{}--> line {}, column {}", gutter, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source_line)?;
+        write!(f, "{} | {}^", gutter, " ".repeat(self.column - 1))
+    }
+}
+
+#[test]
+fn test_misaligned_display() {
+    let e = Error::Misaligned {
+        part: DrumPart::KickDrum,
+        length: 40,
+        time_signature: TimeSignature::from_str("4/4").unwrap(),
+    };
+    assert_eq!(e.to_string(), "kick pattern lasts 5/16, which is 11/16 short of filling whole bars of 4/4");
+}
+
+#[test]
+fn test_parse_error_display() {
+    let e = ParseError::at("8x-(7,8xx", 9, "expected `)` to close the group".to_string());
+    assert_eq!((e.line, e.column), (1, 10));
+    assert_eq!(
+        Error::from(e.clone()).to_json().to_string(),
+        r#"[{"column":10,"line":1,"message":"expected `)` to close the group","source":"8x-(7,8xx"}]"#
+    );
+    assert_eq!(
+        e.to_string(),
+        "error: expected `)` to close the group\n --> line 1, column 10\n  |\n1 | 8x-(7,8xx\n  |          ^"
+    );
+    let e = ParseError::at("kick = 8x\nsnare = 4-y", 18, "unexpected `y`".to_string());
+    assert_eq!((e.line, e.column, e.source_line.as_str()), (2, 9, "snare = 4-y"));
+    let e = ParseError::at("4-y", 2, "unexpected `y`".to_string()).within("snare = 4-y # fill", 7, 9);
+    assert_eq!((e.line, e.column, e.source_line.as_str()), (7, 11, "snare = 4-y # fill"));
+}
@@ -0,0 +1,94 @@
+use std::collections::BTreeMap;
+
+use crate::dsl::dsl::{Group, Groups, Length, Note};
+use crate::midi::core::DrumPart;
+use crate::midi::grid::Grid;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::{groups, KnownLength};
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Notes of a pattern on a grid of `step`, notes longer than a step held with ties, if every
+/// note is a whole number of steps long.
+pub fn on_grid(groups: &Groups, step: Length) -> Result<Vec<Note>, String> {
+    let (step_n, step_d) = step.fraction();
+    let mut notes = Vec::new();
+    for group in groups.0.iter() {
+        let (n, d) = group.length.fraction();
+        if !(n * step_d).is_multiple_of(d * step_n) {
+            return Err(format!("notes of {} aren't made of whole steps of {}", group.length, step));
+        }
+        let steps = (n * step_d / (d * step_n)) as usize;
+        for note in group.notes.iter() {
+            let held = if *note == Note::Rest { Note::Rest } else { Note::Tie };
+            notes.push(*note);
+            notes.extend(std::iter::repeat_n(held, steps - 1));
+        }
+    }
+    Ok(notes)
+}
+
+/// The notes played `by` steps later, the ones pushed past the end coming round to the start.
+pub fn displace(notes: &[Note], by: usize) -> Vec<Note> {
+    let mut displaced = notes.to_vec();
+    if !displaced.is_empty() {
+        displaced.rotate_right(by % notes.len());
+    }
+    displaced
+}
+
+/// The displacement exercise of a groove: the cycle of the parts played as it is, then a step
+/// of `grid` later, then two, until every step of the cycle has been the start, `repeats` times
+/// each. The cycle is as long as the longest part, the others are repeated to fill it. Returns
+/// the parts and the number of steps of a cycle.
+pub fn exercise(
+    parts: &BTreeMap<DrumPart, Groups>,
+    grid: Grid,
+    repeats: usize,
+) -> Result<(BTreeMap<DrumPart, Groups>, usize), String> {
+    let step = grid.step_length();
+    let mut notes = BTreeMap::new();
+    for (part, groups) in parts.iter() {
+        notes.insert(*part, on_grid(groups, step)?);
+    }
+    let cycle = notes.values().map(|n| n.len()).max().unwrap_or(0);
+    if cycle == 0 {
+        return Err("there's no groove to displace".to_string());
+    }
+    let mut exercise = BTreeMap::new();
+    for (part, notes) in notes {
+        if notes.is_empty() || !cycle.is_multiple_of(notes.len()) {
+            return Err(format!("a part of {} steps of {} doesn't fill a cycle of {} evenly", notes.len(), grid, cycle));
+        }
+        let notes = notes.repeat(cycle / notes.len());
+        let groups = (0..cycle)
+            .flat_map(|by| std::iter::repeat_n(displace(&notes, by), repeats))
+            .map(|notes| Group { notes, length: step, times: () })
+            .collect();
+        exercise.insert(part, groups);
+    }
+    Ok((exercise, cycle))
+}
+
+#[test]
+fn test_displacement() {
+    let sixteenths = Grid::from_str("16").unwrap();
+    let (_, eighths) = groups("8x-x-").unwrap();
+    let notes = on_grid(&eighths, sixteenths.step_length()).unwrap();
+    assert_eq!(Groups(vec![Group { notes: notes.clone(), length: sixteenths.step_length(), times: () }]).to_string(), "16x_--x_--");
+    assert_eq!(displace(&notes, 1), on_grid(&groups("16-x_--x_-").unwrap().1, sixteenths.step_length()).unwrap());
+    assert_eq!(displace(&notes, 8), notes);
+    assert!(on_grid(&groups("8tx-x").unwrap().1, sixteenths.step_length()).is_err());
+
+    let parts = BTreeMap::from_iter([
+        (DrumPart::KickDrum, groups("4x-").unwrap().1),
+        (DrumPart::HiHat, groups("8x").unwrap().1),
+    ]);
+    let (played, cycle) = exercise(&parts, Grid::from_str("8").unwrap(), 2).unwrap();
+    assert_eq!(cycle, 4);
+    assert_eq!(played[&DrumPart::KickDrum].to_string(), "8x_--8x_--8-x_-8-x_-8--x_8--x_8_--x8_--x");
+    assert_eq!(played[&DrumPart::HiHat].to_128th(), 8 * 16 * 4);
+    let uneven = BTreeMap::from_iter([(DrumPart::KickDrum, groups("8xxx").unwrap().1), (DrumPart::HiHat, groups("8xx").unwrap().1)]);
+    assert!(exercise(&uneven, Grid::from_str("8").unwrap(), 1).is_err());
+}
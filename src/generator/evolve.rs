@@ -0,0 +1,196 @@
+use std::collections::BTreeMap;
+
+use crate::analysis::metrics::{density, syncopation};
+use crate::dsl::dsl::{BasicLength, Group, Groups, Length, ModdedLength, Note};
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+use crate::random::Rng;
+
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// What the evolution is aiming for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    /// Desired share of hits among all notes.
+    pub density: f64,
+    /// Acceptable share of hits landing off the beat.
+    pub syncopation: (f64, f64),
+    /// Desired number of bars it takes for all the parts to converge.
+    pub bars: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settings {
+    pub population: usize,
+    pub generations: usize,
+    /// Note length of every step of a pattern.
+    pub subdivision: BasicLength,
+    /// Longest pattern a part can evolve into, in steps.
+    pub max_steps: usize,
+}
+
+/// A set of patterns, one for every part, with its fitness. Higher fitness is better, 0 is perfect.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub parts: BTreeMap<DrumPart, Groups>,
+    pub fitness: f64,
+}
+
+type Genome = BTreeMap<DrumPart, Vec<bool>>;
+
+fn to_groups(steps: &[bool], subdivision: BasicLength) -> Groups {
+    Groups(vec![Group {
+        notes: steps
+            .iter()
+            .map(|hit| if *hit { Note::Hit } else { Note::Rest })
+            .collect(),
+        length: Length::Simple(ModdedLength::Plain(subdivision)),
+        times: (),
+    }])
+}
+
+/// Scores a set of patterns against the target, every term is a distance from it.
+pub fn fitness(parts: &BTreeMap<DrumPart, Groups>, time_signature: TimeSignature, target: &Target) -> f64 {
+    if parts.is_empty() {
+        return f64::MIN;
+    }
+    let count = parts.len() as f64;
+    let silent = parts.values().filter(|g| density(g) == 0.0).count() as f64;
+    let mean_density = parts.values().map(density).sum::<f64>() / count;
+    let mean_syncopation = parts.values().map(|g| syncopation(g, time_signature)).sum::<f64>() / count;
+    let (low, high) = target.syncopation;
+    let syncopation_distance = (low - mean_syncopation).max(mean_syncopation - high).max(0.0);
+    let bars_distance = match time_signature.converges(parts.values()) {
+        Ok(bars) => (bars as f64 / target.bars.max(1) as f64).ln().abs(),
+        Err(_) => 10.0,
+    };
+    -((mean_density - target.density).abs() + syncopation_distance + bars_distance / 2.0 + silent)
+}
+
+/// Evolves patterns for `parts` towards the `target`. Returns the final population, best first.
+pub fn evolve(
+    parts: &[DrumPart],
+    time_signature: TimeSignature,
+    target: &Target,
+    settings: &Settings,
+    rng: &mut Rng,
+) -> Vec<Candidate> {
+    let max_steps = settings.max_steps.max(1);
+    let random_genome = |rng: &mut Rng| -> Genome {
+        parts
+            .iter()
+            .map(|part| {
+                let steps = 1 + rng.below(max_steps as u64) as usize;
+                (*part, (0..steps).map(|_| rng.chance(target.density)).collect())
+            })
+            .collect()
+    };
+    let score = |genome: &Genome| -> Candidate {
+        let groups: BTreeMap<DrumPart, Groups> = genome
+            .iter()
+            .map(|(part, steps)| (*part, to_groups(steps, settings.subdivision)))
+            .collect();
+        let fitness = fitness(&groups, time_signature, target);
+        Candidate { parts: groups, fitness }
+    };
+
+    let population_size = settings.population.max(2);
+    let mut population: Vec<(Genome, Candidate)> = (0..population_size)
+        .map(|_| {
+            let genome = random_genome(rng);
+            let candidate = score(&genome);
+            (genome, candidate)
+        })
+        .collect();
+
+    for _ in 0..settings.generations {
+        population.sort_by(|a, b| b.1.fitness.total_cmp(&a.1.fitness));
+        // The two best candidates always survive.
+        let mut next: Vec<(Genome, Candidate)> = population.iter().take(2).cloned().collect();
+        while next.len() < population_size {
+            let mother = &tournament(&population, rng).0;
+            let father = &tournament(&population, rng).0;
+            let mut child: Genome = mother
+                .iter()
+                .map(|(part, steps)| {
+                    if rng.chance(0.5) {
+                        (*part, steps.clone())
+                    } else {
+                        (*part, father[part].clone())
+                    }
+                })
+                .collect();
+            for steps in child.values_mut() {
+                mutate(steps, max_steps, rng);
+            }
+            let candidate = score(&child);
+            next.push((child, candidate));
+        }
+        population = next;
+    }
+    population.sort_by(|a, b| b.1.fitness.total_cmp(&a.1.fitness));
+    population.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+fn tournament<'a>(population: &'a [(Genome, Candidate)], rng: &mut Rng) -> &'a (Genome, Candidate) {
+    (0..3)
+        .map(|_| &population[rng.below(population.len() as u64) as usize])
+        .max_by(|a, b| a.1.fitness.total_cmp(&b.1.fitness))
+        .unwrap()
+}
+
+fn mutate(steps: &mut Vec<bool>, max_steps: usize, rng: &mut Rng) {
+    if rng.chance(0.5) {
+        let i = rng.below(steps.len() as u64) as usize;
+        steps[i] = !steps[i];
+    }
+    // Changing the length of a pattern is what changes the convergence.
+    if rng.chance(0.2) {
+        if rng.chance(0.5) && steps.len() < max_steps {
+            let i = rng.below(steps.len() as u64 + 1) as usize;
+            steps.insert(i, false);
+        } else if steps.len() > 1 {
+            let i = rng.below(steps.len() as u64) as usize;
+            steps.remove(i);
+        }
+    }
+}
+
+#[test]
+fn test_evolve() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let target = Target {
+        density: 0.5,
+        syncopation: (0.2, 0.6),
+        bars: 3,
+    };
+    let settings = Settings {
+        population: 32,
+        generations: 40,
+        subdivision: BasicLength::Sixteenth,
+        max_steps: 32,
+    };
+    let parts = [DrumPart::KickDrum, DrumPart::SnareDrum];
+    let population = evolve(&parts, four_fourth, &target, &settings, &mut Rng::new(7));
+    assert_eq!(population.len(), 32);
+    let best = &population[0];
+    assert!(population.iter().all(|c| c.fitness <= best.fitness));
+    assert_eq!(best.parts.keys().cloned().collect::<Vec<_>>(), parts.to_vec());
+    assert!(best.fitness > -0.5, "fitness {} is too far from the target", best.fitness);
+    assert_eq!(evolve(&parts, four_fourth, &target, &settings, &mut Rng::new(7)), population);
+}
+
+#[test]
+fn test_fitness() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let target = Target {
+        density: 0.5,
+        syncopation: (0.0, 0.5),
+        bars: 1,
+    };
+    let perfect = BTreeMap::from_iter([(DrumPart::KickDrum, to_groups(&[true, false, true, false], BasicLength::Eighth))]);
+    assert_eq!(fitness(&perfect, four_fourth, &target), 0.0);
+    let silent = BTreeMap::from_iter([(DrumPart::KickDrum, to_groups(&[false; 4], BasicLength::Eighth))]);
+    assert!(fitness(&silent, four_fourth, &target) < -1.0);
+}
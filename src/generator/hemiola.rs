@@ -0,0 +1,93 @@
+use crate::dsl::dsl::{BasicLength, Group, GroupOrNote, KnownLength, Length, ModdedLength, Note, Times};
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Notes of the meter grouped in a different number than the meter implies, like 16th notes
+/// of 4/4 accented in groups of three.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrossRhythm {
+    pub grouping: u16,
+    pub subdivision: BasicLength,
+}
+
+/// Where a cross-rhythm lines up with the bar line again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resolution {
+    /// Number of bars it takes for the grouping to start on a downbeat again.
+    pub bars: u32,
+    /// Number of groupings played over these bars.
+    pub groupings: u32,
+}
+
+impl CrossRhythm {
+    /// A single grouping: an accent followed by the rest of the grouping. A grouping of no notes
+    /// has nothing to accent and is an error.
+    pub fn to_group(&self) -> Result<Group<GroupOrNote<Times>, Times>, String> {
+        if self.grouping == 0 {
+            return Err("Grouping should have at least one note".to_string());
+        }
+        let mut notes = vec![GroupOrNote::SingleNote(Note::Hit)];
+        notes.extend((1..self.grouping).map(|_| GroupOrNote::SingleNote(Note::Rest)));
+        Ok(Group {
+            notes,
+            length: Length::Simple(ModdedLength::Plain(self.subdivision)),
+            times: Times(1),
+        })
+    }
+
+    pub fn resolution(&self, time_signature: TimeSignature) -> Result<Resolution, String> {
+        let group = self.to_group()?;
+        let bars = time_signature.converges(vec![&group])?;
+        let groupings = bars * time_signature.to_128th() / group.to_128th();
+        Ok(Resolution { bars, groupings })
+    }
+}
+
+/// Plays every beat of the meter, so the cross-rhythm can be heard against it.
+pub fn meter_pulse(time_signature: TimeSignature) -> Group<GroupOrNote<Times>, Times> {
+    Group {
+        notes: vec![GroupOrNote::SingleNote(Note::Hit)],
+        length: Length::Simple(ModdedLength::Plain(time_signature.denominator)),
+        times: Times(1),
+    }
+}
+
+#[test]
+fn test_cross_rhythm() {
+    let threes = CrossRhythm {
+        grouping: 3,
+        subdivision: BasicLength::Sixteenth,
+    };
+    assert_eq!(threes.to_group().unwrap().to_string(), "16x--");
+    assert_eq!(
+        threes.resolution(TimeSignature::from_str("4/4").unwrap()),
+        Ok(Resolution {
+            bars: 3,
+            groupings: 16
+        })
+    );
+    let eighths_in_fives = CrossRhythm {
+        grouping: 5,
+        subdivision: BasicLength::Eighth,
+    };
+    assert_eq!(
+        eighths_in_fives.resolution(TimeSignature::from_str("7/8").unwrap()),
+        Ok(Resolution {
+            bars: 5,
+            groupings: 7
+        })
+    );
+    assert_eq!(meter_pulse(TimeSignature::from_str("7/8").unwrap()).to_string(), "8x");
+}
+
+#[test]
+fn test_cross_rhythm_of_no_notes() {
+    let empty = CrossRhythm {
+        grouping: 0,
+        subdivision: BasicLength::Sixteenth,
+    };
+    assert!(empty.to_group().is_err());
+    assert!(empty.resolution(TimeSignature::from_str("4/4").unwrap()).is_err());
+}
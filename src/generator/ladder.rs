@@ -0,0 +1,54 @@
+use crate::dsl::dsl::{Group, GroupOrNote, Note, Times};
+use crate::midi::grid::Grid;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::{groups, KnownLength};
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Rungs of a ladder unless it's given its own: quarters, eighths, triplets, sixteenths,
+/// quintuplets, sextuplets and septuplets.
+pub const DEFAULT_RUNGS: &str = "4,8,8t,16,16:5,16t,16:7";
+
+/// A subdivision ladder, the exercise of playing every step of one grid after another over a
+/// steady pulse, `bars` bars of every rung.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ladder {
+    pub rungs: Vec<Grid>,
+    pub bars: u16,
+}
+
+impl Ladder {
+    /// A group of hits on every step of every rung, if the steps of every rung fill its bars.
+    pub fn to_groups(&self, time_signature: TimeSignature) -> Result<Vec<Group<GroupOrNote<Times>, Times>>, String> {
+        self.rungs
+            .iter()
+            .map(|grid| match grid.bar_steps(time_signature) {
+                Some(steps) => Ok(Group {
+                    notes: vec![GroupOrNote::SingleNote(Note::Hit); steps * self.bars as usize],
+                    length: grid.step_length(),
+                    times: Times(1),
+                }),
+                None => Err(format!("a bar of {} isn't made of whole steps of {}", time_signature, grid)),
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_ladder() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let rungs = DEFAULT_RUNGS.split(',').map(|g| Grid::from_str(g).unwrap()).collect();
+    let ladder = Ladder { rungs, bars: 2 };
+    let rungs: Vec<String> = ladder.to_groups(four_four).unwrap().iter().map(|g| g.to_string()).collect();
+    assert_eq!(rungs[0], "4xxxxxxxx");
+    assert_eq!(rungs[2], format!("8t{}", "x".repeat(24)));
+    assert_eq!(rungs[4], format!("16:5:4{}", "x".repeat(40)));
+    // Every rung takes 2 bars.
+    for rung in rungs.iter() {
+        assert_eq!(groups(rung).unwrap().1.to_128th(), 256, "{}", rung);
+    }
+    let quintuplets = Ladder { rungs: vec![Grid::from_str("16:5").unwrap()], bars: 1 };
+    assert!(quintuplets.to_groups(TimeSignature::from_str("7/16").unwrap()).is_err());
+}
@@ -0,0 +1,148 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::dsl::dsl::{BasicLength, Group, Groups, KnownLength, Length, ModdedLength, Note};
+use crate::midi::core::DrumPart;
+use crate::midi::grid::Grid;
+use crate::midi::import::ImportedDrums;
+use crate::midi::time::TimeSignature;
+use crate::random::Rng;
+
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Grid the model learns and generates on.
+pub static GRID: BasicLength = BasicLength::Sixteenth;
+
+/// Position of a step used as a part of the context: `DOWNBEAT` for the first step of a bar,
+/// the position within a quarter note otherwise.
+const DOWNBEAT: u32 = 4;
+
+/// Per-part Markov chain over a grid of 16th notes.
+///
+/// The next step depends on the previous `order` steps and the position of the step in the bar,
+/// so the chain picks up both the feel of a groove and where it likes to land.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GrooveModel {
+    order: usize,
+    /// Counts of (rest, hit) that followed a (history, position) context.
+    transitions: BTreeMap<DrumPart, BTreeMap<(u32, u32), [u32; 2]>>,
+    /// Counts of (rest, hit) regardless of the context, used for unseen contexts.
+    totals: BTreeMap<DrumPart, [u32; 2]>,
+}
+
+impl GrooveModel {
+    pub fn new(order: usize) -> Self {
+        GrooveModel {
+            order: order.min(16),
+            transitions: BTreeMap::new(),
+            totals: BTreeMap::new(),
+        }
+    }
+
+    fn position(step: usize, steps_per_bar: usize) -> u32 {
+        if step.is_multiple_of(steps_per_bar) {
+            DOWNBEAT
+        } else {
+            (step % 4) as u32
+        }
+    }
+
+    fn history(&self, steps: &[bool], step: usize) -> u32 {
+        (step.saturating_sub(self.order)..step).fold(0, |acc, i| (acc << 1) | steps[i] as u32)
+    }
+
+    /// Learns from a sequence of grid steps of a single part, `true` being a hit.
+    pub fn learn(&mut self, part: DrumPart, steps: &[bool], time_signature: TimeSignature) -> Result<(), String> {
+        let steps_per_bar = steps_per_bar(time_signature)?;
+        for (i, hit) in steps.iter().enumerate() {
+            let context = (self.history(steps, i), Self::position(i, steps_per_bar));
+            self.transitions.entry(part).or_default().entry(context).or_default()[*hit as usize] += 1;
+            self.totals.entry(part).or_default()[*hit as usize] += 1;
+        }
+        Ok(())
+    }
+
+    /// Learns every part of an imported MIDI file, 4/4 is assumed if it has no time signature.
+    pub fn learn_drums(&mut self, drums: &ImportedDrums) -> Result<(), String> {
+        let time_signature = drums.time_signature.unwrap_or(TimeSignature {
+            numerator: 4,
+            denominator: BasicLength::Fourth,
+        });
+        let parts: BTreeSet<DrumPart> = drums.onsets.iter().map(|o| o.part).collect();
+        for part in parts {
+            self.learn(part, &drums.to_steps(part, Grid::from(GRID)), time_signature)?;
+        }
+        Ok(())
+    }
+
+    /// Parts the model has seen.
+    pub fn parts(&self) -> Vec<DrumPart> {
+        self.totals.keys().cloned().collect()
+    }
+
+    /// Generates `bars` bars of a part in the given time signature.
+    pub fn generate(
+        &self,
+        part: DrumPart,
+        bars: u32,
+        time_signature: TimeSignature,
+        rng: &mut Rng,
+    ) -> Result<Groups, String> {
+        let steps_per_bar = steps_per_bar(time_signature)?;
+        let mut steps = Vec::new();
+        for i in 0..bars as usize * steps_per_bar {
+            let context = (self.history(&steps, i), Self::position(i, steps_per_bar));
+            let [rests, hits] = self
+                .transitions
+                .get(&part)
+                .and_then(|t| t.get(&context))
+                .or(self.totals.get(&part))
+                .cloned()
+                .unwrap_or([1, 0]);
+            steps.push(rng.chance(hits as f64 / (rests + hits) as f64));
+        }
+        Ok(Groups(vec![Group {
+            notes: steps
+                .into_iter()
+                .map(|hit| if hit { Note::Hit } else { Note::Rest })
+                .collect(),
+            length: Length::Simple(ModdedLength::Plain(GRID)),
+            times: (),
+        }]))
+    }
+}
+
+fn steps_per_bar(time_signature: TimeSignature) -> Result<usize, String> {
+    let grid = GRID.to_128th();
+    let bar = time_signature.to_128th();
+    if bar.is_multiple_of(grid) {
+        Ok((bar / grid) as usize)
+    } else {
+        Err("Time signature doesn't fit a grid of 16th notes".to_string())
+    }
+}
+
+#[test]
+fn test_groove_model() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let seven_eighths = TimeSignature::from_str("7/8").unwrap();
+    let backbeat: Vec<bool> = (0..64).map(|i| i % 16 == 4 || i % 16 == 12).collect();
+    let four_on_the_floor: Vec<bool> = (0..64).map(|i| i % 4 == 0).collect();
+
+    let mut model = GrooveModel::new(4);
+    model.learn(DrumPart::SnareDrum, &backbeat, four_fourth).unwrap();
+    model.learn(DrumPart::KickDrum, &four_on_the_floor, four_fourth).unwrap();
+    assert_eq!(model.parts(), vec![DrumPart::KickDrum, DrumPart::SnareDrum]);
+
+    // Deterministic training data reproduces itself.
+    let mut rng = Rng::new(7);
+    let kick = model.generate(DrumPart::KickDrum, 2, four_fourth, &mut rng).unwrap();
+    assert_eq!(kick.to_string(), "16x---x---x---x---x---x---x---x---");
+    let snare = model.generate(DrumPart::SnareDrum, 1, four_fourth, &mut rng).unwrap();
+    assert_eq!(snare.to_string(), "16----x-------x---");
+
+    // Generated patterns fill the requested number of bars of any meter.
+    let kick = model.generate(DrumPart::KickDrum, 3, seven_eighths, &mut rng).unwrap();
+    assert_eq!(kick.to_128th(), 3 * seven_eighths.to_128th());
+    assert!(model.generate(DrumPart::HiHat, 1, four_fourth, &mut rng).unwrap().to_string().chars().all(|c| c != 'x'));
+}
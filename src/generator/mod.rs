@@ -0,0 +1,7 @@
+pub mod displacement;
+pub mod evolve;
+pub mod hemiola;
+pub mod ladder;
+pub mod markov;
+pub mod ratio;
+pub mod variation;
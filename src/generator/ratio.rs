@@ -0,0 +1,133 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dsl::dsl::{BasicLength, Group, GroupOrNote, Length, ModdedLength, Note, Times};
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::{groups, KnownLength};
+
+/// Longest cycle in steps a ratio is written out over, the patterns of its voices get that
+/// many notes.
+pub const STEP_LIMIT: u64 = u16::MAX as u64;
+
+/// A polyrhythm ratio like `3:4` or `3:4:5`. Every number is the amount of evenly spaced hits
+/// a voice plays over a single cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ratio(pub Vec<u16>);
+
+impl FromStr for Ratio {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let voices = s
+            .split(':')
+            .map(|n| match u16::from_str(n) {
+                Ok(0) | Err(_) => Err(format!("Can't parse polyrhythm ratio: {}", s)),
+                Ok(n) => Ok(n),
+            })
+            .collect::<Result<Vec<u16>, String>>()?;
+        if voices.len() < 2 {
+            Err(format!("Polyrhythm ratio needs at least two voices: {}", s))
+        } else {
+            Ok(Ratio(voices))
+        }
+    }
+}
+
+impl fmt::Display for Ratio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let voices: Vec<String> = self.0.iter().map(|n| n.to_string()).collect();
+        write!(f, "{}", voices.join(":"))
+    }
+}
+
+impl Ratio {
+    /// Every ratio of two voices of co-prime numbers of hits from 2 to `max`, like 2:3, 3:4 and
+    /// 2:5, the slower voice first. They go by the faster voice, then by the slower one.
+    pub fn family(max: u16) -> Vec<Ratio> {
+        (3..=max)
+            .flat_map(|n| (2..n).filter(move |k| gcd(*k as u64, n as u64) == 1).map(move |k| Ratio(vec![k, n])))
+            .collect()
+    }
+
+    /// Number of subdivisions in a cycle, every voice lands on it evenly.
+    pub fn cycle_steps(&self) -> u32 {
+        self.0
+            .iter()
+            .fold(1, |acc, n| acc / gcd(acc as u64, *n as u64) as u32 * *n as u32)
+    }
+
+    /// Builds the canonical pattern for every voice: a hit followed by rests up to the next pulse
+    /// of this voice, repeated as many times as the voice has hits. Every voice plays all the
+    /// steps of a cycle, which is an error if there are more than `STEP_LIMIT` of them.
+    pub fn to_groups(&self, subdivision: BasicLength) -> Result<Vec<Group<GroupOrNote<Times>, Times>>, String> {
+        let steps = self.0.iter().try_fold(1u64, |acc, n| (acc / gcd(acc, *n as u64)).checked_mul(*n as u64));
+        match steps {
+            Some(steps) if steps <= STEP_LIMIT => {}
+            _ => return Err(format!("Ratio {} takes more than {} steps to line up again", self, STEP_LIMIT)),
+        }
+        let steps = self.cycle_steps();
+        Ok(self
+            .0
+            .iter()
+            .map(|n| {
+                let pulse = steps / *n as u32;
+                let mut notes = vec![GroupOrNote::SingleNote(Note::Hit)];
+                notes.extend((1..pulse).map(|_| GroupOrNote::SingleNote(Note::Rest)));
+                Group {
+                    notes,
+                    length: Length::Simple(ModdedLength::Plain(subdivision)),
+                    times: Times(*n),
+                }
+            })
+            .collect())
+    }
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[test]
+fn test_ratio_from_str() {
+    assert_eq!(Ratio::from_str("3:4"), Ok(Ratio(vec![3, 4])));
+    assert_eq!(Ratio::from_str("3:4:5"), Ok(Ratio(vec![3, 4, 5])));
+    assert!(Ratio::from_str("3").is_err());
+    assert!(Ratio::from_str("3:0").is_err());
+    assert!(Ratio::from_str("3:x").is_err());
+}
+
+#[test]
+fn test_ratio_to_groups() {
+    let ratio = Ratio(vec![3, 4]);
+    assert_eq!(ratio.cycle_steps(), 12);
+    let patterns: Vec<String> = ratio
+        .to_groups(BasicLength::Eighth)
+        .unwrap()
+        .iter()
+        .map(|g| g.to_string())
+        .collect();
+    assert_eq!(patterns, vec!["(3,8x---)", "(4,8x--)"]);
+    // Every voice spans the same cycle.
+    let lengths: Vec<u32> = patterns
+        .iter()
+        .map(|p| groups(p).unwrap().1.to_128th())
+        .collect();
+    assert_eq!(lengths, vec![192, 192]);
+    assert_eq!(Ratio(vec![3, 4, 5]).cycle_steps(), 60);
+    assert!(Ratio(vec![65535, 65534]).to_groups(BasicLength::Eighth).is_err());
+    assert!(Ratio(vec![255, 256, 257]).to_groups(BasicLength::Eighth).is_err());
+}
+
+#[test]
+fn test_ratio_family() {
+    let family: Vec<String> = Ratio::family(6).iter().map(|r| r.to_string()).collect();
+    assert_eq!(family, vec!["2:3", "3:4", "2:5", "3:5", "4:5", "5:6"]);
+    assert_eq!(Ratio::family(9).len(), 19);
+    assert!(Ratio::family(2).is_empty());
+    assert_eq!(Ratio::from_str("3:4:5").unwrap().to_string(), "3:4:5");
+}
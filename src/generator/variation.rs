@@ -0,0 +1,78 @@
+use crate::dsl::dsl::{Group, Groups, KnownLength, Note};
+use crate::midi::time::TimeSignature;
+use crate::random::Rng;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Produces a variation of a pattern by adding, removing or displacing hits.
+///
+/// Every note gets changed with probability `amount`: a rest may turn into a hit, a hit may be
+/// removed or moved to an adjacent rest of the same group. Lengths of notes are never touched, so
+/// the pattern keeps its length, and notes starting on a downbeat are left as they are.
+pub fn vary(groups: &Groups, time_signature: TimeSignature, amount: f64, rng: &mut Rng) -> Groups {
+    let bar_len = time_signature.to_128th();
+    let mut position = 0;
+    groups
+        .0
+        .iter()
+        .map(|group| {
+            let note_length = group.length.to_128th();
+            let downbeats: Vec<bool> = (0..group.notes.len())
+                .map(|i| (position + i as u32 * note_length).is_multiple_of(bar_len))
+                .collect();
+            position += note_length * group.notes.len() as u32;
+
+            let mut notes = group.notes.clone();
+            for i in 0..notes.len() {
+                if downbeats[i] || !rng.chance(amount) {
+                    continue;
+                }
+                match notes[i] {
+                    Note::Tie => {}
+                    Note::Rest => notes[i] = Note::Hit,
+                    Note::Hit | Note::Stroke(_) if rng.chance(0.5) => notes[i] = Note::Rest,
+                    Note::Hit | Note::Stroke(_) => {
+                        let free = |j: usize| notes[j] == Note::Rest && !downbeats[j];
+                        let neighbours: Vec<usize> = [i.checked_sub(1), Some(i + 1)]
+                            .into_iter()
+                            .flatten()
+                            .filter(|j| *j < notes.len() && free(*j))
+                            .collect();
+                        if !neighbours.is_empty() {
+                            let j = neighbours[rng.below(neighbours.len() as u64) as usize];
+                            notes.swap(i, j);
+                        }
+                    }
+                }
+            }
+            Group {
+                notes,
+                length: group.length,
+                times: (),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_vary() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let (_, pattern) = groups("16x-x-x-x-x-x-x-x-(2,8-x-x)").unwrap();
+
+    let untouched = vary(&pattern, four_fourth, 0.0, &mut Rng::new(7));
+    assert_eq!(untouched, pattern);
+
+    let varied = vary(&pattern, four_fourth, 0.5, &mut Rng::new(7));
+    assert_ne!(varied, pattern);
+    assert_eq!(varied, vary(&pattern, four_fourth, 0.5, &mut Rng::new(7)));
+    assert_eq!(varied.to_128th(), pattern.to_128th());
+
+    // Downbeats survive even when every other note is changed.
+    let (_, downbeats) = groups("4x---x---").unwrap();
+    let varied = vary(&downbeats, four_fourth, 1.0, &mut Rng::new(1));
+    assert_eq!(varied.0[0].notes[0], Note::Hit);
+    assert_eq!(varied.0[0].notes[4], Note::Hit);
+}
@@ -1,2 +1,11 @@
+pub mod analysis;
+pub mod api;
 pub mod dsl;
-pub mod midi;
\ No newline at end of file
+pub mod error;
+pub mod generator;
+pub mod live;
+pub mod midi;
+pub mod random;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod tui;
@@ -0,0 +1,104 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::live::scheduler::{Clock, Update};
+
+/// Address Carabiner listens on by default.
+pub const CARABINER: &str = "127.0.0.1:17000";
+
+/// How often the session is asked where it is, the clock drifts away from it in between.
+const POLL: Duration = Duration::from_millis(500);
+
+/// State of an Ableton Link session as reported by [Carabiner](https://github.com/Deep-Symmetry/carabiner),
+/// a bridge that joins a session and reports it over TCP:
+///
+/// ```text
+/// status { :peers 1 :bpm 120.000000 :start 73743731220 :beat 597.737570 }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Status {
+    pub peers: u32,
+    pub bpm: f64,
+    /// Quarter note of the session when the status was sent.
+    pub beat: f64,
+}
+
+impl FromStr for Status {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields = s
+            .trim()
+            .strip_prefix("status")
+            .map(str::trim)
+            .and_then(|f| f.strip_prefix('{'))
+            .and_then(|f| f.strip_suffix('}'))
+            .ok_or_else(|| format!("{} is not a status of Carabiner", s.trim()))?;
+        let words: Vec<&str> = fields.split_whitespace().collect();
+        let field = |name: &str| {
+            words
+                .chunks(2)
+                .find(|pair| pair[0] == name)
+                .and_then(|pair| pair.get(1))
+                .ok_or_else(|| format!("{} is missing from the status of Carabiner", name))
+        };
+        let number = |name: &str| {
+            field(name)?
+                .parse::<f64>()
+                .map_err(|_| format!("{} of the status of Carabiner is not a number", name))
+        };
+        Ok(Status {
+            peers: number(":peers")? as u32,
+            bpm: number(":bpm")?,
+            beat: number(":beat")?,
+        })
+    }
+}
+
+/// Joins the Link session of the Carabiner at `address`. Clocks following the session come
+/// from the receiver until the connection drops.
+pub fn follow(address: &str) -> io::Result<Receiver<Update>> {
+    let stream = TcpStream::connect(address)?;
+    stream.set_nodelay(true)?;
+    let mut requests = stream.try_clone()?;
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        while requests.write_all(b"status\n").is_ok() {
+            thread::sleep(POLL);
+        }
+    });
+    thread::spawn(move || {
+        for line in BufReader::new(stream).lines() {
+            let Ok(line) = line else { break };
+            // Carabiner answers other commands too, only the statuses matter.
+            let Ok(status) = Status::from_str(&line) else { continue };
+            let clock = Clock {
+                tempo: status.bpm,
+                beat: status.beat,
+                at: Instant::now(),
+            };
+            if sender.send(Update::Sync(clock)).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+#[test]
+fn test_status_from_str() {
+    assert_eq!(
+        Status::from_str("status { :peers 1 :bpm 128.500000 :start 73743731220 :beat 597.737570 }\n"),
+        Ok(Status {
+            peers: 1,
+            bpm: 128.5,
+            beat: 597.73757,
+        })
+    );
+    assert!(Status::from_str("beat-at-time { :when 1 :beat 2.0 :quantum 4.0 }").is_err());
+    assert!(Status::from_str("status { :peers 0 :bpm fast :beat 1.0 }").is_err());
+}
@@ -0,0 +1,181 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::live::scheduler::{Clock, Update};
+
+/// Pulses of MIDI clock in a quarter note.
+const PULSES: f64 = 24.0;
+
+/// Pulses the tempo is averaged over, a quarter note of them.
+const WINDOW: usize = 24;
+
+/// Follows the MIDI clock, Start, Continue, Stop and Song Position Pointer a sequencer sends,
+/// one byte at a time. The pulses pin the transport down, the tempo measured between them
+/// carries it on until the next one.
+#[derive(Debug, Clone)]
+pub struct Follower {
+    /// Pulse of the transport the last pulse was, counted from the start. The one before the
+    /// first after Start or Continue, until it comes.
+    pulse: i64,
+    running: bool,
+    /// Arrivals of the latest pulses.
+    arrivals: VecDeque<Instant>,
+    /// Status byte of the message being read and its data bytes so far.
+    status: Option<u8>,
+    data: Vec<u8>,
+}
+
+impl Default for Follower {
+    fn default() -> Self {
+        Follower {
+            pulse: -1,
+            running: false,
+            arrivals: VecDeque::with_capacity(WINDOW + 1),
+            status: None,
+            data: Vec::with_capacity(2),
+        }
+    }
+}
+
+impl Follower {
+    /// Tempo of the pulses in BPM, 0 until there are two of them.
+    pub fn tempo(&self) -> f64 {
+        match (self.arrivals.front(), self.arrivals.back()) {
+            (Some(first), Some(last)) if self.arrivals.len() > 1 => {
+                let pulse = last.duration_since(*first).as_secs_f64() / (self.arrivals.len() - 1) as f64;
+                if pulse > 0.0 {
+                    60.0 / (pulse * PULSES)
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn clock(&self, at: Instant) -> Clock {
+        Clock {
+            tempo: if self.running { self.tempo() } else { 0.0 },
+            beat: self.pulse as f64 / PULSES,
+            at,
+        }
+    }
+
+    /// Reads a byte that came in at `at`, returns the update of the transport it makes.
+    pub fn feed(&mut self, byte: u8, at: Instant) -> Option<Update> {
+        match byte {
+            // Real time messages may come in the middle of others.
+            0xF8 => {
+                if !self.running {
+                    return None;
+                }
+                self.pulse += 1;
+                self.arrivals.push_back(at);
+                if self.arrivals.len() > WINDOW + 1 {
+                    self.arrivals.pop_front();
+                }
+                Some(Update::Sync(self.clock(at)))
+            }
+            0xFA => {
+                self.pulse = -1;
+                self.start(at)
+            }
+            0xFB => self.start(at),
+            0xFC => {
+                self.running = false;
+                Some(Update::Stop)
+            }
+            0xF9..=0xFF => None,
+            0x80..=0xF7 => {
+                self.status = Some(byte);
+                self.data.clear();
+                None
+            }
+            _ => {
+                // Data of a channel message keeps its status, there's only one System Common
+                // message to follow.
+                if self.status == Some(0xF2) {
+                    self.data.push(byte);
+                    if let [lsb, msb] = self.data[..] {
+                        self.status = None;
+                        if !self.running {
+                            // Song Position Pointer counts sixteenths, 6 pulses each.
+                            let position = ((msb as i64) << 7) | lsb as i64;
+                            self.pulse = position * 6 - 1;
+                            return Some(Update::Locate(self.clock(at)));
+                        }
+                    }
+                }
+                None
+            }
+        }
+    }
+
+    /// Runs the transport from where it is, the next pulse is the first.
+    fn start(&mut self, at: Instant) -> Option<Update> {
+        self.running = true;
+        self.arrivals.clear();
+        Some(Update::Locate(self.clock(at)))
+    }
+}
+
+/// Follows the sequencer sending MIDI clock to the raw MIDI input at `path`, like
+/// `/dev/snd/midiC1D0`. Updates of its transport come from the receiver until the input closes.
+pub fn follow(path: &str) -> io::Result<Receiver<Update>> {
+    let mut input = File::open(path)?;
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut follower = Follower::default();
+        let mut buffer = [0; 64];
+        while let Ok(read) = input.read(&mut buffer) {
+            if read == 0 {
+                // Some inputs have nothing to read until a device sends something.
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+            let at = Instant::now();
+            for byte in &buffer[..read] {
+                if let Some(update) = follower.feed(*byte, at) {
+                    if sender.send(update).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    });
+    Ok(receiver)
+}
+
+#[test]
+fn test_follower() {
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+    let mut follower = Follower::default();
+    // Pulses of a stopped transport move nothing.
+    assert_eq!(follower.feed(0xF8, at(0)), None);
+    let Some(Update::Locate(clock)) = follower.feed(0xFA, at(0)) else { panic!() };
+    assert_eq!((clock.tempo, clock.beat), (0.0, -1.0 / 24.0));
+    // The first pulse after Start is the first quarter note.
+    let Some(Update::Sync(clock)) = follower.feed(0xF8, at(1)) else { panic!() };
+    assert_eq!(clock.beat, 0.0);
+    // A pulse every 25ms is 100 BPM, a note on in between changes nothing.
+    assert_eq!(follower.feed(0x99, at(10)), None);
+    assert_eq!(follower.feed(36, at(10)), None);
+    let Some(Update::Sync(clock)) = follower.feed(0xF8, at(26)) else { panic!() };
+    assert_eq!(clock.beat, 1.0 / 24.0);
+    assert!((clock.tempo - 100.0).abs() < 1e-9);
+    assert_eq!(follower.feed(127, at(30)), None);
+    assert_eq!(follower.feed(0xFC, at(40)), Some(Update::Stop));
+    // Song Position Pointer of bar 2 of 4/4, sixteenth 16.
+    assert_eq!(follower.feed(0xF2, at(50)), None);
+    assert_eq!(follower.feed(16, at(50)), None);
+    let Some(Update::Locate(clock)) = follower.feed(0, at(50)) else { panic!() };
+    assert_eq!((clock.tempo, clock.beat), (0.0, 95.0 / 24.0));
+    assert!(matches!(follower.feed(0xFB, at(60)), Some(Update::Locate(_))));
+    let Some(Update::Sync(clock)) = follower.feed(0xF8, at(70)) else { panic!() };
+    assert_eq!(clock.beat, 4.0);
+}
@@ -0,0 +1,6 @@
+pub mod link;
+pub mod midi_clock;
+pub mod mute;
+pub mod playback;
+pub mod scheduler;
+pub mod tempo;
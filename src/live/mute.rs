@@ -0,0 +1,72 @@
+use std::collections::BTreeSet;
+
+use crate::midi::core::Part;
+
+#[allow(unused_imports)]
+use crate::midi::core::DrumPart;
+
+/// Parts of a loop muted while it plays, a bit each of the lanes of `Loop::parts`. Soloing a
+/// part mutes the rest, soloing it again brings them back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mutes {
+    parts: Vec<Part>,
+    muted: u64,
+}
+
+impl Mutes {
+    /// Mutes of the lanes `parts`, the `muted` ones to start with.
+    pub fn new(parts: &[Part], muted: &BTreeSet<Part>) -> Self {
+        let mut mutes = Mutes {
+            parts: parts.to_vec(),
+            muted: 0,
+        };
+        for part in muted {
+            if let Some(bit) = mutes.bit(*part) {
+                mutes.muted |= bit;
+            }
+        }
+        mutes
+    }
+
+    fn bit(&self, part: Part) -> Option<u64> {
+        self.parts.iter().position(|p| *p == part).map(|lane| 1 << lane)
+    }
+
+    /// Lanes muted, a bit each.
+    pub fn muted(&self) -> u64 {
+        self.muted
+    }
+
+    pub fn is_muted(&self, part: Part) -> bool {
+        self.bit(part).is_some_and(|bit| self.muted & bit != 0)
+    }
+
+    /// Mutes a part, or unmutes it if it's muted.
+    pub fn toggle(&mut self, part: Part) -> Result<u64, String> {
+        let bit = self.bit(part).ok_or_else(|| "the loop doesn't play that part".to_string())?;
+        self.muted ^= bit;
+        Ok(self.muted)
+    }
+
+    /// Mutes every part but one, or unmutes them all if they already are.
+    pub fn solo(&mut self, part: Part) -> Result<u64, String> {
+        let bit = self.bit(part).ok_or_else(|| "the loop doesn't play that part".to_string())?;
+        let others = (u64::MAX >> (64 - self.parts.len())) & !bit;
+        self.muted = if self.muted == others { 0 } else { others };
+        Ok(self.muted)
+    }
+}
+
+#[test]
+fn test_mutes() {
+    let kick = Part::Drum(DrumPart::KickDrum);
+    let hihat = Part::Drum(DrumPart::HiHat);
+    let mut mutes = Mutes::new(&[kick, hihat, Part::Bass], &BTreeSet::from([Part::Bass]));
+    assert_eq!(mutes.muted(), 0b100);
+    assert_eq!(mutes.toggle(kick), Ok(0b101));
+    assert!(mutes.is_muted(kick));
+    assert_eq!(mutes.toggle(kick), Ok(0b100));
+    assert_eq!(mutes.solo(hihat), Ok(0b101));
+    assert_eq!(mutes.solo(hihat), Ok(0));
+    assert!(mutes.toggle(Part::Drum(DrumPart::SnareDrum)).is_err());
+}
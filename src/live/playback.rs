@@ -0,0 +1,330 @@
+use midly::live::LiveEvent;
+use midly::num::u4;
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::api::spec::{description, render, Spec};
+use crate::midi::core::{bar_length, Part};
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::midi::core::DrumPart;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// A MIDI message of a loop, `tick` ticks after the loop starts, played by lane `lane`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cue {
+    tick: u64,
+    channel: u4,
+    message: MidiMessage,
+    lane: u8,
+}
+
+/// The bars of a file after its pickup, played over and over for as long as the transport
+/// runs. Everything is laid out when the loop is made, finding the messages of a stretch of
+/// time neither allocates nor locks, so it can be done on the audio thread of a plugin.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// Notes in the order they're played, note offs of a tick before its note ons. The last
+    /// note offs are a whole loop after the start.
+    cues: Vec<Cue>,
+    /// Program changes and controllers, to be sent before the loop starts.
+    preamble: Vec<Cue>,
+    /// Note offs of every key the loop plays, to stop it.
+    silence: Vec<Cue>,
+    /// Ticks of a round of the loop.
+    length: u64,
+    ppqn: u16,
+    /// Parts of the lanes of the loop, a file made into a loop is a single lane of no part.
+    parts: Vec<Part>,
+}
+
+/// Most lanes a loop may have, the muted ones are a bit each of a `u64`.
+pub const MAX_LANES: usize = 64;
+
+impl Loop {
+    /// The loop of `bars` bars of a file of `time_signature`, bar 1 starts at the "Bar 1"
+    /// marker when the file has one.
+    pub fn new(smf: &Smf, bars: u32, time_signature: TimeSignature) -> Result<Loop, String> {
+        Loop::lane(smf, bars, time_signature, 0)
+    }
+
+    fn lane(smf: &Smf, bars: u32, time_signature: TimeSignature, lane: u8) -> Result<Loop, String> {
+        let ppqn = match smf.header.timing {
+            Timing::Metrical(ticks) => ticks.as_int(),
+            Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing can't be looped".to_string()),
+        };
+        let length = (bar_length(time_signature, ppqn).0 * bars as u128) as u64;
+        if length == 0 {
+            return Err("A loop should be at least a tick long".to_string());
+        }
+        let mut start = 0;
+        let mut notes = Vec::new();
+        let mut preamble = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut time: u64 = 0;
+            for event in track.iter() {
+                time += event.delta.as_int() as u64;
+                match event.kind {
+                    TrackEventKind::Meta(MetaMessage::Marker(b"Bar 1")) => start = time,
+                    TrackEventKind::Midi { channel, message } => {
+                        let cue = Cue {
+                            tick: time,
+                            channel,
+                            message,
+                            lane,
+                        };
+                        match message {
+                            MidiMessage::NoteOn { .. } | MidiMessage::NoteOff { .. } => notes.push(cue),
+                            _ => preamble.push(cue),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        // The pickup is only played once, so it's left out along with the note offs ending it.
+        // The note offs of the last notes fall on the end of the loop, right before the next
+        // round starts.
+        let mut cues: Vec<Cue> = notes
+            .into_iter()
+            .filter(|c| c.tick >= start && c.tick <= start + length && (c.tick > start || is_note_on(&c.message)))
+            .map(|c| Cue { tick: c.tick - start, ..c })
+            .collect();
+        cues.sort_by_key(|c| (c.tick, is_note_on(&c.message)));
+        let silence = silence(&cues);
+        Ok(Loop {
+            cues,
+            preamble,
+            silence,
+            length,
+            ppqn,
+            parts: Vec::new(),
+        })
+    }
+
+    /// The loop of the bars a spec converges over, every part and the bass on a lane of its
+    /// own so they can be muted while it plays.
+    pub fn from_spec(spec: &Spec) -> Result<Loop, String> {
+        let text = description(&spec.patterns, &spec.registry);
+        let parts: Vec<Part> = spec
+            .patterns
+            .keys()
+            .map(|part| Part::Drum(*part))
+            .chain(spec.bass.then_some(Part::Bass))
+            .collect();
+        if parts.len() > MAX_LANES {
+            return Err(format!("A loop can't have more than {} parts", MAX_LANES));
+        }
+        let mut lanes = Vec::with_capacity(parts.len());
+        for (lane, part) in parts.iter().enumerate() {
+            let mut alone = spec.clone();
+            alone.muted = parts.iter().filter(|p| *p != part).cloned().collect();
+            let (smf, bars) = render(&alone, &text)?;
+            lanes.push(Loop::lane(&smf, bars, spec.time_signature, lane as u8)?);
+        }
+        let mut looped = lanes.remove(0);
+        for lane in lanes {
+            looped.cues.extend(lane.cues);
+            // Every lane sets up the drums, the bass lane its own instrument as well.
+            for cue in lane.preamble {
+                if !looped.preamble.iter().any(|c| c.channel == cue.channel && c.message == cue.message) {
+                    looped.preamble.push(cue);
+                }
+            }
+        }
+        looped.cues.sort_by_key(|c| (c.tick, is_note_on(&c.message)));
+        looped.silence = silence(&looped.cues);
+        looped.parts = parts;
+        Ok(looped)
+    }
+
+    /// Parts of the lanes of the loop, in order.
+    pub fn parts(&self) -> &[Part] {
+        &self.parts
+    }
+
+    /// Length of a round of the loop in quarter notes.
+    pub fn beats(&self) -> f64 {
+        self.length as f64 / self.ppqn as f64
+    }
+
+    pub fn ppqn(&self) -> u16 {
+        self.ppqn
+    }
+
+    /// Messages played from quarter note `from` of the transport up to `to`, with the quarter
+    /// notes they're played on. The loop starts on quarter note 0.
+    pub fn between(&self, from: f64, to: f64) -> Cues<'_> {
+        let ppqn = self.ppqn as f64;
+        let from = (from.max(0.0) * ppqn).ceil() as u64;
+        let to = (to.max(0.0) * ppqn).ceil() as u64;
+        // The end of a round is the start of the next one.
+        let (round, offset) = match (from / self.length, from % self.length) {
+            (round, 0) if round > 0 => (round - 1, self.length),
+            x => x,
+        };
+        Cues {
+            cues: &self.cues,
+            ppqn: self.ppqn,
+            length: self.length,
+            round,
+            index: self.cues.partition_point(|c| c.tick < offset),
+            to,
+            muted: 0,
+        }
+    }
+
+    /// Program changes and controllers that set the instruments up.
+    pub fn preamble(&self) -> impl Iterator<Item = LiveEvent<'static>> + '_ {
+        self.preamble.iter().map(live_event)
+    }
+
+    /// Note offs of every key the loop plays, for when the transport stops mid-note.
+    pub fn silence(&self) -> impl Iterator<Item = LiveEvent<'static>> + '_ {
+        self.silence.iter().map(live_event)
+    }
+}
+
+/// Note offs of every key `cues` play.
+fn silence(cues: &[Cue]) -> Vec<Cue> {
+    let mut silence: Vec<Cue> = cues
+        .iter()
+        .filter_map(|c| match c.message {
+            MidiMessage::NoteOn { key, .. } => Some(Cue {
+                tick: 0,
+                channel: c.channel,
+                message: MidiMessage::NoteOff { key, vel: 0.into() },
+                lane: 0,
+            }),
+            _ => None,
+        })
+        .collect();
+    silence.sort_by_key(|c| (c.channel, note_key(&c.message)));
+    silence.dedup();
+    silence
+}
+
+fn live_event(cue: &Cue) -> LiveEvent<'static> {
+    LiveEvent::Midi {
+        channel: cue.channel,
+        message: cue.message,
+    }
+}
+
+fn is_note_on(message: &MidiMessage) -> bool {
+    matches!(message, MidiMessage::NoteOn { vel, .. } if *vel > 0)
+}
+
+fn note_key(message: &MidiMessage) -> u8 {
+    match message {
+        MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => key.as_int(),
+        _ => 0,
+    }
+}
+
+/// Messages of a stretch of a `Loop`, see `Loop::between`.
+#[derive(Debug, Clone)]
+pub struct Cues<'a> {
+    cues: &'a [Cue],
+    ppqn: u16,
+    length: u64,
+    /// Round of the loop of the next message.
+    round: u64,
+    index: usize,
+    /// Tick of the transport the stretch ends at.
+    to: u64,
+    /// Lanes whose notes are left out, a bit each.
+    muted: u64,
+}
+
+impl Cues<'_> {
+    /// Leaves the note ons of the `muted` lanes out, a bit each. Their note offs still come, so
+    /// a lane muted mid-note doesn't hang.
+    pub fn except(self, muted: u64) -> Self {
+        Cues { muted, ..self }
+    }
+}
+
+impl Iterator for Cues<'_> {
+    type Item = (f64, LiveEvent<'static>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cues.is_empty() {
+            return None;
+        }
+        loop {
+            if self.index == self.cues.len() {
+                self.round += 1;
+                self.index = 0;
+            }
+            let cue = &self.cues[self.index];
+            let tick = self.round * self.length + cue.tick;
+            if tick >= self.to {
+                return None;
+            }
+            self.index += 1;
+            if self.muted & (1 << cue.lane) == 0 || !is_note_on(&cue.message) {
+                return Some((tick as f64 / self.ppqn as f64, live_event(cue)));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_loop() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "4x--", "snare": "4-x"}, "ppqn": 4}"#).unwrap();
+    let looped = Loop::from_spec(&spec).unwrap();
+    // 3 fourths against 2 line up after 6 fourths, but bars of 4/4 only after 12 of them.
+    assert_eq!(looped.beats(), 12.0);
+    let notes = |from: f64, to: f64| {
+        looped
+            .between(from, to)
+            .map(|(beat, event)| match event {
+                LiveEvent::Midi {
+                    message: MidiMessage::NoteOn { key, .. },
+                    ..
+                } => (beat, key.as_int(), true),
+                LiveEvent::Midi {
+                    message: MidiMessage::NoteOff { key, .. },
+                    ..
+                } => (beat, key.as_int(), false),
+                _ => panic!("Unexpected {:?}", event),
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(notes(0.0, 2.0), vec![(0.0, 36, true), (1.0, 36, false), (1.0, 38, true)]);
+    assert_eq!(notes(0.5, 1.0), vec![]);
+    // Rounds follow each other, the last notes end where the next round starts.
+    assert_eq!(
+        notes(10.0, 13.0),
+        vec![(10.0, 36, false), (10.0, 38, false), (11.0, 38, true), (12.0, 38, false), (12.0, 36, true)]
+    );
+    assert_eq!(notes(12.0, 13.0), vec![(12.0, 38, false), (12.0, 36, true)]);
+    assert_eq!(notes(-1.0, 0.5), vec![(0.0, 36, true)]);
+    assert_eq!(looped.silence().count(), 2);
+    assert_eq!(looped.preamble().count(), 1);
+    // The snare is on the second lane, its note offs still come when it's muted.
+    assert_eq!(looped.parts(), [Part::Drum(DrumPart::KickDrum), Part::Drum(DrumPart::SnareDrum)]);
+    assert_eq!(looped.between(0.0, 3.0).except(0b10).count(), 3);
+    assert_eq!(looped.between(0.0, 3.0).except(0b11).count(), 2);
+
+    let pickup = Spec::from_str(r#"{"patterns": {"kick": "8xx|4x--", "snare": "4-x"}, "ppqn": 4}"#).unwrap();
+    let looped = Loop::from_spec(&pickup).unwrap();
+    assert_eq!(looped.beats(), 12.0);
+    assert_eq!(looped.between(0.0, 1.0).collect::<Vec<_>>(), Loop::from_spec(&spec).unwrap().between(0.0, 1.0).collect::<Vec<_>>());
+
+    // The bass is on the last lane, its program change is sent along with the one of the drums.
+    let bass = Spec::from_str(r#"{"patterns": {"kick": "4x--", "snare": "4-x"}, "ppqn": 4, "bass": true}"#).unwrap();
+    let looped = Loop::from_spec(&bass).unwrap();
+    assert_eq!(looped.parts().last(), Some(&Part::Bass));
+    let channels: Vec<u8> = looped
+        .preamble()
+        .map(|event| match event {
+            LiveEvent::Midi { channel, .. } => channel.as_int(),
+            _ => panic!("Unexpected {:?}", event),
+        })
+        .collect();
+    assert_eq!(channels.len(), 2);
+    assert_ne!(channels[0], channels[1]);
+}
@@ -0,0 +1,329 @@
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::live::playback::Loop;
+
+#[allow(unused_imports)]
+use crate::api::spec::Spec;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Where the transport is: on quarter note `beat` at `at`, moving at `tempo` BPM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Clock {
+    pub tempo: f64,
+    pub beat: f64,
+    pub at: Instant,
+}
+
+impl Clock {
+    /// Quarter note of the transport at an instant, before `at` too.
+    pub fn beat_at(&self, at: Instant) -> f64 {
+        let seconds = match at.checked_duration_since(self.at) {
+            Some(d) => d.as_secs_f64(),
+            None => -self.at.duration_since(at).as_secs_f64(),
+        };
+        self.beat + seconds * self.tempo / 60.0
+    }
+
+    /// Instant the transport gets to a quarter note, `at` for the ones already passed. A
+    /// transport standing still gets to the ones ahead in an hour.
+    pub fn instant_of(&self, beat: f64) -> Instant {
+        if self.tempo <= 0.0 {
+            return self.at + STILL;
+        }
+        let seconds = (beat - self.beat) * 60.0 / self.tempo;
+        self.at + Duration::from_secs_f64(seconds.clamp(0.0, STILL.as_secs_f64()))
+    }
+}
+
+/// Long enough for a transport standing still.
+const STILL: Duration = Duration::from_secs(3600);
+
+/// A change of the transport a `Player` follows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Update {
+    /// Another tempo and phase, the loop carries on from where it got to.
+    Sync(Clock),
+    /// The transport moved, the loop carries on from the quarter note of the clock.
+    Locate(Clock),
+    /// The transport stopped where it is.
+    Stop,
+    /// Another tempo in BPM, from the next tick of the loop on.
+    Tempo(f64),
+    /// Lanes of the loop to leave out, a bit each, see `Mutes`.
+    Mute(u64),
+}
+
+/// Farthest the transport may jump ahead before the notes in between are skipped, in quarter
+/// notes. Playing them all at once would be a burst of noise.
+const MAX_JUMP: f64 = 1.0;
+
+/// Messages this early in quarter notes count as due, the instants they're due at are rounded.
+const EARLY: f64 = 1e-6;
+
+/// Pulses of MIDI clock in a quarter note.
+const PULSES: f64 = 24.0;
+
+/// Plays a loop to a raw MIDI output, like `/dev/snd/midiC1D0`, as its clock goes. The clock
+/// may change its tempo and phase mid-stream, the loop carries on from where it got to.
+pub struct Player<'a, W: Write> {
+    looped: &'a Loop,
+    out: W,
+    clock: Clock,
+    /// The messages before this quarter note are sent.
+    played: f64,
+    /// Next pulse of MIDI clock to send, counted from the start of the transport, `None` when
+    /// the clock isn't sent.
+    pulse: Option<u64>,
+    /// Lanes of the loop left out, a bit each.
+    muted: u64,
+}
+
+impl<'a, W: Write> Player<'a, W> {
+    /// Sets the instruments up, the loop starts on quarter note `from` of the clock.
+    pub fn new(looped: &'a Loop, mut out: W, clock: Clock, from: f64) -> io::Result<Self> {
+        for event in looped.preamble() {
+            event.write_std(&mut out)?;
+        }
+        out.flush()?;
+        Ok(Player {
+            looped,
+            out,
+            clock,
+            played: from,
+            pulse: None,
+            muted: 0,
+        })
+    }
+
+    /// Sends MIDI clock from now on, along with Start, or Song Position Pointer and Continue
+    /// when the loop doesn't start on the first quarter note, for other devices to follow.
+    pub fn send_clock(&mut self) -> io::Result<()> {
+        if self.played > 0.0 {
+            // The position is counted in sixteenths.
+            let position = ((self.played * 4.0).round() as u16).min(0x3FFF);
+            self.out.write_all(&[0xF2, (position & 0x7F) as u8, (position >> 7) as u8, 0xFB])?;
+        } else {
+            self.out.write_all(&[0xFA])?;
+        }
+        self.out.flush()?;
+        self.pulse = Some((self.played * PULSES).ceil() as u64);
+        Ok(())
+    }
+
+    /// Quarter note the loop got to.
+    pub fn beat(&self) -> f64 {
+        self.played
+    }
+
+    pub fn clock(&self) -> Clock {
+        self.clock
+    }
+
+    /// Follows another tempo and phase from now on. A clock that went back doesn't replay the
+    /// notes already sent, one that jumped far ahead skips the notes in between and moves the
+    /// devices following the clock along.
+    pub fn sync(&mut self, clock: Clock) -> io::Result<()> {
+        self.clock = clock;
+        let now = clock.beat_at(Instant::now());
+        if now - self.played > MAX_JUMP {
+            self.silence()?;
+            self.played = now;
+            if self.pulse.is_some() {
+                self.out.write_all(&[0xFC])?;
+                self.send_clock()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves the loop to the quarter note of `clock`, the notes sounding are stopped.
+    pub fn locate(&mut self, clock: Clock) -> io::Result<()> {
+        self.silence()?;
+        self.clock = clock;
+        self.played = clock.beat;
+        Ok(())
+    }
+
+    /// Stands still until the next update, the notes sounding are stopped.
+    pub fn pause(&mut self) -> io::Result<()> {
+        self.silence()?;
+        self.clock = Clock {
+            tempo: 0.0,
+            beat: self.played,
+            at: Instant::now(),
+        };
+        Ok(())
+    }
+
+    /// Changes the tempo on the next tick of the loop, the tick before it is where it was,
+    /// nothing is due in between.
+    pub fn set_tempo(&mut self, tempo: f64, now: Instant) {
+        let tick = 1.0 / self.looped.ppqn() as f64;
+        let boundary = ((self.clock.beat_at(now) / tick).ceil() * tick).max(self.clock.beat);
+        self.clock = Clock {
+            tempo,
+            beat: boundary,
+            at: self.clock.instant_of(boundary),
+        };
+    }
+
+    /// Follows an update of the transport.
+    pub fn update(&mut self, update: Update) -> io::Result<()> {
+        match update {
+            Update::Sync(clock) => self.sync(clock),
+            Update::Locate(clock) => self.locate(clock),
+            Update::Stop => self.pause(),
+            Update::Tempo(tempo) => {
+                self.set_tempo(tempo, Instant::now());
+                Ok(())
+            }
+            Update::Mute(muted) => {
+                self.muted = muted;
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends the messages before quarter note `beat`.
+    pub fn play_to(&mut self, beat: f64) -> io::Result<()> {
+        if beat > self.played {
+            for (at, event) in self.looped.between(self.played, beat).except(self.muted) {
+                // The pulse of the clock goes before the notes that fall on it.
+                self.pulse_to(|pulse| pulse <= at)?;
+                event.write_std(&mut self.out)?;
+            }
+            self.pulse_to(|pulse| pulse < beat)?;
+            self.out.flush()?;
+            self.played = beat;
+        }
+        Ok(())
+    }
+
+    /// Sends the pulses of the clock that fall on the quarter notes `before` accepts.
+    fn pulse_to(&mut self, before: impl Fn(f64) -> bool) -> io::Result<()> {
+        while let Some(pulse) = self.pulse.filter(|p| before(*p as f64 / PULSES)) {
+            self.out.write_all(&[0xF8])?;
+            self.pulse = Some(pulse + 1);
+        }
+        Ok(())
+    }
+
+    /// When the next message is due.
+    pub fn next_due(&self) -> Instant {
+        let note = self
+            .looped
+            .between(self.played, self.played + self.looped.beats() + 1.0)
+            .except(self.muted)
+            .next()
+            .map_or(self.played + self.looped.beats(), |(beat, _)| beat);
+        let next = match self.pulse {
+            Some(pulse) => note.min(pulse as f64 / PULSES),
+            None => note,
+        };
+        self.clock.instant_of(next)
+    }
+
+    /// Sends the messages due by `now`, returns when the next one is due.
+    pub fn play_until(&mut self, now: Instant) -> io::Result<Instant> {
+        self.play_to(self.clock.beat_at(now) + EARLY)?;
+        Ok(self.next_due())
+    }
+
+    fn silence(&mut self) -> io::Result<()> {
+        for event in self.looped.silence() {
+            event.write_std(&mut self.out)?;
+        }
+        self.out.flush()
+    }
+
+    /// Stops the notes still sounding, and the devices following the clock.
+    pub fn stop(mut self) -> io::Result<W> {
+        if self.pulse.is_some() {
+            self.out.write_all(&[0xFC])?;
+        }
+        self.silence()?;
+        Ok(self.out)
+    }
+}
+
+/// Longest sleep between two looks at the clock updates.
+const POLL: Duration = Duration::from_millis(5);
+
+/// Plays until quarter note `until`, or for as long as the process runs, following the
+/// updates of the transport sent to `updates`.
+pub fn run<W: Write>(mut player: Player<W>, updates: &Receiver<Update>, until: Option<f64>) -> io::Result<W> {
+    loop {
+        while let Ok(update) = updates.try_recv() {
+            player.update(update)?;
+        }
+        if let Some(until) = until {
+            if player.clock().beat_at(Instant::now()) + EARLY >= until {
+                player.play_to(until)?;
+                return player.stop();
+            }
+        }
+        let next = player.play_until(Instant::now())?;
+        let due = until.map_or(next, |u| next.min(player.clock().instant_of(u)));
+        thread::sleep(due.saturating_duration_since(Instant::now()).min(POLL));
+    }
+}
+
+#[test]
+fn test_player() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "4x", "snare": "4-x"}, "ppqn": 4}"#).unwrap();
+    let looped = Loop::from_spec(&spec).unwrap();
+    let start = Instant::now();
+    let clock = Clock {
+        tempo: 120.0,
+        beat: 0.0,
+        at: start,
+    };
+    assert_eq!(clock.beat_at(start + Duration::from_millis(250)), 0.5);
+    assert_eq!(clock.instant_of(1.0), start + Duration::from_millis(500));
+
+    let mut player = Player::new(&looped, Vec::new(), clock, 0.0).unwrap();
+    // The program change of the drum kit.
+    assert_eq!(player.out, vec![0xC9, 0x00]);
+    assert_eq!(player.play_until(start).unwrap(), start + Duration::from_millis(500));
+    assert_eq!(player.out[2..], [0x99, 36, 127]);
+    // Twice as fast from beat 0.5 on.
+    player.clock = Clock {
+        tempo: 240.0,
+        beat: 0.5,
+        at: start + Duration::from_millis(250),
+    };
+    let next = player.play_until(start + Duration::from_millis(300)).unwrap();
+    assert!(next.duration_since(start).abs_diff(Duration::from_millis(375)) < Duration::from_micros(1));
+    assert_eq!(player.out.len(), 5);
+    player.play_until(start + Duration::from_millis(375)).unwrap();
+    assert_eq!(player.out[5..], [0x89, 36, 127, 0x99, 36, 127, 0x99, 38, 127]);
+    let out = player.stop().unwrap();
+    assert_eq!(out[14..], [0x89, 36, 0, 0x89, 38, 0]);
+
+    let mut player = Player::new(&looped, Vec::new(), clock, 0.0).unwrap();
+    player.send_clock().unwrap();
+    assert_eq!(player.out, vec![0xC9, 0x00, 0xFA]);
+    player.play_to(0.1).unwrap();
+    assert_eq!(player.out[3..], [0xF8, 0x99, 36, 127, 0xF8, 0xF8]);
+    // A pulse every 1/24 of a quarter note, 20.8ms at 120 BPM.
+    let next = player.next_due().duration_since(start);
+    assert!(next.abs_diff(Duration::from_micros(62500)) < Duration::from_micros(1));
+    player.play_to(1.0).unwrap();
+    assert_eq!(player.out.iter().filter(|b| **b == 0xF8).count(), 24);
+    assert!(player.stop().unwrap().ends_with(&[0xFC, 0x89, 36, 0, 0x89, 38, 0]));
+
+    // Slower from the next tick on, a sixteenth at PPQN 4.
+    let mut player = Player::new(&looped, Vec::new(), clock, 0.0).unwrap();
+    player.set_tempo(60.0, start + Duration::from_millis(100));
+    assert_eq!((player.clock.beat, player.clock.at), (0.25, start + Duration::from_millis(125)));
+    assert_eq!(player.clock.instant_of(0.5), start + Duration::from_millis(375));
+
+    // Starting on bar 3 of the transport.
+    let mut player = Player::new(&looped, Vec::new(), clock, 8.0).unwrap();
+    player.send_clock().unwrap();
+    assert_eq!(player.out[2..], [0xF2, 32, 0, 0xFB]);
+}
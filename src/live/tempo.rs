@@ -0,0 +1,94 @@
+use std::time::{Duration, Instant};
+
+/// Taps further apart than this start counting the tempo anew.
+const TAP_GAP: Duration = Duration::from_secs(2);
+
+/// Taps the tempo is averaged over.
+const TAPS: usize = 4;
+
+/// Slowest tempo taps and nudges get to, in BPM.
+const SLOWEST: f64 = 20.0;
+
+/// Fastest tempo taps and nudges get to, in BPM.
+const FASTEST: f64 = 400.0;
+
+/// Tempo of a loop set from the keyboard while it plays: an empty line is a tap, the tempo
+/// follows the last taps. Every `+` of a line makes it a BPM faster, every `-` a BPM slower.
+#[derive(Debug, Clone)]
+pub struct TempoControl {
+    tempo: f64,
+    taps: Vec<Instant>,
+}
+
+impl TempoControl {
+    pub fn new(tempo: f64) -> Self {
+        TempoControl {
+            tempo,
+            taps: Vec::with_capacity(TAPS),
+        }
+    }
+
+    pub fn tempo(&self) -> f64 {
+        self.tempo
+    }
+
+    /// Reads a line typed at `at`, returns the tempo when it changes.
+    pub fn line(&mut self, line: &str, at: Instant) -> Option<f64> {
+        let line = line.trim();
+        let tempo = if line.is_empty() {
+            self.tap(at)?
+        } else {
+            let nudge: f64 = line
+                .chars()
+                .map(|c| match c {
+                    '+' => 1.0,
+                    '-' => -1.0,
+                    _ => 0.0,
+                })
+                .sum();
+            self.tempo + nudge
+        };
+        let tempo = tempo.clamp(SLOWEST, FASTEST);
+        if tempo == self.tempo {
+            return None;
+        }
+        self.tempo = tempo;
+        Some(tempo)
+    }
+
+    fn tap(&mut self, at: Instant) -> Option<f64> {
+        if self.taps.last().is_some_and(|last| at.duration_since(*last) > TAP_GAP) {
+            self.taps.clear();
+        }
+        if self.taps.len() == TAPS {
+            self.taps.remove(0);
+        }
+        self.taps.push(at);
+        let (first, last) = (self.taps.first()?, self.taps.last()?);
+        let beats = self.taps.len() - 1;
+        if beats == 0 {
+            return None;
+        }
+        let beat = last.duration_since(*first).as_secs_f64() / beats as f64;
+        Some((60.0 / beat * 10.0).round() / 10.0)
+    }
+}
+
+#[test]
+fn test_tempo_control() {
+    let start = Instant::now();
+    let at = |millis: u64| start + Duration::from_millis(millis);
+    let mut control = TempoControl::new(120.0);
+    assert_eq!(control.line("+", at(0)), Some(121.0));
+    assert_eq!(control.line("---\n", at(0)), Some(118.0));
+    assert_eq!(control.line("+-", at(0)), None);
+    // The first tap only starts counting.
+    assert_eq!(control.line("", at(0)), None);
+    assert_eq!(control.line("", at(600)), Some(100.0));
+    assert_eq!(control.line("\n", at(1200)), None);
+    assert_eq!(control.line("", at(1700)), Some(105.9));
+    // A tap long after the last one starts counting anew.
+    assert_eq!(control.line("", at(5000)), None);
+    assert_eq!(control.line("", at(5500)), Some(120.0));
+    assert_eq!(control.tempo(), 120.0);
+}
@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use crate::dsl::dsl::KnownLength;
+use crate::midi::core::Part;
+use crate::midi::registry::PartRegistry;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::midi::core::DrumPart;
+
+/// Velocity boosts of the hits of every part that fall on the strong beats of the meter, and
+/// of every so many hits of a part, on top of their accents and dynamics.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Accents {
+    /// Velocity added to the hits on the first beat of a bar.
+    pub downbeats: u8,
+    /// Whether the other strong beats of the meter get half of it.
+    pub strong_beats: bool,
+    pub every: Vec<Every>,
+}
+
+impl Accents {
+    /// Velocity added to a hit `position` 128th notes into a bar of `time_signature`.
+    pub fn boost(&self, position: u32, time_signature: TimeSignature) -> u8 {
+        if position == 0 {
+            self.downbeats
+        } else if self.strong_beats && strong_beats(time_signature).contains(&position) {
+            self.downbeats / 2
+        } else {
+            0
+        }
+    }
+
+    /// Velocity added to the hit of a part that is `onset` hits, counted from 0, into bar 1.
+    /// The last of `every` of the part wins or, if it has none, the last one of every part.
+    pub fn boost_onset(&self, part: Part, onset: u32) -> u8 {
+        let every = self.every.iter().rev().find(|e| e.part == Some(part)).or_else(|| self.every.iter().rev().find(|e| e.part.is_none()));
+        match every {
+            Some(every) if onset.is_multiple_of(every.nth) => every.amount,
+            _ => 0,
+        }
+    }
+
+    /// Whether any hit gets a boost.
+    pub fn is_empty(&self) -> bool {
+        self.downbeats == 0 && self.every.iter().all(|e| e.amount == 0)
+    }
+}
+
+/// Accents on every `nth` hit of a part, or of each part, written `hihat=3` or `hihat=3:30`
+/// with the velocity added, 20 if it's left out. The first hit of bar 1 is accented, then the
+/// ones `nth` hits apart, whatever beats they fall on, so `hihat=3` over sixteenth notes
+/// accents dotted eighths. The bass is `bass=2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Every {
+    pub part: Option<Part>,
+    pub nth: u32,
+    pub amount: u8,
+}
+
+impl Every {
+    /// Parses the accents of any part of the `registry`, `from_str` only knows the built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, every) = match s.split_once('=') {
+            Some(("bass", rest)) => (Some(Part::Bass), rest),
+            Some((part, rest)) => (Some(Part::Drum(registry.part(part.trim())?)), rest),
+            None => (None, s),
+        };
+        let (nth, amount) = match every.split_once(':') {
+            Some((nth, amount)) => (nth, Some(amount)),
+            None => (every, None),
+        };
+        let nth = match nth.trim().parse::<u32>() {
+            Ok(nth) if nth > 0 => nth,
+            _ => return Err(format!("expected how many hits apart the accents are like 3 or hihat=3:30, got `{}`", s)),
+        };
+        let amount = match amount.map(|a| a.trim().parse::<u8>()) {
+            None => 20,
+            Some(Ok(amount)) if amount <= 127 => amount,
+            Some(_) => return Err(format!("the accents of `{}` should add a velocity from 0 to 127", s)),
+        };
+        Ok(Every { part, nth, amount })
+    }
+}
+
+impl FromStr for Every {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Every::parse(s, &PartRegistry::default())
+    }
+}
+
+/// Where the strong beats other than the first one fall in a bar, in 128th notes. Beats go in
+/// twos, like the third beat of 4/4, or in threes in compound meters like 6/8 and 12/8. Odd
+/// meters like 5/8 and 7/8 go in twos with a three at the end.
+pub fn strong_beats(time_signature: TimeSignature) -> Vec<u32> {
+    let n = time_signature.numerator as u32;
+    let groups: Vec<u32> = match n {
+        0..=3 => vec![n],
+        n if n.is_multiple_of(3) => vec![3; (n / 3) as usize],
+        n if n.is_multiple_of(2) => vec![2; (n / 2) as usize],
+        n => {
+            let mut groups = vec![2; (n / 2 - 1) as usize];
+            groups.push(3);
+            groups
+        }
+    };
+    let beat = time_signature.denominator.to_128th();
+    groups
+        .iter()
+        .scan(0, |start, group| {
+            *start += group;
+            Some(*start)
+        })
+        .filter(|start| *start < n)
+        .map(|start| start * beat)
+        .collect()
+}
+
+#[test]
+fn test_strong_beats() {
+    let strong = |s: &str| strong_beats(TimeSignature::from_str(s).unwrap());
+    assert_eq!(strong("4/4"), vec![64]);
+    assert_eq!(strong("3/4"), Vec::<u32>::new());
+    assert_eq!(strong("6/8"), vec![48]);
+    assert_eq!(strong("12/8"), vec![48, 96, 144]);
+    assert_eq!(strong("7/8"), vec![32, 64]);
+    assert_eq!(strong("5/4"), vec![64]);
+    let accents = Accents {
+        downbeats: 30,
+        strong_beats: true,
+        every: Vec::new(),
+    };
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    assert_eq!(accents.boost(0, four_four), 30);
+    assert_eq!(accents.boost(64, four_four), 15);
+    assert_eq!(accents.boost(32, four_four), 0);
+    assert_eq!(Accents { strong_beats: false, ..accents }.boost(64, four_four), 0);
+}
+
+#[test]
+fn test_accent_every() {
+    let accents = Accents {
+        every: ["4", "hihat=3:30", "bass=2:0"].iter().map(|e| e.parse().unwrap()).collect(),
+        ..Default::default()
+    };
+    let hihat = Part::Drum(DrumPart::HiHat);
+    let boosts: Vec<u8> = (0..7).map(|onset| accents.boost_onset(hihat, onset)).collect();
+    assert_eq!(boosts, vec![30, 0, 0, 30, 0, 0, 30]);
+    assert_eq!(accents.boost_onset(Part::Drum(DrumPart::KickDrum), 4), 20);
+    assert_eq!(accents.boost_onset(Part::Bass, 2), 0);
+    assert!(!accents.is_empty());
+    assert!(Every::from_str("hihat=0").is_err());
+    assert!(Every::from_str("hihat=3:128").is_err());
+    assert!(Every::from_str("cajon=3").is_err());
+}
@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+use crate::dsl::dsl::{BasicLength, Group, Groups, KnownLength, Length, ModdedLength, Note};
+use crate::error::Error;
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+
+/// What to do with a pattern that doesn't fill a whole number of bars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alignment {
+    /// Refuse the pattern.
+    Strict,
+    /// Keep the pattern as is, but report it.
+    Warn,
+    /// Fill the rest of the last bar with rests, so every repeat starts on a bar line.
+    Pad,
+    /// Keep the pattern as is and repeat it from where it ends, drifting against the bars.
+    Cycle,
+}
+
+impl FromStr for Alignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Alignment::Strict),
+            "warn" => Ok(Alignment::Warn),
+            "pad" => Ok(Alignment::Pad),
+            "cycle" => Ok(Alignment::Cycle),
+            _ => Err(format!("{} is not an alignment mode, expected one of: strict, warn, pad, cycle", s)),
+        }
+    }
+}
+
+/// Rests of the longest lengths that add up to `length` 128th notes, if they can.
+fn rests(length: u32) -> Option<Vec<Group<Note, ()>>> {
+    let mut left = length;
+    let mut out = Vec::new();
+    for basic in [
+        BasicLength::Whole,
+        BasicLength::Half,
+        BasicLength::Fourth,
+        BasicLength::Eighth,
+        BasicLength::Sixteenth,
+        BasicLength::ThirtySecond,
+        BasicLength::SixtyFourth,
+        BasicLength::OneHundredTwentyEighth,
+    ] {
+        let count = left / basic.to_128th();
+        if count > 0 {
+            out.push(Group {
+                notes: vec![Note::Rest; count as usize],
+                length: Length::Simple(ModdedLength::Plain(basic)),
+                times: (),
+            });
+            left -= count * basic.to_128th();
+        }
+    }
+    if left == 0 {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+/// Checks that a pattern fills a whole number of bars.
+///
+/// Returns the pattern, padded with rests in `Pad` mode, and a warning in `Warn` mode.
+/// `Cycle` mode takes any pattern as it is.
+pub fn align(
+    part: DrumPart,
+    groups: Groups,
+    time_signature: TimeSignature,
+    alignment: Alignment,
+) -> Result<(Groups, Option<Error>), Error> {
+    let length = groups.to_128th();
+    let bar = time_signature.to_128th();
+    if length.is_multiple_of(bar) && length > 0 {
+        return Ok((groups, None));
+    }
+    let misaligned = Error::Misaligned {
+        part,
+        length,
+        time_signature,
+    };
+    match alignment {
+        Alignment::Strict => Err(misaligned),
+        Alignment::Warn => Ok((groups, Some(misaligned))),
+        Alignment::Cycle => Ok((groups, None)),
+        Alignment::Pad => match rests(bar - length % bar) {
+            Some(padding) => {
+                let mut out = groups.0;
+                out.extend(padding);
+                Ok((Groups(out), None))
+            }
+            None => Err(misaligned),
+        },
+    }
+}
+
+#[test]
+fn test_align() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let bar = groups("4x-x-").unwrap().1;
+    assert_eq!(align(DrumPart::KickDrum, bar.clone(), four_fourth, Alignment::Strict), Ok((bar, None)));
+
+    let short = groups("8x--x-").unwrap().1;
+    let misaligned = Error::Misaligned {
+        part: DrumPart::KickDrum,
+        length: 80,
+        time_signature: four_fourth,
+    };
+    assert_eq!(align(DrumPart::KickDrum, short.clone(), four_fourth, Alignment::Strict), Err(misaligned.clone()));
+    assert_eq!(
+        align(DrumPart::KickDrum, short.clone(), four_fourth, Alignment::Warn),
+        Ok((short.clone(), Some(misaligned)))
+    );
+    let (padded, warning) = align(DrumPart::KickDrum, short, four_fourth, Alignment::Pad).unwrap();
+    assert_eq!((padded.to_string(), warning), ("8x--x-4-8-".to_string(), None));
+    assert_eq!(padded.to_128th(), four_fourth.to_128th());
+    assert_eq!(align(DrumPart::KickDrum, groups("8x--x-").unwrap().1, four_fourth, Alignment::Cycle).unwrap().0.to_string(), "8x--x-");
+    assert_eq!(Alignment::from_str("cycle"), Ok(Alignment::Cycle));
+
+    // Three triplets make a whole eighth note, two don't and take the finest rests to fill.
+    let triplets = groups("16txxx").unwrap().1;
+    let (padded, _) = align(DrumPart::HiHat, triplets, four_fourth, Alignment::Pad).unwrap();
+    assert_eq!(padded.to_string(), "16txxx2-4-8-");
+    let (padded, _) = align(DrumPart::HiHat, groups("16txx").unwrap().1, four_fourth, Alignment::Pad).unwrap();
+    assert_eq!(padded.to_string(), "16txx2-4-8-32-64-");
+}
+
+/// Repeats a pattern for `length` 128th notes, cutting the last repeat short. A note cut in
+/// two is as long as the longest note that fits, rests fill what's left.
+pub fn fill(groups: &Groups, length: u32) -> Groups {
+    if groups.to_128th() == 0 {
+        return groups.clone();
+    }
+    let mut out = Vec::new();
+    let mut at = 0;
+    while at < length {
+        for group in groups.0.iter() {
+            let note_length = group.length.to_128th().max(1);
+            let fits = ((length - at) / note_length) as usize;
+            if fits >= group.notes.len() {
+                out.push(group.clone());
+                at += note_length * group.notes.len() as u32;
+                continue;
+            }
+            if fits > 0 {
+                out.push(Group {
+                    notes: group.notes[..fits].to_vec(),
+                    length: group.length,
+                    times: (),
+                });
+                at += note_length * fits as u32;
+            }
+            if let Some(mut cut) = rests(length - at).filter(|cut| !cut.is_empty()) {
+                cut[0].notes[0] = group.notes[fits];
+                out.extend(cut);
+            }
+            return Groups(out);
+        }
+    }
+    Groups(out)
+}
+
+/// Repeats the patterns of the `fills` parts over the bars the other parts take to line up
+/// again, `max_bars` at most, so they don't make the file any longer. Returns the number of
+/// bars they fill, `None` if every part fills and they're left as they are.
+pub fn fill_parts(
+    groups: &mut BTreeMap<DrumPart, Groups>,
+    fills: &BTreeSet<DrumPart>,
+    time_signature: TimeSignature,
+    max_bars: u32,
+) -> Option<u32> {
+    let others = groups.iter().filter(|(part, _)| !fills.contains(part)).map(|(_, groups)| groups);
+    if groups.keys().all(|part| fills.contains(part)) {
+        return None;
+    }
+    let bars = time_signature.cycle_bars(others).map_or(max_bars, |bars| bars.min(max_bars as u128) as u32);
+    for (part, part_groups) in groups.iter_mut() {
+        if fills.contains(part) {
+            *part_groups = fill(part_groups, bars * time_signature.to_128th());
+        }
+    }
+    Some(bars)
+}
+
+/// Stretches or squeezes a pattern to last exactly `length` 128th notes, its notes keeping
+/// their proportions: seven sixteenth notes over a bar of 4/4 turn into seven in the time of
+/// four quarter notes. The pattern becomes a single tuplet on the finest grid its notes fall
+/// on, longer notes hold over the steps they take. Returns it with how many times as long it
+/// got, in lowest terms.
+pub fn fit(groups: &Groups, length: u32) -> Result<(Groups, (u32, u32)), String> {
+    // Counted in 384th notes, so triplets are whole too.
+    let mut steps = Vec::new();
+    for group in groups.0.iter() {
+        let step = match group.length {
+            Length::Simple(ml) => ml.to_128th() * 3,
+            Length::Tied(first, second) => (first.to_128th() + second.to_128th()) * 3,
+            Length::Triplet(ml) => ml.to_128th() * 2,
+            Length::Tuplet(..) => return Err(format!("`{}` is a tuplet already and can't be fit again", group)),
+        };
+        steps.push(step);
+    }
+    let total: u32 = groups.0.iter().zip(steps.iter()).map(|(group, step)| step * group.notes.len() as u32).sum();
+    if total == 0 || length == 0 {
+        return Err("there are no notes to fit".to_string());
+    }
+    let grid = steps.iter().fold(0, |acc, step| gcd(acc, *step));
+    let mut notes = Vec::new();
+    for (group, step) in groups.0.iter().zip(steps.iter()) {
+        for note in group.notes.iter() {
+            let hold = match note {
+                Note::Rest => Note::Rest,
+                _ => Note::Tie,
+            };
+            notes.push(*note);
+            notes.extend(std::iter::repeat_n(hold, (step / grid - 1) as usize));
+        }
+    }
+    // Written in the longest note that takes fewer than twice the steps, like 4:7:4.
+    let count = notes.len() as u32;
+    let basic = [
+        BasicLength::Whole,
+        BasicLength::Half,
+        BasicLength::Fourth,
+        BasicLength::Eighth,
+        BasicLength::Sixteenth,
+        BasicLength::ThirtySecond,
+        BasicLength::SixtyFourth,
+        BasicLength::OneHundredTwentyEighth,
+    ]
+    .into_iter()
+    .filter(|basic| length.is_multiple_of(basic.to_128th()))
+    .find(|basic| length / basic.to_128th() * 2 > count)
+    .unwrap_or(BasicLength::OneHundredTwentyEighth);
+    let space = length / basic.to_128th();
+    if count > u16::MAX as u32 || space > u16::MAX as u32 {
+        return Err(format!("{} steps over {} notes are too many to fit", count, space));
+    }
+    let ml = ModdedLength::Plain(basic);
+    let tuplet = match (count, space) {
+        (count, space) if count == space => Length::Simple(ml),
+        (count, space) if count * 2 == space * 3 => Length::Triplet(ml),
+        (count, space) => Length::Tuplet(ml, count as u16, space as u16),
+    };
+    let scale = gcd(length * 3, total);
+    Ok((Groups(vec![Group { notes, length: tuplet, times: () }]), (length * 3 / scale, total / scale)))
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+#[test]
+fn test_fit() {
+    let bar = TimeSignature::from_str("4/4").unwrap().to_128th();
+    let (septuplet, scale) = fit(&groups("16xxxxxxx").unwrap().1, bar).unwrap();
+    assert_eq!((septuplet.to_string(), scale), ("4:7:4xxxxxxx".to_string(), (16, 7)));
+    assert_eq!(septuplet.to_128th(), bar);
+    // Longer notes hold over the finer steps, rests stay rests.
+    let (mixed, scale) = fit(&groups("8x-16xx").unwrap().1, bar).unwrap();
+    assert_eq!((mixed.to_string(), scale), ("4tx_--xx".to_string(), (8, 3)));
+    let (triplets, scale) = fit(&groups("8txxx").unwrap().1, 2 * bar).unwrap();
+    assert_eq!((triplets.to_string(), scale), ("1txxx".to_string(), (8, 1)));
+    assert_eq!(fit(&groups("8xxx").unwrap().1, 112).unwrap().0.to_string(), "8:3:7xxx");
+    assert!(fit(&septuplet, bar).is_err());
+}
+
+#[test]
+fn test_fill() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let three_eighths = groups("8x--").unwrap().1;
+    assert_eq!(fill(&three_eighths, four_fourth.to_128th()).to_string(), "8x--8x--8x-");
+    // A note cut in two rests, or keeps the part of it that fits.
+    assert_eq!(fill(&groups("4.x-").unwrap().1, 64).to_string(), "4.x8-");
+    assert_eq!(fill(&groups("4.-x").unwrap().1, 64).to_string(), "4.-8x");
+    assert_eq!(fill(&groups("8x16xx").unwrap().1, 44).to_string(), "8x16xx16x32-");
+    let mut parts = BTreeMap::from([
+        (DrumPart::KickDrum, groups("4x-x-x-").unwrap().1),
+        (DrumPart::HiHat, three_eighths.clone()),
+    ]);
+    let fills = BTreeSet::from([DrumPart::HiHat]);
+    assert_eq!(fill_parts(&mut parts, &fills, four_fourth, 1000), Some(3));
+    assert_eq!(parts[&DrumPart::HiHat].to_128th(), 3 * four_fourth.to_128th());
+    assert_eq!(four_fourth.cycle_bars(parts.values()), Ok(3));
+    let mut alone = BTreeMap::from([(DrumPart::HiHat, three_eighths.clone())]);
+    assert_eq!(fill_parts(&mut alone, &fills, four_fourth, 1000), None);
+    assert_eq!(alone[&DrumPart::HiHat], three_eighths);
+}
@@ -0,0 +1,58 @@
+use std::collections::BTreeSet;
+
+use midly::num::u7;
+
+use crate::dsl::pitch::{Degree, Key};
+
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// What the bass plays along with the kick drum: the degrees of a key, one for each hit of
+/// the kick, over and over. The root of the key by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BassLine {
+    pub key: Key,
+    pub degrees: Vec<Degree>,
+}
+
+impl Default for BassLine {
+    fn default() -> Self {
+        BassLine {
+            key: Key::default(),
+            degrees: vec![Degree { step: 1, shift: 0 }],
+        }
+    }
+}
+
+impl BassLine {
+    /// A line of `degrees` in a `key`, the root if there are none.
+    pub fn new(key: Key, degrees: Vec<Degree>) -> BassLine {
+        match degrees.is_empty() {
+            true => BassLine { key, ..Default::default() },
+            false => BassLine { key, degrees },
+        }
+    }
+
+    /// MIDI key of the note the bass plays on hit `n` of the kick drum, counted from 0.
+    pub fn note(&self, n: usize) -> u7 {
+        let degree = self.degrees[n % self.degrees.len()];
+        u7::from((self.key.root as i32 + degree.semitones(self.key)).clamp(0, 127) as u8)
+    }
+
+    /// Every key the bass plays.
+    pub fn keys(&self) -> BTreeSet<u8> {
+        (0..self.degrees.len()).map(|n| self.note(n).as_int()).collect()
+    }
+}
+
+#[test]
+fn test_bass_line() {
+    let degrees: Vec<Degree> = ["1", "b3", "5", "8"].iter().map(|d| d.parse().unwrap()).collect();
+    let line = BassLine::new(Key::from_str("A1").unwrap(), degrees);
+    let notes: Vec<u8> = (0..5).map(|n| line.note(n).as_int()).collect();
+    assert_eq!(notes, vec![33, 36, 40, 45, 33]);
+    assert_eq!(line.keys(), BTreeSet::from_iter([33, 36, 40, 45]));
+    let minor = BassLine::new(Key::from_str("Am").unwrap(), vec![Degree::from_str("3").unwrap()]);
+    assert_eq!(minor.note(0).as_int(), 36);
+    assert_eq!(BassLine::new(Key::default(), Vec::new()).note(3).as_int(), 28);
+}
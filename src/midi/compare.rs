@@ -0,0 +1,246 @@
+use std::fmt;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use crate::midi::core::{create_smf, DrumPart, SmfOptions, TICKS_PER_QUARTER_NOTE};
+#[allow(unused_imports)]
+use crate::midi::profile::Profile;
+#[allow(unused_imports)]
+use crate::midi::registry::PartRegistry;
+#[allow(unused_imports)]
+use crate::midi::time::TimeSignature;
+#[allow(unused_imports)]
+use std::collections::BTreeMap;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Something one MIDI file has that the other one doesn't, found by `compare`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// The files differ in their format or timing, like `ppqn 48` and `ppqn 96`.
+    Header { expected: String, actual: String },
+    /// The files have a different number of tracks.
+    Tracks { expected: usize, actual: usize },
+    /// An event of the expected file the other one doesn't have, at a tick of a track in
+    /// ticks of the expected file.
+    Missing { track: usize, tick: u64, event: String },
+    /// An event the expected file doesn't have.
+    Extra { track: usize, tick: u64, event: String },
+    /// The files have the same events on a tick of a track, but play them in another order.
+    Order { track: usize, tick: u64 },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Header { expected, actual } => write!(f, "{} instead of {}", actual, expected),
+            Difference::Tracks { expected, actual } => write!(f, "{} tracks instead of {}", actual, expected),
+            Difference::Missing { track, tick, event } => write!(f, "track {}, tick {}: no {}", track + 1, tick, event),
+            Difference::Extra { track, tick, event } => write!(f, "track {}, tick {}: extra {}", track + 1, tick, event),
+            Difference::Order { track, tick } => write!(f, "track {}, tick {}: same events in another order", track + 1, tick),
+        }
+    }
+}
+
+/// An event of a track in words, note ons of velocity 0 read as the note offs they stand for.
+fn describe(kind: &TrackEventKind) -> String {
+    let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+    match kind {
+        TrackEventKind::Midi { channel, message } => {
+            let channel = channel.as_int() + 1;
+            match message {
+                MidiMessage::NoteOn { key, vel } if *vel == 0 => format!("note off {} on channel {}", key, channel),
+                MidiMessage::NoteOn { key, vel } => format!("note on {} velocity {} on channel {}", key, vel, channel),
+                MidiMessage::NoteOff { key, vel } if *vel == 0 => format!("note off {} on channel {}", key, channel),
+                MidiMessage::NoteOff { key, vel } => format!("note off {} velocity {} on channel {}", key, vel, channel),
+                MidiMessage::Controller { controller, value } => format!("controller {} = {} on channel {}", controller, value, channel),
+                MidiMessage::ProgramChange { program } => format!("program {} on channel {}", program, channel),
+                message => format!("{:?} on channel {}", message, channel),
+            }
+        }
+        TrackEventKind::Meta(MetaMessage::TrackName(name)) => format!("track name `{}`", text(name).escape_debug()),
+        TrackEventKind::Meta(MetaMessage::Text(t)) => format!("text `{}`", text(t).escape_debug()),
+        TrackEventKind::Meta(MetaMessage::Marker(m)) => format!("marker `{}`", text(m).escape_debug()),
+        TrackEventKind::Meta(MetaMessage::Tempo(tempo)) => format!("tempo of {} microseconds per quarter note", tempo),
+        TrackEventKind::Meta(MetaMessage::TimeSignature(n, d, _, _)) => format!("time signature {}/{}", n, 1u32 << d),
+        TrackEventKind::Meta(MetaMessage::EndOfTrack) => "end of track".to_string(),
+        TrackEventKind::Meta(meta) => format!("{:?}", meta),
+        kind => format!("{:?}", kind),
+    }
+}
+
+/// The events of a track on every tick, `scale` times the ticks of the file. Meta events are
+/// sorted, as their order on a tick means nothing, the rest are left in the order they're in.
+fn ticks(track: &[midly::TrackEvent], scale: u64) -> Vec<(u64, Vec<String>)> {
+    let mut out: Vec<(u64, Vec<String>)> = Vec::new();
+    let mut metas = Vec::new();
+    let mut time = 0;
+    for event in track {
+        let delta = event.delta.as_int() as u64 * scale;
+        if delta > 0 || out.is_empty() {
+            if let Some((_, events)) = out.last_mut() {
+                metas.sort();
+                events.append(&mut metas);
+            }
+            time += delta;
+            out.push((time, Vec::new()));
+        }
+        let description = describe(&event.kind);
+        match event.kind {
+            TrackEventKind::Meta(_) => metas.push(description),
+            _ => out.last_mut().unwrap().1.push(description),
+        }
+    }
+    if let Some((_, events)) = out.last_mut() {
+        metas.sort();
+        events.append(&mut metas);
+    }
+    out
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Compares the events of two MIDI files track by track, tick by tick, the way a player would
+/// tell them apart. Files with different resolutions are compared on the time the events are
+/// played at, so a file written with `--ppqn 96` matches the same one with 48 ticks per quarter
+/// note besides its header. The order of meta events on a tick doesn't count, and neither do
+/// note ons of velocity 0 standing for note offs. Returns no differences if the files play the
+/// same.
+pub fn compare(expected: &Smf, actual: &Smf) -> Vec<Difference> {
+    let mut differences = Vec::new();
+    if expected.header.format != actual.header.format {
+        differences.push(Difference::Header {
+            expected: format!("{:?}", expected.header.format),
+            actual: format!("{:?}", actual.header.format),
+        });
+    }
+    let (e, a) = match (expected.header.timing, actual.header.timing) {
+        (Timing::Metrical(e), Timing::Metrical(a)) => (e.as_int() as u64, a.as_int() as u64),
+        (e, a) => {
+            if e != a {
+                differences.push(Difference::Header {
+                    expected: format!("{:?}", e),
+                    actual: format!("{:?}", a),
+                });
+            }
+            (1, 1)
+        }
+    };
+    if e != a {
+        differences.push(Difference::Header {
+            expected: format!("ppqn {}", e),
+            actual: format!("ppqn {}", a),
+        });
+    }
+    // Both files in ticks of their least common resolution.
+    let common = e / gcd(e, a) * a;
+    let (scale_e, scale_a) = (common / e, common / a);
+    if expected.tracks.len() != actual.tracks.len() {
+        differences.push(Difference::Tracks {
+            expected: expected.tracks.len(),
+            actual: actual.tracks.len(),
+        });
+    }
+    for (track, (e_track, a_track)) in expected.tracks.iter().zip(actual.tracks.iter()).enumerate() {
+        let mut e_ticks = ticks(e_track, scale_e).into_iter().peekable();
+        let mut a_ticks = ticks(a_track, scale_a).into_iter().peekable();
+        loop {
+            let (tick, e_events, a_events) = match (e_ticks.peek(), a_ticks.peek()) {
+                (None, None) => break,
+                (Some((t, _)), None) => (*t, e_ticks.next().unwrap().1, Vec::new()),
+                (None, Some((t, _))) => (*t, Vec::new(), a_ticks.next().unwrap().1),
+                (Some((te, _)), Some((ta, _))) if te < ta => (*te, e_ticks.next().unwrap().1, Vec::new()),
+                (Some((te, _)), Some((ta, _))) if ta < te => (*ta, Vec::new(), a_ticks.next().unwrap().1),
+                (Some((t, _)), Some(_)) => (*t, e_ticks.next().unwrap().1, a_ticks.next().unwrap().1),
+            };
+            if e_events == a_events {
+                continue;
+            }
+            let tick = tick / scale_e;
+            let mut missing = e_events.clone();
+            let mut extra = Vec::new();
+            for event in a_events.iter() {
+                match missing.iter().position(|m| m == event) {
+                    Some(i) => {
+                        missing.remove(i);
+                    }
+                    None => extra.push(event.clone()),
+                }
+            }
+            if missing.is_empty() && extra.is_empty() {
+                differences.push(Difference::Order { track, tick });
+                continue;
+            }
+            differences.extend(missing.into_iter().map(|event| Difference::Missing { track, tick, event }));
+            differences.extend(extra.into_iter().map(|event| Difference::Extra { track, tick, event }));
+        }
+    }
+    differences
+}
+
+#[test]
+fn test_compare() {
+    let smf = |kick: &str, ppqn: u16| {
+        create_smf(
+            &PartRegistry::default(),
+            BTreeMap::from_iter([(DrumPart::KickDrum, groups(kick).unwrap().1), (DrumPart::SnareDrum, groups("4-x").unwrap().1)]),
+            BTreeMap::new(),
+            "",
+            &SmfOptions { ppqn, ..SmfOptions::default() },
+        )
+    };
+    let (bytes, other) = (smf("4x-x-", TICKS_PER_QUARTER_NOTE), smf("4x-x-", TICKS_PER_QUARTER_NOTE));
+    assert_eq!(compare(&bytes, &other), Vec::new());
+
+    // The same notes at twice the resolution only differ in the header.
+    let fine = smf("4x-x-", TICKS_PER_QUARTER_NOTE * 2);
+    assert_eq!(
+        compare(&bytes, &fine),
+        vec![Difference::Header {
+            expected: "ppqn 48".to_string(),
+            actual: "ppqn 96".to_string()
+        }]
+    );
+
+    let moved = smf("4x--x", TICKS_PER_QUARTER_NOTE);
+    let differences: Vec<String> = compare(&bytes, &moved).iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        differences,
+        vec![
+            "track 1, tick 96: no note on 36 velocity 127 on channel 10",
+            "track 1, tick 144: no note off 36 velocity 127 on channel 10",
+            "track 1, tick 144: extra note on 36 velocity 127 on channel 10",
+            "track 1, tick 192: extra note off 36 velocity 127 on channel 10",
+            "track 1, tick 192: extra end of track",
+            "track 1, tick 240: no end of track",
+        ]
+    );
+
+    // Meta events on a tick may come in any order.
+    let mut shuffled = bytes.clone();
+    let track = &mut shuffled.tracks[0];
+    let names = track.iter().position(|e| matches!(e.kind, TrackEventKind::Meta(MetaMessage::TrackName(_)))).unwrap();
+    track.swap(names, names + 1);
+    assert_eq!(compare(&bytes, &shuffled), Vec::new());
+    // Notes on a tick may not, a note off going after a note on of its key cuts the note short.
+    let mut swapped = bytes.clone();
+    let track = &mut swapped.tracks[0];
+    let snare = track.iter().position(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } if key == 38)).unwrap();
+    let delta = track[snare - 1].delta;
+    track[snare].delta = delta;
+    track[snare - 1].delta = 0.into();
+    track.swap(snare, snare - 1);
+    assert_eq!(compare(&bytes, &swapped), vec![Difference::Order { track: 0, tick: 48 }]);
+    let mut long = bytes.clone();
+    long.tracks.push(bytes.tracks[0].clone());
+    assert_eq!(compare(&bytes, &long), vec![Difference::Tracks { expected: 1, actual: 2 }]);
+}
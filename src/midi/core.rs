@@ -1,9 +1,11 @@
 extern crate derive_more;
 use std::cmp::Ordering;
 use std::cmp::Ordering::*;
-use std::collections::BTreeMap;
+use std::fmt;
+use std::collections::{BTreeMap, BTreeSet};
 use std::iter::Peekable;
-use std::{str::FromStr};
+use std::str::FromStr;
+use std::time::Duration;
 
 use midly::{
     num::u24, num::u28, num::u4, num::u7, Header, MidiMessage, Smf, TrackEventKind,
@@ -11,13 +13,32 @@ use midly::{
 use midly::{MetaMessage, TrackEvent};
 
 use crate::dsl::dsl::{
-    BasicLength, Group, GroupOrNote, Groups,
-    KnownLength, Length, ModdedLength, Note, Times,
-    groups, group_or_delimited_group, flatten_group,
-    EIGHTH, FOURTH, SIXTEENTH
+    dotted, Articulation, BasicLength, Group, GroupOrNote, Groups, Stroke,
+    KnownLength, Length, ModdedLength, Note, Times, DEFAULT_VELOCITY,
 };
+#[allow(unused_imports)]
+use crate::dsl::dsl::{groups, group_or_delimited_group, flatten_group, SIXTEENTH};
+#[allow(unused_imports)]
+use crate::dsl::pitch::{Degree, Key};
 
-use crate::midi::time::TimeSignature;
+use crate::midi::accents::Accents;
+#[allow(unused_imports)]
+use crate::midi::accents::Every;
+#[allow(unused_imports)]
+use crate::midi::bassline::BassLine;
+use crate::midi::dynamics::Dynamics;
+use crate::midi::echo::{echo_of, Echo};
+use crate::midi::groove::Groove;
+use crate::midi::humanize::Humanize;
+use crate::midi::mixer::Mix;
+use crate::midi::names::{TrackNames, DEFAULT_TRACK_NAMES};
+use crate::midi::profile::{Conventions, Profile};
+use crate::midi::registry::PartRegistry;
+use crate::midi::tempo::TempoMap;
+#[allow(unused_imports)]
+use crate::midi::registry::{CustomPart, Layer};
+use crate::midi::time::{BarBeatTick, TimeSignature};
+use crate::midi::velocity::{high_resolution, respond, respond_precise, Curve, NoteOffs};
 #[allow(unused_imports)]
 use GroupOrNote::*;
 #[allow(unused_imports)]
@@ -64,7 +85,7 @@ fn test_add_tick() {
 #[repr(transparent)]
 pub struct Delta(pub u128);
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EventType {
     NoteOn(Part),
     NoteOff(Part),
@@ -72,6 +93,18 @@ pub enum EventType {
 
 use EventType::*;
 
+impl EventType {
+    pub fn is_note_off(&self) -> bool {
+        matches!(self, NoteOff(_))
+    }
+
+    pub fn part(&self) -> Part {
+        match self {
+            NoteOn(part) | NoteOff(part) => *part,
+        }
+    }
+}
+
 impl Ord for EventType {
     fn cmp(&self, other: &EventType) -> Ordering {
         match (self, other) {
@@ -89,17 +122,80 @@ impl Ord for EventType {
     }
 }
 
+impl PartialOrd for EventType {
+    fn partial_cmp(&self, other: &EventType) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
 pub enum DrumPart {
     KickDrum,
     SnareDrum,
     HiHat,
-    CrashCymbal
+    CrashCymbal,
+    Cowbell,
+    Claves,
+    CongaOpen,
+    CongaSlap,
+    CongaMute,
+    BongoHigh,
+    BongoLow,
+    Shaker,
+    Tambourine,
+    /// A part declared by the user, numbered in the order of the `PartRegistry` declaring it.
+    Custom(u8),
 }
 
 #[allow(unused_imports)]
 use DrumPart::*;
 
+impl FromStr for DrumPart {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hi-hat" => Ok(HiHat),
+            "conga" => Ok(CongaOpen),
+            _ => DrumPart::ALL.into_iter().find(|p| p.name() == s).ok_or_else(|| {
+                format!(
+                    "{} is not a known drum part, expected one of: {}",
+                    s,
+                    DrumPart::ALL.map(|p| p.name()).join(", ")
+                )
+            }),
+        }
+    }
+}
+
+impl DrumPart {
+    /// Every drum part, in the order they are listed to the user.
+    pub const ALL: [DrumPart; 13] = [
+        KickDrum, SnareDrum, HiHat, CrashCymbal, Cowbell, Claves, CongaOpen, CongaSlap, CongaMute, BongoHigh,
+        BongoLow, Shaker, Tambourine,
+    ];
+
+    /// Name of the part as accepted by `from_str`, custom parts are named by their `PartRegistry`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KickDrum => "kick",
+            SnareDrum => "snare",
+            HiHat => "hihat",
+            CrashCymbal => "crash",
+            Cowbell => "cowbell",
+            Claves => "claves",
+            CongaOpen => "conga-open",
+            CongaSlap => "conga-slap",
+            CongaMute => "conga-mute",
+            BongoHigh => "bongo-high",
+            BongoLow => "bongo-low",
+            Shaker => "shaker",
+            Tambourine => "tambourine",
+            Custom(_) => "custom",
+        }
+    }
+}
+
 trait ToMidi {
     fn to_midi_key(&self) -> u7;
 }
@@ -112,6 +208,40 @@ impl ToMidi for DrumPart {
             SnareDrum => u7::from(38),
             HiHat => u7::from(46),
             CrashCymbal => u7::from(49),
+            Cowbell => u7::from(56),
+            Claves => u7::from(75),
+            CongaOpen => u7::from(63),
+            // General MIDI has a single muted conga sound for both slaps and mutes.
+            CongaSlap | CongaMute => u7::from(62),
+            BongoHigh => u7::from(60),
+            BongoLow => u7::from(61),
+            Shaker => u7::from(70), // maracas
+            Tambourine => u7::from(54),
+            // Keys of custom parts are up to the `PartRegistry`, this one is never played.
+            Custom(_) => u7::from(0),
+        }
+    }
+}
+
+impl DrumPart {
+    /// Reverses `to_midi_key`, also accepting General MIDI keys of closely related sounds
+    /// (acoustic kick, side stick and electric snare, closed and pedal hi-hats, second crash,
+    /// low conga, cabasa).
+    pub fn from_midi_key(key: u7) -> Option<DrumPart> {
+        match key.as_int() {
+            35 | 36 => Some(KickDrum),
+            37 | 38 | 40 => Some(SnareDrum),
+            42 | 44 | 46 => Some(HiHat),
+            49 | 57 => Some(CrashCymbal),
+            54 => Some(Tambourine),
+            56 => Some(Cowbell),
+            60 => Some(BongoHigh),
+            61 => Some(BongoLow),
+            62 => Some(CongaMute),
+            63 | 64 => Some(CongaOpen),
+            69 | 70 => Some(Shaker),
+            75 => Some(Claves),
+            _ => None,
         }
     }
 }
@@ -125,6 +255,29 @@ impl ToMidi for Part {
     }
 }
 
+impl Part {
+    /// The key of a hit played with an `articulation`. A part is a lane of hits, the
+    /// articulation picks which of its sounds a hit makes.
+    pub(crate) fn midi_key(&self, articulation: Articulation, registry: &PartRegistry) -> u7 {
+        match (self, articulation) {
+            (Drum(part @ Custom(_)), _) => registry.get(*part).map(|p| p.key).unwrap_or(self.to_midi_key()),
+            (Drum(SnareDrum), Articulation::SideStick) => u7::from(37),
+            (Drum(SnareDrum), Articulation::Rimshot) => u7::from(40),
+            (Drum(HiHat), Articulation::Closed) => u7::from(42),
+            (Bass, _) => registry.bass_line().note(0),
+            _ => self.to_midi_key(),
+        }
+    }
+
+    /// Channel the part is played on, the drum kit shares one unless a custom part has its own.
+    pub(crate) fn channel(&self, registry: &PartRegistry, conventions: &Conventions) -> u4 {
+        match self {
+            Drum(part) => registry.get(*part).and_then(|p| p.channel).unwrap_or(conventions.drum_channel),
+            Bass => conventions.bass_channel,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Ord, Eq, Hash)]
 pub enum Part {
     Drum(DrumPart),
@@ -135,11 +288,29 @@ pub enum Part {
 pub struct Event<T> {
     tick: T,
     event_type: EventType,
+    stroke: Stroke,
 }
 
 impl<T> Event<T> {
     pub fn new(tick: T, event_type: EventType) -> Event<T> {
-        Event { tick, event_type }
+        Event { tick, event_type, stroke: Stroke::default() }
+    }
+
+    /// The event played with a `stroke`, which sets the velocity and articulation of a note on.
+    pub fn with_stroke(self, stroke: Stroke) -> Event<T> {
+        Event { stroke, ..self }
+    }
+
+    pub fn tick(&self) -> &T {
+        &self.tick
+    }
+
+    pub fn event_type(&self) -> EventType {
+        self.event_type
+    }
+
+    pub fn stroke(&self) -> Stroke {
+        self.stroke
     }
 }
 
@@ -183,18 +354,22 @@ where
 }
 
 #[test]
+#[allow(clippy::unnecessary_sort_by)]
 fn test_ord_event_t() {
     let first_on = Event {
         tick: Tick(0),
         event_type: NoteOn(Drum(KickDrum)),
+        stroke: Stroke::default(),
     };
     let first_off = Event {
         tick: Tick(24),
         event_type: NoteOff(Drum(KickDrum)),
+        stroke: Stroke::default(),
     };
     let second_on = Event {
         tick: Tick(24),
         event_type: NoteOn(Drum(KickDrum)),
+        stroke: Stroke::default(),
     };
     assert_eq!(first_on.cmp(&first_off), Less);
     assert_eq!(first_off.cmp(&second_on), Less);
@@ -221,7 +396,7 @@ pub struct EventGrid<T> {
 
 impl EventGrid<Tick> {
     pub fn new(events: Vec<Event<Tick>>, end: Tick) -> EventGrid<Tick> {
-        let start = if events.len() == 0 {
+        let start = if events.is_empty() {
             Tick(0)
         } else {
             match events.first() {
@@ -231,8 +406,8 @@ impl EventGrid<Tick> {
         };
         EventGrid {
             events,
-            start: start,
-            end: end,
+            start,
+            end,
         }
     }
 }
@@ -250,25 +425,192 @@ impl<T> EventGrid<T> {
     pub fn iter(&self) -> std::slice::Iter<'_, Event<T>> {
         self.events.iter()
     }
+
+    pub fn events(&self) -> &[Event<T>] {
+        &self.events
+    }
+
+    /// Number of events in the grid.
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn start(&self) -> Tick {
+        self.start
+    }
+
+    pub fn end(&self) -> Tick {
+        self.end
+    }
+}
+
+/// A broken invariant of an `EventGrid`, found by `EventGrid::validate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// The event at `index` comes before the one preceding it.
+    Unsorted { index: usize },
+    /// The note off at `index` has no note on of its part before it.
+    UnmatchedNoteOff { index: usize, part: Part },
+    /// The note on at `index` is never followed by a note off of its part.
+    UnmatchedNoteOn { index: usize, part: Part },
+    /// The grid ends before it starts.
+    NegativeLength { start: Tick, end: Tick },
+    /// The event at `index` comes after the end of the grid.
+    AfterEnd { index: usize, tick: Tick, end: Tick },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Unsorted { index } => write!(f, "event {} comes before the one preceding it", index),
+            Violation::UnmatchedNoteOff { index, part } => {
+                write!(f, "note off {} of {:?} has no note on before it", index, part)
+            }
+            Violation::UnmatchedNoteOn { index, part } => {
+                write!(f, "note on {} of {:?} has no note off after it", index, part)
+            }
+            Violation::NegativeLength { start, end } => write!(f, "grid ends at {} before it starts at {}", end, start),
+            Violation::AfterEnd { index, tick, end } => {
+                write!(f, "event {} at {} comes after the end of the grid at {}", index, tick, end)
+            }
+        }
+    }
+}
+
+impl EventGrid<Tick> {
+    /// A grid of `events` from `start` to `end`, as long as it passes `validate`.
+    pub fn from_events(events: Vec<Event<Tick>>, start: Tick, end: Tick) -> Result<EventGrid<Tick>, Vec<Violation>> {
+        let grid = EventGrid { events, start, end };
+        grid.validate().map(|_| grid)
+    }
+
+    /// Checks that the events are sorted, every note on of a part is matched by a note off of
+    /// it and the other way round, and that the grid doesn't end before it starts or before
+    /// its last event. Returns every broken invariant it finds.
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        if self.end < self.start {
+            violations.push(Violation::NegativeLength {
+                start: self.start,
+                end: self.end,
+            });
+        }
+        // Indices of the note ons of every part that are still sounding.
+        let mut sounding: BTreeMap<Part, Vec<usize>> = BTreeMap::new();
+        for (index, event) in self.events.iter().enumerate() {
+            if index > 0 && *event < self.events[index - 1] {
+                violations.push(Violation::Unsorted { index });
+            }
+            if event.tick > self.end {
+                violations.push(Violation::AfterEnd {
+                    index,
+                    tick: event.tick,
+                    end: self.end,
+                });
+            }
+            match event.event_type {
+                NoteOn(part) => sounding.entry(part).or_default().push(index),
+                NoteOff(part) => {
+                    if sounding.get_mut(&part).and_then(|s| s.pop()).is_none() {
+                        violations.push(Violation::UnmatchedNoteOff { index, part });
+                    }
+                }
+            }
+        }
+        let mut unmatched: Vec<Violation> = sounding
+            .into_iter()
+            .flat_map(|(part, indices)| indices.into_iter().map(move |index| Violation::UnmatchedNoteOn { index, part }))
+            .collect();
+        unmatched.sort_by_key(|v| match v {
+            Violation::UnmatchedNoteOn { index, .. } => *index,
+            _ => 0,
+        });
+        violations.extend(unmatched);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+#[test]
+fn test_validate_event_grid() {
+    let event = |tick, event_type| Event {
+        tick: Tick(tick),
+        event_type,
+        stroke: Stroke::default(),
+    };
+    let valid = EventGrid::new(vec![event(0, NoteOn(Drum(KickDrum))), event(24, NoteOff(Drum(KickDrum)))], Tick(48));
+    assert_eq!(valid.validate(), Ok(()));
+    assert_eq!(groups_to_event_grid(Drum(SnareDrum), &groups("16x-xx_").unwrap().1, TICKS_PER_QUARTER_NOTE).validate(), Ok(()));
+
+    let broken = EventGrid {
+        events: vec![
+            event(24, NoteOff(Drum(KickDrum))),
+            event(12, NoteOn(Drum(SnareDrum))),
+            event(60, NoteOn(Drum(HiHat))),
+        ],
+        start: Tick(48),
+        end: Tick(36),
+    };
+    assert_eq!(
+        broken.validate(),
+        Err(vec![
+            Violation::NegativeLength { start: Tick(48), end: Tick(36) },
+            Violation::UnmatchedNoteOff { index: 0, part: Drum(KickDrum) },
+            Violation::Unsorted { index: 1 },
+            Violation::AfterEnd { index: 2, tick: Tick(60), end: Tick(36) },
+            Violation::UnmatchedNoteOn { index: 1, part: Drum(SnareDrum) },
+            Violation::UnmatchedNoteOn { index: 2, part: Drum(HiHat) },
+        ])
+    );
+    assert_eq!(Violation::Unsorted { index: 1 }.to_string(), "event 1 comes before the one preceding it");
+}
+
+#[test]
+fn test_event_grid_accessors() {
+    let accent = Stroke { velocity: 100, ..Stroke::default() };
+    let on = Event::new(Tick(12), NoteOn(Drum(SnareDrum))).with_stroke(accent);
+    let off = Event::new(Tick(24), NoteOff(Drum(SnareDrum)));
+    assert_eq!((*on.tick(), on.event_type().part(), on.stroke().velocity), (Tick(12), Drum(SnareDrum), 100));
+    assert!(off.event_type().is_note_off());
+
+    let grid = EventGrid::from_events(vec![on, off], Tick(0), Tick(48)).unwrap();
+    assert_eq!((grid.len(), grid.is_empty(), grid.length(), grid.start(), grid.end()), (2, false, Tick(48), Tick(0), Tick(48)));
+    assert_eq!(grid.events(), &[on, off]);
+    assert!(EventGrid::from_events(vec![off, on], Tick(0), Tick(48)).is_err());
+    assert!(EventGrid::<Tick>::empty().is_empty());
 }
 
 // FIXME: add a mutable version for use in `groups_to_event_grid`
-/// Adds two EventGrids together, manipulates the time of the right `EventGrid` by
-/// adding the length of the left one to timings.
+/// Adds two EventGrids together, manipulates the time of the right `EventGrid` so it
+/// starts where the left one ends.
 impl EventGrid<Tick> {
     pub fn concat(&self, other: EventGrid<Tick>) -> EventGrid<Tick> {
         // FIXME: get rid of unnecessary cloning
         let other_events = other.clone().events.into_iter().map(|mut e| {
-            e.tick = e.tick + self.length();
+            e.tick = e.tick + self.end - other.start;
             e
         });
         let mut self_event_copy = self.events.clone();
+        let seam = self_event_copy.len();
         self_event_copy.extend(other_events);
-        EventGrid {
+        // Events of other parts on the tick where the grids meet may go before the last ones.
+        if seam > 0 && seam < self_event_copy.len() && self_event_copy[seam] < self_event_copy[seam - 1] {
+            self_event_copy.sort();
+        }
+        let grid = EventGrid {
             events: self_event_copy,
             start: self.start,
             end: self.start + self.length() + other.length(),
-                }
+        };
+        debug_assert_eq!(grid.validate(), Ok(()), "concatenated grids should stay valid");
+        grid
     }
 }
 
@@ -278,10 +620,12 @@ fn test_concat_event_grid() {
     let kick_on = Event {
         tick: Tick(0),
         event_type: NoteOn(Drum(KickDrum)),
+        stroke: Stroke::default(),
     };
     let kick_off = Event {
         tick: Tick(24),
         event_type: NoteOff(Drum(KickDrum)),
+        stroke: Stroke::default(),
     };
     let simple_grid = EventGrid {
         events: vec![kick_on, kick_off],
@@ -297,10 +641,12 @@ fn test_concat_event_grid() {
             Event {
                 tick: Tick(12),
                 event_type: NoteOn(Drum(HiHat)),
+                stroke: Stroke::default(),
             },
             Event {
                 tick: Tick(24),
                 event_type: NoteOff(Drum(HiHat)),
+                stroke: Stroke::default(),
             },
         ],
         start: Tick(12),
@@ -309,7 +655,7 @@ fn test_concat_event_grid() {
     assert_eq!(
         input.concat(input.clone()),
         EventGrid {
-            events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }],
+            events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)), stroke: Stroke::default() }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)), stroke: Stroke::default() }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)), stroke: Stroke::default() }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)), stroke: Stroke::default() }],
             start: Tick(12),
             end: Tick(36)
         }
@@ -327,57 +673,370 @@ impl<T> EventGrid<T> {
 }
 
 impl EventGrid<Tick> {
+    /// Moves every event of the grid `by` ticks later.
+    pub fn shift(self, by: Tick) -> EventGrid<Tick> {
+        EventGrid {
+            events: self
+                .events
+                .into_iter()
+                .map(|mut e| {
+                    e.tick = e.tick + by;
+                    e
+                })
+                .collect(),
+            start: self.start + by,
+            end: self.end + by,
+        }
+    }
+
     pub fn length(&self) -> Tick {
         self.end - self.start
     }
 
-    /// Converts a single-track(!!!!) sorted `EventGrid<Tick>`
+    /// The grid up to `end`: notes from there on are left out, and the ones still ringing
+    /// there stop on it.
+    pub fn cut(self, end: Tick) -> EventGrid<Tick> {
+        let mut ringing = 0;
+        let events = self
+            .events
+            .into_iter()
+            .filter_map(|mut e| {
+                if !e.event_type.is_note_off() {
+                    ringing += (e.tick < end) as usize;
+                    return Some(e).filter(|e| e.tick < end);
+                }
+                if e.tick > end {
+                    if ringing == 0 {
+                        return None;
+                    }
+                    e.tick = end;
+                }
+                ringing = ringing.saturating_sub(1);
+                Some(e)
+            })
+            .collect();
+        EventGrid {
+            events,
+            start: self.start.min(end),
+            end: self.end.min(end),
+        }
+    }
+
+    /// Converts the ticks of the events to the time since the event before them, the first
+    /// one since tick 0. The events may be of any number of parts: events on the same tick are
+    /// a delta of 0 apart, in the order of `Event`, so a part hit again on the tick its previous
+    /// note ends gets the note off before the note on. Events that aren't in order are put in
+    /// it first, events equal in order keep the order they're in. The grid keeps its start
+    /// and end in ticks.
     pub fn to_delta(&self) -> EventGrid<Delta> {
+        let mut sorted;
+        let events = if self.events.is_sorted() {
+            &self.events
+        } else {
+            sorted = self.events.clone();
+            sorted.sort();
+            &sorted
+        };
         let mut time = Tick(0);
-        let mut delta_grid = EventGrid::empty();
-        for e in &self.events {
+        let mut delta_grid = EventGrid {
+            events: Vec::with_capacity(events.len()),
+            start: self.start,
+            end: self.end,
+        };
+        for e in events {
             let delta = e.tick - time;
-            time = time + delta;
+            time = e.tick;
             delta_grid.events.push(Event {
                 tick: Delta(delta.0),
                 event_type: e.event_type,
+                stroke: e.stroke,
             })
         }
         delta_grid
     }
 }
 
-#[allow(dead_code)]
-static TICKS_PER_QUARTER_NOTE: u16 = 48;
+impl EventGrid<Delta> {
+    /// Converts the deltas back to the ticks of the events, the inverse of `to_delta` for
+    /// sorted grids.
+    pub fn to_tick(&self) -> EventGrid<Tick> {
+        let mut time = Tick(0);
+        let events = self
+            .events
+            .iter()
+            .map(|e| {
+                time = time + Tick(e.tick.0);
+                Event {
+                    tick: time,
+                    event_type: e.event_type,
+                    stroke: e.stroke,
+                }
+            })
+            .collect();
+        EventGrid {
+            events,
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+#[test]
+fn test_to_delta() {
+    let event = |tick, event_type| Event {
+        tick: Tick(tick),
+        event_type,
+        stroke: Stroke::default(),
+    };
+    let deltas = |grid: &EventGrid<Delta>| grid.iter().map(|e| (e.tick.0, e.event_type)).collect::<Vec<_>>();
+    let empty = EventGrid::new(Vec::new(), Tick(0));
+    assert!(empty.to_delta().is_empty());
+
+    // Kick, snare and hihat on the downbeat, the kick again on the tick its note ends.
+    let grid = EventGrid::new(
+        vec![
+            event(0, NoteOn(Drum(KickDrum))),
+            event(0, NoteOn(Drum(SnareDrum))),
+            event(0, NoteOn(Drum(HiHat))),
+            event(12, NoteOff(Drum(HiHat))),
+            event(24, NoteOff(Drum(KickDrum))),
+            event(24, NoteOn(Drum(KickDrum))),
+            event(24, NoteOff(Drum(SnareDrum))),
+            event(48, NoteOff(Drum(KickDrum))),
+        ],
+        Tick(48),
+    );
+    let delta = grid.to_delta();
+    assert_eq!(
+        deltas(&delta),
+        vec![
+            (0, NoteOn(Drum(KickDrum))),
+            (0, NoteOn(Drum(SnareDrum))),
+            (0, NoteOn(Drum(HiHat))),
+            (12, NoteOff(Drum(HiHat))),
+            (12, NoteOff(Drum(KickDrum))),
+            (0, NoteOn(Drum(KickDrum))),
+            (0, NoteOff(Drum(SnareDrum))),
+            (24, NoteOff(Drum(KickDrum))),
+        ]
+    );
+    assert_eq!((delta.start(), delta.end()), (Tick(0), Tick(48)));
+    assert_eq!(delta.iter().map(|e| e.tick.0).sum::<u128>(), 48);
+    assert_eq!(delta.to_tick(), grid);
+
+    // A grid that doesn't start on tick 0 counts its first delta from there.
+    let late = grid.clone().shift(Tick(96));
+    assert_eq!(late.to_delta().events()[0].tick, Delta(96));
+    assert_eq!(late.to_delta().to_tick(), late);
+
+    // Events of several parts out of order, as echoes and merged tracks may leave them, are
+    // put in order instead of making a negative delta.
+    let mut shuffled = grid.events().to_vec();
+    shuffled.reverse();
+    let shuffled = EventGrid::new(shuffled, Tick(48));
+    assert!(shuffled.validate().is_err());
+    assert_eq!(deltas(&shuffled.to_delta()), deltas(&delta));
+    assert_eq!(shuffled.to_delta().to_tick().validate(), Ok(()));
+
+    // Events equal in order keep the order they're in, strokes and all.
+    let soft = event(0, NoteOn(Drum(KickDrum))).with_stroke(Stroke { velocity: 40, ..Stroke::default() });
+    let doubled = EventGrid::new(vec![event(0, NoteOn(Drum(KickDrum))), soft], Tick(0)).to_delta();
+    assert_eq!(doubled.events()[1].stroke(), soft.stroke());
+}
+
+/// What to do with a part hit again before its previous note ends. Some players cut the
+/// note short, some drop the next one or leave a note hanging.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overlap {
+    /// End the previous note where the next one starts.
+    #[default]
+    TruncatePrevious,
+    /// Join the notes into a single one, played by the first hit.
+    Merge,
+    /// Leave the notes overlapping.
+    Allow,
+}
+
+impl FromStr for Overlap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate-previous" => Ok(Overlap::TruncatePrevious),
+            "merge" => Ok(Overlap::Merge),
+            "allow" => Ok(Overlap::Allow),
+            _ => Err(format!("{} is not an overlap policy, expected truncate-previous, merge or allow", s)),
+        }
+    }
+}
+
+impl EventGrid<Tick> {
+    /// Resolves notes of a part that overlap by the `policy`. Note offs are paired with the
+    /// earliest note on of their part that's still sounding, unpaired events are left as they are.
+    pub fn resolve_overlaps(self, policy: Overlap) -> EventGrid<Tick> {
+        if policy == Overlap::Allow {
+            return self;
+        }
+        // Notes as their note on and note off, if there's any.
+        let mut notes: Vec<(Event<Tick>, Option<Event<Tick>>)> = Vec::new();
+        let mut sounding: BTreeMap<Part, std::collections::VecDeque<usize>> = BTreeMap::new();
+        let mut unpaired = Vec::new();
+        for event in self.events {
+            match event.event_type {
+                NoteOn(part) => {
+                    sounding.entry(part).or_default().push_back(notes.len());
+                    notes.push((event, None));
+                }
+                NoteOff(part) => match sounding.get_mut(&part).and_then(|s| s.pop_front()) {
+                    Some(i) => notes[i].1 = Some(event),
+                    None => unpaired.push(event),
+                },
+            }
+        }
+        let mut kept: Vec<(Event<Tick>, Option<Event<Tick>>)> = Vec::with_capacity(notes.len());
+        // Index of the last note of every part in `kept`.
+        let mut last: BTreeMap<Part, usize> = BTreeMap::new();
+        for (on, off) in notes {
+            let NoteOn(part) = on.event_type else { unreachable!() };
+            if let Some(&i) = last.get(&part) {
+                if let (previous_on, Some(previous_off)) = &mut kept[i] {
+                    if on.tick < previous_off.tick {
+                        match policy {
+                            // A hit on the very same tick replaces the previous one.
+                            Overlap::TruncatePrevious if on.tick == previous_on.tick => {
+                                kept[i] = (on, off);
+                                continue;
+                            }
+                            Overlap::TruncatePrevious => previous_off.tick = on.tick,
+                            _ => {
+                                if let Some(off) = off {
+                                    previous_off.tick = previous_off.tick.max(off.tick);
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
+            last.insert(part, kept.len());
+            kept.push((on, off));
+        }
+        let mut events: Vec<Event<Tick>> = kept.into_iter().flat_map(|(on, off)| std::iter::once(on).chain(off)).collect();
+        events.extend(unpaired);
+        events.sort();
+        EventGrid {
+            events,
+            start: self.start,
+            end: self.end,
+        }
+    }
+}
+
+#[test]
+fn test_resolve_overlaps() {
+    let event = |tick, event_type| Event {
+        tick: Tick(tick),
+        event_type,
+        stroke: Stroke::default(),
+    };
+    let grid = EventGrid::new(
+        vec![
+            event(0, NoteOn(Drum(KickDrum))),
+            event(12, NoteOn(Drum(KickDrum))),
+            event(12, NoteOn(Drum(SnareDrum))),
+            event(24, NoteOff(Drum(KickDrum))),
+            event(24, NoteOff(Drum(SnareDrum))),
+            event(36, NoteOff(Drum(KickDrum))),
+        ],
+        Tick(48),
+    );
+    let ticks = |grid: EventGrid<Tick>| -> Vec<(u128, EventType)> { grid.events.iter().map(|e| (e.tick.0, e.event_type)).collect() };
+    assert_eq!(
+        ticks(grid.clone().resolve_overlaps(Overlap::TruncatePrevious)),
+        vec![
+            (0, NoteOn(Drum(KickDrum))),
+            (12, NoteOff(Drum(KickDrum))),
+            (12, NoteOn(Drum(KickDrum))),
+            (12, NoteOn(Drum(SnareDrum))),
+            (24, NoteOff(Drum(SnareDrum))),
+            (36, NoteOff(Drum(KickDrum))),
+        ]
+    );
+    assert_eq!(
+        ticks(grid.clone().resolve_overlaps(Overlap::Merge)),
+        vec![
+            (0, NoteOn(Drum(KickDrum))),
+            (12, NoteOn(Drum(SnareDrum))),
+            (24, NoteOff(Drum(SnareDrum))),
+            (36, NoteOff(Drum(KickDrum))),
+        ]
+    );
+    assert_eq!(grid.clone().resolve_overlaps(Overlap::Allow), grid);
+
+    // A hit on the same tick replaces the note still sounding.
+    let twice = EventGrid::new(
+        vec![
+            event(0, NoteOn(Drum(HiHat))),
+            event(0, NoteOn(Drum(HiHat))),
+            event(12, NoteOff(Drum(HiHat))),
+            event(24, NoteOff(Drum(HiHat))),
+        ],
+        Tick(24),
+    );
+    assert_eq!(ticks(twice.resolve_overlaps(Overlap::TruncatePrevious)), vec![(0, NoteOn(Drum(HiHat))), (24, NoteOff(Drum(HiHat)))]);
+    assert_eq!(Overlap::from_str("merge"), Ok(Overlap::Merge));
+    assert!(Overlap::from_str("drop").is_err());
+}
+
+/// Default resolution, fits every length down to a sixty-fourth note and its triplet.
+pub static TICKS_PER_QUARTER_NOTE: u16 = 48;
 
 #[allow(dead_code)]
 static TICKS_PER_64TH_NOTE: u16 = TICKS_PER_QUARTER_NOTE / 16;
 
+/// Resolutions to pick from when fitting patterns, in ticks per quarter note.
+static RESOLUTIONS: [u16; 5] = [48, 96, 192, 384, 768];
+
+/// Highest resolution a MIDI file can have, it's stored in 15 bits.
+static MAX_RESOLUTION: u16 = 32767;
+
 impl BasicLength {
-    /// `BasicLength` to MIDI Ticks
-    pub fn to_ticks(&self) -> Tick {
-        match self {
-            BasicLength::Whole => Tick((TICKS_PER_QUARTER_NOTE * 4) as u128),
-            BasicLength::Half => Tick((TICKS_PER_QUARTER_NOTE * 2) as u128),
-            BasicLength::Fourth => Tick(TICKS_PER_QUARTER_NOTE as u128),
-            BasicLength::Eighth => Tick((TICKS_PER_QUARTER_NOTE / 2) as u128),
-            BasicLength::Sixteenth => Tick((TICKS_PER_QUARTER_NOTE / 4) as u128),
-            BasicLength::ThirtySecond => Tick((TICKS_PER_QUARTER_NOTE / 8) as u128),
-            BasicLength::SixtyFourth => Tick((TICKS_PER_QUARTER_NOTE / 16) as u128),
-        }
+    /// `BasicLength` to MIDI Ticks at a resolution of `ppqn` ticks per quarter note
+    pub fn to_ticks(self, ppqn: u16) -> Tick {
+        Tick(ppqn as u128 * self.to_128th() as u128 / 32)
+    }
+
+    fn denominator(self) -> u128 {
+        128 / self.to_128th() as u128
     }
 }
 
 impl ModdedLength {
     /// `ModdedLength` to MIDI Ticks
-    fn to_ticks(&self) -> Tick {
+    fn to_ticks(self, ppqn: u16) -> Tick {
         match self {
-            ModdedLength::Plain(blen) => blen.to_ticks(),
+            ModdedLength::Plain(blen) => blen.to_ticks(ppqn),
             ModdedLength::Dotted(blen) => {
-                let Tick(whole) = blen.to_ticks();
+                let Tick(whole) = blen.to_ticks(ppqn);
                 let half = whole / 2;
                 Tick(whole + half)
             }
+            ModdedLength::MultiDotted(blen, dots) => {
+                let Tick(plain) = blen.to_ticks(ppqn);
+                Tick(dotted(plain as u32, dots) as u128)
+            }
+        }
+    }
+
+    /// Length as a fraction of a whole note, `None` if it doesn't fit into numbers.
+    fn fraction(self) -> Option<(u128, u128)> {
+        match self {
+            ModdedLength::Plain(blen) => Some((1, blen.denominator())),
+            ModdedLength::Dotted(blen) => Some((3, 2 * blen.denominator())),
+            ModdedLength::MultiDotted(blen, dots) => {
+                let power = 1u128.checked_shl(dots as u32).filter(|p| *p < 1 << 64)?;
+                Some((2 * power - 1, power * blen.denominator()))
+            }
         }
     }
 }
@@ -390,29 +1049,84 @@ impl Length {
     /// Arguments:
     ///
     /// * `length`: `length` is a variable of type `Length`, which is an enum that represents different
-    /// types of musical note lengths. The function `length_to_ticks` takes a `Length` as input and returns
-    /// a `Tick`, which is a struct representing the number of ticks (a unit of time in music
+    ///   types of musical note lengths. The function `length_to_ticks` takes a `Length` as input and returns
+    ///   a `Tick`, which is a struct representing the number of ticks (a unit of time in music
     ///
     /// Returns:
     ///
     /// The function `length_to_ticks` takes a `Length` enum as input and returns a `Tick` value. The `Tick`
     /// value represents the duration of the note in ticks, which is a unit of time used in music notation
     /// software.
-    fn to_ticks(&self) -> Tick {
+    fn to_ticks(self, ppqn: u16) -> Tick {
         match self {
-            Length::Simple(mlen) => mlen.to_ticks(),
-            Length::Tied(first, second) => first.to_ticks() + second.to_ticks(),
+            Length::Simple(mlen) => mlen.to_ticks(ppqn),
+            Length::Tied(first, second) => first.to_ticks(ppqn) + second.to_ticks(ppqn),
             Length::Triplet(mlen) => {
-                let Tick(straight) = mlen.to_ticks();
+                let Tick(straight) = mlen.to_ticks(ppqn);
                 let triplet = straight * 2 / 3;
                 Tick(triplet)
             }
+            Length::Tuplet(mlen, n, m) => {
+                let Tick(straight) = mlen.to_ticks(ppqn);
+                Tick(straight * m as u128 / n as u128)
+            }
+        }
+    }
+
+    /// Whether the length is a whole number of ticks at `ppqn`, so nothing gets rounded.
+    fn fits(self, ppqn: u16) -> bool {
+        let whole = ppqn as u128 * 4;
+        let exact = |fraction: Option<(u128, u128)>| {
+            fraction.is_some_and(|(n, d)| whole.checked_mul(n).is_some_and(|t| t % d == 0))
+        };
+        match self {
+            Length::Simple(mlen) => exact(mlen.fraction()),
+            Length::Tied(first, second) => exact(first.fraction()) && exact(second.fraction()),
+            Length::Triplet(mlen) => exact(mlen.fraction().map(|(n, d)| (n * 2, d * 3))),
+            Length::Tuplet(mlen, count, space) => exact(mlen.fraction().map(|(n, d)| (n * space as u128, d * count as u128))),
         }
     }
 }
 
+/// Whether every note of the patterns and every timing offset is a whole number of ticks at
+/// `ppqn`, so nothing gets rounded.
+pub fn fits_resolution<'a, I: IntoIterator<Item = &'a Groups>>(patterns: I, ppqn: u16) -> bool {
+    patterns.into_iter().flat_map(|g| g.0.iter()).all(|g| {
+        g.length.fits(ppqn) && g.notes.iter().all(|n| (ppqn as u128 * n.stroke().offset.unsigned_abs() as u128).is_multiple_of(32))
+    })
+}
+
+/// Lowest resolution every note of the patterns fits into without rounding, in ticks per quarter note.
+/// Falls back to the highest resolution available.
+pub fn resolution<'a, I: IntoIterator<Item = &'a Groups>>(patterns: I) -> u16 {
+    let patterns: Vec<&Groups> = patterns.into_iter().collect();
+    let fits = |ppqn: &u16| fits_resolution(patterns.iter().copied(), *ppqn);
+    RESOLUTIONS
+        .into_iter()
+        .find(fits)
+        // Tuplets like sevens need a resolution they divide.
+        .or_else(|| (1..=MAX_RESOLUTION / RESOLUTIONS[0]).map(|k| k * RESOLUTIONS[0]).find(fits))
+        .unwrap_or(RESOLUTIONS[RESOLUTIONS.len() - 1])
+}
+
+#[test]
+fn test_resolution() {
+    let patterns = |ps: &[&str]| -> Vec<Groups> { ps.iter().map(|p| groups(p).unwrap().1).collect() };
+    assert_eq!(resolution(&patterns(&["8x--x--", "4-x", "64txxx"])), 48);
+    assert_eq!(resolution(&patterns(&["64.x"])), 96);
+    assert_eq!(resolution(&patterns(&["128xx"])), 96);
+    assert_eq!(resolution(&patterns(&["128txxx"])), 48);
+    assert_eq!(resolution(&patterns(&["4x[+2]", "4x[-1]"])), 96);
+    assert_eq!(resolution(&patterns(&["4:7:4xxxxxxx"])), 336);
+    assert_eq!(resolution(&patterns(&["4:7:4xxxxxxx", "64.x"])), 672);
+    assert_eq!(BasicLength::OneHundredTwentyEighth.to_ticks(96), Tick(3));
+    assert!(fits_resolution(&patterns(&["8x--x--", "4-x"]), 2));
+    assert!(!fits_resolution(&patterns(&["8x--x--", "4-x"]), 1));
+    assert!(!fits_resolution(&patterns(&["4x8t-x-"]), 4));
+}
+
 #[allow(dead_code)]
-static MICROSECONDS_PER_MINUTE: u128 = 60000000 as u128;
+static MICROSECONDS_PER_MINUTE: u128 = 60000000;
 
 #[allow(dead_code)]
 static MIDI_CLOCKS_PER_CLICK: u8 = 24;
@@ -434,52 +1148,127 @@ static MIDI_CLOCKS_PER_CLICK: u8 = 24;
 pub struct MidiTempo(u24);
 
 impl MidiTempo {
-    fn from_tempo(tempo: u16) -> Self {
-        let mt = MICROSECONDS_PER_MINUTE as u32 / tempo as u32;
-        Self(mt.into())
+    /// Slowest and fastest tempos in BPM a MIDI file can hold.
+    pub const RANGE: std::ops::RangeInclusive<f64> = 3.6..=60000000.0;
+
+    /// Microseconds per quarter note of a tempo in BPM, rounded to the nearest one.
+    pub fn from_tempo(tempo: f64) -> Self {
+        let mt = (MICROSECONDS_PER_MINUTE as f64 / tempo).round().clamp(1.0, u24::max_value().as_int() as f64);
+        Self(u24::from(mt as u32))
+    }
+
+    /// Tempo of a tempo event, in microseconds per quarter note.
+    pub fn from_microseconds(microseconds: u32) -> Self {
+        Self(u24::from(microseconds.clamp(1, u24::max_value().as_int())))
+    }
+
+    pub fn microseconds(self) -> u32 {
+        self.0.as_int()
+    }
+}
+
+const NANOSECONDS_PER_SECOND: u128 = 1_000_000_000;
+
+impl Tick {
+    /// How long the ticks play for at `tempo`, with `ppqn` ticks to a quarter note, to the
+    /// nanosecond. Saturates at `Duration::MAX`, hundreds of billions of years.
+    pub fn to_duration(self, tempo: MidiTempo, ppqn: u16) -> Duration {
+        let nanos = self.0.saturating_mul(tempo.microseconds() as u128 * 1000) / ppqn.max(1) as u128;
+        match u64::try_from(nanos / NANOSECONDS_PER_SECOND) {
+            Ok(secs) => Duration::new(secs, (nanos % NANOSECONDS_PER_SECOND) as u32),
+            Err(_) => Duration::MAX,
+        }
+    }
+
+    /// Seconds the ticks play for at `tempo`, for lengths `Duration` has no room for, like
+    /// cycles that take longer than the universe has been around.
+    pub fn to_seconds(self, tempo: MidiTempo, ppqn: u16) -> f64 {
+        self.0 as f64 * tempo.microseconds() as f64 / ppqn.max(1) as f64 / 1e6
+    }
+}
+
+/// Converts a time to the ticks of a MIDI file it spans.
+pub trait ToTicks {
+    /// Ticks the time spans at `tempo`, with `ppqn` ticks to a quarter note, rounded to the
+    /// nearest one.
+    fn to_ticks(&self, tempo: MidiTempo, ppqn: u16) -> Tick;
+}
+
+impl ToTicks for Duration {
+    fn to_ticks(&self, tempo: MidiTempo, ppqn: u16) -> Tick {
+        let per_quarter = tempo.microseconds() as u128 * 1000;
+        Tick((self.as_nanos() * ppqn as u128 + per_quarter / 2) / per_quarter)
     }
 }
 
+#[test]
+fn test_tick_duration() {
+    let tempo = MidiTempo::from_tempo(120.0);
+    assert_eq!(Tick(48).to_duration(tempo, 48), Duration::from_millis(500));
+    assert_eq!(Tick(1).to_duration(tempo, 48), Duration::from_nanos(10416666));
+    assert_eq!(Tick(96 * 4 * 315).to_seconds(tempo, 96), 630.0);
+    assert_eq!(Duration::from_millis(500).to_ticks(tempo, 48), Tick(48));
+    assert_eq!(Duration::from_secs(630).to_ticks(tempo, 96), Tick(96 * 4 * 315));
+    // A tick of 10416666.67 nanoseconds comes back as the tick nearest to it.
+    assert_eq!(Duration::from_nanos(10416666).to_ticks(tempo, 48), Tick(1));
+    assert_eq!(Duration::from_millis(5).to_ticks(tempo, 48), Tick(0));
+    let slow = MidiTempo::from_tempo(*MidiTempo::RANGE.start());
+    assert_eq!(Tick(u128::MAX).to_duration(slow, 1), Duration::MAX);
+    assert!(Tick(u64::MAX as u128 * 1000).to_seconds(slow, 1) > Duration::MAX.as_secs_f64());
+}
+
+#[test]
+fn test_midi_tempo() {
+    assert_eq!(MidiTempo::from_tempo(120.0), MidiTempo(u24::from(500000)));
+    // 452830.19 microseconds
+    assert_eq!(MidiTempo::from_tempo(132.5), MidiTempo(u24::from(452830)));
+    // 631578.95 microseconds
+    assert_eq!(MidiTempo::from_tempo(95.0), MidiTempo(u24::from(631579)));
+    assert!(MidiTempo::from_tempo(*MidiTempo::RANGE.start()).0.as_int() < u24::max_value().as_int());
+}
+
 /// Returns an EventGrid and a total length. Length is needed as a group can end with rests that are not in the grid,
 /// so we need it to cycle the group.
-fn group_to_event_grid(
-    Group {
-        notes,
-        length,
-        ..
-    }: &Group<Note, ()>,
-    part: Part,
-    start: &Tick,
-) -> EventGrid<Tick> {
-    let mut time = start.clone();
-    let note_length = length.to_ticks();
+#[allow(dead_code)]
+fn group_to_event_grid(group: &Group<Note, ()>, part: Part, start: &Tick, ppqn: u16) -> EventGrid<Tick> {
     let mut grid = EventGrid::empty();
     grid.start = *start;
-    notes.iter().for_each(|entry| {
+    grid.end = *start;
+    push_group(&mut grid, group, part, ppqn);
+    grid
+}
+
+/// Appends the notes of a group to the end of a grid. Ties extend the last hit of the grid,
+/// even when it comes from an earlier group.
+fn push_group(grid: &mut EventGrid<Tick>, Group { notes, length, .. }: &Group<Note, ()>, part: Part, ppqn: u16) {
+    let note_length = length.to_ticks(ppqn);
+    for entry in notes.iter() {
+        let time = grid.end;
+        let note_end = time + note_length;
         match entry {
-            Note::Rest => {
-                let rest_end = time + note_length;
-                time = rest_end;
-                grid.end = rest_end;
+            Note::Rest => {}
+            Note::Tie => {
+                if let Some(last) = grid.events.last_mut() {
+                    if last.event_type == NoteOff(part) && last.tick == time {
+                        last.tick = note_end;
+                    }
+                }
             }
-            Note::Hit => {
-                let note_end = time + note_length;
-                let note_on = Event {
+            Note::Hit | Note::Stroke(_) => {
+                grid.events.push(Event {
                     tick: time,
                     event_type: NoteOn(part),
-                };
-                let note_off = Event {
+                    stroke: entry.stroke(),
+                });
+                grid.events.push(Event {
                     tick: note_end,
                     event_type: NoteOff(part),
-                };
-                grid.events.push(note_on);
-                grid.events.push(note_off);
-                grid.end = note_end;
-                time = note_end;
+                    stroke: entry.stroke(),
+                });
             }
         };
-    });
-    grid
+        grid.end = note_end;
+    }
 }
 
 #[test]
@@ -487,20 +1276,20 @@ fn test_group_to_event_grid() {
     let start_time = Tick(12);
     let group = Group {
         notes: vec![Hit, Hit],
-        length: SIXTEENTH.clone(),
+        length: *SIXTEENTH,
         times: (),
     };
     let grid = EventGrid {
         events: vec![
-            Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)) },
-            Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) },
-            Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)) },
-            Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }
+            Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)), stroke: Stroke::default() },
+            Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)), stroke: Stroke::default() },
+            Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)), stroke: Stroke::default() },
+            Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)), stroke: Stroke::default() }
         ],
         start: start_time,
         end: Tick(36),
     };
-    assert_eq!(group_to_event_grid(&group, Drum(HiHat), &start_time), grid);
+    assert_eq!(group_to_event_grid(&group, Drum(HiHat), &start_time, TICKS_PER_QUARTER_NOTE), grid);
     // assert_eq!(
     //     group_to_event_grid(
     //         flatten_group(group_or_delimited_group("(2,8x--)").unwrap().1).0.first().unwrap(),
@@ -512,13 +1301,15 @@ fn test_group_to_event_grid() {
 }
 
 fn concat_grid(event_grid: EventGrid<Tick>, times: Times) -> EventGrid<Tick> {
-    if times.0 <= 0 {
+    if times.0 == 0 {
         EventGrid::empty()
     } else {
         // FIXME: think about unnecessary cloning
-        (0..)
+        let grid = (0..)
             .take((times.0 - 1) as usize)
-            .fold(event_grid.clone(), |acc, _| acc.concat(event_grid.clone()))
+            .fold(event_grid.clone(), |acc, _| acc.concat(event_grid.clone()));
+        debug_assert_eq!(grid.validate(), Ok(()), "repeated grids should stay valid");
+        grid
     }
 }
 
@@ -530,11 +1321,13 @@ fn test_concat_grid() {
                 events: vec![
                     Event {
                         tick: Tick(12),
-                        event_type: NoteOn(Drum(HiHat))
+                        event_type: NoteOn(Drum(HiHat)),
+                        stroke: Stroke::default(),
                     },
                     Event {
                         tick: Tick(24),
-                        event_type: NoteOff(Drum(HiHat))
+                        event_type: NoteOff(Drum(HiHat)),
+                        stroke: Stroke::default(),
                     }
                 ],
                 start: Tick(12),
@@ -542,55 +1335,112 @@ fn test_concat_grid() {
             },
             Times(2)
         ),
-        EventGrid { events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)) }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)) }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)) }], start: Tick(12), end: Tick(36) }
+        EventGrid { events: vec![Event { tick: Tick(12), event_type: NoteOn(Drum(HiHat)), stroke: Stroke::default() }, Event { tick: Tick(24), event_type: NoteOff(Drum(HiHat)), stroke: Stroke::default() }, Event { tick: Tick(24), event_type: NoteOn(Drum(HiHat)), stroke: Stroke::default() }, Event { tick: Tick(36), event_type: NoteOff(Drum(HiHat)), stroke: Stroke::default() }], start: Tick(12), end: Tick(36) }
     );
 }
 
 /// Takes multiple `Group`s and turn them into a single `EventGrid`.
 /// The point of it is to combine timings into a single MIDI track.
-fn groups_to_event_grid(part: Part, groups: &Groups) -> EventGrid<Tick> {
-    let mut time: Tick = Tick(0);
+fn groups_to_event_grid(part: Part, groups: &Groups, ppqn: u16) -> EventGrid<Tick> {
     let mut grid: EventGrid<Tick> = EventGrid::empty();
-    groups.0.iter().for_each(|group| {
-        // `group_to_event_grid` doesn't know at which point in time groups starts unless we pass
-        // `time` explicitly. Only the first `Group` in `Groups` starts at zero.
-        let new_grid = group_to_event_grid(group, part, &time);
-
-        grid.events.extend(new_grid.events);
-        grid.end = new_grid.end;
-        time = grid.end;
-    });
+    groups.0.iter().for_each(|group| push_group(&mut grid, group, part, ppqn));
     grid
 }
 
-#[derive(Clone, Debug)]
-pub(crate) struct EventIterator {
-    kick: Peekable<std::vec::IntoIter<Event<Tick>>>,
-    snare: Peekable<std::vec::IntoIter<Event<Tick>>>,
-    hihat: Peekable<std::vec::IntoIter<Event<Tick>>>,
-    crash: Peekable<std::vec::IntoIter<Event<Tick>>>,
-    time_signature: TimeSignature,
-    bars: u32
+/// Length of the ties a pattern starts with, they hold the last hit of the previous repeat.
+fn leading_tie(groups: &Groups, ppqn: u16) -> Tick {
+    groups
+        .0
+        .iter()
+        .flat_map(|g| g.notes.iter().map(move |n| (n, g.length)))
+        .take_while(|(n, _)| **n == Note::Tie)
+        .fold(Tick(0), |acc, (_, length)| acc + length.to_ticks(ppqn))
 }
 
-impl EventIterator {
+/// Repeats a grid like `concat_grid`, letting hits at the end of a repeat ring into
+/// the leading ties of the next one.
+fn repeat_grid(event_grid: EventGrid<Tick>, times: Times, tie: Tick) -> EventGrid<Tick> {
+    let period = event_grid.end;
+    let mut grid = concat_grid(event_grid, times);
+    if tie > Tick(0) && period > Tick(0) {
+        let end = grid.end;
+        for e in grid.events.iter_mut() {
+            if e.event_type.is_note_off() && e.tick < end && e.tick.0 % period.0 == 0 {
+                e.tick = e.tick + tie;
+            }
+        }
+    }
+    grid
+}
+
+/// Events of the drum parts of a file in the order they're played, see `merge_into_iterator`.
+/// `annotated` tags every event with the repeat of its pattern and the bar and beat it's on.
+#[derive(Clone, Debug)]
+pub struct EventIterator {
+    /// Lanes of events of drum parts, in the order they're played. A part may have more than one.
+    lanes: Vec<(DrumPart, Peekable<std::vec::IntoIter<Event<Tick>>>)>,
+    time_signature: TimeSignature,
+    #[allow(dead_code)]
+    bars: u32,
+    /// Length of the pickup, bar 1 starts after it.
+    pickup: Tick,
+    /// Length of a single repeat of the pattern of every part after the pickup.
+    lengths: BTreeMap<DrumPart, Tick>,
+    ppqn: u16,
+}
+
+/// An event with where it's played, see `EventIterator::annotated`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AnnotatedEvent {
+    pub event: Event<Tick>,
+    /// Repeat of the pattern of its part the event is played in, counted from 0. `None` in the
+    /// pickup.
+    pub cycle: Option<u32>,
+    /// Bar, beat and tick the event is played on. Bar 1 starts after the pickup, which is in
+    /// bar 0.
+    pub position: BarBeatTick,
+}
+
+impl EventIterator {
     fn new(
-        kick_grid: EventGrid<Tick>,
-        snare_grid: EventGrid<Tick>,
-        hihat_grid: EventGrid<Tick>,
-        crash_grid: EventGrid<Tick>,
+        lanes: impl IntoIterator<Item = (DrumPart, EventGrid<Tick>)>,
         time_signature: TimeSignature,
-        bars: u32
+        bars: u32,
+        pickup: Tick,
+        ppqn: u16,
     ) -> EventIterator {
-        let event_iterator = EventIterator {
-            kick: kick_grid.into_iter().peekable(),
-            snare: snare_grid.into_iter().peekable(),
-            hihat: hihat_grid.into_iter().peekable(),
-            crash: crash_grid.into_iter().peekable(),
+        EventIterator {
+            lanes: lanes.into_iter().map(|(part, grid)| (part, grid.into_iter().peekable())).collect(),
             time_signature,
-            bars
-        };
-        event_iterator
+            bars,
+            pickup,
+            lengths: BTreeMap::new(),
+            ppqn,
+        }
+    }
+
+    /// Leaves the lanes of the `muted` parts out.
+    fn mute(mut self, muted: &BTreeSet<Part>) -> EventIterator {
+        self.lanes.retain(|(part, _)| !muted.contains(&Drum(*part)));
+        self
+    }
+
+    /// The same events, each with the repeat of its pattern and the bar and beat it's played
+    /// on, for visualizers and practice tools that follow the file as it plays.
+    pub fn annotated(self) -> Annotated {
+        Annotated(self)
+    }
+
+    /// The next event and the part of its lane.
+    fn next_of_part(&mut self) -> Option<(DrumPart, Event<Tick>)> {
+        // Simultaneous events are taken in the order of their parts, then of their lanes.
+        let (_, part, lane) = self
+            .lanes
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, (part, events))| events.peek().map(|x| (*x, *part, i)))
+            .min()?;
+        Some((part, self.lanes[lane].1.next()?))
     }
 }
 
@@ -599,30 +1449,33 @@ impl Iterator for EventIterator {
 
     #[inline]
     fn next(&mut self) -> Option<Self::Item> {
-        let candidates: BTreeMap<DrumPart, Event<Tick>> = [
-            (KickDrum, self.kick.peek()),
-            (SnareDrum, self.snare.peek()),
-            (HiHat, self.hihat.peek()),
-            (CrashCymbal, self.crash.peek()),
-        ]
-        .into_iter()
-        .filter_map(|(p, x)| match x {
-            Some(x) => Some((p, *x)),
-            None => None,
-        })
-        .collect();
+        self.next_of_part().map(|(_, event)| event)
+    }
+}
 
-        if let Some((min_part, min_event)) = candidates.iter().min_by_key(|(_, x)| *x) {
-            match min_part {
-                KickDrum => self.kick.next(),
-                SnareDrum => self.snare.next(),
-                HiHat => self.hihat.next(),
-                CrashCymbal => self.crash.next(),
-            };
-            Some(*min_event)
-        } else {
-            None
-        }
+/// Events of an `EventIterator` with where they're played.
+#[derive(Clone, Debug)]
+pub struct Annotated(EventIterator);
+
+impl Iterator for Annotated {
+    type Item = AnnotatedEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let events = &mut self.0;
+        let (part, event) = events.next_of_part()?;
+        let cycle = match (event.tick.0.checked_sub(events.pickup.0), events.lengths.get(&part)) {
+            (Some(t), Some(length)) if length.0 > 0 => Some((t / length.0) as u32),
+            _ => None,
+        };
+        // The pickup is shorter than a bar, it starts somewhere in bar 0.
+        let bar = bar_length(events.time_signature, events.ppqn);
+        let before = if events.pickup > Tick(0) { bar } else { Tick(0) };
+        let position = BarBeatTick::from_tick(event.tick + before - events.pickup, events.time_signature, events.ppqn);
+        Some(AnnotatedEvent {
+            event,
+            cycle,
+            position: position.add_bars(-i64::from(before > Tick(0))),
+        })
     }
 }
 
@@ -630,127 +1483,680 @@ impl Iterator for EventIterator {
 fn test_event_iterator_impl() {
     let empty = EventGrid::empty();
     let kick1 = group_to_event_grid(
-        &flatten_group(group_or_delimited_group("(4x-)").unwrap().1)
+        flatten_group(group_or_delimited_group("(4x-)").unwrap().1)
             .0
             .first()
             .unwrap(),
         Drum(KickDrum),
-        &mut Tick(0),
+        &Tick(0),
+        TICKS_PER_QUARTER_NOTE,
     );
     let snare1 = group_to_event_grid(
-        &flatten_group(group_or_delimited_group("(4-x)").unwrap().1)
+        flatten_group(group_or_delimited_group("(4-x)").unwrap().1)
             .0
             .first()
             .unwrap(),
         Drum(SnareDrum),
-        &mut Tick(0),
+        &Tick(0),
+        TICKS_PER_QUARTER_NOTE,
     );
 
     assert_eq!(
         EventIterator::new(
-            kick1.clone(),
-            snare1.clone(),
-            empty.clone(),
-            empty.clone(),
+            [(KickDrum, kick1.clone()), (SnareDrum, snare1.clone()), (HiHat, empty.clone())],
             TimeSignature::from_str("4/4").unwrap(),
-            1
+            1,
+            Tick(0),
+            TICKS_PER_QUARTER_NOTE,
         )
-        .into_iter()
         .collect::<Vec<Event<Tick>>>(),
         vec![
             Event {
                 tick: Tick(0),
-                event_type: NoteOn(Drum(KickDrum))
+                event_type: NoteOn(Drum(KickDrum)),
+                stroke: Stroke::default(),
             },
             Event {
                 tick: Tick(48),
-                event_type: NoteOff(Drum(KickDrum))
+                event_type: NoteOff(Drum(KickDrum)),
+                stroke: Stroke::default(),
             },
             Event {
                 tick: Tick(48),
-                event_type: NoteOn(Drum(SnareDrum))
+                event_type: NoteOn(Drum(SnareDrum)),
+                stroke: Stroke::default(),
             },
             Event {
                 tick: Tick(96),
-                event_type: NoteOff(Drum(SnareDrum))
+                event_type: NoteOff(Drum(SnareDrum)),
+                stroke: Stroke::default(),
             }
         ]
     );
 
     assert_eq!(
         EventIterator::new(
-            kick1.clone(),
-            empty.clone(),
-            empty.clone(),
-            empty.clone(),
+            [(KickDrum, kick1.clone()), (SnareDrum, empty.clone())],
             TimeSignature::from_str("4/4").unwrap(),
-            1
+            1,
+            Tick(0),
+            TICKS_PER_QUARTER_NOTE,
         )
-        .into_iter()
         .collect::<Vec<Event<Tick>>>(),
         [
             Event {
                 tick: Tick(0),
-                event_type: NoteOn(Drum(KickDrum))
+                event_type: NoteOn(Drum(KickDrum)),
+                stroke: Stroke::default(),
             },
             Event {
                 tick: Tick(48),
-                event_type: NoteOff(Drum(KickDrum))
+                event_type: NoteOff(Drum(KickDrum)),
+                stroke: Stroke::default(),
             }
         ]
     );
 }
 
+#[test]
+fn test_event_iterator_lanes() {
+    let grid = |part: DrumPart, pattern: &str| groups_to_event_grid(Drum(part), &groups(pattern).unwrap().1, TICKS_PER_QUARTER_NOTE);
+    let note_ons = |lanes: Vec<(DrumPart, EventGrid<Tick>)>| {
+        EventIterator::new(lanes, TimeSignature::from_str("4/4").unwrap(), 1, Tick(0), TICKS_PER_QUARTER_NOTE)
+            .filter(|e| !e.event_type.is_note_off())
+            .map(|e| (e.tick, e.event_type))
+            .collect::<Vec<_>>()
+    };
+    // Any number of lanes of any parts, custom ones included, and a part may take more than one.
+    assert_eq!(
+        note_ons(vec![
+            (Custom(1), grid(Custom(1), "4x")),
+            (Tambourine, grid(Tambourine, "4-x")),
+            (Custom(0), grid(Custom(0), "4x")),
+            (Tambourine, grid(Tambourine, "4--x")),
+        ]),
+        vec![
+            (Tick(0), NoteOn(Drum(Custom(0)))),
+            (Tick(0), NoteOn(Drum(Custom(1)))),
+            (Tick(48), NoteOn(Drum(Tambourine))),
+            (Tick(96), NoteOn(Drum(Tambourine))),
+        ]
+    );
+}
+
+#[test]
+fn test_annotated_events() {
+    let note_ons: Vec<(Part, Option<u32>, String)> = merge_into_iterator(
+        &BTreeMap::from_iter([(KickDrum, groups("4x-x").unwrap().1)]),
+        &BTreeMap::from_iter([(SnareDrum, groups("8x").unwrap().1)]),
+        &BTreeMap::new(),
+        TimeSignature::from_str("4/4").unwrap(),
+        2,
+        TICKS_PER_QUARTER_NOTE,
+    )
+    .annotated()
+    .filter(|a| !a.event.event_type.is_note_off())
+    .map(|a| (a.event.event_type.part(), a.cycle, a.position.to_string()))
+    .collect();
+    assert_eq!(
+        note_ons,
+        vec![
+            (Drum(SnareDrum), None, "0:4:24".to_string()),
+            (Drum(KickDrum), Some(0), "1:1:0".to_string()),
+            (Drum(KickDrum), Some(0), "1:3:0".to_string()),
+            (Drum(KickDrum), Some(1), "1:4:0".to_string()),
+            (Drum(KickDrum), Some(1), "2:2:0".to_string()),
+            // The third repeat is cut off after its first note.
+            (Drum(KickDrum), Some(2), "2:3:0".to_string()),
+        ]
+    );
+}
+
+/// Number of bars it takes the patterns to line up again, the file is cut short at `BAR_LIMIT`
+/// bars if they never do.
+pub fn converges_over_bars(groups: &BTreeMap<DrumPart, Groups>, time_signature: TimeSignature) -> u32 {
+    convergence(groups, time_signature, TICKS_PER_QUARTER_NOTE, &Limits::default()).map_or(BAR_LIMIT, |c| c.bars)
+}
+
+/// Whether a file of the patterns `length` 128th notes long after the pickup would have no
+/// notes: the parts that aren't `muted` only rest before the file is cut off, and so does the
+/// kick drum if the bass follows it.
+pub fn is_silent(groups: &BTreeMap<DrumPart, Groups>, pickups: &BTreeMap<DrumPart, Groups>, muted: &BTreeSet<Part>, add_bass: bool, length: u32) -> bool {
+    let heard = |part: &DrumPart| !muted.contains(&Drum(*part)) || add_bass && *part == KickDrum && !muted.contains(&Bass);
+    let plays = groups.iter().any(|(part, groups)| heard(part) && groups.first_hit().is_some_and(|at| at < length));
+    !plays && !pickups.iter().any(|(part, groups)| heard(part) && groups.has_hits())
+}
+
+/// What to do with patterns that run past a `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OnLimit {
+    /// Cut the file short at the limit.
+    #[default]
+    Truncate,
+    /// Refuse to write the file.
+    Fail,
+}
+
+impl FromStr for OnLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "truncate" => Ok(OnLimit::Truncate),
+            "fail" => Ok(OnLimit::Fail),
+            _ => Err(format!("{} is not a way to handle limits, expected truncate or fail", s)),
+        }
+    }
+}
+
+/// Bounds of the length of a file, patterns that take long to converge make huge files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_bars: u32,
+    /// Highest number of note on and off events of the drums, not counting the pickups.
+    pub max_events: Option<usize>,
+    pub on_limit: OnLimit,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_bars: BAR_LIMIT,
+            max_events: None,
+            on_limit: OnLimit::Truncate,
+        }
+    }
+}
+
+/// How long the file for a set of patterns is, see `convergence`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Convergence {
+    /// Number of bars it takes the patterns to line up again, `None` if they don't within the limit.
+    pub converges: Option<u32>,
+    /// Exact number of bars it takes the patterns to line up again, however many they are.
+    /// `None` if it doesn't even fit into `u128`.
+    pub cycle: Option<u128>,
+    /// Number of bars to write, fewer than it takes to converge if the file is truncated.
+    pub bars: u32,
+    /// Number of note on and off events of the drums in these bars, not counting the pickups.
+    pub events: usize,
+    pub on_limit: OnLimit,
+}
+
+impl Convergence {
+    pub fn is_truncated(&self) -> bool {
+        self.converges != Some(self.bars)
+    }
+}
+
+/// Works out how many bars to write for the patterns to line up again within the `limits`.
+/// Truncated files stop at a bar line, while `OnLimit::Fail` gives an error saying which
+/// limit they run past. A pattern of no length can't be repeated to fill the bars and is an
+/// error too.
+pub fn convergence(
+    groups: &BTreeMap<DrumPart, Groups>,
+    time_signature: TimeSignature,
+    ppqn: u16,
+    limits: &Limits,
+) -> Result<Convergence, String> {
+    if let Some(part) = groups.iter().find(|(_, groups)| groups.to_128th() == 0).map(|(part, _)| part) {
+        return Err(format!("The {} pattern has no length, it can't be repeated", part.name()));
+    }
+    let bar = time_signature.to_128th() as u64;
+    let bar_ticks = bar_length(time_signature, ppqn).0;
+    // A single repeat of every pattern and its length in 128th notes.
+    let repeats: Vec<(EventGrid<Tick>, u64)> = groups
+        .iter()
+        .map(|(part, groups)| (groups_to_event_grid(Drum(*part), groups, ppqn), groups.to_128th().max(1) as u64))
+        .collect();
+    // Whole repeats and the notes of the last one that start before it's cut off.
+    let events = |bars: u32| -> u64 {
+        let end = bar_ticks * bars as u128;
+        repeats
+            .iter()
+            .map(|(grid, _)| {
+                let period = grid.end.0.max(1);
+                let cut = grid.events.iter().filter(|e| !e.event_type.is_note_off() && e.tick.0 < end % period).count();
+                (grid.events.len() as u128 * (end / period) + 2 * cut as u128) as u64
+            })
+            .sum()
+    };
+    // Patterns are repeated at most `u16::MAX` times.
+    let max_bars = repeats
+        .iter()
+        .map(|(_, length)| (u16::MAX as u64 * length / bar.max(1)).min(u32::MAX as u64) as u32)
+        .fold(limits.max_bars.max(1), u32::min);
+
+    let cycle = time_signature.cycle_bars(groups.values()).ok();
+    let converges = cycle.filter(|c| *c <= max_bars as u128).map(|c| c as u32);
+    let mut bars = converges.unwrap_or(max_bars);
+    if converges.is_none() && limits.on_limit == OnLimit::Fail {
+        return Err(match cycle {
+            Some(cycle) => format!("The patterns take {} bars to converge, more than {}", cycle, max_bars),
+            None => format!("The patterns don't converge within {} bars", max_bars),
+        });
+    }
+    if let Some(max_events) = limits.max_events {
+        if events(bars) > max_events as u64 {
+            if limits.on_limit == OnLimit::Fail {
+                return Err(format!(
+                    "The patterns take {} events to converge over {} bars, more than {}",
+                    events(bars),
+                    bars,
+                    max_events
+                ));
+            }
+            // The most bars that fit, or a single one if none does.
+            let (mut fits, mut over) = (1, bars);
+            while over - fits > 1 {
+                let middle = fits + (over - fits) / 2;
+                if events(middle) <= max_events as u64 {
+                    fits = middle
+                } else {
+                    over = middle
+                }
+            }
+            bars = fits;
+        }
+    }
+    let report = Convergence {
+        converges,
+        cycle,
+        bars,
+        events: events(bars) as usize,
+        on_limit: limits.on_limit,
+    };
+    if report.is_truncated() {
+        tracing::info!(bars, events = report.events, "patterns cut short");
+    } else {
+        tracing::debug!(bars, events = report.events, "patterns converge");
+    }
+    Ok(report)
+}
+
+/// Progress of writing a MIDI file, reported after every bar of drums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bars written so far, the pickup counts as one.
+    pub bars: u32,
+    /// Bars of the whole file.
+    pub total: u32,
+}
+
 /// Takes a mapping of drum parts and produce an `EventIterator` that return the next MIDI event.
 /// Calling .collect() on this EventIterator should produce an `EventGrid`.
 ///
+/// Pickups are played before bar 1 and end right on it, so bar 1 starts after the longest one.
+///
 /// Returns time as a number of ticks from beginning, has to be turned into the midi delta-time.
-fn merge_into_iterator(
+pub fn merge_into_iterator(
     groups: &BTreeMap<DrumPart, Groups>,
+    pickups: &BTreeMap<DrumPart, Groups>,
+    grooves: &BTreeMap<DrumPart, Groove>,
     time_signature: TimeSignature,
+    bars: u32,
+    ppqn: u16,
 ) -> EventIterator {
     // Maps a drum part to a number of 128th notes
     let length_map: BTreeMap<DrumPart, u32> = groups.iter().map(|(k, x)| (*k, x.to_128th())).collect();
 
-    // We want exactly length_limit or BAR_LIMIT
-    let converges_over_bars = time_signature
-        .converges(groups.values())
-        .unwrap_or(BAR_LIMIT.clone());
-
-    if converges_over_bars == 1 {
-        println!("Converges over {} bar", converges_over_bars);
-    } else {
-        println!("Converges over {} bars", converges_over_bars);
-    }
-
     // length limit in 128th notes
-    let length_limit = converges_over_bars * time_signature.to_128th();
+    let length_limit = bars * time_signature.to_128th();
+
+    let end = bar_length(time_signature, ppqn) * bars as u128;
+    let pickup = pickup_length(pickups, ppqn);
 
     let to_event_grid = |part: &DrumPart| {
-        match groups.get(part) {
+        let body = match groups.get(part) {
+            // A pattern of no length has nothing to repeat, `convergence` rejects it.
+            Some(_) if length_map[part] == 0 => EventGrid::empty(),
             Some(groups) => {
-                let length_128th = length_map.get(part).unwrap();
-                let times = length_limit / length_128th;
-                let event_grid = groups_to_event_grid(Drum(*part), groups);
-                (event_grid, times)
+                // The last repeat is cut off at the end of the file when it runs past it.
+                let times = length_limit.div_ceil(length_map[part]);
+                let event_grid = groups_to_event_grid(Drum(*part), groups, ppqn);
+                repeat_grid(event_grid, Times(times as u16), leading_tie(groups, ppqn)).cut(end)
+            }
+            None => EventGrid::empty(),
+        };
+        let grid = with_pickup(Drum(*part), pickups.get(part), body, pickup, ppqn);
+        apply_offsets(grid, grooves.get(part), time_signature, pickup, ppqn)
+    };
+
+    let parts: Vec<&DrumPart> = groups.keys().chain(pickups.keys()).collect::<BTreeSet<_>>().into_iter().collect();
+    // Parts don't depend on each other, long files with many of them build faster in parallel.
+    #[cfg(feature = "parallel")]
+    let grids: Vec<(DrumPart, EventGrid<Tick>)> = {
+        use rayon::prelude::*;
+        parts.par_iter().map(|part| (**part, to_event_grid(part))).collect()
+    };
+    #[cfg(not(feature = "parallel"))]
+    let grids: Vec<(DrumPart, EventGrid<Tick>)> = parts.iter().map(|part| (**part, to_event_grid(part))).collect();
+    let lengths = groups.iter().map(|(part, groups)| (*part, Tick(groups.to_128th() as u128 * ppqn as u128 / 32))).collect();
+    EventIterator {
+        lengths,
+        ..EventIterator::new(grids, time_signature, bars, pickup, ppqn)
+    }
+}
+
+/// Moves the hits of a single part grid by their timing offsets and the `groove` of the part,
+/// which also changes their velocity. Hits pushed ahead of the beat stop at the start of
+/// their bar, and a hit that ends up running into the next one is cut short by it.
+fn apply_offsets(
+    grid: EventGrid<Tick>,
+    groove: Option<&Groove>,
+    time_signature: TimeSignature,
+    bar_one: Tick,
+    ppqn: u16,
+) -> EventGrid<Tick> {
+    if groove.is_none() && grid.events.iter().all(|e| e.stroke.offset == 0) {
+        return grid;
+    }
+    let Tick(bar) = bar_length(time_signature, ppqn);
+    let bar_start = |t: Tick| {
+        if t < bar_one {
+            Tick(0)
+        } else {
+            bar_one + Tick((t - bar_one).0 / bar * bar)
+        }
+    };
+    let mut notes: Vec<(Event<Tick>, Event<Tick>)> = Vec::new();
+    let mut note_on: Option<Event<Tick>> = None;
+    for e in grid.events.iter() {
+        match (e.event_type.is_note_off(), note_on.take()) {
+            (false, _) => note_on = Some(*e),
+            (true, Some(mut on)) => {
+                let mut off = *e;
+                let mut shift = e.stroke.offset as i128 * ppqn as i128 / 32;
+                let position = on.tick.0 as i128 - bar_one.0 as i128;
+                if let Some((step, length)) = groove.and_then(|g| g.step_at(position, ppqn)) {
+                    shift += (step.timing * length as f64).round() as i128;
+                    let velocity = (on.stroke.velocity as i16 + step.velocity).clamp(1, 127) as u8;
+                    on.stroke.velocity = velocity;
+                    off.stroke.velocity = velocity;
+                }
+                let shifted = (on.tick.0 as i128 + shift).max(bar_start(on.tick).0 as i128) as u128;
+                off.tick = Tick((off.tick.0 + shifted).saturating_sub(on.tick.0));
+                on.tick = Tick(shifted);
+                notes.push((on, off));
             }
-            None => (EventGrid::empty(), 0),
+            (true, None) => {}
         }
+    }
+    notes.sort_by_key(|(on, _)| on.tick);
+    for i in 1..notes.len() {
+        let next = notes[i].0.tick;
+        let off = &mut notes[i - 1].1;
+        off.tick = off.tick.min(next);
+    }
+    EventGrid {
+        events: notes.into_iter().flat_map(|(on, off)| [on, off]).collect(),
+        ..grid
+    }
+}
+
+#[test]
+fn test_apply_offsets() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let note_ons = |pattern: &str, bar_one: Tick| -> Vec<Tick> {
+        let grid = groups_to_event_grid(Drum(SnareDrum), &groups(pattern).unwrap().1, TICKS_PER_QUARTER_NOTE);
+        let grid = apply_offsets(grid.shift(bar_one), None, four_fourth, bar_one, TICKS_PER_QUARTER_NOTE);
+        grid.events.iter().filter(|e| !e.event_type.is_note_off()).map(|e| e.tick).collect()
     };
+    // A 128th is one and a half ticks at the default resolution.
+    assert_eq!(note_ons("4-x[+2]-x[-2]", Tick(0)), vec![Tick(51), Tick(141)]);
+    // The downbeat can't be pushed into the bar before it.
+    assert_eq!(note_ons("4x[-4]--x", Tick(0)), vec![Tick(0), Tick(144)]);
+    assert_eq!(note_ons("4x[-4]x", Tick(24)), vec![Tick(24), Tick(72)]);
+    // A pushed hit cuts the one before it short.
+    let grid = groups_to_event_grid(Drum(SnareDrum), &groups("8xx[-4]").unwrap().1, TICKS_PER_QUARTER_NOTE);
+    let ticks: Vec<Tick> = apply_offsets(grid, None, four_fourth, Tick(0), TICKS_PER_QUARTER_NOTE).events.iter().map(|e| e.tick).collect();
+    assert_eq!(ticks, vec![Tick(0), Tick(18), Tick(18), Tick(42)]);
+}
+
+#[test]
+fn test_apply_groove() {
+    let two_fourth = TimeSignature::from_str("2/4").unwrap();
+    let swing = Groove::from_str("subdivision = 8\n0 0\n0.5 -27").unwrap();
+    let grid = groups_to_event_grid(Drum(HiHat), &groups("8xxx16xx").unwrap().1, TICKS_PER_QUARTER_NOTE);
+    let note_ons: Vec<(Tick, u8)> = apply_offsets(grid, Some(&swing), two_fourth, Tick(0), TICKS_PER_QUARTER_NOTE)
+        .events
+        .iter()
+        .filter(|e| !e.event_type.is_note_off())
+        .map(|e| (e.tick, e.stroke.velocity))
+        .collect();
+    // Sixteenths between the steps of the groove are left alone.
+    assert_eq!(note_ons, vec![(Tick(0), 127), (Tick(36), 100), (Tick(48), 127), (Tick(84), 100), (Tick(84), 127)]);
+}
 
-    let (kick_grid, kick_repeats) = to_event_grid(&KickDrum);
-    let (snare_grid, snare_repeats) = to_event_grid(&SnareDrum);
-    let (hihat_grid, hihat_repeats) = to_event_grid(&HiHat);
-    let (crash_grid, crash_repeats) = to_event_grid(&CrashCymbal);
+/// Length of a bar in ticks.
+pub fn bar_length(time_signature: TimeSignature, ppqn: u16) -> Tick {
+    Tick(time_signature.to_128th() as u128 * ppqn as u128 / 32)
+}
 
-    EventIterator::new(
-        concat_grid(kick_grid, Times(kick_repeats as u16)),
-        concat_grid(snare_grid, Times(snare_repeats as u16)),
-        concat_grid(hihat_grid, Times(hihat_repeats as u16)),
-        concat_grid(crash_grid, Times(crash_repeats as u16)),
-        time_signature,
-        converges_over_bars
-    )
+/// Scales the velocities of hits by the level of the dynamics they fall under.
+/// Bar 1 starts at `bar_one`, dynamics without bars span all `bars` of the file.
+fn apply_dynamics(events: &mut [Event<Tick>], dynamics: &[Dynamics], bars: u32, bar: Tick, bar_one: Tick) {
+    for d in dynamics {
+        let (first, last) = d.bars.unwrap_or((1, bars.max(1)));
+        let start = bar_one.0 + (first as u128 - 1) * bar.0;
+        let end = bar_one.0 + last as u128 * bar.0;
+        for e in events.iter_mut() {
+            let NoteOn(part) = e.event_type else { continue };
+            // The bass follows the dynamics of the kick drum.
+            let applies = match (d.part, part) {
+                (None, _) => true,
+                (Some(p), Drum(q)) => p == q,
+                (Some(p), Bass) => p == KickDrum,
+            };
+            if !applies || e.tick.0 < start || e.tick.0 >= end {
+                continue;
+            }
+            let level = d.level_at((e.tick.0 - start) as f64 / (end - start) as f64);
+            let precise = (e.stroke.precise_velocity() * level / 127.0).clamp(1.0, 127.0);
+            e.stroke.velocity = (e.stroke.velocity as f64 * level / 127.0).round().clamp(1.0, 127.0) as u8;
+            e.stroke.fine = ((precise - e.stroke.velocity as f64) * 128.0).round().clamp(-128.0, 127.0) as i8;
+        }
+    }
+}
+
+/// Adds the boosts of `accents` to the velocities of hits on the strong beats of the bars
+/// that start at `bar_one` and of hits every so many hits of a part into bar 1. Hits of the
+/// pickup aren't in a bar, so they get none.
+fn apply_accents(events: &mut [Event<Tick>], accents: &Accents, time_signature: TimeSignature, ppqn: u16, bar_one: Tick) {
+    if accents.is_empty() {
+        return;
+    }
+    let bar = bar_length(time_signature, ppqn).0.max(1);
+    let mut onsets: BTreeMap<Part, u32> = BTreeMap::new();
+    for e in events.iter_mut() {
+        let NoteOn(part) = e.event_type else { continue };
+        let Some(since) = e.tick.0.checked_sub(bar_one.0) else { continue };
+        let onset = onsets.entry(part).or_insert(0);
+        let mut boost = accents.boost_onset(part, *onset);
+        *onset += 1;
+        // Hits between 128th notes are on no beat.
+        let position = (since % bar) * 32;
+        if position.is_multiple_of(ppqn as u128) {
+            boost = boost.saturating_add(accents.boost((position / ppqn as u128) as u32, time_signature));
+        }
+        if boost > 0 {
+            e.stroke.velocity = e.stroke.velocity.saturating_add(boost).min(127);
+            if e.stroke.velocity == 127 {
+                e.stroke.fine = e.stroke.fine.min(0);
+            }
+        }
+    }
+}
+
+/// Adds the echoes of the hits of the parts that have them, each as long as its hit and
+/// quieter than the one before. Echoes that would start at `end` or later are dropped, the
+/// ones that ring past it are cut short.
+fn apply_echoes(events: &mut Vec<Event<Tick>>, echoes: &[Echo], ppqn: u16, end: Tick) {
+    if echoes.is_empty() {
+        return;
+    }
+    let mut added = Vec::new();
+    for (i, on) in events.iter().enumerate() {
+        let NoteOn(part) = on.event_type else { continue };
+        let Some(echo) = echo_of(echoes, part) else { continue };
+        let Some(off) = events[i + 1..].iter().find(|e| e.event_type == NoteOff(part)) else { continue };
+        let delay = echo.delay.to_ticks(ppqn).0;
+        for n in 1..=echo.repeats {
+            let tick = on.tick.0 + delay * n as u128;
+            let precise = echo.velocity(on.stroke.precise_velocity(), n);
+            if tick >= end.0 || precise == 0.0 {
+                break;
+            }
+            let velocity = precise.round().clamp(1.0, 127.0) as u8;
+            let stroke = Stroke {
+                velocity,
+                fine: ((precise - velocity as f64) * 128.0).round().clamp(-128.0, 127.0) as i8,
+                ..on.stroke
+            };
+            added.push(Event { tick: Tick(tick), event_type: NoteOn(part), stroke });
+            added.push(Event { tick: Tick((tick + off.tick.0 - on.tick.0).min(end.0)), event_type: NoteOff(part), stroke });
+        }
+    }
+    events.extend(added);
+    events.sort();
+}
+
+/// Moves the hits by the timing of `humanize` and varies their velocities. The note off of a
+/// hit moves along with it, so the hit is as long as it was. Hits aren't moved before the
+/// start of the file, and notes that would end after `end` are cut short.
+fn apply_humanize(events: &mut [Event<Tick>], humanize: &Humanize, tempo: &TempoMap, ppqn: u16, end: Tick) {
+    if humanize.is_empty() {
+        return;
+    }
+    let (mut timing, mut velocity) = humanize.generators();
+    let mut moved: BTreeMap<Part, i128> = BTreeMap::new();
+    for e in events.iter_mut() {
+        match e.event_type {
+            NoteOn(part) => {
+                let ticks = humanize.ticks(&mut timing, tempo.tempo_at(e.tick), ppqn).max(-(e.tick.0 as i128));
+                e.tick = Tick((e.tick.0 as i128 + ticks) as u128).min(end);
+                e.stroke.velocity = humanize.vary(&mut velocity, e.stroke.velocity);
+                moved.insert(part, ticks);
+            }
+            NoteOff(part) => {
+                if let Some(ticks) = moved.remove(&part) {
+                    e.tick = Tick((e.tick.0 as i128 + ticks).max(0) as u128).min(end);
+                }
+            }
+        }
+    }
+    events.sort();
+}
+
+#[test]
+fn test_apply_dynamics() {
+    let grid = groups_to_event_grid(Drum(HiHat), &groups("(8,4x)").unwrap().1, TICKS_PER_QUARTER_NOTE);
+    let mut events = grid.events;
+    let dynamics = [Dynamics::from_str("hihat=1-127@2-2").unwrap(), Dynamics::from_str("snare=ppp-ppp").unwrap()];
+    apply_dynamics(&mut events, &dynamics, 2, Tick(192), Tick(0));
+    let velocities: Vec<u8> = events.iter().filter(|e| !e.event_type.is_note_off()).map(|e| e.stroke.velocity).collect();
+    assert_eq!(velocities, vec![127, 127, 127, 127, 1, 33, 64, 96]);
+}
+
+#[test]
+fn test_apply_accents() {
+    let grid = groups_to_event_grid(Drum(HiHat), &groups("8xxxxxxxx").unwrap().1, TICKS_PER_QUARTER_NOTE);
+    let mut events = grid.events;
+    apply_dynamics(&mut events, &[Dynamics::from_str("64-64").unwrap()], 1, Tick(192), Tick(0));
+    let accents = Accents {
+        downbeats: 30,
+        strong_beats: true,
+        every: Vec::new(),
+    };
+    // The pickup is an eighth note long, the bar starts on the second hit.
+    apply_accents(&mut events, &accents, TimeSignature::from_str("4/4").unwrap(), TICKS_PER_QUARTER_NOTE, Tick(24));
+    let velocities: Vec<u8> = events.iter().filter(|e| !e.event_type.is_note_off()).map(|e| e.stroke.velocity).collect();
+    assert_eq!(velocities, vec![64, 94, 64, 64, 64, 79, 64, 64]);
+    let mut events = groups_to_event_grid(Drum(HiHat), &groups("16xxxxxxxx").unwrap().1, TICKS_PER_QUARTER_NOTE).events;
+    apply_dynamics(&mut events, &[Dynamics::from_str("64-64").unwrap()], 1, Tick(192), Tick(0));
+    let every = Accents {
+        every: vec![Every::from_str("hihat=3").unwrap()],
+        ..Default::default()
+    };
+    apply_accents(&mut events, &every, TimeSignature::from_str("4/4").unwrap(), TICKS_PER_QUARTER_NOTE, Tick(0));
+    let velocities: Vec<u8> = events.iter().filter(|e| !e.event_type.is_note_off()).map(|e| e.stroke.velocity).collect();
+    assert_eq!(velocities, vec![84, 64, 64, 84, 64, 64, 84, 64]);
+}
+
+#[test]
+fn test_apply_echoes() {
+    let mut events = groups_to_event_grid(Drum(SnareDrum), &groups("4-x-x").unwrap().1, TICKS_PER_QUARTER_NOTE).events;
+    apply_echoes(&mut events, &[Echo::from_str("snare=8.,2").unwrap()], TICKS_PER_QUARTER_NOTE, Tick(192));
+    let notes: Vec<(u128, bool, u8)> = events.iter().map(|e| (e.tick.0, e.event_type == NoteOn(Drum(SnareDrum)), e.stroke.velocity)).collect();
+    // Echoes a dotted eighth apart at half the velocity of the one before, cut at the end of the bar.
+    assert_eq!(
+        notes,
+        vec![
+            (48, true, 127),
+            (84, true, 64),
+            (96, false, 127),
+            (120, true, 32),
+            (132, false, 64),
+            (144, true, 127),
+            (168, false, 32),
+            (180, true, 64),
+            (192, false, 127),
+            (192, false, 64),
+        ]
+    );
+}
+
+#[test]
+fn test_apply_humanize() {
+    let grid = groups_to_event_grid(Drum(HiHat), &groups("16xxxxxxxxxxxxxxxx").unwrap().1, TICKS_PER_QUARTER_NOTE);
+    let tempo = TempoMap::constant(MidiTempo::from_tempo(120.0));
+    let mut events = grid.events.clone();
+    // 10 ms are 4 ticks at 120 BPM.
+    apply_humanize(&mut events, &Humanize { timing: 10.0, velocity: 0, seed: 1 }, &tempo, TICKS_PER_QUARTER_NOTE, Tick(192));
+    assert_ne!(events, grid.events);
+    for (on, off) in events.iter().filter(|e| !e.event_type.is_note_off()).zip(events.iter().filter(|e| e.event_type.is_note_off())) {
+        // Hits keep their length and their velocity, unless they start the bar or end it.
+        assert!(off.tick.0 - on.tick.0 == 12 || on.tick == Tick(0) || off.tick == Tick(192));
+        assert_eq!(on.stroke.velocity, 127);
+    }
+    assert!(events.iter().all(|e| e.tick <= Tick(192)));
+
+    let mut events = grid.events.clone();
+    apply_humanize(&mut events, &Humanize { timing: 0.0, velocity: 20, seed: 1 }, &tempo, TICKS_PER_QUARTER_NOTE, Tick(192));
+    let ticks = |events: &[Event<Tick>]| events.iter().map(|e| e.tick).collect::<Vec<_>>();
+    assert_eq!(ticks(&events), ticks(&grid.events));
+    assert!(events.iter().any(|e| e.event_type == NoteOn(Drum(HiHat)) && e.stroke.velocity < 127));
+}
+
+/// Length of the longest pickup in ticks.
+fn pickup_length(pickups: &BTreeMap<DrumPart, Groups>, ppqn: u16) -> Tick {
+    pickups
+        .iter()
+        .map(|(part, groups)| groups_to_event_grid(Drum(*part), groups, ppqn).end)
+        .max()
+        .unwrap_or(Tick(0))
+}
+
+/// Puts the `pickup` of a part right before bar 1 and the rest of it after.
+fn with_pickup(
+    part: Part,
+    pickup: Option<&Groups>,
+    body: EventGrid<Tick>,
+    bar_one: Tick,
+    ppqn: u16,
+) -> EventGrid<Tick> {
+    let mut grid = match pickup {
+        Some(pickup) => {
+            let pickup_grid = groups_to_event_grid(part, pickup, ppqn);
+            let start = bar_one - pickup_grid.end;
+            pickup_grid.shift(start)
+        }
+        None => EventGrid::empty(),
+    };
+    let body = body.shift(bar_one);
+    grid.events.extend(body.events);
+    grid.end = body.end;
+    grid
 }
 
 #[test]
@@ -762,164 +2168,209 @@ fn test_merge_into_iterator() {
         Event {
             tick: Tick(0),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(12),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(12),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(24),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(36),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(48),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(60),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(72),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(72),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(84),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(96),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(108),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(108),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(120),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(132),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(144),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(156),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(168),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(168),
             event_type: NoteOn(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(180),
             event_type: NoteOff(Drum(KickDrum)),
+            stroke: Stroke::default(),
         },
     ];
     let snare_events = vec![
         Event {
             tick: Tick(24),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(48),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(96),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(120),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(24 + 144),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(48 + 144),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(96 + 144),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(120 + 144),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(24 + 288),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(48 + 288),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(96 + 288),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(120 + 288),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(24 + 144 * 3),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(48 + 144 * 3),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(96 + 144 * 3),
             event_type: NoteOn(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
         Event {
             tick: Tick(120 + 144 * 3),
             event_type: NoteOff(Drum(SnareDrum)),
+            stroke: Stroke::default(),
         },
     ];
     let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let kick_and_snare = BTreeMap::from_iter([
+        (KickDrum, groups("16xx-x-xx-").unwrap().1),
+        (SnareDrum, groups("8-x--x-").unwrap().1),
+    ]);
     let flattened_kick_and_snare = merge_into_iterator(
-        &BTreeMap::from_iter([
-            (KickDrum, groups("16xx-x-xx-").unwrap().1),
-            (SnareDrum, groups("8-x--x-").unwrap().1),
-        ]),
+        &kick_and_snare,
+        &BTreeMap::new(),
+        &BTreeMap::new(),
         four_fourth,
+        converges_over_bars(&kick_and_snare, four_fourth),
+        TICKS_PER_QUARTER_NOTE,
     )
     .collect::<Vec<Event<Tick>>>();
 
     assert_eq!(
         merge_into_iterator(
             &BTreeMap::from_iter([(KickDrum, groups(kick_group).unwrap().1)]),
-            four_fourth
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            four_fourth,
+            converges_over_bars(&BTreeMap::from_iter([(KickDrum, groups(kick_group).unwrap().1)]), four_fourth),
+            TICKS_PER_QUARTER_NOTE
         )
         .collect::<Vec<Event<Tick>>>(),
         kick_events
@@ -927,116 +2378,491 @@ fn test_merge_into_iterator() {
     assert_eq!(
         merge_into_iterator(
             &BTreeMap::from_iter([(SnareDrum, groups(snare_group).unwrap().1)]),
-            four_fourth
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+            four_fourth,
+            converges_over_bars(&BTreeMap::from_iter([(SnareDrum, groups(snare_group).unwrap().1)]), four_fourth),
+            TICKS_PER_QUARTER_NOTE
         )
         .collect::<Vec<Event<Tick>>>(),
         snare_events
     );
-    assert_eq!(
+    assert!(
         kick_events
             .iter()
             .all(|x| flattened_kick_and_snare.contains(x))
             && snare_events
                 .iter()
-                .all(|x| flattened_kick_and_snare.contains(x)),
-        true
+                .all(|x| flattened_kick_and_snare.contains(x))
     );
 }
 
+#[test]
+fn test_ties() {
+    let note_offs = |grid: EventGrid<Tick>| -> Vec<Tick> {
+        grid.events.iter().filter(|e| e.event_type.is_note_off()).map(|e| e.tick).collect()
+    };
+    // A tie holds the hit of the group before it.
+    let grid = groups_to_event_grid(Drum(KickDrum), &groups("8x16_").unwrap().1, TICKS_PER_QUARTER_NOTE);
+    assert_eq!(note_offs(grid.clone()), vec![Tick(36)]);
+    assert_eq!(grid.end, Tick(36));
+    // Ties after a rest don't start a note.
+    assert_eq!(note_offs(groups_to_event_grid(Drum(KickDrum), &groups("4x-_").unwrap().1, TICKS_PER_QUARTER_NOTE)), vec![Tick(48)]);
+
+    // The hit at the end of a repeat rings into the tie the pattern starts with, the last one doesn't.
+    let events: Vec<Event<Tick>> = merge_into_iterator(
+        &BTreeMap::from_iter([(KickDrum, groups("4_-x").unwrap().1)]),
+        &BTreeMap::new(),
+        &BTreeMap::new(),
+        TimeSignature::from_str("4/4").unwrap(),
+        3,
+        TICKS_PER_QUARTER_NOTE,
+    )
+    .collect();
+    let note_offs: Vec<Tick> = events.iter().filter(|e| e.event_type.is_note_off()).map(|e| e.tick).collect();
+    assert_eq!(note_offs, vec![Tick(192), Tick(336), Tick(480), Tick(576)]);
+}
+
+#[test]
+fn test_roll_velocities() {
+    let grid = groups_to_event_grid(Drum(SnareDrum), &groups("4x*4<").unwrap().1, TICKS_PER_QUARTER_NOTE);
+    let note_ons: Vec<(Tick, u8)> = grid
+        .events
+        .iter()
+        .filter(|e| !e.event_type.is_note_off())
+        .map(|e| (e.tick, e.stroke.velocity))
+        .collect();
+    assert_eq!(note_ons, vec![(Tick(0), 31), (Tick(12), 63), (Tick(24), 95), (Tick(36), 127)]);
+}
+
+/// How `create_smf` writes the patterns, everything but the patterns themselves. The default
+/// is a file of 4/4 at 120 BPM and 48 ticks per quarter note with every part played as it is.
+#[derive(Debug, Clone)]
+pub struct SmfOptions<'a> {
+    /// Groove templates of the drum parts.
+    pub grooves: BTreeMap<DrumPart, Groove>,
+    /// Crescendos and decrescendos over the bars.
+    pub dynamics: Vec<Dynamics>,
+    /// Velocity boosts of the hits on strong beats.
+    pub accents: Accents,
+    /// Echoes of the hits of the parts.
+    pub echoes: Vec<Echo>,
+    /// How far hits stray from the grid and from their velocities.
+    pub humanize: Humanize,
+    pub mix: Vec<Mix>,
+    /// Velocity responses of the parts.
+    pub curves: Vec<Curve>,
+    /// How note offs are written.
+    pub note_offs: NoteOffs,
+    /// Whether velocities get high resolution velocity prefixes.
+    pub high_resolution_velocity: bool,
+    /// Parts left out of the tracks, the file is as long as it is with them.
+    pub muted: BTreeSet<Part>,
+    /// Names of the tracks.
+    pub names: &'a TrackNames,
+    pub profile: Profile,
+    /// What to do with notes of a part that overlap.
+    pub overlap: Overlap,
+    pub time_signature: TimeSignature,
+    /// Tempo of the file and where it changes.
+    pub tempo: TempoMap,
+    /// Whether the bass follows the kick drum.
+    pub add_bass: bool,
+    pub ppqn: u16,
+}
+
+impl Default for SmfOptions<'_> {
+    fn default() -> Self {
+        SmfOptions {
+            grooves: BTreeMap::new(),
+            dynamics: Vec::new(),
+            accents: Accents::default(),
+            echoes: Vec::new(),
+            humanize: Humanize::default(),
+            mix: Vec::new(),
+            curves: Vec::new(),
+            note_offs: NoteOffs::default(),
+            high_resolution_velocity: false,
+            muted: BTreeSet::new(),
+            names: &DEFAULT_TRACK_NAMES,
+            profile: Profile::default(),
+            overlap: Overlap::default(),
+            time_signature: TimeSignature {
+                numerator: 4,
+                denominator: BasicLength::Fourth,
+            },
+            tempo: TempoMap::constant(MidiTempo::from_tempo(120.0)),
+            add_bass: false,
+            ppqn: TICKS_PER_QUARTER_NOTE,
+        }
+    }
+}
+
 // The length of a beat is not standard, so in order to fully describe the length of a MIDI tick the MetaMessage::Tempo event should be present.
+/// The file of the bars the patterns converge over. It only depends on the arguments, it
+/// carries no timestamps or versions, so the same arguments always produce the same bytes.
 pub fn create_smf<'a>(
+    registry: &PartRegistry,
     groups: BTreeMap<DrumPart, Groups>,
-    time_signature: TimeSignature,
+    pickups: BTreeMap<DrumPart, Groups>,
     text: &'a str,
-    tempo: u16,
-    add_bass: bool
+    options: &SmfOptions<'a>,
 ) -> Smf<'a> {
-    let tracks = create_tracks(groups, time_signature, text, MidiTempo::from_tempo(tempo), add_bass);
-    // https://majicdesigns.github.io/MD_MIDIFile/page_timing.html
-    // says " If it is not specified the MIDI default is 48 ticks per quarter note."
-    // As it's required in `Header`, let's use the same value.
-    let metrical = midly::Timing::Metrical(TICKS_PER_QUARTER_NOTE.into());
-    Smf {
-        header: Header {
-            format: midly::Format::Parallel,
-            timing: metrical,
-        },
-        tracks: tracks,
+    let bars = converges_over_bars(&groups, options.time_signature);
+    create_smf_with_progress(registry, groups, pickups, bars, text, options, &mut |_| {})
+}
+
+/// `create_smf` that writes `bars` bars, as worked out by `convergence`, and calls `progress`
+/// as they are written, long files take a while.
+pub fn create_smf_with_progress<'a>(
+    registry: &PartRegistry,
+    groups: BTreeMap<DrumPart, Groups>,
+    pickups: BTreeMap<DrumPart, Groups>,
+    bars: u32,
+    text: &'a str,
+    options: &SmfOptions<'a>,
+    progress: &mut dyn FnMut(Progress),
+) -> Smf<'a> {
+    create_tracks(registry, groups, pickups, bars, text, options, progress).to_smf()
+}
+
+/// The tracks of a file with their notes yet to be mapped to MIDI events, see `create_tracks`.
+pub struct Tracks<'a, 'o> {
+    pub header: Header,
+    pub tracks: Vec<Track<'a, 'o>>,
+}
+
+impl<'a> Tracks<'a, '_> {
+    /// The file with the events of every track mapped to MIDI.
+    pub fn to_smf(&self) -> Smf<'a> {
+        Smf {
+            header: self.header,
+            tracks: self.tracks.iter().map(|track| track.events().collect()).collect(),
+        }
     }
 }
 
-/// Translates drum parts to a single MIDI track.
+/// A track of a file. Its notes are mapped to MIDI events as the track is read, so a file is
+/// written without a copy of its notes in MIDI, see `midi::stream`.
+pub struct Track<'a, 'o> {
+    registry: &'o PartRegistry,
+    options: &'o SmfOptions<'a>,
+    /// Program, names and mix at the start of the track.
+    preamble: Vec<TrackEvent<'a>>,
+    notes: EventGrid<Delta>,
+    /// Turn of the first note in the bass line.
+    first: i64,
+    /// Events put in at ticks of their own, sorted by them.
+    inserts: Vec<Insert<'a>>,
+    /// Where the track ends, if it's marked with the end of the track.
+    end: Option<Tick>,
+}
+
+/// An event put in at a tick of a track ahead of the notes at the same tick. The end of the
+/// track is worked out along with the notes and the events it `counts`, not with the others.
+#[derive(Debug, Clone, Copy)]
+struct Insert<'a> {
+    at: Tick,
+    kind: TrackEventKind<'a>,
+    counts: bool,
+}
+
+impl<'a> Track<'a, '_> {
+    /// Events of the track in `midly` format, a delta time apart.
+    pub fn events(&self) -> impl Iterator<Item = TrackEvent<'a>> + Clone + '_ {
+        let conventions = self.options.profile.conventions();
+        let notes = self
+            .notes
+            .events
+            .iter()
+            .scan((self.first, self.registry.bass_line().note(0)), move |(turn, bass_key), event| {
+                Some(self.map_note(event, turn, bass_key, &conventions))
+            })
+            .flatten();
+        Placed {
+            events: self.preamble.iter().copied().chain(notes),
+            inserts: &self.inserts,
+            end: self.end,
+            next: None,
+            time: 0,
+            body: 0,
+            counted: (0, 0),
+            ended: false,
+        }
+    }
+
+    /// MIDI events of a note, the bass plays the notes of its line in turn.
+    fn map_note(&self, event: &Event<Delta>, turn: &mut i64, bass_key: &mut u7, conventions: &Conventions) -> Vec<TrackEvent<'a>> {
+        let registry = self.registry;
+        let &SmfOptions {
+            ref curves,
+            ref note_offs,
+            high_resolution_velocity,
+            ..
+        } = self.options;
+        let bass_line = registry.bass_line();
+        let mut events = Vec::new();
+        let (part, custom) = match event.event_type {
+            NoteOn(part) | NoteOff(part) => (part, match part {
+                Drum(drum) => registry.get(drum),
+                Bass => None,
+            }),
+        };
+        let plain = custom.map_or(DEFAULT_VELOCITY, |c| c.velocity);
+        let key = match event.event_type {
+            NoteOn(Bass) => {
+                *bass_key = bass_line.note(turn.rem_euclid(bass_line.degrees.len() as i64) as usize);
+                *turn += 1;
+                *bass_key
+            }
+            NoteOff(Bass) => *bass_key,
+            // Pitched hits play their note rather than the key of the part.
+            _ => event.stroke.pitch.map(|p| u7::from(p.key(registry.key()))).unwrap_or_else(|| part.midi_key(event.stroke.articulation, registry)),
+        };
+        let channel = part.channel(registry, conventions);
+        let mut delta = u28::from(event.tick.0 as u32);
+        let midi_message = match event.event_type {
+            // The velocity with its fraction, the 7 bits below the ones of the note on go
+            // in a high resolution velocity prefix right before it.
+            NoteOn(_) if high_resolution_velocity => {
+                let precise = match (registry.velocity_of(part), custom) {
+                    (Some(v), _) => v.scale(event.stroke.precise_velocity(), plain),
+                    (None, Some(c)) => (event.stroke.precise_velocity() * c.velocity as f64 / 127.0).max(1.0),
+                    (None, None) => event.stroke.precise_velocity(),
+                };
+                let (vel, fine) = high_resolution(respond_precise(curves, part, precise));
+                if fine > 0 {
+                    events.push(TrackEvent {
+                        delta,
+                        kind: TrackEventKind::Midi {
+                            channel,
+                            message: MidiMessage::Controller {
+                                controller: 88.into(),
+                                value: fine.into(),
+                            },
+                        },
+                    });
+                    delta = 0.into();
+                }
+                MidiMessage::NoteOn { key, vel: vel.into() }
+            }
+            // A custom part plays plain hits at its own velocity, the rest are scaled to it,
+            // or to the velocities set for the part and kept in their range, then the curve
+            // of the part maps it.
+            NoteOn(_) => MidiMessage::NoteOn {
+                key,
+                vel: respond(
+                    curves,
+                    part,
+                    match (registry.velocity_of(part), custom) {
+                        (Some(v), _) => v.scale(event.stroke.velocity as f64, plain).round() as u8,
+                        (None, Some(c)) => (event.stroke.velocity as u32 * c.velocity as u32 / 127).max(1) as u8,
+                        (None, None) => event.stroke.velocity,
+                    },
+                )
+                .into(),
+            },
+            NoteOff(_) => note_offs.message(part, key),
+        };
+        events.push(TrackEvent {
+            delta,
+            kind: TrackEventKind::Midi {
+                channel,
+                message: midi_message,
+            },
+        });
+        // Layers of the part sound along with it, at its velocity scaled to theirs.
+        for layer in registry.layers().iter().filter(|l| Drum(l.part) == part) {
+            let message = match midi_message {
+                MidiMessage::NoteOn { vel, .. } if vel > 0 => MidiMessage::NoteOn {
+                    key: layer.key,
+                    vel: layer.scale(vel.as_int()).into(),
+                },
+                _ => note_offs.message(part, layer.key),
+            };
+            events.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Midi {
+                    channel: layer.channel.unwrap_or(channel),
+                    message,
+                },
+            });
+        }
+        events
+    }
+}
+
+/// Events of a track with its inserts put in ahead of the events at their ticks, the way
+/// `insert_at` does, followed by the end of the track.
+#[derive(Clone)]
+struct Placed<'i, 'a, I> {
+    events: I,
+    inserts: &'i [Insert<'a>],
+    end: Option<Tick>,
+    /// The next event and its tick, held while inserts go ahead of it.
+    next: Option<(u128, TrackEventKind<'a>)>,
+    /// Tick of the last event out.
+    time: u128,
+    /// Tick of the last event of `events`.
+    body: u128,
+    /// Tick and delta time of the last event the end of the track counts.
+    counted: (u128, u128),
+    ended: bool,
+}
+
+impl<'a, I: Iterator<Item = TrackEvent<'a>>> Iterator for Placed<'_, 'a, I> {
+    type Item = TrackEvent<'a>;
+
+    fn next(&mut self) -> Option<TrackEvent<'a>> {
+        if self.next.is_none() {
+            self.next = self.events.next().map(|event| {
+                self.body += event.delta.as_int() as u128;
+                (self.body, event.kind)
+            });
+        }
+        let (at, kind, counts) = match (self.inserts.first(), self.next) {
+            (Some(insert), Some((at, _))) if insert.at.0 <= at => {
+                self.inserts = &self.inserts[1..];
+                (insert.at.0, insert.kind, insert.counts)
+            }
+            (_, Some((at, kind))) => {
+                self.next = None;
+                (at, kind, true)
+            }
+            (Some(insert), None) => {
+                self.inserts = &self.inserts[1..];
+                (insert.at.0, insert.kind, insert.counts)
+            }
+            (None, None) => {
+                let end = self.end.filter(|_| !self.ended)?;
+                self.ended = true;
+                // The track lasts every bar even if it ends with rests, or has no notes at all.
+                let (last, delta) = self.counted;
+                let at = if end.0 > last { end.0 } else { last + delta };
+                (at, TrackEventKind::Meta(MetaMessage::EndOfTrack), false)
+            }
+        };
+        if counts {
+            self.counted = (at, at - self.counted.0);
+        }
+        let delta = at - self.time;
+        self.time = at;
+        Some(TrackEvent { delta: u28::from(delta as u32), kind })
+    }
+}
+
+/// Translates drum parts to MIDI tracks.
 ///
 /// /// # Arguments
 ///
 /// * `parts_and_groups` - Drum parts parsed from the command line.
-/// * `time_signature` - Time signature parsed from the command line.
+/// * `pickups` - Parts of the drum parts played before bar 1.
+/// * `bars` - Number of bars to write, see `convergence`.
 /// * `text_event` - Text message to be embedded into the MIDI file.
+/// * `options` - How the parts are played and written, see `SmfOptions`.
+/// * `progress` - Called as the bars are merged.
 ///
 /// # Returns
 ///
-/// Multi-track vectors of MIDI events in `midly` format.
+/// The tracks, their notes are mapped to `midly` events as they're read.
 ///
-fn create_tracks<'a>(
+#[tracing::instrument(name = "create_smf", skip_all, fields(parts = parts_and_groups.len(), time_signature = %options.time_signature, ppqn = options.ppqn))]
+pub fn create_tracks<'a, 'o>(
+    registry: &'o PartRegistry,
     parts_and_groups: BTreeMap<DrumPart, Groups>,
-    time_signature: TimeSignature,
+    pickups: BTreeMap<DrumPart, Groups>,
+    bars: u32,
     text_event: &'a str,
-    midi_tempo: MidiTempo,
-    add_bass: bool
-) -> Vec<Vec<midly::TrackEvent<'a>>> {
-    let events_iter = merge_into_iterator(&parts_and_groups, time_signature);
-    let bars = events_iter.bars.clone();
-    let events: Vec<Event<Tick>> = events_iter.collect();
-
-    let track_time = match events.last() {
-        Some(ev) => ev.tick,
-        None => {
-            panic!("Result has no midi notes")
-        }
+    options: &'o SmfOptions<'a>,
+    progress: &mut dyn FnMut(Progress),
+) -> Tracks<'a, 'o> {
+    let &SmfOptions {
+        ref grooves,
+        ref dynamics,
+        ref accents,
+        ref echoes,
+        ref humanize,
+        ref mix,
+        curves: _,
+        note_offs: _,
+        high_resolution_velocity: _,
+        ref muted,
+        names,
+        profile,
+        overlap,
+        time_signature,
+        ref tempo,
+        add_bass,
+        ppqn,
+    } = options;
+    let events_iter = merge_into_iterator(&parts_and_groups, &pickups, grooves, time_signature, bars, ppqn).mute(muted);
+    let pickup = events_iter.pickup;
+    let Tick(bar) = bar_length(time_signature, ppqn);
+    let total = bars + u32::from(pickup > Tick(0));
+    // Bars before the one of an event are done by the time it comes.
+    let bars_before = |tick: Tick| match tick.0.checked_sub(pickup.0) {
+        Some(t) => (t / bar.max(1)) as u32 + u32::from(pickup > Tick(0)),
+        None => 0,
     };
-    let event_grid_tick = EventGrid::new(events, track_time);
-    let event_grid = event_grid_tick.to_delta();
+    let mut done = 0;
+    let mut events: Vec<Event<Tick>> = Vec::new();
+    for event in events_iter {
+        let before = bars_before(event.tick).min(total);
+        if before > done {
+            done = before;
+            tracing::trace!(bars = done, total, "bars written");
+            progress(Progress { bars: done, total });
+        }
+        events.push(event);
+    }
+    tracing::debug!(events = events.len(), bars = total, "drum events merged");
+    if done < total {
+        progress(Progress { bars: total, total });
+    }
+    apply_dynamics(&mut events, dynamics, bars, bar_length(time_signature, ppqn), pickup);
+    apply_accents(&mut events, accents, time_signature, ppqn, pickup);
+    apply_echoes(&mut events, echoes, ppqn, pickup + bar_length(time_signature, ppqn) * bars as u128);
+    apply_humanize(&mut events, humanize, tempo, ppqn, pickup + bar_length(time_signature, ppqn) * bars as u128);
+
+    // Every drum part may be muted.
+    let track_time = events.last().map_or(Tick(0), |ev| ev.tick);
+    let event_grid = EventGrid::new(events, track_time).resolve_overlaps(overlap).to_delta();
+    let conventions = profile.conventions();
     let mut drums_track = Vec::new();
-
-    // This is likely to be specific to Guitar Pro. Tested with Guitar Pro 7.
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Midi {
-            channel: 9.into(),
-            message: MidiMessage::ProgramChange { program: 0.into() },
-        },
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::TrackName(b"Drumkit")),
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::InstrumentName(b"Drumkit")),
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::MidiChannel(10.into())),
-    });
-    drums_track.push(TrackEvent {
-        delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::MidiPort(10.into())),
-    });
+    if let Some((channel, program)) = conventions.drum_program {
+        drums_track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::ProgramChange { program },
+            },
+        });
+    }
+    drums_track.extend(track_names(names.drums.as_bytes(), &conventions));
+    if conventions.channel_prefix {
+        drums_track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::MidiChannel(conventions.drum_channel)),
+        });
+    }
+    if let Some(port) = conventions.port {
+        drums_track.push(TrackEvent {
+            delta: 0.into(),
+            kind: TrackEventKind::Meta(MetaMessage::MidiPort(port.into())),
+        });
+    }
+    drums_track.extend(mix_events(mix.iter().filter(|m| m.part != Some(Bass)), registry, &conventions));
 
     drums_track.push(TrackEvent {
         delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::Tempo(midi_tempo.0)),
+        kind: TrackEventKind::Meta(MetaMessage::Tempo(tempo.tempo_at(Tick(0)).0)),
     });
 
-    let (midi_time_signature_numerator, midi_time_signature_denominator) = time_signature.to_midi();
+    // The pickup gets a bar of its own, so bar lines fall in place after it.
+    let pickup_time_signature = time_signature.partial(pickup_128th(&pickups));
     drums_track.push(TrackEvent {
         delta: 0.into(),
-        kind: TrackEventKind::Meta(MetaMessage::TimeSignature(
-            midi_time_signature_numerator,
-            midi_time_signature_denominator,
-            MIDI_CLOCKS_PER_CLICK.clone(),
-            8,
-        )),
+        kind: time_signature_event(pickup_time_signature.unwrap_or(time_signature)),
     });
 
     drums_track.push(TrackEvent {
@@ -1044,60 +2870,603 @@ fn create_tracks<'a>(
         kind: TrackEventKind::Meta(MetaMessage::Text(text_event.as_bytes())),
     });
 
-    let map_notes = |grid: EventGrid<Delta>, track: &mut Vec<TrackEvent>| {
-        for event in grid.events {
-            let midi_message = match event.event_type {
-                NoteOn(part) => MidiMessage::NoteOn {
-                    key: part.to_midi_key(),
-                    vel: 127.into(),
-                },
-                NoteOff(part) => MidiMessage::NoteOff {
-                    key: part.to_midi_key(),
-                    vel: 127.into(),
-                },
-            };
-            track.push(TrackEvent {
-                delta: u28::from(event.tick.0 as u32),
-                kind: TrackEventKind::Midi {
-                    channel: u4::from(10),
-                    message: midi_message,
-                },
-            })
+    let end = pickup + Tick(bar * bars as u128);
+    // Tempo changes after the first one go where they fall, up to the end of the last bar,
+    // ahead of the bar line after the pickup.
+    let mut inserts: Vec<Insert> = tempo
+        .changes()
+        .iter()
+        .skip(1)
+        .filter(|(at, _)| *at < end)
+        .map(|(at, change)| Insert {
+            at: *at,
+            kind: TrackEventKind::Meta(MetaMessage::Tempo(change.0)),
+            counts: false,
+        })
+        .collect();
+    if pickup_time_signature.is_some() {
+        for kind in [time_signature_event(time_signature), TrackEventKind::Meta(MetaMessage::Marker(b"Bar 1"))] {
+            inserts.push(Insert { at: pickup, kind, counts: true });
         }
+    }
+    inserts.sort_by_key(|insert| (insert.at, insert.counts));
+
+    let drums_track = Track {
+        registry,
+        options,
+        preamble: drums_track,
+        notes: event_grid,
+        first: 0,
+        inserts,
+        end: Some(end),
+    };
+    let metrical = midly::Timing::Metrical(ppqn.into());
+    // https://majicdesigns.github.io/MD_MIDIFile/page_timing.html
+    // says " If it is not specified the MIDI default is 48 ticks per quarter note."
+    // That's what `TICKS_PER_QUARTER_NOTE` is, finer notes need a higher resolution.
+    let header = Header {
+        format: midly::Format::Parallel,
+        timing: metrical,
     };
-
-    map_notes(event_grid, &mut drums_track);
-
-    drums_track.push(TrackEvent {
-        delta: drums_track.last().unwrap().delta,
-        kind: TrackEventKind::Meta(MetaMessage::EndOfTrack),
-    });
 
     if add_bass {
         let mut bass_track = Vec::new();
         let empty_groups = Groups(Vec::new());
         let kick = parts_and_groups.get(&KickDrum).unwrap_or(&empty_groups);
-        let bass = groups_to_event_grid(Bass, kick);
-        // This is likely to be specific to Guitar Pro. Tested with Guitar Pro 7.
-        bass_track.push(TrackEvent {
-            delta: 0.into(),
-            kind: TrackEventKind::Midi {
-                channel: 0.into(),
-                message: MidiMessage::ProgramChange { program: 34.into() },
-            },
-        });
-        bass_track.push(TrackEvent {
-            delta: 0.into(),
-            kind: TrackEventKind::Meta(MetaMessage::TrackName(b"Bass")),
-        });
-        bass_track.push(TrackEvent {
+        let bass = groups_to_event_grid(Bass, kick, ppqn);
+        let bass_pickup = pickups.get(&KickDrum);
+        if let Some(program) = conventions.bass_program {
+            bass_track.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Midi {
+                    channel: conventions.bass_channel,
+                    message: MidiMessage::ProgramChange { program },
+                },
+            });
+        }
+        bass_track.extend(track_names(names.bass.as_bytes(), &conventions));
+        if conventions.channel_prefix {
+            bass_track.push(TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Meta(MetaMessage::MidiChannel(conventions.bass_channel)),
+            });
+        }
+        bass_track.extend(mix_events(mix.iter().filter(|m| m.part == Some(Bass)), registry, &conventions));
+        // Without a kick drum the bass has nothing to follow and stays silent. Like the kick
+        // drum, its last repeat is cut off at the end of the file.
+        let times = match kick.to_128th() {
+            0 => 0,
+            length => (bars * time_signature.to_128th()).div_ceil(length),
+        };
+        let bass = repeat_grid(bass, Times(times as u16), leading_tie(kick, ppqn)).cut(Tick(bar * bars as u128));
+        let bass = with_pickup(Bass, bass_pickup, bass, pickup, ppqn);
+        let mut bass = apply_offsets(bass, grooves.get(&KickDrum), time_signature, pickup, ppqn);
+        apply_dynamics(&mut bass.events, dynamics, bars, bar_length(time_signature, ppqn), pickup);
+        apply_accents(&mut bass.events, accents, time_signature, ppqn, pickup);
+        apply_echoes(&mut bass.events, echoes, ppqn, pickup + bar_length(time_signature, ppqn) * bars as u128);
+        if muted.contains(&Bass) {
+            bass.events.clear();
+        }
+        // The line starts over on bar 1, the pickup leads into it.
+        let pickup_hits = bass.events.iter().filter(|e| matches!(e.event_type, NoteOn(_)) && e.tick < pickup).count();
+        let bass_track = Track {
+            registry,
+            options,
+            preamble: bass_track,
+            notes: bass.resolve_overlaps(overlap).to_delta(),
+            first: -(pickup_hits as i64),
+            inserts: Vec::new(),
+            end: None,
+        };
+        Tracks {
+            header,
+            tracks: vec![drums_track, bass_track],
+        }
+    } else {
+        Tracks {
+            header,
+            tracks: vec![drums_track],
+        }
+    }
+}
+
+/// Name of a track, also given as the name of its instrument when the target expects it.
+fn track_names<'a>(name: &'a [u8], conventions: &Conventions) -> Vec<TrackEvent<'a>> {
+    let mut events = vec![TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Meta(MetaMessage::TrackName(name)),
+    }];
+    if conventions.instrument_names {
+        events.push(TrackEvent {
             delta: 0.into(),
-            kind: TrackEventKind::Meta(MetaMessage::InstrumentName(b"Bass")),
+            kind: TrackEventKind::Meta(MetaMessage::InstrumentName(name)),
         });
-        let times = bars * time_signature.to_128th() / kick.to_128th();
-        map_notes(concat_grid(bass, Times(times as u16)).to_delta(), &mut bass_track);
-        vec![drums_track, bass_track]
-    } else {
-        vec![drums_track]
     }
+    events
+}
+
+/// Volume (CC7) and pan (CC10) of the channels of the `mix`, the later settings of a channel
+/// win over the earlier ones.
+fn mix_events<'a, 'b>(
+    mix: impl Iterator<Item = &'b Mix>,
+    registry: &PartRegistry,
+    conventions: &Conventions,
+) -> Vec<TrackEvent<'a>> {
+    let mut channels: BTreeMap<u4, (Option<u8>, Option<i8>)> = BTreeMap::new();
+    for m in mix {
+        let channel = m.part.unwrap_or(Drum(KickDrum)).channel(registry, conventions);
+        let settings = channels.entry(channel).or_default();
+        settings.0 = m.volume.or(settings.0);
+        settings.1 = m.pan.or(settings.1);
+    }
+    let controller = |channel: u4, controller: u8, value: u8| TrackEvent {
+        delta: 0.into(),
+        kind: TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::Controller {
+                controller: controller.into(),
+                value: value.into(),
+            },
+        },
+    };
+    channels
+        .into_iter()
+        .flat_map(|(channel, (volume, pan))| {
+            volume
+                .map(|v| controller(channel, 7, v))
+                .into_iter()
+                .chain(pan.map(|p| controller(channel, 10, Mix::pan_value(p))))
+        })
+        .collect()
+}
+
+/// Length of the longest pickup in 128th notes.
+fn pickup_128th(pickups: &BTreeMap<DrumPart, Groups>) -> u32 {
+    pickups.values().map(|g| g.to_128th()).max().unwrap_or(0)
+}
+
+fn time_signature_event<'a>(time_signature: TimeSignature) -> TrackEventKind<'a> {
+    let (numerator, denominator) = time_signature.to_midi();
+    TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, MIDI_CLOCKS_PER_CLICK, 8))
+}
+
+/// Inserts events at an absolute `tick` of a track, ahead of the events already there at the
+/// same time. The rest of the track keeps its timing.
+pub fn insert_at<'a>(track: &mut Vec<TrackEvent<'a>>, tick: Tick, kinds: Vec<TrackEventKind<'a>>) {
+    let mut time = 0;
+    let mut index = track.len();
+    for (i, event) in track.iter().enumerate() {
+        let delta = event.delta.as_int() as u128;
+        if time + delta >= tick.0 {
+            index = i;
+            break;
+        }
+        time += delta;
+    }
+    let delta = tick.0 - time;
+    if let Some(next) = track.get_mut(index) {
+        next.delta = u28::from((next.delta.as_int() as u128 - delta) as u32);
+    }
+    for (i, kind) in kinds.into_iter().enumerate() {
+        let delta = if i == 0 { delta } else { 0 };
+        track.insert(index + i, TrackEvent { delta: u28::from(delta as u32), kind });
+    }
+}
+
+#[test]
+fn test_create_smf_with_pickup() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let smf = create_smf(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(KickDrum, groups("4x---").unwrap().1)]),
+        BTreeMap::from_iter([(SnareDrum, groups("8xx").unwrap().1)]),
+        "",
+        &SmfOptions { time_signature: four_fourth, add_bass: true, ..SmfOptions::default() },
+    );
+    let timeline = |track: &[TrackEvent]| {
+        let mut time = 0;
+        let mut out = Vec::new();
+        for event in track.iter() {
+            time += event.delta.as_int();
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::TimeSignature(n, d, _, _)) => out.push((time, format!("{}/{}", n, 1 << d))),
+                TrackEventKind::Meta(MetaMessage::Marker(m)) => out.push((time, String::from_utf8_lossy(m).to_string())),
+                TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => out.push((time, key.to_string())),
+                _ => {}
+            }
+        }
+        out
+    };
+    // Two eighths of a snare pickup in a 1/4 bar, then the kick on bar 1.
+    assert_eq!(
+        timeline(&smf.tracks[0]),
+        vec![
+            (0, "1/4".to_string()),
+            (0, "38".to_string()),
+            (24, "38".to_string()),
+            (48, "4/4".to_string()),
+            (48, "Bar 1".to_string()),
+            (48, "36".to_string()),
+        ]
+    );
+    // The bass follows the kick, which has no pickup.
+    assert_eq!(timeline(&smf.tracks[1]).first().map(|(time, _)| *time), Some(48));
+}
+
+#[test]
+fn test_snare_articulations() {
+    let smf = create_smf(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(SnareDrum, groups("8xsr-").unwrap().1)]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions { time_signature: TimeSignature::from_str("2/4").unwrap(), ..SmfOptions::default() },
+    );
+    let notes: Vec<(u8, u8)> = smf.tracks[0]
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { key, vel }, .. } => Some((key.as_int(), vel.as_int())),
+            _ => None,
+        })
+        .collect();
+    // A side stick is played softer than a hit or a rimshot by default.
+    assert_eq!(notes, vec![(38, 127), (37, 96), (40, 127)]);
+}
+
+#[test]
+fn test_latin_percussion() {
+    let smf = create_smf(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([
+            (Claves, groups("8x--x--x-").unwrap().1),
+            (CongaSlap, groups("4-x").unwrap().1),
+            (Cowbell, groups("4x-").unwrap().1),
+        ]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions::default(),
+    );
+    let keys: Vec<u8> = smf.tracks[0]
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+            _ => None,
+        })
+        .take(4)
+        .collect();
+    assert_eq!(keys, vec![56, 75, 62, 75]);
+    for part in DrumPart::ALL {
+        assert_eq!(DrumPart::from_str(part.name()), Ok(part));
+    }
+    assert_eq!(DrumPart::from_midi_key(u7::from(75)), Some(Claves));
+}
+
+#[test]
+fn test_custom_parts() {
+    let mut registry = PartRegistry::default();
+    let cajon = registry.declare("Cajon key=48 velocity=100".parse().unwrap()).unwrap();
+    let tom = registry.declare("\"Low Tom\" key=45 channel=2".parse().unwrap()).unwrap();
+    let smf = create_smf(
+        &registry,
+        BTreeMap::from_iter([(cajon, groups("4xs--").unwrap().1), (tom, groups("2-x").unwrap().1)]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions::default(),
+    );
+    let notes: Vec<(u8, u8, u8)> = smf.tracks[0]
+        .iter()
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } => {
+                Some((channel.as_int(), key.as_int(), vel.as_int()))
+            }
+            _ => None,
+        })
+        .collect();
+    // A side stick of a custom part is a softer hit on its key.
+    assert_eq!(notes, vec![(9, 48, 100), (9, 48, 75), (1, 45, 127)]);
+}
+
+#[test]
+fn test_mix_events() {
+    let mut registry = PartRegistry::default();
+    let tom = registry.declare("tom key=45 channel=2".parse().unwrap()).unwrap();
+    let mix: Vec<Mix> = ["100", "hihat=,-20", "tom=90,10", "bass=80"]
+        .iter()
+        .map(|m| Mix::parse(m, &registry).unwrap())
+        .collect();
+    let smf = create_smf(
+        &registry,
+        BTreeMap::from_iter([(KickDrum, groups("4x").unwrap().1), (tom, groups("4-x").unwrap().1)]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions { mix: mix.to_vec(), time_signature: TimeSignature::from_str("2/4").unwrap(), add_bass: true, ..SmfOptions::default() },
+    );
+    let controllers = |track: &[TrackEvent]| {
+        track
+            .iter()
+            .filter_map(|event| match event.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } } => {
+                    Some((channel.as_int(), controller.as_int(), value.as_int()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+    // The hi-hat shares the channel of the kit, so it pans the whole kit.
+    assert_eq!(controllers(&smf.tracks[0]), vec![(1, 7, 90), (1, 10, 74), (9, 7, 100), (9, 10, 44)]);
+    assert_eq!(controllers(&smf.tracks[1]), vec![(0, 7, 80)]);
+}
+
+#[test]
+fn test_profiles() {
+    let preamble = |profile: Profile| {
+        let smf = create_smf(
+            &PartRegistry::default(),
+            BTreeMap::from_iter([(KickDrum, groups("4x").unwrap().1)]),
+            BTreeMap::new(),
+            "",
+            &SmfOptions { profile, time_signature: TimeSignature::from_str("1/4").unwrap(), ..SmfOptions::default() },
+        );
+        smf.tracks[0]
+            .iter()
+            .map_while(|event| match event.kind {
+                TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { program } } => {
+                    Some(format!("program {} on {}", program, channel))
+                }
+                TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { .. } } => Some(format!("note on {}", channel)),
+                TrackEventKind::Meta(MetaMessage::TrackName(_)) => Some("track name".to_string()),
+                TrackEventKind::Meta(MetaMessage::InstrumentName(_)) => Some("instrument name".to_string()),
+                TrackEventKind::Meta(MetaMessage::MidiChannel(c)) => Some(format!("channel {}", c)),
+                TrackEventKind::Meta(MetaMessage::MidiPort(p)) => Some(format!("port {}", p)),
+                TrackEventKind::Meta(MetaMessage::EndOfTrack) => None,
+                _ => Some(String::new()),
+            })
+            .filter(|e| !e.is_empty())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        preamble(Profile::GuitarPro),
+        vec!["program 0 on 9", "track name", "instrument name", "channel 10", "port 10", "note on 10"]
+    );
+    assert_eq!(preamble(Profile::GenericGm), vec!["program 0 on 9", "track name", "note on 9"]);
+    assert_eq!(preamble(Profile::Ableton), vec!["track name", "note on 9"]);
+}
+
+#[test]
+fn test_create_smf_is_deterministic() {
+    let bytes = || {
+        let mut bytes = Vec::new();
+        create_smf(
+            &PartRegistry::default(),
+            BTreeMap::from_iter([
+                (KickDrum, groups("8x--x--").unwrap().1),
+                (HiHat, groups("16x*2x[+2]").unwrap().1),
+                (SnareDrum, groups("4-s").unwrap().1),
+            ]),
+            BTreeMap::from_iter([(SnareDrum, groups("8xx").unwrap().1)]),
+            "blueprint",
+            &SmfOptions { tempo: TempoMap::constant(MidiTempo::from_tempo(132.5)), add_bass: true, ..SmfOptions::default() },
+        )
+        .write_std(&mut bytes)
+        .unwrap();
+        bytes
+    };
+    assert_eq!(bytes(), bytes());
+}
+
+#[test]
+fn test_high_resolution_velocity() {
+    let smf = create_smf_with_progress(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(HiHat, groups("4xxxx").unwrap().1)]),
+        BTreeMap::new(),
+        1,
+        "",
+        &SmfOptions { dynamics: [Dynamics::from_str("1-127").unwrap()].to_vec(), high_resolution_velocity: true, ..SmfOptions::default() },
+        &mut |_| {},
+    );
+    let velocities: Vec<(u32, u8, bool)> = smf.tracks[0]
+        .iter()
+        .filter_map(|e| match e.kind {
+            TrackEventKind::Midi { message: MidiMessage::Controller { controller, value }, .. } if controller == 88 => Some((e.delta.as_int(), value.as_int(), false)),
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { vel, .. }, .. } => Some((e.delta.as_int(), vel.as_int(), true)),
+            _ => None,
+        })
+        .collect();
+    // Velocities of 32.5 and 95.5 halfway between the steps of 7 bits get a prefix of 64.
+    assert_eq!(velocities, vec![(0, 1, true), (0, 64, false), (0, 32, true), (0, 64, true), (0, 64, false), (0, 95, true)]);
+}
+
+#[test]
+fn test_tempo_changes() {
+    let mut tempo = TempoMap::constant(MidiTempo::from_tempo(120.0));
+    tempo.set(Tick(192), MidiTempo::from_tempo(60.0));
+    // Past the end of the track, so it's left out.
+    tempo.set(Tick(400), MidiTempo::from_tempo(90.0));
+    let smf = create_smf_with_progress(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(KickDrum, groups("4xxxx").unwrap().1)]),
+        BTreeMap::new(),
+        2,
+        "",
+        &SmfOptions { tempo: tempo.clone(), ..SmfOptions::default() },
+        &mut |_| {},
+    );
+    let mut time = 0;
+    let mut tempos = Vec::new();
+    for event in smf.tracks[0].iter() {
+        time += event.delta.as_int();
+        if let TrackEventKind::Meta(MetaMessage::Tempo(t)) = event.kind {
+            tempos.push((time, t.as_int()));
+        }
+    }
+    assert_eq!(tempos, vec![(0, 500_000), (192, 1_000_000)]);
+    assert_eq!(TempoMap::read(&smf).changes(), &tempo.changes()[..2]);
+}
+
+#[test]
+fn test_bass_line() {
+    let mut registry = PartRegistry::default();
+    let degrees = ["1", "5", "8"].iter().map(|d| Degree::from_str(d).unwrap()).collect();
+    registry.set_bass_line(BassLine::new(Key::default(), degrees));
+    let smf = create_smf(
+        &registry,
+        BTreeMap::from_iter([(KickDrum, groups("4xxxx").unwrap().1)]),
+        BTreeMap::from_iter([(KickDrum, groups("8x").unwrap().1)]),
+        "",
+        &SmfOptions { add_bass: true, ..SmfOptions::default() },
+    );
+    let keys: Vec<u8> = smf.tracks[1]
+        .iter()
+        .filter_map(|e| match e.kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { key, .. }, .. } => Some(key.as_int()),
+            _ => None,
+        })
+        .collect();
+    // The pickup leads into bar 1 with the last note of the line, which starts over on bar 1.
+    assert_eq!(keys, vec![40, 28, 35, 40, 28]);
+}
+
+#[test]
+fn test_silent_patterns() {
+    let rests = BTreeMap::from_iter([(KickDrum, groups("4----").unwrap().1), (SnareDrum, groups("2--").unwrap().1)]);
+    let hits = BTreeMap::from_iter([(KickDrum, groups("4x---").unwrap().1)]);
+    let none = BTreeSet::new();
+    assert!(is_silent(&rests, &BTreeMap::new(), &none, true, u32::MAX));
+    assert!(!is_silent(&rests, &hits, &none, false, u32::MAX));
+    assert!(is_silent(&hits, &BTreeMap::new(), &BTreeSet::from_iter([Drum(KickDrum)]), false, u32::MAX));
+    assert!(!is_silent(&hits, &BTreeMap::new(), &BTreeSet::from_iter([Drum(KickDrum)]), true, u32::MAX));
+    // Hits after the file is cut off aren't heard.
+    let late = BTreeMap::from_iter([(KickDrum, groups("1-x").unwrap().1)]);
+    assert!(is_silent(&late, &BTreeMap::new(), &none, false, 128));
+    assert!(!is_silent(&late, &BTreeMap::new(), &none, false, 129));
+    // A file of rests still lasts its bars, and the bass has no kick drum to follow.
+    let smf = create_smf(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(SnareDrum, groups("2--").unwrap().1)]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions { add_bass: true, ..SmfOptions::default() },
+    );
+    let length: u32 = smf.tracks[0].iter().map(|e| e.delta.as_int()).sum();
+    assert_eq!(length, TICKS_PER_QUARTER_NOTE as u32 * 4);
+    assert!(!smf.tracks[1].iter().any(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. })));
+}
+
+#[test]
+fn test_pitched_part() {
+    let mut registry = PartRegistry::default();
+    let marimba = registry.declare(CustomPart::from_str("\"Marimba\" key=60 channel=2").unwrap()).unwrap();
+    let smf = create_smf(
+        &registry,
+        BTreeMap::from_iter([(marimba, groups("8x[E4]x[G3][+2]-x").unwrap().1)]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions::default(),
+    );
+    let notes: Vec<(u8, u8, bool)> = smf.tracks[0]
+        .iter()
+        .filter_map(|e| match e.kind {
+            TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, .. } } => Some((channel.as_int(), key.as_int(), true)),
+            TrackEventKind::Midi { channel, message: MidiMessage::NoteOff { key, .. } } => Some((channel.as_int(), key.as_int(), false)),
+            _ => None,
+        })
+        .take(6)
+        .collect();
+    // Notes end on the keys they started on, plain hits play the key of the part.
+    assert_eq!(notes, vec![(1, 64, true), (1, 64, false), (1, 55, true), (1, 55, false), (1, 60, true), (1, 60, false)]);
+}
+
+#[test]
+fn test_layers() {
+    let mut registry = PartRegistry::default();
+    registry.layer(Layer::parse("kick key=35 channel=2 velocity=64", &registry).unwrap());
+    let smf = create_smf(
+        &registry,
+        BTreeMap::from_iter([(KickDrum, groups("4x").unwrap().1)]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions::default(),
+    );
+    let notes: Vec<(u32, u8, u8, Option<u8>)> = smf.tracks[0]
+        .iter()
+        .filter_map(|e| match e.kind {
+            TrackEventKind::Midi { channel, message: MidiMessage::NoteOn { key, vel } } => Some((e.delta.as_int(), channel.as_int(), key.as_int(), Some(vel.as_int()))),
+            TrackEventKind::Midi { channel, message: MidiMessage::NoteOff { key, .. } } => Some((e.delta.as_int(), channel.as_int(), key.as_int(), None)),
+            _ => None,
+        })
+        .take(4)
+        .collect();
+    // Every hit of the kick also plays its layer, on the second channel at half the velocity.
+    assert_eq!(
+        notes,
+        vec![(0, 9, 36, Some(127)), (0, 1, 35, Some(64)), (48, 9, 36, None), (0, 1, 35, None)]
+    );
+}
+
+#[test]
+fn test_bass_cut_off_with_the_kick_drum() {
+    // A kick drum of 3 bars cut off after 4 of them plays its first note again, so does the bass.
+    let smf = create_smf_with_progress(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(KickDrum, groups("1x--").unwrap().1)]),
+        BTreeMap::new(),
+        4,
+        "",
+        &SmfOptions { add_bass: true, ..SmfOptions::default() },
+        &mut |_| {},
+    );
+    let note_ons = |track: &[TrackEvent]| track.iter().filter(|e| matches!(e.kind, TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. })).count();
+    assert_eq!((note_ons(&smf.tracks[0]), note_ons(&smf.tracks[1])), (2, 2));
+}
+
+#[test]
+fn test_create_smf_progress() {
+    let mut reports = Vec::new();
+    create_smf_with_progress(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(KickDrum, groups("8x--x--").unwrap().1), (SnareDrum, groups("4-x").unwrap().1)]),
+        BTreeMap::from_iter([(HiHat, groups("8x").unwrap().1)]),
+        3,
+        "",
+        &SmfOptions::default(),
+        &mut |p| reports.push((p.bars, p.total)),
+    );
+    assert_eq!(reports, vec![(1, 4), (2, 4), (3, 4), (4, 4)]);
+}
+
+#[test]
+fn test_convergence() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    let parts = BTreeMap::from_iter([(KickDrum, groups("8x--x--").unwrap().1), (SnareDrum, groups("4-x").unwrap().1)]);
+    let limits = |max_bars, max_events, on_limit| Limits { max_bars, max_events, on_limit };
+    let converges = convergence(&parts, four_fourth, TICKS_PER_QUARTER_NOTE, &Limits::default()).unwrap();
+    assert_eq!(
+        converges,
+        Convergence {
+            converges: Some(3),
+            cycle: Some(3),
+            bars: 3,
+            events: 28,
+            on_limit: OnLimit::Truncate,
+        }
+    );
+    assert!(!converges.is_truncated());
+
+    let cut = convergence(&parts, four_fourth, TICKS_PER_QUARTER_NOTE, &limits(2, None, OnLimit::Truncate)).unwrap();
+    // The kick drum plays 2 whole repeats and the first 2 notes of the third one.
+    assert_eq!((cut.converges, cut.cycle, cut.bars, cut.events), (None, Some(3), 2, 20));
+    assert!(cut.is_truncated());
+    let cut = convergence(&parts, four_fourth, TICKS_PER_QUARTER_NOTE, &limits(1000, Some(20), OnLimit::Truncate)).unwrap();
+    assert_eq!((cut.converges, cut.bars, cut.events), (Some(3), 2, 20));
+
+    assert!(convergence(&parts, four_fourth, TICKS_PER_QUARTER_NOTE, &limits(2, None, OnLimit::Fail)).is_err());
+    assert!(convergence(&parts, four_fourth, TICKS_PER_QUARTER_NOTE, &limits(1000, Some(20), OnLimit::Fail)).is_err());
+    let empty = BTreeMap::from_iter([(KickDrum, groups("8x").unwrap().1), (SnareDrum, Groups(Vec::new()))]);
+    assert_eq!(
+        convergence(&empty, four_fourth, TICKS_PER_QUARTER_NOTE, &Limits::default()),
+        Err("The snare pattern has no length, it can't be repeated".to_string())
+    );
+    assert_eq!(OnLimit::from_str("fail"), Ok(OnLimit::Fail));
+    assert!(OnLimit::from_str("wrap").is_err());
 }
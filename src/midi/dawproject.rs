@@ -0,0 +1,254 @@
+use std::fmt::Write;
+
+use midly::{MetaMessage, Smf, Timing, TrackEventKind};
+
+use crate::dsl::dsl::KnownLength;
+use crate::midi::core::{MidiTempo, Tick};
+use crate::midi::events::played;
+use crate::midi::tempo::TempoMap;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::api::spec::{render, Spec};
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// A note of a clip, in quarter notes from the start of the clip.
+#[derive(Debug, Clone, PartialEq)]
+struct ClipNote {
+    time: f64,
+    duration: f64,
+    channel: u8,
+    key: u8,
+    velocity: u8,
+    release: u8,
+}
+
+/// The tracks of a file as a [DAWproject](https://github.com/bitwig/dawproject), the open
+/// project format of Bitwig Studio, Studio One and Cubase: a clip of the notes of every track,
+/// named after the track and looped over the bars of the file after the pickup, at the tempo
+/// and in the time signature of the file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DawProject {
+    pub title: String,
+    pub signature: TimeSignature,
+    pub tempo: TempoMap,
+    pub ppqn: u16,
+    /// Tick bar 1 starts on, after the pickup, which the loop of the clips starts on.
+    pub bar_one: Tick,
+    /// Length of the file in ticks, pickup included, which the loop of the clips ends on.
+    pub length: Tick,
+    /// Name and notes of every track with notes.
+    tracks: Vec<(String, Vec<ClipNote>)>,
+}
+
+impl DawProject {
+    pub fn new(title: &str, signature: TimeSignature, tempo: &TempoMap, ppqn: u16, bar_one: Tick, length: Tick) -> DawProject {
+        DawProject {
+            title: title.to_string(),
+            signature,
+            tempo: tempo.clone(),
+            ppqn,
+            bar_one,
+            length,
+            tracks: Vec::new(),
+        }
+    }
+
+    /// Adds the tracks of a file that play notes, before its timing is changed to SMPTE.
+    pub fn read(&mut self, smf: &Smf) -> Result<(), String> {
+        let ppqn = match smf.header.timing {
+            Timing::Metrical(ticks) => ticks.as_int() as f64,
+            Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing aren't supported".to_string()),
+        };
+        for track in smf.tracks.iter() {
+            let name = track.iter().find_map(|event| match event.kind {
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => Some(String::from_utf8_lossy(name).into_owned()),
+                _ => None,
+            });
+            let notes: Vec<ClipNote> = played(track)
+                .into_iter()
+                .map(|note| ClipNote {
+                    time: note.tick.0 as f64 / ppqn,
+                    duration: note.duration.0 as f64 / ppqn,
+                    channel: note.channel,
+                    key: note.key,
+                    velocity: note.velocity,
+                    release: note.release,
+                })
+                .collect();
+            if !notes.is_empty() {
+                let name = name.unwrap_or_else(|| format!("Track {}", self.tracks.len() + 1));
+                self.tracks.push((name, notes));
+            }
+        }
+        Ok(())
+    }
+
+    /// The `project.xml` of the project.
+    pub fn to_xml(&self) -> String {
+        let beats = |tick: Tick| tick.0 as f64 / self.ppqn.max(1) as f64;
+        let bpm = |tempo: &MidiTempo| (60_000_000.0 / tempo.microseconds() as f64 * 1000.0).round() / 1000.0;
+        let (start, length) = (beats(self.bar_one), beats(self.length));
+        let changes = self.tempo.changes();
+        let mut xml = String::new();
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n");
+        let _ = writeln!(xml, "<Project version=\"1.0\">\n  <Application name=\"poly\" version=\"{}\"/>", env!("CARGO_PKG_VERSION"));
+        xml.push_str("  <Transport>\n");
+        let _ = writeln!(xml, "    <Tempo id=\"tempo\" name=\"Tempo\" unit=\"bpm\" min=\"20\" max=\"999\" value=\"{}\"/>", bpm(&changes[0].1));
+        let _ = writeln!(
+            xml,
+            "    <TimeSignature id=\"signature\" numerator=\"{}\" denominator=\"{}\"/>",
+            self.signature.numerator,
+            128 / self.signature.denominator.to_128th()
+        );
+        xml.push_str("  </Transport>\n  <Structure>\n");
+        for (i, (name, _)) in self.tracks.iter().enumerate() {
+            let _ = writeln!(
+                xml,
+                "    <Track id=\"track{i}\" name=\"{}\" contentType=\"notes\" loaded=\"true\">\n      <Channel id=\"channel{i}\" role=\"regular\" audioChannels=\"2\" destination=\"master\"/>\n    </Track>",
+                escape(name)
+            );
+        }
+        xml.push_str("    <Channel id=\"master\" name=\"Master\" role=\"master\" audioChannels=\"2\"/>\n  </Structure>\n");
+        xml.push_str("  <Arrangement id=\"arrangement\">\n");
+        if changes.len() > 1 {
+            xml.push_str("    <TempoAutomation id=\"tempoAutomation\" unit=\"bpm\">\n      <Target parameter=\"tempo\"/>\n");
+            for (at, tempo) in changes.iter() {
+                let _ = writeln!(xml, "      <RealPoint time=\"{}\" value=\"{}\" interpolation=\"hold\"/>", beats(*at), bpm(tempo));
+            }
+            xml.push_str("    </TempoAutomation>\n");
+        }
+        xml.push_str("    <Lanes id=\"lanes\" timeUnit=\"beats\">\n");
+        for (i, (name, notes)) in self.tracks.iter().enumerate() {
+            let _ = writeln!(xml, "      <Lanes id=\"lanes{i}\" track=\"track{i}\">\n        <Clips id=\"clips{i}\">");
+            let _ = writeln!(
+                xml,
+                "          <Clip time=\"0\" duration=\"{length}\" playStart=\"0\" loopStart=\"{start}\" loopEnd=\"{length}\" name=\"{}\">\n            <Notes id=\"notes{i}\">",
+                escape(name)
+            );
+            for note in notes {
+                let _ = writeln!(
+                    xml,
+                    "              <Note time=\"{}\" duration=\"{}\" channel=\"{}\" key=\"{}\" vel=\"{:.4}\" rel=\"{:.4}\"/>",
+                    note.time,
+                    note.duration,
+                    note.channel,
+                    note.key,
+                    note.velocity as f64 / 127.0,
+                    note.release as f64 / 127.0
+                );
+            }
+            xml.push_str("            </Notes>\n          </Clip>\n        </Clips>\n      </Lanes>\n");
+        }
+        xml.push_str("    </Lanes>\n  </Arrangement>\n</Project>\n");
+        xml
+    }
+
+    /// The project as the bytes of a `.dawproject` file, a zip archive of its `project.xml`
+    /// and `metadata.xml`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let metadata = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<MetaData>\n  <Title>{}</Title>\n  <Comment>Written by poly</Comment>\n</MetaData>\n",
+            escape(&self.title)
+        );
+        zip(&[("metadata.xml", metadata.as_bytes()), ("project.xml", self.to_xml().as_bytes())])
+    }
+}
+
+/// A zip archive of `files` stored as they are, dated 1980-01-01 so that the same files give
+/// the same bytes.
+pub(crate) fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let (time, date) = (0u16, (1 << 5) | 1u16);
+    let mut archive = Vec::new();
+    let mut directory = Vec::new();
+    for (name, data) in files {
+        let offset = archive.len() as u32;
+        let crc = crc32(data);
+        let mut common = Vec::new();
+        common.extend(20u16.to_le_bytes()); // version needed to extract, 2.0
+        common.extend(0u16.to_le_bytes()); // flags
+        common.extend(0u16.to_le_bytes()); // stored, no compression
+        common.extend(time.to_le_bytes());
+        common.extend(date.to_le_bytes());
+        common.extend(crc.to_le_bytes());
+        common.extend((data.len() as u32).to_le_bytes());
+        common.extend((data.len() as u32).to_le_bytes());
+        common.extend((name.len() as u16).to_le_bytes());
+        common.extend(0u16.to_le_bytes()); // extra field length
+
+        archive.extend(0x04034b50u32.to_le_bytes());
+        archive.extend(&common);
+        archive.extend(name.as_bytes());
+        archive.extend(*data);
+
+        directory.extend(0x02014b50u32.to_le_bytes());
+        directory.extend(20u16.to_le_bytes()); // made by
+        directory.extend(&common);
+        directory.extend(0u16.to_le_bytes()); // comment length
+        directory.extend(0u16.to_le_bytes()); // disk number
+        directory.extend(0u16.to_le_bytes()); // internal attributes
+        directory.extend(0u32.to_le_bytes()); // external attributes
+        directory.extend(offset.to_le_bytes());
+        directory.extend(name.as_bytes());
+    }
+    let offset = archive.len() as u32;
+    let size = directory.len() as u32;
+    archive.extend(directory);
+    archive.extend(0x06054b50u32.to_le_bytes());
+    archive.extend(0u16.to_le_bytes()); // this disk
+    archive.extend(0u16.to_le_bytes()); // disk of the directory
+    archive.extend((files.len() as u16).to_le_bytes());
+    archive.extend((files.len() as u16).to_le_bytes());
+    archive.extend(size.to_le_bytes());
+    archive.extend(offset.to_le_bytes());
+    archive.extend(0u16.to_le_bytes()); // comment length
+    archive
+}
+
+/// CRC-32 of the zip format, of the polynomial 0xEDB88320.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+#[test]
+fn test_dawproject() {
+    assert_eq!(crc32(b"123456789"), 0xCBF43926);
+
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "4x-", "snare": "4-x"}, "bass": true, "tempo": 90}"#).unwrap();
+    let (smf, bars) = render(&spec, "").unwrap();
+    let ppqn = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int(),
+        Timing::Timecode(_, _) => unreachable!(),
+    };
+    let tempo = TempoMap::read(&smf);
+    let mut project = DawProject::new("Groove & Co", spec.time_signature, &tempo, ppqn, Tick(0), Tick(ppqn as u128 * 4 * bars as u128));
+    project.read(&smf).unwrap();
+    let xml = project.to_xml();
+    assert!(xml.contains("<Tempo id=\"tempo\" name=\"Tempo\" unit=\"bpm\" min=\"20\" max=\"999\" value=\"90\"/>"));
+    assert!(xml.contains("numerator=\"4\" denominator=\"4\""));
+    // The drums and the bass are tracks of their own, the clips loop over the bar.
+    assert_eq!(xml.matches("<Track ").count(), 2);
+    assert_eq!(xml.matches("loopEnd=\"4\"").count(), 2);
+    assert!(xml.contains("<Note time=\"0\" duration=\""));
+    assert!(xml.contains("key=\"36\"") && xml.contains("key=\"38\""));
+    assert!(!xml.contains("TempoAutomation"));
+
+    let bytes = project.to_bytes();
+    assert_eq!(&bytes[..4], b"PK\x03\x04");
+    assert!(bytes.windows(11).any(|w| w == b"project.xml"));
+    assert!(bytes.windows(20).any(|w| w == b"Groove &amp; Co</Tit"));
+    assert_eq!(&bytes[bytes.len() - 22..bytes.len() - 18], b"PK\x05\x06");
+    assert_eq!(project.to_bytes(), bytes);
+}
@@ -0,0 +1,140 @@
+use std::fmt::Write;
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEventKind};
+
+use crate::midi::core::{DrumPart, Tick};
+use crate::midi::registry::PartRegistry;
+use crate::midi::time::{SignatureMap, TimeSignature};
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use crate::midi::core::{create_smf, SmfOptions, TICKS_PER_QUARTER_NOTE};
+#[allow(unused_imports)]
+use crate::midi::profile::Profile;
+#[allow(unused_imports)]
+use std::collections::BTreeMap;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// A note event of a MIDI file with its absolute time.
+struct Row {
+    time: u64,
+    part: String,
+    key: u8,
+    on: bool,
+    velocity: u8,
+}
+
+/// Renders the notes of a MIDI file as a table of `bar:beat:tick`, part, key, note on or off
+/// and velocity, in the order they're played. Bars follow the time signatures of the file and
+/// a pickup is bar 0. Parts are named after the custom parts and the layers of the `registry`,
+/// or the General MIDI key they use,
+/// the notes of the track named `bass` are the bass.
+pub fn dump_events(smf: &Smf, registry: &PartRegistry, bass: &str) -> String {
+    let ppqn = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int(),
+        Timing::Timecode(_, _) => return "MIDI files with SMPTE timing aren't supported\n".to_string(),
+    };
+    // Time signatures as (time, time signature) and the time bar 1 starts at.
+    let mut signatures = Vec::new();
+    let mut bar_one = 0;
+    let mut rows = Vec::new();
+    for track in smf.tracks.iter() {
+        let mut time = 0;
+        let mut is_bass = false;
+        for event in track.iter() {
+            time += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+                    if let Ok(signature) = TimeSignature::from_midi(numerator, denominator) {
+                        signatures.push((Tick(time as u128), signature));
+                    }
+                }
+                TrackEventKind::Meta(MetaMessage::Marker(b"Bar 1")) => bar_one = time,
+                TrackEventKind::Meta(MetaMessage::TrackName(name)) => is_bass = name == bass.as_bytes(),
+                TrackEventKind::Midi { channel, message } => {
+                    let (key, on, velocity) = match message {
+                        MidiMessage::NoteOn { key, vel } => (key, vel > 0, vel.as_int()),
+                        MidiMessage::NoteOff { key, .. } => (key, false, 0),
+                        _ => continue,
+                    };
+                    // Parts of a channel of their own may play any note there.
+                    let custom = registry
+                        .custom()
+                        .iter()
+                        .find(|p| p.key == key && p.channel.map(|c| c == channel).unwrap_or(true))
+                        .or_else(|| registry.custom().iter().find(|p| p.channel == Some(channel)));
+                    let layer = registry
+                        .layers()
+                        .iter()
+                        .find(|l| l.key == key && l.channel.map(|c| c == channel).unwrap_or(true));
+                    let part = match (is_bass, custom, layer, DrumPart::from_midi_key(key)) {
+                        (true, _, _, _) => "bass".to_string(),
+                        (_, Some(custom), _, _) => custom.name.clone(),
+                        (_, _, Some(layer), _) => registry.name(layer.part),
+                        (_, _, _, Some(part)) => part.name().to_string(),
+                        _ => "?".to_string(),
+                    };
+                    rows.push(Row {
+                        time,
+                        part,
+                        key: key.as_int(),
+                        on,
+                        velocity: if on { velocity } else { 0 },
+                    });
+                }
+                _ => {}
+            }
+        }
+    }
+    let signatures = SignatureMap::from_changes(signatures);
+    rows.sort_by_key(|r| (r.time, r.on));
+
+    let position = |time: u64| signatures.position(Tick(time as u128), ppqn);
+    let first_bar = 1 - position(bar_one).bar;
+
+    let mut out = String::new();
+    let _ = writeln!(out, "{:<14} {:<12} {:>3}  {:<5} {:>8}", "bar:beat:tick", "part", "key", "event", "velocity");
+    for row in rows {
+        let at = position(row.time).add_bars(first_bar).to_string();
+        let (event, velocity) = if row.on {
+            ("on", row.velocity.to_string())
+        } else {
+            ("off", String::new())
+        };
+        let line = format!("{:<14} {:<12} {:>3}  {:<5} {:>8}", at, row.part, row.key, event, velocity);
+        let _ = writeln!(out, "{}", line.trim_end());
+    }
+    out
+}
+
+#[test]
+fn test_dump_events() {
+    let smf = create_smf(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(DrumPart::KickDrum, groups("2xx").unwrap().1), (DrumPart::SnareDrum, groups("2-s").unwrap().1)]),
+        BTreeMap::from_iter([(DrumPart::HiHat, groups("8x").unwrap().1)]),
+        "",
+        &SmfOptions { add_bass: true, ..SmfOptions::default() },
+    );
+    let dump = dump_events(&smf, &PartRegistry::default(), "Bass");
+    assert_eq!(
+        dump.lines().collect::<Vec<_>>(),
+        vec![
+            "bar:beat:tick  part         key  event velocity",
+            "0:1:0          hihat         46  on         127",
+            "1:1:0          hihat         46  off",
+            "1:1:0          kick          36  on         127",
+            "1:1:0          bass          28  on         127",
+            "1:3:0          kick          36  off",
+            "1:3:0          bass          28  off",
+            "1:3:0          kick          36  on         127",
+            "1:3:0          snare         37  on          96",
+            "1:3:0          bass          28  on         127",
+            "2:1:0          kick          36  off",
+            "2:1:0          snare         37  off",
+            "2:1:0          bass          28  off",
+        ]
+    );
+}
@@ -0,0 +1,118 @@
+use std::str::FromStr;
+
+use crate::midi::core::DrumPart;
+use crate::midi::registry::PartRegistry;
+
+/// A crescendo or a decrescendo: velocities of a part, or of every part, move from one
+/// level to another over a span of bars. Written `hihat=pp-ff@1-4`; the part and the bars
+/// are optional, without bars it spans every bar of the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dynamics {
+    pub part: Option<DrumPart>,
+    /// Level at the start of the span, as a MIDI velocity.
+    pub from: u8,
+    /// Level at the end of the span, as a MIDI velocity.
+    pub to: u8,
+    /// First and last bar of the span, counting from 1.
+    pub bars: Option<(u32, u32)>,
+}
+
+/// Velocity of a dynamic mark like `mf`, or of a plain number from 1 to 127.
+fn level(s: &str) -> Result<u8, String> {
+    match s {
+        "ppp" => Ok(16),
+        "pp" => Ok(32),
+        "p" => Ok(48),
+        "mp" => Ok(64),
+        "mf" => Ok(80),
+        "f" => Ok(96),
+        "ff" => Ok(112),
+        "fff" => Ok(127),
+        _ => match s.parse::<u8>() {
+            Ok(v) if (1..=127).contains(&v) => Ok(v),
+            _ => Err(format!(
+                "{} is not a dynamic level, expected one of ppp, pp, p, mp, mf, f, ff, fff or a velocity from 1 to 127",
+                s
+            )),
+        },
+    }
+}
+
+impl FromStr for Dynamics {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Dynamics::parse(s, &PartRegistry::default())
+    }
+}
+
+impl Dynamics {
+    /// Parses dynamics of any part of the `registry`, `from_str` only knows the built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, rest) = match s.split_once('=') {
+            Some((part, rest)) => (Some(registry.part(part)?), rest),
+            None => (None, s),
+        };
+        let (levels, bars) = match rest.split_once('@') {
+            Some((levels, bars)) => (levels, Some(bars)),
+            None => (rest, None),
+        };
+        let Some((from, to)) = levels.split_once('-') else {
+            return Err(format!("expected levels like pp-ff, got {}", levels));
+        };
+        let bars = match bars {
+            None => None,
+            Some(bars) => {
+                let span = bars
+                    .split_once('-')
+                    .and_then(|(first, last)| first.parse::<u32>().ok().zip(last.parse::<u32>().ok()))
+                    .filter(|(first, last)| *first >= 1 && first <= last);
+                match span {
+                    Some(span) => Some(span),
+                    None => return Err(format!("expected bars like 1-4, got {}", bars)),
+                }
+            }
+        };
+        Ok(Dynamics {
+            part,
+            from: level(from)?,
+            to: level(to)?,
+            bars,
+        })
+    }
+
+    /// Level at `position` within the span, as a share from 0 at its start to 1 at its end.
+    pub fn level_at(&self, position: f64) -> f64 {
+        self.from as f64 + (self.to as f64 - self.from as f64) * position
+    }
+}
+
+#[test]
+fn test_dynamics_from_str() {
+    assert_eq!(
+        Dynamics::from_str("hihat=pp-ff@1-4"),
+        Ok(Dynamics {
+            part: Some(DrumPart::HiHat),
+            from: 32,
+            to: 112,
+            bars: Some((1, 4)),
+        })
+    );
+    assert_eq!(
+        Dynamics::from_str("fff-40"),
+        Ok(Dynamics {
+            part: None,
+            from: 127,
+            to: 40,
+            bars: None,
+        })
+    );
+    assert!(Dynamics::from_str("pp").is_err());
+    assert!(Dynamics::from_str("pp-loud").is_err());
+    assert!(Dynamics::from_str("pp-ff@4-1").is_err());
+    assert_eq!(Dynamics::from_str("p-f@2-2").map(|d| d.bars), Ok(Some((2, 2))));
+    assert!(Dynamics::from_str("cajon=pp-ff").is_err());
+    let mut registry = PartRegistry::default();
+    let cajon = registry.declare("cajon key=48".parse().unwrap()).unwrap();
+    assert_eq!(Dynamics::parse("cajon=pp-ff", &registry).map(|d| d.part), Ok(Some(cajon)));
+}
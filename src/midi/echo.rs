@@ -0,0 +1,98 @@
+use std::str::FromStr;
+
+use crate::dsl::dsl::{KnownLength, Length};
+use crate::midi::core::Part;
+use crate::midi::registry::PartRegistry;
+
+#[allow(unused_imports)]
+use crate::midi::core::DrumPart;
+
+/// Echoes of the hits of a part, or of every part, written `snare=8.,3`: a delay as long as a
+/// note of the patterns, how many echoes follow a hit, and optionally the percentage of the
+/// velocity each echo keeps of the one before it, like `snare=8.,3,60`. Half by default. The
+/// bass is `bass=8,2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Echo {
+    pub part: Option<Part>,
+    pub delay: Length,
+    pub repeats: u8,
+    /// Percentage of the velocity kept by each echo, from 1 to 100.
+    pub feedback: u8,
+}
+
+impl Echo {
+    /// Parses the echo of any part of the `registry`, `from_str` only knows the built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, echo) = match s.split_once('=') {
+            Some(("bass", rest)) => (Some(Part::Bass), rest),
+            Some((part, rest)) => (Some(Part::Drum(registry.part(part.trim())?)), rest),
+            None => (None, s),
+        };
+        let fields: Vec<&str> = echo.split(',').map(str::trim).collect();
+        let (delay, repeats, feedback) = match fields[..] {
+            [delay, repeats] => (delay, repeats, None),
+            [delay, repeats, feedback] => (delay, repeats, Some(feedback)),
+            _ => return Err(format!("expected an echo like 8.,3 or snare=8.,3,60, got `{}`", s)),
+        };
+        let delay = Length::from_str(delay)?;
+        if delay.to_128th() == 0 {
+            return Err(format!("the delay of `{}` should be longer than nothing", s));
+        }
+        let repeats = match repeats.parse::<u8>() {
+            Ok(repeats) if repeats > 0 => repeats,
+            _ => return Err(format!("{} is not a number of echoes, expected a number from 1 to 255", repeats)),
+        };
+        let feedback = match feedback.map(str::parse::<u8>) {
+            None => 50,
+            Some(Ok(feedback)) if (1..=100).contains(&feedback) => feedback,
+            Some(_) => return Err(format!("the echoes of `{}` should keep from 1 to 100 percent of the velocity", s)),
+        };
+        Ok(Echo {
+            part,
+            delay,
+            repeats,
+            feedback,
+        })
+    }
+
+    /// Velocity of echo `n`, counted from 1, of a hit of `velocity`, 0 once it fades out.
+    pub fn velocity(&self, velocity: f64, n: u8) -> f64 {
+        let velocity = velocity * (self.feedback as f64 / 100.0).powi(n as i32);
+        if velocity < 1.0 {
+            0.0
+        } else {
+            velocity
+        }
+    }
+}
+
+impl FromStr for Echo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Echo::parse(s, &PartRegistry::default())
+    }
+}
+
+/// The echo of a part, the last one of the part or, if it has none, the last one of every part.
+pub fn echo_of(echoes: &[Echo], part: Part) -> Option<&Echo> {
+    echoes.iter().rev().find(|e| e.part == Some(part)).or_else(|| echoes.iter().rev().find(|e| e.part.is_none()))
+}
+
+#[test]
+fn test_echo_from_str() {
+    let echo = Echo::from_str("snare=8.,3").unwrap();
+    assert_eq!(echo.part, Some(Part::Drum(DrumPart::SnareDrum)));
+    assert_eq!(echo.delay.to_128th(), 24);
+    assert_eq!((echo.repeats, echo.feedback), (3, 50));
+    assert_eq!(echo.velocity(100.0, 2), 25.0);
+    assert_eq!(echo.velocity(3.0, 2), 0.0);
+    assert_eq!(Echo::from_str("8t,2,80").map(|e| (e.part, e.delay.to_128th(), e.feedback)), Ok((None, 10, 80)));
+    let echoes = [Echo::from_str("8,1").unwrap(), Echo::from_str("bass=4,2").unwrap()];
+    assert_eq!(echo_of(&echoes, Part::Bass).map(|e| e.repeats), Some(2));
+    assert_eq!(echo_of(&echoes, Part::Drum(DrumPart::HiHat)).map(|e| e.repeats), Some(1));
+    assert!(Echo::from_str("snare=8.").is_err());
+    assert!(Echo::from_str("snare=8.,0").is_err());
+    assert!(Echo::from_str("snare=8.,2,0").is_err());
+    assert!(Echo::from_str("snare=7,2").is_err());
+}
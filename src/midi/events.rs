@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::api::json::Json;
+use crate::midi::core::Tick;
+use crate::midi::keymap::KeyMap;
+use crate::midi::tempo::TempoMap;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::api::spec::{description, render, Spec};
+#[allow(unused_imports)]
+use crate::midi::core::{DrumPart, Part};
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Version of the JSON of an `EventList`, raised whenever a member changes its meaning or goes
+/// away. Members may be added without raising it.
+pub const EVENTS_VERSION: u32 = 1;
+
+/// A note of a track, from its note on to its note off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayedNote {
+    pub tick: Tick,
+    pub duration: Tick,
+    /// Channel counted from 0, the way MIDI messages count them.
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+    /// Velocity of the note off.
+    pub release: u8,
+}
+
+/// Notes of a track in the order they start, a note off ending the note of its channel and
+/// key that started first.
+pub fn played(track: &[TrackEvent]) -> Vec<PlayedNote> {
+    let mut notes = Vec::new();
+    let mut sounding: BTreeMap<(u8, u8), VecDeque<(u128, u8)>> = BTreeMap::new();
+    let mut time = 0;
+    for event in track.iter() {
+        time += event.delta.as_int() as u128;
+        let TrackEventKind::Midi { channel, message } = event.kind else {
+            continue;
+        };
+        let (key, on, velocity) = match message {
+            MidiMessage::NoteOn { key, vel } => (key.as_int(), vel > 0, vel.as_int()),
+            MidiMessage::NoteOff { key, vel } => (key.as_int(), false, vel.as_int()),
+            _ => continue,
+        };
+        let voice = sounding.entry((channel.as_int(), key)).or_default();
+        if on {
+            voice.push_back((time, velocity));
+        } else if let Some((start, velocity_on)) = voice.pop_front() {
+            notes.push(PlayedNote {
+                tick: Tick(start),
+                duration: Tick(time - start),
+                channel: channel.as_int(),
+                key,
+                velocity: velocity_on,
+                release: velocity,
+            });
+        }
+    }
+    notes.sort_by_key(|n| (n.tick, n.channel, n.key));
+    notes
+}
+
+/// Every note of a file with the part playing it, in absolute ticks rather than the deltas of
+/// MIDI, for sequencers in the browser like Tone.js that schedule notes themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventList {
+    pub ppqn: u16,
+    pub signature: Option<TimeSignature>,
+    pub tempo: Vec<(Tick, f64)>,
+    /// Tick bar 1 starts on, after the pickup, where a loop of the file starts.
+    pub bar_one: Tick,
+    /// Length of the file in ticks, pickup included.
+    pub length: Tick,
+    /// Notes with the name of their part, `None` if no part plays their key.
+    pub events: Vec<(PlayedNote, Option<String>)>,
+}
+
+impl EventList {
+    pub fn new(bar_one: Tick, length: Tick) -> EventList {
+        EventList {
+            ppqn: 0,
+            signature: None,
+            tempo: Vec::new(),
+            bar_one,
+            length,
+            events: Vec::new(),
+        }
+    }
+
+    /// Adds the notes of a file, named after the parts of `keys` that play them. The notes of
+    /// files of every part read one after another come together in the order they're played.
+    pub fn read(&mut self, smf: &Smf, keys: &KeyMap) -> Result<(), String> {
+        self.ppqn = match smf.header.timing {
+            Timing::Metrical(ticks) => ticks.as_int(),
+            Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing aren't supported".to_string()),
+        };
+        let tempo = TempoMap::read(smf);
+        // To a thousandth of a BPM, microseconds per quarter note turn 90 BPM into 89.99995.
+        let bpm = |microseconds: u32| (60_000_000_000.0 / microseconds as f64).round() / 1000.0;
+        self.tempo = tempo.changes().iter().map(|(at, t)| (*at, bpm(t.microseconds()))).collect();
+        for track in smf.tracks.iter() {
+            for event in track.iter() {
+                if let TrackEventKind::Meta(MetaMessage::TimeSignature(n, d, _, _)) = event.kind {
+                    self.signature = self.signature.or(TimeSignature::from_midi(n, d).ok());
+                }
+            }
+            for note in played(track) {
+                let part = keys.part_of(note.channel + 1, note.key).map(|p| p.to_string());
+                self.events.push((note, part));
+            }
+        }
+        self.events.sort_by(|(a, p), (b, q)| (a.tick, a.channel, a.key, p).cmp(&(b.tick, b.channel, b.key, q)));
+        Ok(())
+    }
+
+    /// The events as JSON, like
+    ///
+    /// ```json
+    /// {
+    ///   "format": "poly-events",
+    ///   "version": 1,
+    ///   "ppqn": 48,
+    ///   "timeSignature": "4/4",
+    ///   "tempo": [{ "tick": 0, "bpm": 120 }],
+    ///   "loopStart": 0,
+    ///   "length": 192,
+    ///   "events": [
+    ///     { "tick": 0, "part": "kick", "velocity": 127, "duration": 24, "channel": 10, "key": 36 }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// Channels count from 1, velocities go from 1 to 127.
+    pub fn to_json(&self) -> Json {
+        let number = |n: f64| Json::Number(n);
+        let object = |members: Vec<(&str, Json)>| Json::Object(members.into_iter().map(|(k, v)| (k.to_string(), v)).collect());
+        let tempo = self.tempo.iter().map(|(at, bpm)| object(vec![("tick", number(at.0 as f64)), ("bpm", number(*bpm))])).collect();
+        let events = self
+            .events
+            .iter()
+            .map(|(note, part)| {
+                object(vec![
+                    ("tick", number(note.tick.0 as f64)),
+                    ("part", part.clone().map_or(Json::Null, Json::String)),
+                    ("velocity", number(note.velocity as f64)),
+                    ("duration", number(note.duration.0 as f64)),
+                    ("channel", number(note.channel as f64 + 1.0)),
+                    ("key", number(note.key as f64)),
+                ])
+            })
+            .collect();
+        object(vec![
+            ("format", Json::String("poly-events".to_string())),
+            ("version", number(EVENTS_VERSION as f64)),
+            ("ppqn", number(self.ppqn as f64)),
+            ("timeSignature", self.signature.map_or(Json::Null, |s| Json::String(s.to_string()))),
+            ("tempo", Json::Array(tempo)),
+            ("loopStart", number(self.bar_one.0 as f64)),
+            ("length", number(self.length.0 as f64)),
+            ("events", Json::Array(events)),
+        ])
+    }
+}
+
+#[test]
+fn test_event_list() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "8x--x--", "snare": "4-s"}, "bass": true, "tempo": 90}"#).unwrap();
+    let text = description(&spec.patterns, &spec.registry);
+    let (smf, bars) = render(&spec, &text).unwrap();
+    let mut keys = KeyMap { ppqn: 48, ..KeyMap::default() };
+    let parts = [Part::Drum(DrumPart::KickDrum), Part::Drum(DrumPart::SnareDrum), Part::Bass];
+    keys.read(&smf, &parts, &spec.registry, spec.target);
+    let mut events = EventList::new(Tick(0), Tick(192 * bars as u128));
+    events.read(&smf, &keys).unwrap();
+    // Three bars of a kick every three eighths, a side stick on every other beat and a bass note on every kick.
+    assert_eq!(events.events.len(), 8 + 6 + 8);
+    assert_eq!((events.ppqn, events.length), (48, Tick(3 * 192)));
+    assert_eq!(events.tempo, vec![(Tick(0), 90.0)]);
+    let (first, part) = &events.events[0];
+    assert_eq!((first.tick, first.channel, first.key, part.as_deref()), (Tick(0), 0, 28, Some("bass")));
+    assert!(events.events.windows(2).all(|w| w[0].0.tick <= w[1].0.tick));
+    let json = events.to_json().to_string();
+    assert!(json.starts_with("{\"events\":[{\"channel\":1,\"duration\":"));
+    assert!(json.contains("{\"channel\":10,\"duration\":48,\"key\":37,\"part\":\"snare\",\"tick\":48,\"velocity\":96}"));
+    assert!(json.ends_with("\"format\":\"poly-events\",\"length\":576,\"loopStart\":0,\"ppqn\":48,\"tempo\":[{\"bpm\":90,\"tick\":0}],\"timeSignature\":\"4/4\",\"version\":1}"));
+
+    let notes = played(&smf.tracks[0]);
+    assert!(notes.iter().all(|n| n.duration > Tick(0)));
+}
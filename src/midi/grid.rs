@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dsl::dsl::{BasicLength, KnownLength, Length, ModdedLength};
+use crate::midi::core::DrumPart;
+use crate::midi::time::TimeSignature;
+
+/// Steps hits are snapped to: notes of a length, or tuplets of them, `tuplet` notes in the time
+/// of the power of two below. Written like `16`, `8t` for triplets, `16:5` for quintuplets or
+/// `16:7` for septuplets, five or seven of them to a quarter note.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Grid {
+    pub length: BasicLength,
+    /// Notes in the time of the power of two below, 1 for plain notes.
+    pub tuplet: u8,
+}
+
+impl From<BasicLength> for Grid {
+    fn from(length: BasicLength) -> Self {
+        Grid { length, tuplet: 1 }
+    }
+}
+
+impl Grid {
+    /// Notes the tuplet takes the time of.
+    pub fn normal(&self) -> u32 {
+        1 << (self.tuplet.max(1) as u32).ilog2()
+    }
+
+    /// A step is `numerator / denominator` ticks long.
+    fn ratio(&self, ppqn: u16) -> (i128, i128) {
+        (
+            ppqn as i128 * self.length.to_128th() as i128 * self.normal() as i128,
+            32 * self.tuplet.max(1) as i128,
+        )
+    }
+
+    /// Whether a step is at least a tick long at a resolution.
+    pub fn fits(&self, ppqn: u16) -> bool {
+        let (numerator, denominator) = self.ratio(ppqn);
+        numerator >= denominator
+    }
+
+    /// Tick of step `index`, the nearest one if steps don't fall on whole ticks.
+    pub fn tick(&self, index: i128, ppqn: u16) -> i128 {
+        let (numerator, denominator) = self.ratio(ppqn);
+        (2 * index * numerator + denominator).div_euclid(2 * denominator)
+    }
+
+    /// The step nearest to a tick.
+    pub fn nearest(&self, tick: i128, ppqn: u16) -> i128 {
+        let (numerator, denominator) = self.ratio(ppqn);
+        if numerator == 0 {
+            return 0;
+        }
+        (2 * tick * denominator + numerator).div_euclid(2 * numerator)
+    }
+
+    /// Length of a step in ticks.
+    pub fn step(&self, ppqn: u16) -> f64 {
+        let (numerator, denominator) = self.ratio(ppqn);
+        numerator as f64 / denominator as f64
+    }
+
+    /// Number of steps in a bar, if a bar is made of whole steps.
+    pub fn bar_steps(&self, time_signature: TimeSignature) -> Option<usize> {
+        let steps = time_signature.to_128th() * self.tuplet.max(1) as u32;
+        let step = self.length.to_128th() * self.normal();
+        steps.is_multiple_of(step).then_some((steps / step) as usize)
+    }
+
+    /// Length of the notes of a pattern on the grid, if patterns can have them.
+    pub fn to_length(&self) -> Option<Length> {
+        match self.tuplet {
+            1 => Some(Length::Simple(ModdedLength::Plain(self.length))),
+            3 => Some(Length::Triplet(ModdedLength::Plain(self.length))),
+            _ => None,
+        }
+    }
+
+    /// Length of a step written as a tuplet group, like `16:5:4`, for every tuplet.
+    pub fn step_length(&self) -> Length {
+        match self.tuplet {
+            1 | 3 => self.to_length().unwrap(),
+            tuplet => Length::Tuplet(ModdedLength::Plain(self.length), tuplet as u16, self.normal() as u16),
+        }
+    }
+}
+
+impl FromStr for Grid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (length, tuplet) = match (s.strip_suffix('t'), s.split_once(':')) {
+            (Some(length), _) => (length, Ok(3)),
+            (None, Some((length, tuplet))) => (length, tuplet.parse::<u8>()),
+            (None, None) => (s, Ok(1)),
+        };
+        let length = length
+            .parse::<u16>()
+            .map_err(|e| e.to_string())
+            .and_then(BasicLength::from_num)
+            .map_err(|_| format!("`{}` is not a grid, expected a note length like 16, 8t for triplets or 16:5 for quintuplets", s))?;
+        match tuplet {
+            Ok(tuplet) if tuplet == 1 || (tuplet <= 32 && !tuplet.is_power_of_two()) => Ok(Grid { length, tuplet }),
+            _ => Err(format!("`{}` is not a grid, tuplets are of 3, 5, 6, 7 or more notes up to 32 that aren't a power of two", s)),
+        }
+    }
+}
+
+impl fmt::Display for Grid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.tuplet {
+            1 => write!(f, "{}", self.length),
+            3 => write!(f, "{}t", self.length),
+            tuplet => write!(f, "{}:{}", self.length, tuplet),
+        }
+    }
+}
+
+/// A grid for every part and the ones of single parts, which win over it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grids {
+    pub every: Grid,
+    pub parts: BTreeMap<DrumPart, Grid>,
+}
+
+impl From<Grid> for Grids {
+    fn from(every: Grid) -> Self {
+        Grids {
+            every,
+            parts: BTreeMap::new(),
+        }
+    }
+}
+
+impl Grids {
+    /// Grid of a part, or of notes of no part.
+    pub fn of(&self, part: Option<DrumPart>) -> Grid {
+        part.and_then(|p| self.parts.get(&p)).copied().unwrap_or(self.every)
+    }
+}
+
+#[test]
+fn test_grid() {
+    let quintuplets = Grid::from_str("16:5").unwrap();
+    assert_eq!(quintuplets, Grid { length: BasicLength::Sixteenth, tuplet: 5 });
+    assert_eq!(Grid::from_str("8t"), Ok(Grid { length: BasicLength::Eighth, tuplet: 3 }));
+    assert_eq!(Grid::from_str("16"), Ok(Grid::from(BasicLength::Sixteenth)));
+    assert!(Grid::from_str("16:4").is_err());
+    assert!(Grid::from_str("12").is_err());
+    for grid in ["16", "8t", "16:5", "32:7"] {
+        assert_eq!(Grid::from_str(grid).unwrap().to_string(), grid);
+    }
+    // Five steps to a quarter note of 48 ticks fall between the ticks.
+    let ticks: Vec<i128> = (0..=5).map(|i| quintuplets.tick(i, 48)).collect();
+    assert_eq!(ticks, vec![0, 10, 19, 29, 38, 48]);
+    assert_eq!(quintuplets.nearest(21, 48), 2);
+    assert_eq!(quintuplets.nearest(-10, 48), -1);
+    let septuplets = Grid::from_str("16:7").unwrap();
+    assert_eq!(septuplets.bar_steps(TimeSignature::from_str("4/4").unwrap()), Some(28));
+    assert_eq!(quintuplets.bar_steps(TimeSignature::from_str("7/8").unwrap()), None);
+    assert_eq!(quintuplets.to_length(), None);
+    assert_eq!(quintuplets.step_length().to_string(), "16:5:4");
+    assert!(!Grid::from_str("128:7").unwrap().fits(48));
+}
@@ -0,0 +1,316 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::dsl::dsl::BasicLength;
+use crate::error::{Error, ParseError};
+use crate::midi::core::{bar_length, DrumPart};
+use crate::midi::grid::Grid;
+use crate::midi::import::ImportedDrums;
+use crate::midi::registry::PartRegistry;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use crate::midi::core::{create_smf, SmfOptions, Tick, TICKS_PER_QUARTER_NOTE};
+#[allow(unused_imports)]
+use crate::midi::import::import_drums;
+#[allow(unused_imports)]
+use crate::midi::profile::Profile;
+#[allow(unused_imports)]
+use std::collections::BTreeMap;
+
+/// An MPC-style groove template: how late or early and how loud hits are played on every
+/// step of a grid. Swing is a groove which drags every second step.
+///
+/// Templates are written one step per line, the timing as a share of a step and the
+/// velocity as a change to the velocity of the hit:
+///
+/// ```text
+/// # Swung sixteenths
+/// subdivision = 16
+/// 0     0
+/// 0.33  -20
+/// ```
+///
+/// The grid can be of tuplets, like `subdivision = 16:5` for quintuplet sixteenths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Groove {
+    /// Grid of the steps.
+    pub subdivision: Grid,
+    /// Steps of the template, they repeat from bar 1 on.
+    pub steps: Vec<GrooveStep>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GrooveStep {
+    /// Timing offset as a share of a step, positive drags hits behind the beat.
+    pub timing: f64,
+    /// Added to the velocity of hits on the step.
+    pub velocity: i16,
+}
+
+impl Groove {
+    /// Number of steps of `subdivision` in a bar, at least one. The last step of a grid that
+    /// doesn't make up a bar spills over into the next one.
+    fn bar_steps(time_signature: TimeSignature, subdivision: Grid, ppqn: u16) -> usize {
+        let spilling = || (bar_length(time_signature, ppqn).0 as f64 / subdivision.step(ppqn)).ceil() as usize;
+        subdivision.bar_steps(time_signature).unwrap_or_else(spilling).max(1)
+    }
+
+    /// Learns a bar long groove from the hits of a reference MIDI file, the hits of `part`
+    /// if it has any or every hit otherwise. Hits are snapped to the nearest step of the
+    /// `subdivision` grid and the way they miss it is averaged over every bar.
+    /// The loudest step keeps the velocity of a hit, the others get quieter.
+    pub fn extract(drums: &ImportedDrums, part: Option<DrumPart>, subdivision: Grid) -> Option<Groove> {
+        if !subdivision.fits(drums.ticks_per_quarter_note) {
+            return None;
+        }
+        let step = subdivision.step(drums.ticks_per_quarter_note);
+        let of_part: Vec<_> = drums.onsets.iter().filter(|o| Some(o.part) == part).collect();
+        let onsets = if of_part.is_empty() {
+            drums.onsets.iter().collect()
+        } else {
+            of_part
+        };
+        if onsets.is_empty() {
+            return None;
+        }
+        let time_signature = drums.time_signature.unwrap_or(TimeSignature::from_str("4/4").unwrap());
+        let len = Self::bar_steps(time_signature, subdivision, drums.ticks_per_quarter_note);
+        // Sums of timings and velocities of every step and the number of hits on it.
+        let mut sums = vec![(0.0, 0.0, 0); len];
+        for onset in onsets {
+            let position = onset.tick.0 as f64 / step;
+            let nearest = position.round();
+            let slot = &mut sums[nearest as usize % len];
+            slot.0 += position - nearest;
+            slot.1 += onset.velocity as f64;
+            slot.2 += 1;
+        }
+        let average = |sum: f64, count: u32| if count == 0 { None } else { Some(sum / count as f64) };
+        let loudest = sums
+            .iter()
+            .filter_map(|(_, velocity, count)| average(*velocity, *count))
+            .fold(0.0, f64::max);
+        let steps = sums
+            .iter()
+            .map(|(timing, velocity, count)| GrooveStep {
+                timing: average(*timing, *count).map(|t| (t * 100.0).round() / 100.0).unwrap_or(0.0),
+                velocity: average(*velocity, *count).map(|v| (v - loudest).round() as i16).unwrap_or(0),
+            })
+            .collect();
+        Some(Groove { subdivision, steps })
+    }
+
+    /// The step a hit at `position` ticks from bar 1 lands on, if it lands on the grid at all.
+    /// Positions before bar 1 belong to the pickup and count back from it.
+    pub fn step_at(&self, position: i128, ppqn: u16) -> Option<(GrooveStep, i128)> {
+        let grid = self.subdivision;
+        if !grid.fits(ppqn) || self.steps.is_empty() {
+            return None;
+        }
+        let nearest = grid.nearest(position, ppqn);
+        if grid.tick(nearest, ppqn) != position {
+            return None;
+        }
+        let index = nearest.rem_euclid(self.steps.len() as i128) as usize;
+        Some((self.steps[index], grid.tick(nearest + 1, ppqn) - position))
+    }
+}
+
+/// A drum machine swing of a part, or of every part, written `hihat=66` or `hihat=60:8` with the
+/// grid it swings, sixteenths if it's left out. The second step of every pair is played late, so
+/// it lands `percent` of the way through the pair: 50 is straight, 66 is close to triplets.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Swing {
+    pub part: Option<DrumPart>,
+    pub percent: f64,
+    pub subdivision: Grid,
+}
+
+impl Swing {
+    /// Swing from straight to a dotted feel, like the swing of a drum machine.
+    pub const RANGE: std::ops::RangeInclusive<f64> = 50.0..=75.0;
+
+    /// Parses the swing of any part of the `registry`, `from_str` only knows the built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, swing) = match s.split_once('=') {
+            Some((part, rest)) => (Some(registry.part(part.trim())?), rest),
+            None => (None, s),
+        };
+        let (percent, subdivision) = match swing.split_once(':') {
+            Some((percent, grid)) => (percent, Grid::from_str(grid)?),
+            None => (swing, Grid::from(BasicLength::Sixteenth)),
+        };
+        match percent.trim().parse::<f64>() {
+            Ok(percent) if Swing::RANGE.contains(&percent) => Ok(Swing { part, percent, subdivision }),
+            _ => Err(format!("expected a swing from 50 to 75 percent like 66 or hihat=60:8, got `{}`", s)),
+        }
+    }
+
+    /// The groove playing the swing: the first step of every pair on the grid, the second late.
+    pub fn to_groove(&self) -> Groove {
+        Groove {
+            subdivision: self.subdivision,
+            steps: vec![
+                GrooveStep { timing: 0.0, velocity: 0 },
+                GrooveStep { timing: self.percent / 50.0 - 1.0, velocity: 0 },
+            ],
+        }
+    }
+}
+
+impl FromStr for Swing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Swing::parse(s, &PartRegistry::default())
+    }
+}
+
+impl FromStr for Groove {
+    type Err = Error;
+
+    /// Reports every problem of the template at once.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut subdivision = None;
+        let mut steps = Vec::new();
+        let mut errors = Vec::new();
+        let mut line_start = 0;
+        for raw in s.split('\n') {
+            let offset = line_start;
+            line_start += raw.len() + 1;
+            let content = raw.split('#').next().unwrap_or("");
+            let start = offset + content.len() - content.trim_start().len();
+            let line = content.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                match (key.trim(), Grid::from_str(value)) {
+                    ("subdivision", Ok(grid)) => subdivision = Some(grid),
+                    ("subdivision", Err(e)) => errors.push(ParseError::at(s, start, e)),
+                    (key, _) => errors.push(ParseError::at(s, start, format!("unknown setting `{}`", key))),
+                }
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let step = match fields[..] {
+                [timing, velocity] => timing
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|t| t.is_finite())
+                    .zip(velocity.parse::<i16>().ok())
+                    .map(|(timing, velocity)| GrooveStep { timing, velocity }),
+                _ => None,
+            };
+            match step {
+                Some(step) => steps.push(step),
+                None => errors.push(ParseError::at(
+                    s,
+                    start,
+                    format!("expected `timing velocity` of a step, like `0.33 -20`, got `{}`", line),
+                )),
+            }
+        }
+        if subdivision.is_none() && errors.is_empty() {
+            errors.push(ParseError::at(s, 0, "expected the length of a step, like `subdivision = 16`".to_string()));
+        }
+        if steps.is_empty() && errors.is_empty() {
+            errors.push(ParseError::at(s, s.len(), "expected at least one step, like `0.33 -20`".to_string()));
+        }
+        match subdivision {
+            Some(subdivision) if errors.is_empty() => Ok(Groove { subdivision, steps }),
+            _ => Err(Error::Parse(errors)),
+        }
+    }
+}
+
+impl fmt::Display for Groove {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "subdivision = {}", self.subdivision)?;
+        for step in self.steps.iter() {
+            writeln!(f, "{} {}", step.timing, step.velocity)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_groove_from_str() {
+    let groove = Groove::from_str("# Swing\nsubdivision = 8\n0 0\n0.33 -20 # late and soft\n").unwrap();
+    assert_eq!(groove.subdivision, Grid::from(BasicLength::Eighth));
+    assert_eq!(
+        groove.steps,
+        vec![GrooveStep { timing: 0.0, velocity: 0 }, GrooveStep { timing: 0.33, velocity: -20 }]
+    );
+    assert_eq!(Groove::from_str(&groove.to_string()), Ok(groove));
+    match Groove::from_str("subdivision = 12\n0 0\nlate -20") {
+        Err(Error::Parse(errors)) => assert_eq!(errors.iter().map(|e| e.line).collect::<Vec<_>>(), vec![1, 3]),
+        other => panic!("expected parse errors, got {:?}", other),
+    }
+    assert!(Groove::from_str("0 0").is_err());
+}
+
+#[test]
+fn test_groove_step_at() {
+    let groove = Groove::from_str("subdivision = 8\n0 0\n0.5 -20").unwrap();
+    assert_eq!(groove.step_at(24, 48), Some((groove.steps[1], 24)));
+    assert_eq!(groove.step_at(48, 48), Some((groove.steps[0], 24)));
+    assert_eq!(groove.step_at(-24, 48), Some((groove.steps[1], 24)));
+    assert_eq!(groove.step_at(12, 48), None);
+    // Quintuplet sixteenths fall on the nearest ticks, 9.6 apart.
+    let groove = Groove::from_str("subdivision = 16:5\n0 0\n0.2 -10").unwrap();
+    assert_eq!(groove.step_at(19, 48), Some((groove.steps[0], 10)));
+    assert_eq!(groove.step_at(10, 48), Some((groove.steps[1], 9)));
+    assert_eq!(groove.step_at(12, 48), None);
+}
+
+#[test]
+fn test_extract_groove() {
+    // Hits of a pattern that's played straight and loud land right on the grid.
+    let smf = create_smf(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([(DrumPart::HiHat, groups("8x").unwrap().1)]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions { time_signature: TimeSignature::from_str("2/4").unwrap(), ..SmfOptions::default() },
+    );
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes).unwrap();
+    let drums = import_drums(&bytes).unwrap();
+    let groove = Groove::extract(&drums, Some(DrumPart::HiHat), Grid::from(BasicLength::Sixteenth)).unwrap();
+    assert_eq!(groove.steps.len(), 8);
+    assert!(groove.steps.iter().all(|s| *s == GrooveStep { timing: 0.0, velocity: 0 }));
+
+    let mut swung = drums.clone();
+    for onset in swung.onsets.iter_mut().skip(1).step_by(2) {
+        onset.tick = onset.tick + Tick(8);
+        onset.velocity = 97;
+    }
+    let groove = Groove::extract(&swung, None, Grid::from(BasicLength::Eighth)).unwrap();
+    assert_eq!(
+        groove.steps,
+        vec![GrooveStep { timing: 0.0, velocity: 0 }, GrooveStep { timing: 0.33, velocity: -30 }, GrooveStep { timing: 0.0, velocity: 0 }, GrooveStep { timing: 0.33, velocity: -30 }]
+    );
+}
+
+#[test]
+fn test_swing() {
+    let swing = Swing::from_str("hihat=66").unwrap();
+    assert_eq!(swing.part, Some(DrumPart::HiHat));
+    assert_eq!(swing.subdivision, Grid::from(BasicLength::Sixteenth));
+    let groove = swing.to_groove();
+    assert_eq!(groove.steps.len(), 2);
+    assert!((groove.steps[1].timing - 0.32).abs() < 1e-9);
+    // Straight swing leaves every hit where it is.
+    let straight = Swing::from_str("50:8").unwrap();
+    assert_eq!((straight.part, straight.subdivision), (None, Grid::from(BasicLength::Eighth)));
+    assert!(straight.to_groove().steps.iter().all(|s| *s == GrooveStep { timing: 0.0, velocity: 0 }));
+    // The second eighth of a beat swung by 75 is half a step late, on its last sixteenth.
+    assert_eq!(Swing::from_str("75:8").unwrap().to_groove().step_at(24, 48), Some((GrooveStep { timing: 0.5, velocity: 0 }, 24)));
+    assert!(Swing::from_str("80").is_err());
+    assert!(Swing::from_str("cymbal=60").is_err());
+    assert!(Swing::from_str("60:12").is_err());
+}
@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::dsl::dsl::{Articulation, Groups, KnownLength};
+use crate::midi::core::{DrumPart, Part};
+use crate::midi::dawproject::zip;
+use crate::midi::musicxml::{articulation_name, escape, layout, position, Layout, Voice, Written, GHOST_VELOCITY};
+use crate::midi::registry::PartRegistry;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Voices a staff of Guitar Pro has room for, the parts after them go on more drum tracks.
+pub const VOICES_PER_TRACK: usize = 4;
+
+/// A Guitar Pro 7 file of the drums, which Guitar Pro 7 and 8 open as it is: the parts are
+/// voices of a drum kit track, with the same measures, triplets, ties and instruments of the
+/// kit as `musicxml::score` writes. Parts past the fourth go on drum tracks of their own, soft
+/// hits are ghost notes and the velocity of a hit is the dynamic of its beat. `tempos` are the
+/// BPM from bars on, counted from 1.
+pub fn archive(
+    parts: &BTreeMap<DrumPart, Groups>,
+    registry: &PartRegistry,
+    signature: TimeSignature,
+    tempos: &[(u32, f64)],
+    bars: u32,
+    title: &str,
+) -> Result<Vec<u8>, String> {
+    let layout = layout(parts, registry, signature, bars)?;
+    let mut tracks: Vec<&[Voice]> = layout.voices.chunks(VOICES_PER_TRACK).collect();
+    if tracks.is_empty() {
+        tracks.push(&[]);
+    }
+    let gpif = gpif(&layout, &tracks, registry, signature, tempos, bars, title);
+    Ok(zip(&[
+        ("VERSION", b"7.0"),
+        ("Content/PartConfiguration", &part_configuration(tracks.len())),
+        ("Content/score.gpif", gpif.as_bytes()),
+    ]))
+}
+
+/// The score of a Guitar Pro file. Its bars, voices, beats, notes and rhythms are lists of
+/// their own that refer to each other by id, the way Guitar Pro writes them.
+fn gpif(
+    layout: &Layout,
+    tracks: &[&[Voice]],
+    registry: &PartRegistry,
+    signature: TimeSignature,
+    tempos: &[(u32, f64)],
+    bars: u32,
+    title: &str,
+) -> String {
+    let mut numbered: Vec<_> = layout.instruments.iter().collect();
+    numbered.sort_by_key(|(_, (n, _))| *n);
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<GPIF version=\"7\">\n");
+    let _ = writeln!(xml, "<Score><Title>{}</Title><Instructions>Written by poly</Instructions></Score>", escape(title));
+    let ids: Vec<String> = (0..tracks.len()).map(|t| t.to_string()).collect();
+    let _ = writeln!(xml, "<MasterTrack><Tracks>{}</Tracks><Automations>", ids.join(" "));
+    for (bar, bpm) in tempos {
+        let _ = writeln!(
+            xml,
+            "<Automation><Type>Tempo</Type><Linear>false</Linear><Bar>{}</Bar><Position>0</Position><Visible>true</Visible><Value>{} 2</Value></Automation>",
+            bar.saturating_sub(1),
+            (bpm * 100.0).round() / 100.0
+        );
+    }
+    xml.push_str("</Automations></MasterTrack>\n<Tracks>\n");
+    for (t, voices) in tracks.iter().enumerate() {
+        let name = if t == 0 { "Drums".to_string() } else { format!("Drums {}", t + 1) };
+        // A track plays on one channel, the one of its first part.
+        let channel = voices.first().and_then(|(part, _)| registry.get(*part)).and_then(|p| p.channel).map_or(9, |c| c.as_int());
+        let _ = writeln!(xml, "<Track id=\"{}\"><Name>{}</Name><ShortName>drm.</ShortName>", t, name);
+        xml.push_str("<InstrumentSet><Name>Drums</Name><Type>drumKit</Type><LineCount>5</LineCount><Elements>\n");
+        for ((part, key), (_, articulation)) in numbered.iter() {
+            let name = match articulation_name(*part, *articulation) {
+                Some(sound) => format!("{} ({})", registry.title(*part), sound),
+                None => registry.title(*part),
+            };
+            let (step, octave, notehead) = position(*part, *articulation);
+            let _ = writeln!(
+                xml,
+                "<Element><Name>{}</Name><Type>{}</Type><Articulations><Articulation><Name>{}</Name><StaffLine>{}</StaffLine><Noteheads>{}</Noteheads><TechniquePlacement>outside</TechniquePlacement><TechniqueSymbol></TechniqueSymbol><InputMidiNumbers>{}</InputMidiNumbers><OutputRSESound></OutputRSESound><OutputMidiNumber>{}</OutputMidiNumber></Articulation></Articulations></Element>",
+                escape(&registry.title(*part)),
+                element_type(*part),
+                escape(&name),
+                staff_line(step, octave),
+                noteheads(notehead),
+                key,
+                key
+            );
+        }
+        xml.push_str("</Elements></InstrumentSet>\n");
+        xml.push_str("<Sounds><Sound><Name>Drumkit</Name><Label>Drumkit</Label><Path>Drums/Drumkit</Path><Role>Factory</Role><MIDI><LSB>0</LSB><MSB>0</MSB><Program>0</Program></MIDI></Sound></Sounds>\n");
+        let _ = writeln!(
+            xml,
+            "<MidiConnection><Port>0</Port><PrimaryChannel>{}</PrimaryChannel><SecondaryChannel>{}</SecondaryChannel><ForeOneChannelPerString>false</ForeOneChannelPerString></MidiConnection>",
+            channel, channel
+        );
+        xml.push_str("<PlaybackState>Default</PlaybackState><AudioEngineState>MIDI</AudioEngineState><Staves><Staff><Properties></Properties></Staff></Staves></Track>\n");
+    }
+    xml.push_str("</Tracks>\n");
+
+    let (mut master_bars, mut bar_list, mut voice_list, mut beat_list, mut note_list) =
+        (String::new(), String::new(), String::new(), String::new(), String::new());
+    let (mut voice_id, mut beat_id, mut note_id) = (0, 0, 0);
+    let mut rhythms: BTreeMap<(u32, u8, (u32, u32)), usize> = BTreeMap::new();
+    let time = format!("{}/{}", signature.numerator, 128 / signature.denominator.to_128th());
+    for m in 0..bars as usize {
+        let ids: Vec<String> = (0..tracks.len()).map(|t| (m * tracks.len() + t).to_string()).collect();
+        let _ = writeln!(
+            master_bars,
+            "<MasterBar><Key><AccidentalCount>0</AccidentalCount><Mode>Major</Mode></Key><Time>{}</Time><Bars>{}</Bars></MasterBar>",
+            time,
+            ids.join(" ")
+        );
+        for (t, voices) in tracks.iter().enumerate() {
+            let mut staff = [-1i64; VOICES_PER_TRACK];
+            for (v, (part, measures)) in voices.iter().enumerate() {
+                let Some(notes) = measures.get(m).filter(|notes| !notes.is_empty()) else {
+                    continue;
+                };
+                let mut beats = Vec::new();
+                for note in notes {
+                    let next = rhythms.len();
+                    let rhythm = *rhythms.entry((note.value.to_128th(), note.dots, note.tuplet)).or_insert(next);
+                    let _ = write!(beat_list, "<Beat id=\"{}\"><Dynamic>{}</Dynamic><Rhythm ref=\"{}\"/>", beat_id, dynamic(note), rhythm);
+                    if let Some(stroke) = note.stroke {
+                        let _ = write!(beat_list, "<Notes>{}</Notes>", note_id);
+                        write_note(&mut note_list, note_id, note, *part, stroke.articulation, layout, registry);
+                        note_id += 1;
+                    }
+                    beat_list.push_str("</Beat>\n");
+                    beats.push(beat_id.to_string());
+                    beat_id += 1;
+                }
+                let _ = writeln!(voice_list, "<Voice id=\"{}\"><Beats>{}</Beats></Voice>", voice_id, beats.join(" "));
+                staff[v] = voice_id;
+                voice_id += 1;
+            }
+            let staff: Vec<String> = staff.iter().map(|v| v.to_string()).collect();
+            let _ = writeln!(bar_list, "<Bar id=\"{}\"><Clef>Neutral</Clef><Voices>{}</Voices></Bar>", m * tracks.len() + t, staff.join(" "));
+        }
+    }
+    let mut rhythm_list = String::new();
+    let mut by_id: Vec<_> = rhythms.into_iter().collect();
+    by_id.sort_by_key(|(_, id)| *id);
+    let values = ["Whole", "Half", "Quarter", "Eighth", "16th", "32nd", "64th", "128th"];
+    for ((value, dots, tuplet), id) in by_id {
+        let _ = write!(rhythm_list, "<Rhythm id=\"{}\"><NoteValue>{}</NoteValue>", id, values[value.leading_zeros() as usize - 24]);
+        if dots > 0 {
+            let _ = write!(rhythm_list, "<AugmentationDot count=\"{}\"/>", dots);
+        }
+        if tuplet != (1, 1) {
+            let _ = write!(rhythm_list, "<PrimaryTuplet num=\"{}\" den=\"{}\"/>", tuplet.0, tuplet.1);
+        }
+        rhythm_list.push_str("</Rhythm>\n");
+    }
+    for (tag, list) in [
+        ("MasterBars", master_bars),
+        ("Bars", bar_list),
+        ("Voices", voice_list),
+        ("Beats", beat_list),
+        ("Notes", note_list),
+        ("Rhythms", rhythm_list),
+    ] {
+        let _ = write!(xml, "<{}>\n{}</{}>\n", tag, list, tag);
+    }
+    xml.push_str("</GPIF>\n");
+    xml
+}
+
+fn write_note(xml: &mut String, id: usize, note: &Written, part: DrumPart, articulation: Articulation, layout: &Layout, registry: &PartRegistry) {
+    let key = Part::Drum(part).midi_key(articulation, registry).as_int();
+    let _ = write!(xml, "<Note id=\"{}\">", id);
+    if note.tie_start || note.tie_stop {
+        let _ = write!(xml, "<Tie origin=\"{}\" destination=\"{}\"/>", note.tie_start, note.tie_stop);
+    }
+    if note.stroke.is_some_and(|stroke| stroke.velocity <= GHOST_VELOCITY) {
+        xml.push_str("<AntiAccent>Normal</AntiAccent>");
+    }
+    // Every instrument of the kit is an element of a single articulation, numbered from 0.
+    if let Some((n, _)) = layout.instruments.get(&(part, key)) {
+        let _ = write!(xml, "<InstrumentArticulation>{}</InstrumentArticulation>", n - 1);
+    }
+    let _ = writeln!(xml, "<Properties><Property name=\"Midi\"><Number>{}</Number></Property></Properties></Note>", key);
+}
+
+/// The dynamic of the beat of a hit, from `PPP` to `FFF` in steps of 16 of its velocity.
+fn dynamic(note: &Written) -> &'static str {
+    let dynamics = ["PPP", "PP", "P", "MP", "MF", "F", "FF", "FFF"];
+    note.stroke.map_or("MF", |stroke| dynamics[(stroke.velocity as usize / 16).min(7)])
+}
+
+/// Line of the staff of a note, from 0 on the top line down, the spaces in between.
+fn staff_line(step: char, octave: u8) -> i32 {
+    let diatonic = octave as i32 * 7 + "CDEFGAB".find(step).unwrap_or(0) as i32;
+    // F5 is on the top line.
+    5 * 7 + 3 - diatonic
+}
+
+/// SMuFL noteheads of a black, half and whole note with a notehead of MusicXML.
+fn noteheads(notehead: &str) -> &'static str {
+    match notehead {
+        "x" => "noteheadXBlack noteheadXHalf noteheadXWhole",
+        "circle-x" => "noteheadCircleX noteheadCircleX noteheadCircleX",
+        "triangle" => "noteheadTriangleUpBlack noteheadTriangleUpHalf noteheadTriangleUpWhole",
+        "diamond" => "noteheadDiamondBlack noteheadDiamondHalf noteheadDiamondWhole",
+        "circle dot" => "noteheadCircledBlack noteheadCircledHalf noteheadCircledWhole",
+        _ => "noteheadBlack noteheadHalf noteheadWhole",
+    }
+}
+
+/// Kind of drum Guitar Pro groups an instrument of the kit under.
+fn element_type(part: DrumPart) -> &'static str {
+    match part {
+        DrumPart::KickDrum => "kick",
+        DrumPart::SnareDrum => "snare",
+        DrumPart::HiHat => "hiHat",
+        DrumPart::CrashCymbal => "crash",
+        DrumPart::Cowbell => "cowbell",
+        DrumPart::Tambourine => "tambourine",
+        _ => "percussion",
+    }
+}
+
+/// Views of the score Guitar Pro opens with: all the tracks, then every track by itself, each
+/// in standard notation.
+fn part_configuration(tracks: usize) -> Vec<u8> {
+    let mut views = vec![tracks];
+    views.extend(std::iter::repeat_n(1, tracks));
+    let mut bytes = Vec::new();
+    bytes.extend((views.len() as u32).to_be_bytes());
+    for shown in views {
+        bytes.push(0); // no multi-bar rests
+        bytes.extend((shown as u32).to_be_bytes());
+        bytes.extend(std::iter::repeat_n(0x01, shown)); // standard notation
+    }
+    bytes
+}
+
+#[test]
+fn test_guitar_pro() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let parts = BTreeMap::from_iter([
+        (DrumPart::KickDrum, groups("4x-2x").unwrap().1),
+        (DrumPart::SnareDrum, groups("8t-x-4-2x_").unwrap().1),
+        (DrumPart::HiHat, groups("8c4.x").unwrap().1),
+    ]);
+    let registry = PartRegistry::default();
+    let bytes = archive(&parts, &registry, four_four, &[(1, 120.0), (2, 90.0)], 2, "A & B").unwrap();
+    assert!(bytes.starts_with(b"PK\x03\x04"));
+    // The files are stored as they are.
+    let text = String::from_utf8_lossy(&bytes);
+    assert!(text.contains("VERSION7.0"));
+    assert!(text.contains("Content/score.gpif<?xml"));
+
+    let laid = layout(&parts, &registry, four_four, 2).unwrap();
+    let tracks: Vec<_> = laid.voices.chunks(VOICES_PER_TRACK).collect();
+    let xml = gpif(&laid, &tracks, &registry, four_four, &[(1, 120.0), (2, 90.0)], 2, "A & B");
+    assert!(xml.contains("<Title>A &amp; B</Title>"));
+    assert!(xml.contains("<Bar>0</Bar><Position>0</Position><Visible>true</Visible><Value>120 2</Value>"));
+    assert!(xml.contains("<Bar>1</Bar><Position>0</Position><Visible>true</Visible><Value>90 2</Value>"));
+    assert_eq!(xml.matches("<Track id=").count(), 1);
+    assert_eq!(xml.matches("<MasterBar>").count(), 2);
+    assert!(xml.contains("<Time>4/4</Time><Bars>0</Bars>"));
+    // The kick, the snare and the hi-hat are three voices of the staff.
+    assert!(xml.contains("<Bar id=\"0\"><Clef>Neutral</Clef><Voices>0 1 2 -1</Voices></Bar>"));
+    // The kick on the bottom space, the closed and open hi-hat above the staff.
+    assert!(xml.contains("<Name>Kick Drum</Name><StaffLine>7</StaffLine>"));
+    assert!(xml.contains("<StaffLine>-1</StaffLine><Noteheads>noteheadXBlack"));
+    assert!(xml.contains("<StaffLine>-1</StaffLine><Noteheads>noteheadCircleX"));
+    assert!(xml.contains("<OutputMidiNumber>42</OutputMidiNumber>") && xml.contains("<OutputMidiNumber>46</OutputMidiNumber>"));
+    assert!(xml.contains("<NoteValue>Eighth</NoteValue><PrimaryTuplet num=\"3\" den=\"2\"/>"));
+    assert!(xml.contains("<NoteValue>Quarter</NoteValue><AugmentationDot count=\"1\"/>"));
+    // The half note of the snare is held over the barline.
+    assert_eq!(xml.matches("<Tie origin=\"true\" destination=\"false\"/>").count(), 1);
+    assert_eq!(xml.matches("<Tie origin=\"false\" destination=\"true\"/>").count(), 1);
+
+    // Parts past the fourth go on another track.
+    let many = BTreeMap::from_iter(
+        [DrumPart::KickDrum, DrumPart::SnareDrum, DrumPart::HiHat, DrumPart::CrashCymbal, DrumPart::Cowbell]
+            .map(|part| (part, groups("4x").unwrap().1)),
+    );
+    let laid = layout(&many, &registry, four_four, 1).unwrap();
+    let tracks: Vec<_> = laid.voices.chunks(VOICES_PER_TRACK).collect();
+    let xml = gpif(&laid, &tracks, &registry, four_four, &[(1, 120.0)], 1, "");
+    assert!(xml.contains("<MasterTrack><Tracks>0 1</Tracks>"));
+    assert!(xml.contains("<Time>4/4</Time><Bars>0 1</Bars>"));
+    assert!(xml.contains("<Bar id=\"1\"><Clef>Neutral</Clef><Voices>4 -1 -1 -1</Voices></Bar>"));
+    assert_eq!(part_configuration(2), [0, 0, 0, 3, 0, 0, 0, 0, 2, 1, 1, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 1, 1]);
+}
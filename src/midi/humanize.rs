@@ -0,0 +1,69 @@
+use crate::random::Rng;
+
+#[allow(unused_imports)]
+use crate::midi::core::{MidiTempo, TICKS_PER_QUARTER_NOTE};
+
+/// Hits played a little off the grid and off their velocities, the way a drummer plays them.
+/// Timing and velocities are drawn from generators of their own seeded with `seed`, so either
+/// can be varied without changing what the other draws, and the same seed plays the same.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Humanize {
+    /// Hits land up to this many milliseconds early or late, 0 keeps them on the grid.
+    pub timing: f64,
+    /// Velocities go up or down by up to this much, 0 keeps them as they are.
+    pub velocity: u8,
+    pub seed: u64,
+}
+
+impl Humanize {
+    pub fn is_empty(&self) -> bool {
+        self.timing == 0.0 && self.velocity == 0
+    }
+
+    /// Generators of the timing and of the velocities.
+    pub fn generators(&self) -> (Rng, Rng) {
+        (Rng::new(self.seed), Rng::new(!self.seed))
+    }
+
+    /// Ticks a hit is moved by, early if negative, with `tempo` at the hit and `ppqn` ticks to
+    /// a quarter note.
+    pub fn ticks(&self, rng: &mut Rng, tempo: MidiTempo, ppqn: u16) -> i128 {
+        if self.timing == 0.0 {
+            return 0;
+        }
+        let seconds_per_tick = tempo.microseconds() as f64 / 1e6 / ppqn.max(1) as f64;
+        let ticks = self.timing / 1000.0 / seconds_per_tick;
+        ((rng.next_f64() * 2.0 - 1.0) * ticks).round() as i128
+    }
+
+    /// Velocity of a hit of `velocity`.
+    pub fn vary(&self, rng: &mut Rng, velocity: u8) -> u8 {
+        if self.velocity == 0 {
+            return velocity;
+        }
+        let change = rng.below(2 * self.velocity as u64 + 1) as i16 - self.velocity as i16;
+        (velocity as i16 + change).clamp(1, 127) as u8
+    }
+}
+
+#[test]
+fn test_humanize() {
+    let humanize = Humanize { timing: 10.0, velocity: 8, seed: 7 };
+    let (mut timing, mut velocity) = humanize.generators();
+    // 10 ms are 4 ticks of 48 to a quarter note at 120 BPM.
+    let moves: Vec<i128> = (0..200).map(|_| humanize.ticks(&mut timing, MidiTempo::from_tempo(120.0), TICKS_PER_QUARTER_NOTE)).collect();
+    assert!(moves.iter().all(|t| (-4..=4).contains(t)));
+    assert!(moves.iter().any(|t| *t < 0) && moves.iter().any(|t| *t > 0));
+    let velocities: Vec<u8> = (0..200).map(|_| humanize.vary(&mut velocity, 100)).collect();
+    assert!(velocities.iter().all(|v| (92..=108).contains(v)));
+    assert!(velocities.iter().any(|v| *v != 100));
+    assert!((0..50).all(|_| humanize.vary(&mut velocity, 127) <= 127 && humanize.vary(&mut velocity, 1) >= 1));
+
+    // Velocities draw the same with or without timing.
+    let velocity_only = Humanize { timing: 0.0, ..humanize };
+    let (_, mut alone) = velocity_only.generators();
+    let (_, mut along) = humanize.generators();
+    assert_eq!((0..20).map(|_| velocity_only.vary(&mut alone, 64)).collect::<Vec<_>>(), (0..20).map(|_| humanize.vary(&mut along, 64)).collect::<Vec<_>>());
+    assert_eq!(velocity_only.ticks(&mut alone, MidiTempo::from_tempo(120.0), 48), 0);
+    assert!(Humanize::default().is_empty());
+}
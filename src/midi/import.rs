@@ -0,0 +1,311 @@
+use midly::{MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use std::collections::BTreeMap;
+
+use crate::dsl::dsl::{Group, Groups, Note};
+use crate::midi::core::{bar_length, DrumPart, Tick};
+use crate::midi::grid::{Grid, Grids};
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::{groups, BasicLength};
+#[allow(unused_imports)]
+use crate::midi::core::{create_smf, SmfOptions, TICKS_PER_QUARTER_NOTE};
+#[allow(unused_imports)]
+use crate::midi::profile::Profile;
+#[allow(unused_imports)]
+use crate::midi::registry::PartRegistry;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// A drum hit read from a MIDI file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Onset {
+    pub tick: Tick,
+    pub part: DrumPart,
+    pub velocity: u8,
+}
+
+/// Drum hits of a MIDI file, merged from all of its tracks and sorted by time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedDrums {
+    pub ticks_per_quarter_note: u16,
+    /// The first time signature of the file, if there's any.
+    pub time_signature: Option<TimeSignature>,
+    pub onsets: Vec<Onset>,
+    /// Time of the last event in the file.
+    pub end: Tick,
+}
+
+/// Reads drum hits from a Standard MIDI File. Notes that don't map to a `DrumPart` are skipped.
+pub fn import_drums(bytes: &[u8]) -> Result<ImportedDrums, String> {
+    let smf = Smf::parse(bytes).map_err(|e| format!("Can't parse MIDI file: {}", e))?;
+    import_channel(&smf, None)
+}
+
+/// `import_drums` of a single channel, counted from 0, like 9 for the percussion of General MIDI.
+pub fn import_channel(smf: &Smf, only: Option<u8>) -> Result<ImportedDrums, String> {
+    let ticks_per_quarter_note = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int(),
+        Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing aren't supported".to_string()),
+    };
+    let mut time_signature = None;
+    let mut onsets = Vec::new();
+    let mut end = Tick(0);
+    for track in smf.tracks.iter() {
+        let mut time = Tick(0);
+        for event in track.iter() {
+            time = time + Tick(event.delta.as_int() as u128);
+            match event.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, vel },
+                } if vel > 0 && only.is_none_or(|c| c == channel.as_int()) => {
+                    if let Some(part) = DrumPart::from_midi_key(key) {
+                        onsets.push(Onset {
+                            tick: time,
+                            part,
+                            velocity: vel.as_int(),
+                        });
+                    }
+                }
+                TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _))
+                    if time_signature.is_none() =>
+                {
+                    time_signature = TimeSignature::from_midi(numerator, denominator).ok();
+                }
+                _ => {}
+            }
+        }
+        end = end.max(time);
+    }
+    onsets.sort();
+    Ok(ImportedDrums {
+        ticks_per_quarter_note,
+        time_signature,
+        onsets,
+        end,
+    })
+}
+
+/// A note `quantize` moved from tick `from` to tick `to`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Moved {
+    pub key: u8,
+    /// The part of the key, if it's one of the built-in parts.
+    pub part: Option<DrumPart>,
+    pub from: Tick,
+    pub to: Tick,
+}
+
+impl Moved {
+    /// How far the note moved in ticks, negative if it moved earlier.
+    pub fn shift(&self) -> i128 {
+        self.to.0 as i128 - self.from.0 as i128
+    }
+}
+
+/// Moves the notes of a file towards the nearest step of the grid of their part, by `strength`
+/// of the way from 0 (not at all) to 1 (right onto it), like the quantize of a DAW. Only notes of
+/// channel `only`, counted from 0, move if it's given. Note offs move with their note ons, so
+/// notes keep their lengths. Returns the notes that moved.
+pub fn quantize(smf: &mut Smf, only: Option<u8>, grids: &Grids, strength: f64) -> Result<Vec<Moved>, String> {
+    let ppqn = match smf.header.timing {
+        Timing::Metrical(ticks) => ticks.as_int(),
+        Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing aren't supported".to_string()),
+    };
+    let mut used = std::iter::once(&grids.every).chain(grids.parts.values());
+    if let Some(grid) = used.find(|g| !g.fits(ppqn)) {
+        return Err(format!("Steps of a {} grid are shorter than a tick of the file", grid));
+    }
+    let mut moved = Vec::new();
+    for track in smf.tracks.iter_mut() {
+        // Shifts of the notes still sounding by channel and key.
+        let mut sounding: BTreeMap<(u8, u8), i128> = BTreeMap::new();
+        let mut time: u128 = 0;
+        let mut events: Vec<(u128, usize, TrackEvent)> = Vec::with_capacity(track.len());
+        for (index, event) in track.iter().enumerate() {
+            time += event.delta.as_int() as u128;
+            let mut at = time;
+            if let TrackEventKind::Midi { channel, message } = event.kind {
+                let channel = channel.as_int();
+                if only.is_none_or(|c| c == channel) {
+                    match message {
+                        MidiMessage::NoteOn { key, vel } if vel > 0 => {
+                            let grid = grids.of(DrumPart::from_midi_key(key));
+                            let grid = grid.tick(grid.nearest(time as i128, ppqn), ppqn) as u128;
+                            at = (time as f64 + (grid as f64 - time as f64) * strength).round() as u128;
+                            sounding.insert((channel, key.as_int()), at as i128 - time as i128);
+                            if at != time {
+                                moved.push(Moved {
+                                    key: key.as_int(),
+                                    part: DrumPart::from_midi_key(key),
+                                    from: Tick(time),
+                                    to: Tick(at),
+                                });
+                            }
+                        }
+                        MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                            if let Some(shift) = sounding.remove(&(channel, key.as_int())) {
+                                at = (time as i128 + shift).max(0) as u128;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            events.push((at, index, *event));
+        }
+        // The end of the track stays the end.
+        let end = events.iter().map(|(at, _, _)| *at).max().unwrap_or(0);
+        if let Some(last) = events.last_mut() {
+            if last.2.kind == TrackEventKind::Meta(MetaMessage::EndOfTrack) {
+                last.0 = end;
+                last.1 = usize::MAX;
+            }
+        }
+        events.sort_by_key(|(at, index, _)| (*at, *index));
+        let mut last = 0;
+        *track = events
+            .into_iter()
+            .map(|(at, _, event)| {
+                let delta = (at - last) as u32;
+                last = at;
+                TrackEvent { delta: delta.into(), ..event }
+            })
+            .collect();
+    }
+    moved.sort_by_key(|m| (m.from, m.key));
+    Ok(moved)
+}
+
+impl ImportedDrums {
+    /// Onsets of a single part snapped to the nearest step of a grid.
+    /// Every element of the result is a step of the grid, `true` if the part is hit on it.
+    pub fn to_steps(&self, part: DrumPart, grid: Grid) -> Vec<bool> {
+        let ppqn = self.ticks_per_quarter_note;
+        if !grid.fits(ppqn) {
+            return Vec::new();
+        }
+        let mut steps = vec![false; (self.end.0 as f64 / grid.step(ppqn)).ceil() as usize];
+        for onset in self.onsets.iter().filter(|o| o.part == part) {
+            let index = grid.nearest(onset.tick.0 as i128, ppqn) as usize;
+            if index < steps.len() {
+                steps[index] = true;
+            }
+        }
+        steps
+    }
+
+    /// Patterns of every part hit in the file, snapped to the grid of the part, of whole bars
+    /// of `time_signature` up to the bar of the last hit. A part playing the same bars over and
+    /// over is cut down to them. Parts on grids patterns can't be written on, or that don't
+    /// make up a bar, are left out.
+    pub fn to_patterns(&self, grids: &Grids, time_signature: TimeSignature) -> BTreeMap<DrumPart, Groups> {
+        let ppqn = self.ticks_per_quarter_note;
+        let Tick(bar_ticks) = bar_length(time_signature, ppqn);
+        let snapped = |o: &Onset| {
+            let grid = grids.of(Some(o.part));
+            grid.tick(grid.nearest(o.tick.0 as i128, ppqn), ppqn) as u128
+        };
+        let last = self.onsets.iter().map(snapped).max().unwrap_or(0);
+        let bars = (last / bar_ticks.max(1) + 1) as usize;
+        let mut parts: Vec<DrumPart> = self.onsets.iter().map(|o| o.part).collect();
+        parts.sort();
+        parts.dedup();
+        parts
+            .into_iter()
+            .filter_map(|part| {
+                let grid = grids.of(Some(part));
+                let length = grid.to_length().filter(|_| grid.fits(ppqn))?;
+                let bar = grid.bar_steps(time_signature).filter(|b| *b > 0)?;
+                let mut steps = self.to_steps(part, grid);
+                steps.resize(bars * bar, false);
+                let period = (1..=bars)
+                    .filter(|k| bars.is_multiple_of(*k))
+                    .find(|k| steps.chunks(k * bar).all(|c| c == &steps[..k * bar]))
+                    .unwrap_or(bars);
+                steps.truncate(period * bar);
+                let group = Group {
+                    notes: steps.into_iter().map(|hit| if hit { Note::Hit } else { Note::Rest }).collect(),
+                    length,
+                    times: (),
+                };
+                Some((part, Groups(vec![group])))
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_import_drums() {
+    let smf = create_smf(
+        &PartRegistry::default(),
+        BTreeMap::from_iter([
+            (DrumPart::KickDrum, groups("8x--x--").unwrap().1),
+            (DrumPart::SnareDrum, groups("4-x").unwrap().1),
+        ]),
+        BTreeMap::new(),
+        "",
+        &SmfOptions::default(),
+    );
+    let mut bytes = Vec::new();
+    smf.write_std(&mut bytes).unwrap();
+
+    let imported = import_drums(&bytes).unwrap();
+    assert_eq!(imported.ticks_per_quarter_note, 48);
+    assert_eq!(imported.time_signature, TimeSignature::from_str("4/4").ok());
+    assert_eq!(imported.onsets.first(), Some(&Onset { tick: Tick(0), part: DrumPart::KickDrum, velocity: 127 }));
+    let kick = imported.to_steps(DrumPart::KickDrum, Grid::from(BasicLength::Eighth));
+    assert!(kick.len() >= 24);
+    assert_eq!(&kick[..7], &[true, false, false, true, false, false, true]);
+    let snare = imported.to_steps(DrumPart::SnareDrum, Grid::from(BasicLength::Fourth));
+    assert_eq!(&snare[..4], &[false, true, false, true]);
+    assert!(import_drums(b"not a midi file").is_err());
+
+    // The kick repeats every 3 bars, the snare every bar.
+    let patterns = imported.to_patterns(&Grids::from(Grid::from(BasicLength::Eighth)), TimeSignature::from_str("4/4").unwrap());
+    assert_eq!(patterns[&DrumPart::KickDrum].to_string(), "8x--x--x--x--x--x--x--x--");
+    assert_eq!(patterns[&DrumPart::SnareDrum].to_string(), "8--x---x-");
+    // Only the drums on channel 10.
+    assert!(import_channel(&Smf::parse(&bytes).unwrap(), Some(0)).unwrap().onsets.is_empty());
+    assert_eq!(import_channel(&Smf::parse(&bytes).unwrap(), Some(9)).unwrap(), imported);
+}
+
+#[test]
+fn test_quantize() {
+    let event = |delta: u32, kind| TrackEvent { delta: delta.into(), kind };
+    let note = |on: bool, key: u8| TrackEventKind::Midi {
+        channel: 9.into(),
+        message: if on {
+            MidiMessage::NoteOn { key: key.into(), vel: 100.into() }
+        } else {
+            MidiMessage::NoteOff { key: key.into(), vel: 0.into() }
+        },
+    };
+    // A kick 6 ticks late and a snare 4 ticks early, on a grid of eighths of 24 ticks.
+    let mut smf = Smf {
+        header: midly::Header::new(midly::Format::SingleTrack, Timing::Metrical(48.into())),
+        tracks: vec![vec![
+            event(6, note(true, 36)),
+            event(10, note(false, 36)),
+            event(28, note(true, 38)),
+            event(12, note(false, 38)),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]],
+    };
+    let eighths = Grids::from(Grid::from(BasicLength::Eighth));
+    let mut loose = smf.clone();
+    let moved = quantize(&mut smf.clone(), None, &eighths, 0.5).unwrap();
+    assert_eq!(moved.iter().map(|m| (m.from, m.to)).collect::<Vec<_>>(), vec![(Tick(6), Tick(3)), (Tick(44), Tick(46))]);
+    let moved = quantize(&mut smf, Some(9), &eighths, 1.0).unwrap();
+    assert_eq!(moved.iter().map(|m| (m.part, m.shift())).collect::<Vec<_>>(), vec![(Some(DrumPart::KickDrum), -6), (Some(DrumPart::SnareDrum), 4)]);
+    let deltas: Vec<u32> = smf.tracks[0].iter().map(|e| e.delta.as_int()).collect();
+    assert_eq!(deltas, vec![0, 10, 38, 12, 0]);
+    assert!(quantize(&mut smf, Some(0), &eighths, 1.0).unwrap().is_empty());
+    // The kick on a grid of quintuplet sixteenths, 9.6 ticks each, goes to the second of them.
+    let mut grids = eighths.clone();
+    grids.parts.insert(DrumPart::KickDrum, Grid::from_str("16:5").unwrap());
+    let moved = quantize(&mut loose, None, &grids, 1.0).unwrap();
+    assert_eq!(moved.iter().map(|m| (m.from, m.to)).collect::<Vec<_>>(), vec![(Tick(6), Tick(10)), (Tick(44), Tick(48))]);
+}
@@ -0,0 +1,165 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use midly::{MidiMessage, Smf, TrackEventKind};
+
+use crate::api::json::Json;
+use crate::dsl::dsl::Articulation;
+use crate::midi::core::{Convergence, Part};
+use crate::midi::profile::Profile;
+use crate::midi::registry::PartRegistry;
+
+#[allow(unused_imports)]
+use crate::api::spec::{description, render, Spec};
+#[allow(unused_imports)]
+use crate::midi::core::DrumPart;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Which keys of which channels the parts of a file play, and how long it is, for setting up
+/// sampler templates and other tools that take the file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyMap {
+    pub ppqn: u16,
+    /// Bars written after the pickup.
+    pub bars: u32,
+    /// Bars it takes the patterns to line up again, `None` if it's beyond the limits.
+    pub converges: Option<u32>,
+    /// Channels, counted from 1, and keys of the parts that play, by the names of the parts.
+    pub parts: BTreeMap<String, BTreeMap<u8, BTreeSet<u8>>>,
+    /// Velocity of a plain hit and the lowest and the highest velocity of the parts that have
+    /// their velocities set, by their names.
+    pub velocities: BTreeMap<String, (u8, u8, u8)>,
+}
+
+impl KeyMap {
+    pub fn new(ppqn: u16, convergence: &Convergence) -> KeyMap {
+        KeyMap {
+            ppqn,
+            bars: convergence.bars,
+            converges: convergence.converges,
+            parts: BTreeMap::new(),
+            velocities: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the keys the `parts` play in a file written for a `profile`, the keys of any
+    /// articulation of a part that are played on its channel and the ones of its layers, and
+    /// the notes of the bass line. Parts on a channel of their own get every key played there.
+    /// Parts that play get their velocities if they have them set.
+    pub fn read(&mut self, smf: &Smf, parts: &[Part], registry: &PartRegistry, profile: Profile) {
+        let conventions = profile.conventions();
+        let mut played = BTreeSet::new();
+        for event in smf.tracks.iter().flatten() {
+            if let TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::NoteOn { key, vel },
+            } = event.kind
+            {
+                if vel > 0 {
+                    played.insert((channel.as_int(), key.as_int()));
+                }
+            }
+        }
+        for part in parts {
+            let channel = part.channel(registry, &conventions).as_int();
+            let mut keys: Vec<(u8, u8)> = [Articulation::Normal, Articulation::SideStick, Articulation::Rimshot, Articulation::Closed]
+                .into_iter()
+                .map(|a| (channel, part.midi_key(a, registry).as_int()))
+                .collect();
+            match part {
+                Part::Drum(drum) => {
+                    keys.extend(registry.layers_of(*drum).map(|l| (l.channel.map_or(channel, |c| c.as_int()), l.key.as_int())));
+                    // A part on a channel of its own may play any note there.
+                    if registry.get(*drum).and_then(|p| p.channel).is_some_and(|c| c != conventions.drum_channel) {
+                        keys.extend(played.iter().filter(|(c, _)| *c == channel).cloned());
+                    }
+                }
+                Part::Bass => keys.extend(registry.bass_line().keys().into_iter().map(|k| (channel, k))),
+            }
+            let name = match part {
+                Part::Drum(drum) => registry.name(*drum),
+                Part::Bass => "bass".to_string(),
+            };
+            for (channel, key) in keys.into_iter().filter(|k| played.contains(k)) {
+                self.parts.entry(name.clone()).or_default().entry(channel + 1).or_default().insert(key);
+            }
+            if let Some(v) = registry.velocity_of(*part).filter(|_| self.parts.contains_key(&name)) {
+                let plain = match part {
+                    Part::Drum(drum) => registry.get(*drum).map_or(127, |c| c.velocity),
+                    Part::Bass => 127,
+                };
+                self.velocities.insert(name, (v.default.unwrap_or(plain).clamp(v.min, v.max), v.min, v.max));
+            }
+        }
+    }
+
+    /// Name of the part playing a key on a channel, counted from 1.
+    pub fn part_of(&self, channel: u8, key: u8) -> Option<&str> {
+        self.parts.iter().find(|(_, channels)| channels.get(&channel).is_some_and(|keys| keys.contains(&key))).map(|(name, _)| name.as_str())
+    }
+
+    /// The key map as JSON, like
+    ///
+    /// ```json
+    /// {
+    ///   "bars": 3,
+    ///   "converges": 3,
+    ///   "parts": { "kick": { "10": [36] }, "snare": { "10": [37, 38] } },
+    ///   "ppqn": 96,
+    ///   "velocities": { "snare": { "default": 45, "max": 60, "min": 30 } }
+    /// }
+    /// ```
+    pub fn to_json(&self) -> Json {
+        let number = |n: u32| Json::Number(n as f64);
+        let parts = self
+            .parts
+            .iter()
+            .map(|(name, channels)| {
+                let channels = channels
+                    .iter()
+                    .map(|(channel, keys)| (channel.to_string(), Json::Array(keys.iter().map(|k| number(*k as u32)).collect())))
+                    .collect();
+                (name.clone(), Json::Object(channels))
+            })
+            .collect();
+        let velocities = self
+            .velocities
+            .iter()
+            .map(|(name, (default, min, max))| {
+                let velocity = [("default", default), ("min", min), ("max", max)].map(|(k, v)| (k.to_string(), number(*v as u32)));
+                (name.clone(), Json::Object(BTreeMap::from_iter(velocity)))
+            })
+            .collect();
+        Json::Object(BTreeMap::from_iter([
+            ("ppqn".to_string(), number(self.ppqn as u32)),
+            ("bars".to_string(), number(self.bars)),
+            ("converges".to_string(), self.converges.map_or(Json::Null, number)),
+            ("parts".to_string(), Json::Object(parts)),
+            ("velocities".to_string(), Json::Object(velocities)),
+        ]))
+    }
+}
+
+#[test]
+fn test_key_map() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "8x--x--", "snare": "4-s-x", "hihat": "4-"}, "bass": true, "partVelocity": ["snare=30-60", "hihat=90"]}"#).unwrap();
+    let text = description(&spec.patterns, &spec.registry);
+    let (smf, bars) = render(&spec, &text).unwrap();
+    let convergence = Convergence {
+        converges: Some(bars),
+        cycle: Some(bars as u128),
+        bars,
+        events: 0,
+        on_limit: Default::default(),
+    };
+    let mut key_map = KeyMap::new(48, &convergence);
+    let parts = [Part::Drum(DrumPart::KickDrum), Part::Drum(DrumPart::SnareDrum), Part::Drum(DrumPart::HiHat), Part::Bass];
+    key_map.read(&smf, &parts, &spec.registry, spec.target);
+    let names: Vec<&str> = key_map.parts.keys().map(|n| n.as_str()).collect();
+    assert_eq!(names, vec!["bass", "kick", "snare"]);
+    assert_eq!(key_map.parts["snare"], BTreeMap::from_iter([(10, BTreeSet::from_iter([37, 38]))]));
+    assert_eq!(
+        key_map.to_json().to_string(),
+        "{\"bars\":3,\"converges\":3,\"parts\":{\"bass\":{\"1\":[28]},\"kick\":{\"10\":[36]},\"snare\":{\"10\":[37,38]}},\"ppqn\":48,\"velocities\":{\"snare\":{\"default\":60,\"max\":60,\"min\":30}}}"
+    );
+}
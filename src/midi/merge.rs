@@ -0,0 +1,193 @@
+use std::str::FromStr;
+
+use midly::{Format, MetaMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::midi::core::Tick;
+use crate::midi::tempo::TempoMap;
+use crate::midi::time::{SignatureMap, TimeSignature};
+
+#[allow(unused_imports)]
+use crate::api::spec::{description, render, Spec};
+#[allow(unused_imports)]
+use midly::{Header, MidiMessage};
+
+/// Where the drums go in a song: on a bar, counted from 1, or after a number of seconds,
+/// written like `12.5s`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Position {
+    Bar(u32),
+    Seconds(f64),
+}
+
+impl FromStr for Position {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_suffix('s') {
+            Some(seconds) => match seconds.parse::<f64>() {
+                Ok(x) if x >= 0.0 && x.is_finite() => Ok(Position::Seconds(x)),
+                _ => Err(format!("{} is not a number of seconds", s)),
+            },
+            None => match s.trim().parse::<u32>() {
+                Ok(bar) if bar > 0 => Ok(Position::Bar(bar)),
+                _ => Err(format!("{} is neither a bar, counted from 1, nor seconds like 12.5s", s)),
+            },
+        }
+    }
+}
+
+/// Tempo and time signature changes of a file on its own ticks, which is what it takes to tell
+/// which tick a bar or a second of the song falls on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SongMap {
+    pub ppqn: u16,
+    /// Tempo changes of the song.
+    pub tempos: TempoMap,
+    /// Time signature changes of the song.
+    pub signatures: SignatureMap,
+}
+
+impl SongMap {
+    /// Reads the changes of every track of a file. It's 120 BPM and 4/4 until the first ones.
+    pub fn read(smf: &Smf) -> Result<SongMap, String> {
+        let ppqn = match smf.header.timing {
+            Timing::Metrical(ticks) => ticks.as_int(),
+            Timing::Timecode(_, _) => return Err("MIDI files with SMPTE timing aren't supported".to_string()),
+        };
+        Ok(SongMap {
+            ppqn,
+            tempos: TempoMap::read(smf),
+            signatures: SignatureMap::read(smf)?,
+        })
+    }
+
+    /// Time signature on a tick.
+    pub fn time_signature_at(&self, tick: u64) -> TimeSignature {
+        self.signatures.signature_at(Tick(tick as u128))
+    }
+
+    /// Whether the time signature changes after `from` and before `to`.
+    pub fn changes_signature(&self, from: u64, to: u64) -> bool {
+        self.signatures.changes_between(Tick(from as u128), Tick(to as u128))
+    }
+
+    /// Tick a bar starts on, counted from 1. A time signature changing mid-bar starts a new one.
+    pub fn tick_of_bar(&self, bar: u32) -> u64 {
+        self.signatures.tick_of_bar(bar, self.ppqn).0 as u64
+    }
+
+    /// Tick of the song a number of seconds in, following its tempo changes.
+    pub fn tick_of_seconds(&self, seconds: f64) -> u64 {
+        self.tempos.tick_at(seconds, self.ppqn).0 as u64
+    }
+
+    /// Seconds into the song of a tick, following its tempo changes.
+    pub fn seconds_of_tick(&self, tick: u64) -> f64 {
+        self.tempos.seconds_at(Tick(tick as u128), self.ppqn)
+    }
+
+    /// Tick of a position in the song.
+    pub fn tick_of(&self, position: Position) -> u64 {
+        match position {
+            Position::Bar(bar) => self.tick_of_bar(bar),
+            Position::Seconds(seconds) => self.tick_of_seconds(seconds),
+        }
+    }
+}
+
+/// Adds the tracks of `drums` to `song`, bar 1 of the drums on tick `at` of the song. The
+/// tempo and time signatures of the song stay in charge, the ones of the drums are dropped, so
+/// the drums should be written at the resolution and the time signature of the song.
+pub fn merge<'a>(mut song: Smf<'a>, drums: Smf<'a>, at: u64) -> Result<Smf<'a>, String> {
+    if song.header.format == Format::Sequential {
+        return Err("Songs of independent sequences (format 2) can't take drums".to_string());
+    }
+    if song.header.timing != drums.header.timing {
+        return Err("The drums should have the resolution of the song".to_string());
+    }
+    // A pickup goes before bar 1.
+    let pickup = drums
+        .tracks
+        .iter()
+        .flat_map(|track| {
+            track.iter().scan(0, |time, event| {
+                *time += event.delta.as_int() as u64;
+                Some((*time, event.kind))
+            })
+        })
+        .find(|(_, kind)| *kind == TrackEventKind::Meta(MetaMessage::Marker(b"Bar 1")))
+        .map_or(0, |(time, _)| time);
+    let start = at
+        .checked_sub(pickup)
+        .ok_or_else(|| "The pickup of the drums doesn't fit before the first bar of the song".to_string())?;
+    for track in drums.tracks {
+        let mut time: u64 = start;
+        let mut last: u64 = 0;
+        let mut merged = Vec::with_capacity(track.len());
+        for event in track {
+            time += event.delta.as_int() as u64;
+            match event.kind {
+                TrackEventKind::Meta(MetaMessage::Tempo(_) | MetaMessage::TimeSignature(..) | MetaMessage::Marker(b"Bar 1")) => {}
+                kind => {
+                    merged.push(TrackEvent {
+                        delta: ((time - last) as u32).into(),
+                        kind,
+                    });
+                    last = time;
+                }
+            }
+        }
+        song.tracks.push(merged);
+    }
+    song.header.format = Format::Parallel;
+    Ok(song)
+}
+
+#[test]
+fn test_song_map() {
+    let event = |delta: u32, kind| TrackEvent { delta: delta.into(), kind };
+    let three_four = MetaMessage::TimeSignature(3, 2, 24, 8);
+    // 2 bars of 3/4 at 120 BPM, then 4/4 at 60 BPM.
+    let song = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(96.into())),
+        tracks: vec![vec![
+            event(0, TrackEventKind::Meta(three_four)),
+            event(576, TrackEventKind::Meta(MetaMessage::TimeSignature(4, 2, 24, 8))),
+            event(0, TrackEventKind::Meta(MetaMessage::Tempo(1_000_000.into()))),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]],
+    };
+    let map = SongMap::read(&song).unwrap();
+    assert_eq!(map.time_signature_at(100), TimeSignature::from_str("3/4").unwrap());
+    assert_eq!(map.tick_of_bar(2), 288);
+    assert_eq!(map.tick_of_bar(4), 576 + 384);
+    // 3 seconds at 120 BPM, then a quarter note a second.
+    assert_eq!(map.tick_of_seconds(1.5), 288);
+    assert_eq!(map.tick_of_seconds(5.0), 576 + 192);
+    assert_eq!(map.seconds_of_tick(576 + 192), 5.0);
+    assert!(map.changes_signature(0, 600));
+    assert_eq!(Position::from_str("12.5s"), Ok(Position::Seconds(12.5)));
+    assert_eq!(Position::from_str("3"), Ok(Position::Bar(3)));
+    assert!(Position::from_str("0").is_err());
+
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "8xx|4x"}, "timeSignature": "4/4", "ppqn": 96}"#).unwrap();
+    let text = description(&spec.patterns, &spec.registry);
+    let (drums, _) = render(&spec, &text).unwrap();
+    let merged = merge(song, drums, map.tick_of_bar(3)).unwrap();
+    assert_eq!(merged.header.format, Format::Parallel);
+    assert_eq!(merged.tracks.len(), 2);
+    let note_ons: Vec<u64> = merged.tracks[1]
+        .iter()
+        .scan(0, |time, e| {
+            *time += e.delta.as_int() as u64;
+            Some((*time, e.kind))
+        })
+        .filter_map(|(time, kind)| match kind {
+            TrackEventKind::Midi { message: MidiMessage::NoteOn { .. }, .. } => Some(time),
+            TrackEventKind::Meta(MetaMessage::Tempo(_) | MetaMessage::TimeSignature(..)) => panic!("Unexpected {:?}", kind),
+            _ => None,
+        })
+        .collect();
+    // The pickup of two eighths right before bar 3.
+    assert_eq!(note_ons, vec![480, 528, 576, 672, 768, 864]);
+}
@@ -0,0 +1,86 @@
+use std::str::FromStr;
+
+use crate::midi::core::Part;
+use crate::midi::registry::PartRegistry;
+
+/// Volume and pan of the channel a part is played on, written `hihat=100,-20`. Without a
+/// part it sets the drum kit, `bass=90` sets the bass. Either of them may be left out like
+/// in `snare=,30`.
+///
+/// MIDI mixes channels rather than notes, so parts sharing a channel share its mix too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mix {
+    /// The drum kit if `None`.
+    pub part: Option<Part>,
+    /// Channel volume (CC7) from 0 to 127.
+    pub volume: Option<u8>,
+    /// Pan from -64, hard left, to 63, hard right.
+    pub pan: Option<i8>,
+}
+
+impl Mix {
+    /// Parses the mix of any part of the `registry`, `from_str` only knows the built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, rest) = match s.split_once('=') {
+            Some(("bass", rest)) => (Some(Part::Bass), rest),
+            Some(("drums", rest)) => (None, rest),
+            Some((part, rest)) => (Some(Part::Drum(registry.part(part)?)), rest),
+            None => (None, s),
+        };
+        let (volume, pan) = rest.split_once(',').unwrap_or((rest, ""));
+        let volume = match volume.trim() {
+            "" => None,
+            v => match v.parse::<u8>() {
+                Ok(v) if v <= 127 => Some(v),
+                _ => return Err(format!("{} is not a volume, expected a number from 0 to 127", v)),
+            },
+        };
+        let pan = match pan.trim() {
+            "" => None,
+            p => match p.parse::<i8>() {
+                Ok(p) if (-64..=63).contains(&p) => Some(p),
+                _ => return Err(format!("{} is not a pan, expected a number from -64 (left) to 63 (right)", p)),
+            },
+        };
+        if volume.is_none() && pan.is_none() {
+            return Err(format!("expected a volume and a pan like 100,-20, got {}", s));
+        }
+        Ok(Mix { part, volume, pan })
+    }
+
+    /// Value of the pan controller (CC10), 64 is the center.
+    pub fn pan_value(pan: i8) -> u8 {
+        (pan as i16 + 64) as u8
+    }
+}
+
+impl FromStr for Mix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Mix::parse(s, &PartRegistry::default())
+    }
+}
+
+#[test]
+fn test_mix_from_str() {
+    use crate::midi::core::DrumPart;
+
+    assert_eq!(
+        Mix::from_str("hihat=100,-20"),
+        Ok(Mix {
+            part: Some(Part::Drum(DrumPart::HiHat)),
+            volume: Some(100),
+            pan: Some(-20),
+        })
+    );
+    assert_eq!(Mix::from_str("110"), Ok(Mix { part: None, volume: Some(110), pan: None }));
+    assert_eq!(Mix::from_str("bass=,63").map(|m| (m.part, m.pan)), Ok((Some(Part::Bass), Some(63))));
+    assert_eq!(Mix::from_str("drums=90").map(|m| m.part), Ok(None));
+    assert!(Mix::from_str("kick=128").is_err());
+    assert!(Mix::from_str("kick=100,64").is_err());
+    assert!(Mix::from_str("kick=").is_err());
+    assert!(Mix::from_str("cajon=100").is_err());
+    assert_eq!(Mix::pan_value(-64), 0);
+    assert_eq!(Mix::pan_value(0), 64);
+}
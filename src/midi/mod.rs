@@ -1,2 +1,28 @@
+pub mod accents;
+pub mod alignment;
+pub mod bassline;
+pub mod compare;
 pub mod core;
-pub mod time;
\ No newline at end of file
+pub mod dawproject;
+pub mod dump;
+pub mod dynamics;
+pub mod echo;
+pub mod events;
+pub mod grid;
+pub mod guitar_pro;
+pub mod groove;
+pub mod humanize;
+pub mod import;
+pub mod keymap;
+pub mod merge;
+pub mod musicxml;
+pub mod mixer;
+pub mod names;
+pub mod optimize;
+pub mod profile;
+pub mod registry;
+pub mod smpte;
+pub mod stream;
+pub mod tempo;
+pub mod time;
+pub mod velocity;
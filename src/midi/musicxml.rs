@@ -0,0 +1,477 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::dsl::dsl::{Articulation, BasicLength, Groups, KnownLength, Length, Note, Stroke};
+use crate::midi::core::{DrumPart, Part};
+use crate::midi::registry::PartRegistry;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Hits of this velocity or softer are written as ghost notes, in parentheses.
+pub const GHOST_VELOCITY: u8 = 64;
+
+/// A note of a voice as it's written in a measure.
+#[derive(Debug, Clone)]
+pub(crate) struct Written {
+    /// Length in the divisions of the score.
+    pub duration: u64,
+    pub value: BasicLength,
+    pub dots: u8,
+    /// Notes played in the time of how many notes, 3 in the time of 2 for triplets.
+    pub tuplet: (u32, u32),
+    /// Length of a whole tuplet in divisions, that a bracket goes round.
+    pub bracket: u64,
+    /// The stroke of a hit, `None` for a rest.
+    pub stroke: Option<Stroke>,
+    pub tie_stop: bool,
+    pub tie_start: bool,
+    pub bracket_start: bool,
+    pub bracket_stop: bool,
+}
+
+/// A note of a part, or the part of it in a measure: a hit held for `units`, or a rest.
+struct Held {
+    units: u64,
+    length: Length,
+    stroke: Option<Stroke>,
+    /// Whether it goes on with the hit before it, tied to it.
+    tied: bool,
+}
+
+/// A drum score in MusicXML, for Guitar Pro and notation programs: the parts are voices of a
+/// percussion staff, with the durations, triplets and tuplets of the patterns rather than what
+/// a MIDI import quantizes them to. Hits of another articulation are other instruments of the
+/// kit, the way Guitar Pro tells them apart. Parts repeat to fill `bars` bars, and `tempos` are
+/// the BPM from bars on, counted from 1, the first of them on bar 1.
+/// Grooves, swing and humanizing only move hits of the MIDI file, the score keeps them on the
+/// grid.
+pub fn score(
+    parts: &BTreeMap<DrumPart, Groups>,
+    registry: &PartRegistry,
+    signature: TimeSignature,
+    tempos: &[(u32, f64)],
+    bars: u32,
+    title: &str,
+) -> Result<String, String> {
+    let Layout { unit, bar, instruments, voices } = layout(parts, registry, signature, bars)?;
+
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    xml.push_str("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n");
+    xml.push_str("<score-partwise version=\"4.0\">\n");
+    let _ = writeln!(xml, "  <work><work-title>{}</work-title></work>", escape(title));
+    xml.push_str("  <identification><encoding><software>poly</software></encoding></identification>\n");
+    xml.push_str("  <part-list>\n    <score-part id=\"P1\">\n      <part-name>Drums</part-name>\n");
+    let mut numbered: Vec<_> = instruments.iter().collect();
+    numbered.sort_by_key(|(_, (n, _))| *n);
+    for ((part, _), (n, articulation)) in numbered.iter() {
+        let name = match articulation_name(*part, *articulation) {
+            Some(sound) => format!("{} ({})", registry.title(*part), sound),
+            None => registry.title(*part),
+        };
+        let _ = writeln!(xml, "      <score-instrument id=\"P1-I{}\"><instrument-name>{}</instrument-name></score-instrument>", n, escape(&name));
+    }
+    for ((part, key), (n, _)) in numbered.iter() {
+        let channel = registry.get(*part).and_then(|p| p.channel).map_or(10, |c| c.as_int() + 1);
+        let _ = writeln!(
+            xml,
+            "      <midi-instrument id=\"P1-I{}\"><midi-channel>{}</midi-channel><midi-unpitched>{}</midi-unpitched></midi-instrument>",
+            n,
+            channel,
+            key + 1
+        );
+    }
+    xml.push_str("    </score-part>\n  </part-list>\n  <part id=\"P1\">\n");
+    for m in 0..bars as usize {
+        let _ = writeln!(xml, "    <measure number=\"{}\">", m + 1);
+        if m == 0 {
+            let _ = writeln!(
+                xml,
+                "      <attributes><divisions>{}</divisions><key><fifths>0</fifths></key><time><beats>{}</beats><beat-type>{}</beat-type></time><clef><sign>percussion</sign></clef></attributes>",
+                32 * unit,
+                signature.numerator,
+                128 / signature.denominator.to_128th()
+            );
+        }
+        if let Some((_, bpm)) = tempos.iter().rev().find(|(b, _)| *b as usize == m + 1) {
+            let bpm = (bpm * 100.0).round() / 100.0;
+            let _ = writeln!(
+                xml,
+                "      <direction placement=\"above\"><direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>{}</per-minute></metronome></direction-type><sound tempo=\"{}\"/></direction>",
+                bpm, bpm
+            );
+        }
+        for (v, (part, measures)) in voices.iter().enumerate() {
+            if v > 0 {
+                let _ = writeln!(xml, "      <backup><duration>{}</duration></backup>", bar);
+            }
+            for note in measures.get(m).into_iter().flatten() {
+                write_note(&mut xml, note, *part, v + 1, &instruments, registry);
+            }
+        }
+        xml.push_str("    </measure>\n");
+    }
+    xml.push_str("  </part>\n</score-partwise>\n");
+    Ok(xml)
+}
+
+/// A part and the notes of every measure of it.
+pub(crate) type Voice = (DrumPart, Vec<Vec<Written>>);
+
+/// The parts of a score cut into measures, for the writers of every format of it.
+pub(crate) struct Layout {
+    /// Units of a 128th note, every note is a whole number of them.
+    pub unit: u64,
+    /// Units of a bar.
+    pub bar: u64,
+    /// Instruments of the kit by part and MIDI key, numbered from 1, with the articulation
+    /// they're played with.
+    pub instruments: BTreeMap<(DrumPart, u8), (usize, Articulation)>,
+    /// The notes of every measure of the parts that have any.
+    pub voices: Vec<Voice>,
+}
+
+/// Lays the parts out over `bars` bars of a `signature`.
+pub(crate) fn layout(parts: &BTreeMap<DrumPart, Groups>, registry: &PartRegistry, signature: TimeSignature, bars: u32) -> Result<Layout, String> {
+    // Every note is a whole number of units, a 128th note is `unit` of them.
+    let unit = parts
+        .values()
+        .flat_map(|groups| groups.0.iter())
+        .map(|group| group.length.fraction().1 as u64)
+        .fold(1, lcm);
+    let bar = signature.to_128th() as u64 * unit;
+    if bar == 0 {
+        return Err(format!("bars of {} can't be written as a score", signature));
+    }
+    let mut instruments: BTreeMap<(DrumPart, u8), (usize, Articulation)> = BTreeMap::new();
+    let mut voices = Vec::new();
+    for (part, groups) in parts.iter() {
+        let held = hold(groups, unit, bar * bars as u64);
+        if held.is_empty() {
+            continue;
+        }
+        let mut measures = Vec::new();
+        for notes in in_measures(&held, bar) {
+            let mut written = Vec::new();
+            for held in notes {
+                let mut values = notate(held.units, &held.length, unit)?;
+                for (i, note) in values.iter_mut().enumerate() {
+                    note.stroke = held.stroke;
+                    note.tie_stop = held.stroke.is_some() && (held.tied || i > 0);
+                }
+                written.extend(values);
+            }
+            for i in 1..written.len() {
+                if written[i].tie_stop {
+                    written[i - 1].tie_start = true;
+                }
+            }
+            bracket(&mut written);
+            for stroke in written.iter().filter_map(|n| n.stroke) {
+                let key = Part::Drum(*part).midi_key(stroke.articulation, registry).as_int();
+                let next = instruments.len() + 1;
+                instruments.entry((*part, key)).or_insert((next, stroke.articulation));
+            }
+            measures.push(written);
+        }
+        voices.push((*part, measures));
+    }
+    // Continuations of a hit tied over the barline of the measure before.
+    for (_, measures) in voices.iter_mut() {
+        for m in 1..measures.len() {
+            if measures[m].first().is_some_and(|n| n.tie_stop) {
+                if let Some(last) = measures[m - 1].last_mut() {
+                    last.tie_start = true;
+                }
+            }
+        }
+    }
+    Ok(Layout { unit, bar, instruments, voices })
+}
+
+/// The notes of a part repeated over `total` units, ties holding the hit before them.
+fn hold(groups: &Groups, unit: u64, total: u64) -> Vec<Held> {
+    let mut held: Vec<Held> = Vec::new();
+    let mut at = 0;
+    if groups.to_128th() == 0 {
+        return held;
+    }
+    'cycle: loop {
+        for group in groups.0.iter() {
+            let (n, d) = group.length.fraction();
+            let units = n as u64 * unit / d as u64;
+            for note in group.notes.iter() {
+                if at >= total {
+                    break 'cycle;
+                }
+                let units = units.min(total - at);
+                let last = held.last().and_then(|h| h.stroke);
+                let (stroke, tied) = match note {
+                    Note::Hit | Note::Stroke(_) => (Some(note.stroke()), false),
+                    Note::Tie if last.is_some() => (last, true),
+                    Note::Rest | Note::Tie => (None, false),
+                };
+                held.push(Held { units, length: group.length, stroke, tied });
+                at += units;
+            }
+        }
+    }
+    held
+}
+
+/// Notes of every measure of `bar` units, the ones over a barline split in two, the second
+/// half of a hit tied to the first.
+fn in_measures(held: &[Held], bar: u64) -> Vec<Vec<Held>> {
+    let mut measures = vec![Vec::new()];
+    let mut at = 0;
+    for note in held {
+        let (mut left, mut tied) = (note.units, note.tied);
+        while left > 0 {
+            let room = bar - at % bar;
+            let units = left.min(room);
+            measures.last_mut().unwrap().push(Held { units, length: note.length, stroke: note.stroke, tied });
+            at += units;
+            left -= units;
+            tied = note.stroke.is_some();
+            if at.is_multiple_of(bar) {
+                measures.push(Vec::new());
+            }
+        }
+    }
+    measures.pop_if(|m| m.is_empty());
+    measures
+}
+
+/// A note of `units` out of notes of `length` as the notes a score writes down, the longest
+/// ones first, with up to two dots. Notes of triplets and tuplets keep their time modification.
+fn notate(units: u64, length: &Length, unit: u64) -> Result<Vec<Written>, String> {
+    let tuplet = match length {
+        Length::Triplet(_) => (3, 2),
+        Length::Tuplet(_, n, m) => (*n as u32, *m as u32),
+        _ => (1, 1),
+    };
+    let (actual, normal) = (tuplet.0 as u64, tuplet.1 as u64);
+    let (n, d) = length.fraction();
+    let bracket = actual * n as u64 * unit / d as u64;
+    // Length in 128th notes it's written as, before the time modification.
+    let shown = units * actual;
+    if !shown.is_multiple_of(normal * unit) {
+        return Err(format!("notes of {} over a barline can't be written down", length));
+    }
+    let mut left = shown / (normal * unit);
+    let mut notes = Vec::new();
+    while left > 0 {
+        let (value, dots, span) = [128u64, 64, 32, 16, 8, 4, 2, 1]
+            .into_iter()
+            .flat_map(|base| (0..=2u8).rev().filter(move |dots| base >> dots > 0).map(move |dots| (base, dots, (base << 1) - (base >> dots))))
+            .find(|(_, _, span)| *span <= left)
+            .expect("a 128th note is the shortest");
+        let value = BasicLength::from_num((128 / value) as u16)?;
+        notes.push(Written {
+            duration: span * normal * unit / actual,
+            value,
+            dots,
+            tuplet,
+            bracket,
+            stroke: None,
+            tie_stop: false,
+            tie_start: false,
+            bracket_start: false,
+            bracket_stop: false,
+        });
+        left -= span;
+    }
+    Ok(notes)
+}
+
+/// Marks where the brackets of triplets and tuplets in a measure start and stop, a bracket
+/// around the notes of every tuplet, or as many of them as the measure has.
+fn bracket(notes: &mut [Written]) {
+    // The tuplet of the open bracket and how much of it the notes so far fill.
+    let mut open: Option<((u32, u32), u64)> = None;
+    for i in 0..notes.len() {
+        let tuplet = notes[i].tuplet;
+        if open.is_some_and(|(t, _)| t != tuplet) {
+            notes[i - 1].bracket_stop = true;
+            open = None;
+        }
+        if tuplet == (1, 1) {
+            continue;
+        }
+        let (_, filled) = open.get_or_insert_with(|| {
+            notes[i].bracket_start = true;
+            (tuplet, 0)
+        });
+        *filled += notes[i].duration;
+        if *filled >= notes[i].bracket {
+            notes[i].bracket_stop = true;
+            open = None;
+        }
+    }
+    if open.is_some() {
+        if let Some(last) = notes.last_mut() {
+            last.bracket_stop = true;
+        }
+    }
+}
+
+fn write_note(xml: &mut String, note: &Written, part: DrumPart, voice: usize, instruments: &BTreeMap<(DrumPart, u8), (usize, Articulation)>, registry: &PartRegistry) {
+    let types = ["whole", "half", "quarter", "eighth", "16th", "32nd", "64th", "128th"];
+    let kind = types[note.value.to_128th().leading_zeros() as usize - 24];
+    let mut head = String::new();
+    let mut body = String::new();
+    match note.stroke {
+        Some(stroke) => {
+            let (step, octave, notehead) = position(part, stroke.articulation);
+            let _ = write!(head, "<note dynamics=\"{:.2}\">", stroke.velocity as f64 / 90.0 * 100.0);
+            let _ = write!(body, "<unpitched><display-step>{}</display-step><display-octave>{}</display-octave></unpitched>", step, octave);
+            let _ = write!(body, "<duration>{}</duration>", note.duration);
+            if note.tie_stop {
+                body.push_str("<tie type=\"stop\"/>");
+            }
+            if note.tie_start {
+                body.push_str("<tie type=\"start\"/>");
+            }
+            let key = Part::Drum(part).midi_key(stroke.articulation, registry).as_int();
+            if let Some((n, _)) = instruments.get(&(part, key)) {
+                let _ = write!(body, "<instrument id=\"P1-I{}\"/>", n);
+            }
+            write_value(&mut body, note, voice, kind);
+            let ghost = stroke.velocity <= GHOST_VELOCITY;
+            if notehead != "normal" || ghost {
+                let parentheses = if ghost { " parentheses=\"yes\"" } else { "" };
+                let _ = write!(body, "<notehead{}>{}</notehead>", parentheses, notehead);
+            }
+        }
+        None => {
+            head.push_str("<note>");
+            let _ = write!(body, "<rest/><duration>{}</duration>", note.duration);
+            write_value(&mut body, note, voice, kind);
+        }
+    }
+    let mut notations = String::new();
+    if note.tie_stop {
+        notations.push_str("<tied type=\"stop\"/>");
+    }
+    if note.tie_start {
+        notations.push_str("<tied type=\"start\"/>");
+    }
+    if note.bracket_start {
+        notations.push_str("<tuplet type=\"start\" bracket=\"yes\"/>");
+    }
+    if note.bracket_stop {
+        notations.push_str("<tuplet type=\"stop\"/>");
+    }
+    if !notations.is_empty() {
+        let _ = write!(body, "<notations>{}</notations>", notations);
+    }
+    let _ = writeln!(xml, "      {}{}</note>", head, body);
+}
+
+fn write_value(body: &mut String, note: &Written, voice: usize, kind: &str) {
+    let _ = write!(body, "<voice>{}</voice><type>{}</type>", voice, kind);
+    for _ in 0..note.dots {
+        body.push_str("<dot/>");
+    }
+    if note.tuplet != (1, 1) {
+        let _ = write!(
+            body,
+            "<time-modification><actual-notes>{}</actual-notes><normal-notes>{}</normal-notes></time-modification>",
+            note.tuplet.0, note.tuplet.1
+        );
+    }
+}
+
+/// Line of the staff and notehead of a part played with an `articulation`, from the drum
+/// notation most charts use: the kick in the bottom space, the snare in the third one,
+/// cymbals with crosses above the staff.
+pub(crate) fn position(part: DrumPart, articulation: Articulation) -> (char, u8, &'static str) {
+    match (part, articulation) {
+        (DrumPart::KickDrum, _) => ('F', 4, "normal"),
+        (DrumPart::SnareDrum, Articulation::SideStick) => ('C', 5, "x"),
+        (DrumPart::SnareDrum, Articulation::Rimshot) => ('C', 5, "circle-x"),
+        (DrumPart::SnareDrum, _) => ('C', 5, "normal"),
+        (DrumPart::HiHat, Articulation::Closed) => ('G', 5, "x"),
+        (DrumPart::HiHat, _) => ('G', 5, "circle-x"),
+        (DrumPart::CrashCymbal, _) => ('A', 5, "x"),
+        (DrumPart::Cowbell, _) => ('B', 5, "triangle"),
+        (DrumPart::Claves, _) => ('B', 4, "x"),
+        (DrumPart::CongaOpen, _) => ('D', 5, "normal"),
+        (DrumPart::CongaSlap, _) => ('D', 5, "x"),
+        (DrumPart::CongaMute, _) => ('D', 5, "circle dot"),
+        (DrumPart::BongoHigh, _) => ('E', 5, "normal"),
+        (DrumPart::BongoLow, _) => ('A', 4, "normal"),
+        (DrumPart::Shaker, _) => ('F', 5, "diamond"),
+        (DrumPart::Tambourine, _) => ('G', 4, "diamond"),
+        (DrumPart::Custom(_), _) => ('E', 4, "normal"),
+    }
+}
+
+/// How an instrument of another articulation than the plain hit of its part is named.
+pub(crate) fn articulation_name(part: DrumPart, articulation: Articulation) -> Option<&'static str> {
+    match (part, articulation) {
+        (DrumPart::SnareDrum, Articulation::SideStick) => Some("Side Stick"),
+        (DrumPart::SnareDrum, Articulation::Rimshot) => Some("Rimshot"),
+        (DrumPart::HiHat, Articulation::Closed) => Some("Closed"),
+        (DrumPart::HiHat, _) => Some("Open"),
+        _ => None,
+    }
+}
+
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn lcm(a: u64, b: u64) -> u64 {
+    a / gcd(a, b) * b
+}
+
+#[test]
+fn test_musicxml() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let parts = BTreeMap::from_iter([
+        (DrumPart::KickDrum, groups("4x-2x").unwrap().1),
+        (DrumPart::SnareDrum, groups("8t-x-4-2x_").unwrap().1),
+        (DrumPart::HiHat, groups("8c4.x").unwrap().1),
+    ]);
+    let xml = score(&parts, &PartRegistry::default(), four_four, &[(1, 120.0), (2, 90.0)], 2, "A & B").unwrap();
+    assert!(xml.contains("<work-title>A &amp; B</work-title>"));
+    // Triplet eighths are 32 divisions of 96 to a quarter note.
+    assert!(xml.contains("<divisions>96</divisions>"));
+    assert!(xml.contains("<time><beats>4</beats><beat-type>4</beat-type></time>"));
+    assert!(xml.contains("<sound tempo=\"120\"/>") && xml.contains("<sound tempo=\"90\"/>"));
+    assert_eq!(xml.matches("<measure ").count(), 2);
+    // Every part is a voice, the closed and open hi-hat are instruments of their own.
+    assert_eq!(xml.matches("<backup>").count(), 4);
+    assert!(xml.contains("<instrument-name>Hi-Hat (Closed)</instrument-name>"));
+    assert!(xml.contains("<midi-unpitched>43</midi-unpitched>") && xml.contains("<midi-unpitched>47</midi-unpitched>"));
+    assert!(xml.contains("<type>quarter</type><dot/><notehead>circle-x</notehead>"));
+    // A bracket round every triplet.
+    assert_eq!(xml.matches("<time-modification><actual-notes>3</actual-notes><normal-notes>2</normal-notes></time-modification>").count(), 6);
+    assert_eq!(xml.matches("<tuplet type=\"start\"").count(), 2);
+    // The half note of the snare is held over the barline.
+    assert_eq!(xml.matches("<tied type=\"start\"/>").count(), 1);
+    assert_eq!(xml.matches("<tied type=\"stop\"/>").count(), 1);
+
+    // A dotted quarter over a barline of 3/8 and a quarter.
+    let three_eight = TimeSignature::from_str("3/8").unwrap();
+    let held = BTreeMap::from_iter([(DrumPart::SnareDrum, groups("4x4.x").unwrap().1)]);
+    let xml = score(&held, &PartRegistry::default(), three_eight, &[(1, 120.0)], 2, "").unwrap();
+    assert_eq!(xml.matches("<tied type=\"start\"/>").count(), 1);
+    assert!(xml.contains("<type>quarter</type></note>"));
+    // Three quarter notes in the time of five, over a barline where no note can be written.
+    let stretched = BTreeMap::from_iter([(DrumPart::SnareDrum, groups("4:3:5xxx").unwrap().1)]);
+    assert!(score(&stretched, &PartRegistry::default(), four_four, &[(1, 120.0)], 2, "").is_err());
+}
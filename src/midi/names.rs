@@ -0,0 +1,112 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::midi::core::{DrumPart, Part};
+use crate::midi::registry::PartRegistry;
+
+/// Name of the drum track, written `Verse`, or of the track of a part, written `bass=Walking`
+/// or `kick=Kick In`. A part has a track of its own when the bass follows the kick drum or
+/// when every part is written to a file of its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackName {
+    pub part: Option<Part>,
+    pub name: String,
+}
+
+impl TrackName {
+    /// Parses the name of a track of any part of the `registry`, `from_str` only knows the
+    /// built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, name) = match s.split_once('=') {
+            Some(("bass", rest)) => (Some(Part::Bass), rest),
+            Some((part, rest)) => (Some(Part::Drum(registry.part(part.trim())?)), rest),
+            None => (None, s),
+        };
+        match name.trim() {
+            "" => Err(format!("expected the name of a track like Verse or bass=Walking, got `{}`", s)),
+            name => Ok(TrackName {
+                part,
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    /// The name as `parse` reads it back.
+    pub fn to_string_with(&self, registry: &PartRegistry) -> String {
+        match self.part {
+            Some(Part::Bass) => format!("bass={}", self.name),
+            Some(Part::Drum(part)) => format!("{}={}", registry.name(part), self.name),
+            None => self.name.clone(),
+        }
+    }
+}
+
+impl FromStr for TrackName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        TrackName::parse(s, &PartRegistry::default())
+    }
+}
+
+impl fmt::Display for TrackName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_string_with(&PartRegistry::default()))
+    }
+}
+
+/// Names of the tracks of a file, written both as track names and, for targets that expect
+/// them, as instrument names.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackNames {
+    pub drums: Cow<'static, str>,
+    pub bass: Cow<'static, str>,
+}
+
+/// Names of the tracks of files that aren't given any.
+pub static DEFAULT_TRACK_NAMES: TrackNames = TrackNames {
+    drums: Cow::Borrowed("Drumkit"),
+    bass: Cow::Borrowed("Bass"),
+};
+
+impl Default for TrackNames {
+    fn default() -> Self {
+        DEFAULT_TRACK_NAMES.clone()
+    }
+}
+
+impl TrackNames {
+    /// Names of the tracks by the last of `names` for each of them. The drum track of a file of
+    /// a single `part` goes by the name of the part, its title if it has none.
+    pub fn new(names: &[TrackName], part: Option<DrumPart>, registry: &PartRegistry) -> TrackNames {
+        let name_of = |of: Option<Part>| names.iter().rev().find(|n| n.part == of).map(|n| Cow::Owned(n.name.clone()));
+        let defaults = TrackNames::default();
+        TrackNames {
+            drums: match part {
+                Some(part) => name_of(Some(Part::Drum(part))).unwrap_or_else(|| Cow::Owned(registry.title(part))),
+                None => name_of(None).unwrap_or(defaults.drums),
+            },
+            bass: name_of(Some(Part::Bass)).unwrap_or(defaults.bass),
+        }
+    }
+}
+
+#[test]
+fn test_track_names() {
+    let names: Vec<TrackName> = ["Verse", "bass=Walking", "kick=Kick In", "Chorus"].iter().map(|n| n.parse().unwrap()).collect();
+    let registry = PartRegistry::default();
+    assert_eq!(
+        TrackNames::new(&names, None, &registry),
+        TrackNames {
+            drums: Cow::Borrowed("Chorus"),
+            bass: Cow::Borrowed("Walking")
+        }
+    );
+    assert_eq!(TrackNames::new(&names, Some(DrumPart::KickDrum), &registry).drums, "Kick In");
+    assert_eq!(TrackNames::new(&names, Some(DrumPart::HiHat), &registry).drums, "Hi-Hat");
+    assert_eq!(TrackNames::new(&[], None, &registry), TrackNames::default());
+    assert_eq!(names[2].to_string(), "kick=Kick In");
+    assert!(TrackName::from_str("bass=").is_err());
+    assert!(TrackName::from_str("cajon=Cajon").is_err());
+}
@@ -0,0 +1,134 @@
+use midly::{MetaMessage, MidiMessage, Smf, TrackEvent, TrackEventKind};
+
+#[allow(unused_imports)]
+use midly::{Format, Header, Timing};
+
+/// Size of a file in bytes, as it's written.
+pub fn size(smf: &Smf) -> usize {
+    let mut bytes = Vec::new();
+    match smf.write_std(&mut bytes) {
+        Ok(_) => bytes.len(),
+        Err(_) => 0,
+    }
+}
+
+/// Where an event goes among the events of its tick: meta events first, as they break running
+/// status, then the controllers and program changes, then the notes a channel at a time, so
+/// their status bytes are written once. High resolution velocity prefixes stay with the notes
+/// they come before. The end of the track stays last.
+fn rank(kind: &TrackEventKind) -> (u8, u8) {
+    match kind {
+        TrackEventKind::Meta(MetaMessage::EndOfTrack) => (3, 0),
+        TrackEventKind::Meta(_) | TrackEventKind::SysEx(_) | TrackEventKind::Escape(_) => (0, 0),
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::NoteOn { .. },
+        } => (2, channel.as_int()),
+        TrackEventKind::Midi {
+            channel,
+            message: MidiMessage::Controller { controller, .. },
+        } if *controller == 88 => (2, channel.as_int()),
+        TrackEventKind::Midi { .. } => (1, 0),
+    }
+}
+
+/// Makes a file smaller without changing how it plays. Note offs become note ons of velocity 0,
+/// which share the status of the note ons around them, so running status leaves their status
+/// bytes out; the events of a tick are grouped so as many of them as possible share it. Empty
+/// text, repeated meta events of a tick and tempos or time signatures that change nothing are
+/// dropped. The velocities of note offs are lost.
+pub fn optimize(smf: &mut Smf) {
+    for track in smf.tracks.iter_mut() {
+        let mut time: u64 = 0;
+        let mut tempo = None;
+        let mut signature = None;
+        let mut events: Vec<(u64, TrackEventKind)> = Vec::with_capacity(track.len());
+        for event in track.iter() {
+            time += event.delta.as_int() as u64;
+            let kind = match event.kind {
+                TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOff { key, .. },
+                } => TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, vel: 0.into() },
+                },
+                TrackEventKind::Meta(MetaMessage::Text(b"")) => continue,
+                TrackEventKind::Meta(MetaMessage::Tempo(t)) if tempo.replace(t) == Some(t) => continue,
+                TrackEventKind::Meta(MetaMessage::TimeSignature(a, b, c, d)) if signature.replace((a, b, c, d)) == Some((a, b, c, d)) => {
+                    continue
+                }
+                kind @ TrackEventKind::Meta(_) if events.iter().rev().take_while(|(at, _)| *at == time).any(|(_, k)| *k == kind) => {
+                    continue
+                }
+                kind => kind,
+            };
+            events.push((time, kind));
+        }
+        events.sort_by_key(|(at, kind)| (*at, rank(kind)));
+        let mut last = 0;
+        *track = events
+            .into_iter()
+            .map(|(at, kind)| {
+                let delta = (at - last) as u32;
+                last = at;
+                TrackEvent { delta: delta.into(), kind }
+            })
+            .collect();
+    }
+}
+
+#[test]
+fn test_optimize() {
+    let event = |delta: u32, kind| TrackEvent { delta: delta.into(), kind };
+    let note = |channel: u8, key: u8, on: bool| TrackEventKind::Midi {
+        channel: channel.into(),
+        message: if on {
+            MidiMessage::NoteOn { key: key.into(), vel: 100.into() }
+        } else {
+            MidiMessage::NoteOff { key: key.into(), vel: 127.into() }
+        },
+    };
+    let tempo = TrackEventKind::Meta(MetaMessage::Tempo(500_000.into()));
+    let mut smf = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(48.into())),
+        tracks: vec![vec![
+            event(0, tempo),
+            event(0, TrackEventKind::Meta(MetaMessage::Text(b""))),
+            event(0, note(9, 36, true)),
+            event(0, note(0, 48, true)),
+            event(0, note(9, 42, true)),
+            event(24, note(9, 36, false)),
+            event(0, tempo),
+            event(0, note(9, 36, true)),
+            event(0, note(9, 42, false)),
+            event(24, note(9, 36, false)),
+            event(0, note(0, 48, false)),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]],
+    };
+    let before = size(&smf);
+    optimize(&mut smf);
+    assert!(size(&smf) < before);
+    let events: Vec<(u32, TrackEventKind)> = smf.tracks[0].iter().map(|e| (e.delta.as_int(), e.kind)).collect();
+    let off = |channel: u8, key: u8| TrackEventKind::Midi {
+        channel: channel.into(),
+        message: MidiMessage::NoteOn { key: key.into(), vel: 0.into() },
+    };
+    // Notes of a tick go a channel at a time, a note off still comes before the note on of its key.
+    assert_eq!(
+        events,
+        vec![
+            (0, tempo),
+            (0, note(0, 48, true)),
+            (0, note(9, 36, true)),
+            (0, note(9, 42, true)),
+            (24, off(9, 36)),
+            (0, note(9, 36, true)),
+            (0, off(9, 42)),
+            (24, off(0, 48)),
+            (0, off(9, 36)),
+            (0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]
+    );
+}
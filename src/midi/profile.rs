@@ -0,0 +1,115 @@
+use std::fmt;
+use std::str::FromStr;
+
+use midly::num::{u4, u7};
+
+/// Application the MIDI file is written for. They read the channels, programs and meta
+/// events at the start of a track differently, every profile sticks to what its target expects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Profile {
+    /// Guitar Pro 7, which picks up the instrument from the program change, channel and port.
+    GuitarPro,
+    Logic,
+    Ableton,
+    Reaper,
+    /// Any General MIDI player, the drums play on channel 10.
+    #[default]
+    GenericGm,
+}
+
+/// Conventions of the start of the tracks of a `Profile`. Channels count from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Conventions {
+    /// Channel the drum kit is played on.
+    pub drum_channel: u4,
+    /// Channel and program of the program change that selects the drum kit.
+    pub drum_program: Option<(u4, u7)>,
+    pub bass_channel: u4,
+    pub bass_program: Option<u7>,
+    /// Whether tracks are named by an instrument name as well as a track name.
+    pub instrument_names: bool,
+    /// Whether tracks start with a MIDI channel prefix of their channel.
+    pub channel_prefix: bool,
+    /// MIDI port of the drum track, if it's set.
+    pub port: Option<u8>,
+}
+
+impl Profile {
+    pub fn conventions(&self) -> Conventions {
+        // Electric bass (pick).
+        let bass_program = Some(u7::from(34));
+        match self {
+            // The way it's always been done for Guitar Pro, tested with Guitar Pro 7.
+            Profile::GuitarPro => Conventions {
+                drum_channel: u4::from(10),
+                drum_program: Some((u4::from(9), u7::from(0))),
+                bass_channel: u4::from(0),
+                bass_program,
+                instrument_names: true,
+                channel_prefix: true,
+                port: Some(10),
+            },
+            // Logic and Ableton load instruments of their own and keep program changes as
+            // events that switch patches of them, so they're left out.
+            Profile::Logic | Profile::Ableton => Conventions {
+                drum_channel: u4::from(9),
+                drum_program: None,
+                bass_channel: u4::from(0),
+                bass_program: None,
+                instrument_names: false,
+                channel_prefix: false,
+                port: None,
+            },
+            // Reaper keeps the channel of an imported track when there's a prefix.
+            Profile::Reaper => Conventions {
+                drum_channel: u4::from(9),
+                drum_program: Some((u4::from(9), u7::from(0))),
+                bass_channel: u4::from(0),
+                bass_program,
+                instrument_names: true,
+                channel_prefix: true,
+                port: None,
+            },
+            Profile::GenericGm => Conventions {
+                drum_channel: u4::from(9),
+                drum_program: Some((u4::from(9), u7::from(0))),
+                bass_channel: u4::from(0),
+                bass_program,
+                instrument_names: false,
+                channel_prefix: false,
+                port: None,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Profile::GuitarPro => "guitar-pro",
+            Profile::Logic => "logic",
+            Profile::Ableton => "ableton",
+            Profile::Reaper => "reaper",
+            Profile::GenericGm => "generic-gm",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "guitar-pro" => Ok(Profile::GuitarPro),
+            "logic" => Ok(Profile::Logic),
+            "ableton" => Ok(Profile::Ableton),
+            "reaper" => Ok(Profile::Reaper),
+            "generic-gm" => Ok(Profile::GenericGm),
+            _ => Err(format!(
+                "{} is not a target, expected one of: guitar-pro, logic, ableton, reaper, generic-gm",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,395 @@
+use std::fmt;
+use std::str::FromStr;
+
+use midly::num::{u4, u7};
+
+use crate::dsl::pitch::Key;
+use crate::midi::bassline::BassLine;
+use crate::midi::core::{DrumPart, Part};
+
+/// A part declared by the user, played on a MIDI key and channel of its own. Declared like
+/// `"Cajon" key=48 channel=10 velocity=100`, where the channel and the velocity are optional.
+/// Without a channel the part is played along with the drum kit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomPart {
+    pub name: String,
+    pub key: u7,
+    /// Channel the part is played on counting from 0, the channel of the drum kit if `None`.
+    pub channel: Option<u4>,
+    /// Velocity of a plain hit, accents and rolls are scaled to it.
+    pub velocity: u8,
+}
+
+impl FromStr for CustomPart {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, settings) = match s.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some(x) => x,
+                None => return Err(format!("expected `\"` to close the name of the part in {}", s)),
+            },
+            None => s.split_once(char::is_whitespace).unwrap_or((s, "")),
+        };
+        if name.is_empty() || name.contains(['=', '#', '"']) {
+            return Err(format!("{} is not a valid name of a part, it can't contain `=`, `#` or `\"`", name));
+        }
+        let mut key = None;
+        let mut channel = None;
+        let mut velocity = 127;
+        for setting in settings.split_whitespace() {
+            let number = |what: &str, range: std::ops::RangeInclusive<u8>, value: &str| {
+                value.parse::<u8>().ok().filter(|v| range.contains(v)).ok_or_else(|| {
+                    format!("{} is not a valid {}, expected a number from {} to {}", value, what, range.start(), range.end())
+                })
+            };
+            match setting.split_once('=') {
+                Some(("key", value)) => key = Some(number("key", 0..=127, value)?),
+                Some(("channel", value)) => channel = Some(u4::from(number("channel", 1..=16, value)? - 1)),
+                Some(("velocity", value)) => velocity = number("velocity", 1..=127, value)?,
+                _ => return Err(format!("unknown setting `{}`, expected key=, channel= or velocity=", setting)),
+            }
+        }
+        match key {
+            Some(key) => Ok(CustomPart {
+                name: name.to_string(),
+                key: u7::from(key),
+                channel,
+                velocity,
+            }),
+            None => Err(format!("{} should be given a MIDI key, like key=48", name)),
+        }
+    }
+}
+
+impl fmt::Display for CustomPart {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\"{}\" key={}", self.name, self.key)?;
+        if let Some(channel) = self.channel {
+            write!(f, " channel={}", channel.as_int() + 1)?;
+        }
+        write!(f, " velocity={}", self.velocity)
+    }
+}
+
+/// Another key a part plays along with its own, like a sub drop under the kick on a synth of
+/// its own. Declared like `kick key=35 channel=2 velocity=90`: the layer is played on the
+/// channel of the part unless it's given one, and its velocity scales the velocities of the
+/// hits like the velocity of a custom part does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layer {
+    pub part: DrumPart,
+    pub key: u7,
+    /// Channel the layer is played on counting from 0, the channel of the part if `None`.
+    pub channel: Option<u4>,
+    pub velocity: u8,
+}
+
+impl Layer {
+    /// Parses a layer of any part of the `registry`.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let declaration = CustomPart::from_str(s)?;
+        Ok(Layer {
+            part: registry.part(&declaration.name)?,
+            key: declaration.key,
+            channel: declaration.channel,
+            velocity: declaration.velocity,
+        })
+    }
+
+    /// The layer as `parse` reads it back.
+    pub fn to_string_with(&self, registry: &PartRegistry) -> String {
+        CustomPart {
+            name: registry.name(self.part),
+            key: self.key,
+            channel: self.channel,
+            velocity: self.velocity,
+        }
+        .to_string()
+    }
+
+    /// Velocity of the layer of a hit of `velocity`.
+    pub fn scale(&self, velocity: u8) -> u8 {
+        (velocity as u32 * self.velocity as u32 / 127).max(1) as u8
+    }
+}
+
+/// Velocities a part plays at, declared like `snare=45:30-60`: the velocity of a plain hit,
+/// which the others are scaled to like the ones of a custom part, and the range every velocity
+/// of the part is kept in once dynamics, accents and echoes are added. Either may be left out,
+/// like `kick=100-120` or `snare=45`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartVelocity {
+    pub part: Part,
+    /// Velocity of a plain hit, the one of the custom part or 127 if `None`.
+    pub default: Option<u8>,
+    pub min: u8,
+    pub max: u8,
+}
+
+impl PartVelocity {
+    /// Parses the velocities of any part of the `registry`, or of the bass.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let Some((name, velocities)) = s.split_once('=') else {
+            return Err(format!("expected the velocities of a part like snare=45:30-60, got {}", s.trim()));
+        };
+        let part = match name.trim() {
+            "bass" => Part::Bass,
+            name => Part::Drum(registry.part(name)?),
+        };
+        let velocity = |v: &str| match v.trim().parse::<u8>() {
+            Ok(v) if (1..=127).contains(&v) => Ok(v),
+            _ => Err(format!("{} is not a velocity, expected a number from 1 to 127", v.trim())),
+        };
+        let (default, range) = match velocities.split_once(':') {
+            Some((default, range)) => (Some(default), Some(range)),
+            None if velocities.contains('-') => (None, Some(velocities)),
+            None => (Some(velocities), None),
+        };
+        let (min, max) = match range.map(|r| r.split_once('-')) {
+            Some(Some((min, max))) => (velocity(min)?, velocity(max)?),
+            Some(None) => return Err(format!("expected a range of velocities like 30-60, got {}", range.unwrap_or("").trim())),
+            None => (1, 127),
+        };
+        let default = default.map(velocity).transpose()?;
+        if min > max {
+            return Err(format!("{}-{} is not a range of velocities, the lowest one goes first", min, max));
+        }
+        if let Some(d) = default.filter(|d| !(min..=max).contains(d)) {
+            return Err(format!("velocity {} of a plain hit is outside of {}-{}", d, min, max));
+        }
+        Ok(PartVelocity { part, default, min, max })
+    }
+
+    /// The velocities as `parse` reads them back.
+    pub fn to_string_with(&self, registry: &PartRegistry) -> String {
+        let name = match self.part {
+            Part::Drum(drum) => registry.name(drum),
+            Part::Bass => "bass".to_string(),
+        };
+        match (self.default, (self.min, self.max)) {
+            (Some(default), (1, 127)) => format!("{}={}", name, default),
+            (Some(default), (min, max)) => format!("{}={}:{}-{}", name, default, min, max),
+            (None, (min, max)) => format!("{}={}-{}", name, min, max),
+        }
+    }
+
+    /// Velocity of a hit of `velocity` by a part whose plain hits are `plain` without a
+    /// default of its own.
+    pub fn scale(&self, velocity: f64, plain: u8) -> f64 {
+        (velocity * self.default.unwrap_or(plain) as f64 / 127.0).clamp(self.min as f64, self.max as f64)
+    }
+}
+
+/// Parts that can be used next to the built-in ones. Custom parts are referred to with
+/// `DrumPart::Custom`, which only makes sense within the registry that declared them. It also
+/// has the layers of the parts, their velocities, the line the bass plays and the key of
+/// pitched hits.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct PartRegistry {
+    custom: Vec<CustomPart>,
+    layers: Vec<Layer>,
+    velocities: Vec<PartVelocity>,
+    bass_line: BassLine,
+    key: Option<Key>,
+}
+
+impl PartRegistry {
+    /// Adds a part to the registry, its name should differ from the names of every other part.
+    pub fn declare(&mut self, part: CustomPart) -> Result<DrumPart, String> {
+        if self.part(&part.name).is_ok() {
+            return Err(format!("{} is already a part", part.name));
+        }
+        let id = u8::try_from(self.custom.len()).map_err(|_| "too many custom parts".to_string())?;
+        self.custom.push(part);
+        Ok(DrumPart::Custom(id))
+    }
+
+    /// Looks a part up by its name, built-in parts first.
+    pub fn part(&self, name: &str) -> Result<DrumPart, String> {
+        if let Ok(part) = DrumPart::from_str(name) {
+            return Ok(part);
+        }
+        match self.custom.iter().position(|p| p.name == name) {
+            Some(i) => Ok(DrumPart::Custom(i as u8)),
+            None => Err(format!(
+                "{} is not a known drum part, expected one of: {}",
+                name,
+                DrumPart::ALL
+                    .iter()
+                    .map(|p| p.name())
+                    .chain(self.custom.iter().map(|p| p.name.as_str()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )),
+        }
+    }
+
+    /// The declaration of a custom part, `None` for the built-in ones.
+    pub fn get(&self, part: DrumPart) -> Option<&CustomPart> {
+        match part {
+            DrumPart::Custom(i) => self.custom.get(i as usize),
+            _ => None,
+        }
+    }
+
+    /// Name of the part, as accepted by `part`.
+    pub fn name(&self, part: DrumPart) -> String {
+        match self.get(part) {
+            Some(custom) => custom.name.clone(),
+            None => part.name().to_string(),
+        }
+    }
+
+    /// Name of the part for people, like "Kick Drum", custom parts go by their own names.
+    pub fn title(&self, part: DrumPart) -> String {
+        match part {
+            DrumPart::KickDrum => String::from("Kick Drum"),
+            DrumPart::SnareDrum => String::from("Snare Drum"),
+            DrumPart::HiHat => String::from("Hi-Hat"),
+            DrumPart::CrashCymbal => String::from("Crash Cymbal"),
+            DrumPart::Cowbell => String::from("Cowbell"),
+            DrumPart::Claves => String::from("Claves"),
+            DrumPart::CongaOpen => String::from("Open Conga"),
+            DrumPart::CongaSlap => String::from("Slapped Conga"),
+            DrumPart::CongaMute => String::from("Muted Conga"),
+            DrumPart::BongoHigh => String::from("High Bongo"),
+            DrumPart::BongoLow => String::from("Low Bongo"),
+            DrumPart::Shaker => String::from("Shaker"),
+            DrumPart::Tambourine => String::from("Tambourine"),
+            DrumPart::Custom(_) => self.name(part),
+        }
+    }
+
+    /// Every custom part, in the order they were declared.
+    pub fn custom(&self) -> &[CustomPart] {
+        &self.custom
+    }
+
+    /// Adds a layer to its part, a part can have as many as it takes.
+    pub fn layer(&mut self, layer: Layer) {
+        self.layers.push(layer);
+    }
+
+    /// Every layer, in the order they were added.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    /// Layers of a part.
+    pub fn layers_of(&self, part: DrumPart) -> impl Iterator<Item = &Layer> {
+        self.layers.iter().filter(move |l| l.part == part)
+    }
+
+    /// Sets the velocities of a part, replacing the ones it had.
+    pub fn set_velocity(&mut self, velocity: PartVelocity) {
+        self.velocities.retain(|v| v.part != velocity.part);
+        self.velocities.push(velocity);
+    }
+
+    /// Velocities of every part that has them, in the order they were set.
+    pub fn velocities(&self) -> &[PartVelocity] {
+        &self.velocities
+    }
+
+    pub fn velocity_of(&self, part: Part) -> Option<&PartVelocity> {
+        self.velocities.iter().find(|v| v.part == part)
+    }
+
+    pub fn set_bass_line(&mut self, bass_line: BassLine) {
+        self.bass_line = bass_line;
+    }
+
+    /// The notes the bass plays along with the kick drum.
+    pub fn bass_line(&self) -> &BassLine {
+        &self.bass_line
+    }
+
+    pub fn set_key(&mut self, key: Key) {
+        self.key = Some(key);
+    }
+
+    /// Key the degrees of pitched hits like `x[b3]` are in, C4 major if it isn't set.
+    pub fn key(&self) -> Key {
+        self.key.unwrap_or(Key { root: 60, minor: false })
+    }
+}
+
+#[test]
+fn test_custom_part_from_str() {
+    let cajon = CustomPart::from_str("\"Cajon\" key=48 velocity=100").unwrap();
+    assert_eq!(
+        cajon,
+        CustomPart {
+            name: "Cajon".to_string(),
+            key: u7::from(48),
+            channel: None,
+            velocity: 100,
+        }
+    );
+    assert_eq!(CustomPart::from_str(&cajon.to_string()), Ok(cajon));
+    assert_eq!(CustomPart::from_str("\"Low Tom\" key=45 channel=2").map(|p| (p.name, p.channel)), Ok(("Low Tom".to_string(), Some(u4::from(1)))));
+    assert!(CustomPart::from_str("cajon").is_err());
+    assert!(CustomPart::from_str("cajon key=128").is_err());
+    assert!(CustomPart::from_str("cajon key=48 channel=0").is_err());
+    assert!(CustomPart::from_str("cajon key=48 pan=64").is_err());
+}
+
+#[test]
+fn test_part_registry() {
+    let mut registry = PartRegistry::default();
+    let cajon = registry.declare(CustomPart::from_str("Cajon key=48").unwrap()).unwrap();
+    assert_eq!(registry.part("Cajon"), Ok(cajon));
+    assert_eq!(registry.part("kick"), Ok(DrumPart::KickDrum));
+    assert_eq!(registry.name(cajon), "Cajon");
+    assert!(registry.declare(CustomPart::from_str("Cajon key=49").unwrap()).is_err());
+    assert!(registry.declare(CustomPart::from_str("snare key=49").unwrap()).is_err());
+    assert!(registry.part("cajon").is_err());
+}
+
+#[test]
+fn test_layers() {
+    let mut registry = PartRegistry::default();
+    let cajon = registry.declare(CustomPart::from_str("\"Cajon\" key=48").unwrap()).unwrap();
+    let sub = Layer::parse("kick key=35 channel=2 velocity=64", &registry).unwrap();
+    assert_eq!((sub.part, sub.key, sub.channel), (DrumPart::KickDrum, u7::from(35), Some(u4::from(1))));
+    assert_eq!(sub.scale(127), 64);
+    assert_eq!(sub.scale(1), 1);
+    registry.layer(sub.clone());
+    registry.layer(Layer::parse("Cajon key=50", &registry).unwrap());
+    assert_eq!(registry.layers_of(DrumPart::KickDrum).collect::<Vec<_>>(), vec![&sub]);
+    assert_eq!(registry.layers_of(cajon).count(), 1);
+    assert_eq!(Layer::parse(&sub.to_string_with(&registry), &registry), Ok(sub));
+    assert!(Layer::parse("cajon key=50", &registry).is_err());
+    assert!(Layer::parse("kick channel=2", &registry).is_err());
+}
+
+#[test]
+fn test_part_velocity() {
+    let mut registry = PartRegistry::default();
+    let cajon = registry.declare(CustomPart::from_str("\"Cajon\" key=48 velocity=100").unwrap()).unwrap();
+    let snare = PartVelocity::parse("snare=45:30-60", &registry).unwrap();
+    assert_eq!(snare, PartVelocity { part: Part::Drum(DrumPart::SnareDrum), default: Some(45), min: 30, max: 60 });
+    // Plain hits at 45, ghost notes no quieter than 30, accents no louder than 60.
+    assert_eq!(snare.scale(127.0, 127), 45.0);
+    assert_eq!(snare.scale(20.0, 127), 30.0);
+    let kick = PartVelocity::parse("kick=100-120", &registry).unwrap();
+    assert_eq!((kick.default, kick.scale(127.0, 127), kick.scale(80.0, 127)), (None, 120.0, 100.0));
+    let custom = PartVelocity::parse("Cajon=1-80", &registry).unwrap();
+    assert_eq!(custom.scale(127.0, 100), 80.0);
+    for declared in ["snare=45:30-60", "kick=100-120", "bass=90", "Cajon=1-80"] {
+        assert_eq!(PartVelocity::parse(declared, &registry).unwrap().to_string_with(&registry), declared);
+    }
+    for wrong in ["snare", "snare=60-30", "snare=70:30-60", "snare=0", "snare=45:30", "cajon=45"] {
+        assert!(PartVelocity::parse(wrong, &registry).is_err(), "{}", wrong);
+    }
+
+    registry.set_velocity(snare);
+    registry.set_velocity(PartVelocity::parse("snare=50", &registry).unwrap());
+    registry.set_velocity(custom);
+    assert_eq!(registry.velocities().len(), 2);
+    assert_eq!(registry.velocity_of(Part::Drum(DrumPart::SnareDrum)).and_then(|v| v.default), Some(50));
+    assert_eq!(registry.velocity_of(Part::Drum(cajon)), Some(&custom));
+    assert_eq!(registry.velocity_of(Part::Bass), None);
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+use std::str::FromStr;
+
+use midly::num::u28;
+use midly::{Fps, Smf, Timing, TrackEvent};
+
+use crate::midi::merge::SongMap;
+
+#[allow(unused_imports)]
+use midly::{Format, Header, MetaMessage, TrackEventKind};
+
+/// Timing of the header of a file: ticks of quarter notes, or ticks of SMPTE frames for film
+/// and post production, where every event sits at an absolute time. Written `metrical` or
+/// `smpte:25:40`, 25 frames a second of 40 ticks each. Drop frame 29.97 is `smpte:29.97:80`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FileTiming {
+    #[default]
+    Metrical,
+    Smpte { fps: Fps, subframes: u8 },
+}
+
+impl FromStr for FileTiming {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "metrical" {
+            return Ok(FileTiming::Metrical);
+        }
+        let error = || format!("{} is not a timing, expected metrical or smpte:fps:ticks-per-frame like smpte:25:40", s);
+        let mut fields = s.split(':');
+        let (Some("smpte"), Some(fps), Some(subframes), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+            return Err(error());
+        };
+        let fps = match fps {
+            "29.97" => Fps::Fps29,
+            fps => fps.parse::<u8>().ok().and_then(Fps::from_int).ok_or_else(|| format!("{} is not a frame rate, expected 24, 25, 29.97 or 30", fps))?,
+        };
+        match subframes.parse::<u8>() {
+            Ok(subframes) if subframes > 0 => Ok(FileTiming::Smpte { fps, subframes }),
+            _ => Err(format!("{} is not a number of ticks in a frame, expected one from 1 to 255", subframes)),
+        }
+    }
+}
+
+impl fmt::Display for FileTiming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FileTiming::Metrical => write!(f, "metrical"),
+            FileTiming::Smpte { fps: Fps::Fps29, subframes } => write!(f, "smpte:29.97:{}", subframes),
+            FileTiming::Smpte { fps, subframes } => write!(f, "smpte:{}:{}", fps.as_int(), subframes),
+        }
+    }
+}
+
+impl FileTiming {
+    /// Moves the events of a file of metrical timing to this timing. Their times in seconds
+    /// follow the tempo changes of the file, which stay in it but no longer move anything.
+    /// A gap between events too long for a delta time is an error, and leaves the file as it was.
+    pub fn apply(&self, smf: &mut Smf) -> Result<(), String> {
+        let FileTiming::Smpte { fps, subframes } = *self else {
+            return Ok(());
+        };
+        let map = SongMap::read(smf)?;
+        let ticks_per_second = fps.as_f32() as f64 * subframes as f64;
+        let mut deltas = Vec::with_capacity(smf.tracks.len());
+        for track in smf.tracks.iter() {
+            let mut time: u64 = 0;
+            let mut last: u64 = 0;
+            let mut track_deltas = Vec::with_capacity(track.len());
+            for event in track.iter() {
+                time += event.delta.as_int() as u64;
+                let at = (map.seconds_of_tick(time) * ticks_per_second).round() as u64;
+                let delta = at - last;
+                if delta > u28::max_value().as_int() as u64 {
+                    return Err(format!(
+                        "Events {:.0} seconds apart don't fit into a delta time of {}, which is at most {} ticks",
+                        delta as f64 / ticks_per_second,
+                        self,
+                        u28::max_value()
+                    ));
+                }
+                track_deltas.push(u28::from(delta as u32));
+                last = at;
+            }
+            deltas.push(track_deltas);
+        }
+        for (track, deltas) in smf.tracks.iter_mut().zip(deltas) {
+            for (event, delta) in track.iter_mut().zip(deltas) {
+                *event = TrackEvent { delta, ..*event };
+            }
+        }
+        smf.header.timing = Timing::Timecode(fps, subframes);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_smpte() {
+    assert_eq!(FileTiming::from_str("smpte:25:40"), Ok(FileTiming::Smpte { fps: Fps::Fps25, subframes: 40 }));
+    assert_eq!(FileTiming::from_str("smpte:29.97:80").map(|t| t.to_string()), Ok("smpte:29.97:80".to_string()));
+    assert_eq!(FileTiming::from_str("metrical"), Ok(FileTiming::Metrical));
+    assert!(FileTiming::from_str("smpte:26:40").is_err());
+    assert!(FileTiming::from_str("smpte:25:0").is_err());
+    assert!(FileTiming::from_str("smpte:25").is_err());
+
+    let event = |delta: u32, kind| TrackEvent { delta: delta.into(), kind };
+    let note = TrackEventKind::Meta(MetaMessage::Marker(b"hit"));
+    // A quarter note of 48 ticks at 120 BPM, then at 60 BPM.
+    let mut smf = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(48.into())),
+        tracks: vec![vec![
+            event(0, TrackEventKind::Meta(MetaMessage::Tempo(500_000.into()))),
+            event(48, TrackEventKind::Meta(MetaMessage::Tempo(1_000_000.into()))),
+            event(24, note),
+            event(24, note),
+        ]],
+    };
+    FileTiming::from_str("smpte:25:40").unwrap().apply(&mut smf).unwrap();
+    assert_eq!(smf.header.timing, Timing::Timecode(Fps::Fps25, 40));
+    // A thousand ticks a second.
+    let deltas: Vec<u32> = smf.tracks[0].iter().map(|e| e.delta.as_int()).collect();
+    assert_eq!(deltas, vec![0, 500, 500, 500]);
+    assert!(FileTiming::Metrical.apply(&mut smf.clone()).is_ok());
+    assert!(FileTiming::from_str("smpte:25:40").unwrap().apply(&mut smf).is_err());
+
+    // The longest delta of 48 ticks a quarter note at 120 BPM lasts for days, way more than
+    // 2^28 ticks of 7650 a second.
+    let mut long = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Metrical(48.into())),
+        tracks: vec![vec![event(0, note), event(u28::max_value().as_int(), note)]],
+    };
+    let before = long.clone();
+    assert!(FileTiming::from_str("smpte:30:255").unwrap().apply(&mut long).is_err());
+    assert_eq!(long, before);
+}
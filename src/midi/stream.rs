@@ -0,0 +1,306 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use midly::{Format, Fps, Header, MetaMessage, MidiMessage, Smf, Timing, TrackEvent, TrackEventKind};
+
+use crate::midi::core::Tracks;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+#[allow(unused_imports)]
+use crate::midi::core::{converges_over_bars, create_smf, create_tracks, DrumPart, SmfOptions, TICKS_PER_QUARTER_NOTE};
+#[allow(unused_imports)]
+use crate::midi::profile::Profile;
+#[allow(unused_imports)]
+use crate::midi::registry::PartRegistry;
+#[allow(unused_imports)]
+use crate::midi::time::TimeSignature;
+#[allow(unused_imports)]
+use std::collections::BTreeMap;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Writes a Standard MIDI File an event at a time, without keeping a whole track in memory
+/// like `Smf::write_std` does. The length of a track goes before its events, so they are
+/// gone through twice, once to count their bytes and once to write them, which works on any
+/// output, stdout too. The bytes are the same as the ones of `midly`.
+pub struct StreamWriter<W: Write> {
+    out: W,
+    /// Tracks left to write out of the ones announced in the header.
+    tracks: u16,
+}
+
+impl<W: Write> StreamWriter<W> {
+    /// Starts a file of `tracks` tracks by writing its header.
+    pub fn new(mut out: W, header: Header, tracks: u16) -> io::Result<Self> {
+        let format: u16 = match header.format {
+            Format::SingleTrack => 0,
+            Format::Parallel => 1,
+            Format::Sequential => 2,
+        };
+        let timing = match header.timing {
+            Timing::Metrical(ticks) => ticks.as_int().to_be_bytes(),
+            Timing::Timecode(fps, subframe) => [(-(fps.as_int() as i8)) as u8, subframe],
+        };
+        out.write_all(b"MThd")?;
+        out.write_all(&6u32.to_be_bytes())?;
+        out.write_all(&format.to_be_bytes())?;
+        out.write_all(&tracks.to_be_bytes())?;
+        out.write_all(&timing)?;
+        Ok(StreamWriter { out, tracks })
+    }
+
+    /// Writes a track as its events come, `events` is gone through twice.
+    pub fn write_track<'a>(&mut self, events: impl IntoIterator<Item = TrackEvent<'a>, IntoIter: Clone>) -> io::Result<()> {
+        if self.tracks == 0 {
+            return Err(invalid("every track announced in the header is already written"));
+        }
+        let events = events.into_iter();
+        let mut counter = Counter(0);
+        encode(events.clone(), &mut counter)?;
+        let length = u32::try_from(counter.0).map_err(|_| invalid("the track is longer than 4 GiB"))?;
+        self.tracks -= 1;
+        self.out.write_all(b"MTrk")?;
+        self.out.write_all(&length.to_be_bytes())?;
+        encode(events, &mut self.out)
+    }
+
+    /// Ends the file, every track announced in the header should be written by now.
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.tracks > 0 {
+            return Err(invalid(&format!("{} more tracks were announced in the header", self.tracks)));
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Counts the bytes written to it.
+struct Counter(u64);
+
+impl Write for Counter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes the events of a track, leaving out the status of a MIDI message when it's the same
+/// as the one of the MIDI message before it, which is called running status. Every other
+/// event cancels it.
+fn encode<'a>(events: impl Iterator<Item = TrackEvent<'a>>, out: &mut impl Write) -> io::Result<()> {
+    let mut running_status = None;
+    for event in events {
+        out.write_all(&varlen(event.delta.as_int()))?;
+        match event.kind {
+            TrackEventKind::Midi { channel, message } => {
+                let status = status_nibble(&message) << 4 | channel.as_int();
+                if running_status != Some(status) {
+                    out.write_all(&[status])?;
+                    running_status = Some(status);
+                }
+                write_data(&message, out)?;
+            }
+            TrackEventKind::SysEx(bytes) => {
+                running_status = None;
+                out.write_all(&[0xF0])?;
+                with_length(bytes, out)?;
+            }
+            TrackEventKind::Escape(bytes) => {
+                running_status = None;
+                out.write_all(&[0xF7])?;
+                with_length(bytes, out)?;
+            }
+            TrackEventKind::Meta(meta) => {
+                running_status = None;
+                out.write_all(&[0xFF])?;
+                write_meta(&meta, out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn status_nibble(message: &MidiMessage) -> u8 {
+    match message {
+        MidiMessage::NoteOff { .. } => 0x8,
+        MidiMessage::NoteOn { .. } => 0x9,
+        MidiMessage::Aftertouch { .. } => 0xA,
+        MidiMessage::Controller { .. } => 0xB,
+        MidiMessage::ProgramChange { .. } => 0xC,
+        MidiMessage::ChannelAftertouch { .. } => 0xD,
+        MidiMessage::PitchBend { .. } => 0xE,
+    }
+}
+
+/// Writes the data bytes of a MIDI message, the ones after its status.
+fn write_data(message: &MidiMessage, out: &mut impl Write) -> io::Result<()> {
+    match *message {
+        MidiMessage::NoteOff { key, vel } | MidiMessage::NoteOn { key, vel } | MidiMessage::Aftertouch { key, vel } => {
+            out.write_all(&[key.as_int(), vel.as_int()])
+        }
+        MidiMessage::Controller { controller, value } => out.write_all(&[controller.as_int(), value.as_int()]),
+        MidiMessage::ProgramChange { program } => out.write_all(&[program.as_int()]),
+        MidiMessage::ChannelAftertouch { vel } => out.write_all(&[vel.as_int()]),
+        MidiMessage::PitchBend { bend } => {
+            let raw = bend.0.as_int();
+            out.write_all(&[(raw & 0x7F) as u8, (raw >> 7) as u8])
+        }
+    }
+}
+
+/// Writes a meta event after its status, its type, then its data.
+fn write_meta(meta: &MetaMessage, out: &mut impl Write) -> io::Result<()> {
+    let mut write = |kind: u8, bytes: &[u8]| {
+        out.write_all(&[kind])?;
+        with_length(bytes, out)
+    };
+    match *meta {
+        MetaMessage::TrackNumber(None) => write(0x00, &[]),
+        MetaMessage::TrackNumber(Some(number)) => write(0x00, &number.to_be_bytes()),
+        MetaMessage::Text(bytes) => write(0x01, bytes),
+        MetaMessage::Copyright(bytes) => write(0x02, bytes),
+        MetaMessage::TrackName(bytes) => write(0x03, bytes),
+        MetaMessage::InstrumentName(bytes) => write(0x04, bytes),
+        MetaMessage::Lyric(bytes) => write(0x05, bytes),
+        MetaMessage::Marker(bytes) => write(0x06, bytes),
+        MetaMessage::CuePoint(bytes) => write(0x07, bytes),
+        MetaMessage::ProgramName(bytes) => write(0x08, bytes),
+        MetaMessage::DeviceName(bytes) => write(0x09, bytes),
+        MetaMessage::MidiChannel(channel) => write(0x20, &[channel.as_int()]),
+        MetaMessage::MidiPort(port) => write(0x21, &[port.as_int()]),
+        MetaMessage::EndOfTrack => write(0x2F, &[]),
+        MetaMessage::Tempo(tempo) => write(0x51, &tempo.as_int().to_be_bytes()[1..]),
+        MetaMessage::SmpteOffset(smpte) => {
+            // The frame rate goes in the 2 bits above the hour.
+            let fps = match smpte.fps() {
+                Fps::Fps24 => 0,
+                Fps::Fps25 => 1,
+                Fps::Fps29 => 2,
+                Fps::Fps30 => 3,
+            };
+            write(0x54, &[smpte.hour() | fps << 5, smpte.minute(), smpte.second(), smpte.frame(), smpte.subframe()])
+        }
+        MetaMessage::TimeSignature(numerator, denominator, clocks, notes) => write(0x58, &[numerator, denominator, clocks, notes]),
+        MetaMessage::KeySignature(sharps, minor) => write(0x59, &[sharps as u8, minor as u8]),
+        MetaMessage::SequencerSpecific(bytes) => write(0x7F, bytes),
+        MetaMessage::Unknown(kind, bytes) => write(kind, bytes),
+    }
+}
+
+/// Bytes after their length as a variable-length quantity.
+fn with_length(bytes: &[u8], out: &mut impl Write) -> io::Result<()> {
+    let length = u32::try_from(bytes.len()).ok().filter(|l| *l < 1 << 28).ok_or_else(|| invalid("an event is longer than 256 MiB"))?;
+    out.write_all(&varlen(length))?;
+    out.write_all(bytes)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+/// A number as a MIDI variable-length quantity, 7 bits a byte with the highest ones first.
+fn varlen(value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        bytes.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    bytes.reverse();
+    bytes
+}
+
+/// Writes `smf` to `out` a track at a time.
+pub fn write(smf: &Smf, out: impl Write) -> io::Result<()> {
+    let tracks = u16::try_from(smf.tracks.len()).map_err(|_| invalid("too many tracks"))?;
+    let mut writer = StreamWriter::new(out, smf.header, tracks)?;
+    for track in smf.tracks.iter() {
+        writer.write_track(track.iter().copied())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the `tracks` of a file to `out`, their notes are mapped to MIDI events as they're
+/// written, the file is never in memory as a whole.
+pub fn write_tracks(tracks: &Tracks, out: impl Write) -> io::Result<()> {
+    let count = u16::try_from(tracks.tracks.len()).map_err(|_| invalid("too many tracks"))?;
+    let mut writer = StreamWriter::new(out, tracks.header, count)?;
+    for track in tracks.tracks.iter() {
+        writer.write_track(track.events())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// Saves `smf` to `path` a track at a time.
+pub fn save(smf: &Smf, path: impl AsRef<Path>) -> io::Result<()> {
+    write(smf, BufWriter::new(File::create(path)?))
+}
+
+#[test]
+fn test_stream_writer() {
+    assert_eq!(varlen(0), vec![0x00]);
+    assert_eq!(varlen(0x7F), vec![0x7F]);
+    assert_eq!(varlen(0x80), vec![0x81, 0x00]);
+    assert_eq!(varlen(0x0FFFFFFF), vec![0xFF, 0xFF, 0xFF, 0x7F]);
+
+    let registry = PartRegistry::default();
+    let options = SmfOptions { profile: Profile::GuitarPro, add_bass: true, ..SmfOptions::default() };
+    let parts = BTreeMap::from_iter([(DrumPart::KickDrum, groups("8x--x--").unwrap().1), (DrumPart::SnareDrum, groups("4-x").unwrap().1)]);
+    let pickups = BTreeMap::from_iter([(DrumPart::HiHat, groups("8x").unwrap().1)]);
+    let smf = create_smf(&registry, parts.clone(), pickups.clone(), "streamed", &options);
+    let mut expected = Vec::new();
+    smf.write_std(&mut expected).unwrap();
+
+    let mut writer = StreamWriter::new(Vec::new(), smf.header, smf.tracks.len() as u16).unwrap();
+    for track in smf.tracks.iter() {
+        writer.write_track(track.iter().copied()).unwrap();
+    }
+    assert!(writer.write_track(Vec::new()).is_err());
+    assert_eq!(writer.finish().unwrap(), expected);
+
+    // Tracks mapped as they're written come out the same.
+    let bars = converges_over_bars(&parts, options.time_signature);
+    let tracks = create_tracks(&registry, parts, pickups, bars, "streamed", &options, &mut |_| {});
+    let mut streamed = Vec::new();
+    write_tracks(&tracks, &mut streamed).unwrap();
+    assert_eq!(streamed, expected);
+
+    let unfinished = StreamWriter::new(Vec::new(), smf.header, 1).unwrap();
+    assert!(unfinished.finish().is_err());
+
+    // Events `poly` doesn't write, and running status broken by the ones that aren't MIDI.
+    let event = |delta: u32, kind| TrackEvent { delta: delta.into(), kind };
+    let note = |key: u8| TrackEventKind::Midi {
+        channel: 9.into(),
+        message: MidiMessage::NoteOn { key: key.into(), vel: 100.into() },
+    };
+    let others = Smf {
+        header: Header::new(Format::SingleTrack, Timing::Timecode(Fps::Fps29, 80)),
+        tracks: vec![vec![
+            event(0, TrackEventKind::Meta(MetaMessage::SmpteOffset(midly::SmpteTime::new(1, 2, 3, 4, 5, Fps::Fps25).unwrap()))),
+            event(0, TrackEventKind::Meta(MetaMessage::KeySignature(-3, true))),
+            event(0, TrackEventKind::Meta(MetaMessage::TrackNumber(Some(7)))),
+            event(0, note(36)),
+            event(200, note(38)),
+            event(0, TrackEventKind::SysEx(&[0x7E, 0x7F, 0x09, 0x01, 0xF7])),
+            event(0, note(36)),
+            event(0, TrackEventKind::Midi { channel: 1.into(), message: MidiMessage::PitchBend { bend: midly::PitchBend::from_int(-100) } }),
+            event(0, TrackEventKind::Escape(&[0xF3, 0x01])),
+            event(20_000, TrackEventKind::Meta(MetaMessage::Unknown(0x60, b"unknown"))),
+            event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)),
+        ]],
+    };
+    let mut expected = Vec::new();
+    others.write_std(&mut expected).unwrap();
+    let mut written = Vec::new();
+    write(&others, &mut written).unwrap();
+    assert_eq!(written, expected);
+}
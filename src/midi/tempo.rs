@@ -0,0 +1,235 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use midly::{MetaMessage, Smf, TrackEventKind};
+
+use crate::dsl::dsl::Length;
+use crate::midi::core::{MidiTempo, Tick};
+
+/// Tempo of a file from tick 0 on, changing on later ticks: a single tempo, the tempo changes
+/// of a song the drums are merged into or a ramp of them. Ticks count from the start of the
+/// file, before a pickup if there's one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TempoMap {
+    /// Tempo from a tick on, the first one is at tick 0 and there's at most one on a tick.
+    changes: Vec<(Tick, MidiTempo)>,
+}
+
+impl TempoMap {
+    /// The same tempo all the way.
+    pub fn constant(tempo: MidiTempo) -> TempoMap {
+        TempoMap { changes: vec![(Tick(0), tempo)] }
+    }
+
+    /// Tempo changes in any order, the last of a tick wins. It's 120 BPM until the first one.
+    pub fn from_changes(mut changes: Vec<(Tick, MidiTempo)>) -> TempoMap {
+        changes.sort_by_key(|(at, _)| *at);
+        let mut map = TempoMap::constant(MidiTempo::from_tempo(120.0));
+        for (at, tempo) in changes {
+            map.set(at, tempo);
+        }
+        map
+    }
+
+    /// Reads the tempo changes of every track of a file.
+    pub fn read(smf: &Smf) -> TempoMap {
+        let mut changes = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut time = 0;
+            for event in track.iter() {
+                time += event.delta.as_int() as u128;
+                if let TrackEventKind::Meta(MetaMessage::Tempo(tempo)) = event.kind {
+                    changes.push((Tick(time), MidiTempo::from_microseconds(tempo.as_int())));
+                }
+            }
+        }
+        TempoMap::from_changes(changes)
+    }
+
+    /// Changes the tempo from `at` on, up to the next change.
+    pub fn set(&mut self, at: Tick, tempo: MidiTempo) {
+        match self.changes.binary_search_by_key(&at, |(t, _)| *t) {
+            Ok(i) => self.changes[i].1 = tempo,
+            Err(i) => self.changes.insert(i, (at, tempo)),
+        }
+    }
+
+    /// Moves the tempo from the one at `from` to `tempo` BPM at `to` a `step` of ticks at a
+    /// time, in even steps of BPM, then keeps it there. Changes in between are dropped.
+    pub fn ramp(&mut self, from: Tick, to: Tick, tempo: f64, step: Tick) {
+        let start = 60_000_000.0 / self.tempo_at(from).microseconds() as f64;
+        self.changes.retain(|(at, _)| *at <= from || *at > to);
+        let (length, step) = (to.0.saturating_sub(from.0), step.0.max(1));
+        let mut at = from.0 + step;
+        while at < to.0 {
+            let bpm = start + (tempo - start) * (at - from.0) as f64 / length as f64;
+            self.set(Tick(at), MidiTempo::from_tempo(bpm));
+            at += step;
+        }
+        self.set(to, MidiTempo::from_tempo(tempo));
+    }
+
+    /// The tempo of `tempo` BPM with the `modulations` of a file of `bars` bars, in the order of
+    /// their bars, bar 1 starting on tick `bar_one` and every bar `bar` ticks long. The last
+    /// modulation of a bar wins.
+    pub fn modulated(tempo: f64, modulations: &[Modulation], bar_one: Tick, bar: Tick, bars: u32) -> Result<TempoMap, String> {
+        let mut map = TempoMap::constant(MidiTempo::from_tempo(tempo));
+        let mut modulations: Vec<Modulation> = modulations.iter().rev().cloned().collect();
+        modulations.sort_by_key(|m| m.bar);
+        modulations.dedup_by_key(|m| m.bar);
+        for modulation in modulations {
+            if modulation.bar > bars {
+                let plural = if bars == 1 { "" } else { "s" };
+                return Err(format!("the modulation {} is past the end of the file, which has {} bar{}", modulation, bars, plural));
+            }
+            let at = bar_one + bar * (modulation.bar - 1) as u128;
+            let bpm = 60_000_000.0 / map.tempo_at(at).microseconds() as f64 * modulation.ratio();
+            if !MidiTempo::RANGE.contains(&bpm) {
+                return Err(format!("the modulation {} takes the tempo to {:.2} BPM, out of the 3.6 to 60000000 a file can hold", modulation, bpm));
+            }
+            map.set(at, MidiTempo::from_tempo(bpm));
+        }
+        Ok(map)
+    }
+
+    pub fn changes(&self) -> &[(Tick, MidiTempo)] {
+        &self.changes
+    }
+
+    /// Tempo on a tick.
+    pub fn tempo_at(&self, tick: Tick) -> MidiTempo {
+        let i = self.changes.partition_point(|(at, _)| *at <= tick);
+        self.changes[i.saturating_sub(1)].1
+    }
+
+    /// Seconds from the start of the file to a tick, with `ppqn` ticks to a quarter note.
+    pub fn seconds_at(&self, tick: Tick, ppqn: u16) -> f64 {
+        self.segments(tick).map(|(length, tempo)| length.to_seconds(tempo, ppqn)).sum()
+    }
+
+    /// `seconds_at` as a `Duration`, to the nanosecond of every tempo.
+    pub fn duration_at(&self, tick: Tick, ppqn: u16) -> Duration {
+        self.segments(tick)
+            .map(|(length, tempo)| length.to_duration(tempo, ppqn))
+            .fold(Duration::ZERO, Duration::saturating_add)
+    }
+
+    /// Tick of the file a number of seconds in, rounded to the nearest one.
+    pub fn tick_at(&self, seconds: f64, ppqn: u16) -> Tick {
+        let mut elapsed = 0.0;
+        for (i, (start, tempo)) in self.changes.iter().enumerate() {
+            let seconds_per_tick = tempo.microseconds() as f64 / 1e6 / ppqn.max(1) as f64;
+            if let Some((next, _)) = self.changes.get(i + 1) {
+                let length = (*next - *start).to_seconds(*tempo, ppqn);
+                if seconds >= elapsed + length {
+                    elapsed += length;
+                    continue;
+                }
+            }
+            return *start + Tick(((seconds - elapsed) / seconds_per_tick).round() as u128);
+        }
+        unreachable!("the last tempo goes on forever")
+    }
+
+    /// Lengths of the ticks up to `tick` played at every tempo.
+    fn segments(&self, tick: Tick) -> impl Iterator<Item = (Tick, MidiTempo)> + '_ {
+        self.changes.iter().enumerate().take_while(move |(_, (at, _))| *at < tick).map(move |(i, (at, tempo))| {
+            let end = self.changes.get(i + 1).map_or(tick, |(next, _)| (*next).min(tick));
+            (end - *at, *tempo)
+        })
+    }
+}
+
+/// A metric modulation on the first beat of a bar, written `8.=4@5`: from bar 5 on, a note of
+/// the second length lasts as long as a note of the first one did before, so the dotted eighths
+/// of bar 4 go on as the quarter notes of bar 5 and the tempo goes up by a third.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modulation {
+    pub from: Length,
+    pub to: Length,
+    pub bar: u32,
+}
+
+impl Modulation {
+    /// The new tempo over the one before.
+    pub fn ratio(&self) -> f64 {
+        let ((from, of_from), (to, of_to)) = (self.from.fraction(), self.to.fraction());
+        (to * of_from) as f64 / (from * of_to) as f64
+    }
+}
+
+impl FromStr for Modulation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expected = || format!("expected a modulation like 8.=4@5, a length becoming another one from a bar on, got `{}`", s);
+        let (lengths, bar) = s.split_once('@').ok_or_else(expected)?;
+        let (from, to) = lengths.split_once('=').ok_or_else(expected)?;
+        let bar = match bar.trim().parse::<u32>() {
+            Ok(bar) if bar >= 2 => bar,
+            _ => return Err(format!("the modulation `{}` should start on bar 2 or later", s)),
+        };
+        Ok(Modulation {
+            from: Length::from_str(from)?,
+            to: Length::from_str(to)?,
+            bar,
+        })
+    }
+}
+
+impl fmt::Display for Modulation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}@{}", self.from, self.to, self.bar)
+    }
+}
+
+#[test]
+fn test_tempo_map() {
+    let bpm = MidiTempo::from_tempo;
+    let constant = TempoMap::constant(bpm(120.0));
+    assert_eq!(constant.seconds_at(Tick(48 * 8), 48), 4.0);
+    assert_eq!(constant.duration_at(Tick(24), 48), Duration::from_millis(250));
+    assert_eq!(constant.tick_at(4.0, 48), Tick(48 * 8));
+
+    // 4 beats at 120 BPM, then 60 BPM from tick 192 on.
+    let map = TempoMap::from_changes(vec![(Tick(192), bpm(60.0)), (Tick(0), bpm(120.0)), (Tick(192), bpm(30.0)), (Tick(192), bpm(60.0))]);
+    assert_eq!(map.changes(), &[(Tick(0), bpm(120.0)), (Tick(192), bpm(60.0))]);
+    assert_eq!((map.tempo_at(Tick(191)), map.tempo_at(Tick(192))), (bpm(120.0), bpm(60.0)));
+    assert_eq!(map.seconds_at(Tick(192 + 96), 48), 4.0);
+    assert_eq!(map.duration_at(Tick(192 + 96), 48), Duration::from_secs(4));
+    assert_eq!(map.tick_at(4.0, 48), Tick(192 + 96));
+    assert_eq!(map.tick_at(1.0, 48), Tick(96));
+    assert_eq!(TempoMap::from_changes(Vec::new()), TempoMap::constant(bpm(120.0)));
+
+    // From 100 to 140 BPM over a bar, a beat at a time.
+    let mut ramp = TempoMap::constant(bpm(100.0));
+    ramp.set(Tick(96), bpm(200.0));
+    ramp.ramp(Tick(0), Tick(192), 140.0, Tick(48));
+    assert_eq!(ramp.changes(), &[(Tick(0), bpm(100.0)), (Tick(48), bpm(110.0)), (Tick(96), bpm(120.0)), (Tick(144), bpm(130.0)), (Tick(192), bpm(140.0))]);
+}
+
+#[test]
+fn test_modulation() {
+    let bpm = MidiTempo::from_tempo;
+    let dotted = Modulation::from_str("8.=4@3").unwrap();
+    assert_eq!(dotted.to_string(), "8.=4@3");
+    assert_eq!(Modulation::from_str(" 8t = 8 @ 2 ").unwrap().to_string(), "8t=8@2");
+    // 90 BPM in 4/4, a dotted eighth becomes the quarter on bar 3, then an eighth the triplet eighth on bar 5.
+    let bar = Tick(192);
+    let modulations = [Modulation::from_str("8=8t@5").unwrap(), dotted];
+    let map = TempoMap::modulated(90.0, &modulations, Tick(0), bar, 8).unwrap();
+    assert_eq!(map.changes(), &[(Tick(0), bpm(90.0)), (Tick(384), bpm(120.0)), (Tick(768), bpm(80.0))]);
+    // A dotted eighth of bar 2 lasts as long as a quarter note of bar 3.
+    let (before, after) = (map.seconds_at(Tick(384), 48) - map.seconds_at(Tick(384 - 36), 48), map.seconds_at(Tick(384 + 48), 48) - map.seconds_at(Tick(384), 48));
+    assert!((before - after).abs() < 1e-6);
+    // After a pickup of a beat, bar 3 starts a beat later.
+    assert_eq!(TempoMap::modulated(90.0, &[dotted], Tick(48), bar, 8).unwrap().changes()[1].0, Tick(432));
+    let twice = [Modulation::from_str("8=4@3").unwrap(), dotted];
+    assert_eq!(TempoMap::modulated(90.0, &twice, Tick(0), bar, 8).unwrap().changes()[1], (Tick(384), bpm(120.0)));
+    assert!(TempoMap::modulated(90.0, &[dotted], Tick(0), bar, 2).is_err());
+    assert!(TempoMap::modulated(5.0, &[Modulation::from_str("4=32@2").unwrap()], Tick(0), bar, 2).is_err());
+    assert!(Modulation::from_str("8.=4@1").is_err());
+    assert!(Modulation::from_str("8.=4").is_err());
+    assert!(Modulation::from_str("8.=x@3").is_err());
+}
@@ -1,8 +1,16 @@
 extern crate derive_more;
 
-use std::{cmp::Ordering, str::FromStr};
+#[allow(unused_imports)]
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use midly::{MetaMessage, Smf, TrackEventKind};
 
-use crate::dsl::dsl::{BasicLength, GroupOrNote, KnownLength, Note, Group, groups, Times, EIGHTH, FOURTH};
+use crate::dsl::dsl::{BasicLength, GroupOrNote, KnownLength, Note};
+use crate::midi::core::Tick;
+#[allow(unused_imports)]
+use crate::dsl::dsl::{Group, Times, EIGHTH, FOURTH};
 
 use BasicLength::*;
 #[allow(unused_imports)]
@@ -18,7 +26,7 @@ pub struct TimeSignature {
 }
 
 impl TimeSignature {
-    pub(crate) fn to_midi(&self) -> (u8, u8) {
+    pub(crate) fn to_midi(self) -> (u8, u8) {
         let denominator = match self.denominator {
             Whole => 0, // FIXME: should it be an error?
             Half => 1,
@@ -27,9 +35,39 @@ impl TimeSignature {
             Sixteenth => 4,
             ThirtySecond => 5,
             SixtyFourth => 6,
+            OneHundredTwentyEighth => 7,
         };
         (self.numerator, denominator)
     }
+
+    /// Time signature of a partial bar of `length` 128th notes, like a pickup. Counts in beats of
+    /// this time signature when it can, in shorter notes when it has to.
+    pub fn partial(self, length: u32) -> Option<TimeSignature> {
+        [Whole, Half, Fourth, Eighth, Sixteenth, ThirtySecond, SixtyFourth, OneHundredTwentyEighth]
+            .into_iter()
+            .filter(|d| *d >= self.denominator && length.is_multiple_of(d.to_128th()))
+            .map(|d| (length / d.to_128th(), d))
+            .find(|(n, _)| *n > 0 && *n <= u8::MAX as u32)
+            .map(|(n, denominator)| TimeSignature {
+                numerator: n as u8,
+                denominator,
+            })
+    }
+
+    /// Reverses `to_midi`, the denominator is a power of two.
+    pub fn from_midi(numerator: u8, denominator: u8) -> Result<Self, String> {
+        let denominator = match denominator {
+            0..=7 => BasicLength::from_num(1 << denominator)?,
+            e => return Err(format!("2^{} is not a supported time signature denominator", e)),
+        };
+        Ok(TimeSignature { numerator, denominator })
+    }
+}
+
+impl fmt::Display for TimeSignature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
 }
 
 impl FromStr for TimeSignature {
@@ -59,13 +97,32 @@ impl FromStr for TimeSignature {
 #[test]
 fn test_time_signature_from_str() {
     assert_eq!(TimeSignature::from_str("4/4").unwrap(), TimeSignature { numerator: 4, denominator: Fourth });
+    assert_eq!(TimeSignature::from_str("7/8").unwrap().to_string(), "7/8");
+}
+
+#[test]
+fn test_time_signature_from_midi() {
+    let seven_eighths = TimeSignature::from_str("7/8").unwrap();
+    let (numerator, denominator) = seven_eighths.to_midi();
+    assert_eq!(TimeSignature::from_midi(numerator, denominator), Ok(seven_eighths));
+    assert!(TimeSignature::from_midi(4, 8).is_err());
+}
+
+#[test]
+fn test_time_signature_partial() {
+    let four_fourth = TimeSignature::from_str("4/4").unwrap();
+    assert_eq!(four_fourth.partial(32), TimeSignature::from_str("1/4").ok());
+    assert_eq!(four_fourth.partial(48), TimeSignature::from_str("3/8").ok());
+    assert_eq!(four_fourth.partial(96), TimeSignature::from_str("3/4").ok());
+    assert_eq!(four_fourth.partial(15), TimeSignature::from_str("15/128").ok());
+    assert_eq!(four_fourth.partial(0), None);
 }
 
 impl std::ops::Mul<u8> for TimeSignature {
     type Output = TimeSignature;
     fn mul(self, rhs: u8) -> TimeSignature {
         TimeSignature {
-            numerator: self.numerator * rhs as u8,
+            numerator: self.numerator * rhs,
             denominator: self.denominator,
         }
     }
@@ -104,37 +161,73 @@ fn test_time_signature_known_length() {
 
 impl TimeSignature {
     pub fn converges<T: KnownLength, I: IntoIterator<Item = T>>(&self, multiple: I) -> Result<u32, String> {
-        let bar_len = self.to_128th();
-        let result = multiple
-            .into_iter()
-            .fold(bar_len, |acc, t| lowest_common_divisor(t.to_128th(), acc));
-
-        let limit = 1000;
-
-        let out = result / bar_len;
+        self.converges_within(multiple, 1000)
+    }
 
-        if limit > out {
-            Ok(out)
-        } else {
-            Err("Does not converge".to_string())
+    /// Number of bars it takes the lengths to line up again, if it's less than `limit`.
+    pub fn converges_within<T: KnownLength, I: IntoIterator<Item = T>>(&self, multiple: I, limit: u32) -> Result<u32, String> {
+        match self.cycle_bars(multiple) {
+            Ok(bars) if bars < limit as u128 => Ok(bars as u32),
+            _ => Err("Does not converge".to_string()),
         }
     }
+
+    /// Exact number of bars it takes the lengths to line up again. The lowest common multiple
+    /// of co-prime lengths grows fast, it's an error if it doesn't fit into `u128`.
+    pub fn cycle_bars<T: KnownLength, I: IntoIterator<Item = T>>(&self, multiple: I) -> Result<u128, String> {
+        converge(multiple.into_iter().map(|t| t.to_128th()), *self)
+            .bars
+            .ok_or_else(|| "The lengths take more than 2^128 128th notes to line up again".to_string())
+    }
 }
 
-fn lowest_common_divisor(a: u32, b: u32) -> u32 {
-    let mut lcm = u32::max(a, b);
+/// When patterns line up again on a bar line, see `converge`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConvergenceReport {
+    /// Bars it takes the patterns to line up again, `None` if it doesn't fit into `u128`.
+    pub bars: Option<u128>,
+    /// The same in 128th notes.
+    pub length: Option<u128>,
+    /// Times every pattern is played in these bars, in the order of the lengths. `None` for
+    /// patterns of no length, and for all of them if the bars don't fit into `u128`.
+    pub repeats: Vec<Option<u128>>,
+}
 
-    while lcm % a != 0 || lcm % b != 0 {
-        lcm += 1;
+/// Works out when patterns of `lengths` 128th notes, like the `KnownLength::to_128th` of their
+/// groups, line up again on a bar line of `time_signature`. It needs no MIDI and builds no
+/// grids, so it's cheap enough for a calculator to run on every key press. A length of 0 makes
+/// them line up right away, after 0 bars.
+pub fn converge(lengths: impl IntoIterator<Item = u32>, time_signature: TimeSignature) -> ConvergenceReport {
+    let lengths: Vec<u128> = lengths.into_iter().map(u128::from).collect();
+    let bar = time_signature.to_128th() as u128;
+    let length = lengths.iter().try_fold(bar, |result, l| lowest_common_multiple(*l, result));
+    ConvergenceReport {
+        bars: length.map(|l| l / bar.max(1)),
+        length,
+        repeats: lengths.iter().map(|l| length.and_then(|total| total.checked_div(*l))).collect(),
     }
+}
 
-    lcm
+fn lowest_common_multiple(a: u128, b: u128) -> Option<u128> {
+    match greatest_common_divisor(a, b) {
+        0 => Some(0),
+        gcd => (a / gcd).checked_mul(b),
+    }
+}
+
+fn greatest_common_divisor(a: u128, b: u128) -> u128 {
+    if b == 0 {
+        a
+    } else {
+        greatest_common_divisor(b, a % b)
+    }
 }
 
 #[test]
 fn test_lcm() {
-    assert_eq!(lowest_common_divisor(128, 96), 384);
-    assert_eq!(lowest_common_divisor(96, 128), 384);
+    assert_eq!(lowest_common_multiple(128, 96), Some(384));
+    assert_eq!(lowest_common_multiple(96, 128), Some(384));
+    assert_eq!(lowest_common_multiple(u128::MAX, 2), None);
 }
 
 #[test]
@@ -153,7 +246,7 @@ fn test_converges() {
     };
     let thirteen_eights = Group {
         notes: vec![SingleNote(Hit)],
-        length: FOURTH.clone(),
+        length: *FOURTH,
         times: Times(12),
     };
     let in_shards_poly = Group {
@@ -162,7 +255,7 @@ fn test_converges() {
             GroupOrNote::SingleNote(Note::Rest),
             GroupOrNote::SingleGroup(thirteen_eights),
         ],
-        length: EIGHTH.clone(),
+        length: *EIGHTH,
         times: Times(1),
     };
     assert_eq!(three_fourth.converges(vec![four_fourth]), Ok(4));
@@ -170,4 +263,266 @@ fn test_converges() {
     assert_eq!(four_fourth.converges(vec![three_fourth, four_fourth]), Ok(3));
     assert_eq!(four_fourth.converges(vec![three_fourth, six_fourth, four_fourth]), Ok(3));
     assert_eq!(four_fourth.converges(vec![in_shards_poly]), Ok(13));
+    assert_eq!(four_fourth.converges_within(vec![three_fourth], 3), Err("Does not converge".to_string()));
+    assert_eq!(four_fourth.converges_within(vec![three_fourth], 4), Ok(3));
+}
+
+#[test]
+fn test_cycle_bars() {
+    let four_fourth = TimeSignature { numerator: 4, denominator: Fourth };
+    let primes = |limit: u8| {
+        (2..=limit)
+            .filter(|n| (2..*n).all(|d| n % d != 0))
+            .map(|n| TimeSignature { numerator: n, denominator: OneHundredTwentyEighth })
+            .collect::<Vec<_>>()
+    };
+    // Lengths of every prime number of 128th notes up to 97 line up after the product of the odd ones.
+    assert_eq!(four_fourth.cycle_bars(primes(97)), Ok(1152783981972759212376551073665878035));
+    assert_eq!(four_fourth.converges(primes(97)), Err("Does not converge".to_string()));
+    assert!(four_fourth.cycle_bars(primes(251)).is_err());
+}
+
+#[test]
+fn test_converge() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let seven_eight = TimeSignature::from_str("7/8").unwrap();
+    // Five and seven quarter notes against a bar of 4/4.
+    assert_eq!(
+        converge([5 * 32, 7 * 32], four_four),
+        ConvergenceReport { bars: Some(35), length: Some(35 * 128), repeats: vec![Some(28), Some(20)] }
+    );
+    assert_eq!(converge([], seven_eight), ConvergenceReport { bars: Some(1), length: Some(7 * 16), repeats: Vec::new() });
+    assert_eq!(converge([16 * 3], seven_eight).bars, Some(3));
+    assert_eq!(converge([0, 32], four_four), ConvergenceReport { bars: Some(0), length: Some(0), repeats: vec![None, Some(0)] });
+    let primes: Vec<u32> = (2..=251).filter(|n| (2..*n).all(|d| n % d != 0)).collect();
+    let report = converge(primes.iter().copied(), four_four);
+    assert_eq!((report.bars, report.repeats[0]), (None, None));
+}
+
+/// A position as a bar, a beat of it and a tick into the beat, written `2:3:24` the way
+/// `poly dump` prints them. Bars and beats count from 1, bars before bar 1 like a pickup
+/// count down from 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BarBeatTick {
+    pub bar: i64,
+    pub beat: u32,
+    pub tick: u64,
+}
+
+impl TimeSignature {
+    /// Length of a beat in ticks, at least one, rounded down when the beat doesn't fill whole
+    /// ticks, like a hundred-twenty-eighth at 48 ticks per quarter note.
+    fn beat_ticks(self, ppqn: u16) -> u64 {
+        (self.denominator.to_128th() as u64 * ppqn as u64 / 32).max(1)
+    }
+
+    /// Length of a bar counted in beats of `beat_ticks`.
+    fn bar_ticks(self, ppqn: u16) -> u64 {
+        (self.numerator as u64 * self.beat_ticks(ppqn)).max(1)
+    }
+}
+
+impl BarBeatTick {
+    /// Position of `tick` ticks after the start of bar 1.
+    pub fn from_tick(tick: Tick, time_signature: TimeSignature, ppqn: u16) -> BarBeatTick {
+        let (bar, beat) = (time_signature.bar_ticks(ppqn) as u128, time_signature.beat_ticks(ppqn) as u128);
+        let into_bar = tick.0 % bar;
+        BarBeatTick {
+            bar: (tick.0 / bar) as i64 + 1,
+            beat: (into_bar / beat) as u32 + 1,
+            tick: (into_bar % beat) as u64,
+        }
+    }
+
+    /// Ticks from the start of bar 1 to the position, the reverse of `from_tick`. Positions
+    /// before bar 1 and beats or ticks past the end of their bar or beat have none.
+    pub fn to_tick(self, time_signature: TimeSignature, ppqn: u16) -> Result<Tick, String> {
+        let (bar, beat) = (time_signature.bar_ticks(ppqn), time_signature.beat_ticks(ppqn));
+        if self.bar < 1 {
+            return Err(format!("{} comes before bar 1", self));
+        }
+        if self.beat < 1 || self.beat as u64 * beat > bar || self.tick >= beat {
+            return Err(format!("{} is not in a bar of {} with {} ticks to a quarter note", self, time_signature, ppqn));
+        }
+        Ok(Tick((self.bar - 1) as u128 * bar as u128 + (self.beat - 1) as u128 * beat as u128 + self.tick as u128))
+    }
+
+    /// The position `bars` bars later, or earlier for a negative number.
+    pub fn add_bars(self, bars: i64) -> BarBeatTick {
+        BarBeatTick { bar: self.bar + bars, ..self }
+    }
+}
+
+impl fmt::Display for BarBeatTick {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.bar, self.beat, self.tick)
+    }
+}
+
+impl FromStr for BarBeatTick {
+    type Err = String;
+
+    /// Reads a position like `2:3:24`, or `2:3` and `2` starting on the beat and on the bar.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let malformed = || format!("expected a position like 2:3:24 for bar 2, beat 3, tick 24, got `{}`", s.trim());
+        let mut fields = s.trim().split(':');
+        let bar = fields.next().and_then(|b| b.parse().ok()).ok_or_else(malformed)?;
+        let beat = fields.next().map_or(Ok(1), |b| b.parse().map_err(|_| malformed()))?;
+        let tick = fields.next().map_or(Ok(0), |t| t.parse().map_err(|_| malformed()))?;
+        if fields.next().is_some() || beat == 0 {
+            return Err(malformed());
+        }
+        Ok(BarBeatTick { bar, beat, tick })
+    }
+}
+
+#[test]
+fn test_bar_beat_tick() {
+    let four_four = TimeSignature::from_str("4/4").unwrap();
+    let seven_eight = TimeSignature::from_str("7/8").unwrap();
+    let at = |s: &str| BarBeatTick::from_str(s).unwrap();
+    assert_eq!(BarBeatTick::from_tick(Tick(0), four_four, 48), at("1:1:0"));
+    assert_eq!(BarBeatTick::from_tick(Tick(48 * 4 + 60), four_four, 48), at("2:2:12"));
+    assert_eq!(BarBeatTick::from_tick(Tick(24 * 7 + 30), seven_eight, 48), at("2:2:6"));
+    assert_eq!(at("2:2:6").to_tick(seven_eight, 48), Ok(Tick(24 * 7 + 30)));
+    for tick in [0, 1, 47, 48, 191, 192, 1000] {
+        let position = BarBeatTick::from_tick(Tick(tick), seven_eight, 96);
+        assert_eq!(position.to_tick(seven_eight, 96), Ok(Tick(tick)));
+    }
+    assert_eq!(at("3"), at("3:1:0"));
+    assert_eq!(at("0:2").to_string(), "0:2:0");
+    assert_eq!(at("1:4:0").add_bars(-1), at("0:4:0"));
+    assert!(at("0:1").to_tick(four_four, 48).is_err());
+    assert_eq!(at("1:5").to_tick(four_four, 48), Err("1:5:0 is not in a bar of 4/4 with 48 ticks to a quarter note".to_string()));
+    assert!(at("1:1:48").to_tick(four_four, 48).is_err());
+    assert!(BarBeatTick::from_str("1:0").is_err());
+    assert!(BarBeatTick::from_str("1:2:3:4").is_err());
+}
+
+/// Time signature of a file from tick 0 on, changing on later ticks, to number its bars and
+/// find where they start. A time signature changing mid-bar starts a new bar, the one it cuts
+/// short still counts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureMap {
+    /// Time signature from a tick on, the first one is at tick 0 and there's at most one on a
+    /// tick.
+    changes: Vec<(Tick, TimeSignature)>,
+}
+
+impl SignatureMap {
+    /// The same time signature all the way.
+    pub fn constant(time_signature: TimeSignature) -> SignatureMap {
+        SignatureMap { changes: vec![(Tick(0), time_signature)] }
+    }
+
+    /// Time signature changes in any order, the last of a tick wins. It's 4/4 until the first
+    /// one.
+    pub fn from_changes(mut changes: Vec<(Tick, TimeSignature)>) -> SignatureMap {
+        changes.sort_by_key(|(at, _)| *at);
+        let mut map = SignatureMap::constant(TimeSignature { numerator: 4, denominator: Fourth });
+        for (at, time_signature) in changes {
+            map.set(at, time_signature);
+        }
+        map
+    }
+
+    /// Reads the time signatures of every track of a file.
+    pub fn read(smf: &Smf) -> Result<SignatureMap, String> {
+        let mut changes = Vec::new();
+        for track in smf.tracks.iter() {
+            let mut time = 0;
+            for event in track.iter() {
+                time += event.delta.as_int() as u128;
+                if let TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) = event.kind {
+                    changes.push((Tick(time), TimeSignature::from_midi(numerator, denominator)?));
+                }
+            }
+        }
+        Ok(SignatureMap::from_changes(changes))
+    }
+
+    /// Changes the time signature from `at` on, up to the next change.
+    pub fn set(&mut self, at: Tick, time_signature: TimeSignature) {
+        match self.changes.binary_search_by_key(&at, |(t, _)| *t) {
+            Ok(i) => self.changes[i].1 = time_signature,
+            Err(i) => self.changes.insert(i, (at, time_signature)),
+        }
+    }
+
+    pub fn changes(&self) -> &[(Tick, TimeSignature)] {
+        &self.changes
+    }
+
+    /// Time signature on a tick.
+    pub fn signature_at(&self, tick: Tick) -> TimeSignature {
+        let i = self.changes.partition_point(|(at, _)| *at <= tick);
+        self.changes[i.saturating_sub(1)].1
+    }
+
+    /// Whether the time signature changes after `from` and before `to`.
+    pub fn changes_between(&self, from: Tick, to: Tick) -> bool {
+        self.changes.iter().any(|(at, _)| *at > from && *at < to)
+    }
+
+    /// Tick a bar starts on, counted from 1 on tick 0.
+    pub fn tick_of_bar(&self, bar: u32, ppqn: u16) -> Tick {
+        let mut first = 1;
+        for (i, (start, time_signature)) in self.changes.iter().enumerate() {
+            let length = time_signature.bar_ticks(ppqn) as u128;
+            match self.changes.get(i + 1) {
+                Some((next, _)) if bar.saturating_sub(first) as u128 * length >= next.0 - start.0 => {
+                    first += (next.0 - start.0).div_ceil(length) as u32;
+                }
+                _ => return *start + Tick(bar.saturating_sub(first) as u128 * length),
+            }
+        }
+        unreachable!("the last time signature goes on forever")
+    }
+
+    /// Position of a tick, bar 1 starting on tick 0.
+    pub fn position(&self, tick: Tick, ppqn: u16) -> BarBeatTick {
+        let mut bars = 0;
+        for (i, (start, time_signature)) in self.changes.iter().enumerate() {
+            match self.changes.get(i + 1) {
+                Some((next, _)) if tick >= *next => {
+                    bars += (next.0 - start.0).div_ceil(time_signature.bar_ticks(ppqn) as u128) as i64;
+                }
+                _ => return BarBeatTick::from_tick(tick - *start, *time_signature, ppqn).add_bars(bars),
+            }
+        }
+        unreachable!("the last time signature goes on forever")
+    }
+}
+
+#[test]
+fn test_signature_map() {
+    let signature = |s: &str| TimeSignature::from_str(s).unwrap();
+    let at = |s: &str| BarBeatTick::from_str(s).unwrap();
+    let constant = SignatureMap::constant(signature("7/8"));
+    assert_eq!(constant.tick_of_bar(3, 48), Tick(2 * 7 * 24));
+    assert_eq!(constant.position(Tick(2 * 7 * 24 + 30), 48), at("3:2:6"));
+    assert!(!constant.changes_between(Tick(0), Tick(10_000)));
+
+    // 2 bars of 3/4, half a bar of 4/4 then 6/8.
+    let map = SignatureMap::from_changes(vec![
+        (Tick(288 + 96), signature("6/8")),
+        (Tick(0), signature("3/4")),
+        (Tick(288), signature("4/4")),
+        (Tick(288), signature("5/4")),
+        (Tick(288), signature("4/4")),
+    ]);
+    assert_eq!(map.changes(), &[(Tick(0), signature("3/4")), (Tick(288), signature("4/4")), (Tick(384), signature("6/8"))]);
+    assert_eq!((map.signature_at(Tick(287)), map.signature_at(Tick(288))), (signature("3/4"), signature("4/4")));
+    assert_eq!(map.tick_of_bar(2, 48), Tick(144));
+    assert_eq!(map.tick_of_bar(3, 48), Tick(288));
+    assert_eq!(map.tick_of_bar(4, 48), Tick(384));
+    assert_eq!(map.tick_of_bar(6, 48), Tick(384 + 2 * 144));
+    assert_eq!(map.position(Tick(300), 48), at("3:1:12"));
+    assert_eq!(map.position(Tick(384 + 144 + 24), 48), at("5:2:0"));
+    for bar in 1..8 {
+        assert_eq!(map.position(map.tick_of_bar(bar, 48), 48), at(&bar.to_string()));
+    }
+    assert!(map.changes_between(Tick(0), Tick(300)));
+    assert!(!map.changes_between(Tick(288), Tick(384)));
+    assert_eq!(SignatureMap::from_changes(Vec::new()), SignatureMap::constant(signature("4/4")));
 }
@@ -0,0 +1,242 @@
+use std::str::FromStr;
+
+use midly::num::u7;
+use midly::MidiMessage;
+
+use crate::midi::core::Part;
+use crate::midi::registry::PartRegistry;
+
+#[allow(unused_imports)]
+use crate::midi::core::DrumPart;
+
+/// How the velocities of hits map to the velocities written, so files sit well with samplers
+/// whose velocity layers are quick to switch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    /// Velocities as they are.
+    Linear,
+    /// Quiet hits come out louder.
+    Soft,
+    /// Quiet hits come out quieter, only hard ones get loud.
+    Hard,
+    /// Every hit at the same velocity.
+    Fixed(u8),
+    /// Velocities between breakpoints of `velocity:written` go on straight lines between them,
+    /// the ones beyond the first or the last breakpoint get its velocity.
+    Breakpoints(Vec<(u8, u8)>),
+}
+
+impl Response {
+    /// The velocity written for a hit of `velocity`.
+    pub fn apply(&self, velocity: u8) -> u8 {
+        self.apply_precise(velocity as f64).round().clamp(1.0, 127.0) as u8
+    }
+
+    /// `apply` of a velocity with a fraction, keeping the fraction of the result.
+    pub fn apply_precise(&self, velocity: f64) -> f64 {
+        let share = velocity / 127.0;
+        let written = match self {
+            Response::Linear => velocity,
+            Response::Soft => share.sqrt() * 127.0,
+            Response::Hard => share * share * 127.0,
+            Response::Fixed(written) => *written as f64,
+            Response::Breakpoints(points) => match points.iter().position(|(at, _)| *at as f64 >= velocity) {
+                Some(0) => points[0].1 as f64,
+                Some(i) => {
+                    let ((x0, y0), (x1, y1)) = (points[i - 1], points[i]);
+                    y0 as f64 + (y1 as f64 - y0 as f64) * (velocity - x0 as f64) / (x1 - x0) as f64
+                }
+                None => points.last().map_or(velocity, |(_, y)| *y as f64),
+            },
+        };
+        written.clamp(1.0, 127.0)
+    }
+}
+
+impl FromStr for Response {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let velocity = |v: &str| match v.trim().parse::<u8>() {
+            Ok(v) if (1..=127).contains(&v) => Ok(v),
+            _ => Err(format!("{} is not a velocity, expected a number from 1 to 127", v.trim())),
+        };
+        match s.trim() {
+            "linear" => Ok(Response::Linear),
+            "soft" => Ok(Response::Soft),
+            "hard" => Ok(Response::Hard),
+            s if s.contains(':') => {
+                let mut points = Vec::new();
+                for point in s.split(',') {
+                    let Some((at, written)) = point.split_once(':') else {
+                        return Err(format!("expected a breakpoint like 64:100, got {}", point.trim()));
+                    };
+                    points.push((velocity(at)?, velocity(written)?));
+                }
+                if points.len() < 2 || points.windows(2).any(|w| w[0].0 >= w[1].0) {
+                    return Err(format!("{} should have two or more breakpoints of rising velocities", s));
+                }
+                Ok(Response::Breakpoints(points))
+            }
+            s => velocity(s).map(Response::Fixed).map_err(|_| {
+                format!("{} is not a velocity curve, expected linear, soft, hard, a velocity or breakpoints like 1:1,64:100,127:127", s)
+            }),
+        }
+    }
+}
+
+/// The velocity response of a part, or of every part, written `hihat=soft`. The bass is
+/// `bass=hard`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Curve {
+    pub part: Option<Part>,
+    pub response: Response,
+}
+
+impl Curve {
+    /// Parses the curve of any part of the `registry`, `from_str` only knows the built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, response) = match s.split_once('=') {
+            Some(("bass", rest)) => (Some(Part::Bass), rest),
+            Some((part, rest)) => (Some(Part::Drum(registry.part(part)?)), rest),
+            None => (None, s),
+        };
+        Ok(Curve {
+            part,
+            response: Response::from_str(response)?,
+        })
+    }
+}
+
+impl FromStr for Curve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Curve::parse(s, &PartRegistry::default())
+    }
+}
+
+/// The velocity written for a hit of a part, by the last curve of the part or, if it has
+/// none, the last one of every part.
+pub fn respond(curves: &[Curve], part: Part, velocity: u8) -> u8 {
+    match curve_of(curves, part) {
+        Some(response) => response.apply(velocity),
+        None => velocity,
+    }
+}
+
+/// `respond` of a velocity with a fraction.
+pub fn respond_precise(curves: &[Curve], part: Part, velocity: f64) -> f64 {
+    match curve_of(curves, part) {
+        Some(response) => response.apply_precise(velocity),
+        None => velocity,
+    }
+}
+
+fn curve_of(curves: &[Curve], part: Part) -> Option<&Response> {
+    let curve = curves.iter().rev().find(|c| c.part == Some(part)).or_else(|| curves.iter().rev().find(|c| c.part.is_none()));
+    curve.map(|c| &c.response)
+}
+
+/// A MIDI velocity of 14 bits for a velocity with a fraction: the 7 bits of the note on and
+/// the ones below them, sent before it as a high resolution velocity prefix (CC88).
+pub fn high_resolution(velocity: f64) -> (u8, u8) {
+    let bits = (velocity * 128.0).round().clamp(128.0, 16383.0) as u16;
+    ((bits >> 7) as u8, (bits & 0x7F) as u8)
+}
+
+/// Velocity of the note offs of a part, or of every part, written `hihat=0`. Some samplers
+/// play their release samples as loud as it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Release {
+    pub part: Option<Part>,
+    pub velocity: u8,
+}
+
+impl Release {
+    /// Parses the release of any part of the `registry`, `from_str` only knows the built-in ones.
+    pub fn parse(s: &str, registry: &PartRegistry) -> Result<Self, String> {
+        let (part, velocity) = match s.split_once('=') {
+            Some(("bass", rest)) => (Some(Part::Bass), rest),
+            Some((part, rest)) => (Some(Part::Drum(registry.part(part)?)), rest),
+            None => (None, s),
+        };
+        match velocity.trim().parse::<u8>() {
+            Ok(velocity) if velocity <= 127 => Ok(Release { part, velocity }),
+            _ => Err(format!("{} is not a note off velocity, expected a number from 0 to 127", velocity.trim())),
+        }
+    }
+}
+
+impl FromStr for Release {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Release::parse(s, &PartRegistry::default())
+    }
+}
+
+/// How note offs are written: with the velocities of `releases`, 127 for parts without one,
+/// or as note ons of velocity 0, which some players expect and running status packs tighter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NoteOffs {
+    pub releases: Vec<Release>,
+    pub as_note_ons: bool,
+}
+
+impl NoteOffs {
+    /// Velocity of the note offs of a part, by the last release of the part or, if it has
+    /// none, the last one of every part.
+    pub fn velocity(&self, part: Part) -> u8 {
+        let release = self.releases.iter().rev().find(|r| r.part == Some(part)).or_else(|| self.releases.iter().rev().find(|r| r.part.is_none()));
+        release.map_or(127, |r| r.velocity)
+    }
+
+    /// The message that ends a note of a part on `key`.
+    pub fn message(&self, part: Part, key: u7) -> MidiMessage {
+        if self.as_note_ons {
+            MidiMessage::NoteOn { key, vel: 0.into() }
+        } else {
+            MidiMessage::NoteOff { key, vel: self.velocity(part).into() }
+        }
+    }
+}
+
+#[test]
+fn test_velocity_curves() {
+    let curves: Vec<Curve> = ["soft", "kick=110", "snare=1:1,64:100,127:127"].iter().map(|c| c.parse().unwrap()).collect();
+    let hihat = Part::Drum(DrumPart::HiHat);
+    assert_eq!(respond(&curves, hihat, 32), 64);
+    assert_eq!(respond(&curves, hihat, 127), 127);
+    assert_eq!(respond(&curves, Part::Drum(DrumPart::KickDrum), 40), 110);
+    let snare = Part::Drum(DrumPart::SnareDrum);
+    assert_eq!(respond(&curves, snare, 64), 100);
+    assert_eq!(respond(&curves, snare, 96), 114);
+    assert_eq!(respond(&[], snare, 96), 96);
+    assert_eq!(Response::Hard.apply(64), 32);
+    assert_eq!(Curve::from_str("bass=hard").map(|c| c.part), Ok(Some(Part::Bass)));
+    assert!(Curve::from_str("snare=64:100").is_err());
+    assert!(Curve::from_str("snare=64:100,32:127").is_err());
+    assert!(Curve::from_str("loud").is_err());
+    assert!(Curve::from_str("cajon=soft").is_err());
+    assert_eq!(respond_precise(&curves, snare, 32.5), 50.5);
+    assert_eq!(high_resolution(63.5), (63, 64));
+    assert_eq!(high_resolution(0.2), (1, 0));
+}
+
+#[test]
+fn test_note_offs() {
+    let hihat = Part::Drum(DrumPart::HiHat);
+    let kick = Part::Drum(DrumPart::KickDrum);
+    assert_eq!(NoteOffs::default().message(hihat, 42.into()), MidiMessage::NoteOff { key: 42.into(), vel: 127.into() });
+    let note_offs = NoteOffs {
+        releases: ["64", "hihat=0"].iter().map(|r| r.parse().unwrap()).collect(),
+        as_note_ons: false,
+    };
+    assert_eq!(note_offs.velocity(hihat), 0);
+    assert_eq!(note_offs.velocity(kick), 64);
+    let as_note_ons = NoteOffs { as_note_ons: true, ..note_offs };
+    assert_eq!(as_note_ons.message(kick, 36.into()), MidiMessage::NoteOn { key: 36.into(), vel: 0.into() });
+    assert!(Release::from_str("hihat=128").is_err());
+    assert_eq!(Release::from_str("bass=0"), Ok(Release { part: Some(Part::Bass), velocity: 0 }));
+}
@@ -0,0 +1,53 @@
+/// Small seeded pseudo-random generator (SplitMix64).
+///
+/// Generated patterns have to be reproducible from a seed across platforms and releases,
+/// so we don't rely on an external generator which may change its output between versions.
+/// What the state of the generator goes up by on every draw, so the `n`th draw of a seed is
+/// the first one of the seed plus `n` times it.
+pub const GOLDEN_GAMMA: u64 = 0x9E3779B97F4A7C15;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(GOLDEN_GAMMA);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed value in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Uniformly distributed value in `[0, n)`. `n` should be non-zero.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Returns `true` with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+}
+
+#[test]
+fn test_rng_is_reproducible() {
+    let mut a = Rng::new(7);
+    let mut b = Rng::new(7);
+    let xs: Vec<u64> = (0..8).map(|_| a.next_u64()).collect();
+    let ys: Vec<u64> = (0..8).map(|_| b.next_u64()).collect();
+    assert_eq!(xs, ys);
+    assert_ne!(Rng::new(8).next_u64(), xs[0]);
+    assert!((0..1000).all(|_| a.below(3) < 3));
+    assert!((0..1000).map(|_| a.next_f64()).all(|x| (0.0..1.0).contains(&x)));
+}
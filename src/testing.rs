@@ -0,0 +1,188 @@
+//! [proptest](https://docs.rs/proptest) strategies for the core types, to check the laws of
+//! patterns and grids on values nobody thought to write down. Built with the `testing`
+//! feature, and for the tests of the crate itself.
+//!
+//! Every strategy makes values `poly` could make itself: lengths and groups that print back
+//! to patterns which parse, and grids that pass `EventGrid::validate`.
+
+use proptest::collection::{btree_map, vec};
+use proptest::prelude::*;
+
+use crate::dsl::dsl::{BasicLength, Group, Groups, KnownLength, Length, ModdedLength, Note};
+use crate::midi::core::{DrumPart, Event, EventGrid, EventType, Part, Tick};
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups as parse_groups;
+#[allow(unused_imports)]
+use crate::midi::core::Overlap;
+
+pub fn basic_length() -> impl Strategy<Value = BasicLength> {
+    prop_oneof![
+        Just(BasicLength::Whole),
+        Just(BasicLength::Half),
+        Just(BasicLength::Fourth),
+        Just(BasicLength::Eighth),
+        Just(BasicLength::Sixteenth),
+        Just(BasicLength::ThirtySecond),
+        Just(BasicLength::SixtyFourth),
+        Just(BasicLength::OneHundredTwentyEighth),
+    ]
+}
+
+/// Lengths with up to three dots, `MultiDotted` only with two or more of them, the way the
+/// parser reads them. No dot is shorter than a 128th note.
+pub fn modded_length() -> impl Strategy<Value = ModdedLength> {
+    let fits = |l: &BasicLength, dots: u8| l.to_128th().trailing_zeros() >= dots as u32;
+    prop_oneof![
+        basic_length().prop_map(ModdedLength::Plain),
+        basic_length().prop_filter("a dot shorter than a 128th note", move |l| fits(l, 1)).prop_map(ModdedLength::Dotted),
+        (basic_length(), 2..=3u8)
+            .prop_filter("a dot shorter than a 128th note", move |(l, dots)| fits(l, *dots))
+            .prop_map(|(l, dots)| ModdedLength::MultiDotted(l, dots)),
+    ]
+}
+
+/// Lengths of every kind, tuplets of up to 9 notes in the time of up to 8.
+pub fn length() -> impl Strategy<Value = Length> {
+    prop_oneof![
+        4 => modded_length().prop_map(Length::Simple),
+        1 => (modded_length(), modded_length()).prop_map(|(a, b)| Length::Tied(a, b)),
+        1 => modded_length().prop_map(Length::Triplet),
+        1 => (modded_length(), 1..=9u16, 1..=8u16).prop_map(|(l, n, m)| Length::Tuplet(l, n, m)),
+    ]
+}
+
+/// Hits, rests and ties. Strokes don't print their velocity back, so they're left out.
+pub fn note() -> impl Strategy<Value = Note> {
+    prop_oneof![3 => Just(Note::Hit), 2 => Just(Note::Rest), 1 => Just(Note::Tie)]
+}
+
+/// A group of 1 to 8 notes of a length.
+pub fn group() -> impl Strategy<Value = Group<Note, ()>> {
+    (vec(note(), 1..=8), length()).prop_map(|(notes, length)| Group { notes, length, times: () })
+}
+
+/// A pattern of 1 to 6 groups.
+pub fn groups() -> impl Strategy<Value = Groups> {
+    vec(group(), 1..=6).prop_map(Groups)
+}
+
+pub fn drum_part() -> impl Strategy<Value = DrumPart> {
+    prop_oneof![
+        Just(DrumPart::KickDrum),
+        Just(DrumPart::SnareDrum),
+        Just(DrumPart::HiHat),
+        Just(DrumPart::CrashCymbal),
+    ]
+}
+
+/// A valid grid of up to 4 notes of each of up to 4 parts. The notes of a part don't overlap,
+/// the grid ends up to 48 ticks after its last event.
+pub fn event_grid() -> impl Strategy<Value = EventGrid<Tick>> {
+    // Every note is the gap before it and how long it lasts.
+    let notes = vec((0..96u128, 1..96u128), 0..=4);
+    (btree_map(drum_part(), notes, 0..=4), 0..48u128).prop_map(|(parts, tail)| {
+        let mut events = Vec::new();
+        for (part, notes) in parts {
+            let mut at = 0;
+            for (gap, duration) in notes {
+                events.push(Event::new(Tick(at + gap), EventType::NoteOn(Part::Drum(part))));
+                at += gap + duration;
+                events.push(Event::new(Tick(at), EventType::NoteOff(Part::Drum(part))));
+            }
+        }
+        events.sort();
+        let end = events.last().map_or(0, |e| e.tick().0) + tail;
+        EventGrid::new(events, Tick(end))
+    })
+}
+
+impl Arbitrary for BasicLength {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        basic_length().boxed()
+    }
+}
+
+impl Arbitrary for ModdedLength {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        modded_length().boxed()
+    }
+}
+
+impl Arbitrary for Length {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        length().boxed()
+    }
+}
+
+impl Arbitrary for Groups {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        groups().boxed()
+    }
+}
+
+impl Arbitrary for EventGrid<Tick> {
+    type Parameters = ();
+    type Strategy = BoxedStrategy<Self>;
+
+    fn arbitrary_with(_: ()) -> Self::Strategy {
+        event_grid().boxed()
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_length_round_trip(length: Length) {
+        prop_assert_eq!(length.to_string().parse::<Length>().map(|l| l.to_128th()), Ok(length.to_128th()));
+    }
+
+    #[test]
+    fn test_groups_round_trip(groups: Groups) {
+        let printed = groups.to_string();
+        let (rest, parsed) = parse_groups(&printed).unwrap();
+        prop_assert_eq!(rest, "");
+        prop_assert_eq!(parsed.to_string(), printed);
+        prop_assert_eq!(parsed.to_128th(), groups.to_128th());
+    }
+
+    #[test]
+    fn test_generated_grids_are_valid(grid: EventGrid<Tick>) {
+        prop_assert_eq!(grid.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_concat_adds_lengths(a: EventGrid<Tick>, b: EventGrid<Tick>) {
+        let joined = a.concat(b.clone());
+        prop_assert_eq!(joined.length(), a.length() + b.length());
+        prop_assert_eq!(joined.len(), a.len() + b.len());
+        prop_assert_eq!(joined.validate(), Ok(()));
+    }
+
+    #[test]
+    fn test_delta_round_trip(grid: EventGrid<Tick>) {
+        let delta = grid.to_delta();
+        prop_assert_eq!(delta.iter().map(|e| e.tick().0).sum::<u128>(), grid.events().last().map_or(0, |e| e.tick().0));
+        prop_assert_eq!(delta.to_tick(), grid.clone());
+        let mut shuffled = grid.events().to_vec();
+        shuffled.reverse();
+        let unsorted = EventGrid::new(shuffled, grid.end()).to_delta();
+        prop_assert_eq!(unsorted.events(), delta.events());
+    }
+
+    #[test]
+    fn test_resolve_overlaps_keeps_valid_grids(grid: EventGrid<Tick>) {
+        prop_assert_eq!(grid.clone().resolve_overlaps(Overlap::TruncatePrevious), grid);
+    }
+}
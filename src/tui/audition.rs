@@ -0,0 +1,105 @@
+use std::io::{self, Write};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::live::playback::Loop;
+use crate::live::scheduler::{Clock, Player};
+
+#[allow(unused_imports)]
+use crate::api::spec::Spec;
+#[allow(unused_imports)]
+use std::str::FromStr;
+
+/// Longest sleep between two looks at the cues.
+const POLL: Duration = Duration::from_millis(5);
+
+enum Cue {
+    Play(Box<Loop>),
+    Stop,
+}
+
+/// Loops played to a MIDI device on a thread of their own while the patterns are edited. The
+/// loop of the patterns as they were edited last takes over on the quarter note the one before
+/// got to, so the groove carries on.
+pub struct Audition {
+    cues: Sender<Cue>,
+}
+
+impl Audition {
+    /// Plays to `out` at `tempo` BPM, once a loop is given.
+    pub fn start<W: Write + Send + 'static>(out: W, tempo: f64) -> Audition {
+        let (cues, received) = channel();
+        thread::spawn(move || audition(out, tempo, received));
+        Audition { cues }
+    }
+
+    /// Plays a loop in place of the one playing, or from its start if none is.
+    pub fn play(&self, looped: Loop) -> Result<(), String> {
+        self.send(Cue::Play(Box::new(looped)))
+    }
+
+    /// Stops the loop playing, the next one starts from the start.
+    pub fn stop(&self) -> Result<(), String> {
+        self.send(Cue::Stop)
+    }
+
+    fn send(&self, cue: Cue) -> Result<(), String> {
+        self.cues.send(cue).map_err(|_| "the device stopped taking messages".to_string())
+    }
+}
+
+/// Plays the loops cued until the `Audition` is dropped, returns the output.
+fn audition<W: Write>(mut out: W, tempo: f64, cues: Receiver<Cue>) -> io::Result<W> {
+    let mut next = None;
+    let mut beat = 0.0;
+    loop {
+        let looped = match next.take() {
+            Some(looped) => looped,
+            None => match cues.recv() {
+                Ok(Cue::Play(looped)) => looped,
+                Ok(Cue::Stop) => continue,
+                Err(_) => return Ok(out),
+            },
+        };
+        let clock = Clock {
+            tempo,
+            beat,
+            at: Instant::now(),
+        };
+        let mut player = Player::new(&looped, out, clock, beat)?;
+        let done = loop {
+            match cues.try_recv() {
+                Ok(Cue::Play(looped)) => {
+                    next = Some(looped);
+                    break false;
+                }
+                Ok(Cue::Stop) => break false,
+                Err(TryRecvError::Disconnected) => break true,
+                Err(TryRecvError::Empty) => {}
+            }
+            let due = player.play_until(Instant::now())?;
+            thread::sleep(due.saturating_duration_since(Instant::now()).min(POLL));
+        };
+        beat = if next.is_some() { player.beat() } else { 0.0 };
+        out = player.stop()?;
+        if done {
+            return Ok(out);
+        }
+    }
+}
+
+#[test]
+fn test_audition() {
+    let spec = Spec::from_str(r#"{"patterns": {"kick": "4x", "snare": "4-x"}, "tempo": 6000, "ppqn": 4}"#).unwrap();
+    let (cues, received) = channel();
+    let player = thread::spawn(move || audition(Vec::new(), 6000.0, received));
+    cues.send(Cue::Play(Box::new(Loop::from_spec(&spec).unwrap()))).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    cues.send(Cue::Stop).unwrap();
+    drop(cues);
+    let out = player.join().unwrap().unwrap();
+    // The program change of the drum kit, then the kick and the snare a hundred times a second.
+    assert_eq!(out[..5], [0xC9, 0x00, 0x99, 36, 127]);
+    assert!(out.windows(3).any(|w| w == [0x99, 38, 127]));
+}
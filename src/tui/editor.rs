@@ -0,0 +1,373 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use crate::dsl::dsl::{Group, Groups, Length, Note};
+use crate::generator::displacement::on_grid;
+use crate::midi::core::DrumPart;
+use crate::midi::grid::Grid;
+use crate::midi::registry::PartRegistry;
+use crate::midi::time::TimeSignature;
+
+#[allow(unused_imports)]
+use crate::dsl::dsl::groups;
+
+/// Subdivisions `<` and `>` step through, from the coarsest to the finest.
+pub const SUBDIVISIONS: [&str; 6] = ["4", "8", "8t", "16", "16t", "32"];
+
+/// A key pressed in the editor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Escape,
+    /// Ctrl-C, which a terminal in raw mode passes on rather than interrupting.
+    Interrupt,
+    Char(char),
+}
+
+impl Key {
+    /// Keys of the bytes a terminal in raw mode sends, arrows as the escape sequences of
+    /// either cursor key mode.
+    pub fn parse(bytes: &[u8]) -> Vec<Key> {
+        let mut keys = Vec::new();
+        let mut i = 0;
+        while i < bytes.len() {
+            let key = match bytes[i..] {
+                [0x1b, b'[' | b'O', arrow @ b'A'..=b'D', ..] => {
+                    i += 2;
+                    match arrow {
+                        b'A' => Key::Up,
+                        b'B' => Key::Down,
+                        b'C' => Key::Right,
+                        _ => Key::Left,
+                    }
+                }
+                [0x1b, ..] => Key::Escape,
+                [b'\r' | b'\n', ..] => Key::Enter,
+                [0x03, ..] => Key::Interrupt,
+                [byte, ..] if byte.is_ascii_graphic() || byte == b' ' => Key::Char(byte as char),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+            keys.push(key);
+            i += 1;
+        }
+        keys
+    }
+}
+
+/// What the editor asks of the program running it after a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    None,
+    /// The patterns changed, a loop playing them should follow.
+    Changed,
+    /// Starts the loop of the patterns, or stops it if it's playing.
+    Play,
+    /// Writes the patterns back.
+    Save,
+    Quit,
+}
+
+/// The steps of a part, a row of the editor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Row {
+    pub part: DrumPart,
+    pub steps: Vec<Note>,
+}
+
+/// A step sequencer of patterns: a row of steps for every part, all of them on the same grid.
+/// Rows may have different numbers of steps, the parts line up again the way patterns do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Editor {
+    pub grid: Grid,
+    pub time_signature: TimeSignature,
+    /// Names of the parts.
+    pub registry: PartRegistry,
+    pub rows: Vec<Row>,
+    /// Row and step of the cursor.
+    pub cursor: (usize, usize),
+    /// Whether a loop of the patterns is playing, set by the program playing it.
+    pub playing: bool,
+    /// What the last key did, shown under the grid.
+    pub status: String,
+}
+
+impl Editor {
+    /// An editor of `patterns` on a grid, with rows of rests for the parts of `parts` without a
+    /// pattern, as long as the first pattern or a bar if there's none. Fails if a pattern has
+    /// notes that aren't whole steps of the grid.
+    pub fn new(
+        patterns: &BTreeMap<DrumPart, Groups>,
+        parts: &[DrumPart],
+        grid: Grid,
+        time_signature: TimeSignature,
+        registry: &PartRegistry,
+    ) -> Result<Editor, String> {
+        let step = grid.step_length();
+        let mut rows = BTreeMap::new();
+        for (part, groups) in patterns.iter() {
+            let steps = on_grid(groups, step).map_err(|e| format!("{} can't go on a grid of {}: {}", registry.name(*part), grid, e))?;
+            rows.insert(*part, steps);
+        }
+        let length = rows.values().next().map(|steps| steps.len()).or(grid.bar_steps(time_signature)).unwrap_or(16);
+        for part in parts {
+            rows.entry(*part).or_insert_with(|| vec![Note::Rest; length]);
+        }
+        if rows.is_empty() {
+            return Err("there are no parts to edit".to_string());
+        }
+        Ok(Editor {
+            grid,
+            time_signature,
+            registry: registry.clone(),
+            rows: rows.into_iter().map(|(part, steps)| Row { part, steps }).collect(),
+            cursor: (0, 0),
+            playing: false,
+            status: String::new(),
+        })
+    }
+
+    /// Patterns of the rows with a hit, a group of notes a step long each.
+    pub fn patterns(&self) -> BTreeMap<DrumPart, String> {
+        self.rows
+            .iter()
+            .filter(|row| row.steps.iter().any(|note| matches!(note, Note::Hit | Note::Stroke(_))))
+            .map(|row| (row.part, Groups(vec![Group { notes: row.steps.clone(), length: self.grid.step_length(), times: () }]).to_string()))
+            .collect()
+    }
+
+    /// Does what a key stands for.
+    pub fn key(&mut self, key: Key) -> Action {
+        self.status.clear();
+        let (row, step) = self.cursor;
+        match key {
+            Key::Up | Key::Char('k') => self.move_to(row.saturating_sub(1), step),
+            Key::Down | Key::Char('j') => self.move_to(row + 1, step),
+            Key::Left | Key::Char('h') => self.move_to(row, step.saturating_sub(1)),
+            Key::Right | Key::Char('l') => self.move_to(row, step + 1),
+            Key::Enter | Key::Char(' ' | 'x') => {
+                let note = &mut self.rows[row].steps[step];
+                *note = match note {
+                    Note::Rest | Note::Tie => Note::Hit,
+                    Note::Hit | Note::Stroke(_) => Note::Rest,
+                };
+                return Action::Changed;
+            }
+            Key::Char('_') => {
+                let note = &mut self.rows[row].steps[step];
+                *note = if *note == Note::Tie { Note::Rest } else { Note::Tie };
+                return Action::Changed;
+            }
+            Key::Char(']') => {
+                self.rows[row].steps.push(Note::Rest);
+                return Action::Changed;
+            }
+            Key::Char('[') if self.rows[row].steps.len() > 1 => {
+                self.rows[row].steps.pop();
+                self.move_to(row, step);
+                return Action::Changed;
+            }
+            Key::Char('[') => self.status = "A row can't be shorter than a step".to_string(),
+            Key::Char(c @ ('<' | '>')) => {
+                // The nearest subdivision the rows fit, finer or coarser.
+                let step_of = |grid: &Grid| grid.step_length().fraction();
+                let (n, d) = step_of(&self.grid);
+                let grids = SUBDIVISIONS.iter().filter_map(|s| Grid::from_str(s).ok());
+                let mut next: Vec<Grid> = if c == '>' {
+                    grids.filter(|g| step_of(g).0 * d < n * step_of(g).1).collect()
+                } else {
+                    grids.filter(|g| step_of(g).0 * d > n * step_of(g).1).collect()
+                };
+                if c == '<' {
+                    next.reverse();
+                }
+                let mut error = None;
+                for grid in next {
+                    match self.set_grid(grid) {
+                        Ok(()) => return Action::Changed,
+                        Err(e) => error = error.or(Some(e)),
+                    }
+                }
+                let coarser = if c == '>' { "finer" } else { "coarser" };
+                self.status = error.unwrap_or_else(|| format!("There's no subdivision {} than {}", coarser, self.grid));
+            }
+            Key::Char('p') => return Action::Play,
+            Key::Char('w') => return Action::Save,
+            Key::Char('q') | Key::Escape | Key::Interrupt => return Action::Quit,
+            Key::Char(_) => {}
+        }
+        Action::None
+    }
+
+    fn move_to(&mut self, row: usize, step: usize) {
+        let row = row.min(self.rows.len() - 1);
+        self.cursor = (row, step.min(self.rows[row].steps.len() - 1));
+    }
+
+    /// Puts every row on another grid, if their hits and ties fall on its steps and they're
+    /// made of whole steps of it.
+    pub fn set_grid(&mut self, grid: Grid) -> Result<(), String> {
+        let mut rows = Vec::new();
+        for row in self.rows.iter() {
+            let steps = resample(&row.steps, self.grid.step_length(), grid.step_length())
+                .map_err(|e| format!("{} can't go on a grid of {}: {}", self.registry.name(row.part), grid, e))?;
+            rows.push(steps);
+        }
+        let (from, to) = (self.grid.step_length().fraction(), grid.step_length().fraction());
+        for (row, steps) in self.rows.iter_mut().zip(rows) {
+            row.steps = steps;
+        }
+        self.grid = grid;
+        let (row, step) = self.cursor;
+        self.move_to(row, (step as u64 * from.0 as u64 * to.1 as u64 / (from.1 as u64 * to.0 as u64)) as usize);
+        Ok(())
+    }
+
+    /// The screen of the editor `width` columns wide: the steps of every part around the
+    /// cursor, bar lines between bars, the pattern of the row of the cursor, the status and
+    /// the keys.
+    pub fn render(&self, width: usize) -> String {
+        let names: Vec<String> = self.rows.iter().map(|row| self.registry.name(row.part)).collect();
+        let margin = names.iter().map(|n| n.len()).max().unwrap_or(0) + 2;
+        let bar = self.grid.bar_steps(self.time_signature).filter(|steps| *steps > 0);
+        let beat = bar.filter(|steps| steps.is_multiple_of(self.time_signature.numerator as usize)).map(|steps| steps / self.time_signature.numerator as usize);
+        // A step takes 2 columns and a bar line 2 more, the steps shown are a page of them.
+        let columns = (width.saturating_sub(margin) / 2).max(1);
+        let shown = bar.map_or(columns, |bar| (columns * bar / (bar + 1)).max(1));
+        let (row, step) = self.cursor;
+        let first = step / shown * shown;
+        let mut screen = format!("Steps of {}, step {} of {}\n\n", self.grid, step + 1, self.rows[row].steps.len());
+        let mut ruler = " ".repeat(margin);
+        let longest = self.rows.iter().map(|row| row.steps.len()).max().unwrap_or(0);
+        for i in first..(first + shown).min(longest) {
+            if i > first && bar.is_some_and(|bar| i.is_multiple_of(bar)) {
+                ruler.push_str("  ");
+            }
+            let mark = match beat {
+                Some(beat) if i.is_multiple_of(beat) => (((i / beat) % self.time_signature.numerator as usize) + 1).to_string(),
+                _ => " ".to_string(),
+            };
+            ruler.push_str(&format!("{:<2}", mark));
+        }
+        screen.push_str(ruler.trim_end());
+        screen.push('\n');
+        for (r, (steps, name)) in self.rows.iter().map(|row| &row.steps).zip(names.iter()).enumerate() {
+            screen.push_str(&format!("{:<margin$}", name));
+            for (i, note) in steps.iter().enumerate().skip(first).take(shown) {
+                if i > first && bar.is_some_and(|bar| i.is_multiple_of(bar)) {
+                    screen.push_str("| ");
+                }
+                let cell = match note {
+                    Note::Hit => "x".to_string(),
+                    Note::Rest => ".".to_string(),
+                    Note::Tie => "_".to_string(),
+                    Note::Stroke(stroke) => stroke.articulation.to_string(),
+                };
+                if (r, i) == self.cursor {
+                    screen.push_str(&format!("\x1b[7m{}\x1b[0m ", cell));
+                } else {
+                    screen.push_str(&format!("{} ", cell));
+                }
+            }
+            screen.push('\n');
+        }
+        let pattern = self.patterns().remove(&self.rows[row].part).unwrap_or_else(|| "rests".to_string());
+        screen.push_str(&format!("\n{} = {}\n", names[row], pattern));
+        let playing = if self.playing { "Playing. " } else { "" };
+        screen.push_str(&format!("{}{}\n\n", playing, self.status));
+        screen.push_str("arrows move  space hit  _ tie  [ ] fewer or more steps  < > subdivision  p play  w write  q quit\n");
+        screen
+    }
+}
+
+/// Notes `from` long each as notes `to` long. A step of `to` is the note on its start, the
+/// notes after it in the step have to be ties or rests.
+fn resample(notes: &[Note], from: Length, to: Length) -> Result<Vec<Note>, String> {
+    let ((a, b), (c, d)) = (from.fraction(), to.fraction());
+    let (from_units, to_units) = (a as u64 * d as u64, c as u64 * b as u64);
+    let fine = gcd(from_units, to_units);
+    let (up, down) = ((from_units / fine) as usize, (to_units / fine) as usize);
+    let mut fine_notes = Vec::new();
+    for note in notes {
+        let held = if *note == Note::Rest { Note::Rest } else { Note::Tie };
+        fine_notes.push(*note);
+        fine_notes.extend(std::iter::repeat_n(held, up - 1));
+    }
+    if !fine_notes.len().is_multiple_of(down) {
+        return Err("the row isn't made of whole steps of it".to_string());
+    }
+    let mut steps = Vec::new();
+    for step in fine_notes.chunks(down) {
+        if step[1..].iter().any(|note| matches!(note, Note::Hit | Note::Stroke(_))) {
+            return Err("there are hits between its steps".to_string());
+        }
+        steps.push(step[0]);
+    }
+    Ok(steps)
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+#[test]
+fn test_editor() {
+    let (sixteenths, eighths) = (Grid::from_str("16").unwrap(), Grid::from_str("8").unwrap());
+    let four = TimeSignature::from_str("4/4").unwrap();
+    let registry = PartRegistry::default();
+    let patterns = BTreeMap::from_iter([(DrumPart::KickDrum, groups("8x--x--").unwrap().1)]);
+    let parts = [DrumPart::KickDrum, DrumPart::SnareDrum];
+    let mut editor = Editor::new(&patterns, &parts, sixteenths, four, &registry).unwrap();
+    // The kick held for its eighths, a row of rests as long for the snare.
+    assert_eq!(editor.rows[0].steps.len(), 12);
+    assert_eq!(editor.rows[1].steps, vec![Note::Rest; 12]);
+    assert_eq!(editor.patterns(), BTreeMap::from_iter([(DrumPart::KickDrum, "16x_----x_----".to_string())]));
+
+    for key in Key::parse(b"\x1b[B\x1b[C\x1b[C\x1b[C\x1b[C ") {
+        editor.key(key);
+    }
+    assert_eq!(editor.cursor, (1, 4));
+    assert_eq!(editor.patterns()[&DrumPart::SnareDrum], "16----x-------");
+    assert_eq!(editor.key(Key::Char('x')), Action::Changed);
+    assert!(!editor.patterns().contains_key(&DrumPart::SnareDrum));
+    editor.key(Key::Char(' '));
+
+    // A step more for the snare doesn't fill a step of an eighth.
+    assert_eq!(editor.key(Key::Char(']')), Action::Changed);
+    assert!(editor.set_grid(eighths).unwrap_err().contains("isn't made of whole steps"));
+    editor.key(Key::Char('['));
+    editor.set_grid(eighths).unwrap();
+    assert_eq!(editor.cursor, (1, 2));
+    assert_eq!(editor.patterns()[&DrumPart::KickDrum], "8x--x--");
+    assert_eq!(editor.patterns()[&DrumPart::SnareDrum], "8--x---");
+    // The kick falls between eighth note triplets, the next finer subdivision is sixteenths.
+    assert_eq!(editor.key(Key::Char('>')), Action::Changed);
+    assert_eq!((editor.grid, editor.cursor), (sixteenths, (1, 4)));
+    assert_eq!(editor.patterns()[&DrumPart::SnareDrum], "16----x_------");
+    assert_eq!(editor.key(Key::Char('<')), Action::Changed);
+    assert_eq!((editor.grid, editor.cursor), (eighths, (1, 2)));
+    editor.key(Key::Char('l'));
+    editor.key(Key::Char('x'));
+    assert_eq!(editor.key(Key::Char('<')), Action::None);
+    assert!(editor.status.starts_with("kick can't go on a grid of 4"));
+
+    assert_eq!(Key::parse(b"q\x1bOA\x03\r"), vec![Key::Char('q'), Key::Up, Key::Interrupt, Key::Enter]);
+    assert_eq!(editor.key(Key::Escape), Action::Quit);
+    assert_eq!(editor.key(Key::Char('p')), Action::Play);
+
+    let screen = editor.render(80);
+    assert!(screen.contains("\nsnare  . . x \x1b[7mx\x1b[0m . . \n"));
+    assert!(screen.contains("snare = 8--xx--"));
+    let thirty_seconds = BTreeMap::from_iter([(DrumPart::KickDrum, groups("32xx").unwrap().1)]);
+    assert!(Editor::new(&thirty_seconds, &[], sixteenths, four, &registry).is_err());
+}
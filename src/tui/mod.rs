@@ -0,0 +1,3 @@
+pub mod audition;
+pub mod editor;
+pub mod terminal;
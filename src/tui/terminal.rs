@@ -0,0 +1,72 @@
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use crate::tui::editor::Key;
+
+/// The terminal in raw mode on its alternate screen, so that keys come in as they're pressed
+/// without being echoed. Dropping it puts the terminal back the way it was.
+pub struct Terminal {
+    /// Settings of the terminal before, as `stty -g` prints them.
+    saved: String,
+}
+
+impl Terminal {
+    pub fn open() -> io::Result<Terminal> {
+        let saved = stty(&["-g"])?.trim().to_string();
+        stty(&["raw", "-echo"])?;
+        let mut out = io::stdout();
+        out.write_all(b"\x1b[?1049h\x1b[?25l")?;
+        out.flush()?;
+        Ok(Terminal { saved })
+    }
+
+    /// Columns of the terminal, 80 if it doesn't tell.
+    pub fn width(&self) -> usize {
+        stty(&["size"]).ok().and_then(|size| size.split_whitespace().nth(1)?.parse().ok()).filter(|width| *width > 0).unwrap_or(80)
+    }
+
+    /// Clears the screen and writes `screen` on it.
+    pub fn draw(&mut self, screen: &str) -> io::Result<()> {
+        let mut out = io::stdout().lock();
+        out.write_all(b"\x1b[H\x1b[2J")?;
+        // A line feed doesn't go back to the start of the line in raw mode.
+        out.write_all(screen.replace('\n', "\r\n").as_bytes())?;
+        out.flush()
+    }
+
+    /// Keys pressed, read from stdin on a thread of their own until it closes.
+    pub fn keys(&self) -> Receiver<Key> {
+        let (sender, keys) = channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+            let mut stdin = io::stdin().lock();
+            while let Ok(read @ 1..) = stdin.read(&mut buffer) {
+                for key in Key::parse(&buffer[..read]) {
+                    if sender.send(key).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        keys
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        let _ = out.write_all(b"\x1b[?25h\x1b[?1049l").and_then(|_| out.flush());
+        let _ = stty(&[self.saved.as_str()]);
+    }
+}
+
+/// Runs `stty` on the terminal of stdin, returns what it prints.
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("stty {} failed: {}", args.join(" "), String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
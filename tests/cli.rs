@@ -0,0 +1,61 @@
+//! Runs the `poly` executable on inputs that used to make it panic or hang, every one of them
+//! should end with an error message and exit code 1 instead.
+
+use std::process::{Command, Output};
+
+fn poly(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_poly")).args(args).output().unwrap()
+}
+
+fn assert_fails(args: &[&str]) -> String {
+    let output = poly(args);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("panicked"), "poly {} panicked:\n{}", args.join(" "), stderr);
+    assert_eq!(output.status.code(), Some(1), "poly {}", args.join(" "));
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+#[test]
+fn test_pattern_of_no_length() {
+    let stdout = assert_fails(&["-K", "(0,8x)", "-S", "4x", "--dry-run"]);
+    // Groups repeated no times are caught by the parser, before any of them is repeated.
+    assert!(stdout.contains("found `0`"), "{}", stdout);
+}
+
+#[test]
+fn test_time_signature_of_no_note() {
+    for signature in ["4/0", "4/3"] {
+        let stdout = assert_fails(&["-K", "4x", "-s", signature, "--dry-run"]);
+        assert!(stdout.contains("Can't parse the time signature"), "{}", stdout);
+    }
+}
+
+#[test]
+fn test_ratio_of_a_long_cycle() {
+    let stdout = assert_fails(&["ratio", "65535:65534", "--dry-run"]);
+    assert!(stdout.contains("takes more than 65535 steps"), "{}", stdout);
+}
+
+#[test]
+fn test_resolution_too_coarse_for_the_notes() {
+    let stdout = assert_fails(&["-K", "8x", "--ppqn", "1", "--dry-run"]);
+    assert!(stdout.contains("aren't a whole number of ticks"), "{}", stdout);
+}
+
+#[test]
+fn test_hits_past_the_bar_limit() {
+    // The kick drum comes in on bar 2, after the file is cut off.
+    let stdout = assert_fails(&["-K", "1-x", "--max-bars", "1", "--dry-run"]);
+    assert!(stdout.contains("the file would be silent"), "{}", stdout);
+}
+
+#[test]
+fn test_flags_a_subcommand_has_no_use_for() {
+    // Refused before any file is read, rather than quietly doing nothing.
+    let stdout = assert_fails(&["merge", "patterns.txt", "--into", "song.mid", "-t", "200", "-s", "7/8"]);
+    assert!(stdout.contains("--tempo doesn't apply to poly merge"), "{}", stdout);
+    let stdout = assert_fails(&["play", "patterns.txt", "-d", "/dev/null", "--gp", "drums.gp"]);
+    assert!(stdout.contains("--gp doesn't apply to poly play"), "{}", stdout);
+    let help = String::from_utf8_lossy(&poly(&["merge", "--help"]).stdout).into_owned();
+    assert!(!help.contains("--time-signature") && help.contains("--groove"), "{}", help);
+}